@@ -39,8 +39,49 @@ fn parse_kconfig(arch: &str) -> Option<()> {
     Some(())
 }
 
+/// Run git with `args`, returning its trimmed output if it succeeded.
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_string())
+}
+
+/// Export the git revision and enabled features, for sys:version.
+fn build_info() {
+    let revision = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=KERNEL_GIT_REVISION={}", revision);
+
+    // HEAD only changes when switching branches, while commits update the branch it points to,
+    // which is either a file of its own or packed with the others. Paths are asked from git, as
+    // the kernel may be a submodule or worktree.
+    let mut refs = vec!["HEAD".to_string(), "packed-refs".to_string()];
+    refs.extend(git(&["symbolic-ref", "-q", "HEAD"]));
+    for name in refs {
+        let path = git(&["rev-parse", "--git-path", &name]);
+        if let Some(path) = path.filter(|path| Path::new(path).exists()) {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+
+    let mut features = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase())
+        })
+        .collect::<Vec<_>>();
+    features.sort();
+    println!("cargo:rustc-env=KERNEL_FEATURES={}", features.join(" "));
+    // Read by option_env! for the initial log filters.
+    println!("cargo:rerun-if-env-changed=KERNEL_LOG");
+}
+
 fn main() {
     println!("cargo:rustc-env=TARGET={}", env::var("TARGET").unwrap());
+    build_info();
 
     let out_dir = env::var("OUT_DIR").unwrap();
     let cfg = Cfg::new(env::var_os("TARGET").unwrap()).unwrap();
@@ -0,0 +1,479 @@
+//! The Fixed ACPI Description Table, and the fixed hardware registers it describes.
+//!
+//! The kernel handles the SCI itself, since the events raising it must be cleared before the line
+//! is released. Fixed events, the power and sleep buttons and the RTC alarm, are cleared and
+//! queued for `acpi:events`. General purpose events (GPEs) are handled by AML methods, so a GPE
+//! that fires is disabled and queued, until userspace has run its `_Lxx` or `_Exx` method and
+//! enables it again. This is also how a lid switch is reported, by the method of its GPE notifying
+//! the lid device.
+
+use core::{
+    mem, ptr,
+    sync::atomic::{AtomicU16, AtomicU64, Ordering},
+};
+
+use spin::{Mutex, Once};
+
+use crate::{
+    interrupt,
+    paging::{KernelMapper, PhysicalAddress, RmmA, RmmArch},
+    syscall::io::{Io, Pio},
+};
+
+use super::{find_sdt, get_sdt, map_linearly, sdt::Sdt};
+
+/// PM1 status and enable bits of the fixed events.
+pub const PWRBTN: u16 = 1 << 8;
+pub const SLPBTN: u16 = 1 << 9;
+pub const RTC: u16 = 1 << 10;
+/// PM1 status bit set once the system has woken.
+const WAK_STS: u16 = 1 << 15;
+
+/// The fixed events reported, and their names in `acpi:events`.
+pub const FIXED_EVENTS: [(u16, &str); 3] = [
+    (PWRBTN, "power_button"),
+    (SLPBTN, "sleep_button"),
+    (RTC, "rtc"),
+];
+const FIXED_MASK: u16 = PWRBTN | SLPBTN | RTC;
+
+/// PM1 control bits.
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+/// FADT flags: the power or sleep button is a control method device, rather than a fixed event.
+const FLAG_PWR_BUTTON: u32 = 1 << 4;
+const FLAG_SLP_BUTTON: u32 = 1 << 5;
+
+/// Number of GPEs that can be reported.
+pub const MAX_GPES: usize = 256;
+
+/// How long to wait for the firmware to switch to ACPI mode, or to put the system to sleep.
+const SPINS: usize = 10_000_000;
+
+/// Offset of the 32-bit and 64-bit firmware waking vectors in the FACS.
+const FACS_WAKING_VECTOR: usize = 12;
+const FACS_X_WAKING_VECTOR: usize = 24;
+
+pub static FADT: Once<Fadt> = Once::new();
+
+/// Fixed events and GPEs raised since they were last taken.
+static PENDING_FIXED: AtomicU16 = AtomicU16::new(0);
+const NO_GPES: AtomicU64 = AtomicU64::new(0);
+static PENDING_GPES: [AtomicU64; MAX_GPES / 64] = [NO_GPES; MAX_GPES / 64];
+
+/// Serializes changes of the enable registers, between the SCI and `acpi:events`.
+static REGISTERS: Mutex<()> = Mutex::new(());
+
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+struct GenericAddress {
+    space: u8,
+    _bit_width: u8,
+    _bit_offset: u8,
+    _access_size: u8,
+    address: u64,
+}
+
+const SPACE_MEMORY: u8 = 0;
+const SPACE_IO: u8 = 1;
+
+/// A fixed hardware register block, in I/O space or linearly mapped memory.
+#[derive(Clone, Copy, Debug)]
+enum Register {
+    Io(u16),
+    Memory(usize),
+}
+
+impl Register {
+    unsafe fn read_u8(self, offset: usize) -> u8 {
+        match self {
+            Self::Io(port) => Pio::<u8>::new(port + offset as u16).read(),
+            Self::Memory(addr) => ptr::read_volatile((addr + offset) as *const u8),
+        }
+    }
+    unsafe fn write_u8(self, offset: usize, value: u8) {
+        match self {
+            Self::Io(port) => Pio::<u8>::new(port + offset as u16).write(value),
+            Self::Memory(addr) => ptr::write_volatile((addr + offset) as *mut u8, value),
+        }
+    }
+    unsafe fn read_u16(self, offset: usize) -> u16 {
+        match self {
+            Self::Io(port) => Pio::<u16>::new(port + offset as u16).read(),
+            Self::Memory(addr) => ptr::read_volatile((addr + offset) as *const u16),
+        }
+    }
+    unsafe fn write_u16(self, offset: usize, value: u16) {
+        match self {
+            Self::Io(port) => Pio::<u16>::new(port + offset as u16).write(value),
+            Self::Memory(addr) => ptr::write_volatile((addr + offset) as *mut u16, value),
+        }
+    }
+}
+
+/// A GPE block, with its status bytes followed by as many enable bytes.
+#[derive(Clone, Copy, Debug)]
+struct GpeBlock {
+    register: Register,
+    /// Number of status bytes.
+    len: usize,
+    /// Number of the first GPE.
+    base: usize,
+}
+
+impl GpeBlock {
+    fn contains(&self, gpe: usize) -> bool {
+        gpe >= self.base && gpe < self.base + self.len * 8
+    }
+}
+
+/// The Fixed ACPI Description Table
+#[derive(Debug)]
+pub struct Fadt {
+    pub sci_irq: u16,
+    smi_cmd: u32,
+    acpi_enable: u8,
+    pm1a_evt: Option<Register>,
+    pm1b_evt: Option<Register>,
+    /// Offset of the enable register in the PM1 event blocks.
+    pm1_enable_offset: usize,
+    pm1a_cnt: Option<Register>,
+    pm1b_cnt: Option<Register>,
+    gpe0: Option<GpeBlock>,
+    gpe1: Option<GpeBlock>,
+    /// Virtual address of the FACS.
+    facs: Option<usize>,
+    dsdt: Option<&'static Sdt>,
+    flags: u32,
+}
+
+/// Read the field at `offset` of the table, if the table is long enough.
+fn field<T: Copy>(sdt: &Sdt, offset: usize) -> Option<T> {
+    let data = sdt.data();
+    let offset = offset.checked_sub(mem::size_of::<Sdt>())?;
+    if offset + mem::size_of::<T>() > data.len() {
+        return None;
+    }
+    Some(unsafe { data.as_ptr().add(offset).cast::<T>().read_unaligned() })
+}
+
+/// The register block with the extended address at `extended`, or else the I/O port at `legacy`.
+fn register(sdt: &Sdt, legacy: usize, extended: usize, len: usize) -> Option<Register> {
+    if let Some(gas) = field::<GenericAddress>(sdt, extended) {
+        let address = gas.address;
+        match gas.space {
+            _ if address == 0 => (),
+            SPACE_IO => return Some(Register::Io(address as u16)),
+            SPACE_MEMORY => {
+                let physaddr = PhysicalAddress::new(address as usize);
+                unsafe {
+                    let mut mapper = KernelMapper::lock();
+                    let mapper = mapper
+                        .get_mut()
+                        .expect("KernelMapper locked re-entrant while mapping FADT registers");
+                    map_linearly(physaddr, len, mapper);
+                }
+                return Some(Register::Memory(RmmA::phys_to_virt(physaddr).data()));
+            }
+            space => {
+                log::warn!("FADT: register in unsupported address space {}", space);
+                return None;
+            }
+        }
+    }
+    match field::<u32>(sdt, legacy)? {
+        0 => None,
+        port => Some(Register::Io(port as u16)),
+    }
+}
+
+impl Fadt {
+    pub fn init() {
+        let fadt_sdt = find_sdt("FACP");
+        let fadt = if fadt_sdt.len() == 1 {
+            Fadt::new(fadt_sdt[0])
+        } else {
+            println!("Unable to find FADT");
+            return;
+        };
+        let Some(fadt) = fadt else {
+            return;
+        };
+        println!("  FADT: SCI {}", fadt.sci_irq);
+
+        unsafe {
+            fadt.enable_acpi_mode();
+            fadt.enable_fixed_events();
+        }
+        FADT.call_once(|| fadt);
+    }
+
+    pub fn new(sdt: &'static Sdt) -> Option<Fadt> {
+        if &sdt.signature != b"FACP" {
+            return None;
+        }
+        let pm1_evt_len = usize::from(field::<u8>(sdt, 88)?);
+        let gpe0_len = usize::from(field::<u8>(sdt, 92)?) / 2;
+        let gpe1_len = usize::from(field::<u8>(sdt, 93)?) / 2;
+
+        let gpe0 = register(sdt, 80, 220, gpe0_len * 2).map(|register| GpeBlock {
+            register,
+            len: gpe0_len,
+            base: 0,
+        });
+        let gpe1 = register(sdt, 84, 232, gpe1_len * 2).map(|register| GpeBlock {
+            register,
+            len: gpe1_len,
+            base: usize::from(field::<u8>(sdt, 94).unwrap_or(0)),
+        });
+
+        let facs = match field::<u64>(sdt, 132).filter(|&addr| addr != 0) {
+            Some(addr) => Some(addr as usize),
+            None => field::<u32>(sdt, 36)
+                .filter(|&addr| addr != 0)
+                .map(|addr| addr as usize),
+        };
+        let facs = facs.map(|addr| unsafe {
+            let mut mapper = KernelMapper::lock();
+            let mapper = mapper
+                .get_mut()
+                .expect("KernelMapper locked re-entrant while mapping FACS");
+            map_linearly(PhysicalAddress::new(addr), 64, mapper);
+            RmmA::phys_to_virt(PhysicalAddress::new(addr)).data()
+        });
+
+        let dsdt = match field::<u64>(sdt, 140).filter(|&addr| addr != 0) {
+            Some(addr) => Some(addr as usize),
+            None => field::<u32>(sdt, 40)
+                .filter(|&addr| addr != 0)
+                .map(|addr| addr as usize),
+        };
+        let dsdt = dsdt.map(|addr| get_sdt(addr, &mut KernelMapper::lock()));
+
+        Some(Fadt {
+            sci_irq: field(sdt, 46)?,
+            smi_cmd: field(sdt, 48)?,
+            acpi_enable: field(sdt, 52)?,
+            pm1a_evt: register(sdt, 56, 148, pm1_evt_len),
+            pm1b_evt: register(sdt, 60, 160, pm1_evt_len),
+            pm1_enable_offset: pm1_evt_len / 2,
+            pm1a_cnt: register(sdt, 64, 172, 2),
+            pm1b_cnt: register(sdt, 68, 184, 2),
+            gpe0: gpe0.filter(|block| block.len > 0),
+            gpe1: gpe1.filter(|block| block.len > 0),
+            facs,
+            dsdt,
+            flags: field(sdt, 112).unwrap_or(0),
+        })
+    }
+
+    /// The Differentiated System Description Table, which is not listed in the RSDT or XSDT.
+    pub fn dsdt(&self) -> Option<&'static Sdt> {
+        self.dsdt
+    }
+
+    fn pm1_events(&self) -> impl Iterator<Item = Register> {
+        self.pm1a_evt.into_iter().chain(self.pm1b_evt)
+    }
+    fn pm1_controls(&self) -> impl Iterator<Item = Register> {
+        self.pm1a_cnt.into_iter().chain(self.pm1b_cnt)
+    }
+    fn gpe_blocks(&self) -> impl Iterator<Item = GpeBlock> {
+        self.gpe0.into_iter().chain(self.gpe1)
+    }
+
+    /// Switch the firmware from legacy to ACPI mode, in which events raise the SCI.
+    unsafe fn enable_acpi_mode(&self) {
+        let Some(pm1a_cnt) = self.pm1a_cnt else {
+            return;
+        };
+        if pm1a_cnt.read_u16(0) & SCI_EN != 0 || self.smi_cmd == 0 || self.acpi_enable == 0 {
+            return;
+        }
+        Pio::<u8>::new(self.smi_cmd as u16).write(self.acpi_enable);
+        for _ in 0..SPINS {
+            if pm1a_cnt.read_u16(0) & SCI_EN != 0 {
+                return;
+            }
+            interrupt::pause();
+        }
+        log::warn!("FADT: firmware did not switch to ACPI mode");
+    }
+
+    /// Enable the fixed power and sleep buttons, unless they are control method devices.
+    unsafe fn enable_fixed_events(&self) {
+        let mut enable = 0;
+        if self.flags & FLAG_PWR_BUTTON == 0 {
+            enable |= PWRBTN;
+        }
+        if self.flags & FLAG_SLP_BUTTON == 0 {
+            enable |= SLPBTN;
+        }
+        for register in self.pm1_events() {
+            // Clear events raised before the kernel was listening.
+            register.write_u16(0, FIXED_MASK);
+            let value = register.read_u16(self.pm1_enable_offset);
+            register.write_u16(self.pm1_enable_offset, value | enable);
+        }
+    }
+
+    /// Clear and queue the enabled fixed events that are raised, returning whether there were any.
+    unsafe fn take_fixed_events(&self) -> bool {
+        let mut raised = 0;
+        for register in self.pm1_events() {
+            let status = register.read_u16(0);
+            let enable = register.read_u16(self.pm1_enable_offset);
+            let events = status & enable & FIXED_MASK;
+            if events != 0 {
+                // The status bits are cleared by writing ones.
+                register.write_u16(0, events);
+                raised |= events;
+            }
+        }
+        PENDING_FIXED.fetch_or(raised, Ordering::Relaxed);
+        raised != 0
+    }
+
+    /// Disable and queue the enabled GPEs that are raised, returning whether there were any.
+    unsafe fn take_gpes(&self) -> bool {
+        let mut raised = false;
+        for block in self.gpe_blocks() {
+            for index in 0..block.len {
+                let status = block.register.read_u8(index);
+                let enable = block.register.read_u8(block.len + index);
+                let gpes = status & enable;
+                if gpes == 0 {
+                    continue;
+                }
+                block.register.write_u8(block.len + index, enable & !gpes);
+                raised = true;
+
+                for bit in (0..8).filter(|bit| gpes & 1 << bit != 0) {
+                    let gpe = block.base + index * 8 + bit;
+                    if gpe < MAX_GPES {
+                        PENDING_GPES[gpe / 64].fetch_or(1 << (gpe % 64), Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+        raised
+    }
+
+    /// Clear the status of `gpe` and enable it, after userspace has run its method. Returns false
+    /// if there is no such GPE. Called from syscalls, with interrupts enabled.
+    pub fn enable_gpe(&self, gpe: usize) -> bool {
+        let Some(block) = self.gpe_blocks().find(|block| block.contains(gpe)) else {
+            return false;
+        };
+        let index = (gpe - block.base) / 8;
+        let bit = 1 << ((gpe - block.base) % 8);
+
+        // The SCI takes the same lock, so it must not be handled on this CPU while it is held.
+        unsafe { interrupt::disable() };
+        {
+            let _guard = REGISTERS.lock();
+            unsafe {
+                block.register.write_u8(index, bit);
+                let enable = block.register.read_u8(block.len + index);
+                block.register.write_u8(block.len + index, enable | bit);
+            }
+        }
+        unsafe { interrupt::enable() };
+        true
+    }
+
+    /// Whether the system can be put to sleep, which needs the PM1 control block and the FACS.
+    pub fn can_sleep(&self) -> bool {
+        self.pm1a_cnt.is_some() && self.facs.is_some()
+    }
+
+    /// Make the firmware resume at the real mode address `address` after sleeping.
+    pub unsafe fn set_waking_vector(&self, address: u32) {
+        let Some(facs) = self.facs else {
+            return;
+        };
+        ptr::write_volatile((facs + FACS_WAKING_VECTOR) as *mut u32, address);
+        // Would take precedence, and resume in protected or long mode.
+        let length = ptr::read_volatile((facs + 4) as *const u32);
+        if length as usize >= FACS_X_WAKING_VECTOR + 8 {
+            ptr::write_volatile((facs + FACS_X_WAKING_VECTOR) as *mut u64, 0);
+        }
+    }
+
+    /// Enter the sleep state with the sleep type values `slp_typa` and `slp_typb`, from the `\_Sx`
+    /// object of the state. Returns only if the system did not sleep, or woke without losing the
+    /// CPU state.
+    pub unsafe fn sleep(&self, slp_typa: u16, slp_typb: u16) {
+        for register in self.pm1_events() {
+            register.write_u16(0, WAK_STS);
+        }
+
+        let controls = [(self.pm1a_cnt, slp_typa), (self.pm1b_cnt, slp_typb)];
+        for (register, slp_typ) in controls {
+            if let Some(register) = register {
+                let value = register.read_u16(0) & !(SLP_TYP_MASK | SLP_EN);
+                register.write_u16(0, value | (slp_typ << SLP_TYP_SHIFT) & SLP_TYP_MASK);
+            }
+        }
+        for register in self.pm1_controls() {
+            let value = register.read_u16(0);
+            register.write_u16(0, value | SLP_EN);
+        }
+
+        for _ in 0..SPINS {
+            if self
+                .pm1_events()
+                .any(|register| register.read_u16(0) & WAK_STS != 0)
+            {
+                return;
+            }
+            interrupt::pause();
+        }
+    }
+}
+
+/// Handle the SCI, if it is raised on `irq`. Returns whether the kernel handled it, in which case
+/// it is not passed on to the IRQ scheme.
+pub fn sci(irq: u8) -> bool {
+    let Some(fadt) = FADT.get() else {
+        return false;
+    };
+    if u16::from(irq) != fadt.sci_irq {
+        return false;
+    }
+
+    let raised = {
+        let _guard = REGISTERS.lock();
+        unsafe { fadt.take_fixed_events() | fadt.take_gpes() }
+    };
+    if raised {
+        crate::scheme::acpi::events_notify();
+    }
+    raised
+}
+
+/// Whether any event is queued.
+pub fn events_pending() -> bool {
+    PENDING_FIXED.load(Ordering::Relaxed) != 0
+        || PENDING_GPES
+            .iter()
+            .any(|gpes| gpes.load(Ordering::Relaxed) != 0)
+}
+
+/// Take the queued fixed events, as PM1 status bits, and GPEs.
+pub fn take_events() -> (u16, [u64; MAX_GPES / 64]) {
+    let fixed = PENDING_FIXED.swap(0, Ordering::Relaxed);
+    let gpes = core::array::from_fn(|index| PENDING_GPES[index].swap(0, Ordering::Relaxed));
+    (fixed, gpes)
+}
+
+/// Queue events again that were taken but could not be delivered.
+pub fn requeue_events(fixed: u16, gpes: &[u64; MAX_GPES / 64]) {
+    PENDING_FIXED.fetch_or(fixed, Ordering::Relaxed);
+    for (pending, &bits) in PENDING_GPES.iter().zip(gpes) {
+        pending.fetch_or(bits, Ordering::Relaxed);
+    }
+}
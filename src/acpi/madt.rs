@@ -10,7 +10,8 @@ use super::{find_sdt, sdt::Sdt};
 use core::sync::atomic::{AtomicU8, Ordering};
 
 use crate::{
-    device::local_apic::LOCAL_APIC,
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    device::local_apic::{set_apic_id, LocalApic, LOCAL_APIC},
     interrupt,
     start::{kstart_ap, AP_READY, CPU_COUNT},
 };
@@ -23,7 +24,8 @@ pub struct Madt {
     pub flags: u32,
 }
 
-const TRAMPOLINE: usize = 0x8000;
+/// Where the trampoline runs in real mode, which is also the firmware waking vector.
+pub(crate) const TRAMPOLINE: usize = 0x8000;
 static TRAMPOLINE_DATA: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/trampoline"));
 
 pub static mut MADT: Option<Madt> = None;
@@ -46,7 +48,7 @@ impl Madt {
             println!("  APIC: {:>08X}: {}", madt.local_address, madt.flags);
 
             let local_apic = unsafe { &mut LOCAL_APIC };
-            let me = local_apic.id() as u8;
+            let me = local_apic.id();
 
             if local_apic.x2 {
                 println!("    X2APIC {}", me);
@@ -55,136 +57,43 @@ impl Madt {
             }
 
             if cfg!(feature = "multi_core") {
-                // Map trampoline
-                let trampoline_frame = Frame::containing_address(PhysicalAddress::new(TRAMPOLINE));
-                let trampoline_page = Page::containing_address(VirtualAddress::new(TRAMPOLINE));
-                let (result, page_table_physaddr) = unsafe {
-                    //TODO: do not have writable and executable!
-                    let mut mapper = KernelMapper::lock();
-
-                    let result = mapper
-                        .get_mut()
-                        .expect("expected kernel page table not to be recursively locked while initializing MADT")
-                        .map_phys(trampoline_page.start_address(), trampoline_frame.start_address(), PageFlags::new().execute(true).write(true))
-                        .expect("failed to map trampoline");
-
-                    (result, mapper.table().phys().data())
-                };
-                result.flush();
-
-                // Write trampoline, make sure TRAMPOLINE page is free for use
-                for i in 0..TRAMPOLINE_DATA.len() {
-                    unsafe {
-                        (*((TRAMPOLINE as *mut u8).add(i) as *const AtomicU8))
-                            .store(TRAMPOLINE_DATA[i], Ordering::SeqCst);
-                    }
-                }
+                let page_table_physaddr = map_trampoline();
 
                 for madt_entry in madt.iter() {
                     println!("      {:?}", madt_entry);
-                    match madt_entry {
+                    let (apic_id, flags) = match madt_entry {
                         MadtEntry::LocalApic(ap_local_apic) => {
-                            if ap_local_apic.id == me {
-                                println!("        This is my local APIC");
-                            } else {
-                                if ap_local_apic.flags & 1 == 1 {
-                                    // Increase CPU ID
-                                    CPU_COUNT.fetch_add(1, Ordering::SeqCst);
-
-                                    // Allocate a stack
-                                    let stack_start = allocate_p2frame(4)
-                                        .expect("no more frames in acpi stack_start")
-                                        .start_address()
-                                        .data()
-                                        + crate::PHYS_OFFSET;
-                                    let stack_end = stack_start + (PAGE_SIZE << 4);
-
-                                    let ap_ready = (TRAMPOLINE + 8) as *mut u64;
-                                    let ap_cpu_id = unsafe { ap_ready.add(1) };
-                                    let ap_page_table = unsafe { ap_ready.add(2) };
-                                    let ap_stack_start = unsafe { ap_ready.add(3) };
-                                    let ap_stack_end = unsafe { ap_ready.add(4) };
-                                    let ap_code = unsafe { ap_ready.add(5) };
-
-                                    // Set the ap_ready to 0, volatile
-                                    unsafe {
-                                        ap_ready.write(0);
-                                        ap_cpu_id.write(ap_local_apic.id.into());
-                                        ap_page_table.write(page_table_physaddr as u64);
-                                        ap_stack_start.write(stack_start as u64);
-                                        ap_stack_end.write(stack_end as u64);
-                                        ap_code.write(kstart_ap as u64);
-
-                                        // TODO: Is this necessary (this fence)?
-                                        core::arch::asm!("");
-                                    };
-                                    AP_READY.store(false, Ordering::SeqCst);
-
-                                    print!("        AP {}:", ap_local_apic.id);
-
-                                    // Send INIT IPI
-                                    {
-                                        let mut icr = 0x4500;
-                                        if local_apic.x2 {
-                                            icr |= (ap_local_apic.id as u64) << 32;
-                                        } else {
-                                            icr |= (ap_local_apic.id as u64) << 56;
-                                        }
-                                        print!(" IPI...");
-                                        local_apic.set_icr(icr);
-                                    }
-
-                                    // Send START IPI
-                                    {
-                                        //Start at 0x0800:0000 => 0x8000. Hopefully the bootloader code is still there
-                                        let ap_segment = (TRAMPOLINE >> 12) & 0xFF;
-                                        let mut icr = 0x4600 | ap_segment as u64;
-
-                                        if local_apic.x2 {
-                                            icr |= (ap_local_apic.id as u64) << 32;
-                                        } else {
-                                            icr |= (ap_local_apic.id as u64) << 56;
-                                        }
-
-                                        print!(" SIPI...");
-                                        local_apic.set_icr(icr);
-                                    }
-
-                                    // Wait for trampoline ready
-                                    print!(" Wait...");
-                                    while unsafe {
-                                        (*ap_ready.cast::<AtomicU8>()).load(Ordering::SeqCst)
-                                    } == 0
-                                    {
-                                        interrupt::pause();
-                                    }
-                                    print!(" Trampoline...");
-                                    while !AP_READY.load(Ordering::SeqCst) {
-                                        interrupt::pause();
-                                    }
-                                    println!(" Ready");
-
-                                    unsafe {
-                                        RmmA::invalidate_all();
-                                    }
-                                } else {
-                                    println!("        CPU Disabled");
-                                }
+                            (u32::from(ap_local_apic.id), ap_local_apic.flags)
+                        }
+                        MadtEntry::LocalX2Apic(ap_local_apic) => {
+                            // Firmware may describe a processor with both entry types.
+                            let apic_id = ap_local_apic.x2apic_id;
+                            let duplicate = madt.iter().any(|entry| match entry {
+                                MadtEntry::LocalApic(other) => u32::from(other.id) == apic_id,
+                                _ => false,
+                            });
+                            if duplicate {
+                                continue;
                             }
+                            (apic_id, ap_local_apic.flags)
                         }
-                        _ => (),
+                        _ => continue,
+                    };
+
+                    if apic_id == me {
+                        println!("        This is my local APIC");
+                    } else if flags & 1 != 1 {
+                        println!("        CPU Disabled");
+                    } else if apic_id >= 0xFF && !local_apic.x2 {
+                        println!("        APIC ID not addressable without x2APIC");
+                    } else if CPU_COUNT.load(Ordering::SeqCst) >= MAX_CPU_COUNT {
+                        println!("        Too many CPUs");
+                    } else {
+                        start_ap(local_apic, apic_id, page_table_physaddr);
                     }
                 }
 
-                // Unmap trampoline
-                let (_frame, _, flush) = unsafe {
-                    KernelMapper::lock()
-                        .get_mut()
-                        .expect("expected kernel page table not to be recursively locked while initializing MADT")
-                        .unmap_phys(trampoline_page.start_address(), true)
-                        .expect("failed to unmap trampoline page")
-                };
-                flush.flush();
+                unmap_trampoline();
             }
         }
     }
@@ -217,6 +126,171 @@ impl Madt {
     }
 }
 
+/// Identity map the trampoline and write it, returning the physical address of the kernel page
+/// table it switches to.
+pub(crate) fn map_trampoline() -> usize {
+    let trampoline_frame = Frame::containing_address(PhysicalAddress::new(TRAMPOLINE));
+    let trampoline_page = Page::containing_address(VirtualAddress::new(TRAMPOLINE));
+    let (result, page_table_physaddr) = unsafe {
+        //TODO: do not have writable and executable!
+        let mut mapper = KernelMapper::lock();
+
+        let result = mapper
+            .get_mut()
+            .expect(
+                "expected kernel page table not to be recursively locked while mapping trampoline",
+            )
+            .map_phys(
+                trampoline_page.start_address(),
+                trampoline_frame.start_address(),
+                PageFlags::new().execute(true).write(true),
+            )
+            .expect("failed to map trampoline");
+
+        (result, mapper.table().phys().data())
+    };
+    result.flush();
+
+    // Write trampoline, make sure TRAMPOLINE page is free for use
+    for i in 0..TRAMPOLINE_DATA.len() {
+        unsafe {
+            (*((TRAMPOLINE as *mut u8).add(i) as *const AtomicU8))
+                .store(TRAMPOLINE_DATA[i], Ordering::SeqCst);
+        }
+    }
+
+    page_table_physaddr
+}
+
+pub(crate) fn unmap_trampoline() {
+    let trampoline_page = Page::containing_address(VirtualAddress::new(TRAMPOLINE));
+    let (_frame, _, flush) = unsafe {
+        KernelMapper::lock()
+            .get_mut()
+            .expect("expected kernel page table not to be recursively locked while unmapping trampoline")
+            .unmap_phys(trampoline_page.start_address(), true)
+            .expect("failed to unmap trampoline page")
+    };
+    flush.flush();
+}
+
+/// Set the arguments the trampoline passes to `code`, which it jumps to in long mode with the
+/// stack ending at `stack_end`.
+pub(crate) fn write_trampoline_args(
+    cpu_id: LogicalCpuId,
+    page_table_physaddr: usize,
+    (stack_start, stack_end): (usize, usize),
+    code: usize,
+) {
+    let ap_ready = (TRAMPOLINE + 8) as *mut u64;
+    let ap_cpu_id = unsafe { ap_ready.add(1) };
+    let ap_page_table = unsafe { ap_ready.add(2) };
+    let ap_stack_start = unsafe { ap_ready.add(3) };
+    let ap_stack_end = unsafe { ap_ready.add(4) };
+    let ap_code = unsafe { ap_ready.add(5) };
+
+    // Set the ap_ready to 0, volatile
+    unsafe {
+        ap_ready.write(0);
+        ap_cpu_id.write(cpu_id.get().into());
+        ap_page_table.write(page_table_physaddr as u64);
+        ap_stack_start.write(stack_start as u64);
+        ap_stack_end.write(stack_end as u64);
+        ap_code.write(code as u64);
+
+        // TODO: Is this necessary (this fence)?
+        core::arch::asm!("");
+    };
+}
+
+/// Send the INIT and STARTUP IPIs making the AP with the local APIC `apic_id` run the trampoline.
+fn send_startup_ipis(local_apic: &mut LocalApic, apic_id: u32) {
+    // The destination is the top byte of the ICR in xAPIC mode, and the upper half in x2APIC mode
+    let destination = if local_apic.x2 {
+        u64::from(apic_id) << 32
+    } else {
+        u64::from(apic_id) << 56
+    };
+
+    // Send INIT IPI
+    local_apic.set_icr(0x4500 | destination);
+
+    // Send START IPI
+    {
+        //Start at 0x0800:0000 => 0x8000. Hopefully the bootloader code is still there
+        let ap_segment = (TRAMPOLINE >> 12) & 0xFF;
+        local_apic.set_icr(0x4600 | ap_segment as u64 | destination);
+    }
+}
+
+/// Wait until the AP started last has left the trampoline.
+fn wait_trampoline() {
+    let ap_ready = (TRAMPOLINE + 8) as *const AtomicU8;
+    while unsafe { (*ap_ready).load(Ordering::SeqCst) } == 0 {
+        interrupt::pause();
+    }
+}
+
+/// Run `code` on the AP with the local APIC `apic_id` as `cpu_id`, through the mapped trampoline,
+/// and wait until it left the trampoline.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn run_ap(
+    local_apic: &mut LocalApic,
+    apic_id: u32,
+    cpu_id: LogicalCpuId,
+    page_table_physaddr: usize,
+    stack: (usize, usize),
+    code: usize,
+) {
+    write_trampoline_args(cpu_id, page_table_physaddr, stack, code);
+    send_startup_ipis(local_apic, apic_id);
+    wait_trampoline();
+}
+
+/// Start the AP with the local APIC `apic_id` through the trampoline, as the next logical CPU.
+fn start_ap(local_apic: &mut LocalApic, apic_id: u32, page_table_physaddr: usize) {
+    let cpu_id = LogicalCpuId::new(CPU_COUNT.load(Ordering::SeqCst));
+    set_apic_id(cpu_id, apic_id);
+
+    // Increase CPU ID
+    CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+
+    // Allocate a stack
+    let stack_start = allocate_p2frame(4)
+        .expect("no more frames in acpi stack_start")
+        .start_address()
+        .data()
+        + crate::PHYS_OFFSET;
+    let stack_end = stack_start + (PAGE_SIZE << 4);
+
+    write_trampoline_args(
+        cpu_id,
+        page_table_physaddr,
+        (stack_start, stack_end),
+        kstart_ap as usize,
+    );
+    AP_READY.store(false, Ordering::SeqCst);
+
+    print!("        AP {} (CPU {}):", apic_id, cpu_id);
+
+    print!(" IPI...");
+    send_startup_ipis(local_apic, apic_id);
+
+    // Wait for trampoline ready
+    print!(" Wait...");
+    wait_trampoline();
+    print!(" Trampoline...");
+    crate::device::tsc::sync_bsp();
+    while !AP_READY.load(Ordering::SeqCst) {
+        interrupt::pause();
+    }
+    println!(" Ready");
+
+    unsafe {
+        RmmA::invalidate_all();
+    }
+}
+
 /// MADT Local APIC
 #[derive(Clone, Copy, Debug)]
 #[repr(packed)]
@@ -257,6 +331,20 @@ pub struct MadtIntSrcOverride {
     pub flags: u16,
 }
 
+/// MADT Local x2APIC, used for processors with APIC IDs that do not fit in 8 bits
+#[derive(Clone, Copy, Debug)]
+#[repr(packed)]
+pub struct MadtLocalX2Apic {
+    /// reserved
+    _reserved: u16,
+    /// Local x2APIC ID
+    pub x2apic_id: u32,
+    /// Flags. 1 means that the processor is enabled
+    pub flags: u32,
+    /// ACPI processor UID
+    pub processor_uid: u32,
+}
+
 /// MADT Entries
 #[derive(Debug)]
 pub enum MadtEntry {
@@ -266,6 +354,8 @@ pub enum MadtEntry {
     InvalidIoApic(usize),
     IntSrcOverride(&'static MadtIntSrcOverride),
     InvalidIntSrcOverride(usize),
+    LocalX2Apic(&'static MadtLocalX2Apic),
+    InvalidLocalX2Apic(usize),
     Unknown(u8),
 }
 
@@ -312,6 +402,15 @@ impl Iterator for MadtIter {
                             MadtEntry::InvalidIntSrcOverride(entry_len)
                         }
                     }
+                    9 => {
+                        if entry_len == mem::size_of::<MadtLocalX2Apic>() + 2 {
+                            MadtEntry::LocalX2Apic(unsafe {
+                                &*((self.sdt.data_address() + self.i + 2) as *const MadtLocalX2Apic)
+                            })
+                        } else {
+                            MadtEntry::InvalidLocalX2Apic(entry_len)
+                        }
+                    }
                     _ => MadtEntry::Unknown(entry_type),
                 };
 
@@ -11,13 +11,16 @@ use crate::{
     paging::{KernelMapper, PageFlags, PhysicalAddress, RmmA, RmmArch},
 };
 
-use self::{hpet::Hpet, madt::Madt, rsdp::RSDP, rsdt::Rsdt, rxsdt::Rxsdt, sdt::Sdt, xsdt::Xsdt};
+use self::{
+    fadt::Fadt, hpet::Hpet, madt::Madt, rsdp::RSDP, rsdt::Rsdt, rxsdt::Rxsdt, sdt::Sdt, xsdt::Xsdt,
+};
 
+pub mod fadt;
 pub mod hpet;
 pub mod madt;
 mod rsdp;
 mod rsdt;
-mod rxsdt;
+pub mod rxsdt;
 pub mod sdt;
 mod xsdt;
 
@@ -139,12 +142,15 @@ pub unsafe fn init(already_supplied_rsdp: Option<*const u8>) {
             }
         }
 
-        // TODO: Enumerate processors in userspace, and then provide an ACPI-independent interface
-        // to initialize enumerated processors to userspace?
-        Madt::init();
+        Fadt::init();
+
         // TODO: Let userspace setup HPET, and then provide an interface to specify which timer to
         // use?
         Hpet::init();
+        crate::device::hpet::calibrate();
+        // TODO: Enumerate processors in userspace, and then provide an ACPI-independent interface
+        // to initialize enumerated processors to userspace?
+        Madt::init();
     } else {
         println!("NO RSDP FOUND");
     }
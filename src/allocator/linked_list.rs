@@ -15,6 +15,10 @@ impl Allocator {
     pub unsafe fn init(offset: usize, size: usize) {
         *HEAP.lock() = Some(Heap::new(offset, size));
     }
+    /// Returns the mapped size and the allocated part of the heap, in bytes.
+    pub fn usage() -> Option<(usize, usize)> {
+        HEAP.lock().as_ref().map(|heap| (heap.size(), heap.used()))
+    }
 }
 
 unsafe impl GlobalAlloc for Allocator {
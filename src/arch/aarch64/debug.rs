@@ -1,7 +1,7 @@
-use core::fmt;
+use core::{fmt, marker::PhantomData};
 use spin::MutexGuard;
 
-use crate::log::{Log, LOG};
+use crate::log::{Level, Log, Sink, Tag, LOG};
 
 #[cfg(feature = "serial_debug")]
 use super::device::{serial::COM1, uart_pl011::SerialPort};
@@ -9,7 +9,14 @@ use super::device::{serial::COM1, uart_pl011::SerialPort};
 use crate::devices::graphical_debug::{DebugDisplay, DEBUG_DISPLAY};
 
 pub struct Writer<'a> {
-    log: MutexGuard<'a, Option<Log>>,
+    tag: Option<Tag>,
+    log: MutexGuard<'a, Log>,
+    consoles: Consoles<'a>,
+}
+
+struct Consoles<'a> {
+    /// Keeps the lifetime in use when no console is enabled.
+    _marker: PhantomData<&'a ()>,
     #[cfg(feature = "graphical_debug")]
     display: MutexGuard<'a, Option<DebugDisplay>>,
     #[cfg(feature = "serial_debug")]
@@ -18,8 +25,37 @@ pub struct Writer<'a> {
 
 impl<'a> Writer<'a> {
     pub fn new() -> Writer<'a> {
+        Self::with_tag(Tag::default())
+    }
+
+    pub fn with_tag(tag: Tag) -> Writer<'a> {
         Writer {
+            tag: Some(tag),
             log: LOG.lock(),
+            consoles: Consoles::lock(),
+        }
+    }
+
+    /// A writer passing output on without line prefixes, for console output from userspace.
+    pub fn raw() -> Writer<'a> {
+        Writer {
+            tag: None,
+            ..Self::new()
+        }
+    }
+
+    /// Write `buf` to the log, which passes it on to the consoles with each line prefixed.
+    pub fn write(&mut self, buf: &[u8]) {
+        let Writer { tag, log, consoles } = self;
+        let level = tag.map_or(Level::Info, |tag| tag.level);
+        log.write(buf, *tag, |buf| consoles.write(buf, level));
+    }
+}
+
+impl<'a> Consoles<'a> {
+    fn lock() -> Consoles<'a> {
+        Consoles {
+            _marker: PhantomData,
             #[cfg(feature = "graphical_debug")]
             display: DEBUG_DISPLAY.lock(),
             #[cfg(feature = "serial_debug")]
@@ -27,22 +63,17 @@ impl<'a> Writer<'a> {
         }
     }
 
-    pub fn write(&mut self, buf: &[u8]) {
-        {
-            if let Some(ref mut log) = *self.log {
-                log.write(buf);
-            }
-        }
-
+    /// Write `buf` to the consoles whose sink threshold allows `level`.
+    fn write(&mut self, buf: &[u8], level: Level) {
         #[cfg(feature = "graphical_debug")]
-        {
+        if Sink::Display.enabled(level) {
             if let Some(ref mut display) = *self.display {
                 let _ = display.write(buf);
             }
         }
 
         #[cfg(feature = "serial_debug")]
-        {
+        if Sink::Serial.enabled(level) {
             if let Some(ref mut serial) = *self.serial {
                 serial.write(buf);
             }
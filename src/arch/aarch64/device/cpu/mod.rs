@@ -1,6 +1,6 @@
 use core::fmt::{Result, Write};
 
-use crate::device::cpu::registers::control_regs;
+use crate::{device::cpu::registers::control_regs, percpu::CpuTopology};
 
 pub mod registers;
 
@@ -98,7 +98,7 @@ struct CpuInfo {
 impl CpuInfo {
     fn new() -> CpuInfo {
         let midr = unsafe { control_regs::midr() };
-        println!("MIDR: 0x{:x}", midr);
+        log::debug!("MIDR: 0x{:x}", midr);
         let midr = MachineId(midr);
 
         let implementer = match midr.get_implementer() {
@@ -166,6 +166,19 @@ impl CpuInfo {
     }
 }
 
+/// The effective frequency of a CPU. It is not measured, as that needs the activity monitors,
+/// which are optional and not supported yet.
+#[derive(Debug, Default)]
+pub struct Frequency;
+
+impl Frequency {
+    pub fn sample(&self) {}
+
+    pub fn khz(&self) -> Option<u64> {
+        None
+    }
+}
+
 pub fn cpu_info<W: Write>(w: &mut W) -> Result {
     let cpuinfo = CpuInfo::new();
 
@@ -178,3 +191,24 @@ pub fn cpu_info<W: Write>(w: &mut W) -> Result {
 
     Ok(())
 }
+
+/// Topology of the current CPU, from the affinity levels in MPIDR_EL1.
+pub fn topology() -> CpuTopology {
+    let mpidr = unsafe { control_regs::mpidr() };
+    let aff = |level: u32| ((mpidr >> [0, 8, 16, 32][level as usize]) & 0xFF) as u32;
+
+    // The MT bit tells whether the lowest affinity level is made of hardware threads
+    if mpidr & (1 << 24) != 0 {
+        CpuTopology {
+            package: aff(2),
+            core: aff(1),
+            thread: aff(0),
+        }
+    } else {
+        CpuTopology {
+            package: aff(1),
+            core: aff(0),
+            thread: 0,
+        }
+    }
+}
@@ -7,6 +7,7 @@ bitflags! {
         const DEVICE_MEMORY = 0x00 << 16;
         const NORMAL_UNCACHED_MEMORY = 0x44 << 8;
         const NORMAL_WRITEBACK_MEMORY = 0xff;
+        const NORMAL_TAGGED_MEMORY = 0xf0 << 24;
     }
 }
 
@@ -40,6 +41,18 @@ pub unsafe fn mair_el1_write(val: MairEl1) {
     asm!("msr mair_el1, {}", in(reg) val.bits());
 }
 
+pub unsafe fn tcr_el1() -> u64 {
+    let ret: u64;
+    asm!("mrs {}, tcr_el1", out(reg) ret);
+    ret
+}
+
+pub unsafe fn sctlr_el1() -> u64 {
+    let ret: u64;
+    asm!("mrs {}, sctlr_el1", out(reg) ret);
+    ret
+}
+
 pub unsafe fn tpidr_el0() -> u64 {
     let ret: u64;
     asm!("mrs {}, tpidr_el0", out(reg) ret);
@@ -82,6 +95,12 @@ pub unsafe fn cntfreq_el0() -> u32 {
     ret
 }
 
+pub unsafe fn cntpct_el0() -> u64 {
+    let ret: u64;
+    asm!("mrs {}, cntpct_el0", out(reg) ret);
+    ret
+}
+
 pub unsafe fn tmr_ctrl() -> u32 {
     let ret: u32;
     asm!("mrs {}, cntp_ctl_el0", out(reg) ret);
@@ -107,3 +126,9 @@ pub unsafe fn midr() -> u32 {
     asm!("mrs {}, midr_el1", out(reg) ret);
     ret
 }
+
+pub unsafe fn mpidr() -> u64 {
+    let ret: u64;
+    asm!("mrs {}, mpidr_el1", out(reg) ret);
+    ret
+}
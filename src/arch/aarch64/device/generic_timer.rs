@@ -2,12 +2,13 @@ use crate::log::{debug, info};
 use alloc::boxed::Box;
 
 use crate::{
-    arch::device::irqchip::IRQ_CHIP, context, context::timeout,
+    arch::device::irqchip::IRQ_CHIP, context, context::timeout, cpu_set::LogicalCpuId,
     device::cpu::registers::control_regs, dtb::DTB_BINARY, init::device_tree::find_compatible_node,
-    interrupt::irq::trigger, time,
+    interrupt::irq::trigger, percpu::PercpuBlock, time,
 };
 use alloc::vec::Vec;
 use byteorder::{ByteOrder, BE};
+use spin::Once;
 
 use super::irqchip::{register_irq, InterruptHandler};
 
@@ -19,12 +20,11 @@ bitflags! {
     }
 }
 
+/// The timer interrupt, a PPI which every CPU enables for its own timer.
+static TIMER_IRQ: Once<u32> = Once::new();
+
 pub unsafe fn init() {
-    let mut timer = GenericTimer {
-        clk_freq: 0,
-        reload_count: 0,
-    };
-    timer.init();
+    GenericTimer::init();
     let data = DTB_BINARY.get().unwrap();
     let fdt = fdt::DeviceTree::new(data).unwrap();
     if let Some(node) = find_compatible_node(&fdt, "arm,armv7-timer") {
@@ -59,23 +59,48 @@ pub unsafe fn init() {
             .irq_xlate(&intr_data, 1)
             .unwrap();
         info!("generic_timer virq = {}", virq);
-        register_irq(virq as u32, Box::new(timer));
+        register_irq(virq as u32, Box::new(GenericTimer));
         IRQ_CHIP.irq_enable(virq as u32);
+        TIMER_IRQ.call_once(|| virq as u32);
     }
 }
 
-pub struct GenericTimer {
-    pub clk_freq: u32,
-    pub reload_count: u32,
+/// Start the timer of an AP, which shares the handler registered by the BSP.
+pub unsafe fn init_ap() {
+    let Some(&virq) = TIMER_IRQ.get() else {
+        return;
+    };
+    GenericTimer::init();
+    IRQ_CHIP.irq_enable(virq);
+}
+
+/// Stop the timer of the current CPU, while it is idle.
+pub unsafe fn stop() {
+    GenericTimer::disable();
+}
+
+/// Restart the timer of the current CPU, stopped by [`stop`].
+pub unsafe fn restart() {
+    GenericTimer::init();
 }
 
+/// The handler of the timer interrupt, shared by all CPUs. It keeps no state of its own, as all of
+/// it is in the registers of the timer of each CPU.
+pub struct GenericTimer;
+
 impl GenericTimer {
-    pub fn init(&mut self) {
-        let clk_freq = unsafe { control_regs::cntfreq_el0() };
-        self.clk_freq = clk_freq;
-        self.reload_count = clk_freq / 100;
+    /// The frequency of the counter of the current CPU, in Hz.
+    fn clk_freq() -> u32 {
+        unsafe { control_regs::cntfreq_el0() }
+    }
+
+    /// The count the timer is reloaded with, for a tick of 10 ms.
+    fn reload_count() -> u32 {
+        Self::clk_freq() / 100
+    }
 
-        unsafe { control_regs::tmr_tval_write(self.reload_count) };
+    pub fn init() {
+        unsafe { control_regs::tmr_tval_write(Self::reload_count()) };
 
         let mut ctrl = TimerCtrlFlags::from_bits_truncate(unsafe { control_regs::tmr_ctrl() });
         ctrl.insert(TimerCtrlFlags::ENABLE);
@@ -91,13 +116,13 @@ impl GenericTimer {
         unsafe { control_regs::tmr_ctrl_write(ctrl.bits()) };
     }
 
-    pub fn set_irq(&mut self) {
+    pub fn set_irq() {
         let mut ctrl = TimerCtrlFlags::from_bits_truncate(unsafe { control_regs::tmr_ctrl() });
         ctrl.remove(TimerCtrlFlags::IMASK);
         unsafe { control_regs::tmr_ctrl_write(ctrl.bits()) };
     }
 
-    pub fn clear_irq(&mut self) {
+    pub fn clear_irq() {
         let mut ctrl = TimerCtrlFlags::from_bits_truncate(unsafe { control_regs::tmr_ctrl() });
 
         if ctrl.contains(TimerCtrlFlags::ISTATUS) {
@@ -106,29 +131,32 @@ impl GenericTimer {
         }
     }
 
-    pub fn reload_count(&mut self) {
+    pub fn reload() {
         let mut ctrl = TimerCtrlFlags::from_bits_truncate(unsafe { control_regs::tmr_ctrl() });
         ctrl.insert(TimerCtrlFlags::ENABLE);
         ctrl.remove(TimerCtrlFlags::IMASK);
-        unsafe { control_regs::tmr_tval_write(self.reload_count) };
+        unsafe { control_regs::tmr_tval_write(Self::reload_count()) };
         unsafe { control_regs::tmr_ctrl_write(ctrl.bits()) };
     }
 }
 
 impl InterruptHandler for GenericTimer {
     fn irq_handler(&mut self, irq: u32) {
-        self.clear_irq();
-        {
-            *time::OFFSET.lock() += self.clk_freq as u128;
-        }
+        Self::clear_irq();
+        // Every CPU has its own timer, but only the BSP advances the clock.
+        if PercpuBlock::current().cpu_id == LogicalCpuId::BSP {
+            {
+                *time::OFFSET.lock() += Self::clk_freq() as u128;
+            }
 
-        timeout::trigger();
+            timeout::trigger();
+        }
 
         context::switch::tick();
 
         unsafe {
             trigger(irq);
         }
-        self.reload_count();
+        Self::reload();
     }
 }
@@ -1,11 +1,16 @@
-use core::ptr::{read_volatile, write_volatile};
+use core::{
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{AtomicU8, Ordering},
+};
 
 use byteorder::{ByteOrder, BE};
 use fdt::{DeviceTree, Node};
 
 use crate::{
+    cpu_set::MAX_CPU_COUNT,
     init::device_tree::find_compatible_node,
     log::{debug, info},
+    percpu::PercpuBlock,
 };
 use syscall::{
     error::{Error, EINVAL},
@@ -27,6 +32,19 @@ static GICC_IAR: u32 = 0x000c;
 static GICC_CTLR: u32 = 0x0000;
 static GICC_PMR: u32 = 0x0004;
 
+const NO_CPU_INTERFACE: AtomicU8 = AtomicU8::new(0);
+/// The `GICD_ITARGETSR` bit of each logical CPU's interface, once it was enabled.
+static CPU_INTERFACES: [AtomicU8; MAX_CPU_COUNT as usize] =
+    [NO_CPU_INTERFACE; MAX_CPU_COUNT as usize];
+
+/// Record the CPU interface of the current CPU, which need not match its logical ID.
+fn record_cpu_interface(dist_if: &GicDistIf) {
+    let cpu = PercpuBlock::current().cpu_id.get() as usize;
+    if let Some(interface) = CPU_INTERFACES.get(cpu) {
+        interface.store(unsafe { dist_if.cpu_if_mask() }, Ordering::SeqCst);
+    }
+}
+
 pub struct GenericInterruptController {
     gic_dist_if: GicDistIf,
     gic_cpu_if: GicCpuIf,
@@ -101,10 +119,9 @@ impl InterruptController for GenericInterruptController {
             self.gic_dist_if.init(crate::PHYS_OFFSET + dist_addr);
 
             // Enable CPU0's GIC interface
-            self.gic_cpu_if.write(GICC_CTLR, 1);
-            // Set CPU0's Interrupt Priority Mask
-            self.gic_cpu_if.write(GICC_PMR, 0xff);
+            self.gic_cpu_if.enable();
         }
+        record_cpu_interface(&self.gic_dist_if);
         let idx = *irq_idx;
         let cnt = if self.gic_dist_if.nirqs > 1024 {
             1024
@@ -164,6 +181,27 @@ impl InterruptController for GenericInterruptController {
     }
 
     fn irq_handler(&mut self, _irq: u32) {}
+
+    fn irq_init_ap(&mut self) {
+        // The CPU interface is banked, so every CPU enables its own at the same address.
+        unsafe { self.gic_cpu_if.enable() }
+        record_cpu_interface(&self.gic_dist_if);
+    }
+
+    fn irq_set_affinity(&mut self, irq_num: u32, cpu_id: u32) -> Result<()> {
+        // Only SPIs can be routed, and only to CPUs whose interface was enabled.
+        if irq_num < 32 || irq_num >= self.gic_dist_if.nirqs {
+            return Err(Error::new(EINVAL));
+        }
+        let mask = CPU_INTERFACES
+            .get(cpu_id as usize)
+            .map_or(0, |interface| interface.load(Ordering::SeqCst));
+        if mask == 0 {
+            return Err(Error::new(EINVAL));
+        }
+        unsafe { self.gic_dist_if.irq_set_target(irq_num, mask) };
+        Ok(())
+    }
 }
 
 pub struct GicDistIf {
@@ -197,13 +235,14 @@ impl GicDistIf {
             self.write(GICD_ICENABLER + ((irq / 32) * 4), 0xffff_ffff);
         }
 
-        // Affine all SPIs to CPU0 and set priorities for all IRQs
+        // Affine all SPIs to the boot CPU and set priorities for all IRQs
+        let boot_mask = u32::from(self.cpu_if_mask());
         for irq in 0..self.nirqs {
             if irq > 31 {
                 let ext_offset = GICD_ITARGETSR + (4 * (irq / 4));
                 let int_offset = irq % 4;
                 let mut val = self.read(ext_offset);
-                val |= 0b0000_0001 << (8 * int_offset);
+                val |= boot_mask << (8 * int_offset);
                 self.write(ext_offset, val);
             }
 
@@ -234,6 +273,25 @@ impl GicDistIf {
         self.write(offset, val);
     }
 
+    unsafe fn irq_set_target(&mut self, irq: u32, mask: u8) {
+        let ext_offset = GICD_ITARGETSR + (4 * (irq / 4));
+        let int_offset = irq % 4;
+        let mut val = self.read(ext_offset);
+        val &= !(0xff << (8 * int_offset));
+        val |= u32::from(mask) << (8 * int_offset);
+        self.write(ext_offset, val);
+    }
+
+    /// The target mask of the current CPU's interface, as the banked `GICD_ITARGETSR0`
+    /// reads back that mask in each of its bytes. It reads as zero on uniprocessor
+    /// implementations, where the targets are ignored.
+    unsafe fn cpu_if_mask(&self) -> u8 {
+        match self.read(GICD_ITARGETSR) as u8 {
+            0 => 1,
+            mask => mask,
+        }
+    }
+
     unsafe fn read(&self, reg: u32) -> u32 {
         let val = read_volatile((self.address + reg as usize) as *const u32);
         val
@@ -253,6 +311,13 @@ impl GicCpuIf {
         self.address = addr;
     }
 
+    unsafe fn enable(&mut self) {
+        // Enable the GIC interface of the current CPU
+        self.write(GICC_CTLR, 1);
+        // Set the Interrupt Priority Mask of the current CPU
+        self.write(GICC_PMR, 0xff);
+    }
+
     unsafe fn irq_ack(&mut self) -> u32 {
         let irq = self.read(GICC_IAR) & 0x1ff;
         if irq == 1023 {
@@ -0,0 +1,453 @@
+//! GICv3 and GICv4, using the system register CPU interface.
+//!
+//! Unlike the GICv2, each CPU has its own redistributor, which holds the configuration of its SGIs
+//! and PPIs, and has to be woken up before the CPU can receive interrupts. SPIs are routed by the
+//! affinity of the target CPU, and SGIs are sent to CPUs by affinity through `ICC_SGI1R_EL1`.
+//!
+//! LPIs, and with them MSIs, are not supported, since the redistributors' LPI tables are left
+//! disabled and there is no ITS driver.
+
+use core::{
+    arch::asm,
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, BE};
+use fdt::{DeviceTree, Node};
+use syscall::{
+    error::{Error, EINVAL, ENODEV},
+    Result,
+};
+
+use super::{InterruptController, IrqDesc};
+use crate::{
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    device::cpu::registers::control_regs,
+    init::device_tree::find_compatible_node,
+    log::{debug, info},
+    percpu::PercpuBlock,
+};
+
+const GICD_CTLR: usize = 0x0000;
+const GICD_TYPER: usize = 0x0004;
+const GICD_IGROUPR: usize = 0x0080;
+const GICD_ISENABLER: usize = 0x0100;
+const GICD_ICENABLER: usize = 0x0180;
+const GICD_IPRIORITYR: usize = 0x0400;
+const GICD_ICFGR: usize = 0x0c00;
+const GICD_IROUTER: usize = 0x6000;
+
+const GICD_CTLR_ENABLE_G1: u32 = 1 << 0;
+const GICD_CTLR_ENABLE_G1A: u32 = 1 << 1;
+const GICD_CTLR_ARE_NS: u32 = 1 << 4;
+const GICD_CTLR_RWP: u32 = 1 << 31;
+
+// Registers in the RD_base frame of a redistributor
+const GICR_CTLR: usize = 0x0000;
+const GICR_TYPER: usize = 0x0008;
+const GICR_WAKER: usize = 0x0014;
+
+const GICR_CTLR_RWP: u32 = 1 << 3;
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+// Registers in the SGI_base frame of a redistributor, which follows the RD_base frame
+const GICR_SGI_BASE: usize = 0x1_0000;
+const GICR_IGROUPR0: usize = GICR_SGI_BASE + 0x0080;
+const GICR_ISENABLER0: usize = GICR_SGI_BASE + 0x0100;
+const GICR_ICENABLER0: usize = GICR_SGI_BASE + 0x0180;
+const GICR_IPRIORITYR: usize = GICR_SGI_BASE + 0x0400;
+
+/// Size of the RD_base and SGI_base frames of a GICv3 redistributor.
+const GICR_FRAMES_SIZE: usize = 0x2_0000;
+/// Size of the additional VLPI frames of a GICv4 redistributor.
+const GICR_VLPI_FRAMES_SIZE: usize = 0x2_0000;
+
+const ICC_SRE_EL1_SRE: u64 = 1 << 0;
+
+/// Priority of all interrupts, which is above the priority mask.
+const DEFAULT_PRIORITY: u8 = 0xa0;
+/// The highest INTID of the SPIs, as 1020 to 1023 are special.
+const MAX_SPI: u32 = 1019;
+const SPURIOUS: u32 = 1023;
+
+const NO_REDISTRIBUTOR: AtomicUsize = AtomicUsize::new(0);
+/// The redistributor of each logical CPU, once it was initialized.
+static REDISTRIBUTORS: [AtomicUsize; MAX_CPU_COUNT as usize] =
+    [NO_REDISTRIBUTOR; MAX_CPU_COUNT as usize];
+const NO_AFFINITY: AtomicU64 = AtomicU64::new(0);
+/// The affinity of each logical CPU, used to route SPIs and SGIs to it.
+static AFFINITIES: [AtomicU64; MAX_CPU_COUNT as usize] = [NO_AFFINITY; MAX_CPU_COUNT as usize];
+
+/// The affinity fields of the current CPU, in the layout of `MPIDR_EL1` and `GICD_IROUTER`.
+fn current_affinity() -> u64 {
+    unsafe { control_regs::mpidr() & 0xFF_00FF_FFFF }
+}
+
+fn current_cpu() -> usize {
+    PercpuBlock::current().cpu_id.get() as usize
+}
+
+pub struct GicV3 {
+    dist_addr: usize,
+    /// Base and size of each redistributor region.
+    redist_regions: Vec<(usize, usize)>,
+    /// Distance between redistributors, if given by the device tree.
+    redist_stride: Option<usize>,
+    nirqs: u32,
+    irq_range: (usize, usize),
+}
+
+impl GicV3 {
+    pub fn new() -> Self {
+        GicV3 {
+            dist_addr: 0,
+            redist_regions: Vec::new(),
+            redist_stride: None,
+            nirqs: 0,
+            irq_range: (0, 0),
+        }
+    }
+
+    fn parse(&mut self, fdt: &DeviceTree) -> Result<()> {
+        let node = find_compatible_node(fdt, "arm,gic-v3").ok_or(Error::new(EINVAL))?;
+        self.parse_inner(&node)
+    }
+
+    fn parse_inner(&mut self, node: &Node) -> Result<()> {
+        //assert address_cells == 0x2, size_cells == 0x2
+        let reg = node
+            .properties()
+            .find(|p| p.name == "reg")
+            .ok_or(Error::new(EINVAL))?;
+        let region_count = node
+            .properties()
+            .find(|p| p.name == "#redistributor-regions")
+            .map_or(1, |p| BE::read_u32(p.data) as usize);
+        self.redist_stride = node
+            .properties()
+            .find(|p| p.name == "redistributor-stride")
+            .map(|p| BE::read_u64(p.data) as usize)
+            .filter(|&stride| stride != 0);
+
+        let mut regs = reg.data.chunks_exact(16).map(|chunk| {
+            (
+                BE::read_u64(&chunk[..8]) as usize,
+                BE::read_u64(&chunk[8..]) as usize,
+            )
+        });
+        let (dist_addr, _) = regs.next().ok_or(Error::new(EINVAL))?;
+        self.dist_addr = crate::PHYS_OFFSET + dist_addr;
+        for _ in 0..region_count {
+            let (base, size) = regs.next().ok_or(Error::new(EINVAL))?;
+            debug!("gicv3: redistributor region {:08x}:{:08x}", base, size);
+            self.redist_regions.push((crate::PHYS_OFFSET + base, size));
+        }
+        Ok(())
+    }
+
+    unsafe fn dist_read(&self, reg: usize) -> u32 {
+        read_volatile((self.dist_addr + reg) as *const u32)
+    }
+
+    unsafe fn dist_write(&self, reg: usize, value: u32) {
+        write_volatile((self.dist_addr + reg) as *mut u32, value);
+    }
+
+    unsafe fn dist_wait_rwp(&self) {
+        while self.dist_read(GICD_CTLR) & GICD_CTLR_RWP != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    unsafe fn init_dist(&mut self) {
+        // Disable IRQ Distribution
+        self.dist_write(GICD_CTLR, 0);
+        self.dist_wait_rwp();
+
+        let typer = self.dist_read(GICD_TYPER);
+        self.nirqs = (((typer & 0x1f) + 1) * 32).min(MAX_SPI + 1);
+        info!("gicv3: Distributor supports {} IRQs", self.nirqs);
+
+        let affinity = current_affinity();
+        for irq in (32..self.nirqs).step_by(32) {
+            // Put all SPIs in non-secure group 1, and disable them
+            self.dist_write(GICD_IGROUPR + (irq as usize / 32) * 4, 0xffff_ffff);
+            self.dist_write(GICD_ICENABLER + (irq as usize / 32) * 4, 0xffff_ffff);
+        }
+        for irq in (32..self.nirqs).step_by(16) {
+            // Set all SPIs to level triggered
+            self.dist_write(GICD_ICFGR + (irq as usize / 16) * 4, 0);
+        }
+        for irq in 32..self.nirqs {
+            write_volatile(
+                (self.dist_addr + GICD_IPRIORITYR + irq as usize) as *mut u8,
+                DEFAULT_PRIORITY,
+            );
+            // Route all SPIs to the BSP
+            write_volatile(
+                (self.dist_addr + GICD_IROUTER + irq as usize * 8) as *mut u64,
+                affinity,
+            );
+        }
+        self.dist_wait_rwp();
+
+        // Enable IRQ distribution with affinity routing
+        self.dist_write(
+            GICD_CTLR,
+            GICD_CTLR_ARE_NS | GICD_CTLR_ENABLE_G1A | GICD_CTLR_ENABLE_G1,
+        );
+        self.dist_wait_rwp();
+    }
+
+    /// Find the redistributor of the current CPU, by its affinity.
+    unsafe fn find_redist(&self) -> Option<usize> {
+        let affinity = current_affinity();
+        // GICR_TYPER packs Aff3 next to Aff2, unlike MPIDR_EL1.
+        let packed = ((affinity >> 8) & 0xFF00_0000) | (affinity & 0xFF_FFFF);
+
+        for &(base, size) in self.redist_regions.iter() {
+            let mut addr = base;
+            while addr < base + size {
+                let typer = read_volatile((addr + GICR_TYPER) as *const u64);
+                if typer >> 32 == packed {
+                    return Some(addr);
+                }
+                if typer & GICR_TYPER_LAST != 0 {
+                    break;
+                }
+                addr += self
+                    .redist_stride
+                    .unwrap_or(if typer & GICR_TYPER_VLPIS != 0 {
+                        GICR_FRAMES_SIZE + GICR_VLPI_FRAMES_SIZE
+                    } else {
+                        GICR_FRAMES_SIZE
+                    });
+            }
+        }
+        None
+    }
+
+    /// Initialize the redistributor and the CPU interface of the current CPU.
+    unsafe fn init_cpu(&self) -> Result<()> {
+        let cpu = current_cpu();
+        let Some(redist) = self.find_redist() else {
+            info!(
+                "gicv3: no redistributor for CPU {} (affinity {:#x})",
+                cpu,
+                current_affinity()
+            );
+            return Err(Error::new(ENODEV));
+        };
+        let read = |reg: usize| read_volatile((redist + reg) as *const u32);
+        let write = |reg: usize, value: u32| write_volatile((redist + reg) as *mut u32, value);
+        let wait_rwp = || {
+            while read(GICR_CTLR) & GICR_CTLR_RWP != 0 {
+                core::hint::spin_loop();
+            }
+        };
+
+        // Wake up the redistributor
+        write(GICR_WAKER, read(GICR_WAKER) & !GICR_WAKER_PROCESSOR_SLEEP);
+        while read(GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+            core::hint::spin_loop();
+        }
+
+        // Put SGIs and PPIs in non-secure group 1, and enable only the SGIs used for IPIs
+        write(GICR_IGROUPR0, 0xffff_ffff);
+        write(GICR_ICENABLER0, 0xffff_0000);
+        write(GICR_ISENABLER0, 0x0000_ffff);
+        for irq in 0..32 {
+            write_volatile(
+                (redist + GICR_IPRIORITYR + irq) as *mut u8,
+                DEFAULT_PRIORITY,
+            );
+        }
+        wait_rwp();
+
+        // Enable the system register interface, before using any other ICC_* register
+        let mut sre: u64;
+        asm!("mrs {}, icc_sre_el1", out(reg) sre);
+        sre |= ICC_SRE_EL1_SRE;
+        asm!("msr icc_sre_el1, {}", "isb", in(reg) sre);
+
+        // Unmask all priorities, use a single priority group, and drop priority and deactivate
+        // together on EOI
+        asm!("msr icc_pmr_el1, {}", in(reg) 0xff_u64);
+        asm!("msr icc_bpr1_el1, {}", in(reg) 0_u64);
+        asm!("msr icc_ctlr_el1, {}", in(reg) 0_u64);
+        // Enable group 1 interrupts
+        asm!("msr icc_igrpen1_el1, {}", "isb", in(reg) 1_u64);
+
+        AFFINITIES[cpu].store(current_affinity(), Ordering::SeqCst);
+        REDISTRIBUTORS[cpu].store(redist, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Enable or disable an SGI or PPI of the current CPU in its redistributor.
+    unsafe fn redist_enable(&self, irq: u32, enable: bool) {
+        let redist = REDISTRIBUTORS[current_cpu()].load(Ordering::SeqCst);
+        if redist == 0 {
+            return;
+        }
+        let reg = if enable {
+            GICR_ISENABLER0
+        } else {
+            GICR_ICENABLER0
+        };
+        write_volatile((redist + reg) as *mut u32, 1 << irq);
+    }
+}
+
+impl InterruptController for GicV3 {
+    fn irq_init(
+        &mut self,
+        fdt: &DeviceTree,
+        irq_desc: &mut [IrqDesc; 1024],
+        ic_idx: usize,
+        irq_idx: &mut usize,
+    ) -> Result<Option<usize>> {
+        self.parse(fdt)?;
+
+        unsafe {
+            self.init_dist();
+            self.init_cpu()?;
+        }
+
+        let idx = *irq_idx;
+        let cnt = self.nirqs as usize;
+        let mut i: usize = 0;
+        //only support linear irq map now.
+        while i < cnt && (idx + i < 1024) {
+            irq_desc[idx + i].basic.ic_idx = ic_idx;
+            irq_desc[idx + i].basic.ic_irq = i as u32;
+            irq_desc[idx + i].basic.used = true;
+
+            i += 1;
+        }
+
+        info!("gicv3 irq_range = ({}, {})", idx, idx + cnt);
+        self.irq_range = (idx, idx + cnt);
+        *irq_idx = idx + cnt;
+        Ok(None)
+    }
+    fn irq_ack(&mut self) -> u32 {
+        let irq: u64;
+        unsafe { asm!("mrs {}, icc_iar1_el1", out(reg) irq) };
+        irq as u32 & 0xff_ffff
+    }
+    fn irq_eoi(&mut self, irq_num: u32) {
+        if irq_num == SPURIOUS {
+            return;
+        }
+        unsafe { asm!("msr icc_eoir1_el1, {}", in(reg) u64::from(irq_num)) };
+    }
+    fn irq_enable(&mut self, irq_num: u32) {
+        unsafe {
+            if irq_num < 32 {
+                self.redist_enable(irq_num, true);
+            } else {
+                self.dist_write(
+                    GICD_ISENABLER + (irq_num as usize / 32) * 4,
+                    1 << (irq_num % 32),
+                );
+            }
+        }
+    }
+    fn irq_disable(&mut self, irq_num: u32) {
+        unsafe {
+            if irq_num < 32 {
+                self.redist_enable(irq_num, false);
+            } else {
+                self.dist_write(
+                    GICD_ICENABLER + (irq_num as usize / 32) * 4,
+                    1 << (irq_num % 32),
+                );
+            }
+        }
+    }
+    fn irq_xlate(&mut self, irq_data: &[u32], idx: usize) -> Result<usize> {
+        let mut off: usize = 0;
+        let mut i = 0;
+        for chunk in irq_data.chunks(3) {
+            if i == idx {
+                match chunk[0] {
+                    0 => off = chunk[1] as usize + 32, //SPI
+                    1 => off = chunk[1] as usize + 16, //PPI,
+                    _ => return Err(Error::new(EINVAL)),
+                }
+                off += self.irq_range.0;
+                return Ok(off);
+            }
+            i += 1;
+        }
+        Err(Error::new(EINVAL))
+    }
+    fn irq_to_virq(&mut self, hwirq: u32) -> Option<usize> {
+        if hwirq >= self.nirqs {
+            None
+        } else {
+            Some(self.irq_range.0 + hwirq as usize)
+        }
+    }
+
+    fn irq_handler(&mut self, _irq: u32) {}
+
+    fn irq_set_affinity(&mut self, irq_num: u32, cpu_id: u32) -> Result<()> {
+        // Only SPIs can be routed, to any CPU whose redistributor was initialized.
+        if irq_num < 32 || irq_num >= self.nirqs || cpu_id >= MAX_CPU_COUNT {
+            return Err(Error::new(EINVAL));
+        }
+        if REDISTRIBUTORS[cpu_id as usize].load(Ordering::SeqCst) == 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let affinity = AFFINITIES[cpu_id as usize].load(Ordering::SeqCst);
+        unsafe {
+            write_volatile(
+                (self.dist_addr + GICD_IROUTER + irq_num as usize * 8) as *mut u64,
+                affinity,
+            );
+        }
+        Ok(())
+    }
+
+    fn irq_init_ap(&mut self) {
+        let _ = unsafe { self.init_cpu() };
+    }
+
+    fn can_send_sgi(&self) -> bool {
+        true
+    }
+
+    fn send_sgi(&mut self, sgi: u32, target: Option<LogicalCpuId>) {
+        let intid = u64::from(sgi & 0xf) << 24;
+        let value = match target {
+            Some(cpu_id) => {
+                let cpu = cpu_id.get() as usize;
+                if cpu >= MAX_CPU_COUNT as usize || REDISTRIBUTORS[cpu].load(Ordering::SeqCst) == 0
+                {
+                    return;
+                }
+                let affinity = AFFINITIES[cpu].load(Ordering::SeqCst);
+                let aff0 = affinity & 0xff;
+                let aff1 = (affinity >> 8) & 0xff;
+                let aff2 = (affinity >> 16) & 0xff;
+                let aff3 = (affinity >> 32) & 0xff;
+                // The target list holds 16 CPUs of Aff0, selected by the range selector.
+                (aff3 << 48)
+                    | ((aff0 / 16) << 44)
+                    | (aff2 << 32)
+                    | intid
+                    | (aff1 << 16)
+                    | (1 << (aff0 % 16))
+            }
+            // Interrupt routing mode 1 targets all CPUs but the current one.
+            None => (1 << 40) | intid,
+        };
+        unsafe { asm!("dsb ishst", "msr icc_sgi1r_el1, {}", "isb", in(reg) value) };
+    }
+}
@@ -5,14 +5,19 @@ use alloc::{
 };
 use byteorder::{ByteOrder, BE};
 use fdt::DeviceTree;
-use syscall::Result;
+use syscall::{
+    error::{Error, EOPNOTSUPP},
+    Result,
+};
 
 use crate::{
+    cpu_set::LogicalCpuId,
     init::device_tree::travel_interrupt_ctrl,
     log::{debug, error},
 };
 
 mod gic;
+mod gicv3;
 mod irq_bcm2835;
 mod irq_bcm2836;
 
@@ -38,6 +43,18 @@ pub trait InterruptController {
     fn irq_xlate(&mut self, irq_data: &[u32], idx: usize) -> Result<usize>;
     fn irq_to_virq(&mut self, hwirq: u32) -> Option<usize>;
     fn irq_handler(&mut self, irq: u32);
+    /// Route `irq_num` to the CPU with the given interface number.
+    fn irq_set_affinity(&mut self, _irq_num: u32, _cpu_id: u32) -> Result<()> {
+        Err(Error::new(EOPNOTSUPP))
+    }
+    /// Initialize the per-CPU state of the controller on an AP, after `irq_init` on the BSP.
+    fn irq_init_ap(&mut self) {}
+    /// Whether the controller can send software generated interrupts, used for IPIs.
+    fn can_send_sgi(&self) -> bool {
+        false
+    }
+    /// Send the software generated interrupt `sgi` to `target`, or to all other CPUs if `None`.
+    fn send_sgi(&mut self, _sgi: u32, _target: Option<LogicalCpuId>) {}
 }
 
 pub trait InterruptHandler {
@@ -227,6 +244,36 @@ impl IrqChipCore {
         self.irq_chip_list.chips[ic_idx].ic.irq_disable(hwirq)
     }
 
+    pub fn irq_set_affinity(&mut self, virq: u32, cpu_id: u32) -> Result<()> {
+        let irq_desc = &self.irq_desc[virq as usize];
+        let ic_idx = irq_desc.basic.ic_idx;
+        let hwirq = irq_desc.basic.ic_irq;
+
+        self.irq_chip_list.chips[ic_idx]
+            .ic
+            .irq_set_affinity(hwirq, cpu_id)
+    }
+
+    pub fn init_ap(&mut self) {
+        for chip in self.irq_chip_list.chips.iter_mut() {
+            chip.ic.irq_init_ap();
+        }
+    }
+
+    pub fn can_send_sgi(&self) -> bool {
+        self.irq_chip_list
+            .chips
+            .get(self.irq_chip_list.root_idx)
+            .map_or(false, |root| root.ic.can_send_sgi())
+    }
+
+    pub fn send_sgi(&mut self, sgi: u32, target: Option<LogicalCpuId>) {
+        // IPIs can be sent before the interrupt controllers are found.
+        if let Some(root) = self.irq_chip_list.chips.get_mut(self.irq_chip_list.root_idx) {
+            root.ic.send_sgi(sgi, target);
+        }
+    }
+
     pub fn irq_to_virq(&mut self, hwirq: u32) -> Option<usize> {
         self.irq_chip_list.chips[self.irq_chip_list.root_idx]
             .ic
@@ -245,6 +292,8 @@ impl IrqChipCore {
     pub fn new_ic(ic_str: &str) -> Option<Box<dyn InterruptController>> {
         if ic_str.contains("arm,cortex-a15-gic") {
             Some(Box::new(gic::GenericInterruptController::new()))
+        } else if ic_str.contains("arm,gic-v3") {
+            Some(Box::new(gicv3::GicV3::new()))
         } else if ic_str.contains("brcm,bcm2836-l1-intc") {
             Some(Box::new(irq_bcm2836::Bcm2836ArmInterruptController::new()))
         } else if ic_str.contains("brcm,bcm2836-armctrl-ic") {
@@ -283,6 +332,12 @@ pub fn init(fdt: &DeviceTree) {
     }
 }
 
+pub fn init_ap() {
+    unsafe {
+        IRQ_CHIP.init_ap();
+    }
+}
+
 pub fn register_irq(virq: u32, handler: Box<dyn InterruptHandler>) {
     if virq >= 1024 {
         error!("irq {} exceed 1024!!!", virq);
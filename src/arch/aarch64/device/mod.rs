@@ -19,6 +19,7 @@ pub unsafe fn init() {
     let data = DTB_BINARY.get().unwrap();
     let fdt = fdt::DeviceTree::new(data).unwrap();
     irqchip::init(&fdt);
+    crate::interrupt::ipi::init();
     info!("GIT INIT");
     generic_timer::init();
 }
@@ -30,7 +31,10 @@ pub unsafe fn init_noncore() {
     rtc::init();
 }
 
-pub unsafe fn init_ap() {}
+pub unsafe fn init_ap() {
+    irqchip::init_ap();
+    generic_timer::init_ap();
+}
 
 //map physical addr X to virtual addr PHYS_OFFSET + X
 pub unsafe fn io_mmap(addr: usize, io_size: usize) {
@@ -1,8 +1,19 @@
+//! The real time clock, a PL031 or goldfish RTC found in the device tree.
+//!
+//! It seeds `CLOCK_REALTIME` at boot, and can be read and set as `sys:rtc`, in seconds since the
+//! Unix epoch, for `hwclock`.
+
+use alloc::vec::Vec;
 use core::ptr::{read_volatile, write_volatile};
 
+use byteorder::{ByteOrder, BE};
+use spin::Mutex;
+
 use crate::{
-    memory::Frame,
-    paging::{KernelMapper, Page, PageFlags, PhysicalAddress, TableKind, VirtualAddress},
+    dtb::DTB_BINARY,
+    init::device_tree::{find_compatible_node, root_cell_sz},
+    log::info,
+    syscall::error::{Error, Result, EINVAL, ENODEV},
     time,
 };
 
@@ -15,41 +26,71 @@ static RTC_RIS: u32 = 0x014;
 static RTC_MIS: u32 = 0x018;
 static RTC_ICR: u32 = 0x01c;
 
-static mut PL031_RTC: Pl031rtc = Pl031rtc { address: 0 };
+/// Nanoseconds since the epoch, where reading the low half latches the high half.
+static GOLDFISH_TIME_LOW: u32 = 0x000;
+static GOLDFISH_TIME_HIGH: u32 = 0x004;
+
+static RTC: Mutex<Option<Rtc>> = Mutex::new(None);
 
 pub unsafe fn init() {
-    PL031_RTC.init();
-    *time::START.lock() = (PL031_RTC.time() as u128) * time::NANOS_PER_SEC;
+    let Some(rtc) = Rtc::find() else {
+        info!("RTC: none found in the device tree");
+        return;
+    };
+    let now = rtc.time_ns();
+    info!(
+        "RTC: {:?} at {:X}, {} s",
+        rtc.kind,
+        rtc.address,
+        now / time::NANOS_PER_SEC
+    );
+    *time::START.lock() = now.saturating_sub(time::monotonic());
+    *RTC.lock() = Some(rtc);
 }
 
-struct Pl031rtc {
-    pub address: usize,
+#[derive(Clone, Copy, Debug)]
+enum RtcKind {
+    Pl031,
+    Goldfish,
 }
 
-impl Pl031rtc {
-    unsafe fn init(&mut self) {
-        let mut mapper = KernelMapper::lock();
-
-        let start_frame = Frame::containing_address(PhysicalAddress::new(0x09010000));
-        let end_frame = Frame::containing_address(PhysicalAddress::new(0x09010000 + 0x1000 - 1));
-
-        for frame in Frame::range_inclusive(start_frame, end_frame) {
-            let page = Page::containing_address(VirtualAddress::new(
-                frame.start_address().data() + crate::PHYS_OFFSET,
-            ));
-            mapper
-                .get_mut()
-                .expect("failed to access KernelMapper for mapping RTC")
-                .map_phys(
-                    page.start_address(),
-                    frame.start_address(),
-                    PageFlags::new().write(true),
-                )
-                .expect("failed to map RTC")
-                .flush();
-        }
+struct Rtc {
+    address: usize,
+    kind: RtcKind,
+}
+
+impl Rtc {
+    unsafe fn find() -> Option<Self> {
+        let data = DTB_BINARY.get()?;
+        let fdt = fdt::DeviceTree::new(data).ok()?;
+        let (kind, node) = if let Some(node) = find_compatible_node(&fdt, "arm,pl031") {
+            (RtcKind::Pl031, node)
+        } else {
+            (
+                RtcKind::Goldfish,
+                find_compatible_node(&fdt, "google,goldfish-rtc")?,
+            )
+        };
+        let reg = node.properties().find(|p| p.name == "reg")?;
+        let (address_cells, _) = root_cell_sz(&fdt)?;
+        let phys = match address_cells {
+            1 => BE::read_u32(reg.data.get(..4)?) as usize,
+            2 => BE::read_u64(reg.data.get(..8)?) as usize,
+            _ => return None,
+        };
 
-        self.address = crate::PHYS_OFFSET + 0x09010000;
+        super::io_mmap(phys, 0x1000);
+        let mut rtc = Rtc {
+            address: crate::PHYS_OFFSET + phys,
+            kind,
+        };
+        if let RtcKind::Pl031 = kind {
+            // Start the counter, in case the firmware did not
+            if rtc.read(RTC_CR) & 1 == 0 {
+                rtc.write(RTC_CR, 1);
+            }
+        }
+        Some(rtc)
     }
 
     unsafe fn read(&self, reg: u32) -> u32 {
@@ -61,8 +102,54 @@ impl Pl031rtc {
         write_volatile((self.address + reg as usize) as *mut u32, value);
     }
 
-    pub fn time(&mut self) -> u64 {
-        let seconds = unsafe { self.read(RTC_DR) } as u64;
-        seconds
+    /// Nanoseconds since the epoch.
+    fn time_ns(&self) -> u128 {
+        match self.kind {
+            RtcKind::Pl031 => u128::from(unsafe { self.read(RTC_DR) }) * time::NANOS_PER_SEC,
+            RtcKind::Goldfish => {
+                let low = unsafe { self.read(GOLDFISH_TIME_LOW) };
+                let high = unsafe { self.read(GOLDFISH_TIME_HIGH) };
+                u128::from(u64::from(high) << 32 | u64::from(low))
+            }
+        }
     }
+
+    fn set_time(&mut self, secs: u64) -> Result<()> {
+        match self.kind {
+            RtcKind::Pl031 => {
+                let secs = u32::try_from(secs).map_err(|_| Error::new(EINVAL))?;
+                unsafe { self.write(RTC_LR, secs) };
+            }
+            RtcKind::Goldfish => {
+                let ns = secs
+                    .checked_mul(time::NANOS_PER_SEC as u64)
+                    .ok_or(Error::new(EINVAL))?;
+                // The high half is only applied by the write of the low half.
+                unsafe {
+                    self.write(GOLDFISH_TIME_HIGH, (ns >> 32) as u32);
+                    self.write(GOLDFISH_TIME_LOW, ns as u32);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read handler of `sys:rtc`, the time of the RTC in seconds since the epoch.
+pub fn resource() -> Result<Vec<u8>> {
+    let rtc = RTC.lock();
+    let rtc = rtc.as_ref().ok_or(Error::new(ENODEV))?;
+    Ok(format!("{}\n", rtc.time_ns() / time::NANOS_PER_SEC).into_bytes())
+}
+
+/// Write handler of `sys:rtc`, setting the RTC to the given seconds since the epoch. The system
+/// clock is not changed.
+pub fn write(buf: &[u8]) -> Result<usize> {
+    let text = core::str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+    let secs = text.trim().parse().map_err(|_| Error::new(EINVAL))?;
+    RTC.lock()
+        .as_mut()
+        .ok_or(Error::new(ENODEV))?
+        .set_time(secs)?;
+    Ok(buf.len())
 }
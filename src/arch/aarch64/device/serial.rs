@@ -3,9 +3,10 @@ use spin::Mutex;
 
 use crate::{
     device::uart_pl011::SerialPort,
+    devices::serial_console::{self, SerialConsole, SerialConsolePort},
     init::device_tree,
     interrupt::irq::trigger,
-    log::{debug, info},
+    log::{debug, info, warn},
 };
 
 use super::irqchip::{register_irq, InterruptHandler, IRQ_CHIP};
@@ -22,18 +23,50 @@ impl InterruptHandler for Com1Irq {
         if let Some(ref mut serial_port) = *COM1.lock() {
             serial_port.receive();
         };
+        // Echoing input needs the serial port.
+        crate::scheme::debug::debug_notify();
         unsafe {
             trigger(irq);
         }
     }
 }
 
-pub unsafe fn init_early(dtb_base: usize, dtb_size: usize) {
+/// Find the serial port used as console, from the `console=` boot environment variable if it
+/// selects a PL011 UART, or from the device tree at `dtb` otherwise. Other UARTs selected by
+/// `console=` are ignored with a warning.
+pub unsafe fn init_early(env: &[u8], dtb: Option<(usize, usize)>) {
     if COM1.lock().is_some() {
         // Hardcoded UART
         return;
     }
 
+    match serial_console::parse(env) {
+        Some(SerialConsole {
+            port: SerialConsolePort::Pl011(phys),
+            baud,
+            clock,
+        }) => {
+            let virt = crate::PHYS_OFFSET + phys;
+            let mut serial_port = SerialPort::new(virt, false, false);
+            serial_port.init(false);
+            if let (Some(baud), Some(clock)) = (baud, clock) {
+                serial_port.set_baud_rate(baud, clock);
+            }
+            *COM1.lock() = Some(serial_port);
+            info!("UART at {:X} from console=", virt);
+            return;
+        }
+        // Only PL011 UARTs can be driven as console here.
+        Some(console) => warn!(
+            "console={:?} is not supported, using the UART of the device tree",
+            console.port
+        ),
+        None => (),
+    }
+
+    let Some((dtb_base, dtb_size)) = dtb else {
+        return;
+    };
     if let Some((phys, size, skip_init, cts)) = device_tree::diag_uart_range(dtb_base, dtb_size) {
         let virt = crate::PHYS_OFFSET + phys;
         {
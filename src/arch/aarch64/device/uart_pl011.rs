@@ -1,6 +1,6 @@
 use core::ptr;
 
-use crate::scheme::debug::{debug_input, debug_notify};
+use crate::scheme::debug::debug_input;
 
 bitflags! {
     /// UARTFR
@@ -175,6 +175,23 @@ impl SerialPort {
         }
     }
 
+    /// Program the baud rate for a reference clock of `clock` Hz. The divisor only takes effect
+    /// with a write to UARTLCR_H, which is done with the UART disabled.
+    pub fn set_baud_rate(&mut self, baud: u32, clock: u32) {
+        // In 64ths, as the fractional part of the divisor is 6 bits.
+        let divisor = (u64::from(clock) * 4 + u64::from(baud) / 2) / u64::from(baud);
+
+        let ctrl = self.read_reg(self.ctrl_reg);
+        self.write_reg(self.ctrl_reg, 0x0);
+        while self.line_sts().contains(UartFrFlags::BUSY) {}
+
+        self.write_reg(self.int_baud_reg, (divisor >> 6) as u32);
+        self.write_reg(self.frac_baud_reg, (divisor & 0x3F) as u32);
+        self.write_reg(self.line_ctrl_reg, self.read_reg(self.line_ctrl_reg));
+
+        self.write_reg(self.ctrl_reg, ctrl);
+    }
+
     fn line_sts(&self) -> UartFrFlags {
         UartFrFlags::from_bits_truncate(self.read_reg(self.flag_reg))
     }
@@ -217,7 +234,6 @@ impl SerialPort {
 
             flags = self.intr_stats();
         }
-        debug_notify();
     }
 
     pub fn send(&mut self, data: u8) {
@@ -1,17 +1,18 @@
 use rmm::VirtualAddress;
 
 use crate::{
+    context::{context::FaultInfo, memory::AccessMode, signal::record_fault},
     exception_stack,
     interrupt::stack_trace,
     memory::{ArchIntCtx, GenericPfFlags},
-    syscall,
+    ptrace, syscall,
     syscall::flag::*,
 };
 
 use super::InterruptStack;
 
 exception_stack!(synchronous_exception_at_el1_with_sp0, |stack| {
-    println!("Synchronous exception at EL1 with SP0");
+    log::error!("Synchronous exception at EL1 with SP0");
     stack.dump();
     stack_trace();
     loop {}
@@ -56,6 +57,23 @@ unsafe fn instr_data_abort_inner(
     flags.set(GenericPfFlags::INSTR_NOT_DATA, instr_not_data);
     flags.set(GenericPfFlags::USER_NOT_SUPERVISOR, from_user);
 
+    // "Synchronous Tag Check Fault", which no page table change can fix.
+    if fsc == 0b010001 {
+        if from_user {
+            record_fault(FaultInfo {
+                signal: SIGSEGV,
+                ip: stack.iret.elr_el1,
+                address: Some(far_el1()),
+                access: Some(if write_not_read_if_data {
+                    AccessMode::Write
+                } else {
+                    AccessMode::Read
+                }),
+            });
+        }
+        return false;
+    }
+
     let faulting_addr = VirtualAddress::new(far_el1());
     //dbg!(faulting_addr, flags, from);
 
@@ -140,13 +158,13 @@ exception_stack!(synchronous_exception_at_el1_with_spx, |stack| {
         exception_code(stack.iret.esr_el1),
         "sync_exc_el1_spx",
     ) {
-        println!("Synchronous exception at EL1 with SPx");
+        log::error!("Synchronous exception at EL1 with SPx");
         if exception_code(stack.iret.esr_el1) == 0b100101 {
             let far_el1 = far_el1();
-            println!("FAR_EL1 = 0x{:08x}", far_el1);
+            log::error!("FAR_EL1 = 0x{:08x}", far_el1);
         } else if exception_code(stack.iret.esr_el1) == 0b100100 {
             let far_el1 = far_el1();
-            println!("USER FAR_EL1 = 0x{:08x}", far_el1);
+            log::error!("USER FAR_EL1 = 0x{:08x}", far_el1);
         }
         stack.dump();
         stack_trace();
@@ -171,21 +189,68 @@ unsafe fn pf_inner(stack: &mut InterruptStack, ty: u8, from: &str) -> bool {
 }
 
 exception_stack!(synchronous_exception_at_el0, |stack| {
+    // Asynchronous tag check faults are only noticed when entering the kernel.
+    if crate::arch::mte::take_async_fault() {
+        log::error!("Asynchronous tag check fault");
+        record_fault(FaultInfo {
+            signal: SIGSEGV,
+            ip: stack.iret.elr_el1,
+            address: None,
+            access: None,
+        });
+        crate::ksignal(SIGSEGV);
+    }
+
     match exception_code(stack.iret.esr_el1) {
         0b010101 => {
-            let scratch = &stack.scratch;
-            syscall::syscall(
-                scratch.x8, scratch.x0, scratch.x1, scratch.x2, scratch.x3, scratch.x4, stack,
+            let entry_event = {
+                let scratch = &stack.scratch;
+                ptrace::syscall_entry_event([
+                    scratch.x8, scratch.x0, scratch.x1, scratch.x2, scratch.x3, scratch.x4,
+                ])
+            };
+            let allowed = ptrace::breakpoint_callback(PTRACE_STOP_PRE_SYSCALL, Some(entry_event))
+                .and_then(|_| ptrace::next_breakpoint().map(|f| !f.contains(PTRACE_FLAG_IGNORE)));
+
+            // The tracer may have changed the registers while stopped.
+            let number = stack.scratch.x8;
+
+            if allowed.unwrap_or(true) {
+                let scratch = &stack.scratch;
+                syscall::syscall(
+                    scratch.x8, scratch.x0, scratch.x1, scratch.x2, scratch.x3, scratch.x4, stack,
+                );
+            }
+
+            ptrace::breakpoint_callback(
+                PTRACE_STOP_POST_SYSCALL,
+                Some(ptrace::syscall_exit_event(number, stack.syscall_ret_reg())),
             );
         }
 
+        // "Access to SVE functionality trapped"
+        0b011001 if crate::arch::sve::handle_trap() => {}
+
+        // "Branch Target Exception"
+        0b001101 => {
+            log::error!("Branch target exception");
+            stack.dump();
+            crate::ksignal(SIGILL);
+        }
+        // "Exception from a Pointer Authentication instruction authentication failure"
+        0b011100 => {
+            log::error!("Pointer authentication failure");
+            stack.dump();
+            crate::ksignal(SIGILL);
+        }
+
         ty => {
             if !pf_inner(stack, ty as u8, "sync_exc_el0") {
                 log::error!(
                     "FATAL: Not an SVC induced synchronous exception (ty={:b})",
                     ty
                 );
-                println!("FAR_EL1: {:#0x}", far_el1());
+                log::error!("FAR_EL1: {:#0x}", far_el1());
                 //crate::debugger::debugger(None);
                 stack.dump();
                 stack_trace();
@@ -196,7 +261,7 @@ exception_stack!(synchronous_exception_at_el0, |stack| {
 });
 
 exception_stack!(unhandled_exception, |stack| {
-    println!("Unhandled exception");
+    log::error!("Unhandled exception");
     stack.dump();
     stack_trace();
     loop {}
@@ -27,25 +27,25 @@ pub struct ScratchRegisters {
 
 impl ScratchRegisters {
     pub fn dump(&self) {
-        println!("X0:    {:>016X}", { self.x0 });
-        println!("X1:    {:>016X}", { self.x1 });
-        println!("X2:    {:>016X}", { self.x2 });
-        println!("X3:    {:>016X}", { self.x3 });
-        println!("X4:    {:>016X}", { self.x4 });
-        println!("X5:    {:>016X}", { self.x5 });
-        println!("X6:    {:>016X}", { self.x6 });
-        println!("X7:    {:>016X}", { self.x7 });
-        println!("X8:    {:>016X}", { self.x8 });
-        println!("X9:    {:>016X}", { self.x9 });
-        println!("X10:   {:>016X}", { self.x10 });
-        println!("X11:   {:>016X}", { self.x11 });
-        println!("X12:   {:>016X}", { self.x12 });
-        println!("X13:   {:>016X}", { self.x13 });
-        println!("X14:   {:>016X}", { self.x14 });
-        println!("X15:   {:>016X}", { self.x15 });
-        println!("X16:   {:>016X}", { self.x16 });
-        println!("X17:   {:>016X}", { self.x17 });
-        println!("X18:   {:>016X}", { self.x18 });
+        log::error!("X0:    {:>016X}", { self.x0 });
+        log::error!("X1:    {:>016X}", { self.x1 });
+        log::error!("X2:    {:>016X}", { self.x2 });
+        log::error!("X3:    {:>016X}", { self.x3 });
+        log::error!("X4:    {:>016X}", { self.x4 });
+        log::error!("X5:    {:>016X}", { self.x5 });
+        log::error!("X6:    {:>016X}", { self.x6 });
+        log::error!("X7:    {:>016X}", { self.x7 });
+        log::error!("X8:    {:>016X}", { self.x8 });
+        log::error!("X9:    {:>016X}", { self.x9 });
+        log::error!("X10:   {:>016X}", { self.x10 });
+        log::error!("X11:   {:>016X}", { self.x11 });
+        log::error!("X12:   {:>016X}", { self.x12 });
+        log::error!("X13:   {:>016X}", { self.x13 });
+        log::error!("X14:   {:>016X}", { self.x14 });
+        log::error!("X15:   {:>016X}", { self.x15 });
+        log::error!("X16:   {:>016X}", { self.x16 });
+        log::error!("X17:   {:>016X}", { self.x17 });
+        log::error!("X18:   {:>016X}", { self.x18 });
     }
 }
 
@@ -69,18 +69,18 @@ pub struct PreservedRegisters {
 
 impl PreservedRegisters {
     pub fn dump(&self) {
-        println!("X19:   {:>016X}", { self.x19 });
-        println!("X20:   {:>016X}", { self.x20 });
-        println!("X21:   {:>016X}", { self.x21 });
-        println!("X22:   {:>016X}", { self.x22 });
-        println!("X23:   {:>016X}", { self.x23 });
-        println!("X24:   {:>016X}", { self.x24 });
-        println!("X25:   {:>016X}", { self.x25 });
-        println!("X26:   {:>016X}", { self.x26 });
-        println!("X27:   {:>016X}", { self.x27 });
-        println!("X28:   {:>016X}", { self.x28 });
-        println!("X29:   {:>016X}", { self.x29 });
-        println!("X30:   {:>016X}", { self.x30 });
+        log::error!("X19:   {:>016X}", { self.x19 });
+        log::error!("X20:   {:>016X}", { self.x20 });
+        log::error!("X21:   {:>016X}", { self.x21 });
+        log::error!("X22:   {:>016X}", { self.x22 });
+        log::error!("X23:   {:>016X}", { self.x23 });
+        log::error!("X24:   {:>016X}", { self.x24 });
+        log::error!("X25:   {:>016X}", { self.x25 });
+        log::error!("X26:   {:>016X}", { self.x26 });
+        log::error!("X27:   {:>016X}", { self.x27 });
+        log::error!("X28:   {:>016X}", { self.x28 });
+        log::error!("X29:   {:>016X}", { self.x29 });
+        log::error!("X30:   {:>016X}", { self.x30 });
     }
 }
 
@@ -97,10 +97,10 @@ pub struct IretRegisters {
 
 impl IretRegisters {
     pub fn dump(&self) {
-        println!("ELR_EL1: {:>016X}", { self.elr_el1 });
-        println!("SPSR_EL1: {:>016X}", { self.spsr_el1 });
-        println!("ESR_EL1: {:>016X}", { self.esr_el1 });
-        println!("SP_EL0: {:>016X}", { self.sp_el0 });
+        log::error!("ELR_EL1: {:>016X}", { self.elr_el1 });
+        log::error!("SPSR_EL1: {:>016X}", { self.spsr_el1 });
+        log::error!("ESR_EL1: {:>016X}", { self.esr_el1 });
+        log::error!("SP_EL0: {:>016X}", { self.sp_el0 });
     }
 }
 
@@ -127,6 +127,9 @@ impl InterruptStack {
     pub fn set_syscall_ret_reg(&mut self, ret: usize) {
         self.scratch.x0 = ret;
     }
+    pub fn syscall_ret_reg(&self) -> usize {
+        self.scratch.x0
+    }
     pub fn dump(&self) {
         self.iret.dump();
         self.scratch.dump();
@@ -0,0 +1,44 @@
+use alloc::boxed::Box;
+
+use crate::{
+    context::{self, switch::SwitchResult},
+    device::irqchip::{register_irq, InterruptHandler, IRQ_CHIP},
+    ipi::IpiKind,
+    percpu::PercpuBlock,
+};
+
+/// Handler of the SGI delivering one kind of IPI.
+struct IpiHandler(IpiKind);
+
+impl InterruptHandler for IpiHandler {
+    fn irq_handler(&mut self, irq: u32) {
+        unsafe { IRQ_CHIP.irq_eoi(irq) };
+
+        match self.0 {
+            IpiKind::Wakeup => (),
+            IpiKind::Tlb => PercpuBlock::current().maybe_handle_tlb_shootdown(),
+            // Like a tick, this returns to userspace, where the signals of the new context are
+            // handled.
+            IpiKind::Switch => {
+                if let SwitchResult::Switched { signal: true } = context::switch() {
+                    context::signal::signal_handler();
+                }
+            }
+            // Switch after a sufficient amount of time since the last switch.
+            IpiKind::Pit => context::switch::tick(),
+        }
+    }
+}
+
+/// Register the handlers of the SGIs used for IPIs, which are enabled on every CPU by the
+/// interrupt controller, if it supports them.
+pub fn init() {
+    if !unsafe { IRQ_CHIP.can_send_sgi() } {
+        return;
+    }
+    for kind in [IpiKind::Wakeup, IpiKind::Tlb, IpiKind::Switch, IpiKind::Pit] {
+        if let Some(virq) = unsafe { IRQ_CHIP.irq_to_virq(kind.sgi()) } {
+            register_irq(virq as u32, Box::new(IpiHandler(kind)));
+        }
+    }
+}
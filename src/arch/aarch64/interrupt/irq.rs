@@ -7,6 +7,7 @@ pub struct IrqDesc {
 }
 
 exception_stack!(irq_at_el0, |stack| {
+    let _irq = crate::context::cputime::IrqScope::enter();
     let irq = IRQ_CHIP.irq_ack();
     if let Some(virq) = IRQ_CHIP.irq_to_virq(irq) && virq < 1024 {
         if let Some(handler) = &mut IRQ_CHIP.irq_desc[virq].handler {
@@ -15,11 +16,12 @@ exception_stack!(irq_at_el0, |stack| {
             IRQ_CHIP.irq_chip_list.chips[ic_idx].ic.irq_handler(virq as u32);
         }
     } else {
-        println!("unexpected irq num {}", irq);
+        log::warn!("unexpected irq num {}", irq);
     }
 });
 
 exception_stack!(irq_at_el1, |stack| {
+    let _irq = crate::context::cputime::IrqScope::enter();
     let irq = IRQ_CHIP.irq_ack();
     if let Some(virq) = IRQ_CHIP.irq_to_virq(irq) && virq < 1024 {
         if let Some(handler) = &mut IRQ_CHIP.irq_desc[virq].handler {
@@ -28,7 +30,7 @@ exception_stack!(irq_at_el1, |stack| {
             IRQ_CHIP.irq_chip_list.chips[ic_idx].ic.irq_handler(virq as u32);
         }
     } else {
-        println!("unexpected irq num {}", irq);
+        log::warn!("unexpected irq num {}", irq);
     }
 });
 
@@ -42,14 +44,27 @@ pub unsafe fn trigger(irq: u32) {
     IRQ_CHIP.irq_eoi(irq);
 }
 
-pub unsafe fn acknowledge(_irq: usize) {
-    // TODO
+pub unsafe fn acknowledge(irq: usize) {
+    // Lines are only disabled when masked by the IRQ scheme.
+    IRQ_CHIP.irq_enable(irq as u32);
+}
+
+/// Mask the IRQ until it is acknowledged again, used by the IRQ scheme to silence storming lines.
+pub unsafe fn mask(irq: usize) {
+    IRQ_CHIP.irq_disable(irq as u32);
+}
+
+/// Route `irq` to the logical CPU `cpu_id`.
+pub unsafe fn set_affinity(irq: u8, cpu_id: u32) -> syscall::Result<()> {
+    IRQ_CHIP.irq_set_affinity(irq.into(), cpu_id)
 }
 
 pub unsafe fn irq_handler_com1(irq: u32) {
     if let Some(ref mut serial_port) = *COM1.lock() {
         serial_port.receive();
     };
+    // Echoing input needs the serial port.
+    crate::scheme::debug::debug_notify();
     trigger(irq);
 }
 
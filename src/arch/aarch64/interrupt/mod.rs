@@ -6,6 +6,7 @@ use core::arch::asm;
 pub mod handler;
 
 pub mod exception;
+pub mod ipi;
 pub mod irq;
 pub mod syscall;
 pub mod trace;
@@ -66,6 +67,20 @@ pub fn bsp_apic_id() -> Option<u32> {
     None
 }
 
+/// Whether message signaled interrupts can be allocated through `irq:msi`.
+///
+/// TODO: They cannot, as there is no driver for a GICv2m frame or a GICv3 ITS, which translates
+/// device writes into LPIs. An ITS also identifies the writing device by its DeviceID, the PCI
+/// requester ID mapped through the `msi-map` of the device tree, so the `irq:msi` interface would
+/// have to take the device as well.
+pub fn msi_supported() -> bool {
+    false
+}
+
+pub fn msi_message(_cpu_id: u32, _vector: u8) -> Option<(u64, u32)> {
+    None
+}
+
 #[inline]
 pub fn is_reserved(cpu_id: LogicalCpuId, index: u8) -> bool {
     //TODO
@@ -7,6 +7,13 @@ pub enum IpiKind {
     Pit = 0x43,
 }
 
+impl IpiKind {
+    /// The SGI used to deliver the IPI.
+    pub fn sgi(self) -> u32 {
+        u32::from(self as u8 - IpiKind::Wakeup as u8)
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 #[repr(u8)]
 pub enum IpiTarget {
@@ -21,7 +28,18 @@ pub fn ipi(_kind: IpiKind, _target: IpiTarget) {}
 
 #[cfg(feature = "multi_core")]
 #[inline(always)]
-pub fn ipi(kind: IpiKind, target: IpiTarget) {}
+pub fn ipi(kind: IpiKind, target: IpiTarget) {
+    use crate::{device::irqchip::IRQ_CHIP, percpu::PercpuBlock};
+
+    unsafe {
+        if matches!(target, IpiTarget::All | IpiTarget::Other) {
+            IRQ_CHIP.send_sgi(kind.sgi(), None);
+        }
+        if matches!(target, IpiTarget::All | IpiTarget::Current) {
+            IRQ_CHIP.send_sgi(kind.sgi(), Some(PercpuBlock::current().cpu_id));
+        }
+    }
+}
 
 #[cfg(not(feature = "multi_core"))]
 #[inline(always)]
@@ -29,4 +47,8 @@ pub fn ipi_single(_kind: IpiKind, _target: crate::cpu_set::LogicalCpuId) {}
 
 #[cfg(feature = "multi_core")]
 #[inline(always)]
-pub fn ipi_single(kind: IpiKind, target: crate::cpu_set::LogicalCpuId) {}
+pub fn ipi_single(kind: IpiKind, target: crate::cpu_set::LogicalCpuId) {
+    use crate::device::irqchip::IRQ_CHIP;
+
+    unsafe { IRQ_CHIP.send_sgi(kind.sgi(), Some(target)) };
+}
@@ -19,11 +19,26 @@ pub mod ipi;
 /// Miscellaneous
 pub mod misc;
 
+/// Memory tagging
+pub mod mte;
+
 /// Paging
 pub mod paging;
 
+/// Pointer authentication and branch target identification
+pub mod pauth;
+
+/// Firmware calls for starting CPUs and powering off
+pub mod psci;
+
 pub mod rmm;
 
+/// Starting the secondary CPUs
+pub mod smp;
+
+/// Scalable Vector Extension state
+pub mod sve;
+
 /// Initialization and start function
 pub mod start;
 
@@ -0,0 +1,207 @@
+//! The Memory Tagging Extension.
+//!
+//! With MTE2, every 16-byte granule of tagged memory has a 4-bit allocation tag, which the CPU
+//! compares to bits 59:56 of the pointers used to access it. Userspace gets tagged memory by
+//! mapping `memory:zeroed@tagged`, whose tags start out as 0, and chooses per context through
+//! `proc:<pid>/mte` whether mismatches are reported synchronously, as a `SIGSEGV` at the faulting
+//! access, or asynchronously, as a `SIGSEGV` at the next syscall or exception of the context. The
+//! address of a synchronous tag fault is in `proc:<pid>/fault`.
+//!
+//! Pointers passed to syscalls must still be untagged. Private tagged memory is not inherited
+//! across forks, as copying a page on write would not copy its tags: the child starts without
+//! tagged memory and tag checks. Shared tagged memory is kept, tags included.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    device::cpu::registers::control_regs::{self, MairEl1},
+    paging::{entry::EntryFlags, PageFlags, RmmA},
+    syscall::error::{Error, Result, EFAULT, EINVAL},
+};
+
+/// `ID_AA64PFR1_EL1.MTE`, which is 2 or more when tags are stored for all memory.
+const PFR1_MTE_SHIFT: u64 = 8;
+
+/// Enable allocation tag access at EL0 and EL1.
+const SCTLR_ATA0: u64 = 1 << 42;
+const SCTLR_ATA: u64 = 1 << 43;
+/// `SCTLR_EL1.TCF0`, how tag check faults at EL0 are reported.
+const SCTLR_TCF0_SHIFT: u64 = 38;
+const SCTLR_TCF0_MASK: u64 = 0b11 << SCTLR_TCF0_SHIFT;
+/// Ignore the top byte of EL0 addresses in translation, where the tag is kept.
+const TCR_TBI0: u64 = 1 << 37;
+/// `TFSRE0_EL1.TF0`, set by an asynchronous tag check fault at EL0.
+const TFSRE0_TF0: u64 = 1 << 0;
+
+/// The size of the granule having one tag.
+const GRANULE_SIZE: usize = 16;
+
+static MTE: AtomicBool = AtomicBool::new(false);
+
+/// How tag check faults of a context are reported.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TagCheckMode {
+    /// Tags are not checked.
+    #[default]
+    None = 0,
+    /// A fault is raised by the mismatching access.
+    Sync = 1,
+    /// Mismatches are recorded, and reported at the next entry to the kernel.
+    Async = 2,
+}
+
+impl TagCheckMode {
+    pub fn from_raw(raw: usize) -> Result<Self> {
+        match raw {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Sync),
+            2 => Ok(Self::Async),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+}
+
+/// The MTE configuration and state of a context.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MteState {
+    pub mode: TagCheckMode,
+    /// Tags which `irg` may generate, one bit per tag. None by default, where `irg` always
+    /// generates 0.
+    pub include: u16,
+    /// `TFSRE0_EL1`, saved when the context is switched away from.
+    tfsr: u64,
+}
+
+unsafe fn sctlr_el1_write(val: u64) {
+    asm!("msr sctlr_el1, {}", "isb", in(reg) val);
+}
+
+/// `GCR_EL1`, excluding the tags `irg` must not generate.
+unsafe fn gcr_el1_write(include: u16) {
+    asm!("msr S3_0_C1_C0_6, {}", in(reg) u64::from(!include));
+}
+
+unsafe fn tfsre0_el1() -> u64 {
+    let ret: u64;
+    // Make sure preceding asynchronous faults have been recorded.
+    asm!("dsb nsh", "isb", "mrs {}, S3_0_C5_C6_1", out(reg) ret);
+    ret
+}
+
+unsafe fn tfsre0_el1_write(val: u64) {
+    asm!("msr S3_0_C5_C6_1, {}", in(reg) val);
+}
+
+/// Enable MTE on the current CPU, if supported. This must run after `paging::init`, which sets
+/// up the other memory attributes.
+pub unsafe fn init() {
+    let pfr1: u64;
+    // ID_AA64PFR1_EL1
+    asm!("mrs {}, S3_0_C0_C4_1", out(reg) pfr1);
+    if (pfr1 >> PFR1_MTE_SHIFT) & 0xF < 2 {
+        return;
+    }
+
+    let mut mair = control_regs::mair_el1();
+    mair.insert(MairEl1::NORMAL_TAGGED_MEMORY);
+    control_regs::mair_el1_write(mair);
+
+    let tcr = control_regs::tcr_el1() | TCR_TBI0;
+    asm!("msr tcr_el1, {}", "isb", in(reg) tcr);
+
+    sctlr_el1_write((control_regs::sctlr_el1() | SCTLR_ATA | SCTLR_ATA0) & !SCTLR_TCF0_MASK);
+    gcr_el1_write(0);
+    // RGSR_EL1, the seed of irg
+    asm!("msr S3_0_C1_C0_5, {}", in(reg) (control_regs::cntpct_el0() & 0xFFFF) << 8);
+    tfsre0_el1_write(0);
+
+    if !MTE.swap(true, Ordering::Relaxed) {
+        log::info!("MTE: tagged memory supported");
+    }
+}
+
+pub fn supported() -> bool {
+    MTE.load(Ordering::Relaxed)
+}
+
+/// Load the tag check mode and tag exclusion of `state` on the current CPU.
+pub unsafe fn load(state: &MteState) {
+    if !supported() {
+        return;
+    }
+    let sctlr =
+        (control_regs::sctlr_el1() & !SCTLR_TCF0_MASK) | ((state.mode as u64) << SCTLR_TCF0_SHIFT);
+    sctlr_el1_write(sctlr);
+    gcr_el1_write(state.include);
+}
+
+/// Save the MTE state of `prev`, and load that of `next`.
+pub unsafe fn switch(prev: &mut MteState, next: &MteState) {
+    if !supported() {
+        return;
+    }
+    prev.tfsr = tfsre0_el1();
+    tfsre0_el1_write(next.tfsr);
+    if prev.mode != next.mode || prev.include != next.include {
+        load(next);
+    }
+}
+
+/// Take the asynchronous tag check fault of the current context, if one was recorded.
+pub unsafe fn take_async_fault() -> bool {
+    if !supported() || tfsre0_el1() & TFSRE0_TF0 == 0 {
+        return false;
+    }
+    tfsre0_el1_write(0);
+    true
+}
+
+/// Set the tags of `len` bytes at the user address `dst`, which is granule aligned, to 0. Like
+/// the usercopy functions, this allocates lazily mapped pages, and returns nonzero if the memory
+/// is not writable.
+#[naked]
+#[link_section = ".usercopy-fns"]
+unsafe extern "C" fn arch_clear_user_tags(dst: usize, len: usize) -> u8 {
+    // x0, x1
+    core::arch::asm!(
+        "
+        .arch_extension memtag
+        mov x2, x0
+        mov x0, 0
+    2:
+        cbz x1, 3f
+
+        stg x2, [x2]
+
+        add x2, x2, 16
+        sub x1, x1, 16
+
+        b 2b
+    3:
+        ret
+    ",
+        options(noreturn)
+    );
+}
+
+/// Set the tags of the writable user memory at `dst` to 0, in the current address space.
+pub fn clear_user_tags(dst: usize, len: usize) -> Result<()> {
+    debug_assert!(dst % GRANULE_SIZE == 0 && len % GRANULE_SIZE == 0);
+    if unsafe { arch_clear_user_tags(dst, len) } != 0 {
+        return Err(Error::new(EFAULT));
+    }
+    Ok(())
+}
+
+/// Map pages with `flags` as tagged memory.
+pub fn page_flags(flags: PageFlags<RmmA>) -> PageFlags<RmmA> {
+    flags.custom_flag(EntryFlags::TAGGED.bits(), true)
+}
+
+/// Whether pages mapped with `flags` are tagged memory.
+pub fn is_tagged(flags: PageFlags<RmmA>) -> bool {
+    flags.data() & EntryFlags::TAGGED.bits() == EntryFlags::TAGGED.bits()
+}
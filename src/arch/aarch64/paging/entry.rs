@@ -9,5 +9,9 @@ bitflags! {
     pub struct EntryFlags: usize {
         const NO_CACHE = 1 << 2;
         const DEV_MEM = 2 << 2;
+        /// Write-back memory with allocation tags
+        const TAGGED = 3 << 2;
+        /// Guarded page, where indirect branches must land on a BTI instruction
+        const GUARDED = 1 << 50;
     }
 }
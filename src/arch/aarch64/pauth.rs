@@ -0,0 +1,185 @@
+//! Pointer authentication and branch target identification for userspace.
+//!
+//! With pointer authentication (ARMv8.3), return addresses and other pointers are signed with
+//! keys that userspace cannot read. Each address space gets its own random keys, which are kept
+//! across forks, as the stacks of the child still hold pointers signed by the parent, and loaded
+//! whenever the address space becomes current. A pointer failing authentication faults once it is
+//! used, or right away with `FEAT_FPAC`.
+//!
+//! With branch target identification (ARMv8.5), indirect branches into guarded pages must land on
+//! a `bti` instruction. Binaries built for it enable it by writing 1 to `proc:<pid>/bti` before
+//! mapping their code, after which new mappings of the address space are guarded.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{
+    device::cpu::registers::control_regs,
+    paging::{entry::EntryFlags, PageFlags, RmmA},
+};
+
+/// Fields of `ID_AA64ISAR1_EL1` enumerating the address and generic authentication algorithms.
+const ISAR1_APA_SHIFT: u64 = 4;
+const ISAR1_API_SHIFT: u64 = 8;
+const ISAR1_GPA_SHIFT: u64 = 24;
+const ISAR1_GPI_SHIFT: u64 = 28;
+/// Fields of `ID_AA64ISAR2_EL1` enumerating the QARMA3 algorithm.
+const ISAR2_GPA3_SHIFT: u64 = 8;
+const ISAR2_APA3_SHIFT: u64 = 12;
+/// `ID_AA64ISAR0_EL1.RNDR`, enumerating the random number registers.
+const ISAR0_RNDR_SHIFT: u64 = 60;
+/// `ID_AA64PFR1_EL1.BT`, enumerating branch target identification.
+const PFR1_BT_SHIFT: u64 = 0;
+
+/// Enable authentication with the IA, IB, DA and DB keys.
+const SCTLR_ENIA: u64 = 1 << 31;
+const SCTLR_ENIB: u64 = 1 << 30;
+const SCTLR_ENDA: u64 = 1 << 27;
+const SCTLR_ENDB: u64 = 1 << 13;
+
+static PAUTH: AtomicBool = AtomicBool::new(false);
+static BTI: AtomicBool = AtomicBool::new(false);
+static RNDR: AtomicBool = AtomicBool::new(false);
+/// State of the fallback generator, used without `RNDR`.
+static SEED: AtomicU64 = AtomicU64::new(0);
+
+/// The pointer authentication keys of an address space.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Keys {
+    ia: [u64; 2],
+    ib: [u64; 2],
+    da: [u64; 2],
+    db: [u64; 2],
+    ga: [u64; 2],
+}
+
+impl Keys {
+    /// Generate a new set of keys.
+    pub fn generate() -> Self {
+        let mut key = || [random(), random()];
+        Self {
+            ia: key(),
+            ib: key(),
+            da: key(),
+            db: key(),
+            ga: key(),
+        }
+    }
+}
+
+fn field(reg: u64, shift: u64) -> u64 {
+    (reg >> shift) & 0xF
+}
+
+/// A random number from `RNDR`, or else from the counter mixed through SplitMix64, which is
+/// predictable but still differs between address spaces.
+fn random() -> u64 {
+    if RNDR.load(Ordering::Relaxed) {
+        for _ in 0..8 {
+            let value: u64;
+            // RNDR, which returns 0 when no entropy was available.
+            unsafe { asm!("mrs {}, S3_3_C2_C4_0", out(reg) value) };
+            if value != 0 {
+                return value;
+            }
+        }
+    }
+    let counter = unsafe { control_regs::cntpct_el0() };
+    let mut z = SEED
+        .fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)
+        .wrapping_add(counter);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Enable pointer authentication on the current CPU, if supported.
+pub unsafe fn init() {
+    let isar0: u64;
+    let isar1: u64;
+    let isar2: u64;
+    let pfr1: u64;
+    asm!("mrs {}, id_aa64isar0_el1", out(reg) isar0);
+    asm!("mrs {}, id_aa64isar1_el1", out(reg) isar1);
+    // ID_AA64ISAR2_EL1, which reads as zero before ARMv8.7.
+    asm!("mrs {}, S3_0_C0_C6_2", out(reg) isar2);
+    // ID_AA64PFR1_EL1
+    asm!("mrs {}, S3_0_C0_C4_1", out(reg) pfr1);
+
+    RNDR.store(field(isar0, ISAR0_RNDR_SHIFT) != 0, Ordering::Relaxed);
+    BTI.store(field(pfr1, PFR1_BT_SHIFT) != 0, Ordering::Relaxed);
+
+    let address = field(isar1, ISAR1_APA_SHIFT) != 0
+        || field(isar1, ISAR1_API_SHIFT) != 0
+        || field(isar2, ISAR2_APA3_SHIFT) != 0;
+    let generic = field(isar1, ISAR1_GPA_SHIFT) != 0
+        || field(isar1, ISAR1_GPI_SHIFT) != 0
+        || field(isar2, ISAR2_GPA3_SHIFT) != 0;
+    if !address || !generic {
+        return;
+    }
+
+    let sctlr = control_regs::sctlr_el1() | SCTLR_ENIA | SCTLR_ENIB | SCTLR_ENDA | SCTLR_ENDB;
+    asm!("msr sctlr_el1, {}", "isb", in(reg) sctlr);
+
+    if !PAUTH.swap(true, Ordering::Relaxed) {
+        SEED.store(control_regs::cntpct_el0(), Ordering::Relaxed);
+        log::info!(
+            "Pointer authentication enabled, BTI {}",
+            if bti_supported() {
+                "supported"
+            } else {
+                "not supported"
+            }
+        );
+    }
+}
+
+pub fn supported() -> bool {
+    PAUTH.load(Ordering::Relaxed)
+}
+
+pub fn bti_supported() -> bool {
+    BTI.load(Ordering::Relaxed)
+}
+
+/// Load the keys of the address space becoming current.
+pub unsafe fn load(keys: &Keys) {
+    if !supported() {
+        return;
+    }
+    // The key registers, which the assembler only knows by name with pointer authentication
+    // enabled.
+    asm!(
+        "msr S3_0_C2_C1_0, {ia_lo}",
+        "msr S3_0_C2_C1_1, {ia_hi}",
+        "msr S3_0_C2_C1_2, {ib_lo}",
+        "msr S3_0_C2_C1_3, {ib_hi}",
+        "msr S3_0_C2_C2_0, {da_lo}",
+        "msr S3_0_C2_C2_1, {da_hi}",
+        "msr S3_0_C2_C2_2, {db_lo}",
+        "msr S3_0_C2_C2_3, {db_hi}",
+        "msr S3_0_C2_C3_0, {ga_lo}",
+        "msr S3_0_C2_C3_1, {ga_hi}",
+        "isb",
+        ia_lo = in(reg) keys.ia[0],
+        ia_hi = in(reg) keys.ia[1],
+        ib_lo = in(reg) keys.ib[0],
+        ib_hi = in(reg) keys.ib[1],
+        da_lo = in(reg) keys.da[0],
+        da_hi = in(reg) keys.da[1],
+        db_lo = in(reg) keys.db[0],
+        db_hi = in(reg) keys.db[1],
+        ga_lo = in(reg) keys.ga[0],
+        ga_hi = in(reg) keys.ga[1],
+        options(nostack),
+    );
+}
+
+/// Mark `flags` as guarded, for an address space using BTI. Only executable pages are affected by
+/// the guard, but it is also set on other pages in case they are made executable later.
+pub fn guard(flags: PageFlags<RmmA>) -> PageFlags<RmmA> {
+    flags.custom_flag(EntryFlags::GUARDED.bits(), true)
+}
@@ -0,0 +1,152 @@
+//! The Power State Coordination Interface, through which the firmware starts CPUs and powers the
+//! system off or resets it.
+//!
+//! Calls go through the conduit named by the `method` of the `/psci` device tree node, either
+//! `hvc` to a hypervisor or `smc` to the secure monitor. PSCI 0.1 firmware only provides the
+//! functions whose IDs are listed in the node, which never include `SYSTEM_OFF` or
+//! `SYSTEM_RESET`.
+
+use core::arch::asm;
+
+use byteorder::{ByteOrder, BE};
+use fdt::{DeviceTree, Node};
+use spin::Once;
+
+use crate::dtb::DTB_BINARY;
+
+const PSCI_VERSION: u32 = 0x8400_0000;
+const PSCI_SYSTEM_OFF: u32 = 0x8400_0008;
+const PSCI_SYSTEM_RESET: u32 = 0x8400_0009;
+/// `CPU_ON` of PSCI 0.2 and later, using the SMC64 calling convention.
+const PSCI_CPU_ON_64: u32 = 0xC400_0003;
+
+pub const PSCI_SUCCESS: i64 = 0;
+pub const PSCI_ALREADY_ON: i64 = -4;
+
+static PSCI: Once<Psci> = Once::new();
+
+#[derive(Clone, Copy, Debug)]
+enum Conduit {
+    Hvc,
+    Smc,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Psci {
+    conduit: Conduit,
+    /// Whether the firmware implements PSCI 0.2 or later, with the standard function IDs.
+    standard: bool,
+    cpu_on: Option<u32>,
+}
+
+impl Psci {
+    fn parse(dt: &DeviceTree) -> Option<Self> {
+        let (node, _) = dt.find_node("/psci")?;
+        let conduit = match property_str(&node, "method")? {
+            "hvc" => Conduit::Hvc,
+            "smc" => Conduit::Smc,
+            _ => return None,
+        };
+        let standard = property(&node, "compatible")?
+            .split(|&b| b == 0)
+            .any(|compat| compat == b"arm,psci-0.2" || compat == b"arm,psci-1.0");
+        let cpu_on = if standard {
+            Some(PSCI_CPU_ON_64)
+        } else {
+            property(&node, "cpu_on")
+                .filter(|data| data.len() >= 4)
+                .map(BE::read_u32)
+        };
+        Some(Self {
+            conduit,
+            standard,
+            cpu_on,
+        })
+    }
+
+    /// Call the firmware function `function` with up to three arguments, returning `x0`.
+    pub unsafe fn call(&self, function: u32, arg1: u64, arg2: u64, arg3: u64) -> i64 {
+        let mut ret = u64::from(function);
+        match self.conduit {
+            Conduit::Hvc => asm!(
+                "hvc #0",
+                inout("x0") ret,
+                in("x1") arg1,
+                in("x2") arg2,
+                in("x3") arg3,
+                clobber_abi("C"),
+            ),
+            Conduit::Smc => asm!(
+                "smc #0",
+                inout("x0") ret,
+                in("x1") arg1,
+                in("x2") arg2,
+                in("x3") arg3,
+                clobber_abi("C"),
+            ),
+        }
+        ret as i64
+    }
+
+    /// Start the CPU with affinity `mpidr` at the physical address `entry`, with the MMU off.
+    /// Returns `None` if the firmware cannot start CPUs.
+    pub unsafe fn cpu_on(&self, mpidr: u64, entry: usize) -> Option<i64> {
+        let function = self.cpu_on?;
+        Some(self.call(function, mpidr, entry as u64, 0))
+    }
+
+    /// Power off the system. Only returns if the firmware does not implement it.
+    pub unsafe fn system_off(&self) {
+        if self.standard {
+            self.call(PSCI_SYSTEM_OFF, 0, 0, 0);
+        }
+    }
+
+    /// Reset the system. Only returns if the firmware does not implement it.
+    pub unsafe fn system_reset(&self) {
+        if self.standard {
+            self.call(PSCI_SYSTEM_RESET, 0, 0, 0);
+        }
+    }
+}
+
+fn property<'a>(node: &'a Node, name: &str) -> Option<&'a [u8]> {
+    node.properties().find(|p| p.name == name).map(|p| p.data)
+}
+
+fn property_str<'a>(node: &'a Node, name: &str) -> Option<&'a str> {
+    core::str::from_utf8(property(node, name)?)
+        .ok()
+        .map(|s| s.trim_end_matches('\0'))
+}
+
+/// Find the PSCI firmware in the device tree.
+pub fn init() {
+    let Some(data) = DTB_BINARY.get() else {
+        return;
+    };
+    let Ok(dt) = DeviceTree::new(data) else {
+        return;
+    };
+    let Some(psci) = Psci::parse(&dt) else {
+        log::info!("PSCI: no /psci node");
+        return;
+    };
+    let psci = PSCI.call_once(|| psci);
+    if psci.standard {
+        let version = unsafe { psci.call(PSCI_VERSION, 0, 0, 0) } as u32;
+        log::info!(
+            "PSCI: {}.{} through {:?}",
+            version >> 16,
+            version & 0xFFFF,
+            psci.conduit
+        );
+    } else {
+        log::info!("PSCI: 0.1 through {:?}", psci.conduit);
+    }
+}
+
+/// The PSCI firmware, if the device tree describes one.
+pub fn get() -> Option<&'static Psci> {
+    PSCI.get()
+}
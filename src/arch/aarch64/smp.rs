@@ -0,0 +1,400 @@
+//! Starting the secondary CPUs described by the device tree.
+//!
+//! Each CPU node under `/cpus` names its `enable-method`, either `psci`, where the CPU is started
+//! by a `CPU_ON` call to the firmware through [`psci`], or `spin-table`, where the CPU waits in
+//! firmware for an entry address to be written to its `cpu-release-addr`. Both enter [`ap_entry`]
+//! at its physical address with the MMU off, which loads the translation registers of the BSP and
+//! continues in [`kstart_ap`] on its own stack.
+//!
+//! CPUs are started one at a time, so the trampoline parameters are shared by all of them.
+
+use core::{
+    arch::{asm, global_asm},
+    mem::offset_of,
+    sync::atomic::Ordering,
+};
+
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, BE};
+use fdt::{DeviceTree, Node};
+
+use super::{
+    psci::{self, PSCI_ALREADY_ON, PSCI_SUCCESS},
+    start::{kstart_ap, KernelArgsAp, AP_READY, CPU_COUNT, KERNEL_BASE},
+};
+use crate::{
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    device::cpu::registers::control_regs,
+    dtb::DTB_BINARY,
+    interrupt,
+    memory::{allocate_frame, allocate_p2frame, deallocate_frame, deallocate_p2frame, Frame},
+    paging::{
+        KernelMapper, Page, PageFlags, PhysicalAddress, RmmA, RmmArch, VirtualAddress, PAGE_SIZE,
+    },
+};
+
+/// Affinity fields of `MPIDR_EL1`, which are what the `reg` of a CPU node holds.
+const MPIDR_AFFINITY_MASK: u64 = 0xFF_00FF_FFFF;
+
+/// Order of the kernel stack allocated for each AP.
+const STACK_ORDER: u32 = 4;
+
+/// How long to wait for a started AP to report that it is ready, in milliseconds.
+const READY_TIMEOUT_MS: u64 = 1000;
+
+/// Block descriptor of the identity map, for normal write-back memory (MAIR index 0), inner
+/// shareable, with the access flag set.
+const IDENTITY_BLOCK_FLAGS: u64 = (1 << 10) | (0b11 << 8) | 0b01;
+const TABLE_DESCRIPTOR: u64 = 0b11;
+const BLOCK_SIZE: usize = 1 << 30;
+
+/// Registers loaded by [`ap_entry`] before enabling the MMU, copied from the BSP.
+#[repr(C)]
+struct Trampoline {
+    mair: u64,
+    tcr: u64,
+    /// Identity map of the trampoline, needed for the instructions following the MMU enable.
+    ttbr0: u64,
+    ttbr1: u64,
+    sctlr: u64,
+    stack_end: u64,
+    /// Virtual address of [`kstart_ap`].
+    entry: u64,
+    /// Virtual address of [`AP_ARGS`].
+    args: u64,
+}
+
+static mut TRAMPOLINE: Trampoline = Trampoline {
+    mair: 0,
+    tcr: 0,
+    ttbr0: 0,
+    ttbr1: 0,
+    sctlr: 0,
+    stack_end: 0,
+    entry: 0,
+    args: 0,
+};
+
+static mut AP_ARGS: KernelArgsAp = KernelArgsAp {
+    cpu_id: 0,
+    page_table: 0,
+    stack_start: 0,
+    stack_end: 0,
+};
+
+extern "C" {
+    fn ap_entry();
+}
+
+// Entered at its physical address with the MMU and caches off, so the parameters are read through
+// a PC-relative address, and must have been cleaned to the point of coherency.
+global_asm!(
+    "
+    .pushsection .text
+    .balign 4
+    .global ap_entry
+ap_entry:
+    adrp x9, {trampoline}
+    add x9, x9, :lo12:{trampoline}
+
+    ldr x10, [x9, #{mair}]
+    msr mair_el1, x10
+    ldr x10, [x9, #{tcr}]
+    msr tcr_el1, x10
+    ldr x10, [x9, #{ttbr0}]
+    msr ttbr0_el1, x10
+    ldr x10, [x9, #{ttbr1}]
+    msr ttbr1_el1, x10
+    isb
+    tlbi vmalle1
+    dsb nsh
+
+    ldr x11, [x9, #{stack_end}]
+    ldr x12, [x9, #{entry}]
+    ldr x0, [x9, #{args}]
+
+    ldr x10, [x9, #{sctlr}]
+    msr sctlr_el1, x10
+    isb
+
+    mov sp, x11
+    br x12
+    .popsection
+    ",
+    trampoline = sym TRAMPOLINE,
+    mair = const offset_of!(Trampoline, mair),
+    tcr = const offset_of!(Trampoline, tcr),
+    ttbr0 = const offset_of!(Trampoline, ttbr0),
+    ttbr1 = const offset_of!(Trampoline, ttbr1),
+    sctlr = const offset_of!(Trampoline, sctlr),
+    stack_end = const offset_of!(Trampoline, stack_end),
+    entry = const offset_of!(Trampoline, entry),
+    args = const offset_of!(Trampoline, args),
+);
+
+#[derive(Clone, Copy, Debug)]
+enum EnableMethod {
+    Psci,
+    SpinTable { release_addr: usize },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Cpu {
+    mpidr: u64,
+    enable_method: EnableMethod,
+}
+
+fn property<'a>(node: &'a Node, name: &str) -> Option<&'a [u8]> {
+    node.properties().find(|p| p.name == name).map(|p| p.data)
+}
+
+fn property_str<'a>(node: &'a Node, name: &str) -> Option<&'a str> {
+    core::str::from_utf8(property(node, name)?)
+        .ok()
+        .map(|s| s.trim_end_matches('\0'))
+}
+
+/// Read a number of one or two cells.
+fn read_cells(data: &[u8]) -> Option<u64> {
+    match data.len() {
+        4 => Some(BE::read_u32(data).into()),
+        8 => Some(BE::read_u64(data)),
+        _ => None,
+    }
+}
+
+fn parse_cpus(dt: &DeviceTree) -> Vec<Cpu> {
+    let mut cpus = Vec::new();
+    for node in dt.nodes().filter(|node| node.name.starts_with("cpu@")) {
+        if property_str(&node, "device_type") != Some("cpu") {
+            continue;
+        }
+        if property_str(&node, "status").map_or(false, |status| status != "okay") {
+            continue;
+        }
+        let Some(mpidr) = property(&node, "reg").and_then(read_cells) else {
+            log::warn!("{}: no usable reg", node.name);
+            continue;
+        };
+        let enable_method = match property_str(&node, "enable-method") {
+            Some("psci") => EnableMethod::Psci,
+            Some("spin-table") => {
+                let Some(release_addr) = property(&node, "cpu-release-addr").and_then(read_cells)
+                else {
+                    log::warn!("{}: spin-table without cpu-release-addr", node.name);
+                    continue;
+                };
+                EnableMethod::SpinTable {
+                    release_addr: release_addr as usize,
+                }
+            }
+            method => {
+                log::warn!("{}: unsupported enable-method {:?}", node.name, method);
+                continue;
+            }
+        };
+        cpus.push(Cpu {
+            mpidr,
+            enable_method,
+        });
+    }
+    cpus
+}
+
+/// Clean the data cache for `len` bytes at `addr` to the point of coherency, for the CPUs running
+/// with their caches off.
+unsafe fn clean_dcache(addr: usize, len: usize) {
+    let ctr: u64;
+    asm!("mrs {}, ctr_el0", out(reg) ctr);
+    let line = 4 << ((ctr >> 16) & 0xF);
+    let mut line_addr = addr & !(line - 1);
+    while line_addr < addr + len {
+        asm!("dc cvac, {}", in(reg) line_addr);
+        line_addr += line;
+    }
+    asm!("dsb sy");
+}
+
+/// The physical address of a kernel symbol.
+fn kernel_phys(virt: usize) -> usize {
+    virt - crate::KERNEL_OFFSET + KERNEL_BASE.load(Ordering::SeqCst)
+}
+
+/// Build the identity map of the 1 GiB block containing `phys`, returning its root table and the
+/// table it points to.
+unsafe fn identity_map(phys: usize) -> [Frame; 2] {
+    let l0 = allocate_frame().expect("failed to allocate AP identity map");
+    let l1 = allocate_frame().expect("failed to allocate AP identity map");
+    let l0_virt = RmmA::phys_to_virt(l0.start_address()).data() as *mut u64;
+    let l1_virt = RmmA::phys_to_virt(l1.start_address()).data() as *mut u64;
+    l0_virt.write_bytes(0, PAGE_SIZE / 8);
+    l1_virt.write_bytes(0, PAGE_SIZE / 8);
+
+    l0_virt
+        .add((phys >> 39) & 0o777)
+        .write(l1.start_address().data() as u64 | TABLE_DESCRIPTOR);
+    l1_virt
+        .add((phys >> 30) & 0o777)
+        .write((phys & !(BLOCK_SIZE - 1)) as u64 | IDENTITY_BLOCK_FLAGS);
+
+    clean_dcache(l0_virt as usize, PAGE_SIZE);
+    clean_dcache(l1_virt as usize, PAGE_SIZE);
+    [l0, l1]
+}
+
+/// The translation table the BSP uses for lower addresses, loaded by each AP in place of the
+/// identity map once it runs at kernel addresses.
+static mut USER_TABLE: u64 = 0;
+
+/// Switch the current AP from the identity map of the trampoline to [`USER_TABLE`], so that the
+/// identity map can be freed.
+pub unsafe fn leave_identity_map() {
+    control_regs::ttbr0_el1_write(USER_TABLE);
+    asm!("isb", "tlbi vmalle1", "dsb nsh", "isb");
+}
+
+/// Write the entry address to the `cpu-release-addr` of a spin-table CPU, and wake it up.
+unsafe fn release_spin_table(release_addr: usize, entry: usize) {
+    let virt = VirtualAddress::new(crate::PHYS_OFFSET + release_addr);
+    {
+        let mut mapper = KernelMapper::lock();
+        let mapper = mapper
+            .get_mut()
+            .expect("expected kernel page table not to be recursively locked while starting APs");
+        // The release address is usually in memory reserved by the firmware, which is not in the
+        // physical map.
+        if mapper.translate(virt).is_none() {
+            let frame = Frame::containing_address(PhysicalAddress::new(release_addr));
+            mapper
+                .map_phys(
+                    Page::containing_address(virt).start_address(),
+                    frame.start_address(),
+                    PageFlags::new().write(true),
+                )
+                .expect("failed to map cpu-release-addr")
+                .flush();
+        }
+    }
+
+    (virt.data() as *mut u64).write_volatile(entry as u64);
+    clean_dcache(virt.data(), 8);
+    asm!("sev");
+}
+
+/// Wait for the AP being started to set [`AP_READY`], returning false on timeout.
+fn wait_ready() -> bool {
+    let (freq, start) = unsafe { (control_regs::cntfreq_el0(), control_regs::cntpct_el0()) };
+    let timeout = u64::from(freq) * READY_TIMEOUT_MS / 1000;
+    while !AP_READY.load(Ordering::SeqCst) {
+        if unsafe { control_regs::cntpct_el0() } - start > timeout {
+            return false;
+        }
+        interrupt::pause();
+    }
+    true
+}
+
+/// Start `cpu` as the next logical CPU. Returns false if it was released but never became ready,
+/// in which case it may still enter the trampoline later, so no further CPU can be started.
+unsafe fn start_ap(cpu: &Cpu, entry: usize) -> bool {
+    let cpu_id = LogicalCpuId::new(CPU_COUNT.load(Ordering::SeqCst));
+
+    // Allocate a stack
+    let stack_frame = allocate_p2frame(STACK_ORDER).expect("no more frames for AP stack");
+    let stack_start = stack_frame.start_address().data() + crate::PHYS_OFFSET;
+    let stack_end = stack_start + (PAGE_SIZE << STACK_ORDER);
+
+    AP_ARGS.cpu_id = cpu_id.get().into();
+    AP_ARGS.stack_start = stack_start as u64;
+    AP_ARGS.stack_end = stack_end as u64;
+    TRAMPOLINE.stack_end = stack_end as u64;
+    clean_dcache(
+        core::ptr::addr_of!(TRAMPOLINE) as usize,
+        core::mem::size_of::<Trampoline>(),
+    );
+    AP_READY.store(false, Ordering::SeqCst);
+
+    log::info!(
+        "AP {:#x} (CPU {}): {:?}",
+        cpu.mpidr,
+        cpu_id,
+        cpu.enable_method
+    );
+
+    match cpu.enable_method {
+        EnableMethod::Psci => {
+            let Some(ret) = psci::get().and_then(|psci| psci.cpu_on(cpu.mpidr, entry)) else {
+                log::warn!("  no PSCI CPU_ON");
+                deallocate_p2frame(stack_frame, STACK_ORDER);
+                return true;
+            };
+            if ret != PSCI_SUCCESS {
+                if ret == PSCI_ALREADY_ON {
+                    log::warn!("  already on");
+                } else {
+                    log::warn!("  CPU_ON failed: {}", ret);
+                }
+                deallocate_p2frame(stack_frame, STACK_ORDER);
+                return true;
+            }
+        }
+        EnableMethod::SpinTable { release_addr } => release_spin_table(release_addr, entry),
+    }
+
+    if !wait_ready() {
+        log::warn!("  did not become ready");
+        return false;
+    }
+    CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+    log::info!("  ready");
+    true
+}
+
+/// Start all CPUs described by the device tree, other than the current one.
+pub unsafe fn init() {
+    if !cfg!(feature = "multi_core") {
+        return;
+    }
+    let Some(data) = DTB_BINARY.get() else {
+        return;
+    };
+    let Ok(dt) = DeviceTree::new(data) else {
+        return;
+    };
+    let cpus = parse_cpus(&dt);
+    let me = control_regs::mpidr() & MPIDR_AFFINITY_MASK;
+
+    let entry = kernel_phys(ap_entry as usize);
+    let identity_tables = identity_map(entry);
+    USER_TABLE = control_regs::ttbr0_el1();
+    TRAMPOLINE = Trampoline {
+        mair: control_regs::mair_el1().bits(),
+        tcr: control_regs::tcr_el1(),
+        ttbr0: identity_tables[0].start_address().data() as u64,
+        ttbr1: control_regs::ttbr1_el1(),
+        sctlr: control_regs::sctlr_el1(),
+        stack_end: 0,
+        entry: kstart_ap as u64,
+        args: core::ptr::addr_of!(AP_ARGS) as u64,
+    };
+    AP_ARGS.page_table = control_regs::ttbr1_el1();
+
+    for cpu in cpus.iter() {
+        if cpu.mpidr == me {
+            continue;
+        }
+        if CPU_COUNT.load(Ordering::SeqCst) >= MAX_CPU_COUNT {
+            log::warn!("Too many CPUs, not starting {:#x}", cpu.mpidr);
+            break;
+        }
+        if !start_ap(cpu, entry) {
+            // The CPU may still enter the trampoline later, so the identity map is kept.
+            return;
+        }
+    }
+
+    // Every AP that was started has left the identity map.
+    for frame in identity_tables {
+        deallocate_frame(frame);
+    }
+}
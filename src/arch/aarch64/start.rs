@@ -65,10 +65,8 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
         KERNEL_BASE.store(args.kernel_base, Ordering::SeqCst);
         KERNEL_SIZE.store(args.kernel_size, Ordering::SeqCst);
 
-        if args.dtb_base != 0 {
-            // Try to find serial port prior to logging
-            device::serial::init_early(crate::PHYS_OFFSET + args.dtb_base, args.dtb_size);
-        }
+        // Buffer log records until the consoles are set up
+        log::init_early_logger();
 
         // Convert env to slice
         let env = slice::from_raw_parts(
@@ -76,6 +74,12 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
             args.env_size,
         );
 
+        // Try to find serial port prior to logging
+        device::serial::init_early(
+            env,
+            (args.dtb_base != 0).then(|| (crate::PHYS_OFFSET + args.dtb_base, args.dtb_size)),
+        );
+
         // Set up graphical debug
         //#[cfg(feature = "graphical_debug")]
         //graphical_debug::init(env);
@@ -83,11 +87,10 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
         // Initialize logger
         log::init_logger(|r| {
             use core::fmt::Write;
-            let _ = write!(
-                crate::debug::Writer::new(),
-                "{}:{} -- {}\n",
+            let _ = writeln!(
+                crate::debug::Writer::with_tag(log::Tag::of(r)),
+                "{} -- {}",
                 r.target(),
-                r.level(),
                 r.args()
             );
         });
@@ -142,6 +145,9 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
         let env_size = device_tree::fill_env_data(crate::PHYS_OFFSET + dtb_base, dtb_size, env_base);
         */
 
+        // Keep the panic record range out of the memory map
+        crate::pstore::reserve(env);
+
         // Initialize RMM
         crate::arch::rmm::init(
             args.kernel_base,
@@ -163,6 +169,13 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
 
         crate::misc::init(crate::cpu_set::LogicalCpuId::new(0));
 
+        crate::arch::sve::init();
+        crate::arch::pauth::init();
+        crate::arch::mte::init();
+
+        // Tag log output with the CPU and context
+        log::init_percpu();
+
         // Reset AP variables
         CPU_COUNT.store(1, Ordering::SeqCst);
         AP_READY.store(false, Ordering::SeqCst);
@@ -175,17 +188,20 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
         //#[cfg(feature = "graphical_debug")]
         //graphical_debug::init_heap();
 
-        // Activate memory logging
-        log::init();
-
         dtb::init(Some((crate::PHYS_OFFSET + args.dtb_base, args.dtb_size)));
 
+        // Find the firmware interface used to start the APs and to power off
+        crate::arch::psci::init();
+
         // Initialize devices
         device::init();
 
         // Initialize all of the non-core devices not otherwise needed to complete initialization
         device::init_noncore();
 
+        // Start the APs
+        crate::arch::smp::init();
+
         // Stop graphical debug
         //#[cfg(feature = "graphical_debug")]
         //graphical_debug::fini();
@@ -206,13 +222,52 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
 
 #[repr(packed)]
 pub struct KernelArgsAp {
-    cpu_id: u64,
-    page_table: u64,
-    stack_start: u64,
-    stack_end: u64,
+    pub(super) cpu_id: u64,
+    pub(super) page_table: u64,
+    pub(super) stack_start: u64,
+    pub(super) stack_end: u64,
 }
 
-/// Entry to rust for an AP
+/// Entry to rust for an AP, from the trampoline which already loaded the kernel page table and
+/// switched to the AP stack
 pub unsafe extern "C" fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
-    loop {}
+    let cpu_id = {
+        let args = &*args_ptr;
+        let cpu_id = crate::cpu_set::LogicalCpuId::new(args.cpu_id as u32);
+
+        assert_eq!(BSS_TEST_ZERO, 0);
+        assert_eq!(DATA_TEST_NONZERO, 0xFFFF_FFFF_FFFF_FFFF);
+
+        // Setup interrupt handlers
+        core::arch::asm!(
+            "
+            ldr {tmp}, =exception_vector_base
+            msr vbar_el1, {tmp}
+            ",
+            tmp = out(reg) _,
+        );
+
+        // Initialize paging
+        paging::init();
+        crate::arch::smp::leave_identity_map();
+
+        crate::misc::init(cpu_id);
+
+        crate::arch::sve::init();
+        crate::arch::pauth::init();
+        crate::arch::mte::init();
+
+        // Initialize devices (for AP)
+        device::init_ap();
+
+        AP_READY.store(true, Ordering::SeqCst);
+
+        cpu_id
+    };
+
+    while !BSP_READY.load(Ordering::SeqCst) {
+        interrupt::pause();
+    }
+
+    crate::kmain_ap(cpu_id);
 }
@@ -1,23 +1,40 @@
-use core::arch::asm;
+use crate::{arch::psci, interrupt};
+
+/// Halt the current CPU forever, once the firmware could not power off or reset.
+unsafe fn halt() -> ! {
+    log::info!("HALT");
+    loop {
+        interrupt::disable();
+        interrupt::halt();
+    }
+}
 
 pub unsafe fn kreset() -> ! {
-    println!("kreset");
+    log::info!("kreset");
 
-    let val: u32 = 0x8400_0009;
-    asm!("mov   x0, {}", in(reg) val);
-    asm!("hvc   #0", options(noreturn));
+    emergency_reset();
 }
 
 pub unsafe fn emergency_reset() -> ! {
-    let val: u32 = 0x8400_0009;
-    asm!("mov   x0, {}", in(reg) val);
-    asm!("hvc   #0", options(noreturn));
+    match psci::get() {
+        Some(psci) => {
+            psci.system_reset();
+            log::warn!("PSCI SYSTEM_RESET not supported");
+        }
+        None => log::warn!("No PSCI firmware to reset with"),
+    }
+    halt();
 }
 
 pub unsafe fn kstop() -> ! {
-    println!("kstop");
+    log::info!("kstop");
 
-    let val: u32 = 0x8400_0008;
-    asm!("mov   x0, {}", in(reg) val);
-    asm!("hvc   #0", options(noreturn));
+    match psci::get() {
+        Some(psci) => {
+            psci.system_off();
+            log::warn!("PSCI SYSTEM_OFF not supported");
+        }
+        None => log::warn!("No PSCI firmware to power off with"),
+    }
+    halt();
 }
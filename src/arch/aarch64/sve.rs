@@ -0,0 +1,301 @@
+//! The Scalable Vector Extension.
+//!
+//! SVE widens the 32 FP/SIMD registers to an implementation defined vector length of up to 2048
+//! bits, and adds 16 predicate registers and the first fault register. As most contexts never use
+//! SVE, it starts out trapped for every context, and the state is only allocated and switched from
+//! the first SVE instruction of a context onwards. Until then, only the low 128 bits of each
+//! vector, which alias the FP/SIMD registers, are part of the context.
+//!
+//! The saved state is the Z registers followed by the P registers and FFR, in the format of the
+//! `str` and `ldr` instructions, which is also what `proc:<pid>/regs/sve` exchanges after the
+//! vector length.
+
+use alloc::vec;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{context, syscall::FloatRegisters};
+
+/// `ID_AA64PFR0_EL1.SVE`, nonzero when SVE is implemented.
+const PFR0_SVE_SHIFT: u64 = 32;
+/// `CPACR_EL1.ZEN`, where `0b11` stops trapping SVE at EL0 and EL1.
+const CPACR_ZEN: u64 = 0b11 << 16;
+/// `ZCR_EL1.LEN` asking for the longest vector length, which is then constrained by the CPU and
+/// the higher exception levels.
+const ZCR_LEN_MAX: u64 = 0xF;
+
+/// Vector length in bytes, or 0 if SVE is not implemented.
+static VECTOR_LENGTH: AtomicUsize = AtomicUsize::new(0);
+
+unsafe fn cpacr_el1() -> u64 {
+    let ret: u64;
+    asm!("mrs {}, cpacr_el1", out(reg) ret);
+    ret
+}
+
+unsafe fn cpacr_el1_write(val: u64) {
+    asm!("msr cpacr_el1, {}", "isb", in(reg) val);
+}
+
+/// Enable SVE up to its longest vector length on the current CPU, leaving it trapped.
+pub unsafe fn init() {
+    let pfr0: u64;
+    asm!("mrs {}, id_aa64pfr0_el1", out(reg) pfr0);
+    if (pfr0 >> PFR0_SVE_SHIFT) & 0xF == 0 {
+        return;
+    }
+
+    enable();
+    // ZCR_EL1, which the assembler only knows by name with SVE enabled.
+    asm!("msr S3_0_C1_C2_0, {}", "isb", in(reg) ZCR_LEN_MAX);
+    let vl: usize;
+    asm!(".arch_extension sve", "rdvl {}, #1", out(reg) vl);
+    disable();
+
+    // The APs are expected to support the vector length of the BSP.
+    if VECTOR_LENGTH.swap(vl, Ordering::Relaxed) == 0 {
+        log::info!("SVE: {}-bit vectors", vl * 8);
+    }
+}
+
+/// Vector length in bytes, if SVE is implemented.
+pub fn vector_length() -> Option<usize> {
+    match VECTOR_LENGTH.load(Ordering::Relaxed) {
+        0 => None,
+        vl => Some(vl),
+    }
+}
+
+/// Size of the saved state for a vector length of `vl` bytes: 32 vectors, then 16 predicates and
+/// FFR of `vl / 8` bytes each.
+pub fn state_size(vl: usize) -> usize {
+    32 * vl + 17 * (vl / 8)
+}
+
+/// Stop trapping SVE on the current CPU.
+pub unsafe fn enable() {
+    cpacr_el1_write(cpacr_el1() | CPACR_ZEN);
+}
+
+/// Trap SVE on the current CPU.
+pub unsafe fn disable() {
+    cpacr_el1_write(cpacr_el1() & !CPACR_ZEN);
+}
+
+/// Save the SVE registers of the current CPU to `state`. SVE must be enabled.
+pub unsafe fn save(state: &mut [u8]) {
+    let vl = VECTOR_LENGTH.load(Ordering::Relaxed);
+    assert_eq!(state.len(), state_size(vl));
+    let z = state.as_mut_ptr();
+    let p = z.add(32 * vl);
+    asm!(
+        ".arch_extension sve",
+        "str z0, [{z}, #0, mul vl]",
+        "str z1, [{z}, #1, mul vl]",
+        "str z2, [{z}, #2, mul vl]",
+        "str z3, [{z}, #3, mul vl]",
+        "str z4, [{z}, #4, mul vl]",
+        "str z5, [{z}, #5, mul vl]",
+        "str z6, [{z}, #6, mul vl]",
+        "str z7, [{z}, #7, mul vl]",
+        "str z8, [{z}, #8, mul vl]",
+        "str z9, [{z}, #9, mul vl]",
+        "str z10, [{z}, #10, mul vl]",
+        "str z11, [{z}, #11, mul vl]",
+        "str z12, [{z}, #12, mul vl]",
+        "str z13, [{z}, #13, mul vl]",
+        "str z14, [{z}, #14, mul vl]",
+        "str z15, [{z}, #15, mul vl]",
+        "str z16, [{z}, #16, mul vl]",
+        "str z17, [{z}, #17, mul vl]",
+        "str z18, [{z}, #18, mul vl]",
+        "str z19, [{z}, #19, mul vl]",
+        "str z20, [{z}, #20, mul vl]",
+        "str z21, [{z}, #21, mul vl]",
+        "str z22, [{z}, #22, mul vl]",
+        "str z23, [{z}, #23, mul vl]",
+        "str z24, [{z}, #24, mul vl]",
+        "str z25, [{z}, #25, mul vl]",
+        "str z26, [{z}, #26, mul vl]",
+        "str z27, [{z}, #27, mul vl]",
+        "str z28, [{z}, #28, mul vl]",
+        "str z29, [{z}, #29, mul vl]",
+        "str z30, [{z}, #30, mul vl]",
+        "str z31, [{z}, #31, mul vl]",
+        "str p0, [{p}, #0, mul vl]",
+        "str p1, [{p}, #1, mul vl]",
+        "str p2, [{p}, #2, mul vl]",
+        "str p3, [{p}, #3, mul vl]",
+        "str p4, [{p}, #4, mul vl]",
+        "str p5, [{p}, #5, mul vl]",
+        "str p6, [{p}, #6, mul vl]",
+        "str p7, [{p}, #7, mul vl]",
+        "str p8, [{p}, #8, mul vl]",
+        "str p9, [{p}, #9, mul vl]",
+        "str p10, [{p}, #10, mul vl]",
+        "str p11, [{p}, #11, mul vl]",
+        "str p12, [{p}, #12, mul vl]",
+        "str p13, [{p}, #13, mul vl]",
+        "str p14, [{p}, #14, mul vl]",
+        "str p15, [{p}, #15, mul vl]",
+        // FFR can only be stored through a predicate register, which has already been saved.
+        "rdffr p0.b",
+        "str p0, [{p}, #16, mul vl]",
+        "ldr p0, [{p}, #0, mul vl]",
+        z = in(reg) z,
+        p = in(reg) p,
+        options(nostack),
+    );
+}
+
+/// Load the SVE registers of the current CPU from `state`. SVE must be enabled.
+pub unsafe fn load(state: &[u8]) {
+    let vl = VECTOR_LENGTH.load(Ordering::Relaxed);
+    assert_eq!(state.len(), state_size(vl));
+    let z = state.as_ptr();
+    let p = z.add(32 * vl);
+    asm!(
+        ".arch_extension sve",
+        "ldr p0, [{p}, #16, mul vl]",
+        "wrffr p0.b",
+        "ldr p0, [{p}, #0, mul vl]",
+        "ldr p1, [{p}, #1, mul vl]",
+        "ldr p2, [{p}, #2, mul vl]",
+        "ldr p3, [{p}, #3, mul vl]",
+        "ldr p4, [{p}, #4, mul vl]",
+        "ldr p5, [{p}, #5, mul vl]",
+        "ldr p6, [{p}, #6, mul vl]",
+        "ldr p7, [{p}, #7, mul vl]",
+        "ldr p8, [{p}, #8, mul vl]",
+        "ldr p9, [{p}, #9, mul vl]",
+        "ldr p10, [{p}, #10, mul vl]",
+        "ldr p11, [{p}, #11, mul vl]",
+        "ldr p12, [{p}, #12, mul vl]",
+        "ldr p13, [{p}, #13, mul vl]",
+        "ldr p14, [{p}, #14, mul vl]",
+        "ldr p15, [{p}, #15, mul vl]",
+        "ldr z0, [{z}, #0, mul vl]",
+        "ldr z1, [{z}, #1, mul vl]",
+        "ldr z2, [{z}, #2, mul vl]",
+        "ldr z3, [{z}, #3, mul vl]",
+        "ldr z4, [{z}, #4, mul vl]",
+        "ldr z5, [{z}, #5, mul vl]",
+        "ldr z6, [{z}, #6, mul vl]",
+        "ldr z7, [{z}, #7, mul vl]",
+        "ldr z8, [{z}, #8, mul vl]",
+        "ldr z9, [{z}, #9, mul vl]",
+        "ldr z10, [{z}, #10, mul vl]",
+        "ldr z11, [{z}, #11, mul vl]",
+        "ldr z12, [{z}, #12, mul vl]",
+        "ldr z13, [{z}, #13, mul vl]",
+        "ldr z14, [{z}, #14, mul vl]",
+        "ldr z15, [{z}, #15, mul vl]",
+        "ldr z16, [{z}, #16, mul vl]",
+        "ldr z17, [{z}, #17, mul vl]",
+        "ldr z18, [{z}, #18, mul vl]",
+        "ldr z19, [{z}, #19, mul vl]",
+        "ldr z20, [{z}, #20, mul vl]",
+        "ldr z21, [{z}, #21, mul vl]",
+        "ldr z22, [{z}, #22, mul vl]",
+        "ldr z23, [{z}, #23, mul vl]",
+        "ldr z24, [{z}, #24, mul vl]",
+        "ldr z25, [{z}, #25, mul vl]",
+        "ldr z26, [{z}, #26, mul vl]",
+        "ldr z27, [{z}, #27, mul vl]",
+        "ldr z28, [{z}, #28, mul vl]",
+        "ldr z29, [{z}, #29, mul vl]",
+        "ldr z30, [{z}, #30, mul vl]",
+        "ldr z31, [{z}, #31, mul vl]",
+        z = in(reg) z,
+        p = in(reg) p,
+        options(nostack, readonly),
+    );
+}
+
+/// Clear all SVE state of the current CPU except the FP/SIMD registers. SVE must be enabled.
+unsafe fn flush_live() {
+    asm!(
+        ".arch_extension sve",
+        // Writing a vector through its FP/SIMD view zeroes the rest of it.
+        "mov v0.16b, v0.16b",
+        "mov v1.16b, v1.16b",
+        "mov v2.16b, v2.16b",
+        "mov v3.16b, v3.16b",
+        "mov v4.16b, v4.16b",
+        "mov v5.16b, v5.16b",
+        "mov v6.16b, v6.16b",
+        "mov v7.16b, v7.16b",
+        "mov v8.16b, v8.16b",
+        "mov v9.16b, v9.16b",
+        "mov v10.16b, v10.16b",
+        "mov v11.16b, v11.16b",
+        "mov v12.16b, v12.16b",
+        "mov v13.16b, v13.16b",
+        "mov v14.16b, v14.16b",
+        "mov v15.16b, v15.16b",
+        "mov v16.16b, v16.16b",
+        "mov v17.16b, v17.16b",
+        "mov v18.16b, v18.16b",
+        "mov v19.16b, v19.16b",
+        "mov v20.16b, v20.16b",
+        "mov v21.16b, v21.16b",
+        "mov v22.16b, v22.16b",
+        "mov v23.16b, v23.16b",
+        "mov v24.16b, v24.16b",
+        "mov v25.16b, v25.16b",
+        "mov v26.16b, v26.16b",
+        "mov v27.16b, v27.16b",
+        "mov v28.16b, v28.16b",
+        "mov v29.16b, v29.16b",
+        "mov v30.16b, v30.16b",
+        "mov v31.16b, v31.16b",
+        "pfalse p0.b",
+        "pfalse p1.b",
+        "pfalse p2.b",
+        "pfalse p3.b",
+        "pfalse p4.b",
+        "pfalse p5.b",
+        "pfalse p6.b",
+        "pfalse p7.b",
+        "pfalse p8.b",
+        "pfalse p9.b",
+        "pfalse p10.b",
+        "pfalse p11.b",
+        "pfalse p12.b",
+        "pfalse p13.b",
+        "pfalse p14.b",
+        "pfalse p15.b",
+        "wrffr p0.b",
+        options(nomem, nostack),
+    );
+}
+
+/// Handle the first SVE instruction of the current context, which trapped. Returns false if SVE
+/// is not implemented.
+pub unsafe fn handle_trap() -> bool {
+    let Some(vl) = vector_length() else {
+        return false;
+    };
+    let Ok(context_lock) = context::current() else {
+        return false;
+    };
+    let mut context = context_lock.write();
+    if context.arch.sve.is_none() {
+        context.arch.sve = Some(vec![0; state_size(vl)].into_boxed_slice());
+    }
+    // Keep the FP/SIMD registers, but none of the state left behind by the previous SVE user.
+    enable();
+    flush_live();
+    true
+}
+
+/// Copy the FP/SIMD registers into the low 128 bits of the vectors of `state`, as the vectors are
+/// loaded after them.
+pub fn set_fpsimd(state: &mut [u8], regs: &FloatRegisters) {
+    let vl = VECTOR_LENGTH.load(Ordering::Relaxed);
+    for (i, reg) in regs.fp_simd_regs.iter().enumerate() {
+        state[i * vl..][..16].copy_from_slice(&reg.to_le_bytes());
+    }
+}
@@ -2,3 +2,45 @@ pub fn counter() -> u128 {
     //TODO: aarch64 generic timer counter
     0
 }
+
+/// Period of the scheduler tick, which drives timeouts without a high-resolution timer.
+pub fn tick_period() -> u128 {
+    // The generic timer is reloaded at 100 Hz.
+    10_000_000
+}
+
+/// Resolution of the high-resolution timer in nanoseconds, or `None` if there is none.
+pub fn hrtimer_resolution() -> Option<u128> {
+    //TODO: use the compare value of the generic timer, once the counter is implemented
+    None
+}
+
+/// Program the high-resolution timer of the current CPU to fire at the monotonic time `deadline`.
+/// Returns false if there is no high-resolution timer.
+pub unsafe fn hrtimer_arm(_deadline: u128) -> bool {
+    false
+}
+
+/// Stop the scheduler tick of the current CPU, which has gone idle. Returns whether it was
+/// stopped, which is never on the BSP as its tick keeps the time.
+pub unsafe fn tick_stop_cpu() -> bool {
+    if crate::cpu_id() == crate::cpu_set::LogicalCpuId::BSP {
+        return false;
+    }
+    super::device::generic_timer::stop();
+    true
+}
+
+/// Restart the scheduler tick of the current CPU, stopped by [`tick_stop_cpu`].
+pub unsafe fn tick_restart_cpu() {
+    super::device::generic_timer::restart();
+}
+
+/// Stop the scheduler tick of the BSP once every CPU has gone idle. Returns whether it was
+/// stopped, which is never as its tick keeps the time.
+pub unsafe fn tick_stop_all() -> bool {
+    false
+}
+
+/// Restart the scheduler tick of the BSP, stopped by [`tick_stop_all`], from any CPU.
+pub unsafe fn tick_restart_all() {}
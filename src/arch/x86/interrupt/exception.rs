@@ -2,6 +2,7 @@ use rmm::TableKind;
 use x86::irq::PageFaultError;
 
 use crate::{
+    context::{context::FaultInfo, signal::record_fault},
     interrupt::stack_trace,
     ksignal,
     ptrace,
@@ -14,7 +15,7 @@ use crate::{
 };
 
 interrupt_stack!(divide_by_zero, |stack| {
-    println!("Divide by zero");
+    log::error!("Divide by zero");
     stack.dump();
     stack_trace();
     ksignal(SIGFPE);
@@ -29,7 +30,26 @@ interrupt_stack!(debug, @paranoid, |stack| {
     let had_singlestep = stack.iret.eflags & (1 << 8) == 1 << 8;
     stack.set_singlestep(false);
 
-    if ptrace::breakpoint_callback(PTRACE_STOP_SINGLESTEP, None).is_some() {
+    // Hardware breakpoints and watchpoints, set through proc:<pid>/regs/debug. The event carries
+    // the mask of triggered debug registers.
+    let hw_hits = crate::arch::hw_breakpoint::HwBreakpoints::take_hits();
+    if hw_hits != 0 {
+        // Set RF, so that instruction breakpoints don't immediately trigger again on return.
+        stack.iret.eflags |= 1 << 16;
+
+        // Watchpoints also trigger when the kernel accesses the watched user memory, e.g. in
+        // usercopy, which is not reported.
+        handled = stack.iret.cs & 3 != 3
+            || ptrace::breakpoint_callback(
+                PTRACE_STOP_BREAKPOINT,
+                Some(crate::syscall::ptrace_event!(PTRACE_STOP_BREAKPOINT, hw_hits, stack.iret.eip)),
+            )
+            .is_some();
+    }
+
+    if handled {
+        // The tracer decides whether to keep singlestepping.
+    } else if ptrace::breakpoint_callback(PTRACE_STOP_SINGLESTEP, None).is_some() {
         handled = true;
     } else {
         // There was no breakpoint, restore original value
@@ -37,15 +57,18 @@ interrupt_stack!(debug, @paranoid, |stack| {
     }
 
     if !handled {
-        println!("Debug trap");
+        log::error!("Debug trap");
         stack.dump();
         ksignal(SIGTRAP);
     }
 });
 
 interrupt_stack!(non_maskable, @paranoid, |stack| {
-    println!("Non-maskable interrupt");
-    stack.dump();
+    // The watchdog reports any lockup it detects by itself.
+    if !crate::device::watchdog::nmi(stack) {
+        log::error!("Non-maskable interrupt");
+        stack.dump();
+    }
 });
 
 interrupt_stack!(breakpoint, |stack| {
@@ -63,72 +86,78 @@ interrupt_stack!(breakpoint, |stack| {
     stack.iret.eip -= 1;
 
     if ptrace::breakpoint_callback(PTRACE_STOP_BREAKPOINT, None).is_none() {
-        println!("Breakpoint trap");
+        log::error!("Breakpoint trap");
         stack.dump();
         ksignal(SIGTRAP);
     }
 });
 
 interrupt_stack!(overflow, |stack| {
-    println!("Overflow trap");
+    log::error!("Overflow trap");
     stack.dump();
     stack_trace();
     ksignal(SIGFPE);
 });
 
 interrupt_stack!(bound_range, |stack| {
-    println!("Bound range exceeded fault");
+    log::error!("Bound range exceeded fault");
     stack.dump();
     stack_trace();
     ksignal(SIGSEGV);
 });
 
 interrupt_stack!(invalid_opcode, |stack| {
-    println!("Invalid opcode fault");
+    log::error!("Invalid opcode fault");
     stack.dump();
     stack_trace();
     ksignal(SIGILL);
 });
 
 interrupt_stack!(device_not_available, |stack| {
-    println!("Device not available fault");
+    log::error!("Device not available fault");
     stack.dump();
     stack_trace();
     ksignal(SIGILL);
 });
 
 interrupt_error!(double_fault, |stack| {
-    println!("Double fault");
+    log::error!("Double fault");
     stack.dump();
     stack_trace();
     ksignal(SIGSEGV);
 });
 
 interrupt_error!(invalid_tss, |stack| {
-    println!("Invalid TSS fault");
+    log::error!("Invalid TSS fault");
     stack.dump();
     stack_trace();
     ksignal(SIGSEGV);
 });
 
 interrupt_error!(segment_not_present, |stack| {
-    println!("Segment not present fault");
+    log::error!("Segment not present fault");
     stack.dump();
     stack_trace();
     ksignal(SIGSEGV);
 });
 
 interrupt_error!(stack_segment, |stack| {
-    println!("Stack segment fault");
+    log::error!("Stack segment fault");
     stack.dump();
     stack_trace();
     ksignal(SIGSEGV);
 });
 
 interrupt_error!(protection, |stack| {
-    println!("Protection fault");
+    log::error!("Protection fault");
     stack.dump();
     stack_trace();
+    record_fault(FaultInfo {
+        signal: SIGSEGV,
+        ip: stack.iret.eip,
+        address: None,
+        access: None,
+    });
     ksignal(SIGSEGV);
 });
 
@@ -159,7 +188,7 @@ interrupt_error!(page, |stack| {
     );
 
     if crate::memory::page_fault_handler(&mut stack.inner, generic_flags, cr2).is_err() {
-        println!("Page fault: {:>08X} {:#?}", cr2.data(), arch_flags);
+        log::error!("Page fault: {:>08X} {:#?}", cr2.data(), arch_flags);
         stack.dump();
         stack_trace();
         ksignal(SIGSEGV);
@@ -167,42 +196,55 @@ interrupt_error!(page, |stack| {
 });
 
 interrupt_stack!(fpu_fault, |stack| {
-    println!("FPU floating point fault");
+    log::error!("FPU floating point fault");
     stack.dump();
     stack_trace();
     ksignal(SIGFPE);
 });
 
 interrupt_error!(alignment_check, |stack| {
-    println!("Alignment check fault");
+    log::error!("Alignment check fault");
     stack.dump();
     stack_trace();
+    record_fault(FaultInfo {
+        signal: SIGBUS,
+        ip: stack.iret.eip,
+        address: None,
+        access: None,
+    });
     ksignal(SIGBUS);
 });
 
 interrupt_stack!(machine_check, @paranoid, |stack| {
-    println!("Machine check fault");
+    log::error!("Machine check fault");
     stack.dump();
     stack_trace();
     ksignal(SIGBUS);
 });
 
 interrupt_stack!(simd, |stack| {
-    println!("SIMD floating point fault");
+    log::error!("SIMD floating point fault");
     stack.dump();
     stack_trace();
     ksignal(SIGFPE);
 });
 
 interrupt_stack!(virtualization, |stack| {
-    println!("Virtualization fault");
+    log::error!("Virtualization fault");
     stack.dump();
     stack_trace();
     ksignal(SIGBUS);
 });
 
+interrupt_error!(control_protection, |stack| {
+    log::error!("Control protection fault");
+    stack.dump();
+    stack_trace();
+    ksignal(SIGSEGV);
+});
+
 interrupt_error!(security, |stack| {
-    println!("Security exception");
+    log::error!("Security exception");
     stack.dump();
     stack_trace();
     ksignal(SIGBUS);
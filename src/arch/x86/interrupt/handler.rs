@@ -14,9 +14,9 @@ pub struct ScratchRegisters {
 
 impl ScratchRegisters {
     pub fn dump(&self) {
-        println!("EAX:   {:08x}", { self.eax });
-        println!("ECX:   {:08x}", { self.ecx });
-        println!("EDX:   {:08x}", { self.edx });
+        log::error!("EAX:   {:08x}", { self.eax });
+        log::error!("ECX:   {:08x}", { self.ecx });
+        log::error!("EDX:   {:08x}", { self.edx });
     }
 }
 
@@ -31,10 +31,10 @@ pub struct PreservedRegisters {
 
 impl PreservedRegisters {
     pub fn dump(&self) {
-        println!("EBX:   {:08x}", { self.ebx });
-        println!("EDI:   {:08x}", { self.edi });
-        println!("ESI:   {:08x}", { self.esi });
-        println!("EBP:   {:08x}", { self.ebp });
+        log::error!("EBX:   {:08x}", { self.ebx });
+        log::error!("EDI:   {:08x}", { self.edi });
+        log::error!("ESI:   {:08x}", { self.esi });
+        log::error!("EBP:   {:08x}", { self.ebp });
     }
 }
 
@@ -55,13 +55,13 @@ pub struct IretRegisters {
 
 impl IretRegisters {
     pub fn dump(&self) {
-        println!("EFLAG: {:08x}", { self.eflags });
-        println!("CS:    {:08x}", { self.cs });
-        println!("EIP:   {:08x}", { self.eip });
+        log::error!("EFLAG: {:08x}", { self.eflags });
+        log::error!("CS:    {:08x}", { self.cs });
+        log::error!("EIP:   {:08x}", { self.eip });
 
         if self.cs & 0b11 != 0b00 {
-            println!("ESP:   {:08x}", { self.esp });
-            println!("SS:    {:08x}", { self.ss });
+            log::error!("ESP:   {:08x}", { self.esp });
+            log::error!("SS:    {:08x}", { self.ss });
         }
     }
 }
@@ -137,6 +137,9 @@ impl InterruptStack {
     pub fn set_syscall_ret_reg(&mut self, ret: usize) {
         self.scratch.eax = ret;
     }
+    pub fn syscall_ret_reg(&self) -> usize {
+        self.scratch.eax
+    }
     /// Loads all registers from a struct used by the proc:
     /// scheme to read/write registers.
     pub fn load(&mut self, all: &IntRegisters) {
@@ -190,7 +193,7 @@ pub struct InterruptErrorStack {
 
 impl InterruptErrorStack {
     pub fn dump(&self) {
-        println!("CODE:  {:08x}", { self.code });
+        log::error!("CODE:  {:08x}", { self.code });
         self.inner.dump();
     }
 }
@@ -329,6 +332,7 @@ macro_rules! interrupt {
         #[naked]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner() {
+                let _irq = $crate::context::cputime::IrqScope::enter();
                 $code
             }
 
@@ -10,9 +10,24 @@ pub unsafe fn init() {}
 
 macro_rules! with_interrupt_stack {
     (|$stack:ident| $code:block) => {{
-        let allowed = ptrace::breakpoint_callback(PTRACE_STOP_PRE_SYSCALL, None)
+        let entry_event = {
+            let scratch = &(*$stack).scratch;
+            let preserved = &(*$stack).preserved;
+            ptrace::syscall_entry_event([
+                scratch.eax,
+                preserved.ebx,
+                scratch.ecx,
+                scratch.edx,
+                preserved.esi,
+                preserved.edi,
+            ])
+        };
+        let allowed = ptrace::breakpoint_callback(PTRACE_STOP_PRE_SYSCALL, Some(entry_event))
             .and_then(|_| ptrace::next_breakpoint().map(|f| !f.contains(PTRACE_FLAG_IGNORE)));
 
+        // The tracer may have changed the registers while stopped.
+        let number = (*$stack).scratch.eax;
+
         if allowed.unwrap_or(true) {
             // If the syscall is `clone`, the clone won't return here. Instead,
             // it'll return early and leave any undropped values. This is
@@ -22,7 +37,13 @@ macro_rules! with_interrupt_stack {
             $code
         }
 
-        ptrace::breakpoint_callback(PTRACE_STOP_POST_SYSCALL, None);
+        ptrace::breakpoint_callback(
+            PTRACE_STOP_POST_SYSCALL,
+            Some(ptrace::syscall_exit_event(
+                number,
+                (*$stack).syscall_ret_reg(),
+            )),
+        );
     }};
 }
 
@@ -75,12 +75,18 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
         KERNEL_BASE.store(args.kernel_base as usize, Ordering::SeqCst);
         KERNEL_SIZE.store(args.kernel_size as usize, Ordering::SeqCst);
 
+        // Buffer log records until the consoles are set up
+        log::init_early_logger();
+
         // Convert env to slice
         let env = slice::from_raw_parts(
             (args.env_base as usize + crate::PHYS_OFFSET) as *const u8,
             args.env_size as usize,
         );
 
+        // Select the serial console
+        device::serial::init_console(env);
+
         // Set up graphical debug
         #[cfg(feature = "graphical_debug")]
         graphical_debug::init(env);
@@ -92,10 +98,9 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
         log::init_logger(|r| {
             use core::fmt::Write;
             let _ = writeln!(
-                super::debug::Writer::new(),
-                "{}:{} -- {}",
+                super::debug::Writer::with_tag(log::Tag::of(r)),
+                "{} -- {}",
                 r.target(),
-                r.level(),
                 r.args()
             );
         });
@@ -138,6 +143,9 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
         // Set up IDT before paging
         idt::init();
 
+        // Keep the panic record range out of the memory map
+        crate::pstore::reserve(env);
+
         // Initialize RMM
         crate::arch::rmm::init(
             args.kernel_base as usize,
@@ -162,6 +170,9 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
             LogicalCpuId::BSP,
         );
 
+        // Tag log output with the CPU and context
+        log::init_percpu();
+
         // Set up IDT
         idt::init_paging_bsp();
 
@@ -182,9 +193,6 @@ pub unsafe extern "C" fn kstart(args_ptr: *const KernelArgs) -> ! {
 
         idt::init_paging_post_heap(LogicalCpuId::BSP);
 
-        // Activate memory logging
-        log::init();
-
         // Initialize devices
         device::init();
 
@@ -271,5 +279,8 @@ pub unsafe extern "C" fn kstart_ap(args_ptr: *const KernelArgsAp) -> ! {
         interrupt::pause();
     }
 
+    // Only watch for lockups once the tick is about to start.
+    device::watchdog::init_ap();
+
     crate::kmain_ap(cpu_id);
 }
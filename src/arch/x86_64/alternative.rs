@@ -3,7 +3,7 @@
 use core::mem::size_of;
 
 use spin::Once;
-use x86::controlregs::{Cr4, Xcr0};
+use x86::controlregs::Cr4;
 
 use crate::{
     context::memory::PageSpan,
@@ -81,7 +81,6 @@ pub unsafe fn early_init(bsp: bool) {
             x86::controlregs::cr4() | x86::controlregs::Cr4::CR4_ENABLE_OS_XSAVE,
         );
 
-        let mut xcr0 = Xcr0::XCR0_FPU_MMX_STATE | Xcr0::XCR0_SSE_STATE;
         let ext_state_info = cpuid()
             .get_extended_state_info()
             .expect("must be present if XSAVE is supported");
@@ -89,11 +88,29 @@ pub unsafe fn early_init(bsp: bool) {
         enable |= KcpuFeatures::XSAVE;
         enable.set(KcpuFeatures::XSAVEOPT, ext_state_info.has_xsaveopt());
 
+        // Enable every state component the CPU can save that userspace may use, so that the size
+        // of the XSAVE area follows from what the CPU supports. AMX is only enabled while running
+        // processes permitted to use it, as its tile data alone takes 8 KiB.
+        let supported = raw_cpuid::cpuid!(0xD, 0);
+        let supported = u64::from(supported.eax) | u64::from(supported.edx) << 32;
+        let mut xcr0 = xsave::XCR0_X87 | xsave::XCR0_SSE;
+        if feature_info().has_avx() {
+            xcr0 |= xsave::XCR0_AVX;
+            if has_ext_feat(|feat| feat.has_avx512f())
+                && supported & xsave::XCR0_AVX512 == xsave::XCR0_AVX512
+            {
+                xcr0 |= xsave::XCR0_AVX512;
+            }
+        }
+        // Only valid after XCR0 has been written.
+        let amx_xsave_size = (supported & xsave::XCR0_AMX == xsave::XCR0_AMX).then(|| {
+            xsave::xcr0_write(xcr0 | xsave::XCR0_AMX);
+            raw_cpuid::cpuid!(0xD, 0).ebx
+        });
+        xsave::xcr0_write(xcr0);
+
         let info = xsave::XsaveInfo {
             ymm_upper_offset: feature_info().has_avx().then(|| {
-                xcr0 |= Xcr0::XCR0_AVX_STATE;
-                x86::controlregs::xcr0_write(xcr0);
-
                 let state = ext_state_info
                     .iter()
                     .find(|state| {
@@ -108,7 +125,11 @@ pub unsafe fn early_init(bsp: bool) {
 
                 state.offset()
             }),
-            xsave_size: ext_state_info.xsave_area_size_enabled_features(),
+            // Only valid after XCR0 has been written.
+            xsave_size: raw_cpuid::cpuid!(0xD, 0).ebx,
+            xcr0,
+            amx_xsave_size,
+            has_xinuse: raw_cpuid::cpuid!(0xD, 1).eax & xsave::CPUID_XGETBV_XINUSE != 0,
         };
         log::debug!("XSAVE: {:?}", info);
 
@@ -256,26 +277,100 @@ pub fn features() -> KcpuFeatures {
 }
 
 #[cfg(not(cpu_feature_never = "xsave"))]
-mod xsave {
+pub mod xsave {
     use super::*;
 
+    pub const XCR0_X87: u64 = 1 << 0;
+    pub const XCR0_SSE: u64 = 1 << 1;
+    pub const XCR0_AVX: u64 = 1 << 2;
+    /// Opmask, upper halves of ZMM0-15 and ZMM16-31.
+    pub const XCR0_AVX512: u64 = 0b111 << 5;
+    /// Tile configuration and tile data.
+    pub const XCR0_AMX: u64 = 0b11 << 17;
+
+    /// XGETBV with ECX = 1 returns the XINUSE bitmap.
+    pub(super) const CPUID_XGETBV_XINUSE: u32 = 1 << 2;
+
+    /// Offset of the MXCSR register in the legacy region.
+    pub const MXCSR_OFFSET: usize = 24;
+    /// Offset of XSTATE_BV, the bitmap of state components not in their initial configuration,
+    /// in the XSAVE header.
+    pub const XSTATE_BV_OFFSET: usize = FXSAVE_SIZE;
+
     #[derive(Debug)]
     pub struct XsaveInfo {
         pub ymm_upper_offset: Option<u32>,
+        /// Size of the XSAVE area for the components enabled in XCR0, including the legacy region
+        /// and the header.
         pub xsave_size: u32,
+        /// The components enabled in XCR0 for every context.
+        pub xcr0: u64,
+        /// Size of the XSAVE area with AMX enabled too, if supported.
+        pub amx_xsave_size: Option<u32>,
+        /// Whether the XINUSE bitmap can be read, to find out if the extended state is in use.
+        pub has_xinuse: bool,
     }
     pub(super) static XSAVE_INFO: Once<XsaveInfo> = Once::new();
 
     pub fn info() -> Option<&'static XsaveInfo> {
         XSAVE_INFO.get()
     }
+
+    pub unsafe fn xcr0_read() -> u64 {
+        let (low, high): (u32, u32);
+        core::arch::asm!(
+            "xgetbv",
+            in("ecx") 0,
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack, preserves_flags),
+        );
+        u64::from(low) | u64::from(high) << 32
+    }
+
+    pub unsafe fn xcr0_write(xcr0: u64) {
+        core::arch::asm!(
+            "xsetbv",
+            in("ecx") 0,
+            in("eax") xcr0 as u32,
+            in("edx") (xcr0 >> 32) as u32,
+            options(nostack, preserves_flags),
+        );
+    }
+
+    /// The state components of the current CPU not in their initial configuration. MXCSR is not
+    /// tracked.
+    pub unsafe fn xinuse() -> u64 {
+        let (low, high): (u32, u32);
+        core::arch::asm!(
+            "xgetbv",
+            in("ecx") 1,
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack, preserves_flags),
+        );
+        u64::from(low) | u64::from(high) << 32
+    }
+}
+
+/// Size of the XSAVE area of contexts permitted to use AMX, which is at least `kfx_size()`.
+pub fn amx_kfx_size() -> Option<usize> {
+    #[cfg(not(cpu_feature_never = "xsave"))]
+    {
+        let size = xsave::info()?.amx_xsave_size?;
+        Some((size as usize).max(kfx_size()))
+    }
+    #[cfg(cpu_feature_never = "xsave")]
+    {
+        None
+    }
 }
 
 pub fn kfx_size() -> usize {
     #[cfg(not(cpu_feature_never = "xsave"))]
     {
         match xsave::info() {
-            Some(info) => FXSAVE_SIZE + XSAVE_HEADER_SIZE + info.xsave_size as usize,
+            Some(info) => (info.xsave_size as usize).max(FXSAVE_SIZE + XSAVE_HEADER_SIZE),
             None => FXSAVE_SIZE,
         }
     }
@@ -0,0 +1,927 @@
+//! A GDB remote stub, for debugging the kernel itself.
+//!
+//! With `gdbstub=ttyS<n>[,<baud>]` or `gdbstub=uart,io,<port>[,<baud>]` in the boot environment, a
+//! legacy serial port other than the console speaks the GDB remote serial protocol, and GDB
+//! attaches to it with `target remote` on the other end of the line. The kernel stops in the stub:
+//! - on a panic, before halting or rebooting,
+//! - on `int3` in kernel mode, which includes the breakpoints set by GDB,
+//! - on the `debugger` trigger, see [`crate::scheme::sys::trigger`],
+//! - when GDB interrupts the kernel, if the port is COM1 or COM2, which have an IRQ.
+//!
+//! The other CPUs are stopped with an NMI while one is in the stub. GDB sees each CPU as a thread,
+//! with the registers it was interrupted with, and each context not running as another thread,
+//! with the registers saved by its last context switch. Memory is accessed through the page tables
+//! of the CPU which stopped, so lower half addresses are those of the context it was running.
+//! Only software breakpoints are supported, and single stepping releases the other CPUs.
+
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    hint, ptr, str,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU8, AtomicUsize, Ordering},
+};
+
+use spin::{Mutex, Once};
+
+use crate::{
+    context::{self, ContextId},
+    cpu_set::MAX_CPU_COUNT,
+    device::{serial, watchdog},
+    devices::{
+        serial_console::{self, SerialConsolePort},
+        uart_16550::SerialPort,
+    },
+    interrupt::InterruptStack,
+    ipi::{ipi, IpiKind, IpiTarget},
+    memory::TheFrameAllocator,
+    paging::{PageMapper, RmmA, RmmArch, TableKind, VirtualAddress},
+    syscall::io::Pio,
+};
+
+use super::flags::{FLAG_INTERRUPTS, FLAG_SINGLESTEP};
+
+/// The largest packet exchanged, as reported to GDB.
+const PACKET_SIZE: usize = 0x1000;
+const MAX_BREAKPOINTS: usize = 64;
+/// How long to spin for the other CPUs to stop or leave.
+const STOP_SPINS: usize = 100_000_000;
+
+/// Thread IDs of contexts start here, below are the CPUs, from 1.
+const CONTEXT_THREADS: usize = 0x1000;
+
+/// The GDB register numbers of `rsp`, `rip` and `eflags`, after the other general purpose
+/// registers, in the order `rax`, `rbx`, `rcx`, `rdx`, `rsi`, `rdi`, `rbp`, `rsp`, `r8`-`r15`.
+/// The 32 bit segment registers follow, as `cs`, `ss`, `ds`, `es`, `fs` and `gs`.
+const RSP: usize = 7;
+const RIP: usize = 16;
+const EFLAGS: usize = 17;
+const REGISTER_COUNT: usize = 24;
+/// The status flags, and the trap flag, which may be changed from GDB.
+const WRITABLE_FLAGS: usize = 0xDD5 | FLAG_SINGLESTEP;
+
+/// Byte of the `int3` instruction.
+const INT3: u8 = 0xCC;
+const CR0_WP: usize = 1 << 16;
+const CR4_CET: usize = 1 << 23;
+
+const NONE: u32 = u32::MAX;
+
+/// Why the kernel stopped, as the signal reported to GDB.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum StopReason {
+    Interrupt = 2,
+    Breakpoint = 5,
+    Panic = 6,
+}
+
+/// I/O port base of the serial port dedicated to the stub.
+static PORT: Once<u16> = Once::new();
+/// The CPU in the stub, if any.
+static ACTIVE: AtomicU32 = AtomicU32::new(NONE);
+/// The reason of the next stop, if not a breakpoint.
+static REASON: AtomicU8 = AtomicU8::new(StopReason::Breakpoint as u8);
+/// Incremented when the CPU in the stub lets the others go.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+/// Whether the CPU in the stub asked to single step, and whether interrupts were enabled before.
+static STEPPING: AtomicBool = AtomicBool::new(false);
+static STEP_INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+const NO_FRAME: AtomicPtr<InterruptStack> = AtomicPtr::new(ptr::null_mut());
+/// The registers of each stopped CPU.
+static FRAMES: [AtomicPtr<InterruptStack>; MAX_CPU_COUNT as usize] =
+    [NO_FRAME; MAX_CPU_COUNT as usize];
+const NOT_STOPPING: AtomicBool = AtomicBool::new(false);
+/// Whether a CPU was sent an NMI to stop.
+static STOPPING: [AtomicBool; MAX_CPU_COUNT as usize] = [NOT_STOPPING; MAX_CPU_COUNT as usize];
+
+static SESSION: Mutex<Session> = Mutex::new(Session {
+    input: [0; PACKET_SIZE],
+    output: [0; PACKET_SIZE],
+    state: State {
+        connected: false,
+        signal: 0,
+        thread: 0,
+        thread_cursor: 0,
+        breakpoints: [None; MAX_BREAKPOINTS],
+    },
+});
+
+struct Session {
+    input: [u8; PACKET_SIZE],
+    output: [u8; PACKET_SIZE],
+    state: State,
+}
+
+struct State {
+    /// Whether GDB is attached, and waits for a stop reply when the kernel stops.
+    connected: bool,
+    signal: u8,
+    /// The thread selected by `Hg`.
+    thread: usize,
+    /// Position of the next `qsThreadInfo` reply in the thread list.
+    thread_cursor: usize,
+    /// The inserted breakpoints, with the byte they replaced.
+    breakpoints: [Option<(usize, u8)>; MAX_BREAKPOINTS],
+}
+
+/// What the stub does after a packet.
+enum Action {
+    Reply,
+    Resume { step: bool },
+    Detach,
+}
+
+type Registers = [Option<usize>; REGISTER_COUNT];
+
+/// Dedicate the port selected by the boot environment to the stub, once the serial ports were
+/// initialized.
+pub fn init(env: &[u8]) {
+    let Some(value) = str::from_utf8(env)
+        .unwrap_or("")
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("gdbstub="))
+        .last()
+    else {
+        return;
+    };
+    let Some(config) = serial_console::parse_value(value) else {
+        log::warn!("gdbstub: invalid port {:?}", value);
+        return;
+    };
+    let base = match config.port {
+        SerialConsolePort::Tty(index) => serial::PORT_BASES.get(usize::from(index)).copied(),
+        SerialConsolePort::Io(base) => Some(base),
+        _ => None,
+    };
+    let Some(base) = base else {
+        log::warn!(
+            "gdbstub: only legacy serial ports are supported, not {:?}",
+            value
+        );
+        return;
+    };
+    if base == serial::console_base() {
+        log::warn!("gdbstub: {:#X} is the console", base);
+        return;
+    }
+
+    SerialPort::<Pio<u8>>::new(base).init_with_baud(config.baud.unwrap_or(115200));
+    PORT.call_once(|| base);
+    log::info!("gdbstub: listening on {:#X}", base);
+}
+
+/// Whether a port is dedicated to the stub.
+pub fn enabled() -> bool {
+    PORT.get().is_some()
+}
+
+/// Stop in the stub, if enabled.
+pub fn enter(reason: StopReason) {
+    if !enabled() || ACTIVE.load(Ordering::Acquire) == crate::cpu_id().get() {
+        return;
+    }
+    REASON.store(reason as u8, Ordering::Relaxed);
+    // The trap skips this instruction when resuming.
+    unsafe { asm!("int3") };
+}
+
+/// Handle an IRQ of the serial port at `base`, returning whether it is that of the stub. GDB sends
+/// 0x03 to interrupt the kernel.
+pub fn serial_interrupt(base: u16) -> bool {
+    if PORT.get() != Some(&base) {
+        return false;
+    }
+    let mut port = SerialPort::<Pio<u8>>::new(base);
+    let mut interrupt = false;
+    while let Some(byte) = port.receive() {
+        interrupt |= byte == 0x03;
+    }
+    if interrupt {
+        enter(StopReason::Interrupt);
+    }
+    true
+}
+
+/// Handle an `int3` in kernel mode, after the instruction pointer was moved back to it. Returns
+/// whether the stub handled it.
+pub unsafe fn trap(stack: &mut InterruptStack) -> bool {
+    let Some(&base) = PORT.get() else {
+        return false;
+    };
+    let cpu = crate::cpu_id().get();
+    if let Err(active) = ACTIVE.compare_exchange(NONE, cpu, Ordering::AcqRel, Ordering::Acquire) {
+        // A trap in the stub itself cannot be debugged.
+        if active == cpu {
+            return false;
+        }
+        // Wait for the other CPU to leave the stub, and then take the trap again.
+        park(cpu as usize, stack);
+        return true;
+    }
+    let signal = REASON.swap(StopReason::Breakpoint as u8, Ordering::Relaxed);
+
+    FRAMES[cpu as usize].store(&mut *stack, Ordering::Release);
+    stop_others(cpu as usize);
+
+    let mut session = SESSION.lock();
+    let Session {
+        input,
+        output,
+        state,
+    } = &mut *session;
+    state.signal = signal;
+    state.thread = cpu as usize + 1;
+
+    let mut port = SerialPort::<Pio<u8>>::new(base);
+    if state.connected {
+        let mut out = Writer::new(output);
+        state.stop_reply(&mut out);
+        send_packet(&mut port, out.data());
+    } else {
+        log::info!("gdbstub: CPU {} stopped, waiting for GDB", cpu);
+    }
+
+    let step = loop {
+        let len = receive_packet(&mut port, input);
+        state.connected = true;
+        let mut out = Writer::new(output);
+        match state.handle(&input[..len], &mut out) {
+            Action::Reply => send_packet(&mut port, out.data()),
+            Action::Resume { step } => break step,
+            Action::Detach => {
+                state.remove_breakpoints();
+                state.connected = false;
+                break false;
+            }
+        }
+    };
+
+    // Skip the int3 of `enter`, which is not a breakpoint of GDB.
+    let rip = stack.iret.rip;
+    if linear(rip).map_or(false, |byte| byte.read_volatile() == INT3)
+        && !state
+            .breakpoints
+            .iter()
+            .flatten()
+            .any(|&(addr, _)| addr == rip)
+    {
+        stack.iret.rip += 1;
+    }
+    drop(session);
+
+    if step {
+        STEP_INTERRUPTS.store(stack.iret.rflags & FLAG_INTERRUPTS != 0, Ordering::Relaxed);
+        stack.iret.rflags = (stack.iret.rflags | FLAG_SINGLESTEP) & !FLAG_INTERRUPTS;
+        STEPPING.store(true, Ordering::Relaxed);
+    }
+
+    FRAMES[cpu as usize].store(ptr::null_mut(), Ordering::Release);
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+    for _ in 0..STOP_SPINS {
+        if stopped_count() == 0 {
+            break;
+        }
+        hint::spin_loop();
+    }
+    ACTIVE.store(NONE, Ordering::Release);
+    watchdog::touch();
+    true
+}
+
+/// Handle a debug exception in kernel mode, returning whether it ends a single step of the stub.
+pub unsafe fn single_step(stack: &mut InterruptStack) -> bool {
+    if !STEPPING.swap(false, Ordering::Relaxed) {
+        return false;
+    }
+    stack.iret.rflags &= !FLAG_SINGLESTEP;
+    if STEP_INTERRUPTS.load(Ordering::Relaxed) {
+        stack.iret.rflags |= FLAG_INTERRUPTS;
+    }
+    trap(stack)
+}
+
+/// Handle an NMI, returning whether it was sent to stop this CPU.
+pub unsafe fn nmi(stack: &mut InterruptStack) -> bool {
+    let cpu = crate::cpu_id().get() as usize;
+    if !STOPPING[cpu].swap(false, Ordering::AcqRel) {
+        return false;
+    }
+    // Already waiting after a trap of its own.
+    if FRAMES[cpu].load(Ordering::Acquire).is_null() {
+        park(cpu, stack);
+    }
+    true
+}
+
+fn stop_others(cpu: usize) {
+    let count = crate::cpu_count() as usize;
+    if count <= 1 {
+        return;
+    }
+    for (other, stopping) in STOPPING.iter().enumerate().take(count) {
+        stopping.store(other != cpu, Ordering::Release);
+    }
+    ipi(IpiKind::Stop, IpiTarget::Other);
+    for _ in 0..STOP_SPINS {
+        if stopped_count() >= count {
+            return;
+        }
+        hint::spin_loop();
+    }
+    log::info!(
+        "gdbstub: only {} of {} CPUs stopped",
+        stopped_count(),
+        count
+    );
+}
+
+fn stopped_count() -> usize {
+    FRAMES
+        .iter()
+        .filter(|frame| !frame.load(Ordering::Acquire).is_null())
+        .count()
+}
+
+/// Wait on another CPU until the one in the stub lets it go.
+fn park(cpu: usize, stack: &mut InterruptStack) {
+    let generation = GENERATION.load(Ordering::Acquire);
+    FRAMES[cpu].store(&mut *stack, Ordering::Release);
+    while GENERATION.load(Ordering::Acquire) == generation && ACTIVE.load(Ordering::Acquire) != NONE
+    {
+        hint::spin_loop();
+    }
+    FRAMES[cpu].store(ptr::null_mut(), Ordering::Release);
+    watchdog::touch();
+}
+
+impl State {
+    fn handle(&mut self, packet: &[u8], out: &mut Writer) -> Action {
+        let Some((&command, args)) = packet.split_first() else {
+            return Action::Reply;
+        };
+        match command {
+            b'?' => self.stop_reply(out),
+            b'g' => match registers(self.thread) {
+                Some(regs) => {
+                    for (index, reg) in regs.iter().enumerate() {
+                        out.register(index, *reg);
+                    }
+                }
+                None => out.push(b"E01"),
+            },
+            b'G' => {
+                let ok = unsafe { frame(self.thread) }.map_or(false, |stack| {
+                    let mut data = args;
+                    for index in 0..REGISTER_COUNT {
+                        let size = register_size(index) * 2;
+                        if data.len() < size {
+                            break;
+                        }
+                        let (field, rest) = data.split_at(size);
+                        if let Some(value) = parse_le(field) {
+                            set_register(stack, index, value);
+                        }
+                        data = rest;
+                    }
+                    true
+                });
+                out.ok_or(ok, b"E01");
+            }
+            b'p' => match (parse_hex(args), registers(self.thread)) {
+                (Some(index), Some(regs)) if index < REGISTER_COUNT => {
+                    out.register(index, regs[index])
+                }
+                _ => out.push(b"E01"),
+            },
+            b'P' => {
+                let ok = split(args, b'=').and_then(|(index, value)| {
+                    let stack = unsafe { frame(self.thread)? };
+                    let index = parse_hex(index)?;
+                    (index < REGISTER_COUNT && value.len() == register_size(index) * 2)
+                        .then_some(())?;
+                    set_register(stack, index, parse_le(value)?).then_some(())
+                });
+                out.ok_or(ok.is_some(), b"E01");
+            }
+            b'm' => {
+                let Some((addr, len)) = split(args, b',')
+                    .and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)?)))
+                else {
+                    out.push(b"E01");
+                    return Action::Reply;
+                };
+                let len = len.min(PACKET_SIZE / 2);
+                for offset in 0..len {
+                    match addr
+                        .checked_add(offset)
+                        .and_then(|addr| unsafe { linear(addr) })
+                    {
+                        Some(byte) => out.hex(&[unsafe { byte.read_volatile() }]),
+                        None => {
+                            if offset == 0 {
+                                out.push(b"E0E");
+                            }
+                            break;
+                        }
+                    }
+                }
+            }
+            b'M' => {
+                let ok = split(args, b':').and_then(|(range, data)| {
+                    let (addr, len) = split(range, b',')?;
+                    let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+                    (data.len() == len * 2).then_some(())?;
+                    for (offset, byte) in data.chunks_exact(2).enumerate() {
+                        unsafe { poke(addr.checked_add(offset)?, parse_hex(byte)? as u8)? };
+                    }
+                    Some(())
+                });
+                out.ok_or(ok.is_some(), b"E0E");
+            }
+            b'c' | b's' => {
+                if let (Some(addr), Some(stack)) = (parse_hex(args), unsafe { frame(self.thread) })
+                {
+                    stack.iret.rip = addr;
+                }
+                return Action::Resume {
+                    step: command == b's',
+                };
+            }
+            b'Z' | b'z' => {
+                let Some(addr) = args
+                    .strip_prefix(b"0,")
+                    .and_then(|args| parse_hex(split(args, b',')?.0))
+                else {
+                    // Only software breakpoints are supported.
+                    return Action::Reply;
+                };
+                let ok = if command == b'Z' {
+                    self.insert_breakpoint(addr)
+                } else {
+                    self.remove_breakpoint(addr)
+                };
+                out.ok_or(ok, b"E01");
+            }
+            b'H' => match args.split_first() {
+                Some((b'g', thread)) => match parse_thread(thread) {
+                    Some(0) => {
+                        self.thread = crate::cpu_id().get() as usize + 1;
+                        out.push(b"OK");
+                    }
+                    Some(thread) if registers(thread).is_some() => {
+                        self.thread = thread;
+                        out.push(b"OK");
+                    }
+                    _ => out.push(b"E01"),
+                },
+                _ => out.push(b"OK"),
+            },
+            b'T' => match parse_thread(args) {
+                Some(thread) if registers(thread).is_some() => out.push(b"OK"),
+                _ => out.push(b"E01"),
+            },
+            b'q' => self.query(args, out),
+            b'D' => {
+                out.push(b"OK");
+                return Action::Detach;
+            }
+            b'k' => return Action::Detach,
+            _ => {}
+        }
+        Action::Reply
+    }
+
+    fn query(&mut self, query: &[u8], out: &mut Writer) {
+        if query.starts_with(b"Supported") {
+            let _ = write!(out, "PacketSize={:x}", PACKET_SIZE);
+        } else if query == b"Attached" {
+            out.push(b"1");
+        } else if query == b"C" {
+            let _ = write!(out, "QC{:x}", crate::cpu_id().get() as usize + 1);
+        } else if query == b"fThreadInfo" || query == b"sThreadInfo" {
+            if query[0] == b'f' {
+                self.thread_cursor = 0;
+            }
+            let mut listed = 0;
+            out.push(b"m");
+            for_each_thread(|index, thread| {
+                if index < self.thread_cursor {
+                    return true;
+                }
+                // Room for another ID
+                if out.remaining() < 20 {
+                    return false;
+                }
+                if listed > 0 {
+                    out.push(b",");
+                }
+                let _ = write!(out, "{:x}", thread);
+                listed += 1;
+                true
+            });
+            self.thread_cursor += listed;
+            if listed == 0 {
+                out.clear();
+                out.push(b"l");
+            }
+        } else if let Some(thread) = query.strip_prefix(b"ThreadExtraInfo,") {
+            let thread = parse_thread(thread).unwrap_or(0);
+            let _ = describe_thread(thread, &mut HexWriter(out));
+        }
+    }
+
+    fn stop_reply(&self, out: &mut Writer) {
+        let _ = write!(out, "T{:02x}thread:{:x};", self.signal, self.thread);
+    }
+
+    fn insert_breakpoint(&mut self, addr: usize) -> bool {
+        if self.breakpoints.iter().flatten().any(|&(bp, _)| bp == addr) {
+            return true;
+        }
+        let Some(slot) = self.breakpoints.iter_mut().find(|slot| slot.is_none()) else {
+            return false;
+        };
+        let Some(byte) = (unsafe { linear(addr) }) else {
+            return false;
+        };
+        let original = unsafe { byte.read_volatile() };
+        if unsafe { poke(addr, INT3) }.is_none() {
+            return false;
+        }
+        *slot = Some((addr, original));
+        true
+    }
+
+    fn remove_breakpoint(&mut self, addr: usize) -> bool {
+        let Some(slot) = self
+            .breakpoints
+            .iter_mut()
+            .find(|slot| matches!(slot, Some((bp, _)) if *bp == addr))
+        else {
+            return false;
+        };
+        if let Some((addr, original)) = slot.take() {
+            unsafe { poke(addr, original) };
+        }
+        true
+    }
+
+    fn remove_breakpoints(&mut self) {
+        for (addr, original) in self.breakpoints.iter_mut().filter_map(Option::take) {
+            unsafe { poke(addr, original) };
+        }
+    }
+}
+
+/// The trap frame of a stopped CPU, by thread ID.
+unsafe fn frame(thread: usize) -> Option<&'static mut InterruptStack> {
+    let frame = FRAMES.get(thread.checked_sub(1)?)?.load(Ordering::Acquire);
+    frame.as_mut()
+}
+
+fn registers(thread: usize) -> Option<Registers> {
+    if let Some(stack) = unsafe { frame(thread) } {
+        let (s, p, i) = (&stack.scratch, &stack.preserved, &stack.iret);
+        let mut regs = [None; REGISTER_COUNT];
+        let values = [
+            s.rax, p.rbx, s.rcx, s.rdx, s.rsi, s.rdi, p.rbp, i.rsp, s.r8, s.r9, s.r10, s.r11,
+            p.r12, p.r13, p.r14, p.r15, i.rip, i.rflags, i.cs, i.ss,
+        ];
+        for (reg, value) in regs.iter_mut().zip(values) {
+            *reg = Some(value);
+        }
+        return Some(regs);
+    }
+
+    let id = thread.checked_sub(CONTEXT_THREADS)?;
+    let contexts = context::try_contexts()?;
+    let context = contexts.get(ContextId::new(id))?.try_read()?;
+    if context.running {
+        return None;
+    }
+    let [rbx, rbp, r12, r13, r14, r15, rsp, rflags] = context.arch.saved_registers();
+    let kstack = context.kstack.as_ref()?;
+    let top = kstack.initial_top() as usize;
+    if rsp < top - kstack.len() || rsp + 8 > top {
+        return None;
+    }
+    let rip = unsafe { (rsp as *const usize).read() };
+
+    let mut regs = [None; REGISTER_COUNT];
+    for (index, value) in [
+        (1, rbx),
+        (6, rbp),
+        (RSP, rsp + 8),
+        (12, r12),
+        (13, r13),
+        (14, r14),
+        (15, r15),
+        (RIP, rip),
+        (EFLAGS, rflags),
+    ] {
+        regs[index] = Some(value);
+    }
+    Some(regs)
+}
+
+fn set_register(stack: &mut InterruptStack, index: usize, value: usize) -> bool {
+    let (s, p, i) = (&mut stack.scratch, &mut stack.preserved, &mut stack.iret);
+    let reg = match index {
+        0 => &mut s.rax,
+        1 => &mut p.rbx,
+        2 => &mut s.rcx,
+        3 => &mut s.rdx,
+        4 => &mut s.rsi,
+        5 => &mut s.rdi,
+        6 => &mut p.rbp,
+        RSP => &mut i.rsp,
+        8 => &mut s.r8,
+        9 => &mut s.r9,
+        10 => &mut s.r10,
+        11 => &mut s.r11,
+        12 => &mut p.r12,
+        13 => &mut p.r13,
+        14 => &mut p.r14,
+        15 => &mut p.r15,
+        RIP => &mut i.rip,
+        EFLAGS => {
+            i.rflags = (i.rflags & !WRITABLE_FLAGS) | (value & WRITABLE_FLAGS);
+            return true;
+        }
+        // The segments cannot be changed.
+        _ => return false,
+    };
+    *reg = value;
+    true
+}
+
+fn register_size(index: usize) -> usize {
+    if index <= RIP {
+        8
+    } else {
+        4
+    }
+}
+
+/// Call `f` with the index and ID of each thread, the stopped CPUs followed by the contexts not
+/// running, until it returns false.
+fn for_each_thread(mut f: impl FnMut(usize, usize) -> bool) {
+    let mut index = 0;
+    for (cpu, frame) in FRAMES.iter().enumerate() {
+        if !frame.load(Ordering::Acquire).is_null() {
+            if !f(index, cpu + 1) {
+                return;
+            }
+            index += 1;
+        }
+    }
+    let Some(contexts) = context::try_contexts() else {
+        return;
+    };
+    for (id, context_lock) in contexts.iter() {
+        if context_lock
+            .try_read()
+            .map_or(true, |context| context.running)
+        {
+            continue;
+        }
+        if !f(index, CONTEXT_THREADS + id.get()) {
+            return;
+        }
+        index += 1;
+    }
+}
+
+fn describe_thread(thread: usize, w: &mut impl Write) -> fmt::Result {
+    let contexts = context::try_contexts();
+    let contexts = contexts.as_ref();
+    if let Some(cpu) = thread.checked_sub(1).filter(|&cpu| cpu < CONTEXT_THREADS) {
+        write!(w, "CPU {}", cpu)?;
+        // Nothing is allocated, as another CPU may have stopped in the allocator.
+        for (id, context_lock) in contexts.into_iter().flat_map(|contexts| contexts.iter()) {
+            let Some(context) = context_lock.try_read() else {
+                continue;
+            };
+            if context.running && context.cpu_id.map(|id| id.get() as usize) == Some(cpu) {
+                return write!(w, ", running {} {}", id.get(), context.name);
+            }
+        }
+        return Ok(());
+    }
+    let id = ContextId::new(thread.saturating_sub(CONTEXT_THREADS));
+    match contexts
+        .and_then(|contexts| contexts.get(id))
+        .and_then(|context_lock| context_lock.try_read())
+    {
+        Some(context) => write!(w, "{} {} {:?}", id.get(), context.name, context.status),
+        None => write!(w, "{} <locked>", id.get()),
+    }
+}
+
+/// The address of the byte at `addr` in the linear mapping of physical memory, if mapped. Going
+/// through it avoids faulting on SMAP.
+unsafe fn linear(addr: usize) -> Option<*mut u8> {
+    let mapper = PageMapper::current(TableKind::Kernel, TheFrameAllocator);
+    let (phys, _) = mapper.translate(VirtualAddress::new(addr))?;
+    let virt = RmmA::phys_to_virt(phys);
+    // Device memory is not part of the linear mapping.
+    mapper.translate(virt)?;
+    Some(virt.data() as *mut u8)
+}
+
+/// Write a byte of memory, even if it is mapped read-only, as kernel code is.
+unsafe fn poke(addr: usize, value: u8) -> Option<()> {
+    let byte = linear(addr)?;
+
+    // Write protection cannot be disabled while CET is enabled.
+    let cr0: usize;
+    let cr4: usize;
+    asm!("mov {}, cr0", out(reg) cr0);
+    asm!("mov {}, cr4", out(reg) cr4);
+    if cr4 & CR4_CET != 0 {
+        asm!("mov cr4, {}", in(reg) cr4 & !CR4_CET);
+    }
+    asm!("mov cr0, {}", in(reg) cr0 & !CR0_WP);
+
+    byte.write_volatile(value);
+
+    asm!("mov cr0, {}", in(reg) cr0);
+    if cr4 & CR4_CET != 0 {
+        asm!("mov cr4, {}", in(reg) cr4);
+    }
+    Some(())
+}
+
+fn receive_byte(port: &mut SerialPort<Pio<u8>>) -> u8 {
+    loop {
+        if let Some(byte) = port.receive() {
+            return byte;
+        }
+        watchdog::touch();
+        hint::spin_loop();
+    }
+}
+
+/// Receive a packet into `buf`, acknowledging it, and return its length.
+fn receive_packet(port: &mut SerialPort<Pio<u8>>, buf: &mut [u8]) -> usize {
+    loop {
+        while receive_byte(port) != b'$' {}
+
+        let mut len = 0;
+        let mut sum = 0_u8;
+        let mut overflow = false;
+        loop {
+            let mut byte = receive_byte(port);
+            if byte == b'#' {
+                break;
+            }
+            sum = sum.wrapping_add(byte);
+            if byte == b'}' {
+                let escaped = receive_byte(port);
+                sum = sum.wrapping_add(escaped);
+                byte = escaped ^ 0x20;
+            }
+            match buf.get_mut(len) {
+                Some(slot) => *slot = byte,
+                None => overflow = true,
+            }
+            len += 1;
+        }
+        let checksum = [receive_byte(port), receive_byte(port)];
+
+        if !overflow && parse_hex(&checksum) == Some(usize::from(sum)) {
+            port.send(b'+');
+            return len;
+        }
+        port.send(b'-');
+    }
+}
+
+/// Send a packet, until GDB acknowledges it.
+fn send_packet(port: &mut SerialPort<Pio<u8>>, data: &[u8]) {
+    loop {
+        port.send(b'$');
+        for &byte in data {
+            port.send(byte);
+        }
+        let sum = data.iter().fold(0_u8, |sum, &byte| sum.wrapping_add(byte));
+        port.send(b'#');
+        port.send(HEX[usize::from(sum >> 4)]);
+        port.send(HEX[usize::from(sum & 0xF)]);
+
+        loop {
+            match receive_byte(port) {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+fn parse_hex(s: &[u8]) -> Option<usize> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter().try_fold(0, |value, &c| {
+        Some(value << 4 | (c as char).to_digit(16)? as usize)
+    })
+}
+
+/// Parse a register value, in target byte order.
+fn parse_le(s: &[u8]) -> Option<usize> {
+    let mut bytes = [0; 8];
+    for (byte, hex) in bytes.iter_mut().zip(s.chunks_exact(2)) {
+        *byte = parse_hex(hex)? as u8;
+    }
+    Some(usize::from_le_bytes(bytes))
+}
+
+/// Parse a thread ID, where 0 is any thread and -1 all of them.
+fn parse_thread(s: &[u8]) -> Option<usize> {
+    match s {
+        b"-1" | b"0" => Some(0),
+        _ => parse_hex(s),
+    }
+}
+
+fn split(s: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = s.iter().position(|&c| c == separator)?;
+    Some((&s[..index], &s[index + 1..]))
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.len
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let Some(slot) = self.buf.get_mut(self.len) else {
+                return;
+            };
+            *slot = byte;
+            self.len += 1;
+        }
+    }
+
+    fn ok_or(&mut self, ok: bool, error: &[u8]) {
+        self.push(if ok { b"OK" } else { error });
+    }
+
+    fn hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(&[HEX[usize::from(byte >> 4)], HEX[usize::from(byte & 0xF)]]);
+        }
+    }
+
+    /// A register, in target byte order, or as unavailable.
+    fn register(&mut self, index: usize, value: Option<usize>) {
+        let size = register_size(index);
+        match value {
+            Some(value) => self.hex(&value.to_le_bytes()[..size]),
+            None => {
+                for _ in 0..size {
+                    self.push(b"xx");
+                }
+            }
+        }
+    }
+}
+
+impl Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Text, hex encoded as in `qThreadExtraInfo` replies.
+struct HexWriter<'a, 'b>(&'a mut Writer<'b>);
+
+impl Write for HexWriter<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.hex(s.as_bytes());
+        Ok(())
+    }
+}
@@ -263,6 +263,26 @@ pub unsafe fn init_paging(stack_offset: usize, cpu_id: LogicalCpuId) {
 
     crate::percpu::init_tlb_shootdown(cpu_id, &mut pcr.percpu);
 }
+/// Load the GDT, segments and task register of the PCR at `pcr` again, after the CPU woke from
+/// sleep. FSBASE and the userspace GSBASE are left to the caller.
+#[cfg(feature = "acpi")]
+pub unsafe fn reload(pcr: *mut ProcessorControlRegion) {
+    let pcr = &mut *pcr;
+    let limit = (pcr.gdt.len() * size_of::<GdtEntry>() - 1)
+        .try_into()
+        .expect("main GDT way too large");
+    let base = pcr.gdt.as_ptr() as *const SegmentDescriptor;
+    dtables::lgdt(&DescriptorTablePointer { limit, base });
+
+    load_segments();
+    x86::msr::wrmsr(x86::msr::IA32_GS_BASE, pcr as *mut _ as usize as u64);
+
+    // The TSS was busy when the CPU went to sleep, and loading a busy TSS faults.
+    let access = pcr.gdt[GDT_TSS].access;
+    pcr.gdt[GDT_TSS].access = access & !0xF | GDT_A_TSS_AVAIL;
+    task::load_tr(SegmentSelector::new(GDT_TSS as u16, Ring::Ring0));
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(packed)]
 pub struct GdtEntry {
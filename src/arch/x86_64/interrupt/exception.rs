@@ -1,18 +1,25 @@
 use x86::irq::PageFaultError;
 
 use crate::{
+    context::{context::FaultInfo, signal::record_fault},
     interrupt::stack_trace, interrupt_error, interrupt_stack, ksignal, memory::GenericPfFlags,
-    paging::VirtualAddress, ptrace, syscall::flag::*,
+    paging::{TableKind, VirtualAddress},
+    ptrace, syscall::flag::*,
 };
 
 interrupt_stack!(divide_by_zero, |stack| {
-    println!("Divide by zero");
+    log::error!("Divide by zero");
     stack.dump();
     stack_trace();
     ksignal(SIGFPE);
 });
 
 interrupt_stack!(debug, @paranoid, |stack| {
+    #[cfg(feature = "gdbstub")]
+    if stack.iret.cs & 3 == 0 && crate::arch::gdbstub::single_step(stack) {
+        return;
+    }
+
     let mut handled = false;
 
     // Disable singlestep before there is a breakpoint, since the breakpoint
@@ -21,7 +28,26 @@ interrupt_stack!(debug, @paranoid, |stack| {
     let had_singlestep = stack.iret.rflags & (1 << 8) == 1 << 8;
     stack.set_singlestep(false);
 
-    if ptrace::breakpoint_callback(PTRACE_STOP_SINGLESTEP, None).is_some() {
+    // Hardware breakpoints and watchpoints, set through proc:<pid>/regs/debug. The event carries
+    // the mask of triggered debug registers.
+    let hw_hits = crate::arch::hw_breakpoint::HwBreakpoints::take_hits();
+    if hw_hits != 0 {
+        // Set RF, so that instruction breakpoints don't immediately trigger again on return.
+        stack.iret.rflags |= 1 << 16;
+
+        // Watchpoints also trigger when the kernel accesses the watched user memory, e.g. in
+        // usercopy, which is not reported.
+        handled = stack.iret.cs & 3 != 3
+            || ptrace::breakpoint_callback(
+                PTRACE_STOP_BREAKPOINT,
+                Some(crate::syscall::ptrace_event!(PTRACE_STOP_BREAKPOINT, hw_hits, stack.iret.rip)),
+            )
+            .is_some();
+    }
+
+    if handled {
+        // The tracer decides whether to keep singlestepping.
+    } else if ptrace::breakpoint_callback(PTRACE_STOP_SINGLESTEP, None).is_some() {
         handled = true;
     } else {
         // There was no breakpoint, restore original value
@@ -29,21 +55,35 @@ interrupt_stack!(debug, @paranoid, |stack| {
     }
 
     if !handled {
-        println!("Debug trap");
+        log::error!("Debug trap");
         stack.dump();
         ksignal(SIGTRAP);
     }
 });
 
 interrupt_stack!(non_maskable, @paranoid, |stack| {
-    #[cfg(feature = "profiling")]
-    crate::profiling::nmi_handler(stack);
+    // The watchdog reports any lockup it detects by itself.
+    let mut handled = crate::device::watchdog::nmi(stack);
 
-    #[cfg(not(feature = "profiling"))]
+    // Another CPU may be panicking, and asking for the stack trace of this one.
+    handled |= crate::interrupt::trace::nmi(stack);
+
+    // NMIs raised together are merged, so this may also stop the CPU for the GDB stub.
+    #[cfg(feature = "gdbstub")]
     {
-        // TODO: This will likely deadlock
-        println!("Non-maskable interrupt");
-        stack.dump();
+        handled |= crate::arch::gdbstub::nmi(stack);
+    }
+
+    if !handled {
+        #[cfg(feature = "profiling")]
+        crate::profiling::nmi_handler(stack);
+
+        #[cfg(not(feature = "profiling"))]
+        {
+            // TODO: This will likely deadlock
+            log::error!("Non-maskable interrupt");
+            stack.dump();
+        }
     }
 });
 
@@ -61,73 +101,88 @@ interrupt_stack!(breakpoint, |stack| {
     // int3 instruction. After all, it's the sanest thing to do.
     stack.iret.rip -= 1;
 
+    #[cfg(feature = "gdbstub")]
+    if stack.iret.cs & 3 == 0 && crate::arch::gdbstub::trap(stack) {
+        return;
+    }
+
     if ptrace::breakpoint_callback(PTRACE_STOP_BREAKPOINT, None).is_none() {
-        println!("Breakpoint trap");
+        log::error!("Breakpoint trap");
         stack.dump();
         ksignal(SIGTRAP);
     }
 });
 
 interrupt_stack!(overflow, |stack| {
-    println!("Overflow trap");
+    log::error!("Overflow trap");
     stack.dump();
     stack_trace();
     ksignal(SIGFPE);
 });
 
 interrupt_stack!(bound_range, |stack| {
-    println!("Bound range exceeded fault");
+    log::error!("Bound range exceeded fault");
     stack.dump();
     stack_trace();
     ksignal(SIGSEGV);
 });
 
 interrupt_stack!(invalid_opcode, |stack| {
-    println!("Invalid opcode fault");
+    // AMX faults until XCR0 is reloaded, if it was permitted while the context was running.
+    if crate::context::reload_xcr0() {
+        return;
+    }
+    log::error!("Invalid opcode fault");
     stack.dump();
     stack_trace();
     ksignal(SIGILL);
 });
 
 interrupt_stack!(device_not_available, |stack| {
-    println!("Device not available fault");
+    log::error!("Device not available fault");
     stack.dump();
     stack_trace();
     ksignal(SIGILL);
 });
 
 interrupt_error!(double_fault, |stack, _code| {
-    println!("Double fault");
+    log::error!("Double fault");
     stack.dump();
     stack_trace();
     ksignal(SIGSEGV);
 });
 
 interrupt_error!(invalid_tss, |stack, _code| {
-    println!("Invalid TSS fault");
+    log::error!("Invalid TSS fault");
     stack.dump();
     stack_trace();
     ksignal(SIGSEGV);
 });
 
 interrupt_error!(segment_not_present, |stack, _code| {
-    println!("Segment not present fault");
+    log::error!("Segment not present fault");
     stack.dump();
     stack_trace();
     ksignal(SIGSEGV);
 });
 
 interrupt_error!(stack_segment, |stack, _code| {
-    println!("Stack segment fault");
+    log::error!("Stack segment fault");
     stack.dump();
     stack_trace();
     ksignal(SIGSEGV);
 });
 
 interrupt_error!(protection, |stack, code| {
-    println!("Protection fault code={:#0x}", code);
+    log::error!("Protection fault code={:#0x}", code);
     stack.dump();
     stack_trace();
+    record_fault(FaultInfo {
+        signal: SIGSEGV,
+        ip: stack.iret.rip,
+        address: None,
+        access: None,
+    });
     ksignal(SIGSEGV);
 });
 
@@ -158,50 +213,83 @@ interrupt_error!(page, |stack, code| {
     );
 
     if crate::memory::page_fault_handler(stack, generic_flags, cr2).is_err() {
-        println!("Page fault: {:>016X} {:#?}", cr2.data(), arch_flags);
+        log::error!("Page fault: {:>016X} {:#?}", cr2.data(), arch_flags);
         stack.dump();
         stack_trace();
+
+        // The kernel may only access user memory through the usercopy functions, which recover
+        // from faults. Any other access is a kernel bug, or an attempt to exploit one.
+        if stack.iret.cs & 3 == 0 && cr2.kind() == TableKind::User {
+            let reason = if arch_flags.contains(PageFaultError::ID) {
+                "kernel executed user page (SMEP)"
+            } else if arch_flags.contains(PageFaultError::P) {
+                "kernel accessed user page outside of usercopy (SMAP)"
+            } else {
+                "kernel accessed unmapped user page outside of usercopy"
+            };
+            panic!("{} at {:#x}", reason, cr2.data());
+        }
         ksignal(SIGSEGV);
     }
 });
 
 interrupt_stack!(fpu_fault, |stack| {
-    println!("FPU floating point fault");
+    log::error!("FPU floating point fault");
     stack.dump();
     stack_trace();
     ksignal(SIGFPE);
 });
 
 interrupt_error!(alignment_check, |stack, _code| {
-    println!("Alignment check fault");
+    log::error!("Alignment check fault");
     stack.dump();
     stack_trace();
+    record_fault(FaultInfo {
+        signal: SIGBUS,
+        ip: stack.iret.rip,
+        address: None,
+        access: None,
+    });
     ksignal(SIGBUS);
 });
 
 interrupt_stack!(machine_check, @paranoid, |stack| {
-    println!("Machine check fault");
+    log::error!("Machine check fault");
     stack.dump();
     stack_trace();
     ksignal(SIGBUS);
 });
 
 interrupt_stack!(simd, |stack| {
-    println!("SIMD floating point fault");
+    log::error!("SIMD floating point fault");
     stack.dump();
     stack_trace();
     ksignal(SIGFPE);
 });
 
 interrupt_stack!(virtualization, |stack| {
-    println!("Virtualization fault");
+    log::error!("Virtualization fault");
     stack.dump();
     stack_trace();
     ksignal(SIGBUS);
 });
 
+interrupt_error!(control_protection, |stack, code| {
+    // Raised on a return not matching the shadow stack, among others.
+    log::error!("Control protection fault code={:#0x}", code);
+    stack.dump();
+    stack_trace();
+    record_fault(FaultInfo {
+        signal: SIGSEGV,
+        ip: stack.iret.rip,
+        address: None,
+        access: None,
+    });
+    ksignal(SIGSEGV);
+});
+
 interrupt_error!(security, |stack, _code| {
-    println!("Security exception");
+    log::error!("Security exception");
     stack.dump();
     stack_trace();
     ksignal(SIGBUS);
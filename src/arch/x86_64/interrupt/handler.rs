@@ -20,15 +20,15 @@ pub struct ScratchRegisters {
 
 impl ScratchRegisters {
     pub fn dump(&self) {
-        println!("RAX:   {:016x}", { self.rax });
-        println!("RCX:   {:016x}", { self.rcx });
-        println!("RDX:   {:016x}", { self.rdx });
-        println!("RDI:   {:016x}", { self.rdi });
-        println!("RSI:   {:016x}", { self.rsi });
-        println!("R8:    {:016x}", { self.r8 });
-        println!("R9:    {:016x}", { self.r9 });
-        println!("R10:   {:016x}", { self.r10 });
-        println!("R11:   {:016x}", { self.r11 });
+        log::error!("RAX:   {:016x}", { self.rax });
+        log::error!("RCX:   {:016x}", { self.rcx });
+        log::error!("RDX:   {:016x}", { self.rdx });
+        log::error!("RDI:   {:016x}", { self.rdi });
+        log::error!("RSI:   {:016x}", { self.rsi });
+        log::error!("R8:    {:016x}", { self.r8 });
+        log::error!("R9:    {:016x}", { self.r9 });
+        log::error!("R10:   {:016x}", { self.r10 });
+        log::error!("R11:   {:016x}", { self.r11 });
     }
 }
 
@@ -45,12 +45,12 @@ pub struct PreservedRegisters {
 
 impl PreservedRegisters {
     pub fn dump(&self) {
-        println!("RBX:   {:016x}", { self.rbx });
-        println!("RBP:   {:016x}", { self.rbp });
-        println!("R12:   {:016x}", { self.r12 });
-        println!("R13:   {:016x}", { self.r13 });
-        println!("R14:   {:016x}", { self.r14 });
-        println!("R15:   {:016x}", { self.r15 });
+        log::error!("RBX:   {:016x}", { self.rbx });
+        log::error!("RBP:   {:016x}", { self.rbp });
+        log::error!("R12:   {:016x}", { self.r12 });
+        log::error!("R13:   {:016x}", { self.r13 });
+        log::error!("R14:   {:016x}", { self.r14 });
+        log::error!("R15:   {:016x}", { self.r15 });
     }
 }
 
@@ -70,20 +70,22 @@ pub struct IretRegisters {
 
 impl IretRegisters {
     pub fn dump(&self) {
-        println!("RFLAG: {:016x}", { self.rflags });
-        println!("CS:    {:016x}", { self.cs });
-        println!("RIP:   {:016x}", { self.rip });
+        log::error!("RFLAG: {:016x}", { self.rflags });
+        log::error!("CS:    {:016x}", { self.cs });
+        log::error!("RIP:   {:016x}", { self.rip });
 
-        println!("RSP:   {:016x}", { self.rsp });
-        println!("SS:    {:016x}", { self.ss });
+        log::error!("RSP:   {:016x}", { self.rsp });
+        log::error!("SS:    {:016x}", { self.ss });
 
         unsafe {
             let fsbase = x86::msr::rdmsr(x86::msr::IA32_FS_BASE);
             let gsbase = x86::msr::rdmsr(x86::msr::IA32_KERNEL_GSBASE);
             let kgsbase = x86::msr::rdmsr(x86::msr::IA32_GS_BASE);
-            println!(
+            log::error!(
                 "FSBASE  {:016x}\nGSBASE  {:016x}\nKGSBASE {:016x}",
-                fsbase, gsbase, kgsbase
+                fsbase,
+                gsbase,
+                kgsbase
             );
         }
     }
@@ -117,6 +119,9 @@ impl InterruptStack {
     pub fn set_syscall_ret_reg(&mut self, ret: usize) {
         self.scratch.rax = ret;
     }
+    pub fn syscall_ret_reg(&self) -> usize {
+        self.scratch.rax
+    }
 
     pub fn dump(&self) {
         self.iret.dump();
@@ -430,6 +435,7 @@ macro_rules! interrupt {
         #[naked]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner() {
+                let _irq = $crate::context::cputime::IrqScope::enter();
                 $code
             }
 
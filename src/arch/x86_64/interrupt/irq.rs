@@ -5,16 +5,18 @@ use alloc::vec::Vec;
 use crate::{
     context,
     context::timeout,
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
     device::{
         ioapic, local_apic, pic, pit,
         serial::{COM1, COM2},
     },
     interrupt, interrupt_stack,
-    ipi::{ipi, IpiKind, IpiTarget},
     scheme::{
         debug::{debug_input, debug_notify},
         serio::serio_input,
+        sys::trigger,
     },
+    syscall::error::{Error, Result, EINVAL, ENODEV, EOPNOTSUPP},
     time,
 };
 
@@ -74,6 +76,12 @@ extern "C" {
 /// Notify the IRQ scheme that an IRQ has been registered. This should mask the IRQ until the
 /// scheme user unmasks it ("acknowledges" it).
 unsafe fn trigger(irq: u8) {
+    // The kernel clears the ACPI events raising the SCI by itself.
+    #[cfg(feature = "acpi")]
+    if crate::acpi::fadt::sci(irq) {
+        return;
+    }
+
     match irq_method() {
         IrqMethod::Pic => {
             if irq < 16 {
@@ -98,6 +106,39 @@ pub unsafe fn acknowledge(irq: usize) {
     }
 }
 
+/// Mask the IRQ until it is acknowledged again, used by the IRQ scheme to silence storming lines.
+pub unsafe fn mask(irq: usize) {
+    match irq_method() {
+        IrqMethod::Pic => {
+            if irq < 16 {
+                pic_mask(irq as u8)
+            }
+        }
+        IrqMethod::Apic => ioapic_mask(irq as u8),
+    }
+}
+
+/// Route a legacy IRQ to the logical CPU `cpu_id`, whose IDT must handle the legacy vectors.
+pub unsafe fn set_affinity(irq: u8, cpu_id: u32) -> Result<()> {
+    if irq_method() != IrqMethod::Apic {
+        return Err(Error::new(EOPNOTSUPP));
+    }
+    // The PIT drives the timekeeping on the BSP, and the cascade is never raised.
+    if irq == 0 || irq == 2 || irq >= 16 {
+        return Err(Error::new(EINVAL));
+    }
+    if cpu_id >= MAX_CPU_COUNT {
+        return Err(Error::new(EINVAL));
+    }
+    // The I/O APIC destination field only holds 8-bit APIC IDs.
+    let apic_id = local_apic::apic_id(LogicalCpuId::new(cpu_id));
+    let apic_id = u8::try_from(apic_id).map_err(|_| Error::new(EINVAL))?;
+    if !ioapic::set_destination(irq, apic_id) {
+        return Err(Error::new(ENODEV));
+    }
+    Ok(())
+}
+
 /// Sends an end-of-interrupt, so that the interrupt controller can go on to the next one.
 pub unsafe fn eoi(irq: u8) {
     match irq_method() {
@@ -154,16 +195,22 @@ unsafe fn ioapic_unmask(irq: usize) {
 }
 
 interrupt_stack!(pit_stack, |_stack| {
+    let _irq = context::cputime::IrqScope::enter();
+
     // Saves CPU time by not sending IRQ event irq_trigger(0);
 
     {
-        *time::OFFSET.lock() += pit::RATE;
+        let mut offset = time::OFFSET.lock();
+        // A counter keeps the time by itself once it is the clocksource.
+        if !crate::arch::time::counter_is_clocksource() {
+            *offset += pit::RATE;
+        }
     }
 
     eoi(0);
 
-    // Wake up other CPUs
-    ipi(IpiKind::Pit, IpiTarget::Other);
+    // Wake up other CPUs, except those idle with their tick stopped
+    context::tickless::forward_tick();
 
     // Any better way of doing this?
     timeout::trigger();
@@ -187,17 +234,35 @@ interrupt!(cascade, || {
 });
 
 interrupt!(com2, || {
-    while let Some(c) = COM2.lock().receive() {
-        debug_input(c);
+    #[cfg(feature = "gdbstub")]
+    if crate::arch::gdbstub::serial_interrupt(0x2F8) {
+        eoi(3);
+        return;
+    }
+
+    while let Some((c, is_break)) = COM2.lock().receive_break() {
+        if !trigger::serial_input(c, is_break) {
+            debug_input(c);
+        }
     }
+    trigger::run_serial_pending();
     debug_notify();
     eoi(3);
 });
 
 interrupt!(com1, || {
-    while let Some(c) = COM1.lock().receive() {
-        debug_input(c);
+    #[cfg(feature = "gdbstub")]
+    if crate::arch::gdbstub::serial_interrupt(0x3F8) {
+        eoi(4);
+        return;
     }
+
+    while let Some((c, is_break)) = COM1.lock().receive_break() {
+        if !trigger::serial_input(c, is_break) {
+            debug_input(c);
+        }
+    }
+    trigger::run_serial_pending();
     debug_notify();
     eoi(4);
 });
@@ -272,17 +337,17 @@ interrupt!(ata2, || {
 });
 
 interrupt!(lapic_timer, || {
-    println!("Local apic timer interrupt");
     lapic_eoi();
+    timeout::trigger_hrtimer();
 });
 #[cfg(feature = "profiling")]
 interrupt!(aux_timer, || {
     lapic_eoi();
-    crate::ipi::ipi(IpiKind::Profile, IpiTarget::Other);
+    crate::ipi::ipi(crate::ipi::IpiKind::Profile, crate::ipi::IpiTarget::Other);
 });
 
 interrupt!(lapic_error, || {
-    println!(
+    log::error!(
         "Local apic internal error: ESR={:#0x}",
         local_apic::LOCAL_APIC.esr()
     );
@@ -290,6 +355,8 @@ interrupt!(lapic_error, || {
 });
 
 interrupt_error!(generic_irq, |_stack, code| {
+    let _irq = context::cputime::IrqScope::enter();
+
     // The reason why 128 is subtracted and added from the code, is that PUSH imm8 sign-extends the
     // value, and the longer PUSH imm32 would make the generic_interrupts table twice as large
     // (containing lots of useless NOPs).
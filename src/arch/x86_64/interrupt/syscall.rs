@@ -62,9 +62,23 @@ pub unsafe fn init() {
 
 #[no_mangle]
 pub unsafe extern "C" fn __inner_syscall_instruction(stack: *mut InterruptStack) {
-    let allowed = ptrace::breakpoint_callback(PTRACE_STOP_PRE_SYSCALL, None)
+    let entry_event = {
+        let scratch = &(*stack).scratch;
+        ptrace::syscall_entry_event([
+            scratch.rax,
+            scratch.rdi,
+            scratch.rsi,
+            scratch.rdx,
+            scratch.r10,
+            scratch.r8,
+        ])
+    };
+    let allowed = ptrace::breakpoint_callback(PTRACE_STOP_PRE_SYSCALL, Some(entry_event))
         .and_then(|_| ptrace::next_breakpoint().map(|f| !f.contains(PTRACE_FLAG_IGNORE)));
 
+    // The tracer may have changed the registers while stopped.
+    let number = (*stack).scratch.rax;
+
     if allowed.unwrap_or(true) {
         let scratch = &(*stack).scratch;
 
@@ -79,7 +93,13 @@ pub unsafe extern "C" fn __inner_syscall_instruction(stack: *mut InterruptStack)
         );
     }
 
-    ptrace::breakpoint_callback(PTRACE_STOP_POST_SYSCALL, None);
+    ptrace::breakpoint_callback(
+        PTRACE_STOP_POST_SYSCALL,
+        Some(ptrace::syscall_exit_event(
+            number,
+            (*stack).syscall_ret_reg(),
+        )),
+    );
 }
 
 #[naked]
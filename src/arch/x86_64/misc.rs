@@ -18,6 +18,8 @@ pub unsafe fn init(cpu_id: LogicalCpuId) {
         // obvious reasons.
         x86::controlregs::cr4_write(x86::controlregs::cr4() | Cr4::CR4_ENABLE_SMEP);
     }
+    // CET is only used for userspace shadow stacks, which are enabled per context.
+    crate::arch::shadow_stack::init();
 
     if let Some(feats) = cpuid().get_extended_processor_and_feature_identifiers() && feats.has_rdtscp() {
         x86::msr::wrmsr(x86::msr::IA32_TSC_AUX, cpu_id.get().into());
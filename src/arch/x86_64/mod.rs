@@ -12,6 +12,10 @@ pub mod consts;
 /// CPUID wrapper
 pub mod cpuid;
 
+/// GDB remote stub
+#[cfg(feature = "gdbstub")]
+pub mod gdbstub;
+
 /// Global descriptor table
 pub mod gdt;
 
@@ -27,6 +31,13 @@ pub mod paging;
 
 pub mod rmm;
 
+/// Userspace shadow stacks
+pub mod shadow_stack;
+
+/// Suspend to RAM
+#[cfg(feature = "acpi")]
+pub mod sleep;
+
 /// Initialization and start function
 pub mod start;
 
@@ -69,4 +80,4 @@ pub unsafe extern "C" fn arch_copy_to_user(dst: usize, src: usize, len: usize) -
 }
 pub use arch_copy_to_user as arch_copy_from_user;
 
-pub use alternative::kfx_size;
+pub use alternative::{amx_kfx_size, kfx_size};
@@ -15,6 +15,8 @@ pub mod entry {
     bitflags! {
         pub struct EntryFlags: usize {
             const NO_CACHE =        1 << 4;
+            /// Together with a cleared write bit, marks a shadow stack page.
+            const DIRTY =           1 << 6;
             const HUGE_PAGE =       1 << 7;
             const GLOBAL =          1 << 8;
         }
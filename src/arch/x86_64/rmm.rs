@@ -320,15 +320,13 @@ pub unsafe fn init(
     let mut area_i = 0;
     let areas_raw = &mut *AREAS.get();
 
-    for bootloader_area in bootloader_areas.iter() {
-        if { bootloader_area.kind } != BootloaderMemoryKind::Free {
-            // Not a free area
-            continue;
-        }
-
-        let mut base = bootloader_area.base as usize;
-        let mut size = bootloader_area.size as usize;
-
+    let mut free_areas = bootloader_areas
+        .iter()
+        .filter(|bootloader_area| { bootloader_area.kind } == BootloaderMemoryKind::Free)
+        .map(|bootloader_area| (bootloader_area.base as usize, bootloader_area.size as usize));
+    // The side below the pstore area of an area split by it, checked again on its own
+    let mut split_area = None;
+    while let Some((mut base, mut size)) = split_area.take().or_else(|| free_areas.next()) {
         log::debug!("{:X}:{:X}", base, size);
 
         // Page align base
@@ -418,6 +416,24 @@ pub unsafe fn init(
             new_base = cmp::max(new_base, initfs_end);
         }
 
+        // Ensure the pstore area is not used, keeping the free memory on both sides of it
+        if let Some((pstore_base, pstore_size)) = crate::pstore::region() {
+            let pstore_end = pstore_base + pstore_size;
+            if new_base < pstore_end && base + size > pstore_base {
+                log::warn!(
+                    "{:X}:{:X} overlaps with pstore {:X}:{:X}",
+                    base,
+                    size,
+                    pstore_base,
+                    pstore_size
+                );
+                if new_base < pstore_base {
+                    split_area = Some((new_base, pstore_base - new_base));
+                }
+                new_base = cmp::max(new_base, pstore_end);
+            }
+        }
+
         if new_base != base {
             let end = base + size;
             let new_size = end.checked_sub(new_base).unwrap_or(0);
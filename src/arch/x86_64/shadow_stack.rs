@@ -0,0 +1,140 @@
+//! Userspace shadow stacks, using Intel CET.
+//!
+//! A shadow stack is enabled per context by writing its size to `proc:<pid>/shadow-stack`, which
+//! maps it into the address space of the context. Its pages are mapped read-only but dirty, which
+//! the CPU treats as shadow stack memory, only writable by `call` and the other shadow stack
+//! instructions. A return to an address not matching the shadow stack raises a control protection
+//! fault. As CET is enabled for all of userspace, other pages are made clean whenever they are
+//! write-protected, such as for copy-on-write, so that they never look like shadow stack pages.
+//!
+//! The shadow stack starts out empty on the next return to userspace, so it has to be enabled
+//! from a function which never returns, such as the program entry. Shadow stacks are not copied
+//! when the address space is cloned, so a forked child has to enable its own.
+
+use core::{
+    arch::asm,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::sync::Arc;
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::{
+    context::memory::{AddrSpaceWrapper, Grant, PageSpan},
+    cpuid::has_ext_feat,
+    paging::{entry::EntryFlags, Page, VirtualAddress, PAGE_SIZE},
+    syscall::{
+        error::{Error, Result, EINVAL, EOPNOTSUPP},
+        flag::MapFlags,
+    },
+};
+
+pub(super) const IA32_U_CET: u32 = 0x6A0;
+pub(super) const IA32_PL3_SSP: u32 = 0x6A7;
+/// Enables the shadow stack in `IA32_U_CET`.
+const CET_SH_STK_EN: u64 = 1 << 0;
+/// Enables CET in CR4.
+const CR4_CET: usize = 1 << 23;
+
+/// Largest shadow stack that can be requested. The stack is physically contiguous, and its size
+/// must be a power of two.
+pub const MAX_SIZE: usize = 1024 * 1024;
+
+static SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// The shadow stack of a context.
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowStack {
+    pub base: usize,
+    pub size: usize,
+    /// The userspace shadow stack pointer, saved when the context is switched away from.
+    pub ssp: usize,
+}
+
+/// Enable CET on the current CPU, if shadow stacks are supported.
+pub unsafe fn init() {
+    if !has_ext_feat(|feat| feat.has_cet_ss()) {
+        return;
+    }
+    let mut cr4: usize;
+    asm!("mov {}, cr4", out(reg) cr4);
+    cr4 |= CR4_CET;
+    asm!("mov cr4, {}", in(reg) cr4);
+    wrmsr(IA32_U_CET, 0);
+
+    SUPPORTED.store(true, Ordering::Relaxed);
+}
+
+pub fn supported() -> bool {
+    SUPPORTED.load(Ordering::Relaxed)
+}
+
+/// Map a new shadow stack of `size` bytes into `addr_space`.
+pub fn map(addr_space: &Arc<AddrSpaceWrapper>, size: usize) -> Result<ShadowStack> {
+    if !supported() {
+        return Err(Error::new(EOPNOTSUPP));
+    }
+    if size % PAGE_SIZE != 0 || !size.is_power_of_two() || size > MAX_SIZE {
+        return Err(Error::new(EINVAL));
+    }
+    let page_count = NonZeroUsize::new(size / PAGE_SIZE).ok_or(Error::new(EINVAL))?;
+
+    let base = addr_space.acquire_write().mmap_anywhere(
+        addr_space,
+        page_count,
+        MapFlags::PROT_READ,
+        |page, page_flags, mapper, flusher| {
+            // Shadow stack pages are the only ones mapped dirty without being writable.
+            let page_flags = page_flags
+                .write(false)
+                .custom_flag(EntryFlags::DIRTY.bits(), true);
+            Ok(Grant::zeroed_phys_contiguous(
+                PageSpan::new(page, page_count.get()),
+                page_flags,
+                mapper,
+                flusher,
+            )?)
+        },
+    )?;
+
+    let base = base.start_address().data();
+    Ok(ShadowStack {
+        base,
+        size,
+        ssp: base + size,
+    })
+}
+
+/// Unmap a shadow stack that is no longer used by its context.
+pub fn unmap(addr_space: &Arc<AddrSpaceWrapper>, stack: &ShadowStack) -> Result<()> {
+    let base = Page::containing_address(VirtualAddress::new(stack.base));
+    addr_space.munmap(PageSpan::new(base, stack.size / PAGE_SIZE), false)?;
+    Ok(())
+}
+
+/// The userspace shadow stack pointer of the current context, which is in `IA32_PL3_SSP` while
+/// in the kernel.
+pub unsafe fn current_ssp() -> usize {
+    rdmsr(IA32_PL3_SSP) as usize
+}
+
+/// Load the shadow stack of the current context, or disable it if there is none. It is used from
+/// the next return to userspace.
+pub unsafe fn load(stack: Option<&ShadowStack>) {
+    match stack {
+        Some(stack) => {
+            wrmsr(IA32_PL3_SSP, stack.ssp as u64);
+            wrmsr(IA32_U_CET, CET_SH_STK_EN);
+        }
+        None => wrmsr(IA32_U_CET, 0),
+    }
+}
+
+/// Save the shadow stack pointer of `prev` and load the shadow stack of `next`.
+pub unsafe fn switch(prev: &mut Option<ShadowStack>, next: Option<&ShadowStack>) {
+    if let Some(prev) = prev {
+        prev.ssp = current_ssp();
+    }
+    load(next);
+}
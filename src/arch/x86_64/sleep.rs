@@ -0,0 +1,447 @@
+//! Suspend to RAM, the ACPI S3 sleep state.
+//!
+//! Root puts the system to sleep by writing `S3` and the sleep type values of the `\_S3` object
+//! to `acpi:sleep`, see [`crate::scheme::acpi`]. The CPU handling the write stops the others with
+//! an IPI, and every CPU saves its control registers, descriptor tables, MSRs and FPU state in
+//! memory, which is kept powered while sleeping. The state of the devices the kernel drives is
+//! saved by [`crate::device::suspend`].
+//!
+//! On wakeup, the firmware starts the BSP at the AP trampoline, which brings it back into long mode
+//! on a temporary stack. Its state is restored and it returns to where it stopped, as if from a
+//! function call, then starts each other CPU through the trampoline again the same way. The
+//! monotonic clock continues from the time the system went to sleep, while the realtime clock is
+//! corrected from the RTC.
+//!
+//! Devices driven from userspace are expected to have been quiesced before, and are resumed after.
+
+use alloc::vec::Vec;
+use core::{
+    arch::asm,
+    mem::offset_of,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering},
+};
+
+use x86::msr::{rdmsr, wrmsr, IA32_EFER, IA32_FS_BASE, IA32_KERNEL_GSBASE, IA32_PAT};
+
+#[cfg(not(cpu_feature_never = "xsave"))]
+use super::alternative::xsave;
+use super::{
+    gdt::{self, ProcessorControlRegion},
+    interrupt::syscall,
+    misc,
+    shadow_stack::{self, IA32_PL3_SSP, IA32_U_CET},
+};
+use crate::{
+    acpi::{
+        fadt::{Fadt, FADT},
+        madt,
+    },
+    common::aligned_box::AlignedBox,
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    device::{
+        self,
+        local_apic::{self, LOCAL_APIC},
+    },
+    interrupt,
+    ipi::{ipi, IpiKind, IpiTarget},
+    memory::{allocate_p2frame, deallocate_p2frame},
+    paging::PAGE_SIZE,
+    percpu::PercpuBlock,
+    syscall::error::{Error, Result, EBUSY, EIO, ENODEV, ENOMEM},
+    time::{self, NANOS_PER_SEC},
+    PHYS_OFFSET,
+};
+
+/// The temporary stack the CPUs resume on, until their own stack is restored.
+const STACK_ORDER: u32 = 2;
+
+/// How long to wait for the other CPUs to stop.
+const STOP_TIMEOUT: u128 = NANOS_PER_SEC;
+
+const RUNNING: u8 = 0;
+const STOPPING: u8 = 1;
+
+static PHASE: AtomicU8 = AtomicU8::new(RUNNING);
+const NOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+static REQUESTED: [AtomicBool; MAX_CPU_COUNT as usize] = [NOT_REQUESTED; MAX_CPU_COUNT as usize];
+/// Number of CPUs stopped by the IPI.
+static STOPPED: AtomicUsize = AtomicUsize::new(0);
+/// Number of CPUs whose state was restored after waking.
+static RESTORED: AtomicUsize = AtomicUsize::new(0);
+
+/// The saved state of each CPU, indexed by logical CPU ID.
+static STATES: AtomicPtr<CpuState> = AtomicPtr::new(ptr::null_mut());
+static PAGE_TABLE: AtomicUsize = AtomicUsize::new(0);
+static STACK: AtomicUsize = AtomicUsize::new(0);
+
+/// The registers preserved across a function call, and where it returns to.
+#[derive(Default)]
+#[repr(C)]
+struct Registers {
+    rbx: usize,
+    rbp: usize,
+    r12: usize,
+    r13: usize,
+    r14: usize,
+    r15: usize,
+    rsp: usize,
+    rip: usize,
+    rflags: usize,
+}
+
+#[derive(Default)]
+#[repr(C, packed)]
+struct Idtr {
+    limit: u16,
+    base: u64,
+}
+
+/// The state of a CPU lost while sleeping.
+struct CpuState {
+    registers: Registers,
+    cr0: usize,
+    cr3: usize,
+    cr4: usize,
+    efer: u64,
+    xcr0: Option<u64>,
+    idtr: Idtr,
+    pcr: *mut ProcessorControlRegion,
+    fs_base: u64,
+    kernel_gs_base: u64,
+    pat: u64,
+    /// `IA32_U_CET` and `IA32_PL3_SSP`, if shadow stacks are supported.
+    cet: Option<(u64, u64)>,
+    fpu: AlignedBox<[u8], 64>,
+}
+
+impl CpuState {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            registers: Registers::default(),
+            cr0: 0,
+            cr3: 0,
+            cr4: 0,
+            efer: 0,
+            xcr0: None,
+            idtr: Idtr::default(),
+            pcr: ptr::null_mut(),
+            fs_base: 0,
+            kernel_gs_base: 0,
+            pat: 0,
+            cet: None,
+            // The CPU may be suspended while running a context permitted to use AMX.
+            fpu: AlignedBox::try_zeroed_slice(
+                crate::arch::amx_kfx_size().unwrap_or(crate::arch::kfx_size()),
+            )?,
+        })
+    }
+}
+
+fn state(cpu: LogicalCpuId) -> *mut CpuState {
+    STATES
+        .load(Ordering::Acquire)
+        .wrapping_add(cpu.get() as usize)
+}
+
+/// Put the system to sleep with the sleep type values `slp_typa` and `slp_typb`, returning once
+/// it woke.
+pub unsafe fn suspend(slp_typa: u16, slp_typb: u16) -> Result<()> {
+    let fadt = FADT
+        .get()
+        .filter(|fadt| fadt.can_sleep())
+        .ok_or(Error::new(ENODEV))?;
+
+    let mut states = Vec::new();
+    for _ in 0..crate::cpu_count() {
+        states.push(CpuState::new()?);
+    }
+    let stack = allocate_p2frame(STACK_ORDER).ok_or(Error::new(ENOMEM))?;
+
+    let result = if PHASE
+        .compare_exchange(RUNNING, STOPPING, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+    {
+        STATES.store(states.as_mut_ptr(), Ordering::Release);
+        STACK.store(stack.base().data() + PHYS_OFFSET, Ordering::Relaxed);
+        // Before stopping the other CPUs, as mapping and unmapping the trampoline shoots down
+        // their TLBs.
+        PAGE_TABLE.store(madt::map_trampoline(), Ordering::Relaxed);
+        let result = sleep(fadt, slp_typa, slp_typb);
+
+        PHASE.store(RUNNING, Ordering::Release);
+        while STOPPED.load(Ordering::Acquire) != 0 {
+            interrupt::pause();
+        }
+        madt::unmap_trampoline();
+        STATES.store(ptr::null_mut(), Ordering::Release);
+        result
+    } else {
+        Err(Error::new(EBUSY))
+    };
+
+    deallocate_p2frame(stack, STACK_ORDER);
+    result
+}
+
+unsafe fn sleep(fadt: &Fadt, slp_typa: u16, slp_typb: u16) -> Result<()> {
+    let cpu = crate::cpu_id();
+    let count = crate::cpu_count() as usize;
+
+    let now = time::monotonic();
+
+    RESTORED.store(0, Ordering::Relaxed);
+    for (id, requested) in REQUESTED.iter().enumerate().take(count) {
+        requested.store(id != cpu.get() as usize, Ordering::Release);
+    }
+    ipi(IpiKind::Sleep, IpiTarget::Other);
+
+    let percpu = PercpuBlock::current();
+    while STOPPED.load(Ordering::Acquire) + 1 < count {
+        // The others may wait for this CPU to handle a TLB shootdown before they can stop.
+        percpu.maybe_handle_tlb_shootdown();
+        if time::monotonic() > now + STOP_TIMEOUT {
+            log::warn!(
+                "sleep: only {} CPUs stopped",
+                STOPPED.load(Ordering::Acquire) + 1
+            );
+            return Err(Error::new(EBUSY));
+        }
+        interrupt::pause();
+    }
+
+    let devices = device::suspend();
+    let state = &mut *state(cpu);
+    capture(state);
+    fadt.set_waking_vector(madt::TRAMPOLINE as u32);
+    let stack = STACK.load(Ordering::Relaxed);
+    madt::write_trampoline_args(
+        LogicalCpuId::BSP,
+        PAGE_TABLE.load(Ordering::Relaxed),
+        (stack, stack + (PAGE_SIZE << STACK_ORDER)),
+        resume_entry as usize,
+    );
+    asm!("wbinvd");
+
+    let woke = if save(&mut state.registers) == 0 {
+        fadt.sleep(slp_typa, slp_typb);
+        false
+    } else {
+        resumed(cpu);
+        true
+    };
+
+    if woke {
+        while RESTORED.load(Ordering::Acquire) < count {
+            interrupt::pause();
+        }
+        *time::OFFSET.lock() = now;
+        device::resume(devices);
+        log::info!("sleep: woke up");
+        Ok(())
+    } else {
+        log::warn!("sleep: the system did not go to sleep");
+        Err(Error::new(EIO))
+    }
+}
+
+/// Stop the current CPU until the system woke, when asked to by the sleep IPI.
+pub unsafe fn park() {
+    let cpu = crate::cpu_id();
+    if !REQUESTED[cpu.get() as usize].swap(false, Ordering::AcqRel)
+        || PHASE.load(Ordering::Acquire) != STOPPING
+    {
+        return;
+    }
+
+    let state = &mut *state(cpu);
+    capture(state);
+    if save(&mut state.registers) == 0 {
+        STOPPED.fetch_add(1, Ordering::AcqRel);
+        asm!("wbinvd");
+    } else {
+        resumed(cpu);
+    }
+
+    while PHASE.load(Ordering::Acquire) == STOPPING {
+        interrupt::pause();
+    }
+    STOPPED.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Called on each CPU once its state was restored. The BSP then starts the other CPUs.
+unsafe fn resumed(cpu: LogicalCpuId) {
+    device::resume_ap();
+    RESTORED.fetch_add(1, Ordering::AcqRel);
+    if cpu != LogicalCpuId::BSP {
+        return;
+    }
+
+    let stack = STACK.load(Ordering::Relaxed);
+    for id in 1..crate::cpu_count() {
+        let other = LogicalCpuId::new(id);
+        let restored = RESTORED.load(Ordering::Acquire);
+        madt::run_ap(
+            &mut LOCAL_APIC,
+            local_apic::apic_id(other),
+            other,
+            PAGE_TABLE.load(Ordering::Relaxed),
+            (stack, stack + (PAGE_SIZE << STACK_ORDER)),
+            resume_entry as usize,
+        );
+        // The CPUs share the stack until their state is restored.
+        while RESTORED.load(Ordering::Acquire) == restored {
+            interrupt::pause();
+        }
+    }
+}
+
+/// Save the state of the current CPU, other than its registers.
+unsafe fn capture(state: &mut CpuState) {
+    asm!("mov {}, cr0", out(reg) state.cr0);
+    asm!("mov {}, cr3", out(reg) state.cr3);
+    asm!("mov {}, cr4", out(reg) state.cr4);
+    asm!("sidt [{}]", in(reg) ptr::addr_of_mut!(state.idtr));
+    state.efer = rdmsr(IA32_EFER);
+    state.pcr = gdt::pcr();
+    state.fs_base = rdmsr(IA32_FS_BASE);
+    state.kernel_gs_base = rdmsr(IA32_KERNEL_GSBASE);
+    state.pat = rdmsr(IA32_PAT);
+    state.cet = shadow_stack::supported().then(|| (rdmsr(IA32_U_CET), rdmsr(IA32_PL3_SSP)));
+
+    #[cfg(not(cpu_feature_never = "xsave"))]
+    if xsave::info().is_some() {
+        state.xcr0 = Some(xsave::xcr0_read());
+        asm!(
+            "xsave64 [{}]",
+            in(reg) state.fpu.as_mut_ptr(),
+            in("eax") u32::MAX,
+            in("edx") u32::MAX,
+        );
+        return;
+    }
+    asm!("fxsave64 [{}]", in(reg) state.fpu.as_mut_ptr());
+}
+
+/// Entered from the trampoline on wakeup, with the arguments it was given.
+unsafe extern "C" fn resume_entry(args: *const u64) -> ! {
+    // The CPU ID comes first.
+    let cpu = LogicalCpuId::new(*args as u32);
+    let state = &*state(cpu);
+
+    wrmsr(IA32_EFER, state.efer);
+    asm!("mov cr3, {}", in(reg) state.cr3);
+    asm!("mov cr0, {}", in(reg) state.cr0);
+    asm!("mov cr4, {}", in(reg) state.cr4);
+    if let Some(xcr0) = state.xcr0 {
+        asm!(
+            "xsetbv",
+            in("ecx") 0,
+            in("eax") xcr0 as u32,
+            in("edx") (xcr0 >> 32) as u32,
+        );
+    }
+
+    // The per-CPU data is only available from here.
+    gdt::reload(state.pcr);
+    asm!("lidt [{}]", in(reg) ptr::addr_of!(state.idtr));
+    wrmsr(IA32_FS_BASE, state.fs_base);
+    wrmsr(IA32_KERNEL_GSBASE, state.kernel_gs_base);
+    wrmsr(IA32_PAT, state.pat);
+
+    syscall::init();
+    misc::init(cpu);
+    // After misc::init, which disables the shadow stack.
+    if let Some((u_cet, pl3_ssp)) = state.cet {
+        wrmsr(IA32_U_CET, u_cet);
+        wrmsr(IA32_PL3_SSP, pl3_ssp);
+    }
+
+    if state.xcr0.is_some() {
+        asm!(
+            "xrstor64 [{}]",
+            in(reg) state.fpu.as_ptr(),
+            in("eax") u32::MAX,
+            in("edx") u32::MAX,
+        );
+    } else {
+        asm!("fxrstor64 [{}]", in(reg) state.fpu.as_ptr());
+    }
+
+    restore(&state.registers)
+}
+
+/// Save the registers, returning 0, and return 1 again when they are restored.
+#[naked]
+unsafe extern "sysv64" fn save(_registers: &mut Registers) -> usize {
+    use Registers as R;
+
+    core::arch::asm!(
+        "
+        mov [rdi + {off_rbx}], rbx
+        mov [rdi + {off_rbp}], rbp
+        mov [rdi + {off_r12}], r12
+        mov [rdi + {off_r13}], r13
+        mov [rdi + {off_r14}], r14
+        mov [rdi + {off_r15}], r15
+
+        // The stack pointer and return address of the caller
+        lea rax, [rsp + 8]
+        mov [rdi + {off_rsp}], rax
+        mov rax, [rsp]
+        mov [rdi + {off_rip}], rax
+
+        pushfq
+        pop QWORD PTR [rdi + {off_rflags}]
+
+        xor eax, eax
+        ret
+        ",
+
+        off_rbx = const(offset_of!(R, rbx)),
+        off_rbp = const(offset_of!(R, rbp)),
+        off_r12 = const(offset_of!(R, r12)),
+        off_r13 = const(offset_of!(R, r13)),
+        off_r14 = const(offset_of!(R, r14)),
+        off_r15 = const(offset_of!(R, r15)),
+        off_rsp = const(offset_of!(R, rsp)),
+        off_rip = const(offset_of!(R, rip)),
+        off_rflags = const(offset_of!(R, rflags)),
+        options(noreturn),
+    );
+}
+
+/// Return from [`save`] again, with the saved registers.
+#[naked]
+unsafe extern "sysv64" fn restore(_registers: &Registers) -> ! {
+    use Registers as R;
+
+    core::arch::asm!(
+        "
+        mov rbx, [rdi + {off_rbx}]
+        mov rbp, [rdi + {off_rbp}]
+        mov r12, [rdi + {off_r12}]
+        mov r13, [rdi + {off_r13}]
+        mov r14, [rdi + {off_r14}]
+        mov r15, [rdi + {off_r15}]
+        mov rsp, [rdi + {off_rsp}]
+
+        push QWORD PTR [rdi + {off_rflags}]
+        popfq
+
+        mov eax, 1
+        jmp QWORD PTR [rdi + {off_rip}]
+        ",
+
+        off_rbx = const(offset_of!(R, rbx)),
+        off_rbp = const(offset_of!(R, rbp)),
+        off_r12 = const(offset_of!(R, r12)),
+        off_r13 = const(offset_of!(R, r13)),
+        off_r14 = const(offset_of!(R, r14)),
+        off_r15 = const(offset_of!(R, r15)),
+        off_rsp = const(offset_of!(R, rsp)),
+        off_rip = const(offset_of!(R, rip)),
+        off_rflags = const(offset_of!(R, rflags)),
+        options(noreturn),
+    );
+}
@@ -1,11 +1,11 @@
-use core::fmt;
+use core::{fmt, marker::PhantomData};
 #[cfg(feature = "qemu_debug")]
 use spin::Mutex;
 use spin::MutexGuard;
 
 #[cfg(any(feature = "lpss_debug", feature = "serial_debug"))]
 use crate::devices::uart_16550::SerialPort;
-use crate::log::{Log, LOG};
+use crate::log::{Level, Log, Sink, Tag, LOG};
 #[cfg(feature = "lpss_debug")]
 use crate::syscall::io::Mmio;
 #[cfg(any(feature = "qemu_debug", feature = "serial_debug"))]
@@ -14,7 +14,7 @@ use crate::syscall::io::Pio;
 use syscall::io::Io;
 
 #[cfg(feature = "serial_debug")]
-use super::device::serial::COM1;
+use super::device::serial;
 #[cfg(feature = "lpss_debug")]
 use super::device::serial::LPSS;
 #[cfg(feature = "system76_ec_debug")]
@@ -26,7 +26,14 @@ use crate::devices::graphical_debug::{DebugDisplay, DEBUG_DISPLAY};
 pub static QEMU: Mutex<Pio<u8>> = Mutex::new(Pio::<u8>::new(0x402));
 
 pub struct Writer<'a> {
-    log: MutexGuard<'a, Option<Log>>,
+    tag: Option<Tag>,
+    log: MutexGuard<'a, Log>,
+    consoles: Consoles<'a>,
+}
+
+struct Consoles<'a> {
+    /// Keeps the lifetime in use when no console is enabled.
+    _marker: PhantomData<&'a ()>,
     #[cfg(feature = "graphical_debug")]
     display: MutexGuard<'a, Option<DebugDisplay>>,
     #[cfg(feature = "lpss_debug")]
@@ -41,8 +48,47 @@ pub struct Writer<'a> {
 
 impl<'a> Writer<'a> {
     pub fn new() -> Writer<'a> {
+        Self::with_tag(Tag::default())
+    }
+
+    pub fn with_tag(tag: Tag) -> Writer<'a> {
         Writer {
+            tag: Some(tag),
             log: LOG.lock(),
+            consoles: Consoles::lock(),
+        }
+    }
+
+    /// Like [`Writer::with_tag`], but returns `None` instead of waiting if the log or a console is
+    /// locked, for NMI handlers, which may have interrupted the code holding it.
+    pub fn try_with_tag(tag: Tag) -> Option<Writer<'a>> {
+        Some(Writer {
+            tag: Some(tag),
+            log: LOG.try_lock()?,
+            consoles: Consoles::try_lock()?,
+        })
+    }
+
+    /// A writer passing output on without line prefixes, for console output from userspace.
+    pub fn raw() -> Writer<'a> {
+        Writer {
+            tag: None,
+            ..Self::new()
+        }
+    }
+
+    /// Write `buf` to the log, which passes it on to the consoles with each line prefixed.
+    pub fn write(&mut self, buf: &[u8]) {
+        let Writer { tag, log, consoles } = self;
+        let level = tag.map_or(Level::Info, |tag| tag.level);
+        log.write(buf, *tag, |buf| consoles.write(buf, level));
+    }
+}
+
+impl<'a> Consoles<'a> {
+    fn lock() -> Consoles<'a> {
+        Consoles {
+            _marker: PhantomData,
             #[cfg(feature = "graphical_debug")]
             display: DEBUG_DISPLAY.lock(),
             #[cfg(feature = "lpss_debug")]
@@ -50,47 +96,58 @@ impl<'a> Writer<'a> {
             #[cfg(feature = "qemu_debug")]
             qemu: QEMU.lock(),
             #[cfg(feature = "serial_debug")]
-            serial: COM1.lock(),
+            serial: serial::console().lock(),
             #[cfg(feature = "system76_ec_debug")]
             system76_ec: SYSTEM76_EC.lock(),
         }
     }
 
-    pub fn write(&mut self, buf: &[u8]) {
-        {
-            if let Some(ref mut log) = *self.log {
-                log.write(buf);
-            }
-        }
+    fn try_lock() -> Option<Consoles<'a>> {
+        Some(Consoles {
+            _marker: PhantomData,
+            #[cfg(feature = "graphical_debug")]
+            display: DEBUG_DISPLAY.try_lock()?,
+            #[cfg(feature = "lpss_debug")]
+            lpss: LPSS.try_lock()?,
+            #[cfg(feature = "qemu_debug")]
+            qemu: QEMU.try_lock()?,
+            #[cfg(feature = "serial_debug")]
+            serial: serial::console().try_lock()?,
+            #[cfg(feature = "system76_ec_debug")]
+            system76_ec: SYSTEM76_EC.try_lock()?,
+        })
+    }
 
+    /// Write `buf` to the consoles whose sink threshold allows `level`.
+    fn write(&mut self, buf: &[u8], level: Level) {
         #[cfg(feature = "graphical_debug")]
-        {
+        if Sink::Display.enabled(level) {
             if let Some(ref mut display) = *self.display {
                 let _ = display.write(buf);
             }
         }
 
         #[cfg(feature = "lpss_debug")]
-        {
+        if Sink::Serial.enabled(level) {
             if let Some(ref mut lpss) = *self.lpss {
                 lpss.write(buf);
             }
         }
 
         #[cfg(feature = "qemu_debug")]
-        {
+        if Sink::Serial.enabled(level) {
             for &b in buf {
                 self.qemu.write(b);
             }
         }
 
         #[cfg(feature = "serial_debug")]
-        {
+        if Sink::Serial.enabled(level) {
             self.serial.write(buf);
         }
 
         #[cfg(feature = "system76_ec_debug")]
-        {
+        if Sink::Serial.enabled(level) {
             if let Some(ref mut system76_ec) = *self.system76_ec {
                 system76_ec.print_slice(buf);
             }
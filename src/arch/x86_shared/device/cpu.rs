@@ -1,6 +1,76 @@
-use core::fmt::{Result, Write};
+use core::{
+    cell::Cell,
+    fmt::{Result, Write},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use crate::arch::cpuid::cpuid;
+use raw_cpuid::TopologyType;
+use spin::Once;
+
+use crate::{
+    arch::cpuid::cpuid,
+    percpu::CpuTopology,
+    time::{self, NANOS_PER_SEC},
+};
+
+const IA32_MPERF: u32 = 0xE7;
+const IA32_APERF: u32 = 0xE8;
+
+/// How often the frequency of a CPU is measured, since reading the counters may trap to the
+/// hypervisor.
+const FREQUENCY_INTERVAL: u128 = NANOS_PER_SEC;
+
+/// Whether the CPUs have the APERF and MPERF counters.
+static APERF_MPERF: Once<bool> = Once::new();
+
+/// The effective frequency of a CPU, measured on its scheduler tick from the APERF and MPERF
+/// counters, which count at the actual and at the TSC frequency while the CPU is not halted.
+#[derive(Debug, Default)]
+pub struct Frequency {
+    khz: AtomicU64,
+    /// The monotonic time, APERF and MPERF at the last measurement
+    last: Cell<(u128, u64, u64)>,
+}
+
+impl Frequency {
+    /// Measure the frequency if it was last measured over a second ago. Must be called on the
+    /// CPU this is the frequency of.
+    pub fn sample(&self) {
+        let supported = *APERF_MPERF.call_once(|| {
+            cpuid()
+                .get_thermal_power_info()
+                .map_or(false, |info| info.has_hw_coord_feedback())
+        });
+        let (Some(tsc_khz), true) = (super::local_apic::tsc_khz(), supported) else {
+            return;
+        };
+        let now = time::monotonic();
+        let (last_time, last_aperf, last_mperf) = self.last.get();
+        if now < last_time + FREQUENCY_INTERVAL {
+            return;
+        }
+
+        let (aperf, mperf) = unsafe { (x86::msr::rdmsr(IA32_APERF), x86::msr::rdmsr(IA32_MPERF)) };
+        self.last.set((now, aperf, mperf));
+        // Nothing to compare the first reading with, or the CPU was halted throughout.
+        let mperf_delta = mperf.wrapping_sub(last_mperf);
+        if last_time == 0 || mperf_delta == 0 {
+            return;
+        }
+        let aperf_delta = aperf.wrapping_sub(last_aperf);
+        let khz = u128::from(tsc_khz) * u128::from(aperf_delta) / u128::from(mperf_delta);
+        self.khz
+            .store(u64::try_from(khz).unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    /// The frequency in kHz as last measured, if known.
+    pub fn khz(&self) -> Option<u64> {
+        match self.khz.load(Ordering::Relaxed) {
+            0 => None,
+            khz => Some(khz),
+        }
+    }
+}
 
 pub fn cpu_info<W: Write>(w: &mut W) -> Result {
     let cpuid = cpuid();
@@ -279,3 +349,38 @@ pub fn cpu_info<W: Write>(w: &mut W) -> Result {
 
     Ok(())
 }
+
+/// Topology of the current CPU, decoded from its APIC ID.
+pub fn topology() -> CpuTopology {
+    let cpuid = cpuid();
+
+    if let Some(levels) = cpuid.get_extended_topology_info() {
+        let mut x2apic_id = 0;
+        let mut smt_shift = 0;
+        let mut core_shift = 0;
+        for level in levels {
+            x2apic_id = level.x2apic_id();
+            match level.level_type() {
+                TopologyType::SMT => smt_shift = level.shift_right_for_next_apic_id(),
+                TopologyType::Core => core_shift = level.shift_right_for_next_apic_id(),
+                _ => (),
+            }
+        }
+        let core_shift = core_shift.max(smt_shift);
+        return CpuTopology {
+            package: x2apic_id.checked_shr(core_shift).unwrap_or(0),
+            core: (x2apic_id & ((1 << core_shift) - 1)) >> smt_shift,
+            thread: x2apic_id & ((1 << smt_shift) - 1),
+        };
+    }
+
+    // Without the extended topology leaf, treat each APIC as a separate core
+    let apic_id = cpuid
+        .get_feature_info()
+        .map_or(0, |info| u32::from(info.initial_local_apic_id()));
+    CpuTopology {
+        package: 0,
+        core: apic_id,
+        thread: 0,
+    }
+}
@@ -1,5 +1,5 @@
-use super::pit;
-use crate::acpi::hpet::Hpet;
+use super::{local_apic, pit};
+use crate::acpi::{hpet::Hpet, ACPI_TABLE};
 
 const LEG_RT_CNF: u64 = 2;
 const ENABLE_CNF: u64 = 1;
@@ -13,14 +13,91 @@ const GENERAL_CONFIG_OFFSET: usize = 0x10;
 const GENERAL_INTERRUPT_OFFSET: usize = 0x20;
 pub(crate) const MAIN_COUNTER_OFFSET: usize = 0xF0;
 // const NUM_TIMER_CAP_MASK: u64 = 0x0f00;
+const COUNT_SIZE_CAP: u64 = 0x2000;
 const LEG_RT_CAP: u64 = 0x8000;
 const T0_CONFIG_CAPABILITY_OFFSET: usize = 0x100;
 pub(crate) const T0_COMPARATOR_OFFSET: usize = 0x108;
 
 const PER_INT_CAP: u64 = 0x10;
 
+/// Femtoseconds over which the TSC and local APIC timer are calibrated, 10 ms.
+const CALIBRATION_FS: u64 = 10_000_000_000_000;
+
+/// The period of the main counter in femtoseconds.
+pub fn period_fs(hpet: &Hpet) -> u64 {
+    let capability = unsafe { hpet.base_address.read_u64(CAPABILITY_OFFSET) };
+    // There seems to be a bug in qemu on macos that causes the calculation to produce 0 for
+    // period_fs and hence a divide by zero calculating the divisor - workaround it while we
+    // try and get a fix from qemu: https://gitlab.com/qemu-project/qemu/-/issues/1570
+    match capability >> 32 {
+        0 => 10_000_000,
+        period_fs => period_fs,
+    }
+}
+
+/// Calibrate the TSC and the local APIC timer of the BSP against the main counter, before the
+/// APs are started, as they use the same calibration. The PIT is not precise enough for this.
+pub unsafe fn calibrate() {
+    if cfg!(target_arch = "x86") {
+        //TODO: fix HPET on i686
+        return;
+    }
+    let mut hpet_guard = ACPI_TABLE.hpet.write();
+    let Some(ref mut hpet) = *hpet_guard else {
+        return;
+    };
+
+    // Start the main counter, without routing any interrupts until the HPET is initialized.
+    let config_word = hpet.base_address.read_u64(GENERAL_CONFIG_OFFSET);
+    hpet.base_address
+        .write_u64(GENERAL_CONFIG_OFFSET, config_word | ENABLE_CNF);
+
+    let local_apic = &mut local_apic::LOCAL_APIC;
+    let tsc_khz = measure_khz(hpet, || x86::time::rdtsc());
+    local_apic.start_calibration();
+    let timer_khz = measure_khz(hpet, || local_apic.calibration_ticks());
+    hpet.base_address
+        .write_u64(GENERAL_CONFIG_OFFSET, config_word);
+    log::info!(
+        "HPET calibration: TSC {} kHz, local APIC timer {} kHz",
+        tsc_khz,
+        timer_khz
+    );
+    local_apic.set_calibration(tsc_khz, timer_khz);
+}
+
+/// Measure the rate in kHz at which the value returned by `read` increases, against the running
+/// main counter.
+unsafe fn measure_khz(hpet: &Hpet, mut read: impl FnMut() -> u64) -> u64 {
+    let period_fs = period_fs(hpet);
+    // Only the low 32 bits are valid if the main counter is not 64 bits wide.
+    let mask = if hpet.base_address.read_u64(CAPABILITY_OFFSET) & COUNT_SIZE_CAP == 0 {
+        u64::from(u32::MAX)
+    } else {
+        u64::MAX
+    };
+    let elapsed = |start: u64| {
+        hpet.base_address
+            .read_u64(MAIN_COUNTER_OFFSET)
+            .wrapping_sub(start)
+            & mask
+    };
+
+    let start = hpet.base_address.read_u64(MAIN_COUNTER_OFFSET);
+    let start_value = read();
+    while elapsed(start) < CALIBRATION_FS / period_fs {
+        core::hint::spin_loop();
+    }
+    let end_value = read();
+    let elapsed_fs = u128::from(elapsed(start)) * u128::from(period_fs);
+
+    // One millisecond is 10^12 femtoseconds.
+    let khz = u128::from(end_value.wrapping_sub(start_value)) * 1_000_000_000_000 / elapsed_fs;
+    u64::try_from(khz).unwrap_or(u64::MAX)
+}
+
 pub unsafe fn init(hpet: &mut Hpet) -> bool {
-    println!("HPET Before Init");
+    log::debug!("HPET Before Init");
     debug(hpet);
 
     // Disable HPET
@@ -37,8 +114,7 @@ pub unsafe fn init(hpet: &mut Hpet) -> bool {
         return false;
     }
 
-    let period_fs = capability >> 32;
-    let divisor = (pit::RATE as u64 * 1_000_000) / period_fs;
+    let divisor = (pit::RATE as u64 * 1_000_000) / period_fs(hpet);
 
     let t0_capabilities = hpet.base_address.read_u64(T0_CONFIG_CAPABILITY_OFFSET);
     if t0_capabilities & PER_INT_CAP == 0 {
@@ -65,46 +141,46 @@ pub unsafe fn init(hpet: &mut Hpet) -> bool {
             .write_u64(GENERAL_CONFIG_OFFSET, config_word);
     }
 
-    println!("HPET After Init");
+    log::debug!("HPET After Init");
     debug(hpet);
 
     true
 }
 
 pub unsafe fn debug(hpet: &mut Hpet) {
-    println!("HPET @ {:#x}", { hpet.base_address.address });
+    log::debug!("HPET @ {:#x}", { hpet.base_address.address });
 
     let capability = hpet.base_address.read_u64(CAPABILITY_OFFSET);
     {
-        println!("  caps: {:#x}", capability);
-        println!("    clock period: {}", (capability >> 32) as u32);
-        println!("    ID: {:#x}", (capability >> 16) as u16);
-        println!("    LEG_RT_CAP: {}", capability & (1 << 15) == (1 << 15));
-        println!(
+        log::debug!("  caps: {:#x}", capability);
+        log::debug!("    clock period: {}", (capability >> 32) as u32);
+        log::debug!("    ID: {:#x}", (capability >> 16) as u16);
+        log::debug!("    LEG_RT_CAP: {}", capability & (1 << 15) == (1 << 15));
+        log::debug!(
             "    COUNT_SIZE_CAP: {}",
             capability & (1 << 13) == (1 << 13)
         );
-        println!("    timers: {}", (capability >> 8) as u8 & 0x1F);
-        println!("    revision: {}", capability as u8);
+        log::debug!("    timers: {}", (capability >> 8) as u8 & 0x1F);
+        log::debug!("    revision: {}", capability as u8);
     }
 
     let config_word = hpet.base_address.read_u64(GENERAL_CONFIG_OFFSET);
-    println!("  config: {:#x}", config_word);
+    log::debug!("  config: {:#x}", config_word);
 
     let interrupt_status = hpet.base_address.read_u64(GENERAL_INTERRUPT_OFFSET);
-    println!("  interrupt status: {:#x}", interrupt_status);
+    log::debug!("  interrupt status: {:#x}", interrupt_status);
 
     let counter = hpet.base_address.read_u64(MAIN_COUNTER_OFFSET);
-    println!("  counter: {:#x}", counter);
+    log::debug!("  counter: {:#x}", counter);
 
     let t0_capabilities = hpet.base_address.read_u64(T0_CONFIG_CAPABILITY_OFFSET);
-    println!("  T0 caps: {:#x}", t0_capabilities);
-    println!(
+    log::debug!("  T0 caps: {:#x}", t0_capabilities);
+    log::debug!(
         "    interrupt routing: {:#x}",
         (t0_capabilities >> 32) as u32
     );
-    println!("    flags: {:#x}", t0_capabilities as u16);
+    log::debug!("    flags: {:#x}", t0_capabilities as u16);
 
     let t0_comparator = hpet.base_address.read_u64(T0_COMPARATOR_OFFSET);
-    println!("  T0 comparator: {:#x}", t0_comparator);
+    log::debug!("  T0 comparator: {:#x}", t0_comparator);
 }
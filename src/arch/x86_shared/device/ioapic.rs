@@ -1,18 +1,19 @@
-use core::{fmt, ptr};
+use core::{fmt, fmt::Write, ptr};
 
-use alloc::vec::Vec;
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
 use spin::Mutex;
 
 #[cfg(feature = "acpi")]
 use crate::acpi::madt::{self, Madt, MadtEntry, MadtIntSrcOverride, MadtIoApic};
 
 use crate::{
-    arch::interrupt::irq,
+    arch::{idt, interrupt::irq},
     memory::Frame,
     paging::{
         entry::EntryFlags, KernelMapper, Page, PageFlags, PhysicalAddress, RmmA, RmmArch,
         VirtualAddress,
     },
+    syscall::error::{Error, Result, EBUSY, EINVAL, ENOENT},
 };
 
 use super::pic;
@@ -113,15 +114,38 @@ impl IoApic {
         reg |= u64::from(mask) << 16;
         guard.write_ioredtbl(idx, reg);
     }
+    /// Change the physical local APIC ID an interrupt is delivered to.
+    pub fn set_destination(&self, gsi: u32, dest: u8) {
+        let idx = (gsi - self.gsi_start) as u8;
+        let mut guard = self.regs.lock();
+
+        let mut reg = guard.read_ioredtbl(idx);
+        reg &= !(0xFF << 56);
+        reg |= u64::from(dest) << 56;
+        guard.write_ioredtbl(idx, reg);
+    }
+    /// The redirection table, to restore it once the system has woken from sleep.
+    pub fn save(&self) -> Vec<u64> {
+        let mut guard = self.regs.lock();
+        (0..self.count)
+            .map(|idx| guard.read_ioredtbl(idx))
+            .collect()
+    }
+    pub fn restore(&self, entries: &[u64]) {
+        let mut guard = self.regs.lock();
+        for (idx, &entry) in (0..self.count).zip(entries) {
+            guard.write_ioredtbl(idx, entry);
+        }
+    }
 }
 #[repr(u8)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ApicTriggerMode {
     Edge = 0,
     Level = 1,
 }
 #[repr(u8)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ApicPolarity {
     ActiveHigh = 0,
     ActiveLow = 1,
@@ -217,6 +241,85 @@ pub struct Override {
     polarity: Polarity,
 }
 
+/// The routing of a GSI outside the legacy IRQs, as exchanged through `irq:gsi/<gsi>`, one
+/// `<key> <value>` line each.
+#[derive(Clone, Copy, Debug)]
+pub struct GsiRoute {
+    /// The extended IRQ the GSI is delivered as.
+    pub irq: u8,
+    /// The physical local APIC ID the GSI is delivered to.
+    pub dest: u8,
+    pub trigger_mode: ApicTriggerMode,
+    pub polarity: ApicPolarity,
+    pub masked: bool,
+}
+impl GsiRoute {
+    pub fn format(&self, gsi: u32) -> String {
+        let mut string = String::new();
+        let _ = writeln!(string, "irq {}", self.irq);
+        let _ = writeln!(string, "dest {:02x}", self.dest);
+        let _ = writeln!(
+            string,
+            "trigger {}",
+            match self.trigger_mode {
+                ApicTriggerMode::Edge => "edge",
+                ApicTriggerMode::Level => "level",
+            }
+        );
+        let _ = writeln!(
+            string,
+            "polarity {}",
+            match self.polarity {
+                ApicPolarity::ActiveHigh => "high",
+                ApicPolarity::ActiveLow => "low",
+            }
+        );
+        let _ = writeln!(string, "masked {}", u8::from(self.masked));
+        if let Some(over) = src_overrides().iter().find(|over| over.gsi == gsi) {
+            let _ = writeln!(string, "override {}", over.bus_irq);
+        }
+        string
+    }
+    /// Apply `<key> <value>` lines. A trigger mode or polarity of `default` is taken from the MADT
+    /// interrupt source override of `gsi`, if any, or else from the bus the GSI belongs to. All
+    /// lines are validated before any field is changed.
+    pub fn parse_and_set(&mut self, text: &str, gsi: u32) -> Result<()> {
+        let mut new = *self;
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (key, value) = line.split_once(' ').ok_or(Error::new(EINVAL))?;
+            let value = value.trim();
+            match (key, value) {
+                ("irq", _) => new.irq = value.parse().map_err(|_| Error::new(EINVAL))?,
+                ("dest", _) => {
+                    new.dest = u8::from_str_radix(value, 16).map_err(|_| Error::new(EINVAL))?
+                }
+                ("trigger", "edge") => new.trigger_mode = ApicTriggerMode::Edge,
+                ("trigger", "level") => new.trigger_mode = ApicTriggerMode::Level,
+                ("trigger", "default") => new.trigger_mode = default_mode(gsi).0,
+                ("polarity", "high") => new.polarity = ApicPolarity::ActiveHigh,
+                ("polarity", "low") => new.polarity = ApicPolarity::ActiveLow,
+                ("polarity", "default") => new.polarity = default_mode(gsi).1,
+                ("masked", "0") => new.masked = false,
+                ("masked", "1") => new.masked = true,
+                _ => return Err(Error::new(EINVAL)),
+            }
+        }
+        if is_kernel_irq(new.irq) {
+            return Err(Error::new(EINVAL));
+        }
+        *self = new;
+        Ok(())
+    }
+}
+
+/// Whether the vector of `irq` is used by the kernel itself, and cannot be a GSI route.
+fn is_kernel_irq(irq: u8) -> bool {
+    irq.checked_add(32).map_or(true, idt::is_kernel_vector)
+}
+
+/// The GSIs routed through the IRQ scheme, keyed by the extended IRQ they are delivered as.
+static GSI_ROUTES: Mutex<BTreeMap<u8, u32>> = Mutex::new(BTreeMap::new());
+
 // static mut because only the AP initializes the I/O Apic, and when that is done, it's solely
 // accessed immutably.
 static mut IOAPICS: Option<Vec<IoApic>> = None;
@@ -325,7 +428,7 @@ pub unsafe fn init(active_table: &mut KernelMapper) {
             }
         }
     }
-    println!(
+    log::info!(
         "I/O APICs: {:?}, overrides: {:?}",
         ioapics(),
         src_overrides()
@@ -357,7 +460,7 @@ pub unsafe fn init(active_table: &mut KernelMapper) {
         let apic = match find_ioapic(gsi) {
             Some(ioapic) => ioapic,
             None => {
-                println!("Unable to find a suitable APIC for legacy IRQ {} (GSI {}). It will not be mapped.", legacy_irq, gsi);
+                log::warn!("Unable to find a suitable APIC for legacy IRQ {} (GSI {}). It will not be mapped.", legacy_irq, gsi);
                 continue;
             }
         };
@@ -383,7 +486,7 @@ pub unsafe fn init(active_table: &mut KernelMapper) {
         };
         apic.map(redir_tbl_index, map_info);
     }
-    println!(
+    log::info!(
         "I/O APICs: {:?}, overrides: {:?}",
         ioapics(),
         src_overrides()
@@ -415,8 +518,37 @@ fn get_override(irq: u8) -> Option<&'static Override> {
     src_overrides().iter().find(|over| over.bus_irq == irq)
 }
 fn resolve(irq: u8) -> u32 {
+    if irq >= 16 {
+        if let Some(&gsi) = GSI_ROUTES.lock().get(&irq) {
+            return gsi;
+        }
+    }
     get_override(irq).map_or(u32::from(irq), |over| over.gsi)
 }
+/// Whether `gsi` is used by a legacy IRQ, which is mapped at boot and configured by the override.
+fn is_legacy_gsi(gsi: u32) -> bool {
+    (0..16).any(|irq| resolve(irq) == gsi) || src_overrides().iter().any(|over| over.gsi == gsi)
+}
+/// The trigger mode and polarity of `gsi` as given by its interrupt source override, or else the
+/// ISA defaults for the first 16 GSIs, and the PCI defaults for the rest.
+fn default_mode(gsi: u32) -> (ApicTriggerMode, ApicPolarity) {
+    let over = src_overrides().iter().find(|over| over.gsi == gsi);
+    let isa = over.is_some() || gsi < 16;
+
+    let trigger_mode = match over.map(|over| over.trigger_mode) {
+        Some(TriggerMode::Edge) => ApicTriggerMode::Edge,
+        Some(TriggerMode::Level) => ApicTriggerMode::Level,
+        _ if isa => ApicTriggerMode::Edge,
+        _ => ApicTriggerMode::Level,
+    };
+    let polarity = match over.map(|over| over.polarity) {
+        Some(Polarity::ActiveHigh) => ApicPolarity::ActiveHigh,
+        Some(Polarity::ActiveLow) => ApicPolarity::ActiveLow,
+        _ if isa => ApicPolarity::ActiveHigh,
+        _ => ApicPolarity::ActiveLow,
+    };
+    (trigger_mode, polarity)
+}
 fn find_ioapic(gsi: u32) -> Option<&'static IoApic> {
     ioapics()
         .iter()
@@ -431,6 +563,71 @@ pub unsafe fn mask(irq: u8) {
     };
     apic.set_mask(gsi, true);
 }
+/// Route a legacy IRQ to the local APIC `dest`. Returns false if no I/O APIC handles the IRQ.
+pub unsafe fn set_destination(irq: u8, dest: u8) -> bool {
+    let gsi = resolve(irq);
+    match find_ioapic(gsi) {
+        Some(apic) => {
+            apic.set_destination(gsi, dest);
+            true
+        }
+        None => false,
+    }
+}
+/// The current routing of `gsi`, or `None` if no I/O APIC handles it.
+pub fn gsi_route(gsi: u32) -> Option<GsiRoute> {
+    let apic = find_ioapic(gsi)?;
+    let raw = apic.regs.lock().read_ioredtbl((gsi - apic.gsi_start) as u8);
+
+    Some(GsiRoute {
+        irq: (raw as u8).saturating_sub(32),
+        dest: (raw >> 56) as u8,
+        trigger_mode: if raw & (1 << 15) != 0 {
+            ApicTriggerMode::Level
+        } else {
+            ApicTriggerMode::Edge
+        },
+        polarity: if raw & (1 << 13) != 0 {
+            ApicPolarity::ActiveLow
+        } else {
+            ApicPolarity::ActiveHigh
+        },
+        masked: raw & (1 << 16) != 0,
+    })
+}
+/// Deliver `gsi` as the extended IRQ of `route`, whose vector the caller must have reserved on the
+/// destination. GSIs of legacy IRQs cannot be rerouted.
+pub unsafe fn set_gsi_route(gsi: u32, route: GsiRoute) -> Result<()> {
+    if is_legacy_gsi(gsi) {
+        return Err(Error::new(EBUSY));
+    }
+    // Whatever the caller checked, a GSI must never fire on a vector used by the kernel itself.
+    if is_kernel_irq(route.irq) {
+        return Err(Error::new(EINVAL));
+    }
+    let apic = find_ioapic(gsi).ok_or(Error::new(ENOENT))?;
+
+    let mut routes = GSI_ROUTES.lock();
+    if routes.get(&route.irq).map_or(false, |&other| other != gsi) {
+        return Err(Error::new(EBUSY));
+    }
+    routes.retain(|_, other| *other != gsi);
+    routes.insert(route.irq, gsi);
+
+    apic.map(
+        (gsi - apic.gsi_start) as u8,
+        MapInfo {
+            dest: route.dest,
+            mask: route.masked,
+            trigger_mode: route.trigger_mode,
+            polarity: route.polarity,
+            dest_mode: DestinationMode::Physical,
+            delivery_mode: DeliveryMode::Fixed,
+            vector: route.irq + 32,
+        },
+    );
+    Ok(())
+}
 pub unsafe fn unmask(irq: u8) {
     let gsi = resolve(irq);
     let apic = match find_ioapic(gsi) {
@@ -1,10 +1,14 @@
 use core::{
     ptr::{read_volatile, write_volatile},
-    sync::atomic::{self, AtomicU32},
+    sync::atomic::{self, AtomicU32, AtomicU64, AtomicU8},
 };
 use x86::msr::*;
 
-use crate::{paging::{KernelMapper, PageFlags, PhysicalAddress, RmmA, RmmArch, VirtualAddress}, ipi::IpiKind};
+use crate::{
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    ipi::IpiKind,
+    paging::{KernelMapper, PageFlags, PhysicalAddress, RmmA, RmmArch, VirtualAddress},
+};
 
 use crate::arch::cpuid::cpuid;
 
@@ -32,6 +36,79 @@ struct NoFreqInfo;
 
 static BSP_APIC_ID: AtomicU32 = AtomicU32::new(u32::max_value());
 
+const NO_APIC_ID: AtomicU32 = AtomicU32::new(0);
+/// The local APIC ID of each logical CPU. Logical CPU IDs are assigned in the order the CPUs are
+/// started, as APIC IDs can be sparse, and up to 32 bits wide with x2APIC.
+static APIC_IDS: [AtomicU32; MAX_CPU_COUNT as usize] = [NO_APIC_ID; MAX_CPU_COUNT as usize];
+
+/// The local APIC ID of the logical CPU `cpu_id`.
+pub fn apic_id(cpu_id: LogicalCpuId) -> u32 {
+    APIC_IDS[cpu_id.get() as usize].load(atomic::Ordering::Relaxed)
+}
+/// The logical CPU of the local APIC `apic_id`, or `None` if no started CPU has it.
+pub fn logical_id(apic_id: u32) -> Option<LogicalCpuId> {
+    (0..crate::cpu_count())
+        .map(LogicalCpuId::new)
+        .find(|&cpu_id| self::apic_id(cpu_id) == apic_id)
+}
+/// Assign the logical CPU `cpu_id` to the local APIC `apic_id`, before the CPU is started.
+pub fn set_apic_id(cpu_id: LogicalCpuId, apic_id: u32) {
+    APIC_IDS[cpu_id.get() as usize].store(apic_id, atomic::Ordering::Relaxed);
+}
+
+/// Vector of the local APIC timer, which is used as the high-resolution timer.
+const TIMER_VECTOR: u32 = 48;
+/// Divide configuration making the local APIC timer count at its full rate.
+const TIMER_DIVIDE_BY_1: u32 = 0b1011;
+/// The TSC frequency in kHz, reported by CPUID or calibrated against the HPET, or 0 if unknown.
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+/// The rate of the local APIC timer in kHz, calibrated against the HPET, or 0 if unknown.
+static TIMER_KHZ: AtomicU64 = AtomicU64::new(0);
+/// The [`LvtTimerMode`] of the high-resolution timer, or `NO_HRTIMER`.
+static HRTIMER_MODE: AtomicU8 = AtomicU8::new(NO_HRTIMER);
+const NO_HRTIMER: u8 = u8::MAX;
+
+/// The TSC frequency in kHz, if known.
+pub fn tsc_khz() -> Option<u64> {
+    match TSC_KHZ.load(atomic::Ordering::Relaxed) {
+        0 => None,
+        khz => Some(khz),
+    }
+}
+
+/// The mode of the local APIC timer used as the high-resolution timer, and the rate in kHz at
+/// which it counts, or `None` if there is no high-resolution timer.
+pub fn hrtimer() -> Option<(LvtTimerMode, u64)> {
+    match HRTIMER_MODE.load(atomic::Ordering::Relaxed) {
+        mode if mode == LvtTimerMode::TscDeadline as u8 => Some((
+            LvtTimerMode::TscDeadline,
+            TSC_KHZ.load(atomic::Ordering::Relaxed),
+        )),
+        mode if mode == LvtTimerMode::OneShot as u8 => Some((
+            LvtTimerMode::OneShot,
+            TIMER_KHZ.load(atomic::Ordering::Relaxed),
+        )),
+        _ => None,
+    }
+}
+
+/// The TSC frequency in kHz as reported by CPUID, and whether it is exact rather than the
+/// nominal processor frequency.
+fn cpuid_tsc_khz() -> Option<(u64, bool)> {
+    let cpuid = cpuid();
+    if let Some(hz) = cpuid
+        .get_tsc_info()
+        .and_then(|tsc_info| tsc_info.tsc_frequency())
+    {
+        return Some((hz / 1000, true));
+    }
+    cpuid
+        .get_processor_frequency_info()
+        .map(|info| u64::from(info.processor_base_frequency()) * 1000)
+        .filter(|&khz| khz != 0)
+        .map(|khz| (khz, false))
+}
+
 #[no_mangle]
 pub fn bsp_apic_id() -> Option<u32> {
     let value = BSP_APIC_ID.load(atomic::Ordering::SeqCst);
@@ -42,6 +119,17 @@ pub fn bsp_apic_id() -> Option<u32> {
     }
 }
 
+/// The address and data a device must write to deliver `vector` to the logical CPU `cpu_id` as a
+/// message signaled interrupt, using fixed, edge-triggered delivery in physical destination mode.
+/// Returns `None` if the APIC ID of the CPU does not fit in the 8-bit destination field.
+pub fn msi_message(cpu_id: u32, vector: u8) -> Option<(u64, u32)> {
+    if cpu_id >= MAX_CPU_COUNT {
+        return None;
+    }
+    let apic_id = u8::try_from(apic_id(LogicalCpuId::new(cpu_id))).ok()?;
+    Some((0xFEE0_0000 | u64::from(apic_id) << 12, u32::from(vector)))
+}
+
 impl LocalApic {
     unsafe fn init(&mut self, mapper: &mut KernelMapper) {
         let mapper = mapper
@@ -75,6 +163,7 @@ impl LocalApic {
 
         self.init_ap();
         BSP_APIC_ID.store(self.id(), atomic::Ordering::SeqCst);
+        set_apic_id(LogicalCpuId::BSP, self.id());
     }
 
     unsafe fn init_ap(&mut self) {
@@ -85,7 +174,7 @@ impl LocalApic {
             self.write(0xF0, 0x100);
         }
         self.setup_error_int();
-        //self.setup_timer();
+        self.setup_hrtimer();
     }
 
     unsafe fn read(&self, reg: u32) -> u32 {
@@ -100,7 +189,8 @@ impl LocalApic {
         if self.x2 {
             unsafe { rdmsr(IA32_X2APIC_APICID) as u32 }
         } else {
-            unsafe { self.read(0x20) }
+            // The xAPIC ID is in the top byte.
+            unsafe { self.read(0x20) >> 24 }
         }
     }
 
@@ -125,7 +215,7 @@ impl LocalApic {
             unsafe {
                 wrmsr(IA32_X2APIC_ICR, value);
             }
-        } else {
+        } else if !super::paravirt::send_ipi(value) {
             unsafe {
                 const PENDING: u32 = 1 << 12;
                 while self.read(0x300) & PENDING == PENDING {
@@ -222,6 +312,20 @@ impl LocalApic {
             self.write(0x3E0, div_conf);
         }
     }
+    pub unsafe fn lvt_perf(&mut self) -> u32 {
+        if self.x2 {
+            rdmsr(IA32_X2APIC_LVT_PMI) as u32
+        } else {
+            self.read(0x340)
+        }
+    }
+    pub unsafe fn set_lvt_perf(&mut self, value: u32) {
+        if self.x2 {
+            wrmsr(IA32_X2APIC_LVT_PMI, u64::from(value));
+        } else {
+            self.write(0x340, value);
+        }
+    }
     pub unsafe fn lvt_error(&mut self) -> u32 {
         if self.x2 {
             rdmsr(IA32_X2APIC_LVT_ERROR) as u32
@@ -240,9 +344,63 @@ impl LocalApic {
         let vector = 49u32;
         self.set_lvt_error(vector);
     }
+    /// Set up the timer as the high-resolution timer. The TSC-deadline mode is used if supported
+    /// and the TSC frequency is known, unless the TSC is not invariant and the timer was
+    /// calibrated, in which case the one-shot mode is used instead.
+    unsafe fn setup_hrtimer(&mut self) {
+        let cpuid = cpuid();
+        if TSC_KHZ.load(atomic::Ordering::Relaxed) == 0 {
+            if let Some((khz, _)) = cpuid_tsc_khz() {
+                TSC_KHZ.store(khz, atomic::Ordering::Relaxed);
+            }
+        }
+        let tsc_deadline = TSC_KHZ.load(atomic::Ordering::Relaxed) != 0
+            && cpuid
+                .get_feature_info()
+                .map_or(false, |feature_info| feature_info.has_tsc_deadline());
+        let invariant_tsc = super::tsc::invariant();
+        let calibrated = TIMER_KHZ.load(atomic::Ordering::Relaxed) != 0;
+
+        let mode = if tsc_deadline && (invariant_tsc || !calibrated) {
+            LvtTimerMode::TscDeadline
+        } else if calibrated {
+            self.set_div_conf(TIMER_DIVIDE_BY_1);
+            LvtTimerMode::OneShot
+        } else {
+            return;
+        };
+        self.set_lvt_timer((mode as u32) << 17 | TIMER_VECTOR);
+        HRTIMER_MODE.store(mode as u8, atomic::Ordering::Relaxed);
+    }
+    /// Start the timer counting down from its maximum at its full rate, with the interrupt
+    /// masked, so that its rate can be measured from [`Self::calibration_ticks`].
+    pub unsafe fn start_calibration(&mut self) {
+        self.set_lvt_timer(1 << 16 | (LvtTimerMode::OneShot as u32) << 17 | TIMER_VECTOR);
+        self.set_div_conf(TIMER_DIVIDE_BY_1);
+        self.set_init_count(u32::MAX);
+    }
+    /// The number of ticks since [`Self::start_calibration`].
+    pub unsafe fn calibration_ticks(&mut self) -> u64 {
+        u64::from(u32::MAX - self.cur_count())
+    }
+    /// Use the TSC frequency and timer rate in kHz measured by the BSP, and set up the
+    /// high-resolution timer again. The TSC frequency reported by CPUID is kept if it is exact.
+    pub unsafe fn set_calibration(&mut self, tsc_khz: u64, timer_khz: u64) {
+        self.set_init_count(0);
+        if tsc_khz != 0 && !cpuid_tsc_khz().map_or(false, |(_, exact)| exact) {
+            TSC_KHZ.store(tsc_khz, atomic::Ordering::Relaxed);
+        }
+        TIMER_KHZ.store(timer_khz, atomic::Ordering::Relaxed);
+        self.setup_hrtimer();
+    }
+    /// Fire the timer when the TSC reaches `tsc`, or disarm it if 0.
+    pub unsafe fn set_tsc_deadline(&mut self, tsc: u64) {
+        wrmsr(IA32_TSC_DEADLINE, tsc);
+    }
 }
 
 #[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum LvtTimerMode {
     OneShot = 0b00,
     Periodic = 0b01,
@@ -3,16 +3,23 @@ pub mod cpu;
 pub mod hpet;
 pub mod ioapic;
 pub mod local_apic;
+pub mod paravirt;
 pub mod pic;
 pub mod pit;
 pub mod rtc;
 pub mod serial;
 #[cfg(feature = "system76_ec_debug")]
 pub mod system76_ec;
+pub mod tsc;
+pub mod watchdog;
+
+#[cfg(feature = "acpi")]
+use alloc::vec::Vec;
 
 use crate::paging::KernelMapper;
 
 pub unsafe fn init() {
+    paravirt::init();
     pic::init();
     local_apic::init(&mut KernelMapper::lock());
 }
@@ -49,6 +56,10 @@ pub unsafe fn init_noncore() {
         pit::init();
         log::info!("PIT used as system timer");
     }
+    if !paravirt::init_clocksource() {
+        tsc::init();
+    }
+    watchdog::init();
 
     log::info!("Initializing RTC");
     rtc::init();
@@ -58,5 +69,53 @@ pub unsafe fn init_noncore() {
 }
 
 pub unsafe fn init_ap() {
+    paravirt::init_ap();
+    local_apic::init_ap();
+    tsc::sync_ap();
+}
+
+/// Device state lost while the system sleeps.
+#[cfg(feature = "acpi")]
+pub struct SleepState {
+    ioapics: Vec<Vec<u64>>,
+    pic_masks: [u8; 2],
+}
+
+/// Save the state of the devices before the system goes to sleep.
+#[cfg(feature = "acpi")]
+pub unsafe fn suspend() -> SleepState {
+    SleepState {
+        ioapics: ioapic::ioapics()
+            .iter()
+            .map(|ioapic| ioapic.save())
+            .collect(),
+        pic_masks: pic::masks(),
+    }
+}
+
+/// Program the devices again once the system has woken from sleep, on the BSP.
+#[cfg(feature = "acpi")]
+pub unsafe fn resume(state: SleepState) {
+    pic::resume(state.pic_masks);
+    for (ioapic, entries) in ioapic::ioapics().iter().zip(&state.ioapics) {
+        ioapic.restore(entries);
+    }
+    if !init_hpet() {
+        pit::init();
+    }
+    paravirt::resume();
+    if tsc::is_clocksource() {
+        tsc::resume();
+    }
+    rtc::resume();
+    serial::resume();
+}
+
+/// Program the devices of the current CPU again once the system has woken from sleep.
+#[cfg(feature = "acpi")]
+pub unsafe fn resume_ap() {
+    paravirt::init_ap();
     local_apic::init_ap();
+    watchdog::touch();
+    watchdog::init_ap();
 }
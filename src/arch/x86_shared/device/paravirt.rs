@@ -0,0 +1,454 @@
+//! Paravirtual facilities of KVM and Hyper-V, used when running as their guest.
+//!
+//! - kvmclock, or the Hyper-V reference TSC page, becomes the clocksource. Both give the time from
+//!   the TSC with a scale maintained by the hypervisor, which stays right across migrations and
+//!   does not need an invariant TSC.
+//! - With the xAPIC, IPIs are sent by the KVM `SEND_IPI` hypercall instead of writing the ICR, as
+//!   every access to the memory mapped APIC traps to the hypervisor.
+//! - CPUs waiting for the context switch lock yield to the vCPU holding it with the KVM
+//!   `SCHED_YIELD` hypercall, in case the host preempted it.
+//!
+//! Hypercalls are only made on x86_64. Hyper-V hypercalls need the hypercall page, which is not
+//! set up, so only the clock is used on Hyper-V.
+
+use core::{
+    mem::size_of,
+    ptr::{addr_of, read_volatile},
+    sync::atomic::{fence, AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+
+use x86::msr::{rdmsr, wrmsr};
+
+use super::tsc::rdtsc_ordered;
+use crate::{
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    memory::{allocate_frame, Frame, PAGE_SIZE},
+    paging::{PhysicalAddress, RmmA, RmmArch},
+    percpu::PercpuBlock,
+    time,
+};
+
+const KVM_SIGNATURE: &[u8; 12] = b"KVMKVMKVM\0\0\0";
+const HYPERV_SIGNATURE: &[u8; 12] = b"Microsoft Hv";
+
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const KVM_FEATURE_PV_SEND_IPI: u32 = 1 << 11;
+const KVM_FEATURE_PV_SCHED_YIELD: u32 = 1 << 13;
+const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT: u32 = 1 << 24;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4B56_4D01;
+const KVM_HC_SEND_IPI: u64 = 10;
+const KVM_HC_SCHED_YIELD: u64 = 11;
+/// Set in the pvclock flags when the time agrees between all vCPUs.
+const PVCLOCK_TSC_STABLE_BIT: u8 = 1 << 0;
+
+const HV_MSR_TIME_REF_COUNT_AVAILABLE: u32 = 1 << 1;
+const HV_MSR_REFERENCE_TSC_AVAILABLE: u32 = 1 << 9;
+const HV_X64_MSR_GUEST_OS_ID: u32 = 0x4000_0000;
+const HV_X64_MSR_TIME_REF_COUNT: u32 = 0x4000_0020;
+const HV_X64_MSR_REFERENCE_TSC: u32 = 0x4000_0021;
+/// An open source OS, of no registered type.
+const HV_GUEST_OS_ID: u64 = 1 << 63;
+
+/// The time information kvmclock keeps up to date for each vCPU.
+#[repr(C)]
+struct PvclockTimeInfo {
+    /// Odd while being updated.
+    version: u32,
+    _pad0: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    _pad: [u8; 2],
+}
+const _: () = assert!(size_of::<PvclockTimeInfo>() * MAX_CPU_COUNT as usize <= PAGE_SIZE);
+
+/// The Hyper-V reference TSC page.
+#[repr(C)]
+struct ReferenceTsc {
+    /// 0 while the page is not valid, in which case the reference counter MSR is read instead.
+    sequence: u32,
+    _reserved: u32,
+    scale: u64,
+    offset: i64,
+}
+
+const CLOCK_NONE: u8 = 0;
+const CLOCK_KVM: u8 = 1;
+const CLOCK_HYPERV: u8 = 2;
+
+/// The paravirtual clock available, which is the clocksource once [`BASE`] is set.
+static CLOCK: AtomicU8 = AtomicU8::new(CLOCK_NONE);
+static CLOCKSOURCE: AtomicBool = AtomicBool::new(false);
+/// The clock when it became the clocksource.
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// The largest time read, as kvmclock may go backwards between vCPUs without the stable bit.
+static LAST: AtomicU64 = AtomicU64::new(0);
+static KVM_STABLE: AtomicBool = AtomicBool::new(false);
+/// The kvmclock areas of all CPUs, or the reference TSC page.
+static CLOCK_FRAME: AtomicUsize = AtomicUsize::new(0);
+
+static PV_SEND_IPI: AtomicBool = AtomicBool::new(false);
+static PV_SCHED_YIELD: AtomicBool = AtomicBool::new(false);
+/// Whether hypercalls use `vmmcall` rather than `vmcall`.
+static AMD: AtomicBool = AtomicBool::new(false);
+
+fn cpuid(leaf: u32) -> [u32; 4] {
+    #[cfg(target_arch = "x86")]
+    let result = unsafe { core::arch::x86::__cpuid(leaf) };
+    #[cfg(target_arch = "x86_64")]
+    let result = unsafe { core::arch::x86_64::__cpuid(leaf) };
+    [result.eax, result.ebx, result.ecx, result.edx]
+}
+
+/// Find the CPUID leaves of the hypervisor with `signature`, which KVM moves up by 0x100 when it
+/// also offers Hyper-V enlightenments.
+fn hypervisor_base(signature: &[u8; 12]) -> Option<u32> {
+    if cpuid(1)[2] & 1 << 31 == 0 {
+        return None;
+    }
+    (0x4000_0000..0x4001_0000).step_by(0x100).find(|&base| {
+        let [max, ebx, ecx, edx] = cpuid(base);
+        let mut found = [0_u8; 12];
+        found[..4].copy_from_slice(&ebx.to_le_bytes());
+        found[4..8].copy_from_slice(&ecx.to_le_bytes());
+        found[8..].copy_from_slice(&edx.to_le_bytes());
+        &found == signature && max >= base + 1
+    })
+}
+
+/// Detect the hypervisor and set up its facilities on the BSP, once frames can be allocated.
+pub unsafe fn init() {
+    if let Some(base) = hypervisor_base(KVM_SIGNATURE) {
+        init_kvm(cpuid(base + 1)[0]);
+    } else if hypervisor_base(HYPERV_SIGNATURE).is_some() {
+        init_hyperv(cpuid(0x4000_0003)[0]);
+    }
+}
+
+unsafe fn init_kvm(features: u32) {
+    log::info!("Running on KVM, features {:#x}", features);
+    AMD.store(
+        crate::arch::cpuid::cpuid()
+            .get_vendor_info()
+            .map_or(false, |vendor| {
+                matches!(vendor.as_str(), "AuthenticAMD" | "HygonGenuine")
+            }),
+        Ordering::Relaxed,
+    );
+
+    if features & KVM_FEATURE_CLOCKSOURCE2 != 0 {
+        if let Some(frame) = allocate_frame() {
+            // Areas are not registered while their version is 0.
+            zero_frame(frame);
+            CLOCK_FRAME.store(frame.start_address().data(), Ordering::Relaxed);
+            KVM_STABLE.store(
+                features & KVM_FEATURE_CLOCKSOURCE_STABLE_BIT != 0,
+                Ordering::Relaxed,
+            );
+            CLOCK.store(CLOCK_KVM, Ordering::Release);
+            register_kvmclock(LogicalCpuId::BSP);
+        }
+    }
+    if cfg!(target_arch = "x86_64") {
+        PV_SEND_IPI.store(features & KVM_FEATURE_PV_SEND_IPI != 0, Ordering::Relaxed);
+        PV_SCHED_YIELD.store(
+            features & KVM_FEATURE_PV_SCHED_YIELD != 0,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+unsafe fn init_hyperv(features: u32) {
+    log::info!("Running on Hyper-V, features {:#x}", features);
+    let needed = HV_MSR_TIME_REF_COUNT_AVAILABLE | HV_MSR_REFERENCE_TSC_AVAILABLE;
+    if features & needed != needed {
+        return;
+    }
+    let Some(frame) = allocate_frame() else {
+        return;
+    };
+    zero_frame(frame);
+    if rdmsr(HV_X64_MSR_GUEST_OS_ID) == 0 {
+        wrmsr(HV_X64_MSR_GUEST_OS_ID, HV_GUEST_OS_ID);
+    }
+    CLOCK_FRAME.store(frame.start_address().data(), Ordering::Relaxed);
+    wrmsr(
+        HV_X64_MSR_REFERENCE_TSC,
+        frame.start_address().data() as u64 | 1,
+    );
+    CLOCK.store(CLOCK_HYPERV, Ordering::Release);
+}
+
+/// Zero the clock frame before the hypervisor is given it, as its sequence numbers are read
+/// before the hypervisor first writes them.
+unsafe fn zero_frame(frame: Frame) {
+    (RmmA::phys_to_virt(frame.start_address()).data() as *mut u8).write_bytes(0, PAGE_SIZE);
+}
+
+/// Set up the facilities of the hypervisor on an AP.
+pub unsafe fn init_ap() {
+    if CLOCK.load(Ordering::Acquire) == CLOCK_KVM {
+        register_kvmclock(PercpuBlock::current().cpu_id);
+    }
+}
+
+/// Set up the clock of the hypervisor again once the system has woken from sleep, and restart
+/// counting from its current time. The kvmclock area of each CPU is registered by [`init_ap`].
+pub unsafe fn resume() {
+    let clock = CLOCK.load(Ordering::Acquire);
+    if clock == CLOCK_HYPERV {
+        if rdmsr(HV_X64_MSR_GUEST_OS_ID) == 0 {
+            wrmsr(HV_X64_MSR_GUEST_OS_ID, HV_GUEST_OS_ID);
+        }
+        wrmsr(
+            HV_X64_MSR_REFERENCE_TSC,
+            CLOCK_FRAME.load(Ordering::Relaxed) as u64 | 1,
+        );
+    }
+    if CLOCKSOURCE.load(Ordering::Acquire) {
+        let now = read_clock(clock);
+        BASE.store(now, Ordering::Relaxed);
+        LAST.store(now, Ordering::Relaxed);
+    }
+}
+
+fn clock_page() -> usize {
+    RmmA::phys_to_virt(PhysicalAddress::new(CLOCK_FRAME.load(Ordering::Relaxed))).data()
+}
+
+fn kvmclock_area(cpu_id: LogicalCpuId) -> *const PvclockTimeInfo {
+    (clock_page() as *const PvclockTimeInfo).wrapping_add(cpu_id.get() as usize)
+}
+
+/// Have the hypervisor keep the kvmclock area of `cpu_id` up to date, from that CPU.
+unsafe fn register_kvmclock(cpu_id: LogicalCpuId) {
+    let offset = cpu_id.get() as usize * size_of::<PvclockTimeInfo>();
+    wrmsr(
+        MSR_KVM_SYSTEM_TIME_NEW,
+        (CLOCK_FRAME.load(Ordering::Relaxed) + offset) as u64 | 1,
+    );
+}
+
+/// Make the paravirtual clock the clocksource, if there is one. Returns whether it was.
+pub fn init_clocksource() -> bool {
+    let clock = CLOCK.load(Ordering::Acquire);
+    if clock == CLOCK_NONE {
+        return false;
+    }
+
+    // Hold the clock while switching, so that it continues from the current time.
+    let mut offset = time::OFFSET.lock();
+    *offset += crate::arch::time::counter();
+    let now = read_clock(clock);
+    BASE.store(now, Ordering::Relaxed);
+    LAST.store(now, Ordering::Relaxed);
+    CLOCKSOURCE.store(true, Ordering::Release);
+    drop(offset);
+
+    log::info!(
+        "{} used as clocksource",
+        if clock == CLOCK_KVM {
+            "kvmclock"
+        } else {
+            "Hyper-V reference TSC"
+        }
+    );
+    true
+}
+
+pub fn is_clocksource() -> bool {
+    CLOCKSOURCE.load(Ordering::Acquire)
+}
+
+/// Nanoseconds since the paravirtual clock became the clocksource, or `None` if it is not.
+pub fn nanoseconds() -> Option<u128> {
+    if !is_clocksource() {
+        return None;
+    }
+    let now = read_clock(CLOCK.load(Ordering::Relaxed));
+    Some(u128::from(now.saturating_sub(BASE.load(Ordering::Relaxed))))
+}
+
+fn read_clock(clock: u8) -> u64 {
+    if clock == CLOCK_HYPERV {
+        return read_reference_tsc();
+    }
+    let cpu_id = PercpuBlock::current().cpu_id;
+    let (now, stable) = match read_kvmclock(cpu_id) {
+        Some(read) => read,
+        // Not registered yet on this AP.
+        None => read_kvmclock(LogicalCpuId::BSP).unwrap_or((0, true)),
+    };
+    if stable || KVM_STABLE.load(Ordering::Relaxed) {
+        now
+    } else {
+        LAST.fetch_max(now, Ordering::Relaxed).max(now)
+    }
+}
+
+/// The kvmclock time in nanoseconds from the area of `cpu_id`, and whether the host reports it
+/// stable, or `None` if the area was never written.
+fn read_kvmclock(cpu_id: LogicalCpuId) -> Option<(u64, bool)> {
+    let area = kvmclock_area(cpu_id);
+    loop {
+        let version = unsafe { read_volatile(addr_of!((*area).version)) };
+        if version == 0 {
+            return None;
+        }
+        if version & 1 != 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+        fence(Ordering::Acquire);
+        let (timestamp, system_time, mul, shift, flags) = unsafe {
+            (
+                read_volatile(addr_of!((*area).tsc_timestamp)),
+                read_volatile(addr_of!((*area).system_time)),
+                read_volatile(addr_of!((*area).tsc_to_system_mul)),
+                read_volatile(addr_of!((*area).tsc_shift)),
+                read_volatile(addr_of!((*area).flags)),
+            )
+        };
+        let delta = rdtsc_ordered().wrapping_sub(timestamp);
+        fence(Ordering::Acquire);
+        if unsafe { read_volatile(addr_of!((*area).version)) } != version {
+            continue;
+        }
+
+        let delta = if shift < 0 {
+            delta >> -shift
+        } else {
+            delta << shift
+        };
+        let now = system_time.wrapping_add(((u128::from(delta) * u128::from(mul)) >> 32) as u64);
+        return Some((now, flags & PVCLOCK_TSC_STABLE_BIT != 0));
+    }
+}
+
+/// The Hyper-V reference time in nanoseconds.
+fn read_reference_tsc() -> u64 {
+    let page = clock_page() as *const ReferenceTsc;
+    loop {
+        let sequence = unsafe { read_volatile(addr_of!((*page).sequence)) };
+        if sequence == 0 {
+            // Counted in 100 ns units.
+            return unsafe { rdmsr(HV_X64_MSR_TIME_REF_COUNT) } * 100;
+        }
+        fence(Ordering::Acquire);
+        let (scale, offset) = unsafe {
+            (
+                read_volatile(addr_of!((*page).scale)),
+                read_volatile(addr_of!((*page).offset)),
+            )
+        };
+        let tsc = rdtsc_ordered();
+        fence(Ordering::Acquire);
+        if unsafe { read_volatile(addr_of!((*page).sequence)) } != sequence {
+            continue;
+        }
+
+        let time = ((u128::from(tsc) * u128::from(scale)) >> 64) as u64;
+        return time.wrapping_add(offset as u64) * 100;
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn kvm_hypercall(nr: u64, a0: u64, a1: u64, a2: u64, a3: u64) -> i64 {
+    let ret: i64;
+    // RBX is reserved by LLVM, so it is swapped in for the call.
+    if AMD.load(Ordering::Relaxed) {
+        core::arch::asm!(
+            "xchg {a0}, rbx",
+            "vmmcall",
+            "xchg {a0}, rbx",
+            a0 = inout(reg) a0 => _,
+            inout("rax") nr => ret,
+            in("rcx") a1,
+            in("rdx") a2,
+            in("rsi") a3,
+            options(nostack),
+        );
+    } else {
+        core::arch::asm!(
+            "xchg {a0}, rbx",
+            "vmcall",
+            "xchg {a0}, rbx",
+            a0 = inout(reg) a0 => _,
+            inout("rax") nr => ret,
+            in("rcx") a1,
+            in("rdx") a2,
+            in("rsi") a3,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Send the IPI described by the xAPIC ICR value `icr` with a hypercall. Returns false if it must
+/// be sent by writing the ICR instead.
+pub fn send_ipi(icr: u64) -> bool {
+    #[cfg(target_arch = "x86_64")]
+    if PV_SEND_IPI.load(Ordering::Relaxed) {
+        use super::local_apic::apic_id;
+
+        const FIXED: u64 = 0b000 << 8;
+        const NMI: u64 = 0b100 << 8;
+        const LOGICAL_DESTINATION: u64 = 1 << 11;
+        // INIT and startup IPIs, and logical destinations, go through the ICR.
+        let delivery_mode = icr & 0b111 << 8;
+        if delivery_mode != FIXED && delivery_mode != NMI || icr & LOGICAL_DESTINATION != 0 {
+            return false;
+        }
+
+        let current = PercpuBlock::current().cpu_id;
+        let shorthand = (icr >> 18) & 0b11;
+        let mut bitmap = 0_u128;
+        for cpu_id in (0..crate::cpu_count()).map(LogicalCpuId::new) {
+            let apic_id = apic_id(cpu_id);
+            let targeted = match shorthand {
+                0 => apic_id == (icr >> 56) as u32,
+                2 => true,
+                3 => cpu_id != current,
+                // Self IPIs do not leave the CPU.
+                _ => return false,
+            };
+            if !targeted {
+                continue;
+            }
+            // The bitmap covers APIC IDs 0 to 127.
+            if apic_id >= 128 {
+                return false;
+            }
+            bitmap |= 1 << apic_id;
+        }
+        if bitmap == 0 {
+            return false;
+        }
+
+        let ret = unsafe {
+            kvm_hypercall(
+                KVM_HC_SEND_IPI,
+                bitmap as u64,
+                (bitmap >> 64) as u64,
+                0,
+                icr & 0xFFFF_FFFF & !(0b11 << 18),
+            )
+        };
+        return ret >= 0;
+    }
+    let _ = icr;
+    false
+}
+
+/// Hint the hypervisor that the current vCPU is waiting on `cpu_id`, which may have been
+/// preempted, so that it is run instead.
+pub fn yield_to(cpu_id: LogicalCpuId) {
+    #[cfg(target_arch = "x86_64")]
+    if PV_SCHED_YIELD.load(Ordering::Relaxed) {
+        let apic_id = super::local_apic::apic_id(cpu_id);
+        unsafe { kvm_hypercall(KVM_HC_SCHED_YIELD, u64::from(apic_id), 0, 0, 0) };
+    }
+    let _ = cpu_id;
+}
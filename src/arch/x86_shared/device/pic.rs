@@ -7,6 +7,22 @@ pub static mut MASTER: Pic = Pic::new(0x20);
 pub static mut SLAVE: Pic = Pic::new(0xA0);
 
 pub unsafe fn init() {
+    program([0, 0]);
+
+    // probably already set to PIC, but double-check
+    irq::set_irq_method(irq::IrqMethod::Pic);
+}
+
+/// The interrupt masks, to program the PICs again with them once the system has woken from sleep.
+pub unsafe fn masks() -> [u8; 2] {
+    [MASTER.data.read(), SLAVE.data.read()]
+}
+
+pub unsafe fn resume(masks: [u8; 2]) {
+    program(masks);
+}
+
+unsafe fn program(masks: [u8; 2]) {
     // Start initialization
     MASTER.cmd.write(0x11);
     SLAVE.cmd.write(0x11);
@@ -24,15 +40,12 @@ pub unsafe fn init() {
     SLAVE.data.write(1);
 
     // Unmask interrupts
-    MASTER.data.write(0);
-    SLAVE.data.write(0);
+    MASTER.data.write(masks[0]);
+    SLAVE.data.write(masks[1]);
 
     // Ack remaining interrupts
     MASTER.ack();
     SLAVE.ack();
-
-    // probably already set to PIC, but double-check
-    irq::set_irq_method(irq::IrqMethod::Pic);
 }
 
 pub unsafe fn disable() {
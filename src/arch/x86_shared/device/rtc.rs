@@ -8,6 +8,14 @@ pub fn init() {
     *time::START.lock() = (rtc.time() as u128) * time::NANOS_PER_SEC;
 }
 
+/// Correct the start time once the system has woken from sleep, as the monotonic clock does not
+/// advance while sleeping.
+pub fn resume() {
+    let mut rtc = Rtc::new();
+    let now = (rtc.time() as u128) * time::NANOS_PER_SEC;
+    *time::START.lock() = now.saturating_sub(time::monotonic());
+}
+
 fn cvt_bcd(value: usize) -> usize {
     (value & 0xF) + ((value / 16) * 10)
 }
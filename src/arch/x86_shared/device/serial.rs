@@ -1,6 +1,15 @@
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
 #[cfg(feature = "lpss_debug")]
 use crate::syscall::io::Mmio;
-use crate::{devices::uart_16550::SerialPort, syscall::io::Pio};
+use crate::{
+    devices::{
+        serial_console::{self, SerialConsolePort},
+        uart_16550::SerialPort,
+    },
+    log::warn,
+    syscall::io::Pio,
+};
 use spin::Mutex;
 
 pub static COM1: Mutex<SerialPort<Pio<u8>>> = Mutex::new(SerialPort::<Pio<u8>>::new(0x3F8));
@@ -11,14 +20,81 @@ pub static COM4: Mutex<SerialPort<Pio<u8>>> = Mutex::new(SerialPort::<Pio<u8>>::
 #[cfg(feature = "lpss_debug")]
 pub static LPSS: Mutex<Option<&'static mut SerialPort<Mmio<u32>>>> = Mutex::new(None);
 
+static PORTS: [&Mutex<SerialPort<Pio<u8>>>; 4] = [&COM1, &COM2, &COM3, &COM4];
+pub const PORT_BASES: [u16; 4] = [0x3F8, 0x2F8, 0x3E8, 0x2E8];
+
+/// Index into [`PORTS`] of the port used as console.
+static CONSOLE: AtomicUsize = AtomicUsize::new(0);
+static CONSOLE_BAUD: AtomicU32 = AtomicU32::new(115200);
+/// Physical address of the LPSS UART.
+#[cfg(feature = "lpss_debug")]
+static LPSS_ADDRESS: AtomicUsize = AtomicUsize::new(0xFE032000);
+
+/// The port kernel output is written to.
+pub fn console() -> &'static Mutex<SerialPort<Pio<u8>>> {
+    PORTS[CONSOLE.load(Ordering::Relaxed)]
+}
+
+/// The I/O port base of the console.
+pub fn console_base() -> u16 {
+    PORT_BASES[CONSOLE.load(Ordering::Relaxed)]
+}
+
+/// Switch the console to the one selected by the boot environment, before anything is logged.
+/// Only COM1 and COM2 receive input, as the other ports share their interrupts.
+pub unsafe fn init_console(env: &[u8]) {
+    let Some(console) = serial_console::parse(env) else {
+        return;
+    };
+    let index = match console.port {
+        SerialConsolePort::Tty(index) => usize::from(index),
+        SerialConsolePort::Io(base) => PORT_BASES
+            .iter()
+            .position(|&port_base| port_base == base)
+            .unwrap_or(PORTS.len()),
+        #[cfg(feature = "lpss_debug")]
+        SerialConsolePort::Mmio32(address) => {
+            LPSS_ADDRESS.store(address, Ordering::Relaxed);
+            return;
+        }
+        _ => PORTS.len(),
+    };
+    if index >= PORTS.len() {
+        warn!("console={:?} is not supported, using COM1", console.port);
+        return;
+    }
+
+    CONSOLE.store(index, Ordering::Relaxed);
+    if let Some(baud) = console.baud {
+        CONSOLE_BAUD.store(baud, Ordering::Relaxed);
+    }
+    PORTS[index]
+        .lock()
+        .init_with_baud(CONSOLE_BAUD.load(Ordering::Relaxed));
+}
+
+/// Program the ports again once the system has woken from sleep.
+pub unsafe fn resume() {
+    init_ports();
+}
+
+unsafe fn init_ports() {
+    for (index, port) in PORTS.iter().enumerate() {
+        if index == CONSOLE.load(Ordering::Relaxed) {
+            port.lock()
+                .init_with_baud(CONSOLE_BAUD.load(Ordering::Relaxed));
+        } else if index < 2 {
+            port.lock().init();
+        }
+    }
+}
+
 pub unsafe fn init() {
-    COM1.lock().init();
-    COM2.lock().init();
+    init_ports();
 
     #[cfg(feature = "lpss_debug")]
     {
-        // TODO: Make this configurable
-        let address = crate::PHYS_OFFSET + 0xFE032000;
+        let address = crate::PHYS_OFFSET + LPSS_ADDRESS.load(Ordering::Relaxed);
 
         {
             use crate::{
@@ -38,7 +114,7 @@ pub unsafe fn init() {
             result.flush(&mut active_table);
         }
 
-        let lpss = SerialPort::<Mmio<u32>>::new(crate::PHYS_OFFSET + 0xFE032000);
+        let lpss = SerialPort::<Mmio<u32>>::new(address);
         lpss.init();
 
         *LPSS.lock() = Some(lpss);
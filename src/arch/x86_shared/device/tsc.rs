@@ -0,0 +1,135 @@
+//! The TSC as the monotonic clocksource.
+//!
+//! The TSC is only used if it is invariant, its frequency is known, and it was never seen going
+//! backwards between the BSP and an AP while the APs were started. Otherwise the HPET or PIT
+//! remains the clocksource.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use spin::Mutex;
+
+use super::local_apic;
+use crate::{arch::cpuid::cpuid, interrupt, time};
+
+/// Rounds of the warp check run by both the BSP and the AP being started.
+const WARP_CHECK_ROUNDS: usize = 10_000;
+
+const SYNC_IDLE: u8 = 0;
+const SYNC_AP_ARRIVED: u8 = 1;
+const SYNC_AP_DONE: u8 = 2;
+
+/// Whether the TSC is the clocksource.
+static CLOCKSOURCE: AtomicBool = AtomicBool::new(false);
+/// The TSC when it became the clocksource.
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds per TSC cycle, as a 32.32 fixed point number.
+static MULT: AtomicU64 = AtomicU64::new(0);
+
+/// Progress of the AP currently running the warp check with the BSP.
+static SYNC_STATE: AtomicU8 = AtomicU8::new(SYNC_IDLE);
+/// The last TSC read during the warp check, by either CPU.
+static LAST_TSC: Mutex<u64> = Mutex::new(0);
+/// The largest step backwards of the TSC seen during the warp checks, in cycles.
+static MAX_WARP: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the TSC runs at a constant rate in all power states.
+pub fn invariant() -> bool {
+    cpuid()
+        .get_advanced_power_mgmt_info()
+        .map_or(false, |info| info.has_invariant_tsc())
+}
+
+/// Read the TSC after all previous instructions have completed.
+pub fn rdtsc_ordered() -> u64 {
+    unsafe {
+        core::arch::asm!("lfence", options(nostack, preserves_flags));
+        x86::time::rdtsc()
+    }
+}
+
+/// Read the TSC alternately with the other CPU running the check, recording any time it appears
+/// to go backwards.
+fn check_warp() {
+    for _ in 0..WARP_CHECK_ROUNDS {
+        let mut last = LAST_TSC.lock();
+        let now = rdtsc_ordered();
+        if now < *last {
+            MAX_WARP.fetch_max(*last - now, Ordering::Relaxed);
+        }
+        *last = now;
+    }
+}
+
+/// Run the warp check on the BSP with the AP being started, which calls [`sync_ap`].
+pub fn sync_bsp() {
+    if !invariant() {
+        return;
+    }
+    while SYNC_STATE.load(Ordering::Acquire) == SYNC_IDLE {
+        interrupt::pause();
+    }
+    check_warp();
+    while SYNC_STATE.load(Ordering::Acquire) != SYNC_AP_DONE {
+        interrupt::pause();
+    }
+    SYNC_STATE.store(SYNC_IDLE, Ordering::Release);
+}
+
+/// Run the warp check on an AP being started, with the BSP in [`sync_bsp`].
+pub fn sync_ap() {
+    if !invariant() {
+        return;
+    }
+    SYNC_STATE.store(SYNC_AP_ARRIVED, Ordering::Release);
+    check_warp();
+    SYNC_STATE.store(SYNC_AP_DONE, Ordering::Release);
+}
+
+/// Make the TSC the clocksource if it is safe to do so, after all CPUs have been started.
+pub fn init() {
+    let Some(khz) = local_apic::tsc_khz() else {
+        log::info!("TSC frequency unknown, not used as clocksource");
+        return;
+    };
+    if !invariant() {
+        log::info!("TSC not invariant, not used as clocksource");
+        return;
+    }
+    let warp = MAX_WARP.load(Ordering::Relaxed);
+    if warp != 0 {
+        log::warn!(
+            "TSC out of sync between CPUs by up to {} cycles, not used as clocksource",
+            warp
+        );
+        return;
+    }
+
+    // Hold the clock while switching, so that it continues from the current time.
+    let mut offset = time::OFFSET.lock();
+    *offset += crate::arch::time::counter();
+    MULT.store((1_000_000 << 32) / khz, Ordering::Relaxed);
+    BASE.store(rdtsc_ordered(), Ordering::Relaxed);
+    CLOCKSOURCE.store(true, Ordering::Release);
+    drop(offset);
+
+    log::info!("TSC used as clocksource at {} kHz", khz);
+}
+
+/// Restart counting from the current TSC, which was reset while the system slept.
+pub fn resume() {
+    BASE.store(rdtsc_ordered(), Ordering::Relaxed);
+}
+
+/// Whether the TSC is the clocksource, in which case the clock is not advanced by the tick.
+pub fn is_clocksource() -> bool {
+    CLOCKSOURCE.load(Ordering::Acquire)
+}
+
+/// Nanoseconds since the TSC became the clocksource, or `None` if it is not.
+pub fn nanoseconds() -> Option<u128> {
+    if !is_clocksource() {
+        return None;
+    }
+    let cycles = rdtsc_ordered().saturating_sub(BASE.load(Ordering::Relaxed));
+    Some((u128::from(cycles) * u128::from(MULT.load(Ordering::Relaxed))) >> 32)
+}
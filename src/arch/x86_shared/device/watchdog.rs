@@ -0,0 +1,305 @@
+//! Hard lockup detection, using NMIs raised by a performance counter.
+//!
+//! A performance counter counting unhalted core cycles raises an NMI on each CPU about once a
+//! second while the CPU is running. The handler checks that the scheduler tick of the CPU is still
+//! advancing, and records the stack of the CPU if it stopped for [`THRESHOLD_SECS`], which happens
+//! when the CPU is stuck with interrupts disabled. CPUs idle with their tick stopped are skipped.
+//!
+//! The code interrupted may be holding the log or a console, so the handler only prints the
+//! report if it can lock them without waiting. Otherwise the report is printed from the next
+//! scheduler tick of any CPU.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use spin::{Mutex, Once};
+use x86::msr::{rdmsr, wrmsr};
+
+use super::local_apic::{self, LOCAL_APIC};
+use crate::{
+    arch::{cpuid::cpuid, debug::Writer},
+    cpu_set::MAX_CPU_COUNT,
+    interrupt::{trace::Trace, InterruptStack},
+    log::{Level, Subsystem, Tag},
+    percpu::PercpuBlock,
+};
+
+/// Log target of the reports, putting them in [`Subsystem::Watchdog`].
+const TARGET: &str = "kernel::watchdog";
+
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+const MSR_K7_EVNTSEL0: u32 = 0xC001_0000;
+const MSR_K7_PERFCTR0: u32 = 0xC001_0004;
+
+/// Count in user mode.
+const EVTSEL_USR: u64 = 1 << 16;
+/// Count in kernel mode.
+const EVTSEL_OS: u64 = 1 << 17;
+/// Interrupt through the LVT performance counter entry on overflow.
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+/// Architectural event counting unhalted core cycles.
+const INTEL_EVENT_CYCLES: u64 = 0x3C;
+/// AMD event counting CPU clocks not halted.
+const AMD_EVENT_CYCLES: u64 = 0x76;
+
+/// LVT entry delivering the counter overflow as an NMI.
+const LVT_NMI: u32 = 0b100 << 8;
+
+/// How long the tick of a CPU may stop before it is reported, in seconds.
+const THRESHOLD_SECS: u64 = 10;
+
+/// The performance counter used by the watchdog, detected on the BSP.
+struct Counter {
+    evtsel: u32,
+    ctr: u32,
+    event: u64,
+    /// Width of the counter in bits.
+    width: u32,
+    /// Whether the counter must also be enabled in `IA32_PERF_GLOBAL_CTRL`.
+    global_ctrl: bool,
+    /// Cycles between NMIs, kept below 2^31 as Intel counters are written sign-extended from
+    /// 32 bits.
+    period: u64,
+}
+
+impl Counter {
+    fn detect(tsc_khz: u64) -> Option<Self> {
+        let cpuid = cpuid();
+        let period = (tsc_khz * 1000).min(i32::MAX as u64);
+        let amd = cpuid.get_vendor_info().map_or(false, |vendor| {
+            matches!(vendor.as_str(), "AuthenticAMD" | "HygonGenuine")
+        });
+        if amd {
+            return Some(Self {
+                evtsel: MSR_K7_EVNTSEL0,
+                ctr: MSR_K7_PERFCTR0,
+                event: AMD_EVENT_CYCLES,
+                width: 48,
+                global_ctrl: false,
+                period,
+            });
+        }
+
+        let info = cpuid.get_performance_monitoring_info()?;
+        if info.version_id() == 0
+            || info.number_of_counters() == 0
+            || info.is_core_cyc_ev_unavailable()
+        {
+            return None;
+        }
+        Some(Self {
+            evtsel: IA32_PERFEVTSEL0,
+            ctr: IA32_PMC0,
+            event: INTEL_EVENT_CYCLES,
+            width: u32::from(info.counter_bit_width()),
+            global_ctrl: info.version_id() >= 2,
+            period,
+        })
+    }
+
+    unsafe fn reload(&self) {
+        let mask = (1 << self.width) - 1;
+        wrmsr(self.ctr, self.period.wrapping_neg() & mask);
+    }
+
+    unsafe fn arm(&self) {
+        wrmsr(self.evtsel, 0);
+        self.reload();
+        if self.global_ctrl {
+            wrmsr(IA32_PERF_GLOBAL_CTRL, rdmsr(IA32_PERF_GLOBAL_CTRL) | 1);
+        }
+        LOCAL_APIC.set_lvt_perf(LVT_NMI);
+        wrmsr(
+            self.evtsel,
+            self.event | EVTSEL_USR | EVTSEL_OS | EVTSEL_INT | EVTSEL_EN,
+        );
+    }
+
+    /// Whether the counter overflowed, as it starts at `-period` and so has its top bit set until
+    /// then.
+    unsafe fn overflowed(&self) -> bool {
+        rdmsr(self.ctr) & (1 << (self.width - 1)) == 0
+    }
+
+    unsafe fn rearm(&self) {
+        self.reload();
+        if self.global_ctrl {
+            wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, 1);
+        }
+        // The LVT entry is masked when the NMI is delivered.
+        LOCAL_APIC.set_lvt_perf(LVT_NMI);
+    }
+}
+
+/// The last tick seen by the watchdog on a CPU.
+struct Watch {
+    armed: AtomicBool,
+    ticks: AtomicUsize,
+    /// The TSC when the tick last advanced.
+    progress: AtomicU64,
+    /// Whether the current lockup was already reported.
+    reported: AtomicBool,
+    /// Whether the report of the lockup is waiting to be printed from a timer tick.
+    pending: AtomicBool,
+}
+
+const UNARMED: Watch = Watch {
+    armed: AtomicBool::new(false),
+    ticks: AtomicUsize::new(0),
+    progress: AtomicU64::new(0),
+    reported: AtomicBool::new(false),
+    pending: AtomicBool::new(false),
+};
+static WATCHES: [Watch; MAX_CPU_COUNT as usize] = [UNARMED; MAX_CPU_COUNT as usize];
+
+/// A hard lockup, recorded by the NMI handler of the CPU.
+struct Report {
+    stalled_ms: u64,
+    trace: Trace,
+}
+
+/// The first line of a report, from the CPU and how long it went without a tick in ms.
+struct Header(usize, u64);
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "NMI watchdog: hard lockup on CPU {}, no tick for {} ms",
+            self.0, self.1
+        )
+    }
+}
+
+const NO_REPORT: Mutex<Report> = Mutex::new(Report {
+    stalled_ms: 0,
+    trace: Trace::EMPTY,
+});
+static REPORTS: [Mutex<Report>; MAX_CPU_COUNT as usize] = [NO_REPORT; MAX_CPU_COUNT as usize];
+
+static COUNTER: Once<Counter> = Once::new();
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+
+/// Start watching the current CPU.
+unsafe fn arm(counter: &Counter) {
+    let percpu = PercpuBlock::current();
+    let watch = &WATCHES[percpu.cpu_id.get() as usize];
+    watch
+        .ticks
+        .store(percpu.switch_internals.total_ticks(), Ordering::Relaxed);
+    watch.progress.store(x86::time::rdtsc(), Ordering::Relaxed);
+    watch.reported.store(false, Ordering::Relaxed);
+    watch.armed.store(true, Ordering::Relaxed);
+    counter.arm();
+}
+
+/// Detect the performance counter and start watching the BSP, once the TSC frequency is known.
+pub unsafe fn init() {
+    let Some(tsc_khz) = local_apic::tsc_khz() else {
+        log::info!("NMI watchdog: TSC frequency unknown, disabled");
+        return;
+    };
+    let Some(counter) = Counter::detect(tsc_khz) else {
+        log::info!("NMI watchdog: no usable performance counter, disabled");
+        return;
+    };
+    TSC_KHZ.store(tsc_khz, Ordering::Relaxed);
+    let counter = COUNTER.call_once(|| counter);
+    arm(counter);
+    log::info!(
+        "NMI watchdog: reporting CPUs without a tick for {} s",
+        THRESHOLD_SECS
+    );
+}
+
+/// Start watching an AP, once it is about to start scheduling.
+pub unsafe fn init_ap() {
+    if let Some(counter) = COUNTER.get() {
+        arm(counter);
+    }
+}
+
+/// Restart the lockup timer of the current CPU, after it legitimately ran with interrupts disabled
+/// for long, such as when stopped in the GDB stub.
+pub fn touch() {
+    let watch = &WATCHES[PercpuBlock::current().cpu_id.get() as usize];
+    watch
+        .progress
+        .store(unsafe { x86::time::rdtsc() }, Ordering::Relaxed);
+}
+
+/// Handle an NMI, returning whether it was raised by the watchdog.
+pub unsafe fn nmi(stack: &InterruptStack) -> bool {
+    let Some(counter) = COUNTER.get() else {
+        return false;
+    };
+    let percpu = PercpuBlock::current();
+    let cpu_id = percpu.cpu_id.get() as usize;
+    let watch = &WATCHES[cpu_id];
+    if !watch.armed.load(Ordering::Relaxed) || !counter.overflowed() {
+        return false;
+    }
+
+    let now = x86::time::rdtsc();
+    let ticks = percpu.switch_internals.total_ticks();
+    // An idle CPU with its tick stopped is not locked up, and counts from when it restarts.
+    if watch.ticks.swap(ticks, Ordering::Relaxed) != ticks
+        || crate::context::tickless::is_stopped(percpu.cpu_id)
+    {
+        watch.progress.store(now, Ordering::Relaxed);
+        watch.reported.store(false, Ordering::Relaxed);
+    } else {
+        let tsc_khz = TSC_KHZ.load(Ordering::Relaxed);
+        let stalled = now.saturating_sub(watch.progress.load(Ordering::Relaxed));
+        if stalled >= THRESHOLD_SECS * 1000 * tsc_khz
+            && !watch.reported.swap(true, Ordering::Relaxed)
+        {
+            // Only the tick of another CPU waits for the report, once it is pending.
+            if let Some(mut report) = REPORTS[cpu_id].try_lock() {
+                report.stalled_ms = stalled / tsc_khz;
+                report.trace.record(stack);
+                if !try_print(cpu_id, &report) {
+                    watch.pending.store(true, Ordering::Release);
+                }
+            }
+        }
+    }
+
+    counter.rearm();
+    true
+}
+
+/// Print `report` straight to the log and consoles, returning false if one of them is locked.
+fn try_print(cpu_id: usize, report: &Report) -> bool {
+    let tag = Tag {
+        level: Level::Error,
+        subsystem: Subsystem::Watchdog,
+    };
+    let Some(mut writer) = Writer::try_with_tag(tag) else {
+        return false;
+    };
+    let header = Header(cpu_id, report.stalled_ms);
+    let _ = report.trace.write(&mut writer, format_args!("{}", header));
+    true
+}
+
+/// Print the lockups the NMI handler could not print, from a timer tick. Returns whether any was.
+pub fn report_pending() -> bool {
+    let mut reported = false;
+    for (cpu_id, watch) in WATCHES.iter().enumerate().take(crate::cpu_count() as usize) {
+        if !watch.pending.swap(false, Ordering::Acquire) {
+            continue;
+        }
+        let report = REPORTS[cpu_id].lock();
+        let header = Header(cpu_id, report.stalled_ms);
+        report.trace.print(TARGET, format_args!("{}", header));
+        reported = true;
+    }
+    reported
+}
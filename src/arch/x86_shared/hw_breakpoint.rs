@@ -0,0 +1,82 @@
+//! Per-context hardware breakpoints and watchpoints, using the DR0-DR3 and DR7 debug registers.
+
+use core::arch::asm;
+
+use crate::syscall::error::{Error, Result, EINVAL};
+
+/// Number of address breakpoint registers.
+pub const HW_BREAKPOINT_COUNT: usize = 4;
+
+/// Local enable bits L0-L3.
+const DR7_LOCAL_ENABLE: usize = 0x55;
+/// Condition (R/W) and length fields for all four breakpoints.
+const DR7_CONDITIONS: usize = 0xFFFF_0000;
+/// Bit 10 of DR7 is reserved and always reads as 1.
+const DR7_RESERVED_ONE: usize = 1 << 10;
+
+/// B0-B3, telling which breakpoint conditions were met.
+const DR6_HITS: usize = 0xF;
+/// Value of DR6 with no conditions recorded.
+const DR6_CLEAR: usize = 0xFFFF_0FF0;
+
+/// The debug register state of a context, as exchanged through `proc:<pid>/regs/debug`.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct HwBreakpoints {
+    /// Linear addresses, loaded into DR0-DR3.
+    pub addr: [usize; HW_BREAKPOINT_COUNT],
+    /// Control bits, loaded into DR7. Only local enables and the condition/length fields can be
+    /// set.
+    pub control: usize,
+}
+
+impl HwBreakpoints {
+    pub fn validate(&self) -> Result<()> {
+        if self.control & !(DR7_LOCAL_ENABLE | DR7_CONDITIONS) != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        for (i, addr) in self.addr.iter().enumerate() {
+            if self.control & (1 << (i * 2)) == 0 {
+                continue;
+            }
+            // R/W == 0b10 would be an I/O breakpoint.
+            if (self.control >> (16 + i * 4)) & 0b11 == 0b10 {
+                return Err(Error::new(EINVAL));
+            }
+            if *addr >= crate::USER_END_OFFSET {
+                return Err(Error::new(EINVAL));
+            }
+        }
+        Ok(())
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.control & DR7_LOCAL_ENABLE != 0
+    }
+
+    /// Load the breakpoints into the debug registers of the current CPU.
+    pub unsafe fn load(&self) {
+        asm!(
+            "mov dr0, {}",
+            "mov dr1, {}",
+            "mov dr2, {}",
+            "mov dr3, {}",
+            "mov dr7, {}",
+            in(reg) self.addr[0],
+            in(reg) self.addr[1],
+            in(reg) self.addr[2],
+            in(reg) self.addr[3],
+            in(reg) self.control | DR7_RESERVED_ONE,
+        );
+    }
+    /// Disable all breakpoints on the current CPU.
+    pub unsafe fn clear() {
+        asm!("mov dr7, {}", in(reg) DR7_RESERVED_ONE);
+    }
+    /// Return the mask of breakpoints that triggered the current debug exception, and reset DR6.
+    pub unsafe fn take_hits() -> usize {
+        let dr6: usize;
+        asm!("mov {}, dr6", out(reg) dr6);
+        asm!("mov dr6, {}", in(reg) DR6_CLEAR);
+        dr6 & DR6_HITS
+    }
+}
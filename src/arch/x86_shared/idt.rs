@@ -98,16 +98,21 @@ pub fn set_reserved(cpu_id: LogicalCpuId, index: u8, reserved: bool) {
     let byte_index = index / 32;
     let bit = index % 32;
 
-    {
-        &IDTS
-            .read()
-            .as_ref()
-            .unwrap()
-            .get(&cpu_id)
-            .unwrap()
-            .reservations[usize::from(byte_index)]
+    let idts = IDTS.read();
+    let reservations =
+        &idts.as_ref().unwrap().get(&cpu_id).unwrap().reservations[usize::from(byte_index)];
+    if reserved {
+        reservations.fetch_or(1 << bit, Ordering::AcqRel);
+    } else {
+        reservations.fetch_and(!(1 << bit), Ordering::AcqRel);
     }
-    .fetch_or(u32::from(reserved) << bit, Ordering::AcqRel);
+}
+
+/// Whether `vector` is one the kernel sets up for itself on every CPU in [`init_generic`]: the
+/// exceptions, the legacy IRQs, the local APIC timer and error, the IPIs, the x86 syscall vector
+/// and the spurious vector. These are never handed out through `irq:`.
+pub fn is_kernel_vector(vector: u8) -> bool {
+    vector < 50 || (0x40..=0x47).contains(&vector) || vector == 0x80 || vector == 0xFF
 }
 
 pub fn allocate_interrupt() -> Option<NonZeroU8> {
@@ -169,7 +174,8 @@ fn set_exceptions(idt: &mut [IdtEntry]) {
     idt[18].set_func(exception::machine_check);
     idt[19].set_func(exception::simd);
     idt[20].set_func(exception::virtualization);
-    // 21 through 29 reserved
+    idt[21].set_func(exception::control_protection);
+    // 22 through 29 reserved
     idt[30].set_func(exception::security);
     // 31 reserved
 }
@@ -293,10 +299,28 @@ pub unsafe fn init_generic(cpu_id: LogicalCpuId, idt: &mut Idt) {
         *current_reservations[1].get_mut() |= 0x0003_FFFF;
     } else {
         // TODO: use_default_irqs! but also the legacy IRQs that are only needed on one CPU
+
+        // Legacy IRQs are delivered to the BSP, unless their affinity is changed. The PIT and the
+        // cascade always stay on the BSP.
+        current_idt[33].set_func(irq::keyboard);
+        current_idt[35].set_func(irq::com2);
+        current_idt[36].set_func(irq::com1);
+        current_idt[37].set_func(irq::lpt2);
+        current_idt[38].set_func(irq::floppy);
+        current_idt[39].set_func(irq::lpt1);
+        current_idt[40].set_func(irq::rtc);
+        current_idt[41].set_func(irq::pci1);
+        current_idt[42].set_func(irq::pci2);
+        current_idt[43].set_func(irq::pci3);
+        current_idt[44].set_func(irq::mouse);
+        current_idt[45].set_func(irq::fpu);
+        current_idt[46].set_func(irq::ata1);
+        current_idt[47].set_func(irq::ata2);
+        current_idt[48].set_func(irq::lapic_timer);
         current_idt[49].set_func(irq::lapic_error);
 
-        // reserve bit 49
-        *current_reservations[1].get_mut() |= 1 << 17;
+        // reserve bits 49:32, which are for the legacy IRQs, and for the local apic timer and error.
+        *current_reservations[1].get_mut() |= 0x0003_FFFF;
     }
 
     #[cfg(target_arch = "x86")]
@@ -311,6 +335,11 @@ pub unsafe fn init_generic(cpu_id: LogicalCpuId, idt: &mut Idt) {
     idt.set_reserved_mut(IpiKind::Switch as u8, true);
     idt.set_reserved_mut(IpiKind::Tlb as u8, true);
     idt.set_reserved_mut(IpiKind::Pit as u8, true);
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    {
+        idt.entries[IpiKind::Sleep as usize].set_func(ipi::sleep);
+        idt.set_reserved_mut(IpiKind::Sleep as u8, true);
+    }
     let current_idt = &mut idt.entries;
 
     #[cfg(target_arch = "x86")]
@@ -3,7 +3,7 @@ use core::sync::atomic::Ordering;
 use x86::tlb;
 
 use crate::percpu::PercpuBlock;
-use crate::{context, device::local_apic::LOCAL_APIC};
+use crate::{context, context::switch::SwitchResult, device::local_apic::LOCAL_APIC};
 
 interrupt!(wakeup, || {
     LOCAL_APIC.eoi();
@@ -18,7 +18,17 @@ interrupt!(tlb, || {
 interrupt!(switch, || {
     LOCAL_APIC.eoi();
 
-    let _ = context::switch();
+    // Like a tick, this returns to userspace, where the signals of the new context are handled.
+    if let SwitchResult::Switched { signal: true } = context::switch() {
+        context::signal::signal_handler();
+    }
+});
+
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+interrupt!(sleep, || {
+    LOCAL_APIC.eoi();
+
+    crate::arch::sleep::park();
 });
 
 interrupt!(pit, || {
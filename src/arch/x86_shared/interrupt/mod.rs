@@ -6,10 +6,16 @@ pub mod trace;
 pub use self::trace::stack_trace;
 
 pub use super::{
-    device::local_apic::bsp_apic_id,
+    device::local_apic::{bsp_apic_id, msi_message},
     idt::{available_irqs_iter, is_reserved, set_reserved},
 };
 
+/// Whether message signaled interrupts can be allocated through `irq:msi`, which they always can
+/// through the local APIC.
+pub fn msi_supported() -> bool {
+    true
+}
+
 /// Clear interrupts
 #[inline(always)]
 pub unsafe fn disable() {
@@ -1,15 +1,14 @@
-use core::{mem, str};
-
-use goblin::elf::sym;
-use rustc_demangle::demangle;
+use core::{fmt, mem};
 
 use crate::{
-    paging::{KernelMapper, VirtualAddress},
+    interrupt::InterruptStack,
+    memory::TheFrameAllocator,
+    paging::{PageMapper, TableKind, VirtualAddress},
+    symbols::Symbolized,
     USER_END_OFFSET,
 };
 
 /// Get a stack trace
-//TODO: Check for stack being mapped before dereferencing
 #[inline(never)]
 pub unsafe fn stack_trace() {
     let mut sp: usize;
@@ -18,93 +17,225 @@ pub unsafe fn stack_trace() {
     #[cfg(target_arch = "x86_64")]
     core::arch::asm!("mov {}, rbp", out(reg) sp);
 
-    println!("TRACE: {:>016X}", sp);
-    //Maximum 64 frames
-
-    let mapper = KernelMapper::lock();
-
-    for _frame in 0..64 {
-        if let Some(ip_bp) = sp.checked_add(mem::size_of::<usize>()) {
-            let bp_virt = VirtualAddress::new(sp);
-            let ip_bp_virt = VirtualAddress::new(ip_bp);
-            if bp_virt.data() >= USER_END_OFFSET
-                && ip_bp_virt.data() >= USER_END_OFFSET
-                && mapper.translate(bp_virt).is_some()
-                && mapper.translate(ip_bp_virt).is_some()
-            {
-                let ip = (ip_bp as *const usize).read();
-                if ip == 0 {
-                    println!(" {:>016X}: EMPTY RETURN", sp);
-                    break;
-                }
-                println!("  {:>016X}: {:>016X}", sp, ip);
-                sp = (sp as *const usize).read();
-                symbol_trace(ip);
-            } else {
-                println!("  {:>016X}: GUARD PAGE", sp);
-                break;
-            }
-        } else {
-            println!("  {:>016X}: RBP OVERFLOW", sp);
+    log::error!("TRACE: {:>016X}", sp);
+    crate::log::unlimited(|| frame_trace(sp));
+}
+
+/// Maximum number of frames walked on a stack.
+const MAX_FRAMES: usize = 64;
+/// Maximum number of frames kept by a [`Trace`], lower as one is kept per CPU.
+const MAX_RECORDED_FRAMES: usize = 32;
+
+/// One step of a walk up the frame pointer chain.
+#[derive(Clone, Copy)]
+enum Step {
+    /// A frame, with its frame pointer and return address.
+    Frame(usize, usize),
+    EmptyReturn(usize),
+    GuardPage(usize),
+    Overflow(usize),
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Step::Frame(sp, ip) => write!(f, "  {:>016X}: {:>016X} {}", sp, ip, Symbolized(ip)),
+            Step::EmptyReturn(sp) => write!(f, " {:>016X}: EMPTY RETURN", sp),
+            Step::GuardPage(sp) => write!(f, "  {:>016X}: GUARD PAGE", sp),
+            Step::Overflow(sp) => write!(f, "  {:>016X}: RBP OVERFLOW", sp),
+        }
+    }
+}
+
+/// Walk the frame pointer chain from `sp` for up to `max` frames, passing each step to `each`.
+/// Nothing is locked, so that this can run in an NMI.
+unsafe fn walk(mut sp: usize, max: usize, mut each: impl FnMut(Step)) {
+    // Not KernelMapper, which another CPU stopped by an NMI may be holding.
+    let mapper = PageMapper::current(TableKind::Kernel, TheFrameAllocator);
+
+    for _frame in 0..max {
+        let Some(ip_bp) = sp.checked_add(mem::size_of::<usize>()) else {
+            each(Step::Overflow(sp));
+            break;
+        };
+        let bp_virt = VirtualAddress::new(sp);
+        let ip_bp_virt = VirtualAddress::new(ip_bp);
+        if bp_virt.data() < USER_END_OFFSET
+            || ip_bp_virt.data() < USER_END_OFFSET
+            || mapper.translate(bp_virt).is_none()
+            || mapper.translate(ip_bp_virt).is_none()
+        {
+            each(Step::GuardPage(sp));
             break;
         }
+        let ip = (ip_bp as *const usize).read();
+        if ip == 0 {
+            each(Step::EmptyReturn(sp));
+            break;
+        }
+        each(Step::Frame(sp, ip));
+        sp = (sp as *const usize).read();
     }
 }
 
-/// Get a symbol
-//TODO: Do not create Elf object for every symbol lookup
-#[inline(never)]
-pub unsafe fn symbol_trace(addr: usize) {
-    use core::{slice, sync::atomic::Ordering};
+/// Walk the frame pointer chain from `sp`, printing each return address with its function.
+unsafe fn frame_trace(sp: usize) {
+    walk(sp, MAX_FRAMES, |step| log::error!("{}", step));
+}
 
-    use crate::{
-        elf::Elf,
-        start::{KERNEL_BASE, KERNEL_SIZE},
+/// Where a CPU was interrupted.
+#[derive(Clone, Copy)]
+struct Location {
+    ip: usize,
+    user: bool,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.user {
+            write!(f, "{:>016X} in userspace", self.ip)
+        } else {
+            write!(f, "{:>016X} {}", self.ip, Symbolized(self.ip))
+        }
+    }
+}
+
+/// The stack of a CPU interrupted by an NMI, recorded without printing so that it can be printed
+/// once the NMI has returned, as the code interrupted may be holding the log or a console.
+pub struct Trace {
+    location: Location,
+    steps: [Step; MAX_RECORDED_FRAMES],
+    len: usize,
+}
+
+impl Trace {
+    pub const EMPTY: Self = Self {
+        location: Location { ip: 0, user: false },
+        steps: [Step::EmptyReturn(0); MAX_RECORDED_FRAMES],
+        len: 0,
     };
 
-    let kernel_ptr = (KERNEL_BASE.load(Ordering::SeqCst) + crate::PHYS_OFFSET) as *const u8;
-    let kernel_slice = slice::from_raw_parts(kernel_ptr, KERNEL_SIZE.load(Ordering::SeqCst));
-    if let Ok(elf) = Elf::from(kernel_slice) {
-        let mut strtab_opt = None;
-        for section in elf.sections() {
-            if section.sh_type == ::goblin::elf::section_header::SHT_STRTAB {
-                strtab_opt = Some(section);
-                break;
+    /// Record where `stack` was interrupted, and the kernel stack from there.
+    pub unsafe fn record(&mut self, stack: &InterruptStack) {
+        #[cfg(target_arch = "x86")]
+        let (ip, cs, bp) = (stack.iret.eip, stack.iret.cs, stack.preserved.ebp);
+        #[cfg(target_arch = "x86_64")]
+        let (ip, cs, bp) = (stack.iret.rip, stack.iret.cs, stack.preserved.rbp);
+
+        self.location = Location {
+            ip,
+            user: cs & 3 == 3,
+        };
+        self.len = 0;
+        if !self.location.user {
+            walk(bp, MAX_RECORDED_FRAMES, |step| {
+                self.steps[self.len] = step;
+                self.len += 1;
+            });
+        }
+    }
+
+    /// Print the trace to the log under `target`, following `header`.
+    pub fn print(&self, target: &str, header: fmt::Arguments) {
+        crate::log::unlimited(|| {
+            log::error!(target: target, "{}: {}", header, self.location);
+            for step in &self.steps[..self.len] {
+                log::error!(target: target, "{}", step);
             }
+        });
+    }
+
+    /// Write the trace as lines following `header`, for output that bypasses the logger.
+    pub fn write(&self, out: &mut impl fmt::Write, header: fmt::Arguments) -> fmt::Result {
+        writeln!(out, "{}: {}", header, self.location)?;
+        for step in &self.steps[..self.len] {
+            writeln!(out, "{}", step)?;
         }
+        Ok(())
+    }
+}
 
-        if let Some(symbols) = elf.symbols() {
-            for sym in symbols {
-                if sym::st_type(sym.st_info) == sym::STT_FUNC
-                    && addr >= sym.st_value as usize
-                    && addr < (sym.st_value + sym.st_size) as usize
-                {
-                    println!(
-                        "    {:>016X}+{:>04X}",
-                        sym.st_value,
-                        addr - sym.st_value as usize
-                    );
-
-                    if let Some(strtab) = strtab_opt {
-                        let start = strtab.sh_offset as usize + sym.st_name as usize;
-                        let mut end = start;
-                        while end < elf.data.len() {
-                            let b = elf.data[end];
-                            end += 1;
-                            if b == 0 {
-                                break;
-                            }
-                        }
-
-                        if end > start {
-                            let sym_slice = &elf.data[start..end - 1];
-                            if let Ok(sym_name) = str::from_utf8(sym_slice) {
-                                println!("    {:#}", demangle(sym_name));
-                            }
-                        }
-                    }
-                }
+#[cfg(target_arch = "x86_64")]
+pub use self::other_cpus::{nmi, other_cpus_stack_trace};
+
+/// Stack traces of the other CPUs, which are interrupted by an NMI and record their trace in it.
+/// The traces are only printed once every CPU has returned from the NMI, as one may have been
+/// holding the log or a console.
+#[cfg(target_arch = "x86_64")]
+mod other_cpus {
+    use core::{
+        hint,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use spin::Mutex;
+
+    use super::Trace;
+    use crate::{
+        cpu_set::MAX_CPU_COUNT,
+        interrupt::InterruptStack,
+        ipi::{ipi, IpiKind, IpiTarget},
+    };
+
+    /// How long to spin for a CPU to report.
+    const SPINS: usize = 100_000_000;
+
+    const NOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+    static REQUESTED: [AtomicBool; MAX_CPU_COUNT as usize] =
+        [NOT_REQUESTED; MAX_CPU_COUNT as usize];
+    const NOT_RECORDED: AtomicBool = AtomicBool::new(false);
+    /// Set once the trace of each CPU was recorded.
+    static RECORDED: [AtomicBool; MAX_CPU_COUNT as usize] = [NOT_RECORDED; MAX_CPU_COUNT as usize];
+    const NO_TRACE: Mutex<Trace> = Mutex::new(Trace::EMPTY);
+    static TRACES: [Mutex<Trace>; MAX_CPU_COUNT as usize] = [NO_TRACE; MAX_CPU_COUNT as usize];
+
+    /// Print the stack traces of the other CPUs.
+    pub unsafe fn other_cpus_stack_trace() {
+        let current = crate::cpu_id().get() as usize;
+        let count = crate::cpu_count() as usize;
+        if count <= 1 {
+            return;
+        }
+        for (cpu, (recorded, requested)) in RECORDED.iter().zip(&REQUESTED).enumerate().take(count)
+        {
+            recorded.store(false, Ordering::Release);
+            requested.store(cpu != current, Ordering::Release);
+        }
+        ipi(IpiKind::Backtrace, IpiTarget::Other);
+
+        let mut spins = 0;
+        while spins < SPINS
+            && (0..count).any(|cpu| cpu != current && !RECORDED[cpu].load(Ordering::Acquire))
+        {
+            hint::spin_loop();
+            spins += 1;
+        }
+
+        for cpu in (0..count).filter(|&cpu| cpu != current) {
+            // A CPU answering late must not record its trace while it is printed.
+            REQUESTED[cpu].store(false, Ordering::Release);
+            if !RECORDED[cpu].load(Ordering::Acquire) {
+                log::error!("CPU {}: no response", cpu);
+                continue;
             }
+            TRACES[cpu]
+                .lock()
+                .print(module_path!(), format_args!("CPU {}", cpu));
+        }
+    }
+
+    /// Handle an NMI, returning whether it was sent by [`other_cpus_stack_trace`].
+    pub unsafe fn nmi(stack: &mut InterruptStack) -> bool {
+        let cpu = crate::cpu_id().get() as usize;
+        if !REQUESTED[cpu].swap(false, Ordering::AcqRel) {
+            return false;
+        }
+        // Only the CPU asking locks the trace, after this CPU was done with it.
+        if let Some(mut trace) = TRACES[cpu].try_lock() {
+            trace.record(stack);
+            drop(trace);
+            RECORDED[cpu].store(true, Ordering::Release);
         }
+        true
     }
 }
@@ -8,6 +8,18 @@ pub enum IpiKind {
 
     #[cfg(feature = "profiling")]
     Profile = 0x44,
+
+    /// Stop the other CPUs while one is in the GDB stub.
+    #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+    Stop = 0x45,
+
+    /// Ask the other CPUs for their stack trace, when panicking.
+    #[cfg(target_arch = "x86_64")]
+    Backtrace = 0x46,
+
+    /// Stop the other CPUs while the system goes to sleep.
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    Sleep = 0x47,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -33,6 +45,19 @@ pub fn ipi(kind: IpiKind, target: IpiTarget) {
         unsafe { LOCAL_APIC.set_icr(icr) };
         return;
     }
+    // Also delivered as NMIs, to reach CPUs running with interrupts disabled.
+    #[cfg(target_arch = "x86_64")]
+    if matches!(kind, IpiKind::Backtrace) {
+        let icr = (target as u64) << 18 | 1 << 14 | 0b100 << 8;
+        unsafe { LOCAL_APIC.set_icr(icr) };
+        return;
+    }
+    #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+    if matches!(kind, IpiKind::Stop) {
+        let icr = (target as u64) << 18 | 1 << 14 | 0b100 << 8;
+        unsafe { LOCAL_APIC.set_icr(icr) };
+        return;
+    }
 
     let icr = (target as u64) << 18 | 1 << 14 | (kind as u64);
     unsafe { LOCAL_APIC.set_icr(icr) };
@@ -42,11 +67,10 @@ use crate::cpu_set::LogicalCpuId;
 #[cfg(feature = "multi_core")]
 #[inline(always)]
 pub fn ipi_single(kind: IpiKind, target: LogicalCpuId) {
-    use crate::device::local_apic::LOCAL_APIC;
+    use crate::device::local_apic::{apic_id, LOCAL_APIC};
 
     unsafe {
-        // TODO: Distinguish between logical and physical CPU IDs
-        LOCAL_APIC.ipi(target.get(), kind);
+        LOCAL_APIC.ipi(apic_id(target), kind);
     }
 }
 
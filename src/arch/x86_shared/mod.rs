@@ -7,6 +7,9 @@ pub mod debug;
 /// Devices
 pub mod device;
 
+/// Hardware breakpoints
+pub mod hw_breakpoint;
+
 /// Interrupt descriptor table
 pub mod idt;
 
@@ -1,3 +1,42 @@
+//! Kernel page-table isolation, the mitigation of Meltdown (rogue data cache load).
+//!
+//! TODO: Switching to a user page table with only the entry code, IDT, GDT and per-CPU entry
+//! stacks mapped is not implemented yet, so the kernel remains exposed on affected CPUs, which
+//! are only reported at boot.
+
+use crate::arch::cpuid::cpuid;
+
+/// `IA32_ARCH_CAPABILITIES` is enumerated by CPUID.(EAX=7,ECX=0):EDX[29].
+const CPUID_ARCH_CAPABILITIES: u32 = 1 << 29;
+const IA32_ARCH_CAPABILITIES: u32 = 0x10A;
+/// The CPU is not susceptible to rogue data cache load.
+const ARCH_CAP_RDCL_NO: u64 = 1 << 0;
+
+/// Whether the CPU is affected by Meltdown. AMD and Hygon CPUs are not, and others are unless
+/// they enumerate `RDCL_NO`.
+pub fn cpu_vulnerable() -> bool {
+    let cpuid = cpuid();
+    if cpuid.get_vendor_info().map_or(false, |vendor| {
+        matches!(vendor.as_str(), "AuthenticAMD" | "HygonGenuine")
+    }) {
+        return false;
+    }
+    if cpuid.get_extended_feature_info().is_none()
+        || raw_cpuid::cpuid!(7, 0).edx & CPUID_ARCH_CAPABILITIES == 0
+    {
+        return true;
+    }
+    let capabilities = unsafe { x86::msr::rdmsr(IA32_ARCH_CAPABILITIES) };
+    capabilities & ARCH_CAP_RDCL_NO == 0
+}
+
+/// Warn at boot if the CPU is affected by Meltdown, which is not mitigated.
+pub fn init() {
+    if cpu_vulnerable() {
+        log::warn!("CPU affected by Meltdown, which is not mitigated: no page-table isolation");
+    }
+}
+
 #[cfg(feature = "pti")]
 use core::ptr;
 
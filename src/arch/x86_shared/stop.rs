@@ -8,7 +8,7 @@ pub unsafe fn kreset() -> ! {
 
     // 8042 reset
     {
-        println!("Reset with 8042");
+        log::info!("Reset with 8042");
         let mut port = Pio::<u8>::new(0x64);
         while port.readf(2) {}
         port.write(0xFE);
@@ -69,7 +69,7 @@ pub unsafe fn kstop() -> ! {
     // Magic shutdown code for bochs and qemu (older versions).
     for c in "Shutdown".bytes() {
         let port = 0x8900;
-        println!("Shutdown with outb(0x{:X}, '{}')", port, c as char);
+        log::info!("Shutdown with outb(0x{:X}, '{}')", port, c as char);
         Pio::<u8>::new(port).write(c);
     }
 
@@ -77,12 +77,12 @@ pub unsafe fn kstop() -> ! {
     {
         let port = 0x604;
         let data = 0x2000;
-        println!("Shutdown with outb(0x{:X}, 0x{:X})", port, data);
+        log::info!("Shutdown with outb(0x{:X}, 0x{:X})", port, data);
         Pio::<u16>::new(port).write(data);
     }
 
     // Magic code for VMWare. Also a hard lock.
-    println!("Shutdown with cli hlt");
+    log::info!("Shutdown with cli hlt");
     loop {
         core::arch::asm!("cli; hlt");
     }
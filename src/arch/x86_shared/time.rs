@@ -1,8 +1,18 @@
 #[cfg(feature = "acpi")]
 use super::device::hpet;
-use super::device::pit;
+use super::device::{
+    local_apic::{self, LvtTimerMode},
+    paravirt, pit, tsc,
+};
 
 pub fn counter() -> u128 {
+    if let Some(ns) = paravirt::nanoseconds() {
+        return ns;
+    }
+    if let Some(ns) = tsc::nanoseconds() {
+        return ns;
+    }
+
     #[cfg(feature = "acpi")]
     if let Some(ref hpet) = *crate::acpi::ACPI_TABLE.hpet.read() {
         //TODO: handle rollover?
@@ -13,15 +23,7 @@ pub fn counter() -> u128 {
         // Comparator holds next interrupt count
         let comparator = unsafe { hpet.base_address.read_u64(hpet::T0_COMPARATOR_OFFSET) };
         // Get period in femtoseconds
-        let capability = unsafe { hpet.base_address.read_u64(hpet::CAPABILITY_OFFSET) };
-
-        // There seems to be a bug in qemu on macos that causes the calculation to produce 0 for
-        // period_fs and hence a divide by zero calculating the divisor - workaround it while we
-        // try and get a fix from qemu: https://gitlab.com/qemu-project/qemu/-/issues/1570
-        let mut period_fs = capability >> 32;
-        if period_fs == 0 {
-            period_fs = 10_000_000;
-        }
+        let period_fs = hpet::period_fs(hpet);
 
         // Calculate divisor
         let divisor = (pit::RATE as u64 * 1_000_000) / period_fs;
@@ -38,3 +40,66 @@ pub fn counter() -> u128 {
     // Calculate nanoseconds since last interrupt
     (elapsed as u128 * pit::PERIOD_FS) / 1_000_000
 }
+
+/// Whether the clocksource is a counter that keeps the time by itself, the paravirtual clock or
+/// the TSC, rather than the timer tick adding its period to the time offset.
+pub fn counter_is_clocksource() -> bool {
+    paravirt::is_clocksource() || tsc::is_clocksource()
+}
+
+/// Period of the scheduler tick, which drives timeouts without a high-resolution timer.
+pub fn tick_period() -> u128 {
+    pit::RATE
+}
+
+/// Resolution of the high-resolution timer in nanoseconds, or `None` if there is none.
+pub fn hrtimer_resolution() -> Option<u128> {
+    local_apic::hrtimer().map(|(_, khz)| (1_000_000 / u128::from(khz)).max(1))
+}
+
+/// Program the high-resolution timer of the current CPU to fire at the monotonic time `deadline`,
+/// or immediately if it has passed. Returns false if there is no high-resolution timer.
+pub unsafe fn hrtimer_arm(deadline: u128) -> bool {
+    let Some((mode, khz)) = local_apic::hrtimer() else {
+        return false;
+    };
+    let delta = deadline.saturating_sub(crate::time::monotonic()) * u128::from(khz) / 1_000_000;
+
+    if mode == LvtTimerMode::TscDeadline {
+        let tsc = x86::time::rdtsc().saturating_add(u64::try_from(delta).unwrap_or(u64::MAX));
+        // A deadline of 0 would disarm the timer.
+        local_apic::LOCAL_APIC.set_tsc_deadline(tsc.max(1));
+    } else {
+        // An initial count of 0 would stop the timer. Deadlines beyond the maximum count fire
+        // early, and the timer is armed again for the remainder.
+        let count = u32::try_from(delta).unwrap_or(u32::MAX).max(1);
+        local_apic::LOCAL_APIC.set_init_count(count);
+    }
+    true
+}
+
+/// Stop the scheduler tick of the current CPU, which has gone idle. Returns whether it was
+/// stopped.
+pub unsafe fn tick_stop_cpu() -> bool {
+    // APs are only sent the tick of the BSP by IPI, which skips CPUs with their tick stopped.
+    crate::cpu_id() != crate::cpu_set::LogicalCpuId::BSP
+}
+
+/// Restart the scheduler tick of the current CPU, stopped by [`tick_stop_cpu`].
+pub unsafe fn tick_restart_cpu() {}
+
+/// Stop the scheduler tick of the BSP once every CPU has gone idle. Returns whether it was
+/// stopped, which requires a counter to keep the time and the high-resolution timer to expire
+/// timeouts in its place.
+pub unsafe fn tick_stop_all() -> bool {
+    if !counter_is_clocksource() || hrtimer_resolution().is_none() {
+        return false;
+    }
+    crate::interrupt::irq::mask(0);
+    true
+}
+
+/// Restart the scheduler tick of the BSP, stopped by [`tick_stop_all`], from any CPU.
+pub unsafe fn tick_restart_all() {
+    crate::interrupt::irq::acknowledge(0);
+}
@@ -37,6 +37,10 @@ impl<T, const ALIGN: usize> AlignedBox<T, ALIGN> {
     where
         T: ValidForZero,
     {
+        #[cfg(feature = "fault_injection")]
+        if crate::fault::inject(crate::fault::Point::Heap) {
+            return Err(Enomem);
+        }
         Ok(unsafe {
             let ptr =
                 crate::ALLOCATOR.alloc_zeroed(layout_upgrade_align(Layout::new::<T>(), ALIGN));
@@ -55,6 +59,10 @@ impl<T, const ALIGN: usize> AlignedBox<[T], ALIGN> {
     where
         T: ValidForZero,
     {
+        #[cfg(feature = "fault_injection")]
+        if crate::fault::inject(crate::fault::Point::Heap) {
+            return Err(Enomem);
+        }
         Ok(unsafe {
             let ptr = crate::ALLOCATOR.alloc_zeroed(layout_upgrade_align(
                 Layout::array::<T>(len).unwrap(),
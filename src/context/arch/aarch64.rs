@@ -1,4 +1,4 @@
-use alloc::sync::Arc;
+use alloc::{boxed::Box, sync::Arc};
 use core::{
     arch::asm,
     mem,
@@ -9,6 +9,7 @@ use core::{
 use spin::Once;
 
 use crate::{
+    arch::{mte, sve},
     device::cpu::registers::{control_regs, tlb},
     paging::{RmmA, RmmArch, TableKind},
     syscall::FloatRegisters,
@@ -35,6 +36,10 @@ pub struct Context {
     spsr_el1: usize,
     esr_el1: usize,
     fx_loadable: bool,
+    /// SVE state, allocated once the context first uses SVE. SVE is trapped while it is `None`.
+    pub(crate) sve: Option<Box<[u8]>>,
+    /// Tag check mode and tag exclusion, used with MTE.
+    pub(crate) mte: mte::MteState,
     sp: usize,  /* Stack Pointer (x31)                                  */
     lr: usize,  /* Link Register (x30)                                  */
     fp: usize,  /* Frame pointer Register (x29)                         */
@@ -60,6 +65,8 @@ impl Context {
             spsr_el1: 0,
             esr_el1: 0,
             fx_loadable: false,
+            sve: None,
+            mte: mte::MteState::default(),
             sp: 0,
             lr: 0,
             fp: 0,
@@ -80,6 +87,16 @@ impl Context {
         self.sp = address;
     }
 
+    /// The frame pointer saved when this context was last switched away from.
+    pub fn frame_pointer(&self) -> usize {
+        self.fp
+    }
+
+    /// The kernel stack pointer saved when this context was last switched away from.
+    pub fn stack_pointer(&self) -> usize {
+        self.sp
+    }
+
     pub fn set_x28(&mut self, x28: usize) {
         self.x28 = x28;
     }
@@ -102,25 +119,25 @@ impl Context {
     }
 
     pub fn dump(&self) {
-        println!("elr_el1: 0x{:016x}", self.elr_el1);
-        println!("sp_el0: 0x{:016x}", self.sp_el0);
-        println!("tpidr_el0: 0x{:016x}", self.tpidr_el0);
-        println!("tpidrro_el0: 0x{:016x}", self.tpidrro_el0);
-        println!("spsr_el1: 0x{:016x}", self.spsr_el1);
-        println!("esr_el1: 0x{:016x}", self.esr_el1);
-        println!("sp: 0x{:016x}", self.sp);
-        println!("lr: 0x{:016x}", self.lr);
-        println!("fp: 0x{:016x}", self.fp);
-        println!("x28: 0x{:016x}", self.x28);
-        println!("x27: 0x{:016x}", self.x27);
-        println!("x26: 0x{:016x}", self.x26);
-        println!("x25: 0x{:016x}", self.x25);
-        println!("x24: 0x{:016x}", self.x24);
-        println!("x23: 0x{:016x}", self.x23);
-        println!("x22: 0x{:016x}", self.x22);
-        println!("x21: 0x{:016x}", self.x21);
-        println!("x20: 0x{:016x}", self.x20);
-        println!("x19: 0x{:016x}", self.x19);
+        log::error!("elr_el1: 0x{:016x}", self.elr_el1);
+        log::error!("sp_el0: 0x{:016x}", self.sp_el0);
+        log::error!("tpidr_el0: 0x{:016x}", self.tpidr_el0);
+        log::error!("tpidrro_el0: 0x{:016x}", self.tpidrro_el0);
+        log::error!("spsr_el1: 0x{:016x}", self.spsr_el1);
+        log::error!("esr_el1: 0x{:016x}", self.esr_el1);
+        log::error!("sp: 0x{:016x}", self.sp);
+        log::error!("lr: 0x{:016x}", self.lr);
+        log::error!("fp: 0x{:016x}", self.fp);
+        log::error!("x28: 0x{:016x}", self.x28);
+        log::error!("x27: 0x{:016x}", self.x27);
+        log::error!("x26: 0x{:016x}", self.x26);
+        log::error!("x25: 0x{:016x}", self.x25);
+        log::error!("x24: 0x{:016x}", self.x24);
+        log::error!("x23: 0x{:016x}", self.x23);
+        log::error!("x22: 0x{:016x}", self.x22);
+        log::error!("x21: 0x{:016x}", self.x21);
+        log::error!("x20: 0x{:016x}", self.x20);
+        log::error!("x19: 0x{:016x}", self.x19);
     }
 }
 
@@ -141,6 +158,9 @@ impl super::Context {
         unsafe {
             ptr::write(self.kfx.as_mut_ptr() as *mut FloatRegisters, new);
         }
+        if let Some(state) = self.arch.sve.as_deref_mut() {
+            sve::set_fpsimd(state, &new);
+        }
     }
     pub fn current_syscall(&self) -> Option<[usize; 6]> {
         if !self.inside_syscall {
@@ -188,6 +208,11 @@ pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
 
     prev.arch.fx_loadable = true;
 
+    // SVE is enabled exactly when the previous context has SVE state.
+    if let Some(state) = prev.arch.sve.as_deref_mut() {
+        sve::save(state);
+    }
+
     if next.arch.fx_loadable {
         let mut float_regs = &mut *(next.kfx.as_mut_ptr() as *mut FloatRegisters);
         asm!(
@@ -215,6 +240,23 @@ pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
         );
     }
 
+    // The vectors also hold the FP/SIMD registers just loaded, so they are loaded after them.
+    match next.arch.sve.as_deref() {
+        Some(state) => {
+            if prev.arch.sve.is_none() {
+                sve::enable();
+            }
+            sve::load(state);
+        }
+        None => {
+            if prev.arch.sve.is_some() {
+                sve::disable();
+            }
+        }
+    }
+
+    mte::switch(&mut prev.arch.mte, &next.arch.mte);
+
     PercpuBlock::current().new_addrsp_tmp.set(next.addr_space.clone());
 
     switch_to_inner(&mut prev.arch, &mut next.arch)
@@ -3,6 +3,7 @@ use core::{mem, sync::atomic::AtomicBool};
 use alloc::sync::Arc;
 
 use crate::{
+    arch::hw_breakpoint::HwBreakpoints,
     gdt::{pcr, GDT_USER_FS, GDT_USER_GS},
     paging::{RmmA, RmmArch, TableKind},
     percpu::PercpuBlock,
@@ -50,6 +51,8 @@ pub struct Context {
     /// need to!), and thus it must be re-read from the register before copying this struct.
     pub(crate) gsbase: usize,
     userspace_io_allowed: bool,
+    /// Hardware breakpoints, only loaded if any are enabled.
+    pub(crate) hw_breakpoints: HwBreakpoints,
 }
 
 impl Context {
@@ -64,12 +67,23 @@ impl Context {
             fsbase: 0,
             gsbase: 0,
             userspace_io_allowed: false,
+            hw_breakpoints: HwBreakpoints::default(),
         }
     }
 
     pub fn set_stack(&mut self, address: usize) {
         self.esp = address;
     }
+
+    /// The frame pointer saved when this context was last switched away from.
+    pub fn frame_pointer(&self) -> usize {
+        self.ebp
+    }
+
+    /// The kernel stack pointer saved when this context was last switched away from.
+    pub fn stack_pointer(&self) -> usize {
+        self.esp
+    }
 }
 impl super::Context {
     pub fn get_fx_regs(&self) -> FloatRegisters {
@@ -151,6 +165,12 @@ pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
         prev.arch.gsbase = gdt[GDT_USER_GS].offset() as usize;
         gdt[GDT_USER_GS].set_offset(next.arch.gsbase as u32);
     }
+    if next.arch.hw_breakpoints.is_enabled() {
+        next.arch.hw_breakpoints.load();
+    } else if prev.arch.hw_breakpoints.is_enabled() {
+        HwBreakpoints::clear();
+    }
+
     PercpuBlock::current().new_addrsp_tmp.set(next.addr_space.clone());
 
     core::arch::asm!(
@@ -3,8 +3,22 @@ use core::{
     sync::atomic::AtomicBool,
 };
 
-use crate::syscall::FloatRegisters;
+use crate::{
+    arch::{
+        hw_breakpoint::HwBreakpoints,
+        shadow_stack::{self, ShadowStack},
+    },
+    syscall::{
+        error::{Error, Result, EOPNOTSUPP},
+        FloatRegisters,
+    },
+};
+
+#[cfg(not(cpu_feature_never = "xsave"))]
+use crate::common::aligned_box::AlignedBox;
 
+#[cfg(not(cpu_feature_never = "xsave"))]
+use crate::arch::alternative::xsave;
 use core::mem::offset_of;
 use spin::Once;
 use x86::msr;
@@ -16,6 +30,9 @@ use x86::msr;
 pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
 
 const ST_RESERVED: u128 = 0xFFFF_FFFF_FFFF_0000_0000_0000_0000_0000;
+/// The x87 control word after FNINIT.
+#[cfg(not(cpu_feature_never = "xsave"))]
+const FCW_INIT: u16 = 0x37F;
 
 #[cfg(cpu_feature_never = "xsave")]
 pub const KFX_ALIGN: usize = 16;
@@ -59,6 +76,14 @@ pub struct Context {
     /// need to!), and thus it must be re-read from the register before copying this struct.
     pub(crate) gsbase: usize,
     userspace_io_allowed: bool,
+    /// Hardware breakpoints, only loaded if any are enabled.
+    pub(crate) hw_breakpoints: HwBreakpoints,
+    /// Userspace shadow stack, if enabled through `proc:<pid>/shadow-stack`.
+    pub(crate) shadow_stack: Option<ShadowStack>,
+    /// The components enabled in XCR0 while the context runs, which only include AMX if the
+    /// process was permitted to use it through `proc:<pid>/amx`.
+    #[cfg(not(cpu_feature_never = "xsave"))]
+    xcr0: u64,
 }
 
 impl Context {
@@ -75,17 +100,63 @@ impl Context {
             fsbase: 0,
             gsbase: 0,
             userspace_io_allowed: false,
+            hw_breakpoints: HwBreakpoints::default(),
+            shadow_stack: None,
+            #[cfg(not(cpu_feature_never = "xsave"))]
+            xcr0: xsave::info().map_or(0, |info| info.xcr0),
         }
     }
 
     pub fn set_stack(&mut self, address: usize) {
         self.rsp = address;
     }
+
+    /// The frame pointer saved when this context was last switched away from.
+    pub fn frame_pointer(&self) -> usize {
+        self.rbp
+    }
+
+    /// The kernel stack pointer saved when this context was last switched away from.
+    pub fn stack_pointer(&self) -> usize {
+        self.rsp
+    }
+
+    /// The registers saved when this context was last switched away from, as `rbx`, `rbp`, `r12`
+    /// to `r15`, `rsp` and `rflags`. The return address of the switch is at the saved `rsp`.
+    pub fn saved_registers(&self) -> [usize; 8] {
+        [
+            self.rbx,
+            self.rbp,
+            self.r12,
+            self.r13,
+            self.r14,
+            self.r15,
+            self.rsp,
+            self.rflags,
+        ]
+    }
 }
 impl super::Context {
     pub fn get_fx_regs(&self) -> FloatRegisters {
         let mut regs = unsafe { self.kfx.as_ptr().cast::<FloatRegisters>().read() };
         regs._reserved = 0;
+        // Components in their initial configuration are not necessarily written by XSAVEOPT.
+        #[cfg(not(cpu_feature_never = "xsave"))]
+        if let Some(info) = xsave::info() {
+            let xstate_bv = xstate_bv(&self.kfx[..]) & info.xcr0;
+            if xstate_bv & xsave::XCR0_X87 == 0 {
+                regs.fcw = FCW_INIT;
+                regs.fsw = 0;
+                regs.ftw = 0;
+                regs.fop = 0;
+                regs.fip = 0;
+                regs.fdp = 0;
+                regs.st_space = [0; 8];
+            }
+            if xstate_bv & xsave::XCR0_SSE == 0 {
+                regs.xmm_space = [0; 16];
+            }
+        }
         let mut new_st = regs.st_space;
         for st in &mut new_st {
             // Only allow access to the 80 lowest bits
@@ -113,6 +184,12 @@ impl super::Context {
         unsafe {
             self.kfx.as_mut_ptr().cast::<FloatRegisters>().write(new);
         }
+        // Otherwise XRSTOR would ignore the new x87 and SSE registers.
+        #[cfg(not(cpu_feature_never = "xsave"))]
+        if xsave::info().is_some() {
+            let xstate_bv = xstate_bv(&self.kfx[..]) | xsave::XCR0_X87 | xsave::XCR0_SSE;
+            self.kfx[xsave::XSTATE_BV_OFFSET..][..8].copy_from_slice(&xstate_bv.to_ne_bytes());
+        }
     }
 
     pub fn set_userspace_io_allowed(&mut self, allowed: bool) {
@@ -125,6 +202,34 @@ impl super::Context {
         }
     }
 
+    /// Enable or disable AMX while the context runs, growing the XSAVE area to fit the tile
+    /// state. The area is never shrunk, as a running context is still saved with AMX enabled.
+    pub fn set_amx_allowed(&mut self, allowed: bool) -> Result<()> {
+        #[cfg(not(cpu_feature_never = "xsave"))]
+        if let Some(info) = xsave::info() {
+            if !allowed {
+                // The saved tile state is dropped, as XRSTOR faults on disabled components.
+                let xstate_bv = xstate_bv(&self.kfx[..]) & info.xcr0;
+                self.kfx[xsave::XSTATE_BV_OFFSET..][..8].copy_from_slice(&xstate_bv.to_ne_bytes());
+                self.arch.xcr0 = info.xcr0;
+                return Ok(());
+            }
+            let size = crate::arch::amx_kfx_size().ok_or(Error::new(EOPNOTSUPP))?;
+            if self.kfx.len() < size {
+                let mut kfx = AlignedBox::try_zeroed_slice(size)?;
+                kfx[..self.kfx.len()].copy_from_slice(&self.kfx);
+                self.kfx = kfx;
+            }
+            self.arch.xcr0 = info.xcr0 | xsave::XCR0_AMX;
+            return Ok(());
+        }
+        if allowed {
+            Err(Error::new(EOPNOTSUPP))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn current_syscall(&self) -> Option<[usize; 6]> {
         if !self.inside_syscall {
             return None;
@@ -135,6 +240,95 @@ impl super::Context {
     }
 }
 
+/// The XSTATE_BV field of an XSAVE area.
+#[cfg(not(cpu_feature_never = "xsave"))]
+fn xstate_bv(kfx: &[u8]) -> u64 {
+    let bytes = kfx[xsave::XSTATE_BV_OFFSET..][..8]
+        .try_into()
+        .expect("XSAVE area too small");
+    u64::from_ne_bytes(bytes)
+}
+
+/// Whether the extended state of the current CPU and the saved state of `next` are both in their
+/// initial configuration, in which case saving and restoring it can be skipped. This is the case
+/// for contexts never using the FPU or vector registers.
+unsafe fn extended_state_unused(next: &super::Context) -> bool {
+    #[cfg(not(cpu_feature_never = "xsave"))]
+    {
+        match xsave::info() {
+            Some(info) if info.has_xinuse => {
+                xsave::xinuse() == 0 && xstate_bv(&next.kfx[..]) & next.arch.xcr0 == 0
+            }
+            _ => false,
+        }
+    }
+    #[cfg(cpu_feature_never = "xsave")]
+    {
+        let _ = next;
+        false
+    }
+}
+
+/// Switch the extended state if XCR0 differs between `prev`, `next` and the current CPU, as only
+/// contexts permitted to use AMX run with it enabled. The saved state of `prev` is limited to the
+/// components it may still use, as XRSTOR faults on components not enabled in XCR0.
+unsafe fn switch_xcr0(prev: &mut super::Context, next: &super::Context) -> bool {
+    #[cfg(not(cpu_feature_never = "xsave"))]
+    {
+        if xsave::info().is_none() {
+            return false;
+        }
+        let xcr0 = xsave::xcr0_read();
+        if prev.arch.xcr0 == xcr0 && next.arch.xcr0 == xcr0 {
+            return false;
+        }
+
+        core::arch::asm!(
+            "xsave [{prev_fx}]",
+            prev_fx = in(reg) prev.kfx.as_mut_ptr(),
+            in("eax") u32::MAX,
+            in("edx") u32::MAX,
+        );
+        let xstate_bv = xstate_bv(&prev.kfx[..]) & prev.arch.xcr0;
+        prev.kfx[xsave::XSTATE_BV_OFFSET..][..8].copy_from_slice(&xstate_bv.to_ne_bytes());
+
+        if next.arch.xcr0 != xcr0 {
+            xsave::xcr0_write(next.arch.xcr0);
+        }
+        core::arch::asm!(
+            "xrstor [{next_fx}]",
+            next_fx = in(reg) next.kfx.as_ptr(),
+            in("eax") u32::MAX,
+            in("edx") u32::MAX,
+        );
+        true
+    }
+    #[cfg(cpu_feature_never = "xsave")]
+    {
+        let _ = (prev, next);
+        false
+    }
+}
+
+/// Enable AMX for the current context if it was permitted while the context was running, so that
+/// the faulting instruction can be retried. Returns whether XCR0 changed.
+pub fn reload_xcr0() -> bool {
+    #[cfg(not(cpu_feature_never = "xsave"))]
+    if xsave::info().is_some() {
+        let Ok(context) = super::current() else {
+            return false;
+        };
+        let xcr0 = context.read().arch.xcr0;
+        unsafe {
+            if xsave::xcr0_read() != xcr0 {
+                xsave::xcr0_write(xcr0);
+                return true;
+            }
+        }
+    }
+    false
+}
+
 pub static EMPTY_CR3: Once<rmm::PhysicalAddress> = Once::new();
 
 // SAFETY: EMPTY_CR3 must be initialized.
@@ -152,32 +346,52 @@ pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
     }
     crate::gdt::set_userspace_io_allowed(pcr, next.arch.userspace_io_allowed);
 
-    core::arch::asm!(
-        alternative2!(
-            feature1: "xsaveopt",
-            then1: ["
-                mov eax, 0xffffffff
-                mov edx, eax
-                xsaveopt [{prev_fx}]
-                xrstor [{next_fx}]
-            "],
-            feature2: "xsave",
-            then2: ["
-                mov eax, 0xffffffff
-                mov edx, eax
-                xsave [{prev_fx}]
-                xrstor [{next_fx}]
-            "],
-            default: ["
-                fxsave64 [{prev_fx}]
-                fxrstor64 [{next_fx}]
-            "]
-        ),
-        prev_fx = in(reg) prev.kfx.as_mut_ptr(),
-        next_fx = in(reg) next.kfx.as_ptr(),
-        out("eax") _,
-        out("edx") _,
-    );
+    if switch_xcr0(prev, next) {
+        // The extended state was switched along with XCR0.
+    } else if extended_state_unused(next) {
+        // Only MXCSR may differ, as it is not tracked in XINUSE. The saved state of `prev` is
+        // marked as initial, as if XSAVE found it unused.
+        #[cfg(not(cpu_feature_never = "xsave"))]
+        {
+            core::arch::asm!(
+                "stmxcsr [{prev_mxcsr}]",
+                "ldmxcsr [{next_mxcsr}]",
+                prev_mxcsr = in(reg) prev.kfx.as_mut_ptr().add(xsave::MXCSR_OFFSET),
+                next_mxcsr = in(reg) next.kfx.as_ptr().add(xsave::MXCSR_OFFSET),
+            );
+            prev.kfx[xsave::XSTATE_BV_OFFSET..][..8].fill(0);
+        }
+    } else {
+        // TODO: XSAVEC or XSAVES would also compact the area, but the layout of the registers
+        // exchanged through regs/float assumes the standard format. AMX, the largest component,
+        // is only enabled for processes permitted to use it instead.
+        core::arch::asm!(
+            alternative2!(
+                feature1: "xsaveopt",
+                then1: ["
+                    mov eax, 0xffffffff
+                    mov edx, eax
+                    xsaveopt [{prev_fx}]
+                    xrstor [{next_fx}]
+                "],
+                feature2: "xsave",
+                then2: ["
+                    mov eax, 0xffffffff
+                    mov edx, eax
+                    xsave [{prev_fx}]
+                    xrstor [{next_fx}]
+                "],
+                default: ["
+                    fxsave64 [{prev_fx}]
+                    fxrstor64 [{next_fx}]
+                "]
+            ),
+            prev_fx = in(reg) prev.kfx.as_mut_ptr(),
+            next_fx = in(reg) next.kfx.as_ptr(),
+            out("eax") _,
+            out("edx") _,
+        );
+    }
 
     {
         core::arch::asm!(
@@ -226,11 +440,106 @@ pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
         );
     }
 
+    if next.arch.hw_breakpoints.is_enabled() {
+        next.arch.hw_breakpoints.load();
+    } else if prev.arch.hw_breakpoints.is_enabled() {
+        HwBreakpoints::clear();
+    }
+
+    if prev.arch.shadow_stack.is_some() || next.arch.shadow_stack.is_some() {
+        shadow_stack::switch(&mut prev.arch.shadow_stack, next.arch.shadow_stack.as_ref());
+    }
+
     (*pcr).percpu.new_addrsp_tmp.set(next.addr_space.clone());
 
     switch_to_inner(&mut prev.arch, &mut next.arch)
 }
 
+/// Read the FSBASE and userspace GSBASE of the current context. With FSGSBASE, userspace may
+/// have changed them directly, so the values saved in the context are only updated on a switch.
+pub unsafe fn current_fsgsbase() -> (usize, usize) {
+    let fsbase: usize;
+    let gsbase: usize;
+    core::arch::asm!(
+        alternative!(
+            feature: "fsgsbase",
+            // The userspace GSBASE is only accessible after SWAPGS, during which interrupts would
+            // run with the wrong GSBASE.
+            then: ["
+                pushfq
+                cli
+                rdfsbase {fsbase}
+                swapgs
+                rdgsbase {gsbase}
+                swapgs
+                popfq
+            "],
+            default: ["
+                mov ecx, {MSR_FSBASE}
+                rdmsr
+                shl rdx, 32
+                or rax, rdx
+                mov {fsbase}, rax
+
+                mov ecx, {MSR_KERNEL_GSBASE}
+                rdmsr
+                shl rdx, 32
+                or rax, rdx
+                mov {gsbase}, rax
+            "]
+        ),
+        fsbase = out(reg) fsbase,
+        gsbase = out(reg) gsbase,
+        out("rax") _,
+        out("rdx") _,
+        out("ecx") _,
+        MSR_FSBASE = const msr::IA32_FS_BASE,
+        MSR_KERNEL_GSBASE = const msr::IA32_KERNEL_GSBASE,
+    );
+    (fsbase, gsbase)
+}
+
+/// Set the FSBASE and userspace GSBASE of the current context, which take effect when returning
+/// to userspace.
+pub unsafe fn set_current_fsgsbase(fsbase: usize, gsbase: usize) {
+    core::arch::asm!(
+        alternative!(
+            feature: "fsgsbase",
+            then: ["
+                pushfq
+                cli
+                wrfsbase {fsbase}
+                swapgs
+                wrgsbase {gsbase}
+                swapgs
+                popfq
+            "],
+            // The kernel executes SWAPGS before returning to userspace, so the userspace GSBASE
+            // is written to KERNEL_GSBASE.
+            default: ["
+                mov ecx, {MSR_FSBASE}
+                mov rax, {fsbase}
+                mov rdx, {fsbase}
+                shr rdx, 32
+                wrmsr
+
+                mov ecx, {MSR_KERNEL_GSBASE}
+                mov rax, {gsbase}
+                mov rdx, {gsbase}
+                shr rdx, 32
+                wrmsr
+            "]
+        ),
+        fsbase = in(reg) fsbase,
+        gsbase = in(reg) gsbase,
+        out("rax") _,
+        out("rdx") _,
+        out("ecx") _,
+        MSR_FSBASE = const msr::IA32_FS_BASE,
+        MSR_KERNEL_GSBASE = const msr::IA32_KERNEL_GSBASE,
+    );
+}
+
 // Check disassembly!
 #[naked]
 unsafe extern "sysv64" fn switch_to_inner(_prev: &mut Context, _next: &mut Context) {
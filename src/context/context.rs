@@ -1,10 +1,10 @@
-use alloc::{borrow::Cow, sync::Arc, vec::Vec};
-use syscall::{SIGKILL, SIGSTOP};
+use alloc::{borrow::Cow, collections::VecDeque, sync::Arc, vec::Vec};
+use syscall::{SIGCONT, SIGKILL, SIGSTOP};
 use core::{cmp::Ordering, mem::{self, size_of}, num::NonZeroUsize};
 use spin::RwLock;
 
 use crate::{
-    arch::{interrupt::InterruptStack, paging::PAGE_SIZE}, common::aligned_box::AlignedBox, context::{self, arch, file::FileDescriptor, memory::AddrSpace}, cpu_set::{LogicalCpuId, LogicalCpuSet}, ipi::{ipi, IpiKind, IpiTarget}, memory::{allocate_p2frame, deallocate_p2frame, Enomem, Frame, RaiiFrame}, paging::{RmmA, RmmArch}, percpu::PercpuBlock, scheme::{CallerCtx, FileHandle, SchemeNamespace}, sync::WaitMap,
+    arch::{interrupt::InterruptStack, paging::PAGE_SIZE}, common::aligned_box::AlignedBox, context::{self, arch, file::FileDescriptor, memory::AddrSpace}, cpu_set::{LogicalCpuId, LogicalCpuSet}, memory::{allocate_p2frame, deallocate_p2frame, Enomem, Frame, RaiiFrame}, paging::{RmmA, RmmArch}, percpu::PercpuBlock, scheme::{CallerCtx, FileHandle, SchemeNamespace}, sync::WaitMap,
 };
 
 use crate::syscall::{
@@ -16,7 +16,7 @@ use crate::syscall::{
 /// Unique identifier for a context (i.e. `pid`).
 use ::core::sync::atomic::AtomicUsize;
 
-use super::{memory::{GrantFileRef, AddrSpaceWrapper}, empty_cr3};
+use super::{memory::{AccessMode, GrantFileRef, AddrSpaceWrapper}, empty_cr3, rlimit::{Resource, Rlimits, UserContext}, sched::SchedParams, signal::{SignalInfo, SIGRTMIN}};
 int_like!(ContextId, AtomicContextId, usize, AtomicUsize);
 
 /// The status of a context - used for scheduling
@@ -141,23 +141,53 @@ pub struct Context {
     pub ens: SchemeNamespace,
 
     pub sig: SignalState,
+    /// The last CPU fault that raised a signal in this context
+    pub last_fault: Option<FaultInfo>,
 
     /// Process umask
     pub umask: usize,
+    /// Working directory, empty until set. Paths are resolved against it in userspace, as the
+    /// kernel only opens absolute paths, so it is only kept here for process setup and inspection.
+    pub cwd: Cow<'static, str>,
+    /// Resource limits
+    pub rlimits: Rlimits,
+    /// Accounting of this context to its real user, for the `nproc` limit. Kernel contexts and
+    /// the first userspace context are not accounted.
+    pub user_context: Option<UserContext>,
     /// Status of context
     pub status: Status,
     pub status_reason: &'static str,
     /// Context running or not
     pub running: bool,
-    /// Current CPU ID
+    /// The CPU the context is running on, or last ran on. Only the CPU switching to the context
+    /// sets it, with the context locked, and any other CPU in [`sched_affinity`] may do so once it
+    /// is no longer running.
     pub cpu_id: Option<LogicalCpuId>,
     /// Time this context was switched to
     pub switch_time: u128,
+    /// Time this context was last hard blocked
+    pub hard_block_time: u128,
     /// Amount of CPU time used
     pub cpu_time: u128,
-    /// Scheduler CPU affinity. If set, [`cpu_id`] can except [`None`] never be anything else than
-    /// this value.
+    /// Part of [`cpu_time`] spent inside syscalls
+    pub kernel_time: u128,
+    /// Part of [`cpu_time`] spent in interrupt handlers while this context ran
+    pub irq_time: u128,
+    /// Monotonic time when this context was created
+    pub start_time: u128,
+    /// Number of times this context has been switched to
+    pub switch_count: u64,
+    /// Scheduler CPU affinity, the CPUs the context may be switched to.
     pub sched_affinity: LogicalCpuSet,
+    /// Scheduling policy, nice value and time slice
+    pub sched: SchedParams,
+    /// Virtual runtime in nanoseconds, see [`super::sched`]. Contexts that become runnable after
+    /// sleeping are brought up to the lowest one being scheduled, so as not to run ahead of the
+    /// others to catch up.
+    pub vruntime: u64,
+    /// Number of deferred IRQ handles with events this context has yet to handle. While nonzero,
+    /// the context is picked ahead of the others of the same scheduling rank.
+    pub irq_boost: u32,
     /// Keeps track of whether this context is currently handling a syscall. Only up-to-date when
     /// not running.
     pub inside_syscall: bool,
@@ -203,26 +233,50 @@ pub struct Context {
     pub fmap_ret: Option<Frame>,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct SignalState {
     /// Bitset of pending signals.
     pub pending: u64,
     /// Bitset of procmasked signals.
     pub procmask: u64,
+    /// Information about each pending instance of a signal, oldest first. Real-time signals have
+    /// one entry per instance, and standard signals at most one.
+    pub queued: VecDeque<SignalInfo>,
 
     /// A function pointer to the userspace signal handler.
     pub handler: Option<SignalHandler>,
+    /// The alternate stack handlers run on, as set by `sigaltstack`. It belongs to the thread,
+    /// unlike the handler.
+    pub altstack: Option<Altstack>,
 }
 #[derive(Clone, Copy, Debug)]
 pub struct SignalHandler {
     pub handler: NonZeroUsize,
-    pub altstack: Option<Altstack>,
 }
 #[derive(Clone, Copy, Debug)]
 pub struct Altstack {
     pub base: NonZeroUsize,
     pub len: NonZeroUsize,
 }
+impl Altstack {
+    pub fn top(&self) -> usize {
+        self.base.get() + self.len.get()
+    }
+    /// Whether a stack pointer `sp` is on the stack, which grows down from the top.
+    pub fn contains(&self, sp: usize) -> bool {
+        sp > self.base.get() && sp <= self.top()
+    }
+}
+#[derive(Clone, Copy, Debug)]
+pub struct FaultInfo {
+    /// The signal the fault was converted to
+    pub signal: usize,
+    /// The instruction pointer of the faulting instruction
+    pub ip: usize,
+    /// The accessed address, for page faults
+    pub address: Option<usize>,
+    pub access: Option<AccessMode>,
+}
 
 impl Context {
     pub fn new(id: ContextId) -> Result<Context> {
@@ -240,16 +294,30 @@ impl Context {
             sig: SignalState {
                 pending: 0,
                 procmask: !0,
+                queued: VecDeque::new(),
                 handler: None,
+                altstack: None,
             },
+            last_fault: None,
             umask: 0o022,
+            cwd: Cow::Borrowed(""),
+            rlimits: Rlimits::new(),
+            user_context: None,
             status: Status::HardBlocked { reason: HardBlockedReason::NotYetStarted },
             status_reason: "",
             running: false,
             cpu_id: None,
             switch_time: 0,
+            hard_block_time: 0,
             cpu_time: 0,
+            kernel_time: 0,
+            irq_time: 0,
+            start_time: crate::time::monotonic(),
+            switch_count: 0,
             sched_affinity: LogicalCpuSet::all(),
+            sched: SchedParams::new(),
+            vruntime: 0,
+            irq_boost: 0,
             inside_syscall: false,
             syscall_head: Some(RaiiFrame::allocate()?),
             syscall_tail: Some(RaiiFrame::allocate()?),
@@ -286,6 +354,7 @@ impl Context {
     pub fn hard_block(&mut self, reason: HardBlockedReason) -> bool {
         if self.status.is_runnable() {
             self.status = Status::HardBlocked { reason };
+            self.hard_block_time = crate::time::monotonic();
 
             true
         } else {
@@ -293,15 +362,30 @@ impl Context {
         }
     }
 
+    /// Part of [`Self::cpu_time`] spent outside syscalls and interrupt handlers
+    pub fn user_time(&self) -> u128 {
+        self.cpu_time
+            .saturating_sub(self.kernel_time)
+            .saturating_sub(self.irq_time)
+    }
+
+    /// Bytes of the kernel stack in use when the context was last switched away from. Unknown
+    /// while it is running, or if its kernel stack is not on the heap.
+    pub fn kstack_usage(&self) -> Option<usize> {
+        if self.running {
+            return None;
+        }
+        let kstack = self.kstack.as_ref()?;
+        (kstack.initial_top() as usize)
+            .checked_sub(self.arch.stack_pointer())
+            .filter(|&used| used <= kstack.len())
+    }
+
     /// Unblock context, and return true if it was blocked before being marked runnable
     pub fn unblock(&mut self) -> bool {
         if self.unblock_no_ipi() {
-            if let Some(cpu_id) = self.cpu_id {
-                if cpu_id != crate::cpu_id() {
-                    // Send IPI if not on current CPU
-                    ipi(IpiKind::Wakeup, IpiTarget::Other);
-                }
-            }
+            // Send an IPI to a CPU that can run it, if any needs one
+            super::switch::wake_cpu(self);
 
             true
         } else {
@@ -309,6 +393,46 @@ impl Context {
         }
     }
 
+    /// Make the signal of `info` pending, and wake the context if it can be delivered. A standard
+    /// signal already pending is not queued again, while real-time signals are queued once per
+    /// call. Fails with `EAGAIN` if a real-time signal would exceed the `sigpending` limit.
+    pub fn send_signal(&mut self, info: SignalInfo) -> Result<()> {
+        let sig = info.signo as usize;
+        let bit = 1_u64 << (sig - 1);
+        let full = self.sig.queued.len() as u64 >= self.rlimits.get(Resource::Sigpending).cur;
+
+        if sig >= SIGRTMIN {
+            if full {
+                return Err(Error::new(EAGAIN));
+            }
+            self.sig.queued.push_back(info);
+        } else if self.sig.pending & bit == 0 && !full {
+            // Standard signals are still sent without their information beyond the limit.
+            self.sig.queued.push_back(info);
+        }
+        self.sig.pending |= bit;
+        self.unblock_for_signal();
+        Ok(())
+    }
+
+    /// Send the signal of `info` as `kill` does. Unlike [`Self::send_signal`], this lets a stopped
+    /// context run again, to continue on `SIGCONT` or to die from `SIGKILL`.
+    pub fn kill(&mut self, info: SignalInfo) -> Result<()> {
+        let sig = info.signo as usize;
+        if sig == SIGCONT || sig == SIGKILL {
+            if let Status::Stopped(_sig) = self.status {
+                self.status = Status::Blocked;
+            }
+        }
+        self.send_signal(info)
+    }
+
+    /// Unblock context if it was blocked with a signal to deliver, which the scheduler otherwise
+    /// only notices once a CPU looks for a context to run, and return true if it was
+    pub fn unblock_for_signal(&mut self) -> bool {
+        matches!(self.status, Status::Blocked) && self.sig.deliverable() != 0 && self.unblock()
+    }
+
     /// Unblock context without IPI, and return true if it was blocked before being marked runnable
     pub fn unblock_no_ipi(&mut self) -> bool {
         if self.status.is_soft_blocked() {
@@ -321,6 +445,37 @@ impl Context {
         }
     }
 
+    /// The kernel stack saved at the last switch away from this context, as pairs of frame
+    /// pointer and return address, following the frame pointer chain without leaving the stack.
+    pub fn kernel_frames(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let (bottom, top) = self.kstack.as_ref().map_or((0, 0), |kstack| {
+            let top = kstack.initial_top() as usize;
+            (top - kstack.len(), top)
+        });
+        let mut fp = self.arch.frame_pointer();
+        core::iter::from_fn(move || {
+            if fp < bottom || fp + 2 * size_of::<usize>() > top || fp % size_of::<usize>() != 0 {
+                return None;
+            }
+            let frame = fp;
+            let ip = unsafe { ((fp + size_of::<usize>()) as *const usize).read() };
+            if ip == 0 {
+                return None;
+            }
+            fp = unsafe { (fp as *const usize).read() };
+            Some((frame, ip))
+        })
+    }
+
+    /// Number of file descriptors the context can have, as bounded by its `nofile` limit. Lowering
+    /// the limit keeps the descriptors already beyond it.
+    fn max_files(&self) -> usize {
+        let limit = self.rlimits.get(Resource::Nofile).cur;
+        usize::try_from(limit).map_or(super::CONTEXT_MAX_FILES, |limit| {
+            limit.min(super::CONTEXT_MAX_FILES)
+        })
+    }
+
     /// Add a file to the lowest available slot.
     /// Return the file descriptor number or None if no slot was found
     pub fn add_file(&self, file: FileDescriptor) -> Option<FileHandle> {
@@ -330,15 +485,16 @@ impl Context {
     /// Add a file to the lowest available slot greater than or equal to min.
     /// Return the file descriptor number or None if no slot was found
     pub fn add_file_min(&self, file: FileDescriptor, min: usize) -> Option<FileHandle> {
+        let max_files = self.max_files();
         let mut files = self.files.write();
-        for (i, file_option) in files.iter_mut().enumerate() {
+        for (i, file_option) in files.iter_mut().enumerate().take(max_files) {
             if file_option.is_none() && i >= min {
                 *file_option = Some(file);
                 return Some(FileHandle::from(i));
             }
         }
         let len = files.len();
-        if len < super::CONTEXT_MAX_FILES {
+        if len < max_files {
             if len >= min {
                 files.push(Some(file));
                 Some(FileHandle::from(len))
@@ -351,6 +507,38 @@ impl Context {
         }
     }
 
+    /// Add two files to the lowest available slots at once, so that no other thread, such as one
+    /// forking, can observe the table with only the first of them. Return None, without adding
+    /// either, if there are not enough free slots.
+    pub fn add_file_pair(
+        &self,
+        first: FileDescriptor,
+        second: FileDescriptor,
+    ) -> Option<(FileHandle, FileHandle)> {
+        let max_files = self.max_files();
+        let mut files = self.files.write();
+        let mut slots = files
+            .iter()
+            .enumerate()
+            .take(max_files)
+            .filter(|(_, file_option)| file_option.is_none())
+            .map(|(i, _)| i)
+            .take(2)
+            .collect::<Vec<_>>();
+
+        let len = files.len();
+        let appended = 2 - slots.len();
+        if len + appended > max_files {
+            return None;
+        }
+        slots.extend(len..len + appended);
+        files.resize(len + appended, None);
+
+        files[slots[0]] = Some(first);
+        files[slots[1]] = Some(second);
+        Some((FileHandle::from(slots[0]), FileHandle::from(slots[1])))
+    }
+
     /// Get a file
     pub fn get_file(&self, i: FileHandle) -> Option<FileDescriptor> {
         let files = self.files.read();
@@ -364,8 +552,9 @@ impl Context {
     /// Insert a file with a specific handle number. This is used by dup2
     /// Return the file descriptor number or None if the slot was not empty, or i was invalid
     pub fn insert_file(&self, i: FileHandle, file: FileDescriptor) -> Option<FileHandle> {
+        let max_files = self.max_files();
         let mut files = self.files.write();
-        if i.get() < super::CONTEXT_MAX_FILES {
+        if i.get() < max_files {
             while i.get() >= files.len() {
                 files.push(None);
             }
@@ -391,6 +580,13 @@ impl Context {
         }
     }
 
+    /// Apply the `as` limit of the context to its address space, after changing it.
+    pub fn apply_addr_space_limit(&self) {
+        if let Some(ref addr_space) = self.addr_space {
+            addr_space.acquire_write().size_limit = self.rlimits.get(Resource::As).cur;
+        }
+    }
+
     pub fn addr_space(&self) -> Result<&Arc<AddrSpaceWrapper>> {
         self.addr_space.as_ref().ok_or(Error::new(ESRCH))
     }
@@ -401,6 +597,16 @@ impl Context {
         if let (Some(ref old), Some(ref new)) = (&self.addr_space, &addr_space) && Arc::ptr_eq(old, new) {
             return addr_space;
         };
+        if let Some(ref new) = addr_space {
+            new.acquire_write().size_limit = self.rlimits.get(Resource::As).cur;
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            let amx = addr_space.as_ref().is_some_and(|new| new.acquire_read().amx);
+            if let Err(err) = self.set_amx_allowed(amx) {
+                log::warn!("context {:?} may not use AMX: {:?}", self.id, err);
+            }
+        }
 
         if self.id == super::context_id() {
             // TODO: Share more code with context::arch::switch_to.
@@ -418,7 +624,7 @@ impl Context {
                 new_addrsp.used_by.atomic_set(this_percpu.cpu_id);
 
                 unsafe {
-                    new_addrsp.table.utable.make_current();
+                    new_addrsp.make_current();
                 }
             } else {
                 unsafe {
@@ -478,6 +684,18 @@ impl SignalState {
         const CANT_BLOCK: u64 = (1 << (SIGKILL - 1)) | (1 << (SIGSTOP - 1));
         self.pending & (CANT_BLOCK | !self.procmask)
     }
+
+    /// Take the oldest pending instance of `sig`, leaving it pending if more are queued.
+    pub fn take(&mut self, sig: usize) -> SignalInfo {
+        let info = match self.queued.iter().position(|info| info.signo as usize == sig) {
+            Some(idx) => self.queued.remove(idx).expect("idx was just found"),
+            None => SignalInfo::kernel(sig),
+        };
+        if !self.queued.iter().any(|info| info.signo as usize == sig) {
+            self.pending &= !(1 << (sig - 1));
+        }
+        info
+    }
 }
 
 /// Wrapper struct for borrowing the syscall head or tail buf.
@@ -586,6 +804,10 @@ pub struct Kstack {
 }
 impl Kstack {
     pub fn new() -> Result<Self, Enomem> {
+        #[cfg(feature = "fault_injection")]
+        if crate::fault::inject(crate::fault::Point::Frame) {
+            return Err(Enomem);
+        }
         Ok(Self {
             base: allocate_p2frame(4).ok_or(Enomem)?,
         })
@@ -0,0 +1,117 @@
+//! CPU time accounting, split into user, system, interrupt and idle time.
+//!
+//! Each CPU is in one [`Mode`] at a time, and charges the time since it entered it when it leaves
+//! it: on syscall entry and exit, around interrupt handlers, and on context switches. The system
+//! and interrupt time of a time slice is charged to the context switched away from as well, and
+//! the rest of the slice is its user time. Exceptions such as page faults count as the mode they
+//! interrupted.
+
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use crate::{
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    time,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Mode {
+    User = 0,
+    System = 1,
+    Irq = 2,
+    Idle = 3,
+}
+
+impl Mode {
+    const ALL: [Self; 4] = [Self::User, Self::System, Self::Irq, Self::Idle];
+}
+
+struct CpuTimes {
+    mode: AtomicU8,
+    /// Monotonic time at which the CPU entered `mode`.
+    since: AtomicU64,
+    /// Time spent in each mode in nanoseconds, indexed by [`Mode`].
+    totals: [AtomicU64; 4],
+    /// System and interrupt time since the last context switch.
+    slice_system: AtomicU64,
+    slice_irq: AtomicU64,
+}
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+const IDLE: CpuTimes = CpuTimes {
+    mode: AtomicU8::new(Mode::Idle as u8),
+    since: ZERO,
+    totals: [ZERO; 4],
+    slice_system: ZERO,
+    slice_irq: ZERO,
+};
+static CPUS: [CpuTimes; MAX_CPU_COUNT as usize] = [IDLE; MAX_CPU_COUNT as usize];
+
+/// Charge the time since `cpu` entered its mode, and enter `mode` at `now`. Returns the mode left.
+fn charge(cpu: &CpuTimes, now: u64, mode: Mode) -> Mode {
+    let old = Mode::ALL[usize::from(cpu.mode.swap(mode as u8, Ordering::Relaxed))];
+    let elapsed = now.saturating_sub(cpu.since.swap(now, Ordering::Relaxed));
+
+    cpu.totals[old as usize].fetch_add(elapsed, Ordering::Relaxed);
+    match old {
+        Mode::System => cpu.slice_system.fetch_add(elapsed, Ordering::Relaxed),
+        Mode::Irq => cpu.slice_irq.fetch_add(elapsed, Ordering::Relaxed),
+        Mode::User | Mode::Idle => 0,
+    };
+    old
+}
+
+/// Start accounting the current CPU as idle, as it is when started.
+pub fn init() {
+    CPUS[crate::cpu_id().get() as usize]
+        .since
+        .store(time::monotonic() as u64, Ordering::Relaxed);
+}
+
+/// Enter `mode` on the current CPU, returning the mode it left.
+pub fn enter(mode: Mode) -> Mode {
+    let now = time::monotonic() as u64;
+    charge(&CPUS[crate::cpu_id().get() as usize], now, mode)
+}
+
+/// Accounts the time until dropped as interrupt time, held by interrupt handlers.
+pub struct IrqScope(Mode);
+
+impl IrqScope {
+    pub fn enter() -> Self {
+        Self(enter(Mode::Irq))
+    }
+}
+
+impl Drop for IrqScope {
+    fn drop(&mut self) {
+        // Restored on the stack of the context interrupted, even if another ran in between.
+        enter(self.0);
+    }
+}
+
+/// Called by the scheduler when `cpu_id` switches contexts at `now`, to enter `mode` for the next
+/// context. Returns the system and interrupt time of the slice of the previous context.
+pub fn switch(cpu_id: LogicalCpuId, now: u128, mode: Mode) -> (u128, u128) {
+    let cpu = &CPUS[cpu_id.get() as usize];
+    charge(cpu, now as u64, mode);
+    (
+        cpu.slice_system.swap(0, Ordering::Relaxed).into(),
+        cpu.slice_irq.swap(0, Ordering::Relaxed).into(),
+    )
+}
+
+/// Time `cpu_id` spent in each mode in nanoseconds, indexed by [`Mode`], including the time
+/// since it entered its current mode.
+pub fn totals(cpu_id: LogicalCpuId) -> [u64; 4] {
+    let cpu = &CPUS[cpu_id.get() as usize];
+    let mut totals = cpu
+        .totals
+        .each_ref()
+        .map(|total| total.load(Ordering::Relaxed));
+
+    let mode = usize::from(cpu.mode.load(Ordering::Relaxed));
+    let since = cpu.since.load(Ordering::Relaxed);
+    totals[mode] += (time::monotonic() as u64).saturating_sub(since);
+    totals
+}
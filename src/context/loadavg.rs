@@ -0,0 +1,72 @@
+//! Load averages, computed like on Linux as exponentially-decaying averages of the number of
+//! runnable contexts, sampled every five seconds.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::time::{self, NANOS_PER_SEC};
+
+/// Fixed-point precision of the averages.
+const FSHIFT: u32 = 11;
+const FIXED_1: usize = 1 << FSHIFT;
+/// `FIXED_1 / exp(5s / 1min)`, `FIXED_1 / exp(5s / 5min)` and `FIXED_1 / exp(5s / 15min)`.
+const EXP: [usize; 3] = [1884, 2014, 2037];
+
+const SAMPLE_INTERVAL: u128 = 5 * NANOS_PER_SEC;
+
+static AVERAGES: [AtomicUsize; 3] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+static NEXT_SAMPLE: AtomicU64 = AtomicU64::new(0);
+
+/// Called on every scheduler tick. Only one CPU takes each sample.
+pub fn tick() {
+    let now = time::monotonic();
+    let next = NEXT_SAMPLE.load(Ordering::Relaxed);
+    if now < u128::from(next) {
+        return;
+    }
+    let new_next = (now + SAMPLE_INTERVAL) as u64;
+    if NEXT_SAMPLE
+        .compare_exchange(next, new_next, Ordering::Relaxed, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+
+    let (runnable, _) = count();
+    let active = runnable * FIXED_1;
+    for (average, exp) in AVERAGES.iter().zip(EXP) {
+        let old = average.load(Ordering::Relaxed);
+        let new = (old * exp + active * (FIXED_1 - exp)) >> FSHIFT;
+        average.store(new, Ordering::Relaxed);
+    }
+}
+
+/// Count runnable and total userspace contexts. Contexts that are locked at the time are skipped,
+/// as this runs from the timer interrupt.
+pub fn count() -> (usize, usize) {
+    let mut runnable = 0;
+    let mut total = 0;
+    for (_id, context_lock) in super::contexts().iter() {
+        let Some(context) = context_lock.try_read() else {
+            continue;
+        };
+        if !context.userspace {
+            continue;
+        }
+        total += 1;
+        if context.status.is_runnable() {
+            runnable += 1;
+        }
+    }
+    (runnable, total)
+}
+
+/// The 1, 5 and 15 minute averages, as hundredths.
+pub fn averages() -> [usize; 3] {
+    AVERAGES
+        .each_ref()
+        .map(|average| (average.load(Ordering::Relaxed) * 100 + FIXED_1 / 2) >> FSHIFT)
+}
@@ -0,0 +1,201 @@
+//! Soft lockup and hung context detection, checked from the scheduler tick.
+//!
+//! Each CPU records when it last went through the scheduler. Once a second, one CPU checks that
+//! every CPU did so within the soft lockup threshold, which catches CPUs stuck with interrupts
+//! disabled or spinning in the kernel on every architecture, and reports the stalled CPUs. Idle
+//! CPUs are skipped, as their tick may be stopped until they have a context to run. On x86_64, the
+//! stacks of the other CPUs are then printed to the console, as when panicking.
+//!
+//! The same check reports contexts hard blocked in the kernel, where signals cannot wake them, for
+//! longer than the hung context threshold, along with their kernel stack. A context still blocked
+//! is reported again each time another threshold elapses, up to [`MAX_HUNG_REPORTS`] reports in
+//! total so that a wedged system does not flood the log.
+//!
+//! Reports are logged under the `watchdog` subsystem, and readers of `debug:log` are sent a read
+//! event for them. Both thresholds are set in seconds through `sys:kconfig`, where 0 disables the
+//! check.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::{
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    symbols::Symbolized,
+    time::{self, NANOS_PER_SEC},
+};
+
+use super::{context::HardBlockedReason, ContextId, Status};
+
+/// Log target of the reports, putting them in [`crate::log::Subsystem::Watchdog`].
+const TARGET: &str = "kernel::watchdog";
+
+const CHECK_INTERVAL: u128 = NANOS_PER_SEC;
+
+/// Maximum number of hung context reports since boot, or since the threshold was last set.
+pub const MAX_HUNG_REPORTS: usize = 10;
+
+/// Maximum number of frames printed per kernel stack.
+const MAX_FRAMES: usize = 64;
+
+static SOFT_LOCKUP_SECS: AtomicU64 = AtomicU64::new(20);
+static HUNG_SECS: AtomicU64 = AtomicU64::new(120);
+static HUNG_REPORTS_LEFT: AtomicUsize = AtomicUsize::new(MAX_HUNG_REPORTS);
+
+/// The last pass of a CPU through the scheduler.
+struct Watch {
+    /// Monotonic time, or 0 if the CPU has not scheduled yet.
+    time: AtomicU64,
+    /// The context running after it.
+    context: AtomicUsize,
+    /// Whether the current stall was already reported.
+    reported: AtomicBool,
+}
+
+const UNSCHEDULED: Watch = Watch {
+    time: AtomicU64::new(0),
+    context: AtomicUsize::new(0),
+    reported: AtomicBool::new(false),
+};
+static WATCHES: [Watch; MAX_CPU_COUNT as usize] = [UNSCHEDULED; MAX_CPU_COUNT as usize];
+
+/// Time of the last check.
+static LAST_CHECK: AtomicU64 = AtomicU64::new(0);
+
+pub fn soft_lockup_secs() -> u64 {
+    SOFT_LOCKUP_SECS.load(Ordering::Relaxed)
+}
+pub fn set_soft_lockup_secs(secs: u64) {
+    SOFT_LOCKUP_SECS.store(secs, Ordering::Relaxed);
+}
+pub fn hung_secs() -> u64 {
+    HUNG_SECS.load(Ordering::Relaxed)
+}
+/// Set the hung context threshold, which also allows [`MAX_HUNG_REPORTS`] more reports.
+pub fn set_hung_secs(secs: u64) {
+    HUNG_SECS.store(secs, Ordering::Relaxed);
+    HUNG_REPORTS_LEFT.store(MAX_HUNG_REPORTS, Ordering::Relaxed);
+}
+
+/// Called by the scheduler each time it runs on `cpu_id`, whether or not it switched contexts.
+pub fn scheduled(cpu_id: LogicalCpuId, context_id: ContextId, now: u128) {
+    let watch = &WATCHES[cpu_id.get() as usize];
+    watch.context.store(context_id.get(), Ordering::Relaxed);
+    watch.time.store(now as u64, Ordering::Relaxed);
+}
+
+/// Called on every scheduler tick. Only one CPU runs each check.
+pub fn tick() {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if crate::device::watchdog::report_pending() {
+        crate::scheme::debug::log_notify();
+    }
+
+    let now = time::monotonic();
+    let last = LAST_CHECK.load(Ordering::Relaxed);
+    if now < u128::from(last) + CHECK_INTERVAL
+        || LAST_CHECK
+            .compare_exchange(last, now as u64, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        return;
+    }
+
+    let soft_lockup = check_cpus(now);
+    let hung = check_contexts(u128::from(last), now);
+    if soft_lockup || hung {
+        crate::scheme::debug::log_notify();
+    }
+}
+
+/// Report the CPUs that have not scheduled within the threshold, returning whether any was.
+fn check_cpus(now: u128) -> bool {
+    let threshold = u128::from(soft_lockup_secs()) * NANOS_PER_SEC;
+    if threshold == 0 {
+        return false;
+    }
+
+    let current = crate::cpu_id().get() as usize;
+    let mut reported = false;
+    for (cpu, watch) in WATCHES.iter().enumerate().take(crate::cpu_count() as usize) {
+        let time = u128::from(watch.time.load(Ordering::Relaxed));
+        if time == 0
+            || super::switch::is_idle(LogicalCpuId::new(cpu as u32))
+            || now.saturating_sub(time) < threshold
+        {
+            watch.reported.store(false, Ordering::Relaxed);
+            continue;
+        }
+        if watch.reported.swap(true, Ordering::Relaxed) {
+            continue;
+        }
+        log::error!(
+            target: TARGET,
+            "soft lockup: CPU {} has not scheduled for {} s, running context {}",
+            cpu,
+            (now - time) / NANOS_PER_SEC,
+            watch.context.load(Ordering::Relaxed)
+        );
+        if cpu == current {
+            unsafe { crate::interrupt::stack_trace() };
+        }
+        reported = true;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    if reported {
+        unsafe { crate::interrupt::trace::other_cpus_stack_trace() };
+    }
+    reported
+}
+
+/// Report the contexts that were hard blocked for another threshold since the last check at
+/// `last`, returning whether any was.
+fn check_contexts(last: u128, now: u128) -> bool {
+    let threshold = u128::from(hung_secs()) * NANOS_PER_SEC;
+    if threshold == 0 || HUNG_REPORTS_LEFT.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+    // Only trying to lock, as this runs from the timer interrupt.
+    let Some(contexts) = super::try_contexts() else {
+        return false;
+    };
+
+    let mut reported = false;
+    for (id, context_lock) in contexts.iter() {
+        let Some(context) = context_lock.try_read() else {
+            continue;
+        };
+        let reason = match context.status {
+            Status::HardBlocked {
+                reason: HardBlockedReason::AwaitingMmap { .. },
+            } => "awaiting mmap",
+            _ => continue,
+        };
+        let since = context.hard_block_time;
+        let blocked = now.saturating_sub(since);
+        if blocked / threshold == last.saturating_sub(since) / threshold {
+            continue;
+        }
+        if HUNG_REPORTS_LEFT
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            })
+            .is_err()
+        {
+            break;
+        }
+
+        log::error!(
+            target: TARGET,
+            "hung context: {} ({}) hard blocked {} for {} s",
+            id.get(),
+            context.name,
+            reason,
+            blocked / NANOS_PER_SEC
+        );
+        for (fp, ip) in context.kernel_frames().take(MAX_FRAMES) {
+            log::error!(target: TARGET, "  {:>016X}: {:>016X} {}", fp, ip, Symbolized(ip));
+        }
+        reported = true;
+    }
+    reported
+}
@@ -11,10 +11,13 @@ use crate::{
         deallocate_frame, deallocate_p2frame, get_page_info, init_frame, the_zeroed_frame, AddRefError, Enomem, Frame, PageInfo, RefCount, RefKind
     }, paging::{
         Page, PageFlags, PageMapper, RmmA, TableKind, VirtualAddress,
-    }, percpu::PercpuBlock, scheme::{self, KernelSchemes}
+    }, percpu::PercpuBlock, scheme::{self, KernelSchemes}, sync::{map_tracked, Tracked}
 };
 
-use super::{context::HardBlockedReason, file::FileDescription};
+use super::{
+    context::HardBlockedReason, file::FileDescription, rlimit::RLIM_INFINITY,
+    timer::ProcessTimers,
+};
 
 pub const MMAP_MIN_DEFAULT: usize = PAGE_SIZE;
 
@@ -74,17 +77,34 @@ impl UnmapResult {
 pub struct AddrSpaceWrapper {
     inner: RwLock<AddrSpace>,
     pub tlb_ack: AtomicU32,
+    /// The signal timers of the process using the address space.
+    pub timers: ProcessTimers,
 }
 impl AddrSpaceWrapper {
     pub fn new() -> Result<Arc<Self>> {
+        #[cfg(feature = "fault_injection")]
+        if crate::fault::inject(crate::fault::Point::Heap) {
+            return Err(Error::new(ENOMEM));
+        }
         Arc::try_new(Self {
             inner: RwLock::new(AddrSpace::new()?),
             tlb_ack: AtomicU32::new(0),
+            timers: ProcessTimers::default(),
         }).map_err(|_| Error::new(ENOMEM))
     }
-    pub fn acquire_read(&self) -> RwLockReadGuard<'_, AddrSpace> {
+    pub fn acquire_read(&self) -> Tracked<RwLockReadGuard<'_, AddrSpace>> {
         let my_percpu = PercpuBlock::current();
 
+        #[cfg(feature = "lock_debug")]
+        return crate::sync::lock_debug::ADDR_SPACE.acquire(
+            || self.inner.try_read(),
+            || {
+                my_percpu.maybe_handle_tlb_shootdown();
+                core::hint::spin_loop();
+            },
+        );
+
+        #[cfg(not(feature = "lock_debug"))]
         loop {
             match self.inner.try_read() {
                 Some(g) => return g,
@@ -95,9 +115,19 @@ impl AddrSpaceWrapper {
             }
         }
     }
-    pub fn acquire_upgradeable_read(&self) -> RwLockUpgradableGuard<'_, AddrSpace> {
+    pub fn acquire_upgradeable_read(&self) -> Tracked<RwLockUpgradableGuard<'_, AddrSpace>> {
         let my_percpu = PercpuBlock::current();
 
+        #[cfg(feature = "lock_debug")]
+        return crate::sync::lock_debug::ADDR_SPACE.acquire(
+            || self.inner.try_upgradeable_read(),
+            || {
+                my_percpu.maybe_handle_tlb_shootdown();
+                core::hint::spin_loop();
+            },
+        );
+
+        #[cfg(not(feature = "lock_debug"))]
         loop {
             match self.inner.try_upgradeable_read() {
                 Some(g) => return g,
@@ -108,9 +138,19 @@ impl AddrSpaceWrapper {
             }
         }
     }
-    pub fn acquire_write(&self) -> RwLockWriteGuard<'_, AddrSpace> {
+    pub fn acquire_write(&self) -> Tracked<RwLockWriteGuard<'_, AddrSpace>> {
         let my_percpu = PercpuBlock::current();
 
+        #[cfg(feature = "lock_debug")]
+        return crate::sync::lock_debug::ADDR_SPACE.acquire(
+            || self.inner.try_write(),
+            || {
+                my_percpu.maybe_handle_tlb_shootdown();
+                core::hint::spin_loop();
+            },
+        );
+
+        #[cfg(not(feature = "lock_debug"))]
         loop {
             match self.inner.try_write() {
                 Some(g) => return g,
@@ -121,6 +161,74 @@ impl AddrSpaceWrapper {
             }
         }
     }
+    /// Read or write `buf` from `offset` into `page`, on behalf of another context, as for
+    /// `proc:<pid>/mem`. Fails with `EFAULT` outside of any grant, and with `EIO` if the page is
+    /// not present, as it is not faulted in, or is not RAM managed by the kernel. Writing a
+    /// read-only grant fails with `EACCES`, unless `force` is set and the grant is private.
+    /// Copy-on-write pages are copied before they are written, as when the owner writes them.
+    pub fn access_foreign(
+        &self,
+        page: Page,
+        offset: usize,
+        buf: &mut [u8],
+        access: AccessMode,
+        force: bool,
+    ) -> Result<()> {
+        assert!(offset + buf.len() <= PAGE_SIZE);
+
+        let mut guard = self.acquire_write();
+        let addr_space = &mut *guard;
+
+        let (_, info) = addr_space.grants.contains(page).ok_or(Error::new(EFAULT))?;
+        let (phys, page_flags) = addr_space
+            .table
+            .utable
+            .translate(page.start_address())
+            .ok_or(Error::new(EIO))?;
+        let mut frame = Frame::containing_address(phys);
+
+        // Physical borrows may be MMIO, where accesses have side effects.
+        if matches!(info.provider, Provider::PhysBorrowed { .. }) || get_page_info(frame).is_none()
+        {
+            return Err(Error::new(EIO));
+        }
+
+        if access == AccessMode::Write && !page_flags.has_write() {
+            let private = matches!(info.provider, Provider::Allocated { .. });
+            if !info.flags().has_write() && !(force && private) {
+                return Err(Error::new(EACCES));
+            }
+            let page_info = get_page_info(frame).ok_or(Error::new(EIO))?;
+            let CowResult {
+                new_frame,
+                old_frame,
+            } = cow(frame, page_info, RefKind::Cow).map_err(|_| Error::new(ENOMEM))?;
+
+            // The page stays mapped read-only, and is made writable when the owner next writes it.
+            if let Some(old_frame) = old_frame {
+                let mut flusher = Flusher::with_cpu_set(&mut addr_space.used_by, &self.tlb_ack);
+                unsafe {
+                    let _ = addr_space
+                        .table
+                        .utable
+                        .remap_with_full(page.start_address(), |_, flags| {
+                            (new_frame.start_address(), flags)
+                        });
+                }
+                flusher.queue(old_frame, None, TlbShootdownActions::FREE);
+            }
+            frame = new_frame;
+        }
+
+        unsafe {
+            let ptr = (RmmA::phys_to_virt(frame.start_address()).data() as *mut u8).add(offset);
+            match access {
+                AccessMode::Write => ptr.copy_from_nonoverlapping(buf.as_ptr(), buf.len()),
+                _ => ptr.copy_to_nonoverlapping(buf.as_mut_ptr(), buf.len()),
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -133,6 +241,19 @@ pub struct AddrSpace {
     /// the exception that we have a memory safe kernel which doesn't have to protect itself
     /// against null pointers, so fixed mmaps to address zero are still allowed.
     pub mmap_min: usize,
+    /// Largest size in bytes the grants may add up to when mapping more, from the `as` resource
+    /// limit of the contexts using the address space.
+    pub size_limit: u64,
+    /// Pointer authentication keys of userspace, loaded with the address space.
+    #[cfg(target_arch = "aarch64")]
+    pub pac_keys: crate::arch::pauth::Keys,
+    /// Whether executable pages are mapped as guarded pages, for binaries using BTI.
+    #[cfg(target_arch = "aarch64")]
+    pub bti: bool,
+    /// Whether the contexts using the address space may use AMX, which enlarges their saved
+    /// extended state.
+    #[cfg(target_arch = "x86_64")]
+    pub amx: bool,
 }
 impl AddrSpaceWrapper {
     /// Attempt to clone an existing address space so that all mappings are copied (CoW).
@@ -145,6 +266,17 @@ impl AddrSpaceWrapper {
         let new = Arc::get_mut(&mut new_arc)
             .expect("expected new address space Arc not to be aliased");
 
+        // Pointers signed in this address space remain valid in the clone.
+        #[cfg(target_arch = "aarch64")]
+        {
+            new.inner.get_mut().pac_keys = guard.pac_keys;
+            new.inner.get_mut().bti = guard.bti;
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            new.inner.get_mut().amx = guard.amx;
+        }
+
         let this_mapper = &mut guard.table.utable;
         let mut this_flusher = Flusher::with_cpu_set(&mut guard.used_by, &self.tlb_ack);
 
@@ -166,6 +298,12 @@ impl AddrSpaceWrapper {
                     ..
                 } => continue,
 
+                // Nor private tagged memory, since CoW copies would lose the allocation tags.
+                #[cfg(target_arch = "aarch64")]
+                Provider::Allocated { .. } if crate::arch::mte::is_tagged(grant_info.flags) => {
+                    continue;
+                }
+
                 Provider::PhysBorrowed { base } => Grant::physmap(
                     base.clone(),
                     PageSpan::new(grant_base, grant_info.page_count),
@@ -477,9 +615,31 @@ impl AddrSpace {
             grants: UserGrants::new(),
             table: setup_new_utable()?,
             mmap_min: MMAP_MIN_DEFAULT,
+            size_limit: RLIM_INFINITY,
             used_by: LogicalCpuSet::empty(),
+            #[cfg(target_arch = "aarch64")]
+            pac_keys: crate::arch::pauth::Keys::generate(),
+            #[cfg(target_arch = "aarch64")]
+            bti: false,
+            #[cfg(target_arch = "x86_64")]
+            amx: false,
         })
     }
+    /// Switch the current CPU to this address space.
+    pub unsafe fn make_current(&self) {
+        self.table.utable.make_current();
+        #[cfg(target_arch = "aarch64")]
+        crate::arch::pauth::load(&self.pac_keys);
+    }
+    /// Apply the architecture specific attributes of this address space to the flags of a new
+    /// mapping. They are kept when the mapping is later changed by `mprotect`.
+    fn arch_page_flags(&self, flags: PageFlags<RmmA>) -> PageFlags<RmmA> {
+        #[cfg(target_arch = "aarch64")]
+        if self.bti {
+            return crate::arch::pauth::guard(flags);
+        }
+        flags
+    }
     fn munmap_inner(
         this_grants: &mut UserGrants,
         this_mapper: &mut PageMapper,
@@ -598,6 +758,29 @@ impl AddrSpace {
     ) -> Result<Page> {
         debug_assert_eq!(dst_lock.inner.as_mut_ptr(), self as *mut Self);
 
+        // Checked before a fixed mapping replaces anything, without counting what it replaces.
+        if self.size_limit != RLIM_INFINITY {
+            let replaced = match requested_base_opt {
+                Some(requested_base) if flags.contains(MapFlags::MAP_FIXED) => {
+                    let requested_span = PageSpan::new(requested_base, page_count.get());
+                    self.grants
+                        .conflicts(requested_span)
+                        .map(|(base, info)| {
+                            PageSpan::new(base, info.page_count())
+                                .intersection(requested_span)
+                                .count
+                        })
+                        .sum()
+                }
+                _ => 0,
+            };
+            let new_size =
+                (self.grants.page_count() - replaced + page_count.get()) as u64 * PAGE_SIZE as u64;
+            if new_size > self.size_limit {
+                return Err(Error::new(ENOMEM));
+            }
+        }
+
         let selected_span = match requested_base_opt {
             // TODO: Rename MAP_FIXED+MAP_FIXED_NOREPLACE to MAP_FIXED and
             // MAP_FIXED_REPLACE/MAP_REPLACE?
@@ -634,7 +817,7 @@ impl AddrSpace {
 
         let grant = map(
             selected_span.base,
-            page_flags(flags),
+            self.arch_page_flags(page_flags(flags)),
             &mut self.table.utable,
             &mut Flusher::with_cpu_set(&mut self.used_by, &dst_lock.tlb_ack),
         )?;
@@ -642,6 +825,69 @@ impl AddrSpace {
 
         Ok(selected_span.base)
     }
+
+    /// Memory usage of the address space. The resident size is found by walking the page
+    /// tables, as pages may be mapped lazily.
+    pub fn stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+        for (base, info) in self.grants.iter() {
+            let start = base.start_address().data();
+            let end = start + info.page_count() * PAGE_SIZE;
+            let resident = unsafe { count_mapped(&self.table.utable.table(), start, end) };
+
+            stats.grants += 1;
+            stats.virtual_pages += info.page_count();
+            stats.resident_pages += resident;
+            if !matches!(info.provider, Provider::Allocated { .. }) {
+                stats.shared_pages += resident;
+            }
+        }
+        stats
+    }
+}
+
+/// Make `flags` read-only. The dirty bit is cleared on x86_64, where read-only dirty pages are
+/// shadow stack pages once CET is enabled.
+fn write_protected(flags: PageFlags<RmmA>) -> PageFlags<RmmA> {
+    let flags = flags.write(false);
+    #[cfg(target_arch = "x86_64")]
+    let flags = flags.custom_flag(crate::paging::entry::EntryFlags::DIRTY.bits(), false);
+    flags
+}
+
+/// Count the pages mapped from `start` to `end` under `table`. Absent intermediate entries are
+/// skipped as a whole, so unpopulated reservations cost one entry per table rather than one
+/// translation per page.
+unsafe fn count_mapped(table: &rmm::PageTable<RmmA>, start: usize, end: usize) -> usize {
+    let shift = RmmA::PAGE_SHIFT + RmmA::PAGE_ENTRY_SHIFT * table.level();
+    let mut count = 0;
+    let mut addr = start;
+    while addr < end {
+        let i = (addr >> shift) & (RmmA::PAGE_ENTRIES - 1);
+        let next_addr = cmp::min(end, (addr | ((1 << shift) - 1)).saturating_add(1));
+        if table.level() == 0 {
+            if table.entry(i).is_some_and(|entry| entry.address().is_ok()) {
+                count += 1;
+            }
+        } else if let Some(next) = table.next(i) {
+            count += count_mapped(&next, addr, next_addr);
+        }
+        addr = next_addr;
+    }
+    count
+}
+
+/// Memory usage of an address space, as reported by `proc:<pid>/status`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryStats {
+    pub grants: usize,
+    /// Pages spanned by grants.
+    pub virtual_pages: usize,
+    /// Pages currently mapped.
+    pub resident_pages: usize,
+    /// Part of the resident pages that are shared with other address spaces or borrowed, rather
+    /// than privately owned.
+    pub shared_pages: usize,
 }
 
 #[derive(Debug)]
@@ -650,6 +896,8 @@ pub struct UserGrants {
     inner: BTreeMap<Page, GrantInfo>,
     // Using a BTreeMap for it's range method.
     holes: BTreeMap<VirtualAddress, usize>,
+    // Total page count of all grants, kept for the address space limit.
+    page_count: usize,
     // TODO: Would an additional map ordered by (size,start) to allow for O(log n) allocations be
     // beneficial?
 
@@ -757,6 +1005,7 @@ impl UserGrants {
             inner: BTreeMap::new(),
             holes: core::iter::once((VirtualAddress::new(0), crate::USER_END_OFFSET))
                 .collect::<BTreeMap<_, _>>(),
+            page_count: 0,
             funmap: HashMap::new(),
         }
     }
@@ -906,6 +1155,7 @@ impl UserGrants {
             .next()
             .is_none());
         self.reserve(grant.base, grant.info.page_count);
+        self.page_count += grant.info.page_count;
 
         let before_region = self
             .inner
@@ -944,8 +1194,13 @@ impl UserGrants {
     pub fn remove(&mut self, base: Page) -> Option<Grant> {
         let info = self.inner.remove(&base)?;
         Self::unreserve(&mut self.holes, base, info.page_count);
+        self.page_count -= info.page_count;
         Some(Grant { base, info })
     }
+    /// Returns the number of pages occupied by all grants.
+    pub fn page_count(&self) -> usize {
+        self.page_count
+    }
     pub fn iter(&self) -> impl Iterator<Item = (Page, &GrantInfo)> + '_ {
         self.inner.iter().map(|(base, info)| (*base, info))
     }
@@ -1073,6 +1328,53 @@ impl Grant {
         })
     }
 
+    /// Map the allocated `frames` in order from `base`, sharing them with their current owner,
+    /// which keeps its reference. Used by `shm:` segments.
+    pub fn allocated_shared(
+        frames: impl ExactSizeIterator<Item = Frame>,
+        base: Page,
+        flags: PageFlags<RmmA>,
+        mapper: &mut PageMapper,
+        flusher: &mut Flusher,
+    ) -> Result<Grant> {
+        let page_count = frames.len();
+
+        for (i, frame) in frames.enumerate() {
+            let info = get_page_info(frame).expect("needs page info");
+            info.add_ref(RefKind::Shared).map_err(|_| Error::new(ENOMEM))?;
+
+            unsafe {
+                let Some(flush) = mapper.map_phys(base.next_by(i).start_address(), frame.start_address(), flags) else {
+                    // Unlike lazy grants, the pages cannot be filled in later, so undo the mapping.
+                    if info.remove_ref().is_none() {
+                        deallocate_frame(frame);
+                    }
+                    for page in PageSpan::new(base, i).pages() {
+                        let (phys, _, flush) = mapper.unmap_phys(page.start_address(), true).expect("was just mapped");
+                        flush.ignore();
+                        flusher.queue(Frame::containing_address(phys), None, TlbShootdownActions::FREE);
+                    }
+                    return Err(Error::new(ENOMEM));
+                };
+                flush.ignore();
+
+                flusher.queue(frame, None, TlbShootdownActions::NEW_MAPPING);
+            }
+        }
+
+        Ok(Grant {
+            base,
+            info: GrantInfo {
+                page_count,
+                flags,
+                mapped: true,
+                provider: Provider::AllocatedShared {
+                    is_pinned_userscheme_borrow: false,
+                },
+            },
+        })
+    }
+
     pub fn physmap(
         phys: Frame,
         span: PageSpan,
@@ -1126,6 +1428,10 @@ impl Grant {
         }
 
         let alloc_order = span.count.next_power_of_two().trailing_zeros();
+        #[cfg(feature = "fault_injection")]
+        if crate::fault::inject(crate::fault::Point::Frame) {
+            return Err(Enomem);
+        }
         let base = crate::memory::allocate_p2frame(alloc_order).ok_or(Enomem)?;
 
         for (i, page) in span.pages().enumerate() {
@@ -1502,7 +1808,7 @@ impl Grant {
             let src_frame = match rk {
                 RefKind::Cow => {
                     let Some((_, phys, flush)) = (unsafe {
-                        src_mapper.remap_with(src_page.start_address(), |flags| flags.write(false))
+                        src_mapper.remap_with(src_page.start_address(), write_protected)
                     }) else {
                         // Page is not mapped, let the page fault handler take care of that (initializing
                         // it to zero).
@@ -1980,10 +2286,24 @@ impl GrantInfo {
             && (self.flags.has_execute() || !flags.contains(MapFlags::PROT_EXEC));
 
         match self.provider {
+            // Shadow stacks must never become writable.
+            Provider::Allocated { .. } if self.is_shadow_stack() => false,
             Provider::Allocated { .. } => true,
             _ => is_downgrade,
         }
     }
+    /// Whether the grant is a userspace shadow stack.
+    pub fn is_shadow_stack(&self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        {
+            !self.flags.has_write()
+                && self.flags.data() & crate::paging::entry::EntryFlags::DIRTY.bits() != 0
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            false
+        }
+    }
 
     pub fn can_be_merged_if_adjacent(&self, with: &Self) -> bool {
         if self.mapped != with.mapped || self.flags.data() != with.flags.data() {
@@ -2195,7 +2515,7 @@ pub fn setup_new_utable() -> Result<Table> {
 
     Ok(Table { utable })
 }
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum AccessMode {
     Read,
     Write,
@@ -2250,6 +2570,10 @@ fn cow(old_frame: Frame, old_info: &PageInfo, initial_ref_kind: RefKind) -> Resu
         });
     }
 
+    #[cfg(feature = "fault_injection")]
+    if crate::fault::inject(crate::fault::Point::Frame) {
+        return Err(PfError::Oom);
+    }
     let new_frame = init_frame(initial_rc)?;
 
     if old_frame != the_zeroed_frame().0 {
@@ -2270,6 +2594,10 @@ fn map_zeroed(
     page_flags: PageFlags<RmmA>,
     _writable: bool,
 ) -> Result<Frame, PfError> {
+    #[cfg(feature = "fault_injection")]
+    if crate::fault::inject(crate::fault::Point::Frame) {
+        return Err(PfError::Oom);
+    }
     let new_frame = init_frame(RefCount::One)?;
 
     unsafe {
@@ -2311,11 +2639,11 @@ pub fn try_correcting_page_tables(faulting_page: Page, access: AccessMode) -> Re
 }
 fn correct_inner<'l>(
     addr_space_lock: &'l Arc<AddrSpaceWrapper>,
-    mut addr_space_guard: RwLockWriteGuard<'l, AddrSpace>,
+    mut addr_space_guard: Tracked<RwLockWriteGuard<'l, AddrSpace>>,
     faulting_page: Page,
     access: AccessMode,
     recursion_level: u32,
-) -> Result<(Frame, PageFlush<RmmA>, RwLockWriteGuard<'l, AddrSpace>), PfError> {
+) -> Result<(Frame, PageFlush<RmmA>, Tracked<RwLockWriteGuard<'l, AddrSpace>>), PfError> {
     let mut addr_space = &mut *addr_space_guard;
     let mut flusher = Flusher::with_cpu_set(&mut addr_space.used_by, &addr_space_lock.tlb_ack);
 
@@ -2481,7 +2809,7 @@ fn correct_inner<'l>(
                             flusher.flush();
                         }
 
-                        let mut guard = RwLockUpgradableGuard::upgrade(guard);
+                        let mut guard = map_tracked(guard, RwLockUpgradableGuard::upgrade);
 
                         // TODO: flusher
                         unsafe {
@@ -2503,7 +2831,7 @@ fn correct_inner<'l>(
                 // simply let the current context fail. TODO: But all borrowed memory shouldn't
                 // really be lazy though? TODO: Should a grant be created?
 
-                let mut guard = RwLockUpgradableGuard::upgrade(guard);
+                let mut guard = map_tracked(guard, RwLockUpgradableGuard::upgrade);
 
                 // TODO: Should this be called?
                 log::warn!("Mapped zero page since grant didn't exist");
@@ -2591,7 +2919,7 @@ pub struct BorrowedFmapSource<'a> {
     pub mode: MmapMode,
     // TODO: There should be a method that obtains the lock from the guard.
     pub addr_space_lock: &'a Arc<AddrSpaceWrapper>,
-    pub addr_space_guard: RwLockWriteGuard<'a, AddrSpace>,
+    pub addr_space_guard: Tracked<RwLockWriteGuard<'a, AddrSpace>>,
 }
 
 pub fn handle_notify_files(notify_files: Vec<UnmapResult>) {
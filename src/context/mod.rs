@@ -11,6 +11,7 @@ use crate::{
     cpu_set::LogicalCpuSet,
     paging::{RmmA, RmmArch, TableKind},
     percpu::PercpuBlock,
+    sync::Tracked,
     syscall::error::{Error, Result, ESRCH},
 };
 
@@ -35,9 +36,18 @@ mod arch;
 /// Context struct
 pub mod context;
 
+/// CPU time accounting
+pub mod cputime;
+
 /// Context list
 mod list;
 
+/// Load averages
+pub mod loadavg;
+
+/// Soft lockup and hung context detection
+pub mod lockup;
+
 /// Context switch function
 pub mod switch;
 
@@ -47,12 +57,27 @@ pub mod file;
 /// Memory struct - contains a set of pages for a context
 pub mod memory;
 
+/// Resource limits
+pub mod rlimit;
+
+/// Scheduling parameters
+pub mod sched;
+
 /// Signal handling
 pub mod signal;
 
+/// Signal queues
+pub mod signal_queue;
+
 /// Timeout handling
 pub mod timeout;
 
+/// Signal timers
+pub mod timer;
+
+/// Tickless idle
+pub mod tickless;
+
 pub use self::switch::switch_finish_hook;
 
 /// Limit on number of contexts
@@ -65,6 +90,8 @@ pub const CONTEXT_MAX_FILES: usize = 65_536;
 static CONTEXTS: RwLock<ContextList> = RwLock::new(ContextList::new());
 
 pub use self::arch::empty_cr3;
+#[cfg(target_arch = "x86_64")]
+pub use self::arch::{current_fsgsbase, reload_xcr0, set_current_fsgsbase};
 
 pub fn init() {
     let mut contexts = contexts_mut();
@@ -89,15 +116,33 @@ pub fn init() {
         percpu.switch_internals.set_context_id(context.id);
         percpu.switch_internals.set_idle_id(context.id);
     }
+    switch::init_idle();
+    cputime::init();
 }
 
 /// Get the global schemes list, const
-pub fn contexts() -> RwLockReadGuard<'static, ContextList> {
+pub fn contexts() -> Tracked<RwLockReadGuard<'static, ContextList>> {
+    #[cfg(feature = "lock_debug")]
+    return crate::sync::lock_debug::CONTEXT_LIST
+        .acquire(|| CONTEXTS.try_read(), core::hint::spin_loop);
+
+    #[cfg(not(feature = "lock_debug"))]
     CONTEXTS.read()
 }
 
+/// Try to get the global context list, for debugging paths that must not block, such as those
+/// run from interrupt handlers.
+pub fn try_contexts() -> Option<RwLockReadGuard<'static, ContextList>> {
+    CONTEXTS.try_read()
+}
+
 /// Get the global schemes list, mutable
-pub fn contexts_mut() -> RwLockWriteGuard<'static, ContextList> {
+pub fn contexts_mut() -> Tracked<RwLockWriteGuard<'static, ContextList>> {
+    #[cfg(feature = "lock_debug")]
+    return crate::sync::lock_debug::CONTEXT_LIST
+        .acquire(|| CONTEXTS.try_write(), core::hint::spin_loop);
+
+    #[cfg(not(feature = "lock_debug"))]
     CONTEXTS.write()
 }
 
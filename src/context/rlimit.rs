@@ -0,0 +1,204 @@
+//! Per-context resource limits.
+//!
+//! The resource numbering follows the Linux `RLIMIT_*` constants, so that relibc can pass them
+//! through unchanged.
+
+use alloc::{collections::BTreeMap, string::String};
+use core::fmt::Write;
+use spin::Mutex;
+
+use crate::syscall::error::{Error, Result, EAGAIN, EINVAL, EPERM};
+
+/// Value of a limit that is not enforced.
+pub const RLIM_INFINITY: u64 = u64::MAX;
+/// Default number of signals that can be queued for a context, bounding the memory taken by
+/// real-time signals.
+pub const DEFAULT_SIGPENDING: u64 = 4096;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rlimit {
+    /// The soft limit, which is what is actually enforced.
+    pub cur: u64,
+    /// The hard limit, which is the ceiling for the soft limit.
+    pub max: u64,
+}
+impl Rlimit {
+    pub const UNLIMITED: Self = Self {
+        cur: RLIM_INFINITY,
+        max: RLIM_INFINITY,
+    };
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(usize)]
+pub enum Resource {
+    Cpu = 0,
+    Fsize,
+    Data,
+    Stack,
+    Core,
+    Rss,
+    Nproc,
+    Nofile,
+    Memlock,
+    As,
+    Locks,
+    Sigpending,
+    Msgqueue,
+    Nice,
+    Rtprio,
+    Rttime,
+}
+pub const RESOURCE_COUNT: usize = core::mem::variant_count::<Resource>();
+
+impl Resource {
+    pub const ALL: [Self; RESOURCE_COUNT] = [
+        Self::Cpu,
+        Self::Fsize,
+        Self::Data,
+        Self::Stack,
+        Self::Core,
+        Self::Rss,
+        Self::Nproc,
+        Self::Nofile,
+        Self::Memlock,
+        Self::As,
+        Self::Locks,
+        Self::Sigpending,
+        Self::Msgqueue,
+        Self::Nice,
+        Self::Rtprio,
+        Self::Rttime,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Cpu => "cpu",
+            Self::Fsize => "fsize",
+            Self::Data => "data",
+            Self::Stack => "stack",
+            Self::Core => "core",
+            Self::Rss => "rss",
+            Self::Nproc => "nproc",
+            Self::Nofile => "nofile",
+            Self::Memlock => "memlock",
+            Self::As => "as",
+            Self::Locks => "locks",
+            Self::Sigpending => "sigpending",
+            Self::Msgqueue => "msgqueue",
+            Self::Nice => "nice",
+            Self::Rtprio => "rtprio",
+            Self::Rttime => "rttime",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|resource| resource.name() == name)
+    }
+}
+
+/// The limit table of a context, inherited by children.
+#[derive(Clone, Copy, Debug)]
+pub struct Rlimits([Rlimit; RESOURCE_COUNT]);
+
+impl Rlimits {
+    pub fn new() -> Self {
+        let mut this = Self([Rlimit::UNLIMITED; RESOURCE_COUNT]);
+        this.0[Resource::Nofile as usize] = Rlimit {
+            cur: super::CONTEXT_MAX_FILES as u64,
+            max: super::CONTEXT_MAX_FILES as u64,
+        };
+        // Raising the scheduling priority is a privileged operation by default.
+        this.0[Resource::Nice as usize] = Rlimit { cur: 0, max: 0 };
+        this.0[Resource::Rtprio as usize] = Rlimit { cur: 0, max: 0 };
+        this.0[Resource::Sigpending as usize] = Rlimit {
+            cur: DEFAULT_SIGPENDING,
+            max: DEFAULT_SIGPENDING,
+        };
+        this
+    }
+    pub fn get(&self, resource: Resource) -> Rlimit {
+        self.0[resource as usize]
+    }
+    /// Set a limit. Raising the hard limit requires `privileged`.
+    pub fn set(&mut self, resource: Resource, new: Rlimit, privileged: bool) -> Result<()> {
+        if new.cur > new.max {
+            return Err(Error::new(EINVAL));
+        }
+        if new.max > self.0[resource as usize].max && !privileged {
+            return Err(Error::new(EPERM));
+        }
+        self.0[resource as usize] = new;
+        Ok(())
+    }
+
+    /// Render the table as one `<name> <soft> <hard>` line per resource.
+    pub fn format(&self) -> String {
+        let mut string = String::new();
+        for resource in Resource::ALL {
+            let limit = self.get(resource);
+            let _ = writeln!(
+                string,
+                "{} {} {}",
+                resource.name(),
+                format_value(limit.cur),
+                format_value(limit.max)
+            );
+        }
+        string
+    }
+}
+
+/// Number of contexts of each real user, checked against the `nproc` limit.
+static USER_CONTEXTS: Mutex<BTreeMap<u32, u64>> = Mutex::new(BTreeMap::new());
+
+/// Accounts a context to its real user, until it is dropped along with the context.
+#[derive(Debug)]
+pub struct UserContext {
+    uid: u32,
+}
+impl UserContext {
+    /// Account a new context to `uid`, failing with `EAGAIN` if the user already has `limit`
+    /// contexts.
+    pub fn new(uid: u32, limit: u64) -> Result<Self> {
+        let mut counts = USER_CONTEXTS.lock();
+        let count = counts.entry(uid).or_insert(0);
+        if limit != RLIM_INFINITY && *count >= limit {
+            return Err(Error::new(EAGAIN));
+        }
+        *count += 1;
+        Ok(Self { uid })
+    }
+    /// Move the context to another real user. This is not checked against the limit.
+    pub fn set_uid(&mut self, uid: u32) {
+        if uid == self.uid {
+            return;
+        }
+        let mut counts = USER_CONTEXTS.lock();
+        release(&mut counts, self.uid);
+        *counts.entry(uid).or_insert(0) += 1;
+        self.uid = uid;
+    }
+}
+impl Drop for UserContext {
+    fn drop(&mut self) {
+        release(&mut USER_CONTEXTS.lock(), self.uid);
+    }
+}
+fn release(counts: &mut BTreeMap<u32, u64>, uid: u32) {
+    if let Some(count) = counts.get_mut(&uid) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&uid);
+        }
+    }
+}
+
+fn format_value(value: u64) -> String {
+    if value == RLIM_INFINITY {
+        String::from("unlimited")
+    } else {
+        format!("{}", value)
+    }
+}
@@ -0,0 +1,184 @@
+//! Per-context scheduling parameters.
+//!
+//! The parameters are exchanged as text through `proc:<pid>/sched`, one `<key> <value>` line
+//! each, where a write may update any subset of them.
+//!
+//! Time-shared contexts are picked by their virtual runtime, the CPU time they used scaled down by
+//! the weight of their nice value, so that each nice level gets about 10% more CPU time than the
+//! next when competing. The time slice also grows with the priority.
+//!
+//! Real-time contexts always run ahead of time-shared ones, and a higher real-time priority ahead
+//! of a lower one, preempting them on other CPUs with an IPI when woken. FIFO contexts run until
+//! they block or yield, and round-robin ones for their time slice among those of the same
+//! priority. The kernel itself is not preemptible, so a context woken from a
+//! [`WaitCondition`](crate::sync::WaitCondition) cannot be delayed by a lower priority one holding
+//! a kernel lock, and no priority inheritance is needed.
+
+use alloc::string::String;
+use core::fmt::Write;
+
+use super::rlimit::{Resource, Rlimits, RLIM_INFINITY};
+use crate::syscall::error::{Error, Result, EINVAL, EPERM};
+
+/// Time slice, in scheduler ticks, of a context with a nice value of 0.
+pub const DEFAULT_TIMESLICE: u8 = 3;
+/// Upper bound of explicitly set time slices.
+pub const MAX_TIMESLICE: u8 = 100;
+
+/// Weight of a context with a nice value of 0.
+const NICE_0_WEIGHT: u128 = 1024;
+/// Weight of each nice value from [`NICE_MIN`] to [`NICE_MAX`], the same as on Linux.
+const NICE_WEIGHTS: [u32; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+pub const RT_PRIORITY_MAX: u8 = 99;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SchedPolicy {
+    /// Time-shared, weighted by the nice value.
+    Other,
+    /// Real-time, run until blocking or yielding.
+    Fifo,
+    /// Real-time, round-robin within the same priority.
+    RoundRobin,
+}
+impl SchedPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Other => "other",
+            Self::Fifo => "fifo",
+            Self::RoundRobin => "rr",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Other, Self::Fifo, Self::RoundRobin]
+            .into_iter()
+            .find(|policy| policy.name() == name)
+    }
+    pub fn is_realtime(self) -> bool {
+        self != Self::Other
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SchedParams {
+    pub policy: SchedPolicy,
+    pub nice: i8,
+    /// Only meaningful for real-time policies.
+    pub rt_priority: u8,
+    /// Explicit time slice in ticks, or `None` to derive it from the nice value.
+    pub timeslice: Option<u8>,
+}
+impl SchedParams {
+    pub const fn new() -> Self {
+        Self {
+            policy: SchedPolicy::Other,
+            nice: 0,
+            rt_priority: 0,
+            timeslice: None,
+        }
+    }
+
+    /// The rank of the context, where a runnable context of a higher rank always runs first: 0 for
+    /// time-shared contexts, and 1 above the priority for real-time ones.
+    pub fn rank(&self) -> u8 {
+        if self.policy.is_realtime() {
+            1 + self.rt_priority
+        } else {
+            0
+        }
+    }
+
+    /// The number of ticks the context may run before another of the same rank is switched to.
+    pub fn preemption_ticks(&self) -> usize {
+        match self.policy {
+            SchedPolicy::Fifo => usize::MAX,
+            SchedPolicy::Other | SchedPolicy::RoundRobin => self.timeslice_ticks(),
+        }
+    }
+
+    /// The number of ticks a context may run before being preempted.
+    pub fn timeslice_ticks(&self) -> usize {
+        match self.timeslice {
+            Some(ticks) => usize::from(ticks),
+            // Nice -20 doubles the default slice, and nice 19 shrinks it to a single tick.
+            None => {
+                let weight = (20 - isize::from(self.nice)) as usize;
+                (usize::from(DEFAULT_TIMESLICE) * weight / 20).max(1)
+            }
+        }
+    }
+
+    /// The virtual runtime charged for running `time` nanoseconds.
+    pub fn vruntime_delta(&self, time: u128) -> u64 {
+        let weight = NICE_WEIGHTS[(self.nice - NICE_MIN) as usize];
+        u64::try_from(time * NICE_0_WEIGHT / u128::from(weight)).unwrap_or(u64::MAX)
+    }
+
+    pub fn format(&self) -> String {
+        let mut string = String::new();
+        let _ = writeln!(string, "policy {}", self.policy.name());
+        let _ = writeln!(string, "nice {}", self.nice);
+        let _ = writeln!(string, "rt_priority {}", self.rt_priority);
+        let _ = writeln!(string, "timeslice {}", self.timeslice_ticks());
+        string
+    }
+    /// Apply `<key> <value>` lines. Without `privileged`, the nice value and real-time priority
+    /// can only be raised to what the `nice` and `rtprio` resource limits allow, and the time
+    /// slice cannot be lengthened. All lines are validated before any parameter is changed.
+    pub fn parse_and_set(&mut self, text: &str, limits: &Rlimits, privileged: bool) -> Result<()> {
+        let mut new = *self;
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (key, value) = line.split_once(' ').ok_or(Error::new(EINVAL))?;
+            let value = value.trim();
+            match key {
+                "policy" => new.policy = SchedPolicy::from_name(value).ok_or(Error::new(EINVAL))?,
+                "nice" => new.nice = value.parse().map_err(|_| Error::new(EINVAL))?,
+                "rt_priority" => new.rt_priority = value.parse().map_err(|_| Error::new(EINVAL))?,
+                "timeslice" => new.timeslice = Some(value.parse().map_err(|_| Error::new(EINVAL))?),
+                _ => return Err(Error::new(EINVAL)),
+            }
+        }
+
+        if !(NICE_MIN..=NICE_MAX).contains(&new.nice)
+            || new.rt_priority > RT_PRIORITY_MAX
+            || new
+                .timeslice
+                .map_or(false, |ticks| ticks == 0 || ticks > MAX_TIMESLICE)
+        {
+            return Err(Error::new(EINVAL));
+        }
+
+        if !privileged {
+            // As on Linux, the nice limit is expressed as 20 - nice.
+            let nice_limit = limits.get(Resource::Nice).cur;
+            let min_nice = if nice_limit == RLIM_INFINITY {
+                i64::from(NICE_MIN)
+            } else {
+                20 - nice_limit.min(40) as i64
+            };
+            if new.nice < self.nice && i64::from(new.nice) < min_nice {
+                return Err(Error::new(EPERM));
+            }
+
+            let rtprio_limit = limits.get(Resource::Rtprio).cur;
+            let raises_rt = new.policy.is_realtime()
+                && (!self.policy.is_realtime() || new.rt_priority > self.rt_priority);
+            if raises_rt && (rtprio_limit == 0 || u64::from(new.rt_priority) > rtprio_limit) {
+                return Err(Error::new(EPERM));
+            }
+
+            if new.timeslice != self.timeslice && new.timeslice_ticks() > self.timeslice_ticks() {
+                return Err(Error::new(EPERM));
+            }
+        }
+
+        *self = new;
+        Ok(())
+    }
+}
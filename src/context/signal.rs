@@ -1,20 +1,176 @@
 use alloc::sync::Arc;
-use core::mem::size_of;
+use core::{mem::size_of, num::NonZeroUsize};
 use syscall::{
     flag::{
-        PTRACE_FLAG_IGNORE, PTRACE_STOP_SIGNAL, SIGCHLD, SIGCONT, SIGKILL, SIGSTOP, SIGTSTP,
-        SIGTTIN, SIGTTOU, SIG_DFL, SIG_IGN,
+        PTRACE_FLAG_IGNORE, PTRACE_STOP_SIGNAL, SIGCHLD, SIGCONT, SIGKILL, SIGSEGV, SIGSTOP,
+        SIGTSTP, SIGTTIN, SIGTTOU, SIG_DFL, SIG_IGN,
     },
     ptrace_event, SignalStack, SigActionFlags, IntRegisters, SIGTERM,
 };
 
 use crate::{
     context::{self, switch, Status, WaitpidKey},
+    memory::PAGE_SIZE,
+    paging::{Page, VirtualAddress},
     ptrace,
-    syscall::usercopy::UserSlice, stop::{kstop, kreset},
+    syscall::{
+        error::{Error, Result, EFAULT, EINVAL, ENOMEM, EPERM, ESRCH},
+        usercopy::UserSlice,
+    },
+    stop::{kstop, kreset},
+};
+
+use super::{
+    context::{Altstack, FaultInfo},
+    memory::{AddrSpaceWrapper, PageSpan},
+    Context, ContextId,
 };
 
-use super::ContextId;
+/// The first real-time signal, as numbered by relibc. Unlike standard signals, each instance of a
+/// real-time signal sent is queued and delivered.
+pub const SIGRTMIN: usize = 35;
+
+/// Code of [`SignalInfo`] for signals sent with `kill`. The codes have the values of Linux.
+pub const SI_USER: i32 = 0;
+/// Code of [`SignalInfo`] for signals sent by the kernel, such as for faults.
+pub const SI_KERNEL: i32 = 0x80;
+/// Code of [`SignalInfo`] for signals sent with `sigqueue`.
+pub const SI_QUEUE: i32 = -1;
+/// Code of [`SignalInfo`] for signals sent by timers.
+pub const SI_TIMER: i32 = -2;
+
+/// Information about an instance of a signal, delivered to the handler above its
+/// [`SignalStack`], and read from signal queues.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct SignalInfo {
+    pub signo: u32,
+    /// How the signal was sent, as with `si_code`.
+    pub code: i32,
+    /// Process ID of the sender, or 0 if sent by the kernel.
+    pub pid: u32,
+    /// Real user ID of the sender, or 0 if sent by the kernel.
+    pub uid: u32,
+    /// Value sent with `sigqueue`, or the `sigval` of the timer that sent the signal.
+    pub value: u64,
+}
+
+impl SignalInfo {
+    /// A signal sent by the kernel, without a sender.
+    pub fn kernel(sig: usize) -> Self {
+        Self {
+            signo: sig as u32,
+            code: SI_KERNEL,
+            ..Self::default()
+        }
+    }
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts((self as *const Self).cast(), size_of::<Self>()) }
+    }
+}
+
+/// Flag of [`SigaltstackData`] set when the context is running on its alternate stack.
+pub const SS_ONSTACK: usize = 1;
+/// Flag of [`SigaltstackData`] set when there is no alternate stack, or to remove it.
+pub const SS_DISABLE: usize = 2;
+/// Smallest size of an alternate stack, as on Linux.
+pub const MINSIGSTKSZ: usize = 2048;
+
+/// The alternate signal stack of a context, exchanged through `proc:<pid>/sigaltstack`. The
+/// layout is that of `stack_t`, with the flags widened so that there is no padding.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct SigaltstackData {
+    pub base: usize,
+    pub flags: usize,
+    pub size: usize,
+}
+
+impl SigaltstackData {
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts((self as *const Self).cast(), size_of::<Self>()) }
+    }
+}
+
+/// The alternate stack of `context`, and whether it is running on it.
+pub fn altstack(context: &Context) -> SigaltstackData {
+    let Some(altstack) = context.sig.altstack else {
+        return SigaltstackData {
+            flags: SS_DISABLE,
+            ..SigaltstackData::default()
+        };
+    };
+    let on_stack = context
+        .regs()
+        .map_or(false, |regs| altstack.contains(regs.stack_pointer()));
+    SigaltstackData {
+        base: altstack.base.get(),
+        flags: if on_stack { SS_ONSTACK } else { 0 },
+        size: altstack.len.get(),
+    }
+}
+
+/// Validate an alternate stack at `base` of `size` bytes, which must be mapped writable in
+/// `addr_space`. Fails with `ENOMEM` if smaller than [`MINSIGSTKSZ`], and with `EFAULT` if not
+/// mapped.
+pub fn validate_altstack(
+    addr_space: &AddrSpaceWrapper,
+    base: usize,
+    size: usize,
+) -> Result<Altstack> {
+    if size < MINSIGSTKSZ {
+        return Err(Error::new(ENOMEM));
+    }
+    let end = base
+        .checked_add(size)
+        .filter(|&end| end <= crate::USER_END_OFFSET)
+        .ok_or(Error::new(EFAULT))?;
+    let span = PageSpan::between(
+        Page::containing_address(VirtualAddress::new(base)),
+        Page::containing_address(VirtualAddress::new(end.next_multiple_of(PAGE_SIZE))),
+    );
+
+    let addr_space = addr_space.acquire_read();
+    let mut next = span.base;
+    for (grant_base, info) in addr_space.grants.conflicts(span) {
+        if grant_base > next || !info.flags().has_write() {
+            return Err(Error::new(EFAULT));
+        }
+        next = grant_base.next_by(info.page_count());
+    }
+    if next < span.end() {
+        return Err(Error::new(EFAULT));
+    }
+
+    Ok(Altstack {
+        base: NonZeroUsize::new(base).ok_or(Error::new(EFAULT))?,
+        len: NonZeroUsize::new(size).ok_or(Error::new(ENOMEM))?,
+    })
+}
+
+/// Set the alternate stack of the context `pid` from `data`, as with `sigaltstack`. Fails with
+/// `EPERM` if the context is running on its current alternate stack.
+pub fn set_altstack(pid: ContextId, data: SigaltstackData) -> Result<()> {
+    let context_lock = context::contexts()
+        .get(pid)
+        .cloned()
+        .ok_or(Error::new(ESRCH))?;
+    let new = if data.flags & SS_DISABLE == SS_DISABLE {
+        None
+    } else if data.flags & !SS_ONSTACK != 0 {
+        return Err(Error::new(EINVAL));
+    } else {
+        let addr_space = Arc::clone(context_lock.read().addr_space()?);
+        Some(validate_altstack(&addr_space, data.base, data.size)?)
+    };
+
+    let mut context = context_lock.write();
+    if altstack(&context).flags & SS_ONSTACK == SS_ONSTACK {
+        return Err(Error::new(EPERM));
+    }
+    context.sig.altstack = new;
+    Ok(())
+}
 
 pub fn kmain_signal_handler() {
     if context::context_id() != ContextId::new(1) {
@@ -40,10 +196,55 @@ pub fn kmain_signal_handler() {
     }
 }
 
+/// Remember a fault of the current context, which is about to be sent `fault.signal`.
+pub fn record_fault(fault: FaultInfo) {
+    let Ok(context_lock) = context::current() else {
+        return;
+    };
+    // The fault may have happened with the context lock held, so don't spin on it.
+    if let Some(mut context) = context_lock.try_write() {
+        context.last_fault = Some(fault);
+    }
+}
+
+/// Send the signal `sig` of a fault of the current context, and deliver it before any other
+/// pending signal but `SIGKILL`. A handler may already be running, in which case the new one is
+/// nested on top of it.
+///
+/// If the signal is blocked or ignored, it is unblocked and its action reset to the default
+/// first, like Linux does, since the faulting instruction would otherwise be retried forever.
+pub fn force_signal(sig: usize) {
+    {
+        let Ok(context_lock) = context::current() else {
+            return;
+        };
+        let mut context = context_lock.write();
+        let bit = 1_u64 << (sig - 1);
+
+        {
+            let blocked = context.sig.procmask & bit != 0;
+            let mut actions = context.actions.write();
+            let action = &mut actions[sig - 1].0;
+            if blocked || action.sa_handler.map_or(SIG_DFL, |ptr| ptr as usize) == SIG_IGN {
+                // No handler is the default action.
+                action.sa_handler = None;
+            }
+        }
+        context.sig.procmask &= !bit;
+        let _ = context.send_signal(SignalInfo::kernel(sig));
+    }
+    deliver(Some(sig));
+}
+
+/// Deliver the lowest-numbered deliverable signal of the current context, if any.
+pub fn signal_handler() {
+    deliver(None);
+}
+
 // TODO: Move everything but SIGKILL to userspace. SIGCONT and SIGSTOP do not necessarily need to
 // be done from this current context.
-pub fn signal_handler() {
-    let (action, sig) = {
+fn deliver(forced: Option<usize>) {
+    let (mut action, mut sig, mut info) = {
         // FIXME: Can any low-level state become corrupt if a panic occurs here?
         let context_lock = context::current().expect("context::signal_handler not inside of context");
         let mut context = context_lock.write();
@@ -56,26 +257,37 @@ pub fn signal_handler() {
         let bits = context.sig.deliverable();
 
         // Always prioritize SIGKILL and SIGSTOP, so processes can't simply keep themselves alive
-        // by killing themselves.
+        // by killing themselves. Faults come right after SIGKILL, as the faulting instruction
+        // cannot proceed.
         let selected = if bits & (1 << (SIGKILL - 1)) != 0 {
             SIGKILL
+        } else if let Some(sig) = forced.filter(|sig| bits & (1 << (sig - 1)) != 0) {
+            sig
         } else if bits & (1 << (SIGSTOP - 1)) != 0 {
             SIGSTOP
         } else {
             bits.trailing_zeros() as usize + 1
         };
 
-        context.sig.pending &= !(1 << (selected - 1));
+        let info = context.sig.take(selected);
 
         let actions = context.actions.read();
-        (actions[selected - 1].0, selected)
+        (actions[selected - 1].0, selected, info)
     };
 
     let handler = action.sa_handler.map(|ptr| ptr as usize).unwrap_or(0);
 
+    // Only an override given when resuming from this stop applies, not one left over from an
+    // earlier stop that did not report a signal.
+    let _ = ptrace::take_signal_override();
     let thumbs_down = ptrace::breakpoint_callback(
         PTRACE_STOP_SIGNAL,
-        Some(ptrace_event!(PTRACE_STOP_SIGNAL, sig, handler)),
+        Some(ptrace_event!(
+            PTRACE_STOP_SIGNAL,
+            sig,
+            handler,
+            action.sa_flags.bits() as usize
+        )),
     )
     .and_then(|_| ptrace::next_breakpoint().map(|f| f.contains(PTRACE_FLAG_IGNORE)));
 
@@ -84,6 +296,23 @@ pub fn signal_handler() {
         return;
     }
 
+    // The tracer may also have replaced the signal, or dropped it by replacing it with 0.
+    if let Some(new_sig) = ptrace::take_signal_override().filter(|_| sig != SIGKILL) {
+        let context_lock = context::current().expect("context::signal_handler not inside of context");
+        let context = context_lock.read();
+        let actions = context.actions.read();
+
+        match new_sig.checked_sub(1).and_then(|idx| actions.get(idx)) {
+            Some(&(new_action, _)) => {
+                action = new_action;
+                sig = new_sig;
+                info.signo = new_sig as u32;
+            }
+            None => return,
+        }
+    }
+    let handler = action.sa_handler.map(|ptr| ptr as usize).unwrap_or(0);
+
     if handler == SIG_DFL {
         match sig {
             SIGCHLD => {
@@ -118,7 +347,7 @@ pub fn signal_handler() {
                             (pid, 0xFFFF),
                         );
                     } else {
-                        println!("{}: {} not found for continue", pid.get(), ppid.get());
+                        log::warn!("{}: {} not found for continue", pid.get(), ppid.get());
                     }
                 }
             }
@@ -152,7 +381,7 @@ pub fn signal_handler() {
                             (pid, (sig << 8) | 0x7F),
                         );
                     } else {
-                        println!("{}: {} not found for stop", pid.get(), ppid.get());
+                        log::warn!("{}: {} not found for stop", pid.get(), ppid.get());
                     }
                 }
 
@@ -177,23 +406,46 @@ pub fn signal_handler() {
             log::debug!("signal ignored since context did not setup sighandler");
             return;
         };
+        let altstack = context.sig.altstack;
         let Some(regs) = context.regs_mut() else {
             log::warn!("cannot send signal to context without userspace registers");
             return;
         };
         let mut intregs = IntRegisters::default();
         regs.save(&mut intregs);
+        let sp = regs.stack_pointer();
 
         const STACK_ADJUST: usize = 256;
         // TODO: 16 bytes alignment is sufficient unless XSAVE is enabled.
         const STACK_ALIGN: usize = 64;
 
-        let new_sp_unless_altstack = (regs.stack_pointer() - STACK_ADJUST) / STACK_ALIGN * STACK_ALIGN;
-
-        let new_sp = match handler.altstack {
-            Some(altstack) if !(altstack.base.get()..altstack.base.get() + altstack.len.get()).contains(&regs.stack_pointer()) => altstack.base.get() + altstack.len.get(),
-            _ => new_sp_unless_altstack,
-        } - size_of::<SignalStack>();
+        // The signal information is placed above the signal stack, keeping its alignment.
+        let info_space = size_of::<SignalInfo>().next_multiple_of(STACK_ALIGN);
+        let frame_size = info_space + size_of::<SignalStack>();
+
+        // Handlers nested in one running on the alternate stack stay on it, below the frame of
+        // the handler they interrupted, and must not overflow it.
+        let below_sp = sp.saturating_sub(STACK_ADJUST) / STACK_ALIGN * STACK_ALIGN;
+        let (top, bottom) = match altstack {
+            Some(altstack) if altstack.contains(sp) => (below_sp, altstack.base.get()),
+            Some(altstack) => (
+                altstack.top() / STACK_ALIGN * STACK_ALIGN,
+                altstack.base.get(),
+            ),
+            None => (below_sp, 0),
+        };
+        let Some(new_sp) = top
+            .checked_sub(frame_size)
+            .filter(|&new_sp| new_sp >= bottom)
+        else {
+            drop(context);
+            log::warn!(
+                "{}: signal {} overflowed the signal stack",
+                context::context_id().get(),
+                sig
+            );
+            crate::syscall::exit(SIGSEGV);
+        };
 
         let old_procmask = context.sig.procmask;
 
@@ -203,18 +455,9 @@ pub fn signal_handler() {
             context.sig.procmask |= 1 << (sig - 1);
         }
 
-        let Some(regs) = context.regs_mut() else {
-            return;
-        };
-
-        regs.set_stack_pointer(new_sp);
-        regs.set_instr_pointer(handler.handler.get());
-
         drop(context);
 
-        let Ok(slice) = UserSlice::wo(new_sp, size_of::<SignalStack>()) else {
-            return;
-        };
+        // The frame is written before switching to the handler, which it must not run without.
         let stack = SignalStack {
             intregs,
             old_procmask,
@@ -223,8 +466,27 @@ pub fn signal_handler() {
             sig_num: sig as u32,
             sa_handler: action.sa_handler.map_or(0, |h| h as usize),
         };
-        let Ok(()) = slice.copy_from_slice(&stack) else {
+        let written = UserSlice::wo(new_sp, size_of::<SignalStack>())
+            .and_then(|slice| slice.copy_from_slice(&stack))
+            .and_then(|()| {
+                UserSlice::wo(new_sp + size_of::<SignalStack>(), size_of::<SignalInfo>())
+            })
+            .and_then(|slice| slice.copy_from_slice(info.as_bytes()));
+        if written.is_err() {
+            log::warn!(
+                "{}: cannot write the frame of signal {} at {:#x}",
+                context::context_id().get(),
+                sig,
+                new_sp
+            );
+            crate::syscall::exit(SIGSEGV);
+        }
+
+        let mut context = context_lock.write();
+        let Some(regs) = context.regs_mut() else {
             return;
         };
+        regs.set_stack_pointer(new_sp);
+        regs.set_instr_pointer(handler.handler.get());
     }
 }
@@ -0,0 +1,161 @@
+//! Signal queues, which receive signals as records read from a descriptor, as with Linux
+//! `signalfd`.
+//!
+//! A queue is opened at `proc:<pid>/signal-queue`, and receives the signals in its mask, set by
+//! writing a `u64` bitset to it. Reading it takes the pending signals in the mask from the context,
+//! lowest-numbered first and each real-time signal as many times as it is queued, and returns a
+//! [`SignalInfo`] for each. Signals in the mask should also be
+//! in the procmask of the context, or they may be delivered to the handler first. The queue is
+//! readable with `EVENT_READ` while one of its signals is pending. `SIGKILL` and `SIGSTOP` cannot
+//! be received.
+//!
+//! Readers and events are notified when a signal is sent with `kill` or by a timer with a
+//! timeout. Those raised by CPU time timers are found by the next read or poll of the queue.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::{Mutex, RwLock};
+use syscall::flag::{EventFlags, EVENT_READ, SIGKILL, SIGSTOP};
+
+use crate::{
+    context::{self, signal::SignalInfo, ContextId},
+    event,
+    scheme::SchemeId,
+    sync::WaitCondition,
+    syscall::{
+        error::{Error, Result, EAGAIN, EBADF, EINTR, EINVAL, ESRCH},
+        usercopy::UserSliceWo,
+    },
+};
+
+/// Signals that are always delivered, and cannot be received from a queue.
+const UNQUEUEABLE: u64 = (1 << (SIGKILL - 1)) | (1 << (SIGSTOP - 1));
+
+pub struct SignalQueue {
+    target: ContextId,
+    /// The scheme the queue was opened through, for events.
+    scheme: SchemeId,
+    mask: AtomicU64,
+    /// Held while checking for signals before waiting, and while notifying, so that no wakeup
+    /// can be missed in between.
+    lock: Mutex<()>,
+    condition: WaitCondition,
+}
+
+// Using BTreeMap as hashbrown doesn't have a const constructor.
+static QUEUES: RwLock<BTreeMap<usize, Arc<SignalQueue>>> = RwLock::new(BTreeMap::new());
+
+/// Create the queue `id` receiving signals of `target`, with an empty mask.
+pub fn open(id: usize, target: ContextId, scheme: SchemeId) {
+    QUEUES.write().insert(
+        id,
+        Arc::new(SignalQueue {
+            target,
+            scheme,
+            mask: AtomicU64::new(0),
+            lock: Mutex::new(()),
+            condition: WaitCondition::new(),
+        }),
+    );
+}
+
+pub fn close(id: usize) {
+    QUEUES.write().remove(&id);
+}
+
+pub fn get(id: usize) -> Result<Arc<SignalQueue>> {
+    QUEUES.read().get(&id).cloned().ok_or(Error::new(EBADF))
+}
+
+/// Notify the queues of `target` receiving `sig`, which was just made pending. Must not be called
+/// with any context locked.
+pub fn notify(target: ContextId, sig: usize) {
+    let bit = 1_u64 << (sig - 1);
+    let queues: Vec<_> = QUEUES
+        .read()
+        .iter()
+        .filter(|(_, queue)| {
+            queue.target == target && queue.mask.load(Ordering::Relaxed) & bit != 0
+        })
+        .map(|(&id, queue)| (id, Arc::clone(queue)))
+        .collect();
+
+    for (id, queue) in queues {
+        event::trigger(queue.scheme, id, EVENT_READ);
+        let _guard = queue.lock.lock();
+        queue.condition.notify();
+    }
+}
+
+impl SignalQueue {
+    pub fn mask(&self) -> u64 {
+        self.mask.load(Ordering::Relaxed)
+    }
+    pub fn set_mask(&self, mask: u64) {
+        self.mask.store(mask & !UNQUEUEABLE, Ordering::Relaxed);
+    }
+
+    /// Ready flags of the queue, among `flags`.
+    pub fn fevent(&self, flags: EventFlags) -> Result<EventFlags> {
+        let context_lock = context::contexts()
+            .get(self.target)
+            .cloned()
+            .ok_or(Error::new(ESRCH))?;
+        let pending = context_lock.read().sig.pending & self.mask();
+
+        Ok(if flags.contains(EVENT_READ) && pending != 0 {
+            EVENT_READ
+        } else {
+            EventFlags::empty()
+        })
+    }
+
+    /// Read as many received signals as fit in `buf`, blocking until there is one unless
+    /// `nonblock` is set.
+    pub fn read(&self, buf: UserSliceWo, nonblock: bool) -> Result<usize> {
+        let max = buf.len() / size_of::<SignalInfo>();
+        if max == 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let context_lock = context::contexts()
+            .get(self.target)
+            .cloned()
+            .ok_or(Error::new(ESRCH))?;
+
+        loop {
+            let guard = self.lock.lock();
+            let taken = {
+                let mut context = context_lock.write();
+                let mut taken = Vec::new();
+                while taken.len() < max {
+                    let ready = context.sig.pending & self.mask();
+                    if ready == 0 {
+                        break;
+                    }
+                    taken.push(context.sig.take(ready.trailing_zeros() as usize + 1));
+                }
+                taken
+            };
+
+            if !taken.is_empty() {
+                drop(guard);
+                let bytes = unsafe {
+                    core::slice::from_raw_parts(
+                        taken.as_ptr().cast::<u8>(),
+                        taken.len() * size_of::<SignalInfo>(),
+                    )
+                };
+                return buf.copy_common_bytes_from_slice(bytes);
+            }
+
+            if nonblock {
+                return Err(Error::new(EAGAIN));
+            } else if !self.condition.wait(guard, "SignalQueue::read") {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+}
@@ -1,14 +1,20 @@
-use core::{cell::Cell, mem, ops::Bound, sync::atomic::Ordering};
+use core::{
+    cell::Cell,
+    cmp::Reverse,
+    mem,
+    ops::Bound,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
 
 use alloc::sync::Arc;
 use spinning_top::guard::ArcRwSpinlockWriteGuard;
 use syscall::PtraceFlags;
 
 use crate::{
-    context::{arch, contexts, Context}, cpu_set::LogicalCpuId, interrupt, percpu::PercpuBlock, ptrace, time
+    context::{arch, contexts, sched::DEFAULT_TIMESLICE, Context}, cpu_set::{LogicalCpuId, LogicalCpuSet, MAX_CPU_COUNT}, interrupt, ipi::{ipi, ipi_single, IpiKind, IpiTarget}, percpu::PercpuBlock, ptrace, time
 };
 
-use super::{ContextId, Status};
+use super::{cputime::Mode as CpuMode, ContextId, Status};
 
 enum UpdateResult {
     CanSwitch { signal: bool },
@@ -27,16 +33,12 @@ unsafe fn update_runnable(context: &mut Context, cpu_id: LogicalCpuId) -> Update
         return UpdateResult::Skip;
     }
 
-    // Ignore contexts assigned to other CPUs
+    // Ignore contexts assigned to other CPUs. Any other context may migrate here, as the CPU it
+    // last ran on released it in switch_finish_hook, once no longer running on its stack.
     if !context.sched_affinity.contains(cpu_id) {
         return UpdateResult::Skip;
     }
 
-    //TODO: HACK TO WORKAROUND HANGS BY PINNING TO ONE CPU
-    if !context.cpu_id.map_or(true, |x| x == cpu_id) {
-        return UpdateResult::Skip;
-    }
-
     let signal = context.sig.deliverable() != 0;
 
     // Unblock when there are pending nonmasked signals.
@@ -63,19 +65,142 @@ unsafe fn update_runnable(context: &mut Context, cpu_id: LogicalCpuId) -> Update
     }
 }
 
+/// CPUs running their idle context, which are sent an IPI when a context they may run becomes
+/// runnable. Busy CPUs pick it up at their next switch instead.
+static IDLE_CPUS: LogicalCpuSet = LogicalCpuSet::empty();
+
+/// Mark the current CPU as running its idle context, as it does when started.
+pub fn init_idle() {
+    IDLE_CPUS.atomic_set(crate::cpu_id());
+}
+
+/// Whether `cpu_id` is running its idle context.
+pub fn is_idle(cpu_id: LogicalCpuId) -> bool {
+    IDLE_CPUS.contains_now(cpu_id)
+}
+
+const NO_RANK: AtomicU8 = AtomicU8::new(0);
+/// The rank of the context running on each CPU, see [`super::sched::SchedParams::rank`].
+static RUNNING_RANKS: [AtomicU8; MAX_CPU_COUNT as usize] = [NO_RANK; MAX_CPU_COUNT as usize];
+
+/// Wake a CPU to run `context`, which has just become runnable. An idle CPU is preferred, the one
+/// the context last ran on first, and otherwise a real-time context preempts the CPU running the
+/// lowest rank below its own. Nothing is done if the context is still running, as its CPU has yet
+/// to switch away.
+pub fn wake_cpu(context: &mut Context) {
+    if context.running {
+        return;
+    }
+    let current = crate::cpu_id();
+
+    let previous = context.cpu_id.filter(|&cpu_id| {
+        context.sched_affinity.contains(cpu_id) && IDLE_CPUS.contains_now(cpu_id)
+    });
+    let idle = previous.or_else(|| {
+        context
+            .sched_affinity
+            .iter_mut()
+            .find(|&cpu_id| IDLE_CPUS.contains_now(cpu_id))
+    });
+    if let Some(cpu_id) = idle {
+        // An idle CPU waking a context reschedules by itself once done.
+        if cpu_id != current {
+            ipi_single(IpiKind::Wakeup, cpu_id);
+        }
+        return;
+    }
+
+    let rank = context.sched.rank();
+    if rank == 0 {
+        return;
+    }
+    let cpu_count = crate::cpu_count();
+    let preempted = context
+        .sched_affinity
+        .iter_mut()
+        .take_while(|cpu_id| cpu_id.get() < cpu_count)
+        .map(|cpu_id| {
+            (
+                cpu_id,
+                RUNNING_RANKS[cpu_id.get() as usize].load(Ordering::Relaxed),
+            )
+        })
+        .filter(|&(_, running)| running < rank)
+        .min_by_key(|&(_, running)| running);
+    match preempted {
+        // Taken once interrupts are enabled, when returning to userspace.
+        Some((cpu_id, _)) if cpu_id == current => ipi(IpiKind::Switch, IpiTarget::Current),
+        Some((cpu_id, _)) => ipi_single(IpiKind::Switch, cpu_id),
+        None => (),
+    }
+}
+
+/// The virtual runtime of the last context picked, that contexts waking up are brought up to.
+/// Only changed with the context switch lock held.
+static MIN_VRUNTIME: AtomicU64 = AtomicU64::new(0);
+
+/// The CPU holding the context switch lock, for the paravirtual yield hint.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+static SWITCH_LOCK_OWNER: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+/// Attempts to take the context switch lock between hints to the hypervisor that the CPU holding
+/// it should run.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const SPINS_PER_YIELD: usize = 1024;
+
+/// The order contexts are picked in, the greatest first.
+#[derive(Clone, Copy, Eq, Ord, PartialEq, PartialOrd)]
+struct SwitchKey {
+    rank: u8,
+    irq_boosted: bool,
+    /// Only compared between time-shared contexts without deferred IRQs.
+    vruntime: Reverse<u64>,
+}
+impl SwitchKey {
+    fn of(context: &Context, min_vruntime: u64) -> Self {
+        let rank = context.sched.rank();
+        let irq_boosted = context.irq_boost > 0;
+        let vruntime = if rank == 0 && !irq_boosted {
+            context.vruntime.max(min_vruntime)
+        } else {
+            0
+        };
+        Self {
+            rank,
+            irq_boosted,
+            vruntime: Reverse(vruntime),
+        }
+    }
+}
+
 struct SwitchResultInner {
     _prev_guard: ArcRwSpinlockWriteGuard<Context>,
     _next_guard: ArcRwSpinlockWriteGuard<Context>,
 }
 
 pub fn tick() {
-    let ticks_cell = &PercpuBlock::current().switch_internals.pit_ticks;
+    let switch_internals = &PercpuBlock::current().switch_internals;
+    let ticks_cell = &switch_internals.pit_ticks;
+    switch_internals
+        .total_ticks
+        .set(switch_internals.total_ticks.get().wrapping_add(1));
 
     let new_ticks = ticks_cell.get() + 1;
     ticks_cell.set(new_ticks);
 
-    // Switch after 3 ticks (about 6.75 ms)
-    if new_ticks >= 3 {
+    PercpuBlock::current().frequency.sample();
+    periodic();
+
+    // An idle CPU has nothing to preempt, and is sent an IPI once it has a context to run.
+    if IDLE_CPUS.contains_now(crate::cpu_id()) {
+        return;
+    }
+
+    // Switch after the time slice of the current context, by default 3 ticks (about 6.75 ms)
+    let timeslice = match switch_internals.timeslice.get() {
+        0 => usize::from(DEFAULT_TIMESLICE),
+        ticks => ticks,
+    };
+    if new_ticks >= timeslice {
         match switch() {
             SwitchResult::Switched { signal: true } => {
                 crate::context::signal::signal_handler();
@@ -85,6 +210,13 @@ pub fn tick() {
     }
 }
 
+/// The periodic work of the scheduler tick, each part of which limits how often it runs.
+pub fn periodic() {
+    super::loadavg::tick();
+    super::lockup::tick();
+    crate::scheme::debug::log_tick();
+}
+
 pub unsafe extern "C" fn switch_finish_hook() {
     if let Some(switch_result) = PercpuBlock::current().switch_internals.switch_result.take() {
         drop(switch_result);
@@ -92,6 +224,8 @@ pub unsafe extern "C" fn switch_finish_hook() {
         // TODO: unreachable_unchecked()?
         crate::arch::stop::emergency_reset();
     }
+    #[cfg(feature = "lock_debug")]
+    crate::sync::lock_debug::CONTEXT_SWITCH.mark_released();
     arch::CONTEXT_SWITCH_LOCK.store(false, Ordering::SeqCst);
     crate::percpu::switch_arch_hook();
 }
@@ -111,17 +245,43 @@ pub fn switch() -> SwitchResult {
     //set PIT Interrupt counter to 0, giving each process same amount of PIT ticks
     percpu.switch_internals.pit_ticks.set(0);
 
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let mut spins = 0_usize;
+    let mut relax = || {
+        interrupt::pause();
+        percpu.maybe_handle_tlb_shootdown();
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            spins += 1;
+            if spins % SPINS_PER_YIELD == 0 {
+                let owner = LogicalCpuId::new(SWITCH_LOCK_OWNER.load(Ordering::Relaxed));
+                crate::device::paravirt::yield_to(owner);
+            }
+        }
+    };
+
     // Set the global lock to avoid the unsafe operations below from causing issues
     // TODO: Better memory orderings?
+    #[cfg(feature = "lock_debug")]
+    crate::sync::lock_debug::CONTEXT_SWITCH.acquire_unguarded(
+        || {
+            arch::CONTEXT_SWITCH_LOCK
+                .compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+        },
+        relax,
+    );
+    #[cfg(not(feature = "lock_debug"))]
     while arch::CONTEXT_SWITCH_LOCK
         .compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed)
         .is_err()
     {
-        interrupt::pause();
-        percpu.maybe_handle_tlb_shootdown();
+        relax();
     }
 
     let cpu_id = crate::cpu_id();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    SWITCH_LOCK_OWNER.store(cpu_id.get(), Ordering::Relaxed);
     let switch_time = crate::time::monotonic();
 
     let mut switch_context_opt = None;
@@ -132,12 +292,14 @@ pub fn switch() -> SwitchResult {
         let prev_context_lock = contexts
             .current()
             .expect("context::switch: not inside of context");
-        let prev_context_guard = prev_context_lock.write_arc();
+        let mut prev_context_guard = prev_context_lock.write_arc();
 
         let idle_id = percpu.switch_internals.idle_id();
-        let mut skip_idle = true;
 
-        // Locate next context
+        // Locate the runnable context of the highest rank, then handling deferred IRQs, then with
+        // the lowest virtual runtime, going round-robin among equal ones...
+        let mut best: Option<(ArcRwSpinlockWriteGuard<Context>, bool, SwitchKey)> = None;
+        let min_vruntime = MIN_VRUNTIME.load(Ordering::Relaxed);
         for (pid, next_context_lock) in contexts
             // Include all contexts with IDs greater than the current...
             .range((Bound::Excluded(prev_context_guard.id), Bound::Unbounded))
@@ -146,16 +308,9 @@ pub fn switch() -> SwitchResult {
                     // ... and all contexts with IDs less than the current...
                     .range((Bound::Unbounded, Bound::Excluded(prev_context_guard.id))),
             )
-            .chain(
-                contexts
-                    // ... and finally the idle ID
-                    .range((Bound::Included(idle_id), Bound::Included(idle_id))),
-            )
         // ... but not the current context, which is already locked
         {
-            if pid == &idle_id && skip_idle {
-                // Skip idle process the first time it shows up
-                skip_idle = false;
+            if *pid == idle_id {
                 continue;
             }
 
@@ -163,17 +318,61 @@ pub fn switch() -> SwitchResult {
             let mut next_context_guard = next_context_lock.write_arc();
 
             // Update state of next context and check if runnable
-            if let UpdateResult::CanSwitch { signal } = unsafe { update_runnable(&mut *next_context_guard, cpu_id) } {
-                // Store locks for previous and next context
-                switch_context_opt = Some((prev_context_guard, next_context_guard));
-                percpu.switch_internals.switch_signal.set(signal);
-                break;
-            } else {
-                continue;
+            if let UpdateResult::CanSwitch { signal } =
+                unsafe { update_runnable(&mut *next_context_guard, cpu_id) }
+            {
+                let key = SwitchKey::of(&next_context_guard, min_vruntime);
+                if best
+                    .as_ref()
+                    .map_or(true, |(_, _, best_key)| key > *best_key)
+                {
+                    best = Some((next_context_guard, signal, key));
+                }
             }
         }
+
+        // ... and finally the idle context, unless it is the current one.
+        if best.is_none() && prev_context_guard.id != idle_id {
+            if let Some(next_context_lock) = contexts.get(idle_id) {
+                let mut next_context_guard = next_context_lock.write_arc();
+                if let UpdateResult::CanSwitch { signal } =
+                    unsafe { update_runnable(&mut *next_context_guard, cpu_id) }
+                {
+                    let key = SwitchKey::of(&next_context_guard, min_vruntime);
+                    best = Some((next_context_guard, signal, key));
+                }
+            }
+        }
+
+        // A real-time context keeps running unless a higher rank is runnable.
+        let prev_rank = prev_context_guard.sched.rank();
+        let keep_prev = prev_rank > 0
+            && prev_context_guard.status.is_runnable()
+            && !prev_context_guard.ptrace_stop
+            && prev_context_guard.sched_affinity.contains(cpu_id)
+            && best
+                .as_ref()
+                .map_or(false, |(_, _, key)| key.rank < prev_rank);
+
+        if let Some((mut next_context_guard, signal, key)) = best.filter(|_| !keep_prev) {
+            if next_context_guard.id != idle_id && key.rank == 0 {
+                next_context_guard.vruntime = key.vruntime.0;
+                MIN_VRUNTIME.store(key.vruntime.0, Ordering::Relaxed);
+            }
+            // Store locks for previous and next context
+            switch_context_opt = Some((prev_context_guard, next_context_guard));
+            percpu.switch_internals.switch_signal.set(signal);
+        }
     };
 
+    super::lockup::scheduled(
+        cpu_id,
+        switch_context_opt
+            .as_ref()
+            .map_or(percpu.switch_internals.context_id(), |(_, next)| next.id),
+        switch_time,
+    );
+
     // Switch process states, TSS stack pointer, and store new context ID
     if let Some((mut prev_context_guard, mut next_context_guard)) = switch_context_opt {
         // TODO: Update timestamps in switch_to
@@ -181,16 +380,51 @@ pub fn switch() -> SwitchResult {
         // Set old context as not running and update CPU time
         let prev_context = &mut *prev_context_guard;
         prev_context.running = false;
-        prev_context.cpu_time += switch_time.saturating_sub(prev_context.switch_time);
+        let slice_time = switch_time.saturating_sub(prev_context.switch_time);
+        prev_context.cpu_time += slice_time;
+        prev_context.vruntime = prev_context
+            .vruntime
+            .saturating_add(prev_context.sched.vruntime_delta(slice_time));
+        let next_mode = if next_context_guard.id == percpu.switch_internals.idle_id() {
+            CpuMode::Idle
+        } else if next_context_guard.inside_syscall {
+            CpuMode::System
+        } else {
+            CpuMode::User
+        };
+        let (system_time, irq_time) = super::cputime::switch(cpu_id, switch_time, next_mode);
+        prev_context.kernel_time += system_time;
+        prev_context.irq_time += irq_time;
+        let user_time = slice_time.saturating_sub(system_time + irq_time);
+        super::timer::expire_cpu_time(prev_context, slice_time, user_time);
 
         // Set new context as running and set switch time
         let next_context = &mut *next_context_guard;
         next_context.running = true;
         next_context.cpu_id = Some(cpu_id);
         next_context.switch_time = switch_time;
+        next_context.switch_count += 1;
 
         let percpu = PercpuBlock::current();
+        if next_context.id == percpu.switch_internals.idle_id() {
+            IDLE_CPUS.atomic_set(cpu_id);
+            super::tickless::enter_idle(cpu_id);
+        } else if IDLE_CPUS.contains_now(cpu_id) {
+            IDLE_CPUS.atomic_clear(cpu_id);
+            super::tickless::exit_idle(cpu_id);
+        }
+        RUNNING_RANKS[cpu_id.get() as usize].store(next_context.sched.rank(), Ordering::Relaxed);
+        // Left for another CPU to pick up, as it may no longer run on this one.
+        if prev_context_guard.status.is_runnable()
+            && !prev_context_guard.sched_affinity.contains(cpu_id)
+        {
+            wake_cpu(&mut prev_context_guard);
+        }
         percpu.switch_internals.context_id.set(next_context.id);
+        percpu
+            .switch_internals
+            .timeslice
+            .set(next_context.sched.preemption_ticks());
 
         // FIXME set th switch result in arch::switch_to instead
         let prev_context = unsafe {
@@ -239,6 +473,8 @@ pub fn switch() -> SwitchResult {
         SwitchResult::Switched { signal: new_percpu.switch_internals.switch_signal.get() }
     } else {
         // No target was found, unset global lock and return
+        #[cfg(feature = "lock_debug")]
+        crate::sync::lock_debug::CONTEXT_SWITCH.mark_released();
         arch::CONTEXT_SWITCH_LOCK.store(false, Ordering::SeqCst);
 
         SwitchResult::AllContextsIdle
@@ -249,6 +485,10 @@ pub fn switch() -> SwitchResult {
 pub struct ContextSwitchPercpu {
     switch_result: Cell<Option<SwitchResultInner>>,
     pit_ticks: Cell<usize>,
+    /// Ticks received by this CPU since it was started, watched for hard lockups.
+    total_ticks: Cell<usize>,
+    /// Time slice of the currently running context, in ticks.
+    timeslice: Cell<usize>,
 
     /// Unique ID of the currently running context.
     context_id: Cell<ContextId>,
@@ -258,6 +498,9 @@ pub struct ContextSwitchPercpu {
     switch_signal: Cell<bool>,
 }
 impl ContextSwitchPercpu {
+    pub fn total_ticks(&self) -> usize {
+        self.total_ticks.get()
+    }
     pub fn context_id(&self) -> ContextId {
         self.context_id.get()
     }
@@ -0,0 +1,92 @@
+//! Tickless idle, which stops the periodic scheduler tick of CPUs running their idle context.
+//!
+//! An idle CPU has nothing to preempt, and is sent an IPI once a context it may run becomes
+//! runnable, so it only needs waking for its next timeout. Timeouts, including the `wake` time of
+//! sleeping contexts, program the one-shot high-resolution timer of their CPU for the earliest
+//! deadline, or are otherwise expired by the tick of the BSP.
+//!
+//! The tick of each CPU is stopped where the architecture allows, which is for every AP. Once all
+//! CPUs are idle, the tick of the BSP is stopped as well if it neither keeps the clock nor is
+//! needed for timeouts, and the last CPU to go idle wakes up each [`PERIODIC_INTERVAL`] to do the
+//! periodic work of the tick, such as sampling load averages and checking for hung contexts. Ticks
+//! are restarted as soon as a CPU switches to another context.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::{
+    cpu_set::{LogicalCpuId, LogicalCpuSet},
+    time::{self, NANOS_PER_SEC},
+};
+
+use super::{switch, timeout};
+
+/// How often the periodic work of the tick is done while every tick is stopped.
+const PERIODIC_INTERVAL: u128 = NANOS_PER_SEC;
+
+/// CPUs whose own tick is stopped.
+static STOPPED_CPUS: LogicalCpuSet = LogicalCpuSet::empty();
+/// Whether the tick of every CPU is stopped, including that of the BSP.
+static ALL_STOPPED: AtomicBool = AtomicBool::new(false);
+/// The CPU doing the periodic work of the tick while every tick is stopped.
+static PERIODIC_CPU: AtomicU32 = AtomicU32::new(0);
+
+/// Whether the tick of `cpu_id` is stopped.
+pub fn is_stopped(cpu_id: LogicalCpuId) -> bool {
+    ALL_STOPPED.load(Ordering::Relaxed) || STOPPED_CPUS.contains_now(cpu_id)
+}
+
+/// Called by the scheduler with the switch lock held, once `cpu_id` switches to its idle context.
+pub fn enter_idle(cpu_id: LogicalCpuId) {
+    if unsafe { crate::arch::time::tick_stop_cpu() } {
+        STOPPED_CPUS.atomic_set(cpu_id);
+    }
+
+    let all_idle = (0..crate::cpu_count())
+        .map(LogicalCpuId::new)
+        .all(switch::is_idle);
+    if all_idle && unsafe { crate::arch::time::tick_stop_all() } {
+        PERIODIC_CPU.store(cpu_id.get(), Ordering::Relaxed);
+        ALL_STOPPED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Called by the scheduler with the switch lock held, once `cpu_id` switches from its idle
+/// context to another one.
+pub fn exit_idle(cpu_id: LogicalCpuId) {
+    if ALL_STOPPED.swap(false, Ordering::Relaxed) {
+        unsafe { crate::arch::time::tick_restart_all() };
+    }
+    if STOPPED_CPUS.contains_now(cpu_id) {
+        STOPPED_CPUS.atomic_clear(cpu_id);
+        unsafe { crate::arch::time::tick_restart_cpu() };
+    }
+}
+
+/// Called by the idle loop before halting the CPU until the next interrupt.
+pub fn idle() {
+    let cpu_id = crate::cpu_id().get();
+    if !ALL_STOPPED.load(Ordering::Relaxed) || PERIODIC_CPU.load(Ordering::Relaxed) != cpu_id {
+        return;
+    }
+
+    switch::periodic();
+    timeout::arm_before(time::monotonic() + PERIODIC_INTERVAL);
+}
+
+/// Send the tick of the BSP to the other CPUs, except those with their tick stopped.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn forward_tick() {
+    use crate::ipi::{ipi, ipi_single, IpiKind, IpiTarget};
+
+    let current = crate::cpu_id();
+    let ticking = (0..crate::cpu_count())
+        .map(LogicalCpuId::new)
+        .filter(|&cpu_id| cpu_id != current && !STOPPED_CPUS.contains_now(cpu_id));
+    if ticking.clone().count() + 1 == crate::cpu_count() as usize {
+        ipi(IpiKind::Pit, IpiTarget::Other);
+        return;
+    }
+    for cpu_id in ticking {
+        ipi_single(IpiKind::Pit, cpu_id);
+    }
+}
@@ -1,7 +1,20 @@
-use alloc::collections::VecDeque;
-use spin::{Mutex, MutexGuard, Once};
+//! Timeouts of the `time:` scheme.
+//!
+//! Each CPU keeps its own queue ordered by deadline, and programs its high-resolution timer for
+//! the earliest one, so that timeouts fire within microseconds of their deadline. Without a
+//! high-resolution timer, the queues are only checked on the scheduler tick.
 
+use alloc::{collections::BTreeMap, sync::Weak, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use super::{
+    memory::AddrSpaceWrapper,
+    timer::{self, Expiry},
+    ContextId,
+};
 use crate::{
+    cpu_set::MAX_CPU_COUNT,
     event,
     scheme::SchemeId,
     syscall::{
@@ -11,66 +24,194 @@ use crate::{
     time,
 };
 
+#[derive(Debug)]
+enum Target {
+    /// Trigger a read event on a scheme file.
+    Event {
+        scheme_id: SchemeId,
+        event_id: usize,
+    },
+    /// Expire a signal timer of a process.
+    Timer {
+        process: Weak<AddrSpaceWrapper>,
+        id: usize,
+    },
+    /// Unblock a context whose `wake` time has been reached.
+    Wake { owner: ContextId },
+}
+
 #[derive(Debug)]
 struct Timeout {
-    pub scheme_id: SchemeId,
-    pub event_id: usize,
+    pub target: Target,
     pub clock: usize,
     pub time: u128,
 }
 
-type Registry = VecDeque<Timeout>;
+/// Timeouts keyed by their monotonic deadline, and a sequence number to keep duplicates apart.
+type Registry = BTreeMap<(u128, u64), Timeout>;
 
-static REGISTRY: Once<Mutex<Registry>> = Once::new();
+const EMPTY: Mutex<Registry> = Mutex::new(BTreeMap::new());
+static QUEUES: [Mutex<Registry>; MAX_CPU_COUNT as usize] = [EMPTY; MAX_CPU_COUNT as usize];
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
 
-/// Initialize registry, called if needed
-fn init_registry() -> Mutex<Registry> {
-    Mutex::new(Registry::new())
+/// Resolution in nanoseconds with which timeouts fire.
+pub fn resolution() -> u128 {
+    crate::arch::time::hrtimer_resolution().unwrap_or_else(crate::arch::time::tick_period)
 }
 
-/// Get the global timeouts list
-fn registry() -> MutexGuard<'static, Registry> {
-    REGISTRY.call_once(init_registry).lock()
+/// The monotonic time at which `time` on `clock` is expected to be reached.
+fn monotonic_deadline(clock: usize, time: u128) -> u128 {
+    // Realtime deadlines are queued at their current monotonic equivalent, and checked again
+    // against the realtime clock when expiring, in case it was set in the meantime.
+    match clock {
+        CLOCK_REALTIME => time.saturating_sub(*time::START.lock()),
+        _ => time,
+    }
+}
+
+fn insert(target: Target, clock: usize, time: u128) {
+    let deadline = monotonic_deadline(clock, time);
+    let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+
+    let mut queue = QUEUES[crate::cpu_id().get() as usize].lock();
+    queue.insert(
+        (deadline, seq),
+        Timeout {
+            target,
+            clock,
+            time,
+        },
+    );
+    if queue.first_key_value().map(|(key, _)| *key) == Some((deadline, seq)) {
+        unsafe { crate::arch::time::hrtimer_arm(deadline) };
+    }
 }
 
 pub fn register(scheme_id: SchemeId, event_id: usize, clock: usize, time: TimeSpec) {
-    let mut registry = registry();
-    registry.push_back(Timeout {
-        scheme_id,
-        event_id,
+    let time = (time.tv_sec as u128 * time::NANOS_PER_SEC) + (time.tv_nsec as u128);
+    insert(
+        Target::Event {
+            scheme_id,
+            event_id,
+        },
         clock,
-        time: (time.tv_sec as u128 * time::NANOS_PER_SEC) + (time.tv_nsec as u128),
-    });
+        time,
+    );
 }
 
-pub fn trigger() {
-    let mut registry = registry();
+/// Expire the signal timer `id` of `process` at `time` on `clock`. The timer itself decides
+/// whether it has expired, so that stale timeouts of rearmed timers are harmless.
+pub fn register_timer(process: Weak<AddrSpaceWrapper>, id: usize, clock: usize, time: u128) {
+    insert(Target::Timer { process, id }, clock, time);
+}
+
+/// Unblock `owner` at the monotonic time `time`, if its `wake` time has been reached by then.
+/// Without this, sleeping contexts are only woken on the scheduler tick.
+pub fn register_wake(owner: ContextId, time: u128) {
+    insert(Target::Wake { owner }, CLOCK_MONOTONIC, time);
+}
+
+/// Unblock `owner` if its wake time has been reached. Returns false if the context could not be
+/// locked.
+fn wake(owner: ContextId, now: u128) -> bool {
+    let Some(contexts) = super::try_contexts() else {
+        return false;
+    };
+    let Some(context_lock) = contexts.get(owner) else {
+        return true;
+    };
+    let Some(mut context) = context_lock.try_write() else {
+        return false;
+    };
+    if context.wake.map_or(false, |wake| now >= wake) {
+        context.wake = None;
+        context.unblock();
+    }
+    true
+}
 
+/// Remove and trigger the expired timeouts of `queue`.
+fn expire(queue: &mut Registry) {
     let mono = time::monotonic();
     let real = time::realtime();
 
-    let mut i = 0;
-    while i < registry.len() {
-        let trigger = match registry[i].clock {
-            CLOCK_MONOTONIC => {
-                let time = registry[i].time;
-                mono >= time
-            }
-            CLOCK_REALTIME => {
-                let time = registry[i].time;
-                real >= time
-            }
+    let mut expired = Vec::new();
+    let mut requeue = Vec::new();
+    for (&key, timeout) in queue.iter() {
+        let trigger = match timeout.clock {
+            CLOCK_MONOTONIC => mono >= timeout.time,
+            CLOCK_REALTIME => real >= timeout.time,
             clock => {
-                println!("timeout::trigger: unknown clock {}", clock);
+                log::warn!("timeout::expire: unknown clock {}", clock);
                 true
             }
         };
-
         if trigger {
-            let timeout = registry.remove(i).unwrap();
-            event::trigger(timeout.scheme_id, timeout.event_id, EVENT_READ);
-        } else {
-            i += 1;
+            expired.push(key);
+        } else if key.0 <= mono {
+            // The realtime clock was set back, so the deadline has to be moved as well.
+            requeue.push(key);
+        }
+    }
+
+    for key in expired {
+        let Some(mut timeout) = queue.remove(&key) else {
+            continue;
+        };
+        match timeout.target {
+            Target::Event {
+                scheme_id,
+                event_id,
+            } => event::trigger(scheme_id, event_id, EVENT_READ),
+            Target::Timer { ref process, id } => match timer::expire_timeout(process, id) {
+                Some(Expiry::Rearmed(time)) => {
+                    timeout.time = time;
+                    let deadline = monotonic_deadline(timeout.clock, time);
+                    queue.insert((deadline, key.1), timeout);
+                }
+                Some(Expiry::Idle | Expiry::Disarmed) => (),
+                // The process is locked, so try again on the next tick.
+                None => {
+                    queue.insert((mono + crate::arch::time::tick_period(), key.1), timeout);
+                }
+            },
+            Target::Wake { owner } => {
+                if !wake(owner, mono) {
+                    queue.insert((mono + crate::arch::time::tick_period(), key.1), timeout);
+                }
+            }
+        }
+    }
+    for key in requeue {
+        if let Some(timeout) = queue.remove(&key) {
+            queue.insert((mono + (timeout.time - real), key.1), timeout);
         }
     }
 }
+
+/// Called on the scheduler tick of the BSP, to expire timeouts of all CPUs.
+pub fn trigger() {
+    for queue in QUEUES.iter().take(crate::cpu_count() as usize) {
+        expire(&mut queue.lock());
+    }
+}
+
+/// Called by the high-resolution timer interrupt, to expire the timeouts of the current CPU and
+/// program the timer for the next one.
+pub fn trigger_hrtimer() {
+    let mut queue = QUEUES[crate::cpu_id().get() as usize].lock();
+    expire(&mut queue);
+    if let Some(&(deadline, _)) = queue.first_key_value().map(|(key, _)| key) {
+        unsafe { crate::arch::time::hrtimer_arm(deadline) };
+    }
+}
+
+/// Program the high-resolution timer of the current CPU for its next timeout, or for the monotonic
+/// time `limit` if that is earlier, to wake up an idle CPU by then.
+pub fn arm_before(limit: u128) {
+    let queue = QUEUES[crate::cpu_id().get() as usize].lock();
+    let deadline = queue
+        .first_key_value()
+        .map_or(limit, |(&(deadline, _), _)| deadline.min(limit));
+    unsafe { crate::arch::time::hrtimer_arm(deadline) };
+}
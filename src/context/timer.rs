@@ -0,0 +1,308 @@
+//! Per-process timers delivering a signal on expiry, backing `timer_create`, and `setitimer`
+//! with the `virtual` and `prof` clocks.
+//!
+//! A timer is created by opening `itimer:timer/<clock>`, and belongs to the process of the
+//! opening context. Its settings are exchanged as text, one `<key> <value>` line each, in the same
+//! way as the scheduling parameters.
+//!
+//! Contexts are not grouped into processes, so the timers of a process are kept with its address
+//! space, which its threads share. As with POSIX timers, they are thus deleted when the process
+//! executes a new image, and not inherited by forks.
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::{Mutex, MutexGuard};
+
+use super::{
+    memory::AddrSpaceWrapper,
+    signal::{SignalInfo, SI_TIMER},
+    Context,
+};
+use crate::{
+    syscall::{
+        error::{Error, Result, EINVAL},
+        flag::{CLOCK_MONOTONIC, CLOCK_REALTIME, SIGALRM, SIGPROF, SIGVTALRM},
+    },
+    time,
+};
+
+/// The signal timers of a process, and the CPU time its contexts have used for them.
+#[derive(Debug, Default)]
+pub struct ProcessTimers {
+    /// Timers keyed by the `itimer:` handle that created them.
+    timers: Mutex<BTreeMap<usize, SignalTimer>>,
+    /// CPU time of the contexts of the process in nanoseconds, as of their last switch.
+    cpu_time: AtomicU64,
+    /// Part of `cpu_time` spent outside syscalls and interrupt handlers.
+    user_time: AtomicU64,
+}
+
+impl ProcessTimers {
+    pub fn lock(&self) -> MutexGuard<'_, BTreeMap<usize, SignalTimer>> {
+        self.timers.lock()
+    }
+    /// Account a time slice of a context of the process, of which `user` nanoseconds were spent
+    /// in userspace.
+    pub fn add_cpu_time(&self, slice: u128, user: u128) {
+        let to_u64 = |time: u128| u64::try_from(time).unwrap_or(u64::MAX);
+        self.cpu_time.fetch_add(to_u64(slice), Ordering::Relaxed);
+        self.user_time.fetch_add(to_u64(user), Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimerClock {
+    Realtime,
+    Monotonic,
+    /// CPU time used by the process.
+    Cputime,
+    /// User time of the process, for `ITIMER_VIRTUAL`.
+    Virtual,
+    /// User and system time of the process, for `ITIMER_PROF`.
+    Prof,
+}
+impl TimerClock {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Realtime => "realtime",
+            Self::Monotonic => "monotonic",
+            Self::Cputime => "cputime",
+            Self::Virtual => "virtual",
+            Self::Prof => "prof",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            Self::Realtime,
+            Self::Monotonic,
+            Self::Cputime,
+            Self::Virtual,
+            Self::Prof,
+        ]
+        .into_iter()
+        .find(|clock| clock.name() == name)
+    }
+    /// The signal raised by new timers on the clock, matching `setitimer`.
+    pub fn default_signal(self) -> usize {
+        match self {
+            Self::Realtime | Self::Monotonic | Self::Cputime => SIGALRM,
+            Self::Virtual => SIGVTALRM,
+            Self::Prof => SIGPROF,
+        }
+    }
+    /// The `CLOCK_*` value to register timeouts with, unless a CPU time clock.
+    pub fn timeout_clock(self) -> Option<usize> {
+        match self {
+            Self::Realtime => Some(CLOCK_REALTIME),
+            Self::Monotonic => Some(CLOCK_MONOTONIC),
+            Self::Cputime | Self::Virtual | Self::Prof => None,
+        }
+    }
+    /// The current time of the clock for the process of `timers`.
+    pub fn now(self, timers: &ProcessTimers) -> u128 {
+        match self {
+            Self::Realtime => time::realtime(),
+            Self::Monotonic => time::monotonic(),
+            Self::Cputime | Self::Prof => timers.cpu_time.load(Ordering::Relaxed).into(),
+            Self::Virtual => timers.user_time.load(Ordering::Relaxed).into(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SignalTimer {
+    pub clock: TimerClock,
+    pub signal: usize,
+    /// The `sigval` of the timer, delivered with its signal.
+    pub value: usize,
+    /// Period in nanoseconds, or 0 for a one-shot timer.
+    pub interval: u128,
+    /// Next expiry on the clock of the timer, if armed.
+    pub deadline: Option<u128>,
+    /// Expirations since the signal was last raised, while it was still pending, as returned by
+    /// `timer_getoverrun`.
+    pub overrun: u64,
+}
+
+/// The result of [`SignalTimer::expire`].
+pub enum Expiry {
+    /// The timer is not expired, or was disarmed.
+    Idle,
+    /// The timer expired, and is rearmed for the given deadline.
+    Rearmed(u128),
+    /// The timer expired, and is now disarmed.
+    Disarmed,
+}
+
+impl SignalTimer {
+    pub fn new(clock: TimerClock) -> Self {
+        Self {
+            clock,
+            signal: clock.default_signal(),
+            value: 0,
+            interval: 0,
+            deadline: None,
+            overrun: 0,
+        }
+    }
+
+    pub fn format(&self, now: u128) -> String {
+        let remaining = self
+            .deadline
+            .map_or(0, |deadline| deadline.saturating_sub(now).max(1));
+        let mut string = String::new();
+        let _ = writeln!(string, "clock {}", self.clock.name());
+        let _ = writeln!(string, "signal {}", self.signal);
+        let _ = writeln!(string, "value {}", self.value);
+        let _ = writeln!(string, "interval {}", self.interval);
+        let _ = writeln!(string, "remaining {}", remaining);
+        let _ = writeln!(string, "overrun {}", self.overrun);
+        string
+    }
+    /// Apply `<key> <value>` lines, where `remaining` arms the timer relative to `now`, or disarms
+    /// it if 0, and `deadline` arms it at an absolute time. All lines are validated before any
+    /// setting is changed. Returns the new deadline, if armed.
+    pub fn parse_and_set(&mut self, text: &str, now: u128) -> Result<Option<u128>> {
+        let mut new = *self;
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (key, value) = line.split_once(' ').ok_or(Error::new(EINVAL))?;
+            let value = value.trim();
+            let parse_ns = || value.parse::<u128>().map_err(|_| Error::new(EINVAL));
+            match key {
+                "signal" => new.signal = value.parse().map_err(|_| Error::new(EINVAL))?,
+                "value" => new.value = value.parse().map_err(|_| Error::new(EINVAL))?,
+                "interval" => new.interval = parse_ns()?,
+                "remaining" => {
+                    new.deadline = match parse_ns()? {
+                        0 => None,
+                        remaining => Some(now.saturating_add(remaining)),
+                    }
+                }
+                "deadline" => new.deadline = Some(parse_ns()?),
+                _ => return Err(Error::new(EINVAL)),
+            }
+        }
+
+        if new.signal == 0 || new.signal > 64 {
+            return Err(Error::new(EINVAL));
+        }
+        if new.deadline != self.deadline {
+            new.overrun = 0;
+        }
+
+        *self = new;
+        Ok(self.deadline)
+    }
+
+    /// Raise the signal of the timer in `context`, a context of its process, if it expired by
+    /// `now`, counting an overrun instead if the signal is still pending.
+    pub fn expire(&mut self, context: &mut Context, now: u128) -> Expiry {
+        let Some(deadline) = self.deadline else {
+            return Expiry::Idle;
+        };
+        if now < deadline {
+            return Expiry::Idle;
+        }
+
+        let bit = 1_u64 << (self.signal - 1);
+        if context.sig.pending & bit != 0 {
+            self.overrun = self.overrun.saturating_add(1);
+        } else {
+            // Not queued again while pending, so it cannot exceed the limit of queued signals.
+            let _ = context.send_signal(SignalInfo {
+                signo: self.signal as u32,
+                code: SI_TIMER,
+                value: self.value as u64,
+                ..SignalInfo::default()
+            });
+            self.overrun = 0;
+        }
+
+        if self.interval == 0 {
+            self.deadline = None;
+            return Expiry::Disarmed;
+        }
+        // Expirations missed entirely also count as overruns.
+        let missed = (now - deadline) / self.interval;
+        self.overrun = self
+            .overrun
+            .saturating_add(u64::try_from(missed).unwrap_or(u64::MAX));
+        let next = deadline + (missed + 1) * self.interval;
+        self.deadline = Some(next);
+        Expiry::Rearmed(next)
+    }
+}
+
+/// Expire the timer `id` of `process`, for a timeout registered on its behalf. Returns `None` if
+/// the process could not be locked, in which case the caller should retry later.
+pub fn expire_timeout(process: &Weak<AddrSpaceWrapper>, id: usize) -> Option<Expiry> {
+    let Some(process) = process.upgrade() else {
+        return Some(Expiry::Idle);
+    };
+    let mut timers = process.timers.timers.try_lock()?;
+    let Some(timer) = timers.get_mut(&id) else {
+        return Some(Expiry::Idle);
+    };
+    let now = timer.clock.now(&process.timers);
+    if timer.deadline.map_or(true, |deadline| now < deadline) {
+        return Some(Expiry::Idle);
+    }
+
+    // As for any signal sent to the process, a context not blocking it is preferred.
+    let contexts = super::try_contexts()?;
+    let bit = 1_u64 << (timer.signal - 1);
+    let mut target = None;
+    for (_, context_lock) in contexts.iter() {
+        let context = context_lock.try_read()?;
+        let in_process = context
+            .addr_space
+            .as_ref()
+            .map_or(false, |addr_space| Arc::ptr_eq(addr_space, &process));
+        if in_process && (target.is_none() || context.sig.procmask & bit == 0) {
+            target = Some((context.id, Arc::clone(context_lock)));
+            if context.sig.procmask & bit == 0 {
+                break;
+            }
+        }
+    }
+    drop(contexts);
+    let Some((target_id, target_lock)) = target else {
+        return Some(Expiry::Idle);
+    };
+
+    let expiry = timer.expire(&mut target_lock.try_write()?, now);
+    let signal = timer.signal;
+    drop(timers);
+
+    if !matches!(expiry, Expiry::Idle) {
+        super::signal_queue::notify(target_id, signal);
+    }
+    Some(expiry)
+}
+
+/// Account the time slice `context` just used on the CPU to its process, of which `user`
+/// nanoseconds were spent in userspace, and expire the CPU time timers of the process in it.
+pub fn expire_cpu_time(context: &mut Context, slice: u128, user: u128) {
+    let Some(process) = context.addr_space.clone() else {
+        return;
+    };
+    process.timers.add_cpu_time(slice, user);
+
+    // Otherwise, they are expired on a later switch.
+    let Some(mut timers) = process.timers.timers.try_lock() else {
+        return;
+    };
+    for timer in timers.values_mut() {
+        if timer.clock.timeout_clock().is_none() {
+            let now = timer.clock.now(&process.timers);
+            timer.expire(context, now);
+        }
+    }
+}
@@ -52,7 +52,8 @@ pub struct LogicalCpuSet([AtomicUsize; SET_WORDS]);
 fn parts(id: LogicalCpuId) -> (usize, u32) {
     ((id.get() / usize::BITS) as usize, id.get() % usize::BITS)
 }
-const MASK_SIZE: usize = SET_WORDS * core::mem::size_of::<usize>();
+/// The size in bytes of the widest mask, with a bit for each CPU there can be.
+pub const MASK_SIZE: usize = SET_WORDS * core::mem::size_of::<usize>();
 
 impl LogicalCpuSet {
     pub const fn empty() -> Self {
@@ -123,6 +124,32 @@ impl ToString for LogicalCpuSet {
 
 pub type RawMask = [usize; SET_WORDS];
 
-pub fn mask_as_bytes(mask: &RawMask) -> &[u8] {
-    unsafe { core::slice::from_raw_parts(mask.as_ptr().cast(), core::mem::size_of::<RawMask>()) }
+/// Write `mask` in the form exchanged with userspace, where CPU `n` is bit `n % 8` of byte `n / 8`,
+/// for as many bytes as `bytes` holds. CPUs which were not started read as unset.
+pub fn mask_to_bytes(mask: &RawMask, bytes: &mut [u8]) {
+    const WORD_BYTES: usize = core::mem::size_of::<usize>();
+
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let word = mask.get(i / WORD_BYTES).copied().unwrap_or(0);
+        *byte = (word >> (i % WORD_BYTES * 8)) as u8 & started_bits(i);
+    }
+}
+/// Read a mask in the form written by [`mask_to_bytes`], of any width. CPUs which were not started
+/// are left out, and `None` is returned if that leaves none.
+pub fn mask_from_bytes(bytes: &[u8]) -> Option<RawMask> {
+    const WORD_BYTES: usize = core::mem::size_of::<usize>();
+
+    let mut mask = [0; SET_WORDS];
+    for (i, &byte) in bytes.iter().enumerate() {
+        let byte = byte & started_bits(i);
+        if byte != 0 {
+            mask[i / WORD_BYTES] |= usize::from(byte) << (i % WORD_BYTES * 8);
+        }
+    }
+    mask.iter().any(|&word| word != 0).then_some(mask)
+}
+/// The bits of byte `i` of a mask which stand for CPUs that were started.
+fn started_bits(i: usize) -> u8 {
+    let started = (crate::cpu_count() as usize).saturating_sub(i * 8).min(8);
+    (0xFF_u16 >> (8 - started)) as u8
 }
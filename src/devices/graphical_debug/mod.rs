@@ -1,4 +1,16 @@
-use core::str;
+//! A text console on the framebuffer provided by the bootloader, for output before a display
+//! driver is started in userspace.
+//!
+//! It is controlled by the `KERNEL_FBCON` boot environment variable:
+//! - `off` does not use the framebuffer at all.
+//! - `boot`, the default, shows kernel initialization and stops before userspace is started.
+//! - `on` keeps the console until the display sink is disabled through `sys:kconfig`, which the
+//!   display driver is expected to do once it takes over. Panics are shown regardless.
+
+use core::{
+    str,
+    sync::atomic::{AtomicBool, Ordering},
+};
 use spin::Mutex;
 
 pub use self::debug::DebugDisplay;
@@ -13,14 +25,16 @@ pub static DEBUG_DISPLAY: Mutex<Option<DebugDisplay>> = Mutex::new(None);
 
 pub static FRAMEBUFFER: Mutex<(usize, usize, usize)> = Mutex::new((0, 0, 0));
 
-pub fn init(env: &[u8]) {
-    println!("Starting graphical debug");
+/// Whether the console is kept past kernel initialization.
+static PERSIST: AtomicBool = AtomicBool::new(false);
 
+pub fn init(env: &[u8]) {
     let mut phys = 0;
     let mut virt = 0;
     let mut width = 0;
     let mut height = 0;
     let mut stride = 0;
+    let mut enabled = true;
 
     //TODO: should errors be reported?
     for line in str::from_utf8(env).unwrap_or("").lines() {
@@ -47,10 +61,24 @@ pub fn init(env: &[u8]) {
         if name == "FRAMEBUFFER_STRIDE" {
             stride = usize::from_str_radix(value, 16).unwrap_or(0);
         }
+
+        if name == "KERNEL_FBCON" {
+            match value {
+                "off" => enabled = false,
+                "boot" => PERSIST.store(false, Ordering::Relaxed),
+                "on" => PERSIST.store(true, Ordering::Relaxed),
+                _ => (),
+            }
+        }
     }
 
     *FRAMEBUFFER.lock() = (phys, virt, stride * height * 4);
 
+    if !enabled {
+        return;
+    }
+    println!("Starting graphical debug");
+
     if phys == 0 || virt == 0 || width == 0 || height == 0 || stride == 0 {
         println!("Framebuffer not found");
         return;
@@ -75,8 +103,14 @@ pub fn init_heap() {
     }
 }
 
+/// Stop the console at the end of kernel initialization, unless it is to persist.
 pub fn fini() {
-    DEBUG_DISPLAY.lock().take();
+    if PERSIST.load(Ordering::Relaxed) && DEBUG_DISPLAY.lock().is_some() {
+        println!("Keeping graphical debug");
+        return;
+    }
 
-    println!("Finished graphical debug");
+    if DEBUG_DISPLAY.lock().take().is_some() {
+        println!("Finished graphical debug");
+    }
 }
@@ -0,0 +1,89 @@
+//! Selection of the serial console from the `console=` boot environment variable.
+//!
+//! The accepted forms follow Linux:
+//! - `console=ttyS<n>[,<baud>]` for the legacy PC serial ports.
+//! - `console=uart[8250],io,<port>[,<baud>]` and `console=uart[8250],mmio32,<address>[,<baud>]`
+//!   for 16550 compatible UARTs.
+//! - `console=pl011,<address>[,<baud>[,<clock>]]` for PL011 UARTs, where the baud rate is only
+//!   programmed if the frequency of the reference clock is given as well.
+//!
+//! Options after the baud rate, such as `n8` in `115200n8`, are ignored. If `console=` is given
+//! more than once, the last one is used. The same forms select the port of the GDB stub, with
+//! `gdbstub=`. On aarch64, only PL011 UARTs are supported, and on x86 only the others.
+
+use core::str;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SerialConsolePort {
+    /// A legacy PC serial port, by index.
+    Tty(u8),
+    /// A 16550 compatible UART at an I/O port.
+    Io(u16),
+    /// A 16550 compatible UART with 32 bit registers at a physical address.
+    Mmio32(usize),
+    /// A PL011 UART at a physical address.
+    Pl011(usize),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SerialConsole {
+    pub port: SerialConsolePort,
+    pub baud: Option<u32>,
+    /// Frequency of the UART reference clock in Hz, where it cannot be assumed.
+    pub clock: Option<u32>,
+}
+
+/// Parse the last `console=` line of the boot environment, if any.
+pub fn parse(env: &[u8]) -> Option<SerialConsole> {
+    let value = str::from_utf8(env)
+        .unwrap_or("")
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("console="))
+        .last()?;
+    parse_value(value)
+}
+
+/// Parse the value of a `console=` line.
+pub fn parse_value(value: &str) -> Option<SerialConsole> {
+    let mut fields = value.split(',');
+    let name = fields.next()?;
+    let port = if let Some(index) = name.strip_prefix("ttyS") {
+        SerialConsolePort::Tty(index.parse().ok()?)
+    } else if name == "uart" || name == "uart8250" {
+        let kind = fields.next()?;
+        let address = parse_number(fields.next()?)?;
+        match kind {
+            "io" => SerialConsolePort::Io(u16::try_from(address).ok()?),
+            "mmio32" => SerialConsolePort::Mmio32(address),
+            _ => return None,
+        }
+    } else if name == "pl011" {
+        SerialConsolePort::Pl011(parse_number(fields.next()?)?)
+    } else {
+        return None;
+    };
+
+    let baud = match fields.next() {
+        Some(options) => {
+            let digits = options
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(options.len());
+            Some(options[..digits].parse().ok().filter(|&baud| baud != 0)?)
+        }
+        None => None,
+    };
+    let clock = match fields.next() {
+        Some(clock) => Some(clock.parse().ok()?),
+        None => None,
+    };
+
+    Some(SerialConsole { port, baud, clock })
+}
+
+/// Parse an address, in hexadecimal if prefixed by `0x`.
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
@@ -22,7 +22,8 @@ bitflags! {
     /// Line status flags
     struct LineStsFlags: u8 {
         const INPUT_FULL = 1;
-        // 1 to 4 unknown
+        // 1 to 3 unknown
+        const BREAK = 1 << 4;
         const OUTPUT_EMPTY = 1 << 5;
         // 6 and 7 unknown
     }
@@ -73,13 +74,19 @@ where
     T::Value: From<u8> + TryInto<u8>,
 {
     pub fn init(&mut self) {
+        self.init_with_baud(115200);
+    }
+
+    /// Initialize the port at `baud`, rounded to a divisor of the usual 1.8432 MHz clock.
+    pub fn init_with_baud(&mut self, baud: u32) {
+        let divisor = (115200 / baud.clamp(1, 115200)) as u16;
         unsafe {
             //TODO: Cleanup
             // FIXME: Fix UB if unaligned
             (&mut *addr_of_mut!(self.int_en)).write(0x00.into());
             (&mut *addr_of_mut!(self.line_ctrl)).write(0x80.into());
-            (&mut *addr_of_mut!(self.data)).write(0x01.into());
-            (&mut *addr_of_mut!(self.int_en)).write(0x00.into());
+            (&mut *addr_of_mut!(self.data)).write((divisor as u8).into());
+            (&mut *addr_of_mut!(self.int_en)).write(((divisor >> 8) as u8).into());
             (&mut *addr_of_mut!(self.line_ctrl)).write(0x03.into());
             (&mut *addr_of_mut!(self.fifo_ctrl)).write(0xC7.into());
             (&mut *addr_of_mut!(self.modem_ctrl)).write(0x0B.into());
@@ -96,12 +103,18 @@ where
     }
 
     pub fn receive(&mut self) -> Option<u8> {
-        if self.line_sts().contains(LineStsFlags::INPUT_FULL) {
-            Some(
-                (unsafe { &*addr_of!(self.data) }.read() & 0xFF.into())
-                    .try_into()
-                    .unwrap_or(0),
-            )
+        self.receive_break().map(|(data, _is_break)| data)
+    }
+
+    /// Like [`Self::receive`], but also report whether the byte marks a break condition on the
+    /// line, in which case it is always zero.
+    pub fn receive_break(&mut self) -> Option<(u8, bool)> {
+        let line_sts = self.line_sts();
+        if line_sts.contains(LineStsFlags::INPUT_FULL) {
+            let data = (unsafe { &*addr_of!(self.data) }.read() & 0xFF.into())
+                .try_into()
+                .unwrap_or(0);
+            Some((data, line_sts.contains(LineStsFlags::BREAK)))
         } else {
             None
         }
@@ -1,7 +1,7 @@
-use alloc::sync::Arc;
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use hashbrown::HashMap;
-use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use spin::{Mutex, Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use crate::{
     context,
@@ -9,17 +9,60 @@ use crate::{
     sync::WaitQueue,
     syscall::{
         data::Event,
-        error::{Error, Result, EBADF, ESRCH},
+        error::{Error, Result, EAGAIN, EBADF, EINVAL, ESRCH, ETIMEDOUT},
+        ext::{EVENT_EDGE, EVENT_HANGUP, EVENT_ONESHOT, NO_TIMEOUT, OVERFLOW_EVENT_ID},
         flag::EventFlags,
         usercopy::UserSliceWo,
     },
+    time,
 };
 
 int_like!(EventQueueId, AtomicEventQueueId, usize, AtomicUsize);
 
+/// Default number of undelivered events a queue holds before dropping further events.
+pub const DEFAULT_QUEUE_LIMIT: usize = 4096;
+/// Upper bound of the queue limit settable with
+/// [`F_SETEVLIMIT`](crate::syscall::ext::F_SETEVLIMIT).
+pub const MAX_QUEUE_LIMIT: usize = 1 << 20;
+
+/// Delivery state of a registration.
+struct RegState {
+    key: RegKey,
+    registration: Registration,
+    mode: usize,
+    /// Flags of queued events not yet read, for [`EVENT_EDGE`].
+    pending: EventFlags,
+    /// Whether an [`EVENT_ONESHOT`] registration has queued its event.
+    disarmed: bool,
+}
+
+impl RegState {
+    /// The part of `flags` to queue an event for, updating the state as if it was queued.
+    fn admit(&mut self, flags: EventFlags) -> EventFlags {
+        if self.disarmed {
+            return EventFlags::empty();
+        }
+        let flags = if self.mode & EVENT_EDGE != 0 {
+            flags & !self.pending
+        } else {
+            flags
+        };
+        self.pending |= flags;
+        if self.mode & EVENT_ONESHOT != 0 && !flags.is_empty() {
+            self.disarmed = true;
+        }
+        flags
+    }
+}
+
 pub struct EventQueue {
     id: EventQueueId,
     queue: WaitQueue<Event>,
+    limit: AtomicUsize,
+    /// Read timeout in milliseconds, or [`NO_TIMEOUT`].
+    timeout: AtomicUsize,
+    /// Registrations, by the id of their events.
+    states: Mutex<BTreeMap<usize, RegState>>,
 }
 
 impl EventQueue {
@@ -27,15 +70,131 @@ impl EventQueue {
         EventQueue {
             id,
             queue: WaitQueue::new(),
+            limit: AtomicUsize::new(DEFAULT_QUEUE_LIMIT),
+            timeout: AtomicUsize::new(NO_TIMEOUT),
+            states: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+    /// Set the event limit. Events already queued beyond a lowered limit are kept.
+    pub fn set_limit(&self, limit: usize) -> Result<()> {
+        if limit == 0 || limit > MAX_QUEUE_LIMIT {
+            return Err(Error::new(EINVAL));
+        }
+        self.limit.store(limit, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn timeout(&self) -> usize {
+        self.timeout.load(Ordering::Relaxed)
+    }
+    pub fn set_timeout(&self, timeout_ms: usize) {
+        self.timeout.store(timeout_ms, Ordering::Relaxed);
+    }
+
+    /// Queue `event`, or count it in an overflow event at the end of the queue if full.
+    fn send(&self, event: Event) {
+        {
+            let mut inner = self.queue.inner.lock();
+            if inner.len() < self.limit() {
+                inner.push_back(event);
+            } else {
+                match inner.back_mut() {
+                    Some(last) if last.id == OVERFLOW_EVENT_ID => {
+                        last.data = last.data.saturating_add(1)
+                    }
+                    _ => inner.push_back(Event {
+                        id: OVERFLOW_EVENT_ID,
+                        flags: EventFlags::empty(),
+                        data: 1,
+                    }),
+                }
+            }
         }
+        self.queue.condition.notify();
     }
 
+    /// Queue an event for the registration `id` with `flags`, unless its delivery mode drops it.
+    fn notify(&self, id: usize, flags: EventFlags, data: usize) {
+        let flags = match self.states.lock().get_mut(&id) {
+            Some(state) => state.admit(flags),
+            None => flags,
+        };
+        if !flags.is_empty() {
+            self.send(Event { id, flags, data });
+        }
+    }
+
+    /// Read as many queued events as fit in `buf`, blocking until there is one or the read
+    /// timeout elapses, in which case no events are read.
     pub fn read(&self, buf: UserSliceWo) -> Result<usize> {
-        self.queue.receive_into_user(buf, true, "EventQueue::read")
+        let timeout = self.timeout();
+        let deadline = (timeout != NO_TIMEOUT).then(|| {
+            time::monotonic().saturating_add(timeout as u128 * time::NANOS_PER_SEC / 1000)
+        });
+
+        let mut delivered = Vec::new();
+        let record = |event: &Event| delivered.push(event.id);
+        let bytes_read = match self.queue.receive_into_user_with(
+            buf,
+            timeout != 0,
+            "EventQueue::read",
+            deadline,
+            record,
+        ) {
+            Ok(bytes_read) => bytes_read,
+            Err(err) if err.errno == EAGAIN || err.errno == ETIMEDOUT => 0,
+            Err(err) => return Err(err),
+        };
+
+        delivered.sort_unstable();
+        delivered.dedup();
+        self.delivered(&delivered);
+
+        Ok(bytes_read)
+    }
+
+    /// Update the registrations among `delivered` now that their events were read, and requeue
+    /// the events of level-triggered ones whose file is still ready.
+    fn delivered(&self, delivered: &[usize]) {
+        let registrations = {
+            let mut states = self.states.lock();
+            // Events dropped in an overflow may have been pending, and the reader resynchronizes.
+            if delivered.contains(&OVERFLOW_EVENT_ID) {
+                for state in states.values_mut() {
+                    state.pending = EventFlags::empty();
+                }
+            }
+            delivered
+                .iter()
+                .filter_map(|id| {
+                    let state = states.get_mut(id)?;
+                    state.pending = EventFlags::empty();
+                    let level = state.mode & EVENT_EDGE == 0 && !state.disarmed;
+                    level.then_some((*id, state.key, state.registration))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for (id, reg_key, registration) in registrations {
+            let Some(scheme) = scheme::schemes().get(reg_key.scheme).cloned() else {
+                continue;
+            };
+            let Ok(ready) = scheme.fevent(reg_key.number, registration.flags) else {
+                continue;
+            };
+            let ready = ready & (registration.flags | EVENT_HANGUP);
+            self.notify(id, ready, registration.data);
+        }
     }
 
     pub fn write(&self, events: &[Event]) -> Result<usize> {
         for event in events {
+            let mode = event.flags.bits() & (EVENT_EDGE | EVENT_ONESHOT);
+
             let file = {
                 let contexts = context::contexts();
                 let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
@@ -52,14 +211,36 @@ impl EventQueue {
                 (description.scheme, description.number)
             };
 
+            let registration = Registration {
+                flags: EventFlags::from_bits_truncate(event.flags.bits()),
+                data: event.data,
+            };
+            {
+                // Registering again resets the state, which arms oneshot registrations again.
+                let mut states = self.states.lock();
+                if !registration.flags.is_empty() {
+                    states.insert(
+                        event.id,
+                        RegState {
+                            key: RegKey { scheme, number },
+                            registration,
+                            mode,
+                            pending: EventFlags::empty(),
+                            disarmed: false,
+                        },
+                    );
+                } else {
+                    states.remove(&event.id);
+                }
+            }
+
             register(
                 RegKey { scheme, number },
                 QueueKey {
                     queue: self.id,
                     id: event.id,
-                    data: event.data,
                 },
-                event.flags,
+                registration,
             );
 
             let flags = sync(RegKey { scheme, number })?;
@@ -100,7 +281,7 @@ pub fn queues_mut() -> RwLockWriteGuard<'static, EventQueueList> {
     QUEUES.call_once(init_queues).write()
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct RegKey {
     pub scheme: SchemeId,
     pub number: usize,
@@ -110,10 +291,19 @@ pub struct RegKey {
 pub struct QueueKey {
     pub queue: EventQueueId,
     pub id: usize,
+}
+
+/// The events a queue is interested in for one file.
+#[derive(Clone, Copy, Debug)]
+pub struct Registration {
+    pub flags: EventFlags,
+    /// Cookie supplied by the caller, returned as is in every event of the registration.
+    /// Registering the same file again replaces it, rather than adding a second registration.
+    // TODO: widen to u64 on 32-bit targets, which needs `Event::data` to be widened as well.
     pub data: usize,
 }
 
-type Registry = HashMap<RegKey, HashMap<QueueKey, EventFlags>>;
+type Registry = HashMap<RegKey, HashMap<QueueKey, Registration>>;
 
 static REGISTRY: Once<RwLock<Registry>> = Once::new();
 
@@ -132,15 +322,15 @@ pub fn registry_mut() -> RwLockWriteGuard<'static, Registry> {
     REGISTRY.call_once(init_registry).write()
 }
 
-pub fn register(reg_key: RegKey, queue_key: QueueKey, flags: EventFlags) {
+pub fn register(reg_key: RegKey, queue_key: QueueKey, registration: Registration) {
     let mut registry = registry_mut();
 
     let entry = registry.entry(reg_key).or_insert_with(|| HashMap::new());
 
-    if flags.is_empty() {
+    if registration.flags.is_empty() {
         entry.remove(&queue_key);
     } else {
-        entry.insert(queue_key, flags);
+        entry.insert(queue_key, registration);
     }
 }
 
@@ -151,8 +341,8 @@ pub fn sync(reg_key: RegKey) -> Result<EventFlags> {
         let registry = registry();
 
         if let Some(queue_list) = registry.get(&reg_key) {
-            for (_queue_key, &queue_flags) in queue_list.iter() {
-                flags |= queue_flags;
+            for (_queue_key, registration) in queue_list.iter() {
+                flags |= registration.flags;
             }
         }
     }
@@ -180,16 +370,12 @@ pub fn trigger(scheme: SchemeId, number: usize, flags: EventFlags) {
     let registry = registry();
 
     if let Some(queue_list) = registry.get(&RegKey { scheme, number }) {
-        for (queue_key, &queue_flags) in queue_list.iter() {
-            let common_flags = flags & queue_flags;
+        for (queue_key, registration) in queue_list.iter() {
+            let common_flags = flags & (registration.flags | EVENT_HANGUP);
             if !common_flags.is_empty() {
                 let queues = queues();
                 if let Some(queue) = queues.get(&queue_key.queue) {
-                    queue.queue.send(Event {
-                        id: queue_key.id,
-                        flags: common_flags,
-                        data: queue_key.data,
-                    });
+                    queue.notify(queue_key.id, common_flags, registration.data);
                 }
             }
         }
@@ -0,0 +1,103 @@
+//! Fault injection, to exercise error handling paths.
+//!
+//! Root makes kernel heap allocations, frame allocations and calls to userspace schemes fail at
+//! random by setting a probability in percent for each in `sys:kconfig`, such as `fault.heap 5`,
+//! `fault.frame 1` or `fault.scheme 10`. Setting `fault.seed` restarts the random sequence, so
+//! that a failing run can be replayed. `sys:faults` counts the faults injected at each point.
+//!
+//! Faults are only injected on behalf of userspace, inside syscalls, leaving boot and kernel
+//! contexts alone. Only fallible heap allocations are failed, as the others would panic, and
+//! likewise frames are only failed for user pages, kernel stacks and other callers handling
+//! allocation failure, not for page tables. Failed allocations report ENOMEM, and failed scheme
+//! calls EIO without the scheme seeing the request.
+
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+
+use crate::{percpu::PercpuBlock, syscall::error::Result};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Point {
+    /// Fallible kernel heap allocations.
+    Heap,
+    /// Physical frame allocations.
+    Frame,
+    /// Calls to userspace schemes.
+    Scheme,
+}
+impl Point {
+    pub const ALL: [Self; 3] = [Self::Heap, Self::Frame, Self::Scheme];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Heap => "heap",
+            Self::Frame => "frame",
+            Self::Scheme => "scheme",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|point| point.name() == name)
+    }
+    /// The probability of a fault, in percent.
+    pub fn probability(self) -> u8 {
+        PROBABILITIES[self as usize].load(Ordering::Relaxed)
+    }
+    pub fn set_probability(self, probability: u8) {
+        PROBABILITIES[self as usize].store(probability.min(100), Ordering::Relaxed);
+    }
+}
+
+const NEVER: AtomicU8 = AtomicU8::new(0);
+static PROBABILITIES: [AtomicU8; Point::ALL.len()] = [NEVER; Point::ALL.len()];
+const NONE: AtomicUsize = AtomicUsize::new(0);
+static INJECTED: [AtomicUsize; Point::ALL.len()] = [NONE; Point::ALL.len()];
+
+static SEED: AtomicU64 = AtomicU64::new(0);
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+pub fn seed() -> u64 {
+    SEED.load(Ordering::Relaxed)
+}
+pub fn set_seed(seed: u64) {
+    SEED.store(seed, Ordering::Relaxed);
+    STATE.store(seed, Ordering::Relaxed);
+}
+
+/// The next number of the sequence, from SplitMix64.
+fn random() -> u64 {
+    let mut z = STATE.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed);
+    z = z.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Whether the operation at `point` should fail.
+pub fn inject(point: Point) -> bool {
+    let probability = point.probability();
+    if probability == 0 || !PercpuBlock::current().inside_syscall.get() {
+        return false;
+    }
+    if random() % 100 >= u64::from(probability) {
+        return false;
+    }
+    INJECTED[point as usize].fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// `sys:faults`, the number of faults injected at each point.
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+    for point in Point::ALL {
+        let _ = writeln!(
+            string,
+            "{} {}",
+            point.name(),
+            INJECTED[point as usize].load(Ordering::Relaxed)
+        );
+    }
+    Ok(string.into_bytes())
+}
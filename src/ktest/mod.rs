@@ -0,0 +1,218 @@
+//! In-kernel self-tests of core subsystems.
+//!
+//! Unlike host-side unit tests, these run on the actual target, either at boot when the
+//! bootloader environment contains `KTEST=1`, or on demand by root writing to `sys:selftest`.
+//! Results are reported through the kernel log.
+
+use alloc::{format, string::String};
+use core::num::NonZeroUsize;
+
+use rmm::Arch;
+use spin::Mutex;
+
+use crate::{
+    context::memory::{handle_notify_files, AddrSpaceWrapper, Grant, PageSpan},
+    memory::{allocate_frame, deallocate_frame, RmmA, PAGE_SIZE},
+    paging::{KernelMapper, Page, VirtualAddress},
+    scheme::{SchemeList, SchemeNamespace},
+    sync::WaitQueue,
+    syscall::{
+        error::{Error, Result, EFAULT, EINVAL, EIO},
+        flag::MapFlags,
+        usercopy::{validate_region, UserSliceRo},
+    },
+};
+
+type TestResult = core::result::Result<(), String>;
+
+/// Fail the current test, recording the location and condition, if `$cond` does not hold.
+macro_rules! kassert {
+    ($cond:expr) => {
+        if !$cond {
+            return Err(format!(
+                "{}:{}: assertion failed: {}",
+                file!(),
+                line!(),
+                stringify!($cond)
+            ));
+        }
+    };
+}
+
+const TESTS: &[(&str, fn() -> TestResult)] = &[
+    ("frame_alloc", frame_alloc),
+    ("paging_translate", paging_translate),
+    ("grant_map_unmap", grant_map_unmap),
+    ("wait_queue", wait_queue),
+    ("scheme_list", scheme_list),
+    ("usercopy_bounds", usercopy_bounds),
+];
+
+/// Serializes concurrent runs, so that their log output is not interleaved.
+static RUN_LOCK: Mutex<()> = Mutex::new(());
+
+/// Run all tests, returning the number of passed and failed tests.
+pub fn run_all() -> (usize, usize) {
+    let _guard = RUN_LOCK.lock();
+
+    info!("ktest: running {} tests", TESTS.len());
+    let mut passed = 0;
+    let mut failed = 0;
+    for (name, test) in TESTS.iter() {
+        match test() {
+            Ok(()) => {
+                info!("ktest: {} ... ok", name);
+                passed += 1;
+            }
+            Err(message) => {
+                error!("ktest: {} ... FAILED: {}", name, message);
+                failed += 1;
+            }
+        }
+    }
+    info!("ktest: {} passed, {} failed", passed, failed);
+    (passed, failed)
+}
+
+/// Run the tests at boot, if requested by the bootloader environment.
+pub fn run_at_boot(env: &[u8]) {
+    if env.split(|&b| b == b'\n').any(|line| line == b"KTEST=1") {
+        run_all();
+    }
+}
+
+/// Write handler of `sys:selftest`. Any write runs the whole suite, and fails with `EIO` if a test
+/// failed.
+pub fn sys_write(buf: &[u8]) -> Result<usize> {
+    match run_all() {
+        (_, 0) => Ok(buf.len()),
+        _ => Err(Error::new(EIO)),
+    }
+}
+
+fn frame_alloc() -> TestResult {
+    let frame = allocate_frame().ok_or_else(|| String::from("out of memory"))?;
+    kassert!(frame.start_address().data() % PAGE_SIZE == 0);
+
+    let virt = unsafe { RmmA::phys_to_virt(frame.start_address()) };
+    let ptr = virt.data() as *mut u64;
+    unsafe {
+        ptr.write_volatile(0x5A5A_A5A5_5A5A_A5A5);
+        kassert!(ptr.read_volatile() == 0x5A5A_A5A5_5A5A_A5A5);
+        deallocate_frame(frame);
+    }
+    Ok(())
+}
+
+fn paging_translate() -> TestResult {
+    let frame = allocate_frame().ok_or_else(|| String::from("out of memory"))?;
+    let virt = unsafe { RmmA::phys_to_virt(frame.start_address()) };
+
+    let translated = KernelMapper::lock().translate(virt);
+    unsafe { deallocate_frame(frame) };
+
+    kassert!(translated.map(|(phys, _)| phys) == Some(frame.start_address()));
+
+    // The first user page is never part of the kernel address space.
+    kassert!(KernelMapper::lock()
+        .translate(VirtualAddress::new(0))
+        .is_none());
+    Ok(())
+}
+
+fn grant_map_unmap() -> TestResult {
+    const PAGES: usize = 4;
+
+    let addr_space = AddrSpaceWrapper::new().map_err(|err| format!("new: {:?}", err))?;
+    let page_count = NonZeroUsize::new(PAGES).unwrap();
+
+    let base = addr_space
+        .acquire_write()
+        .mmap_anywhere(
+            &addr_space,
+            page_count,
+            MapFlags::PROT_READ | MapFlags::PROT_WRITE,
+            |dst_page, flags, mapper, flusher| {
+                Ok(Grant::zeroed(
+                    PageSpan::new(dst_page, PAGES),
+                    flags,
+                    mapper,
+                    flusher,
+                    false,
+                )?)
+            },
+        )
+        .map_err(|err| format!("mmap: {:?}", err))?;
+
+    {
+        let guard = addr_space.acquire_read();
+        kassert!(guard.grants.contains(base).is_some());
+        kassert!(guard.grants.contains(base.next_by(PAGES - 1)).is_some());
+        kassert!(guard.grants.contains(base.next_by(PAGES)).is_none());
+    }
+
+    let notify_files = addr_space
+        .munmap(PageSpan::new(base, PAGES), false)
+        .map_err(|err| format!("munmap: {:?}", err))?;
+    handle_notify_files(notify_files);
+
+    kassert!(addr_space.acquire_read().grants.contains(base).is_none());
+    Ok(())
+}
+
+fn wait_queue() -> TestResult {
+    let queue = WaitQueue::<usize>::new();
+    kassert!(queue.send(1) == 1);
+    kassert!(queue.send(2) == 2);
+
+    let mut inner = queue.inner.lock();
+    kassert!(inner.pop_front() == Some(1));
+    kassert!(inner.pop_front() == Some(2));
+    kassert!(inner.is_empty());
+    Ok(())
+}
+
+fn scheme_list() -> TestResult {
+    let mut list = SchemeList::new();
+    let root = SchemeNamespace::from(1);
+
+    kassert!(list.get_name(root, "sys").is_some());
+    kassert!(list.get_name(root, "nonexistent").is_none());
+
+    let ns = list
+        .make_ns(root, ["sys".into()])
+        .map_err(|err| format!("make_ns: {:?}", err))?;
+    kassert!(list.get_name(ns, "sys").is_some());
+    // Root-only schemes must not leak into derived namespaces.
+    kassert!(list.get_name(ns, "debug").is_none());
+
+    kassert!(list.make_ns(root, ["nonexistent".into()]).is_err());
+    Ok(())
+}
+
+fn usercopy_bounds() -> TestResult {
+    let end = crate::USER_END_OFFSET;
+
+    kassert!(UserSliceRo::ro(PAGE_SIZE, PAGE_SIZE).is_ok());
+    kassert!(UserSliceRo::ro(end, 1).map(|_| ()) == Err(Error::new(EFAULT)));
+    kassert!(
+        UserSliceRo::ro(end - PAGE_SIZE, 2 * PAGE_SIZE).map(|_| ()) == Err(Error::new(EFAULT))
+    );
+    kassert!(UserSliceRo::ro(PAGE_SIZE, usize::MAX).map(|_| ()) == Err(Error::new(EFAULT)));
+
+    let slice = UserSliceRo::ro(PAGE_SIZE, 16).map_err(|err| format!("{:?}", err))?;
+    let (left, right) = slice.split_at(4).ok_or_else(|| String::from("split_at"))?;
+    kassert!(left.addr() == PAGE_SIZE && left.len() == 4);
+    kassert!(right.addr() == PAGE_SIZE + 4 && right.len() == 12);
+    kassert!(slice.split_at(17).is_none());
+
+    let first_page = Page::containing_address(VirtualAddress::new(PAGE_SIZE));
+    kassert!(validate_region(PAGE_SIZE, PAGE_SIZE) == Ok((first_page, 1)));
+    kassert!(validate_region(PAGE_SIZE + 1, PAGE_SIZE).map(|_| ()) == Err(Error::new(EINVAL)));
+    kassert!(validate_region(PAGE_SIZE, 0).map(|_| ()) == Err(Error::new(EINVAL)));
+    kassert!(
+        validate_region(end - PAGE_SIZE, 2 * PAGE_SIZE).map(|_| ()) == Err(Error::new(EFAULT))
+    );
+
+    Ok(())
+}
@@ -1,37 +1,551 @@
-use alloc::collections::VecDeque;
-use core::sync::atomic::{AtomicBool, Ordering};
+//! The kernel log, a ring buffer of every line printed by the kernel.
+//!
+//! The buffer is static, so that messages printed during early boot, before the heap or any
+//! console is available, are kept for later retrieval through `sys:log` and `debug:log`. Each line
+//! is prefixed with the monotonic time, level, CPU, context and subsystem it was printed from, such
+//! as `[    1.234567] I cpu0 pid5 scheme: `, and the consoles are fed the same prefixed output.
+//! Readers of `debug:log` can filter lines on the level and subsystem in the prefix. Unless opened
+//! with `O_NONBLOCK`, reads of `debug:log` block for new output, of which readers are notified
+//! with `EVENT_READ` on the next scheduler tick. Its position is the offset in bytes since boot,
+//! so `SEEK_END` skips to new output, and root clears the log by truncating it.
+//!
+//! Output goes to a number of sinks, each with its own level threshold that can be changed at
+//! runtime through `sys:kconfig`. Records are also filtered by the subsystem they come from,
+//! before reaching any sink: each subsystem has a threshold of its own, initially set at build
+//! time by the `KERNEL_LOG` environment variable, such as `KERNEL_LOG=scheme=debug,acpi=warn`,
+//! and changed at runtime through `sys:kconfig` as well. The `log_max_*` features compile out
+//! records more verbose than their level in every subsystem.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+};
 use spin::Mutex;
 
-pub static LOG: Mutex<Option<Log>> = Mutex::new(None);
+pub use ::log::{Level, LevelFilter};
 
-pub fn init() {
-    *LOG.lock() = Some(Log::new(1024 * 1024));
-}
+/// Size of the log ring buffer, in bytes.
+const LOG_SIZE: usize = 1024 * 1024;
+
+pub static LOG: Mutex<Log> = Mutex::new(Log::new());
 
 pub struct Log {
-    data: VecDeque<u8>,
-    size: usize,
+    data: [u8; LOG_SIZE],
+    /// Index of the oldest byte.
+    head: usize,
+    len: usize,
+    /// Number of bytes written since boot, which is the offset just past the newest byte.
+    written: usize,
+    /// Whether the last byte written ended a line, so the next one is to be prefixed.
+    line_start: bool,
+    /// Time of the last line, used when the clock cannot be read without blocking.
+    last_time: u128,
 }
 
 impl Log {
-    pub fn new(size: usize) -> Log {
+    pub const fn new() -> Log {
         Log {
-            data: VecDeque::with_capacity(size),
-            size,
+            data: [0; LOG_SIZE],
+            head: 0,
+            len: 0,
+            written: 0,
+            line_start: true,
+            last_time: 0,
         }
     }
 
+    /// The contents of the log, oldest first, split in two where the ring buffer wraps around.
     pub fn read(&self) -> (&[u8], &[u8]) {
-        self.data.as_slices()
+        let end = self.head + self.len;
+        if end <= LOG_SIZE {
+            (&self.data[self.head..end], &[])
+        } else {
+            (&self.data[self.head..], &self.data[..end - LOG_SIZE])
+        }
+    }
+
+    /// Drop all output so far, leaving the offsets counted from boot as they are.
+    pub fn clear(&mut self) {
+        self.head = (self.head + self.len) % LOG_SIZE;
+        self.len = 0;
+    }
+
+    /// The offset just past the newest byte, counted from boot.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// Copy the contents of the log starting at `offset`, counted from boot, into `buf`. Returns the
+    /// offset of the first byte copied, which is later than `offset` if it was overwritten already,
+    /// and the number of bytes copied.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> (usize, usize) {
+        let oldest = self.written - self.len;
+        let offset = offset.clamp(oldest, self.written);
+        let count = buf.len().min(self.written - offset);
+        let start = self.head + (offset - oldest);
+        for (i, byte) in buf[..count].iter_mut().enumerate() {
+            *byte = self.data[(start + i) % LOG_SIZE];
+        }
+        (offset, count)
+    }
+
+    /// Append `buf` with each line prefixed by `tag`, passing the output on to `console` as well.
+    /// The output is only kept in the ring buffer if the threshold of [`Sink::Log`] allows it.
+    /// Without a tag, lines are written as is, as for console output from userspace.
+    pub fn write(&mut self, buf: &[u8], tag: Option<Tag>, mut console: impl FnMut(&[u8])) {
+        let store = Sink::Log.enabled(tag.map_or(Level::Info, |tag| tag.level));
+        for line in buf.split_inclusive(|&b| b == b'\n') {
+            if let Some(tag) = tag.filter(|_| self.line_start) {
+                let prefix = self.prefix(tag);
+                if store {
+                    self.push(prefix.as_bytes());
+                }
+                console(prefix.as_bytes());
+            }
+            if store {
+                self.push(line);
+            }
+            console(line);
+            self.line_start = line.ends_with(b"\n");
+        }
+    }
+
+    fn prefix(&mut self, tag: Tag) -> Prefix {
+        // The log may be written to with the clock locked, such as from the timer interrupt.
+        if let Some(time) = crate::time::try_monotonic() {
+            self.last_time = time;
+        }
+        let micros = self.last_time / 1000;
+        // Only the BSP prints before per-CPU data is set up.
+        let (cpu, pid) = if PERCPU_READY.load(Ordering::Relaxed) {
+            (crate::cpu_id().get(), crate::context::context_id().get())
+        } else {
+            (0, 0)
+        };
+
+        let mut prefix = Prefix::default();
+        let _ = write!(
+            prefix,
+            "[{:>5}.{:06}] {} cpu{} pid{} {}: ",
+            micros / 1_000_000,
+            micros % 1_000_000,
+            level_char(tag.level),
+            cpu,
+            pid,
+            tag.subsystem.name()
+        );
+        prefix
     }
 
-    pub fn write(&mut self, buf: &[u8]) {
+    fn push(&mut self, buf: &[u8]) {
         for &b in buf {
-            while self.data.len() + 1 >= self.size {
-                self.data.pop_front();
+            if self.len == LOG_SIZE {
+                self.head = (self.head + 1) % LOG_SIZE;
+                self.len -= 1;
+            }
+            self.data[(self.head + self.len) % LOG_SIZE] = b;
+            self.len += 1;
+        }
+        self.written += buf.len();
+    }
+}
+
+/// [`READ_GID`] while anyone may read the log.
+const ANY_GID: u32 = u32::MAX;
+/// Group allowed to read the log besides root, or [`ANY_GID`].
+static READ_GID: AtomicU32 = AtomicU32::new(ANY_GID);
+
+/// Whether a caller may read the log. Unlike writing to the consoles or reading their input, this
+/// is not limited to root, so that log collectors can run unprivileged. Anyone may read it unless
+/// a group is set.
+pub fn may_read(uid: u32, gid: u32) -> bool {
+    uid == 0 || read_gid().map_or(true, |read_gid| gid == read_gid)
+}
+/// The group allowed to read the log besides root, or `None` if anyone may.
+pub fn read_gid() -> Option<u32> {
+    Some(READ_GID.load(Ordering::Relaxed)).filter(|&gid| gid != ANY_GID)
+}
+pub fn set_read_gid(gid: Option<u32>) {
+    READ_GID.store(gid.unwrap_or(ANY_GID), Ordering::Relaxed);
+}
+
+/// A destination of kernel output.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Sink {
+    /// The serial ports, and other byte-oriented debug ports such as the QEMU debug console.
+    Serial,
+    /// The framebuffer text console.
+    Display,
+    /// The ring buffer read through `sys:log`.
+    Log,
+}
+
+static THRESHOLDS: [AtomicUsize; 3] = [
+    AtomicUsize::new(LevelFilter::Info as usize),
+    AtomicUsize::new(LevelFilter::Info as usize),
+    AtomicUsize::new(LevelFilter::Info as usize),
+];
+
+const LEVEL_FILTERS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+impl Sink {
+    pub const ALL: [Self; 3] = [Self::Serial, Self::Display, Self::Log];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Serial => "serial",
+            Self::Display => "display",
+            Self::Log => "log",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|sink| sink.name() == name)
+    }
+
+    /// The most verbose level passed on to the sink.
+    pub fn threshold(self) -> LevelFilter {
+        LEVEL_FILTERS[THRESHOLDS[self as usize].load(Ordering::Relaxed)]
+    }
+    pub fn set_threshold(self, threshold: LevelFilter) {
+        THRESHOLDS[self as usize].store(threshold as usize, Ordering::Relaxed);
+        update_max_level();
+    }
+    pub fn enabled(self, level: Level) -> bool {
+        level <= self.threshold()
+    }
+}
+
+/// Records are filtered by the log crate before reaching the logger, so the maximum level is the
+/// most verbose one passed on by both a subsystem and a sink.
+fn update_max_level() {
+    let sinks = Sink::ALL.into_iter().map(Sink::threshold).max();
+    let subsystems = Subsystem::ALL.into_iter().map(Subsystem::threshold).max();
+    ::log::set_max_level(sinks.min(subsystems).unwrap_or(LevelFilter::Off));
+}
+
+/// Parse a level threshold by its lowercase name, as used by `sys:kconfig`.
+pub fn level_filter_from_name(name: &str) -> Option<LevelFilter> {
+    LEVEL_FILTERS
+        .into_iter()
+        .find(|filter| level_filter_name(*filter) == name)
+}
+pub fn level_filter_name(filter: LevelFilter) -> &'static str {
+    match filter {
+        LevelFilter::Off => "off",
+        LevelFilter::Error => "error",
+        LevelFilter::Warn => "warn",
+        LevelFilter::Info => "info",
+        LevelFilter::Debug => "debug",
+        LevelFilter::Trace => "trace",
+    }
+}
+
+/// Whether the per-CPU data of the BSP can be used to tag output.
+static PERCPU_READY: AtomicBool = AtomicBool::new(false);
+
+/// Start tagging output with the current CPU and context, once per-CPU data is set up on the BSP.
+/// The APs do not print before setting up theirs.
+pub fn init_percpu() {
+    PERCPU_READY.store(true, Ordering::Relaxed);
+}
+
+/// The part of the kernel output originates from, derived from the module path of log records.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Subsystem {
+    Kernel,
+    Arch,
+    Acpi,
+    Context,
+    Memory,
+    Scheme,
+    Syscall,
+    Devices,
+    /// Console output written by userspace, which is not prefixed.
+    User,
+    /// Lockup reports, see [`crate::context::lockup`].
+    Watchdog,
+}
+impl Subsystem {
+    pub const ALL: [Self; 10] = [
+        Self::Kernel,
+        Self::Arch,
+        Self::Acpi,
+        Self::Context,
+        Self::Memory,
+        Self::Scheme,
+        Self::Syscall,
+        Self::Devices,
+        Self::User,
+        Self::Watchdog,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Kernel => "kernel",
+            Self::Arch => "arch",
+            Self::Acpi => "acpi",
+            Self::Context => "context",
+            Self::Memory => "memory",
+            Self::Scheme => "scheme",
+            Self::Syscall => "syscall",
+            Self::Devices => "devices",
+            Self::User => "user",
+            Self::Watchdog => "watchdog",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|subsystem| subsystem.name() == name)
+    }
+    /// The subsystem of a log record target, which is the module path by default.
+    pub fn from_target(target: &str) -> Self {
+        let module = target.split("::").nth(1).unwrap_or("");
+        match module {
+            "allocator" | "memory" | "paging" => Self::Memory,
+            "dtb" => Self::Devices,
+            _ => Self::from_name(module)
+                .filter(|subsystem| *subsystem != Self::User)
+                .unwrap_or(Self::Kernel),
+        }
+    }
+    /// The bit of the subsystem in a filter mask.
+    pub fn bit(self) -> usize {
+        1 << self as usize
+    }
+
+    /// The most verbose level of records from the subsystem passed on to the sinks. Output written
+    /// by userspace is not a log record, and is only filtered by the sinks.
+    pub fn threshold(self) -> LevelFilter {
+        LEVEL_FILTERS[FILTERS[self as usize].load(Ordering::Relaxed)]
+    }
+    pub fn set_threshold(self, threshold: LevelFilter) {
+        FILTERS[self as usize].store(threshold as usize, Ordering::Relaxed);
+        update_max_level();
+    }
+    pub fn enabled(self, level: Level) -> bool {
+        level <= self.threshold()
+    }
+}
+
+const NO_FILTER: AtomicUsize = AtomicUsize::new(LevelFilter::Trace as usize);
+static FILTERS: [AtomicUsize; Subsystem::ALL.len()] = [NO_FILTER; Subsystem::ALL.len()];
+
+/// Apply the subsystem thresholds given at build time, as comma-separated `<subsystem>=<level>`
+/// pairs. Invalid pairs are reported and skipped.
+fn init_filters() {
+    let Some(filters) = option_env!("KERNEL_LOG") else {
+        return;
+    };
+    for filter in filters.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        let parsed = filter.split_once('=').and_then(|(subsystem, level)| {
+            Some((
+                Subsystem::from_name(subsystem)?,
+                level_filter_from_name(level)?,
+            ))
+        });
+        match parsed {
+            Some((subsystem, threshold)) => subsystem.set_threshold(threshold),
+            None => ::log::warn!("KERNEL_LOG: invalid filter {:?}", filter),
+        }
+    }
+}
+
+/// What each line of kernel output is prefixed with, besides the time and location.
+#[derive(Clone, Copy, Debug)]
+pub struct Tag {
+    pub level: Level,
+    pub subsystem: Subsystem,
+}
+impl Tag {
+    pub fn of(record: &log::Record) -> Self {
+        Self {
+            level: record.level(),
+            subsystem: Subsystem::from_target(record.target()),
+        }
+    }
+}
+impl Default for Tag {
+    fn default() -> Self {
+        Self {
+            level: Level::Info,
+            subsystem: Subsystem::Kernel,
+        }
+    }
+}
+
+/// Parse the level and subsystem from the prefix of a line in the log. Lines without a prefix
+/// were written by userspace.
+pub fn parse_tag(line: &[u8]) -> Tag {
+    let parse = || {
+        let line = core::str::from_utf8(line).ok()?;
+        let (_time, rest) = line.strip_prefix('[')?.split_once("] ")?;
+        let mut fields = rest.splitn(5, ' ');
+        let level = match fields.next()? {
+            "E" => Level::Error,
+            "W" => Level::Warn,
+            "I" => Level::Info,
+            "D" => Level::Debug,
+            "T" => Level::Trace,
+            _ => return None,
+        };
+        let _cpu = fields.next()?.strip_prefix("cpu")?;
+        let _pid = fields.next()?.strip_prefix("pid")?;
+        let subsystem = Subsystem::from_name(fields.next()?.strip_suffix(':')?)?;
+        Some(Tag { level, subsystem })
+    };
+    parse().unwrap_or(Tag {
+        level: Level::Info,
+        subsystem: Subsystem::User,
+    })
+}
+
+fn level_char(level: Level) -> char {
+    match level {
+        Level::Error => 'E',
+        Level::Warn => 'W',
+        Level::Info => 'I',
+        Level::Debug => 'D',
+        Level::Trace => 'T',
+    }
+}
+
+/// Line prefix formatted on the stack, as the log is written to before the heap is available.
+#[derive(Default)]
+struct Prefix {
+    buf: [u8; 64],
+    len: usize,
+}
+impl Prefix {
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+impl fmt::Write for Prefix {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+/// Messages allowed from a single call site per [`RATELIMIT_INTERVAL`], beyond which they are
+/// dropped until the next interval.
+const RATELIMIT_BURST: u32 = 10;
+/// Length of a rate limiting interval, in nanoseconds.
+const RATELIMIT_INTERVAL: u128 = 5_000_000_000;
+/// Number of call sites tracked at once. Call sites hashing to the same slot share their limit.
+const CALL_SITES: usize = 64;
+
+#[derive(Clone, Copy)]
+struct CallSite {
+    start: u128,
+    count: u32,
+    suppressed: u32,
+}
+
+/// Rate limiting per call site, and folding of identical consecutive messages, so that a fault
+/// loop cannot live-lock the consoles or push everything else out of the log.
+struct Limiter {
+    sites: [CallSite; CALL_SITES],
+    /// Hash of the last message printed.
+    last: u64,
+    /// Times the last message was repeated since it was printed.
+    repeated: u32,
+}
+
+static LIMITER: Mutex<Limiter> = Mutex::new(Limiter {
+    sites: [CallSite {
+        start: 0,
+        count: 0,
+        suppressed: 0,
+    }; CALL_SITES],
+    last: 0,
+    repeated: 0,
+});
+
+impl Limiter {
+    /// Decide whether `record` is printed. If so, returns how many times the previous message was
+    /// repeated, and how many messages from the call site were suppressed, to be reported first.
+    fn check(&mut self, record: &log::Record) -> Option<(u32, u32)> {
+        let mut hasher = Fnv1a::default();
+        let _ = write!(
+            hasher,
+            "{}{}{}",
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        if hasher.0 == self.last {
+            self.repeated = self.repeated.saturating_add(1);
+            return None;
+        }
+
+        let mut site_hasher = Fnv1a::default();
+        let _ = write!(
+            site_hasher,
+            "{}{}",
+            record.file().unwrap_or(record.target()),
+            record.line().unwrap_or(0)
+        );
+        let site = &mut self.sites[site_hasher.0 as usize % CALL_SITES];
+        let mut suppressed = 0;
+        // Without a clock, limiting is skipped rather than risking dropping everything.
+        let now = crate::time::try_monotonic().filter(|_| UNLIMITED.load(Ordering::Relaxed) == 0);
+        if let Some(now) = now {
+            if now.saturating_sub(site.start) >= RATELIMIT_INTERVAL {
+                suppressed = site.suppressed;
+                *site = CallSite {
+                    start: now,
+                    count: 0,
+                    suppressed: 0,
+                };
+            }
+            if site.count >= RATELIMIT_BURST {
+                site.suppressed = site.suppressed.saturating_add(1);
+                return None;
             }
-            self.data.push_back(b);
+            site.count += 1;
         }
+
+        self.last = hasher.0;
+        Some((core::mem::take(&mut self.repeated), suppressed))
+    }
+}
+
+/// Number of [`unlimited`] calls in progress, during which no call site is rate limited.
+static UNLIMITED: AtomicUsize = AtomicUsize::new(0);
+
+/// Run `f` without rate limiting the output of its call sites, for dumps that print a line per
+/// frame or context from one call site, such as stack traces.
+pub fn unlimited<T>(f: impl FnOnce() -> T) -> T {
+    UNLIMITED.fetch_add(1, Ordering::Relaxed);
+    let ret = f();
+    UNLIMITED.fetch_sub(1, Ordering::Relaxed);
+    ret
+}
+
+/// FNV-1a hash of formatted output.
+struct Fnv1a(u64);
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+impl fmt::Write for Fnv1a {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            self.0 = (self.0 ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        Ok(())
     }
 }
 
@@ -41,26 +555,145 @@ struct RedoxLogger {
 }
 
 impl ::log::Log for RedoxLogger {
-    fn enabled(&self, _: &log::Metadata<'_>) -> bool {
-        false
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        Subsystem::from_target(metadata.target()).enabled(metadata.level())
     }
     fn log(&self, record: &log::Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // Records are also logged from interrupts, which may have interrupted a holder of the
+        // limiter on the same CPU, in which case the record is not rate limited.
+        let (repeated, suppressed) =
+            match LIMITER.try_lock().map(|mut limiter| limiter.check(record)) {
+                Some(Some(notices)) => notices,
+                Some(None) => return,
+                None => (0, 0),
+            };
+
+        if repeated > 0 {
+            (self.log_func)(
+                &log::Record::builder()
+                    .args(format_args!("last message repeated {} times", repeated))
+                    .level(record.level())
+                    .target(record.target())
+                    .build(),
+            );
+        }
+        if suppressed > 0 {
+            (self.log_func)(
+                &log::Record::builder()
+                    .args(format_args!(
+                        "{} messages suppressed by rate limiting",
+                        suppressed
+                    ))
+                    .level(Level::Warn)
+                    .target(record.target())
+                    .build(),
+            );
+        }
         (self.log_func)(record)
     }
     fn flush(&self) {}
 }
 
+/// Size of the buffer for records logged before [`init_logger`], in bytes.
+const EARLY_SIZE: usize = 16 * 1024;
+
+/// Records logged before the consoles are set up, to be passed on once they are. Each record is
+/// stored as its level, target and message, each terminated by a NUL byte.
+struct EarlyRecords {
+    data: [u8; EARLY_SIZE],
+    len: usize,
+}
+
+static EARLY_RECORDS: Mutex<EarlyRecords> = Mutex::new(EarlyRecords {
+    data: [0; EARLY_SIZE],
+    len: 0,
+});
+/// Number of early records that did not fit, or arrived from an interrupt while the buffer was
+/// locked.
+static EARLY_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+impl EarlyRecords {
+    fn push(&mut self, record: &log::Record) {
+        let start = self.len;
+        if write!(
+            self,
+            "{}\0{}\0{}\0",
+            record.level(),
+            record.target(),
+            record.args()
+        )
+        .is_err()
+        {
+            self.len = start;
+            EARLY_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    /// Pass the buffered records on to `func`, in the order they were logged.
+    fn replay(&mut self, func: fn(&log::Record)) {
+        let text = core::str::from_utf8(&self.data[..self.len]).unwrap_or("");
+        let mut fields = text.split('\0');
+        while let (Some(level), Some(target), Some(message)) =
+            (fields.next(), fields.next(), fields.next())
+        {
+            let Ok(level) = level.parse() else {
+                continue;
+            };
+            func(
+                &log::Record::builder()
+                    .args(format_args!("{}", message))
+                    .level(level)
+                    .target(target)
+                    .build(),
+            );
+        }
+        let dropped = EARLY_DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            func(
+                &log::Record::builder()
+                    .args(format_args!("{} early messages dropped", dropped))
+                    .level(Level::Warn)
+                    .target(module_path!())
+                    .build(),
+            );
+        }
+        self.len = 0;
+    }
+}
+impl fmt::Write for EarlyRecords {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.data
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Install the logger, buffering records until [`init_logger`] provides their output. Called
+/// first thing on boot, so that nothing logged while setting up the consoles is lost.
+pub fn init_early_logger() {
+    unsafe {
+        update_max_level();
+        if let Err(e) = ::log::set_logger(&LOGGER) {
+            println!("Logger setup failed! error: {}", e);
+        }
+    }
+}
+
 pub fn init_logger(func: fn(&log::Record)) {
     unsafe {
         match LOGGER.initialized.load(Ordering::SeqCst) {
             false => {
-                ::log::set_max_level(::log::LevelFilter::Info);
                 LOGGER.log_func = func;
-                match ::log::set_logger(&LOGGER) {
-                    Ok(_) => ::log::info!("Logger initialized."),
-                    Err(e) => println!("Logger setup failed! error: {}", e),
-                }
+                EARLY_RECORDS.lock().replay(func);
                 LOGGER.initialized.store(true, Ordering::SeqCst);
+                ::log::info!("Logger initialized.");
+                init_filters();
             }
             true => {
                 ::log::info!("Tried to reinitialize the logger, which is not possible. Ignoring.")
@@ -70,7 +703,13 @@ pub fn init_logger(func: fn(&log::Record)) {
 }
 
 static mut LOGGER: RedoxLogger = RedoxLogger {
-    log_func: |_| {},
+    // Records may come from interrupts, which must not wait for the code they interrupted.
+    log_func: |record| match EARLY_RECORDS.try_lock() {
+        Some(mut early_records) => early_records.push(record),
+        None => {
+            EARLY_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    },
     initialized: AtomicBool::new(false),
 };
 
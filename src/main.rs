@@ -109,6 +109,14 @@ mod event;
 /// External functions
 mod externs;
 
+/// Fault injection
+#[cfg(feature = "fault_injection")]
+mod fault;
+
+/// In-kernel self-tests
+#[cfg(feature = "ktest")]
+mod ktest;
+
 /// Logging
 mod log;
 
@@ -121,6 +129,9 @@ mod panic;
 
 mod percpu;
 
+/// Persistent panic records
+mod pstore;
+
 /// Process tracing
 mod ptrace;
 
@@ -131,6 +142,9 @@ pub mod profiling;
 /// Schemes, filesystem handlers
 mod scheme;
 
+/// Kernel symbols for backtraces
+mod symbols;
+
 /// Synchronization primitives
 mod sync;
 
@@ -182,9 +196,15 @@ static BOOTSTRAP: spin::Once<Bootstrap> = spin::Once::new();
 fn kmain(cpu_count: u32, bootstrap: Bootstrap) -> ! {
     CPU_COUNT.store(cpu_count, Ordering::SeqCst);
 
+    symbols::init();
+
     //Initialize the first context, stored in kernel/src/context/mod.rs
     context::init();
 
+    percpu::init_info();
+
+    pstore::init();
+
     //Initialize global schemes, such as `acpi:`.
     scheme::init_globals();
 
@@ -194,6 +214,9 @@ fn kmain(cpu_count: u32, bootstrap: Bootstrap) -> ! {
 
     BOOTSTRAP.call_once(|| bootstrap);
 
+    #[cfg(feature = "ktest")]
+    ktest::run_at_boot(init_env());
+
     #[cfg(feature = "profiling")]
     profiling::ready_for_profiling();
 
@@ -232,6 +255,8 @@ fn kmain_ap(cpu_id: crate::cpu_set::LogicalCpuId) -> ! {
     }
     context::init();
 
+    percpu::init_info();
+
     let pid = syscall::getpid();
     info!("AP {}: {:?}", cpu_id, pid);
 
@@ -252,6 +277,7 @@ fn run_userspace() -> ! {
                     interrupt::enable_and_nop();
                 }
                 SwitchResult::AllContextsIdle => {
+                    context::tickless::idle();
                     // Enable interrupts, then halt CPU (to save power) until the next interrupt is actually fired.
                     interrupt::enable_and_halt();
                 }
@@ -263,15 +289,10 @@ fn run_userspace() -> ! {
 /// Allow exception handlers to send signal to arch-independent kernel
 pub fn ksignal(signal: usize) {
     info!("SIGNAL {}, CPU {}, PID {:?}", signal, cpu_id(), context::context_id());
-    {
-        let contexts = context::contexts();
-        if let Some(context_lock) = contexts.current() {
-            let mut context = context_lock.write();
-            info!("NAME {}", context.name);
-            context.sig.pending |= 1 << (signal - 1);
-        }
+    if let Ok(context_lock) = context::current() {
+        info!("NAME {}", context_lock.read().name);
     }
-    crate::context::signal::signal_handler();
+    crate::context::signal::force_signal(signal);
 }
 
 // TODO: Use this macro on aarch64 too.
@@ -10,14 +10,14 @@ use core::{
 
 use spin::Mutex;
 
-use crate::context::{self, memory::{AccessMode, PfError}};
+use crate::context::{self, context::FaultInfo, memory::{AccessMode, PfError}};
 use crate::kernel_executable_offsets::{__usercopy_start, __usercopy_end};
 use crate::paging::Page;
 pub use crate::paging::{PAGE_SIZE, PAGE_MASK, PhysicalAddress, RmmA, RmmArch};
 use rmm::{
     BumpAllocator, FrameAllocator, FrameCount, FrameUsage, TableKind, VirtualAddress
 };
-use crate::syscall::error::{ENOMEM, Error};
+use crate::syscall::{error::{ENOMEM, Error}, flag::SIGSEGV};
 
 /// A memory map area
 #[derive(Copy, Clone, Debug, Default)]
@@ -44,6 +44,39 @@ pub fn total_frames() -> usize {
     sections().iter().map(|section| section.frames.len()).sum()
 }
 
+/// Frame usage of one contiguous section of physical memory
+pub struct SectionUsage {
+    pub base: PhysicalAddress,
+    pub frames: usize,
+    pub used: usize,
+    /// Used frames with more than one reference, either shared or CoW
+    pub shared: usize,
+}
+/// Walk the page info of every section. Only meant for diagnostics, as this is linear in the
+/// amount of physical memory.
+pub fn section_usage() -> impl Iterator<Item = SectionUsage> {
+    sections().iter().map(|section| {
+        let mut used = 0;
+        let mut shared = 0;
+        for info in section.frames {
+            match info.refcount() {
+                None => (),
+                Some(RefCount::One) => used += 1,
+                Some(RefCount::Shared(_) | RefCount::Cow(_)) => {
+                    used += 1;
+                    shared += 1;
+                }
+            }
+        }
+        SectionUsage {
+            base: section.base.start_address(),
+            frames: section.frames.len(),
+            used,
+            shared,
+        }
+    })
+}
+
 /// Allocate a range of frames
 pub fn allocate_p2frame(order: u32) -> Option<Frame> {
     allocate_p2frame_complex(order, (), None, order).map(|(f, _)| f)
@@ -299,6 +332,10 @@ pub struct RaiiFrame {
 }
 impl RaiiFrame {
     pub fn allocate() -> Result<Self, Enomem> {
+        #[cfg(feature = "fault_injection")]
+        if crate::fault::inject(crate::fault::Point::Frame) {
+            return Err(Enomem);
+        }
         init_frame(RefCount::One)
             .map_err(|_| Enomem)
             .map(|inner| Self { inner })
@@ -868,9 +905,22 @@ pub fn page_fault_handler(
         }
     };
 
+    let ip = stack.ip();
+    let segv = || {
+        if caused_by_user {
+            context::signal::record_fault(FaultInfo {
+                signal: SIGSEGV,
+                ip,
+                address: Some(faulting_address.data()),
+                access: Some(mode),
+            });
+        }
+        Segv
+    };
+
     if invalid_page_tables {
         // TODO: Better error code than Segv?
-        return Err(Segv);
+        return Err(segv());
     }
 
     if address_is_user && (caused_by_user || is_usercopy) {
@@ -887,7 +937,7 @@ pub fn page_fault_handler(
         return Ok(());
     }
 
-    Err(Segv)
+    Err(segv())
 }
 static THE_ZEROED_FRAME: SyncUnsafeCell<Option<(Frame, &'static PageInfo)>> =
     SyncUnsafeCell::new(None);
@@ -1,16 +1,43 @@
 //! Intrinsics for panic handling
+//!
+//! A panic halts every CPU by default, leaving the output on the console. With `panic=reboot` in
+//! the boot environment, the system is reset instead, for machines that cannot be power cycled by
+//! hand.
+//!
+//! The output is also saved for the next boot when a persistent store is configured, see
+//! [`crate::pstore`].
 
-use core::panic::PanicInfo;
+use core::{panic::PanicInfo, str};
 
-use crate::{context, cpu_id, interrupt, syscall};
+use crate::{
+    context, cpu_id, interrupt,
+    log::{LevelFilter, Sink, Subsystem},
+    syscall,
+};
 
 /// Required to handle panics
 #[panic_handler]
 fn rust_begin_unwind(info: &PanicInfo) -> ! {
+    // Show the panic on every console still available, even those quieted from userspace, along
+    // with the stack traces and register dumps of any subsystem.
+    for sink in Sink::ALL {
+        if sink.threshold() < LevelFilter::Info {
+            sink.set_threshold(LevelFilter::Info);
+        }
+    }
+    for subsystem in Subsystem::ALL {
+        if subsystem.threshold() < LevelFilter::Info {
+            subsystem.set_threshold(LevelFilter::Info);
+        }
+    }
+
     println!("KERNEL PANIC: {}", info);
 
     unsafe {
         interrupt::stack_trace();
+
+        #[cfg(target_arch = "x86_64")]
+        interrupt::trace::other_cpus_stack_trace();
     }
 
     println!("CPU {}, PID {:?}", cpu_id(), context::context_id());
@@ -28,6 +55,18 @@ fn rust_begin_unwind(info: &PanicInfo) -> ! {
         }
     }
 
+    crate::pstore::save();
+
+    #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+    crate::arch::gdbstub::enter(crate::arch::gdbstub::StopReason::Panic);
+
+    if reboot_on_panic() {
+        println!("REBOOT");
+        unsafe {
+            crate::stop::emergency_reset();
+        }
+    }
+
     println!("HALT");
     loop {
         unsafe {
@@ -35,3 +74,17 @@ fn rust_begin_unwind(info: &PanicInfo) -> ! {
         }
     }
 }
+
+/// Whether the last `panic=` line of the boot environment asks for a reset. The environment is
+/// only known once the kernel has reached `kmain`.
+fn reboot_on_panic() -> bool {
+    let Some(bootstrap) = crate::BOOTSTRAP.get() else {
+        return false;
+    };
+    str::from_utf8(bootstrap.env)
+        .unwrap_or("")
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("panic="))
+        .last()
+        == Some("reboot")
+}
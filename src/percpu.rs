@@ -1,8 +1,12 @@
 use core::cell::{Cell, RefCell};
 use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
 
-use alloc::sync::{Arc, Weak};
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+};
 use rmm::Arch;
+use spin::Once;
 use syscall::PtraceFlags;
 
 use crate::context::empty_cr3;
@@ -18,6 +22,12 @@ use crate::syscall::debug::SyscallDebugInfo;
 pub struct PercpuBlock {
     /// A unique immutable number that identifies the current CPU - used for scheduling
     pub cpu_id: LogicalCpuId,
+    /// Where the CPU is located, detected when it is brought up
+    pub topology: CpuTopology,
+    /// Vendor, model and features of the CPU, described once the heap is available
+    pub info: Once<String>,
+    /// Effective frequency of the CPU, measured on its scheduler tick
+    pub frequency: crate::device::cpu::Frequency,
 
     /// Context management
     pub switch_internals: ContextSwitchPercpu,
@@ -39,6 +49,14 @@ pub struct PercpuBlock {
     pub syscall_debug_info: Cell<SyscallDebugInfo>,
 }
 
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuTopology {
+    pub package: u32,
+    pub core: u32,
+    /// Hardware thread (SMT sibling) within the core
+    pub thread: u32,
+}
+
 const NULL: AtomicPtr<PercpuBlock> = AtomicPtr::new(core::ptr::null_mut());
 static ALL_PERCPU_BLOCKS: [AtomicPtr<PercpuBlock>; MAX_CPU_COUNT as usize] = [NULL; MAX_CPU_COUNT as usize];
 
@@ -46,8 +64,22 @@ pub unsafe fn init_tlb_shootdown(id: LogicalCpuId, block: *mut PercpuBlock) {
     ALL_PERCPU_BLOCKS[id.get() as usize].store(block, Ordering::Release)
 }
 
+/// Get the percpu block of another CPU, or `None` if that CPU is not online.
+pub fn get(id: LogicalCpuId) -> Option<&'static PercpuBlock> {
+    unsafe { ALL_PERCPU_BLOCKS.get(id.get() as usize)?.load(Ordering::Acquire).as_ref() }
+}
+
 // PercpuBlock::current() is implemented somewhere in the arch-specific modules
 
+/// Describe the current CPU in its block, for `sys:cpu`. Called once the heap is available.
+pub fn init_info() {
+    PercpuBlock::current().info.call_once(|| {
+        let mut info = String::new();
+        let _ = crate::device::cpu::cpu_info(&mut info);
+        info
+    });
+}
+
 #[cfg(not(feature = "multi_core"))]
 pub fn shootdown_tlb_ipi(_target: Option<LogicalCpuId>) {}
 
@@ -122,7 +154,7 @@ pub unsafe fn switch_arch_hook() {
         let next = next_addrsp.acquire_read();
 
         next.used_by.atomic_set(percpu.cpu_id);
-        next.table.utable.make_current();
+        next.make_current();
     } else {
         crate::paging::RmmA::set_table(rmm::TableKind::User, empty_cr3());
     }
@@ -131,6 +163,9 @@ impl PercpuBlock {
     pub fn init(cpu_id: LogicalCpuId) -> Self {
         Self {
             cpu_id,
+            topology: crate::device::cpu::topology(),
+            info: Once::new(),
+            frequency: Default::default(),
             switch_internals: Default::default(),
             current_addrsp: RefCell::new(None),
             new_addrsp_tmp: Cell::new(None),
@@ -0,0 +1,165 @@
+//! Persistent panic records.
+//!
+//! With `pstore=<address>,<size>` in the boot environment, naming a page aligned physical memory
+//! range that the firmware and bootloader leave alone across a warm reboot, the kernel keeps the
+//! range out of the frame allocator, and saves the tail of the kernel log into it when panicking.
+//! The log then ends with the panic message and backtrace. After the next boot, the record can be
+//! read from `sys:lastpanic`, and is cleared from the range, so that it is only reported once.
+//!
+//! The range should be at the end of a memory area, as only the larger side of an area split by it
+//! is used. It is mapped uncached, so that the record reaches memory even if the reset does not
+//! write back the caches.
+
+use alloc::vec::Vec;
+use core::{
+    cmp, hint, slice, str,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::Once;
+
+use crate::{
+    log::LOG,
+    memory::PAGE_SIZE,
+    paging::{entry::EntryFlags, KernelMapper, PageFlags, PhysicalAddress, RmmA, RmmArch},
+    syscall::error::Result,
+};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"RXPANIC1");
+/// The magic, the length of the record and its checksum.
+const HEADER_SIZE: usize = 3 * 8;
+
+static REGION: Once<(usize, usize)> = Once::new();
+static MAPPED: AtomicBool = AtomicBool::new(false);
+static LAST_PANIC: Once<Vec<u8>> = Once::new();
+
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// FNV-1a, enough to tell a record from whatever the range held before.
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+/// Find the range in the boot environment, before memory is initialized.
+pub fn reserve(env: &[u8]) {
+    let Some(value) = str::from_utf8(env)
+        .unwrap_or("")
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("pstore="))
+        .last()
+    else {
+        return;
+    };
+    let region = value
+        .split_once(',')
+        .and_then(|(base, size)| Some((parse_number(base)?, parse_number(size)?)));
+    match region {
+        Some((base, size))
+            if base % PAGE_SIZE == 0
+                && size % PAGE_SIZE == 0
+                && size > HEADER_SIZE
+                && base.checked_add(size).is_some() =>
+        {
+            log::info!("pstore: {:X}:{:X}", base, base + size);
+            REGION.call_once(|| (base, size));
+        }
+        _ => log::warn!("pstore: invalid range {:?}", value),
+    }
+}
+
+/// The physical range reserved for panic records, to be kept out of the frame allocator.
+pub fn region() -> Option<(usize, usize)> {
+    REGION.get().copied()
+}
+
+fn region_mut() -> Option<&'static mut [u8]> {
+    let &(base, size) = REGION.get()?;
+    if !MAPPED.load(Ordering::Acquire) {
+        return None;
+    }
+    let virt = RmmA::phys_to_virt(PhysicalAddress::new(base));
+    Some(unsafe { slice::from_raw_parts_mut(virt.data() as *mut u8, size) })
+}
+
+/// Map the range, and take the record of the previous boot out of it.
+pub fn init() {
+    let Some((base, size)) = region() else {
+        return;
+    };
+    {
+        let mut mapper = KernelMapper::lock();
+        let mapper = mapper
+            .get_mut()
+            .expect("KernelMapper locked re-entrant while mapping pstore");
+        let flags = PageFlags::new()
+            .write(true)
+            .custom_flag(EntryFlags::NO_CACHE.bits(), true);
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            let (_, flush) = unsafe {
+                mapper
+                    .map_linearly(PhysicalAddress::new(base + offset), flags)
+                    .expect("failed to map pstore")
+            };
+            flush.flush();
+        }
+    }
+    MAPPED.store(true, Ordering::Release);
+
+    let Some(region) = region_mut() else {
+        return;
+    };
+    let (header, data) = region.split_at_mut(HEADER_SIZE);
+    let word = |i: usize| u64::from_le_bytes(header[i * 8..i * 8 + 8].try_into().unwrap());
+    if word(0) != MAGIC {
+        return;
+    }
+    let len = cmp::min(word(1) as usize, data.len());
+    if checksum(&data[..len]) == word(2) {
+        log::warn!("pstore: found a panic record of the previous boot, in sys:lastpanic");
+        LAST_PANIC.call_once(|| data[..len].to_vec());
+    }
+    header.fill(0);
+}
+
+/// Save the tail of the log as the panic record.
+pub fn save() {
+    let Some(region) = region_mut() else {
+        return;
+    };
+    let (header, data) = region.split_at_mut(HEADER_SIZE);
+
+    // Another CPU may be printing, but give up rather than hang the panic.
+    let log = (0..1_000_000).find_map(|_| {
+        let log = LOG.try_lock();
+        if log.is_none() {
+            hint::spin_loop();
+        }
+        log
+    });
+    let Some(log) = log else {
+        return;
+    };
+    let (first, second) = log.read();
+    let len = cmp::min(first.len() + second.len(), data.len());
+    let skip = first.len() + second.len() - len;
+    for (dst, src) in data.iter_mut().zip(first.iter().chain(second).skip(skip)) {
+        *dst = *src;
+    }
+    drop(log);
+
+    header[8..16].copy_from_slice(&(len as u64).to_le_bytes());
+    header[16..24].copy_from_slice(&checksum(&data[..len]).to_le_bytes());
+    header[..8].copy_from_slice(&MAGIC.to_le_bytes());
+}
+
+/// `sys:lastpanic`, the panic record of the previous boot, if any.
+pub fn resource() -> Result<Vec<u8>> {
+    Ok(LAST_PANIC.get().cloned().unwrap_or_default())
+}
@@ -6,7 +6,7 @@ use crate::{
     context::{self, ContextId}, event, percpu::PercpuBlock, scheme::GlobalSchemes, sync::WaitCondition, syscall::{data::PtraceEvent, error::*, flag::*, ptrace_event}
 };
 
-use alloc::{collections::VecDeque, sync::Arc};
+use alloc::{borrow::Cow, collections::VecDeque, sync::Arc};
 use core::cmp;
 use hashbrown::hash_map::{Entry, HashMap};
 use spin::{Mutex, Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
@@ -22,6 +22,11 @@ pub struct SessionData {
     pub(crate) breakpoint: Option<Breakpoint>,
     events: VecDeque<PtraceEvent>,
     file_id: usize,
+    /// Signal to deliver instead of the one reported by the last `PTRACE_STOP_SIGNAL` stop, with
+    /// 0 meaning the signal is dropped.
+    pub(crate) signal_override: Option<usize>,
+    /// Path of the image the process last executed, reported by `PTRACE_EVENT_ADDRSPACE_SWITCH`.
+    exec_path: Option<Cow<'static, str>>,
 }
 impl SessionData {
     fn add_event(&mut self, event: PtraceEvent) {
@@ -119,6 +124,8 @@ pub fn try_new_session(pid: ContextId, file_id: usize) -> bool {
                     breakpoint: None,
                     events: VecDeque::new(),
                     file_id,
+                    signal_override: None,
+                    exec_path: None,
                 }),
                 tracee: WaitCondition::new(),
                 tracer: WaitCondition::new(),
@@ -173,8 +180,30 @@ pub fn send_event(event: PtraceEvent) -> Option<()> {
         context.id
     };
 
+    send_event_to(id, event)
+}
+
+/// Send the `PTRACE_EVENT_ADDRSPACE_SWITCH` event of `pid` executing `path`, carrying the length
+/// of the path in `a` and the new instruction and stack pointers in `b` and `c`. The tracer reads
+/// the path itself from `proc:<pid>/exec-path`, which is kept until the next exec.
+pub fn exec_event(pid: ContextId, path: Cow<'static, str>, ip: usize, sp: usize) -> Option<()> {
+    let event = ptrace_event!(PTRACE_EVENT_ADDRSPACE_SWITCH, path.len(), ip, sp);
+    sessions().get(&pid)?.data.lock().exec_path = Some(path);
+
+    send_event_to(pid, event)
+}
+
+/// The path of the image `pid` last executed, if it was traced at the time.
+pub fn exec_path(pid: ContextId) -> Option<Cow<'static, str>> {
+    let sessions = sessions();
+    let session = sessions.get(&pid)?;
+    let data = session.data.lock();
+    data.exec_path.clone()
+}
+
+fn send_event_to(pid: ContextId, event: PtraceEvent) -> Option<()> {
     let sessions = sessions();
-    let session = sessions.get(&id)?;
+    let session = sessions.get(&pid)?;
     let mut data = session.data.lock();
     let breakpoint = data.breakpoint.as_ref()?;
 
@@ -282,6 +311,39 @@ pub fn breakpoint_callback(
     }
 }
 
+/// The event sent when stopping before a syscall, carrying the syscall number in `a` and the
+/// arguments in `b` through `f`.
+pub fn syscall_entry_event([a, b, c, d, e, f]: [usize; 6]) -> PtraceEvent {
+    PtraceEvent {
+        cause: PTRACE_STOP_PRE_SYSCALL,
+        a,
+        b,
+        c,
+        d,
+        e,
+        f,
+    }
+}
+
+/// The event sent when stopping after a syscall, carrying the syscall number in `a` and the
+/// (errno-muxed) return value in `b`.
+pub fn syscall_exit_event(number: usize, ret: usize) -> PtraceEvent {
+    ptrace_event!(PTRACE_STOP_POST_SYSCALL, number, ret)
+}
+
+/// Take the signal the tracer chose to deliver when resuming the current process from a
+/// `PTRACE_STOP_SIGNAL` stop, if any.
+pub fn take_signal_override() -> Option<usize> {
+    let contexts = context::contexts();
+    let context = contexts.current()?;
+    let context = context.read();
+
+    let sessions = sessions();
+    let session = sessions.get(&context.id)?;
+    let mut data = session.data.lock();
+    data.signal_override.take()
+}
+
 /// Obtain the next breakpoint flags for the current process. This is used for
 /// detecting whether or not the tracer decided to use sysemu mode.
 pub fn next_breakpoint() -> Option<PtraceFlags> {
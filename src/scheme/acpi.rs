@@ -1,25 +1,35 @@
 use core::{
     convert::TryInto,
-    str,
+    fmt::Write,
+    slice, str,
     sync::atomic::{self, AtomicUsize},
 };
 
-use alloc::{boxed::Box, collections::BTreeMap};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
 
 use spin::{Mutex, Once, RwLock};
 
 use crate::{
-    acpi::{RxsdtEnum, RXSDT_ENUM},
+    acpi::{
+        fadt::{self, FADT, FIXED_EVENTS},
+        get_sdt_signature,
+        rxsdt::Rxsdt,
+        sdt::Sdt,
+        RxsdtEnum, RXSDT_ENUM,
+    },
     event,
     sync::WaitCondition,
 };
 
 use crate::syscall::{
     data::Stat,
-    error::{Error, Result, EACCES, EBADF, EBADFD, EINTR, EINVAL, EISDIR, ENOENT, ENOTDIR, EROFS},
+    error::{
+        Error, Result, EACCES, EAGAIN, EBADF, EBADFD, EINTR, EINVAL, EISDIR, ENOENT, ENOTDIR,
+        EOPNOTSUPP, EROFS, ESPIPE,
+    },
     flag::{
         EventFlags, EVENT_READ, MODE_CHR, MODE_DIR, MODE_FILE, O_ACCMODE, O_CREAT, O_DIRECTORY,
-        O_EXCL, O_RDONLY, O_STAT, O_SYMLINK, SEEK_CUR, SEEK_END, SEEK_SET,
+        O_EXCL, O_NONBLOCK, O_RDONLY, O_STAT, O_SYMLINK, SEEK_CUR, SEEK_END, SEEK_SET,
     },
     usercopy::{UserSliceRo, UserSliceWo},
 };
@@ -27,18 +37,32 @@ use crate::syscall::{
 use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
 
 /// A scheme used to access the RSDT or XSDT, which is needed for e.g. `acpid` to function.
+///
+/// - `rxsdt` is the RSDT or XSDT, and `tables/` contains every table it points to and the DSDT,
+///   named by their signature, followed by a number from 1 if there are several, as `SSDT1`.
+/// - `kstop` becomes readable when the kernel is asked to shut down.
+/// - `events` reads the ACPI events the kernel received, one per line: `power_button`,
+///   `sleep_button`, `rtc`, or `gpe <n>`. Reads block unless `O_NONBLOCK` is set. A GPE is disabled
+///   until `gpe <n>` is written back, once its method has run.
+/// - Writing `S3 <SLP_TYPa> <SLP_TYPb>` to `sleep`, with the values of the `\_S3` object, puts the
+///   system to sleep and returns once it woke. Only available on x86_64.
 pub struct AcpiScheme;
 
 struct Handle {
     offset: usize,
     kind: HandleKind,
     stat: bool,
+    flags: usize,
 }
 #[derive(Eq, PartialEq)]
 enum HandleKind {
     TopLevel,
     Rxsdt,
     ShutdownPipe,
+    Events,
+    Sleep,
+    Tables,
+    Table(usize),
 }
 
 // Using BTreeMap as hashbrown doesn't have a const constructor.
@@ -47,7 +71,18 @@ static NEXT_FD: AtomicUsize = AtomicUsize::new(0);
 
 static DATA: Once<Box<[u8]>> = Once::new();
 
-const TOPLEVEL_CONTENTS: &[u8] = b"rxsdt\nkstop\n";
+struct Tables {
+    /// The names, one per line.
+    listing: Box<[u8]>,
+    tables: Vec<(String, &'static [u8])>,
+}
+static TABLES: Once<Tables> = Once::new();
+
+fn tables() -> Result<&'static Tables> {
+    TABLES.get().ok_or(Error::new(EBADFD))
+}
+
+const TOPLEVEL_CONTENTS: &[u8] = b"rxsdt\nkstop\nevents\nsleep\ntables\n";
 
 static KSTOP_WAITCOND: WaitCondition = WaitCondition::new();
 static KSTOP_FLAG: Mutex<bool> = Mutex::new(false);
@@ -76,6 +111,107 @@ pub fn register_kstop() -> bool {
     true
 }
 
+static EVENTS_WAITCOND: WaitCondition = WaitCondition::new();
+static EVENTS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Wake the readers of `events`, called from the SCI handler once events were queued.
+pub fn events_notify() {
+    {
+        let _guard = EVENTS_LOCK.lock();
+        EVENTS_WAITCOND.notify();
+    }
+
+    let handles = HANDLES.read();
+    for (&fd, _) in handles
+        .iter()
+        .filter(|(_, handle)| handle.kind == HandleKind::Events)
+    {
+        event::trigger(GlobalSchemes::Acpi.scheme_id(), fd, EVENT_READ);
+    }
+}
+
+/// Format the queued events, one per line, as read from `events`. Only whole lines that fit in
+/// `len` bytes are taken, and the remaining events stay queued for the next read.
+fn take_events(len: usize) -> Result<String> {
+    let (mut fixed, mut gpes) = fadt::take_events();
+    let mut string = String::new();
+    let mut full = false;
+    let mut push = |line: String| {
+        full |= string.len() + line.len() > len;
+        if !full {
+            string.push_str(&line);
+        }
+        !full
+    };
+    for (bit, name) in FIXED_EVENTS {
+        if fixed & bit != 0 && push(format!("{}\n", name)) {
+            fixed &= !bit;
+        }
+    }
+    for (index, bits) in gpes.iter_mut().enumerate() {
+        for bit in 0..64 {
+            if *bits & (1 << bit) != 0 && push(format!("gpe {}\n", index * 64 + bit)) {
+                *bits &= !(1 << bit);
+            }
+        }
+    }
+    fadt::requeue_events(fixed, &gpes);
+
+    // Not even one event fits.
+    if string.is_empty() {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(string)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn suspend(slp_typa: u16, slp_typb: u16) -> Result<()> {
+    unsafe { crate::arch::sleep::suspend(slp_typa, slp_typb) }
+}
+#[cfg(not(target_arch = "x86_64"))]
+fn suspend(_slp_typa: u16, _slp_typb: u16) -> Result<()> {
+    Err(Error::new(EOPNOTSUPP))
+}
+
+/// The tables pointed to by the RSDT or XSDT, and the DSDT, in that order.
+fn collect_tables() -> Tables {
+    let mut sdts: Vec<&'static Sdt> = Vec::new();
+    if let Some(rxsdt) = RXSDT_ENUM.get() {
+        for sdt_address in rxsdt.iter() {
+            sdts.push(unsafe { &*((sdt_address + crate::PHYS_OFFSET) as *const Sdt) });
+        }
+    }
+    if let Some(dsdt) = FADT.get().and_then(|fadt| fadt.dsdt()) {
+        sdts.push(dsdt);
+    }
+
+    let mut tables = Vec::with_capacity(sdts.len());
+    let mut listing = String::new();
+    for (index, &sdt) in sdts.iter().enumerate() {
+        let signature = get_sdt_signature(sdt).0;
+        let same = |other: &&Sdt| other.signature == sdt.signature;
+        let name = if sdts.iter().filter(same).count() > 1 {
+            format!(
+                "{}{}",
+                signature,
+                sdts[..=index].iter().filter(same).count()
+            )
+        } else {
+            signature
+        };
+        let _ = writeln!(listing, "{}", name);
+
+        let data =
+            unsafe { slice::from_raw_parts(sdt as *const Sdt as *const u8, sdt.length as usize) };
+        tables.push((name, data));
+    }
+
+    Tables {
+        listing: listing.into_bytes().into_boxed_slice(),
+        tables,
+    }
+}
+
 impl AcpiScheme {
     pub fn init() {
         // NOTE: This __must__ be called from the main kernel context, while initializing all
@@ -102,6 +238,8 @@ impl AcpiScheme {
         if !data_init {
             log::error!("AcpiScheme::init called multiple times");
         }
+
+        TABLES.call_once(collect_tables);
     }
 }
 
@@ -118,7 +256,10 @@ impl KernelScheme for AcpiScheme {
         if flags & O_EXCL == O_EXCL || flags & O_SYMLINK == O_SYMLINK {
             return Err(Error::new(EINVAL));
         }
-        if flags & O_ACCMODE != O_RDONLY && flags & O_STAT != O_STAT {
+        if flags & O_ACCMODE != O_RDONLY
+            && flags & O_STAT != O_STAT
+            && !matches!(path, "events" | "sleep")
+        {
             return Err(Error::new(EROFS));
         }
         let handle_kind = match path {
@@ -141,7 +282,33 @@ impl KernelScheme for AcpiScheme {
                 }
                 HandleKind::ShutdownPipe
             }
-            _ => return Err(Error::new(ENOENT)),
+            "events" | "sleep" => {
+                if flags & O_DIRECTORY == O_DIRECTORY && flags & O_STAT != O_STAT {
+                    return Err(Error::new(ENOTDIR));
+                }
+                if path == "events" {
+                    HandleKind::Events
+                } else {
+                    HandleKind::Sleep
+                }
+            }
+            "tables" => {
+                if flags & O_DIRECTORY != O_DIRECTORY && flags & O_STAT != O_STAT {
+                    return Err(Error::new(EISDIR));
+                }
+                HandleKind::Tables
+            }
+            _ => {
+                let name = path.strip_prefix("tables/").ok_or(Error::new(ENOENT))?;
+                let index = TABLES
+                    .get()
+                    .and_then(|tables| tables.tables.iter().position(|(n, _)| n == name))
+                    .ok_or(Error::new(ENOENT))?;
+                if flags & O_DIRECTORY == O_DIRECTORY && flags & O_STAT != O_STAT {
+                    return Err(Error::new(ENOTDIR));
+                }
+                HandleKind::Table(index)
+            }
         };
 
         let fd = NEXT_FD.fetch_add(1, atomic::Ordering::Relaxed);
@@ -153,6 +320,7 @@ impl KernelScheme for AcpiScheme {
                 offset: 0,
                 kind: handle_kind,
                 stat: flags & O_STAT == O_STAT,
+                flags,
             },
         );
 
@@ -170,6 +338,9 @@ impl KernelScheme for AcpiScheme {
             HandleKind::Rxsdt => DATA.get().ok_or(Error::new(EBADFD))?.len(),
             HandleKind::ShutdownPipe => 1,
             HandleKind::TopLevel => TOPLEVEL_CONTENTS.len(),
+            HandleKind::Events | HandleKind::Sleep => return Err(Error::new(ESPIPE)),
+            HandleKind::Tables => tables()?.listing.len(),
+            HandleKind::Table(index) => tables()?.tables[index].1.len(),
         };
 
         let new_offset = match whence {
@@ -209,6 +380,9 @@ impl KernelScheme for AcpiScheme {
             return Err(Error::new(EBADF));
         }
 
+        if handle.kind == HandleKind::Events && fadt::events_pending() {
+            return Ok(EVENT_READ);
+        }
         Ok(EventFlags::empty())
     }
     fn close(&self, id: usize) -> Result<()> {
@@ -217,8 +391,46 @@ impl KernelScheme for AcpiScheme {
         }
         Ok(())
     }
-    fn kwrite(&self, _id: usize, _buf: UserSliceRo) -> Result<usize> {
-        Err(Error::new(EBADF))
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        let kind = {
+            let handles = HANDLES.read();
+            let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+            if handle.stat || handle.flags & O_ACCMODE == O_RDONLY {
+                return Err(Error::new(EBADF));
+            }
+            match handle.kind {
+                HandleKind::Events => HandleKind::Events,
+                HandleKind::Sleep => HandleKind::Sleep,
+                _ => return Err(Error::new(EBADF)),
+            }
+        };
+
+        let mut bytes = [0; 64];
+        let len = buf.copy_common_bytes_to_slice(&mut bytes)?;
+        let text = str::from_utf8(&bytes[..len]).map_err(|_| Error::new(EINVAL))?;
+        let mut words = text.split_whitespace();
+
+        if kind == HandleKind::Events {
+            let gpe = match (words.next(), words.next(), words.next()) {
+                (Some("gpe"), Some(gpe), None) => gpe.parse().map_err(|_| Error::new(EINVAL))?,
+                _ => return Err(Error::new(EINVAL)),
+            };
+            let fadt = FADT.get().ok_or(Error::new(EBADFD))?;
+            if !fadt.enable_gpe(gpe) {
+                return Err(Error::new(EINVAL));
+            }
+            return Ok(len);
+        }
+
+        let (slp_typa, slp_typb) = match (words.next(), words.next(), words.next(), words.next()) {
+            (Some("S3"), Some(a), Some(b), None) => (
+                a.parse().map_err(|_| Error::new(EINVAL))?,
+                b.parse().map_err(|_| Error::new(EINVAL))?,
+            ),
+            _ => return Err(Error::new(EINVAL)),
+        };
+        suspend(slp_typa, slp_typb)?;
+        Ok(len)
     }
     fn kread(&self, id: usize, dst_buf: UserSliceWo) -> Result<usize> {
         let mut handles = HANDLES.write();
@@ -248,8 +460,27 @@ impl KernelScheme for AcpiScheme {
                 handle.offset = 1;
                 return Ok(1);
             }
+            HandleKind::Events => {
+                let nonblock = handle.flags & O_NONBLOCK == O_NONBLOCK;
+                drop(handles);
+                loop {
+                    let guard = EVENTS_LOCK.lock();
+                    if fadt::events_pending() {
+                        break;
+                    } else if nonblock {
+                        return Err(Error::new(EAGAIN));
+                    } else if !EVENTS_WAITCOND.wait(guard, "waiting for ACPI events") {
+                        return Err(Error::new(EINTR));
+                    }
+                }
+                let events = take_events(dst_buf.len())?;
+                return dst_buf.copy_common_bytes_from_slice(events.as_bytes());
+            }
+            HandleKind::Sleep => return Err(Error::new(EBADF)),
             HandleKind::Rxsdt => DATA.get().ok_or(Error::new(EBADFD))?,
             HandleKind::TopLevel => TOPLEVEL_CONTENTS,
+            HandleKind::Tables => &tables()?.listing,
+            HandleKind::Table(index) => tables()?.tables[index].1,
         };
 
         let src_offset = core::cmp::min(handle.offset, data.len());
@@ -289,6 +520,22 @@ impl KernelScheme for AcpiScheme {
                 st_size: 1,
                 ..Default::default()
             },
+            HandleKind::Events | HandleKind::Sleep => Stat {
+                st_mode: MODE_CHR | 0o600,
+                ..Default::default()
+            },
+            HandleKind::Tables => Stat {
+                st_mode: MODE_DIR,
+                st_size: TABLES.get().map_or(0, |tables| tables.listing.len() as u64),
+                ..Default::default()
+            },
+            HandleKind::Table(index) => Stat {
+                st_mode: MODE_FILE,
+                st_size: TABLES
+                    .get()
+                    .map_or(0, |tables| tables.tables[index].1.len() as u64),
+                ..Default::default()
+            },
         })?;
 
         Ok(())
@@ -1,53 +1,229 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
-use spin::RwLock;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
 
 use crate::{
     arch::debug::Writer,
     event,
+    log::{parse_tag, LevelFilter, LOG},
     scheme::*,
-    sync::WaitQueue,
+    sync::{WaitCondition, WaitQueue},
     syscall::{
-        flag::{EventFlags, EVENT_READ, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK},
+        ext::{
+            F_GETLFLAG, F_GETLOGFILTER, F_GETRCVTIMEO, F_SETLFLAG, F_SETLOGFILTER, F_SETRCVTIMEO,
+            LFLAG_ECHO, LFLAG_ICANON, LOG_FILTER_SUBSYSTEM_SHIFT,
+        },
+        flag::{EventFlags, EVENT_READ, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK, O_RDONLY},
+        fs::rcvtimeo_deadline,
         usercopy::{UserSliceRo, UserSliceWo},
     },
 };
 
+/// Filter passing every line.
+const LOG_FILTER_ALL: usize = LevelFilter::Trace as usize | !0 << LOG_FILTER_SUBSYSTEM_SHIFT;
+
+/// Maximum length of a line in canonical mode, beyond which input is dropped.
+const MAX_CANON: usize = 1024;
+/// Maximum number of echoed bytes pending output.
+const MAX_ECHO: usize = 256;
+
+/// Handle number of `debug:log`, a read-only view of the kernel log.
+const LOG_NUM: usize = !1;
+
+/// Readers of `debug:log` blocked for new output, waiting with [`LOG_WAIT`] held.
+static LOG_CONDITION: WaitCondition = WaitCondition::new();
+static LOG_WAIT: Mutex<()> = Mutex::new(());
+/// The offset of the kernel log, counted from boot, up to which readers were notified.
+static LOG_NOTIFIED: AtomicUsize = AtomicUsize::new(0);
+
 static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
 
 /// Input queue
 static INPUT: WaitQueue<u8> = WaitQueue::new();
+/// Whether readers were notified of input that has not been drained yet, so that events are only
+/// sent when input becomes available.
+static INPUT_NOTIFIED: AtomicBool = AtomicBool::new(false);
+
+/// Line discipline applied to input before it reaches the input queue.
+///
+/// Input arrives in interrupt handlers with the serial port locked, so buffers are fixed in size,
+/// and echo is only written out by [`debug_notify`].
+struct LineDiscipline {
+    flags: usize,
+    line: [u8; MAX_CANON],
+    line_len: usize,
+    echo: [u8; MAX_ECHO],
+    echo_len: usize,
+}
+
+static LDISC: Mutex<LineDiscipline> = Mutex::new(LineDiscipline {
+    // Raw mode without echo, as consumers of debug: used to implement line editing themselves.
+    flags: 0,
+    line: [0; MAX_CANON],
+    line_len: 0,
+    echo: [0; MAX_ECHO],
+    echo_len: 0,
+});
+
+impl LineDiscipline {
+    fn input(&mut self, byte: u8) {
+        if self.flags & LFLAG_ICANON == 0 {
+            INPUT.send(byte);
+            self.echo(&[byte]);
+            return;
+        }
+
+        match byte {
+            // Complete the line, translating carriage returns as terminals send them on enter.
+            b'\r' | b'\n' => {
+                self.flush_line();
+                INPUT.send(b'\n');
+                self.echo(b"\r\n");
+            }
+            // Erase the last character, on either backspace or delete.
+            0x08 | 0x7F => {
+                if self.line_len > 0 {
+                    self.line_len -= 1;
+                    self.echo(b"\x08 \x08");
+                }
+            }
+            // Kill the whole line, on ^U.
+            0x15 => {
+                while self.line_len > 0 {
+                    self.line_len -= 1;
+                    self.echo(b"\x08 \x08");
+                }
+            }
+            // Deliver the line without a newline, on ^D.
+            0x04 => self.flush_line(),
+            _ => {
+                if self.line_len < MAX_CANON {
+                    self.line[self.line_len] = byte;
+                    self.line_len += 1;
+                    self.echo(&[byte]);
+                }
+            }
+        }
+    }
+
+    fn flush_line(&mut self) {
+        for &byte in &self.line[..self.line_len] {
+            INPUT.send(byte);
+        }
+        self.line_len = 0;
+    }
+
+    fn echo(&mut self, bytes: &[u8]) {
+        if self.flags & LFLAG_ECHO == 0 {
+            return;
+        }
+        let count = bytes.len().min(MAX_ECHO - self.echo_len);
+        self.echo[self.echo_len..self.echo_len + count].copy_from_slice(&bytes[..count]);
+        self.echo_len += count;
+    }
+
+    fn set_flags(&mut self, flags: usize) -> Result<()> {
+        if flags & !(LFLAG_ICANON | LFLAG_ECHO) != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        // A partial line is passed on as is when leaving canonical mode.
+        if flags & LFLAG_ICANON == 0 {
+            self.flush_line();
+        }
+        self.flags = flags;
+        Ok(())
+    }
+}
 
 #[derive(Clone, Copy)]
 struct Handle {
     flags: usize,
     num: usize,
+    /// Read timeout in microseconds, or 0.
+    read_timeout: usize,
+    /// Position in the kernel log, counted from boot, for `debug:log`.
+    log_offset: usize,
+    /// Lines of the kernel log read, for `debug:log`.
+    log_filter: usize,
 }
 
 // Using BTreeMap as hashbrown doesn't have a const constructor.
 static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
 
-/// Add to the input queue
+/// Add to the input queue, through the line discipline
 pub fn debug_input(data: u8) {
-    INPUT.send(data);
+    LDISC.lock().input(data);
 }
 
-// Notify readers of input updates
+// Notify readers of input updates, and write out the echo of the input. Must be called with the
+// serial ports unlocked.
 pub fn debug_notify() {
+    let mut echo = [0_u8; MAX_ECHO];
+    let echo_len = {
+        let mut ldisc = LDISC.lock();
+        let len = ldisc.echo_len;
+        echo[..len].copy_from_slice(&ldisc.echo[..len]);
+        ldisc.echo_len = 0;
+        len
+    };
+    if echo_len > 0 {
+        Writer::raw().write(&echo[..echo_len]);
+    }
+
+    if INPUT.inner.lock().is_empty() {
+        return;
+    }
+    if INPUT_NOTIFIED.swap(true, Ordering::SeqCst) {
+        return;
+    }
     for (id, _handle) in HANDLES.read().iter() {
         event::trigger(GlobalSchemes::Debug.scheme_id(), *id, EVENT_READ);
     }
 }
 
+/// Notify readers of `debug:log` of output since the last notification, from the scheduler tick.
+/// Output cannot notify readers as it is printed, since that may be with any lock held.
+pub fn log_tick() {
+    let Some(log) = LOG.try_lock() else {
+        return;
+    };
+    let written = log.written();
+    drop(log);
+    if LOG_NOTIFIED.swap(written, Ordering::Relaxed) != written {
+        log_notify();
+    }
+}
+
+/// Notify readers of `debug:log` of new output right away, for output worth waking a log reader
+/// for, such as lockup reports. Never called with locks held that event delivery may take.
+pub fn log_notify() {
+    drop(LOG_WAIT.lock());
+    LOG_CONDITION.notify();
+    for (id, _handle) in HANDLES
+        .read()
+        .iter()
+        .filter(|(_, handle)| handle.num == LOG_NUM)
+    {
+        event::trigger(GlobalSchemes::Debug.scheme_id(), *id, EVENT_READ);
+    }
+}
+
 pub struct DebugScheme;
 
 impl KernelScheme for DebugScheme {
     fn kopen(&self, path: &str, flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
-        if ctx.uid != 0 {
+        // Reading the kernel log may be granted to a group, while console input and output remain
+        // limited to root.
+        if path == "log" {
+            if flags & O_ACCMODE != O_RDONLY || !crate::log::may_read(ctx.uid, ctx.gid) {
+                return Err(Error::new(EACCES));
+            }
+        } else if ctx.uid != 0 {
             return Err(Error::new(EPERM));
         }
 
         let num = match path {
             "" => !0,
+            "log" => LOG_NUM,
 
             #[cfg(feature = "profiling")]
             p if p.starts_with("profiling-") => {
@@ -63,6 +239,9 @@ impl KernelScheme for DebugScheme {
             Handle {
                 flags: flags & !O_ACCMODE,
                 num,
+                read_timeout: 0,
+                log_offset: 0,
+                log_filter: LOG_FILTER_ALL,
             },
         );
 
@@ -78,6 +257,26 @@ impl KernelScheme for DebugScheme {
                     handle.flags = arg & !O_ACCMODE;
                     Ok(0)
                 }
+                F_GETRCVTIMEO => Ok(handle.read_timeout),
+                F_SETRCVTIMEO => {
+                    handle.read_timeout = arg;
+                    Ok(0)
+                }
+                F_GETLOGFILTER if handle.num == LOG_NUM => Ok(handle.log_filter),
+                F_SETLOGFILTER if handle.num == LOG_NUM => {
+                    if arg & 0xFF > LevelFilter::Trace as usize {
+                        return Err(Error::new(EINVAL));
+                    }
+                    handle.log_filter = arg;
+                    Ok(0)
+                }
+                F_GETLFLAG => Ok(LDISC.lock().flags),
+                F_SETLFLAG => {
+                    LDISC.lock().set_flags(arg)?;
+                    drop(handles);
+                    debug_notify();
+                    Ok(0)
+                }
                 _ => Err(Error::new(EINVAL)),
             }
         } else {
@@ -85,13 +284,55 @@ impl KernelScheme for DebugScheme {
         }
     }
 
-    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
-        let _handle = {
+    /// Move the position of a `debug:log` handle, in bytes of the kernel log since boot. Offsets
+    /// before the oldest output kept are read from the oldest, and `SEEK_END` skips to new output.
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<usize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        if handle.num != LOG_NUM {
+            return Err(Error::new(ESPIPE));
+        }
+        let written = LOG.lock().written();
+        handle.log_offset = calc_seek_offset(handle.log_offset, pos, whence, written)?;
+        Ok(handle.log_offset)
+    }
+
+    /// Clear the kernel log through `debug:log`, by truncating it to 0 bytes, which only root may.
+    fn ftruncate(&self, id: usize, len: usize) -> Result<()> {
+        let handle = {
+            let handles = HANDLES.read();
+            *handles.get(&id).ok_or(Error::new(EBADF))?
+        };
+        if handle.num != LOG_NUM {
+            return Err(Error::new(EBADF));
+        }
+        if len != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        if crate::context::current()?.read().euid != 0 {
+            return Err(Error::new(EPERM));
+        }
+        LOG.lock().clear();
+        Ok(())
+    }
+
+    fn fevent(&self, id: usize, flags: EventFlags) -> Result<EventFlags> {
+        let handle = {
             let handles = HANDLES.read();
             *handles.get(&id).ok_or(Error::new(EBADF))?
         };
 
-        Ok(EventFlags::empty())
+        if handle.num == LOG_NUM && flags.contains(EVENT_READ) {
+            if LOG.lock().written() > handle.log_offset {
+                Ok(EVENT_READ)
+            } else {
+                Ok(EventFlags::empty())
+            }
+        } else if handle.num == !0 && flags.contains(EVENT_READ) && !INPUT.inner.lock().is_empty() {
+            Ok(EVENT_READ)
+        } else {
+            Ok(EventFlags::empty())
+        }
     }
 
     fn fsync(&self, id: usize) -> Result<()> {
@@ -113,27 +354,88 @@ impl KernelScheme for DebugScheme {
         Ok(())
     }
     fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        self.kreadv(id, &[buf])
+    }
+    fn kreadv(&self, id: usize, bufs: &[UserSliceWo]) -> Result<usize> {
         let handle = {
             let handles = HANDLES.read();
             *handles.get(&id).ok_or(Error::new(EBADF))?
         };
 
+        if handle.num == LOG_NUM {
+            let deadline = rcvtimeo_deadline(handle.read_timeout);
+            loop {
+                let written = LOG.lock().written();
+                let bytes_read = read_log_bufs(id, handle.log_filter, bufs)?;
+                if bytes_read > 0
+                    || handle.flags & O_NONBLOCK == O_NONBLOCK
+                    || bufs.iter().all(|buf| buf.is_empty())
+                {
+                    return Ok(bytes_read);
+                }
+                // Nothing passed the filter, so wait for output after what was there.
+                wait_log(written, deadline).map_err(|err| match err.errno {
+                    ETIMEDOUT => Error::new(EAGAIN),
+                    _ => err,
+                })?;
+            }
+        }
+
         #[cfg(feature = "profiling")]
         if handle.num != !0 {
             return crate::profiling::drain_buffer(
                 crate::cpu_set::LogicalCpuId::new(handle.num as u32),
-                buf,
+                first_nonempty(bufs),
             );
         }
 
-        INPUT.receive_into_user(
-            buf,
-            handle.flags & O_NONBLOCK != O_NONBLOCK,
-            "DebugScheme::read",
-        )
+        // Only the first read may block, the other buffers take what input is left.
+        let start = bufs
+            .iter()
+            .position(|buf| !buf.is_empty())
+            .unwrap_or(bufs.len());
+        let first = bufs.get(start).copied().unwrap_or(UserSliceWo::empty());
+        let mut result = INPUT
+            .receive_timeout(
+                first,
+                handle.flags & O_NONBLOCK != O_NONBLOCK,
+                "DebugScheme::read",
+                rcvtimeo_deadline(handle.read_timeout),
+            )
+            .map_err(|err| match err.errno {
+                ETIMEDOUT => Error::new(EAGAIN),
+                _ => err,
+            });
+        if let Ok(ref mut bytes_read) = result {
+            if *bytes_read == first.len() {
+                for &buf in bufs.get(start + 1..).unwrap_or(&[]) {
+                    match INPUT.receive_timeout(buf, false, "DebugScheme::read", None) {
+                        Ok(count) => {
+                            *bytes_read += count;
+                            if count < buf.len() {
+                                break;
+                            }
+                        }
+                        Err(_) => break,
+                    }
+                }
+            }
+        }
+
+        // Checked with the queue locked, so that input arriving afterwards is notified again.
+        let input = INPUT.inner.lock();
+        if input.is_empty() {
+            INPUT_NOTIFIED.store(false, Ordering::SeqCst);
+        }
+        drop(input);
+
+        result
     }
 
     fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        self.kwritev(id, &[buf])
+    }
+    fn kwritev(&self, id: usize, bufs: &[UserSliceRo]) -> Result<usize> {
         let handle = {
             let handles = HANDLES.read();
             *handles.get(&id).ok_or(Error::new(EBADF))?
@@ -143,18 +445,27 @@ impl KernelScheme for DebugScheme {
         }
 
         let mut tmp = [0_u8; 512];
+        let mut bytes_written = 0;
 
-        for chunk in buf.in_variable_chunks(tmp.len()) {
-            let byte_count = chunk.copy_common_bytes_to_slice(&mut tmp)?;
-            let tmp_bytes = &tmp[..byte_count];
+        for buf in bufs {
+            for chunk in buf.in_variable_chunks(tmp.len()) {
+                let byte_count = match chunk.copy_common_bytes_to_slice(&mut tmp) {
+                    Ok(count) => count,
+                    Err(_) if bytes_written > 0 => return Ok(bytes_written),
+                    Err(error) => return Err(error),
+                };
+                let tmp_bytes = &tmp[..byte_count];
 
-            // The reason why a new writer is created for each iteration, is because the page fault
-            // handler in usercopy might use the same lock when printing for debug purposes, and
-            // although it most likely won't, it would be dangerous to rely on that assumption.
-            Writer::new().write(tmp_bytes);
+                // The reason why a new writer is created for each iteration, is because the page
+                // fault handler in usercopy might use the same lock when printing for debug
+                // purposes, and although it most likely won't, it would be dangerous to rely on
+                // that assumption.
+                Writer::raw().write(tmp_bytes);
+                bytes_written += byte_count;
+            }
         }
 
-        Ok(buf.len())
+        Ok(bytes_written)
     }
     fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
         let handle = {
@@ -175,3 +486,100 @@ impl KernelScheme for DebugScheme {
         Ok(byte_count)
     }
 }
+
+/// Read the kernel log into `bufs` in turn for the `debug:log` handle `id`, from its offset.
+fn read_log_bufs(id: usize, filter: usize, bufs: &[UserSliceWo]) -> Result<usize> {
+    let mut bytes_read = 0;
+    for &buf in bufs {
+        let offset = match HANDLES.read().get(&id) {
+            Some(handle) => handle.log_offset,
+            None => break,
+        };
+        let count = match read_log(id, offset, filter, buf) {
+            Ok(count) => count,
+            Err(_) if bytes_read > 0 => break,
+            Err(error) => return Err(error),
+        };
+        bytes_read += count;
+        if count < buf.len() {
+            break;
+        }
+    }
+    Ok(bytes_read)
+}
+
+/// Block until the kernel log has more than `written` bytes written since boot, or the monotonic
+/// time `deadline` is reached, if any.
+fn wait_log(written: usize, deadline: Option<u128>) -> Result<()> {
+    loop {
+        // Not waiting with the log itself locked, as it is locked when printing with any other
+        // lock held.
+        let guard = LOG_WAIT.lock();
+        if LOG.lock().written() > written {
+            return Ok(());
+        }
+        if !LOG_CONDITION.wait_until(guard, "DebugScheme::read_log", deadline)? {
+            return Err(Error::new(EINTR));
+        }
+    }
+}
+
+/// Read the kernel log from `offset` for the `debug:log` handle `id`, skipping ahead if older
+/// output was overwritten. Unless `filter` passes everything, only whole lines passing it are
+/// read. Returns 0 once all output so far has been read.
+fn read_log(id: usize, mut offset: usize, filter: usize, buf: UserSliceWo) -> Result<usize> {
+    let mut tmp = [0_u8; 512];
+    let mut bytes_read = 0;
+
+    while bytes_read < buf.len() {
+        // Not copying to userspace with the log locked, as page faults may print.
+        let len = if filter == LOG_FILTER_ALL {
+            tmp.len().min(buf.len() - bytes_read)
+        } else {
+            tmp.len()
+        };
+        let (start, count) = LOG.lock().read_at(offset, &mut tmp[..len]);
+        if count == 0 {
+            break;
+        }
+        if filter == LOG_FILTER_ALL {
+            buf.advance(bytes_read)
+                .ok_or(Error::new(EINVAL))?
+                .copy_common_bytes_from_slice(&tmp[..count])?;
+            offset = start + count;
+            bytes_read += count;
+            continue;
+        }
+
+        let mut consumed = 0;
+        for line in tmp[..count].split_inclusive(|&b| b == b'\n') {
+            // A line still being written is left for later, unless too long to ever fit.
+            if !line.ends_with(b"\n") && (consumed > 0 || count < tmp.len()) {
+                break;
+            }
+            if passes_filter(filter, line) {
+                let remaining = buf.advance(bytes_read).ok_or(Error::new(EINVAL))?;
+                if remaining.len() < line.len() && bytes_read > 0 {
+                    break;
+                }
+                bytes_read += remaining.copy_common_bytes_from_slice(line)?;
+            }
+            consumed += line.len();
+        }
+        if consumed == 0 {
+            break;
+        }
+        offset = start + consumed;
+    }
+
+    if let Some(handle) = HANDLES.write().get_mut(&id) {
+        handle.log_offset = offset;
+    }
+    Ok(bytes_read)
+}
+
+fn passes_filter(filter: usize, line: &[u8]) -> bool {
+    let tag = parse_tag(line);
+    tag.level as usize <= filter & 0xFF
+        && filter >> LOG_FILTER_SUBSYSTEM_SHIFT & tag.subsystem.bit() != 0
+}
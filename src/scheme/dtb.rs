@@ -1,16 +1,32 @@
-use core::sync::atomic::{self, AtomicUsize};
+//! `kernel.dtb:` - the flattened device tree passed by the bootloader.
+//!
+//! Opening `kernel.dtb:` gives the whole blob. Nodes and properties can also be looked up by path,
+//! from the root node at `kernel.dtb:/`, as in `kernel.dtb:/soc/serial@9000000/reg` or
+//! `kernel.dtb:/chosen/bootargs`. A node is a directory listing its properties and then its
+//! children, which end with `/`, one per line, and a property reads as its raw value. A path
+//! component without a unit address matches the first child of that name, so
+//! `kernel.dtb:/soc/serial` finds `serial@9000000` too.
+//!
+//! `kernel.dtb:memreserve` lists the memory reserved by the firmware, as `base size` lines in
+//! hexadecimal: the entries of the memory reservation block, followed by the `reg` ranges of the
+//! children of `/reserved-memory`.
+
+use core::{
+    fmt::Write,
+    str,
+    sync::atomic::{self, AtomicUsize},
+};
 
-use alloc::{boxed::Box, collections::BTreeMap};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
 use spin::{Once, RwLock};
 
 use super::{CallerCtx, KernelScheme, OpenResult};
 use crate::{
     dtb::DTB_BINARY,
-    scheme::SchemeId,
     syscall::{
         data::Stat,
         error::*,
-        flag::{MODE_FILE, O_STAT, SEEK_CUR, SEEK_END, SEEK_SET},
+        flag::{MODE_DIR, MODE_FILE, O_DIRECTORY, O_STAT, SEEK_CUR, SEEK_END, SEEK_SET},
         usercopy::{UserSliceRo, UserSliceWo},
     },
 };
@@ -20,6 +36,10 @@ pub struct DtbScheme;
 #[derive(Eq, PartialEq)]
 enum HandleKind {
     RawData,
+    /// The listing of a node.
+    Node(Box<[u8]>),
+    /// The value of a property, or the reserved memory ranges.
+    Data(Box<[u8]>),
 }
 
 struct Handle {
@@ -32,6 +52,199 @@ static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
 static NEXT_FD: AtomicUsize = AtomicUsize::new(0);
 static DATA: Once<Box<[u8]>> = Once::new();
 
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Read a number made of big-endian cells, as in `reg`.
+fn cells(data: &[u8]) -> u64 {
+    data.chunks_exact(4).fold(0, |acc, cell| {
+        (acc << 32) | u64::from(u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+    })
+}
+
+fn cstr(data: &[u8]) -> Option<&str> {
+    let len = data.iter().position(|&b| b == 0)?;
+    str::from_utf8(&data[..len]).ok()
+}
+
+fn align4(offset: usize) -> usize {
+    offset.next_multiple_of(4)
+}
+
+enum Token<'a> {
+    BeginNode(&'a str),
+    EndNode,
+    Prop(&'a str, &'a [u8]),
+}
+
+enum Entry<'a> {
+    Prop(&'a str, &'a [u8]),
+    /// A child node, and the offset of its contents.
+    Child(&'a str, usize),
+}
+
+/// The structure and strings blocks of a device tree blob, and its memory reservation block.
+struct Fdt<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+    reservations: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        if be32(data, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let off_structs = be32(data, 8)? as usize;
+        let off_strings = be32(data, 12)? as usize;
+        let off_reservations = be32(data, 16)? as usize;
+        let size_strings = be32(data, 32)? as usize;
+        let size_structs = be32(data, 36)? as usize;
+
+        Some(Self {
+            structs: data.get(off_structs..off_structs.checked_add(size_structs)?)?,
+            strings: data.get(off_strings..off_strings.checked_add(size_strings)?)?,
+            reservations: data.get(off_reservations..)?,
+        })
+    }
+
+    /// Read the token at `offset`, skipping `FDT_NOP`s, and advance past it. Returns `None` at
+    /// `FDT_END`, or if the blob is malformed.
+    fn token(&self, offset: &mut usize) -> Option<Token<'a>> {
+        loop {
+            let token = be32(self.structs, *offset)?;
+            *offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(self.structs.get(*offset..)?)?;
+                    *offset = align4(*offset + name.len() + 1);
+                    return Some(Token::BeginNode(name));
+                }
+                FDT_END_NODE => return Some(Token::EndNode),
+                FDT_PROP => {
+                    let len = be32(self.structs, *offset)? as usize;
+                    let name = cstr(
+                        self.strings
+                            .get(be32(self.structs, *offset + 4)? as usize..)?,
+                    )?;
+                    let value = self
+                        .structs
+                        .get(*offset + 8..(*offset + 8).checked_add(len)?)?;
+                    *offset = align4(*offset + 8 + len);
+                    return Some(Token::Prop(name, value));
+                }
+                FDT_NOP => continue,
+                _ => return None,
+            }
+        }
+    }
+
+    /// The properties and children of the node whose contents start at `offset`.
+    fn entries(&self, mut offset: usize) -> Option<Vec<Entry<'a>>> {
+        let mut entries = Vec::new();
+        let mut depth = 0_usize;
+        loop {
+            match self.token(&mut offset)? {
+                Token::BeginNode(name) => {
+                    if depth == 0 {
+                        entries.push(Entry::Child(name, offset));
+                    }
+                    depth += 1;
+                }
+                Token::EndNode => match depth.checked_sub(1) {
+                    Some(parent) => depth = parent,
+                    None => return Some(entries),
+                },
+                Token::Prop(name, value) => {
+                    if depth == 0 {
+                        entries.push(Entry::Prop(name, value));
+                    }
+                }
+            }
+        }
+    }
+
+    fn property(&self, node: usize, name: &str) -> Option<&'a [u8]> {
+        self.entries(node)?
+            .into_iter()
+            .find_map(|entry| match entry {
+                Entry::Prop(prop, value) if prop == name => Some(value),
+                _ => None,
+            })
+    }
+
+    /// Find the node at `path`, returning the offset of its contents.
+    fn find_node(&self, path: &str) -> Option<usize> {
+        let mut offset = 0;
+        let Token::BeginNode(_) = self.token(&mut offset)? else {
+            return None;
+        };
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            offset = self
+                .entries(offset)?
+                .into_iter()
+                .find_map(|entry| match entry {
+                    Entry::Child(name, child)
+                        if name == component || name.split('@').next() == Some(component) =>
+                    {
+                        Some(child)
+                    }
+                    _ => None,
+                })?;
+        }
+        Some(offset)
+    }
+
+    /// The reserved memory ranges, from the memory reservation block and `/reserved-memory`.
+    fn reserved(&self) -> Vec<(u64, u64)> {
+        let mut ranges = Vec::new();
+        for entry in self.reservations.chunks_exact(16) {
+            let (base, size) = (cells(&entry[..8]), cells(&entry[8..]));
+            if base == 0 && size == 0 {
+                break;
+            }
+            ranges.push((base, size));
+        }
+
+        let Some(node) = self.find_node("/reserved-memory") else {
+            return ranges;
+        };
+        let cell_count = |name, default| {
+            self.property(node, name)
+                .and_then(|value| be32(value, 0))
+                .map_or(default, |count| count as usize)
+        };
+        let address_cells = cell_count("#address-cells", 2);
+        let size_cells = cell_count("#size-cells", 1);
+        let stride = (address_cells + size_cells) * 4;
+        if stride == 0 {
+            return ranges;
+        }
+
+        for entry in self.entries(node).unwrap_or_default() {
+            let Entry::Child(_, child) = entry else {
+                continue;
+            };
+            let Some(reg) = self.property(child, "reg") else {
+                continue;
+            };
+            for range in reg.chunks_exact(stride) {
+                let (base, size) = range.split_at(address_cells * 4);
+                ranges.push((cells(base), cells(size)));
+            }
+        }
+        ranges
+    }
+}
+
 impl DtbScheme {
     pub fn init() {
         let mut data_init = false;
@@ -51,29 +264,78 @@ impl DtbScheme {
             log::error!("DtbScheme::new called multiple times");
         }
     }
+
+    /// Look up the node or property at `path`.
+    fn lookup(fdt: &Fdt, path: &str) -> Option<HandleKind> {
+        if let Some(node) = fdt.find_node(path) {
+            let mut listing = String::new();
+            let entries = fdt.entries(node)?;
+            for entry in entries.iter() {
+                if let Entry::Prop(name, _) = entry {
+                    let _ = writeln!(listing, "{}", name);
+                }
+            }
+            for entry in entries.iter() {
+                if let Entry::Child(name, _) = entry {
+                    let _ = writeln!(listing, "{}/", name);
+                }
+            }
+            return Some(HandleKind::Node(listing.into_bytes().into_boxed_slice()));
+        }
+
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let value = fdt.property(fdt.find_node(parent)?, name)?;
+        Some(HandleKind::Data(Box::from(value)))
+    }
 }
 
 impl KernelScheme for DtbScheme {
-    fn kopen(&self, path: &str, _flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
-        let path = path.trim_matches('/');
+    fn kopen(&self, path: &str, flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
+        let kind = if path.is_empty() {
+            HandleKind::RawData
+        } else {
+            let data = DATA.get().ok_or(Error::new(ENOENT))?;
+            let fdt = Fdt::new(data).ok_or(Error::new(ENOENT))?;
+            let path = path.trim_matches('/');
+
+            match Self::lookup(&fdt, path) {
+                Some(kind) => kind,
+                None if path == "memreserve" => {
+                    let mut ranges = String::new();
+                    for (base, size) in fdt.reserved() {
+                        let _ = writeln!(ranges, "{:#x} {:#x}", base, size);
+                    }
+                    HandleKind::Data(ranges.into_bytes().into_boxed_slice())
+                }
+                None => return Err(Error::new(ENOENT)),
+            }
+        };
 
-        if path.is_empty() {
-            let id = NEXT_FD.fetch_add(1, atomic::Ordering::Relaxed);
+        if flags & O_STAT != O_STAT {
+            match kind {
+                HandleKind::Node(_) if flags & O_DIRECTORY != O_DIRECTORY => {
+                    return Err(Error::new(EISDIR))
+                }
+                HandleKind::RawData | HandleKind::Data(_) if flags & O_DIRECTORY == O_DIRECTORY => {
+                    return Err(Error::new(ENOTDIR))
+                }
+                _ => (),
+            }
+        }
 
-            let mut handles_guard = HANDLES.write();
+        let id = NEXT_FD.fetch_add(1, atomic::Ordering::Relaxed);
 
-            let _ = handles_guard.insert(
-                id,
-                Handle {
-                    offset: 0,
-                    kind: HandleKind::RawData,
-                    stat: _flags & O_STAT == O_STAT,
-                },
-            );
-            return Ok(OpenResult::SchemeLocal(id));
-        }
+        let mut handles_guard = HANDLES.write();
 
-        Err(Error::new(ENOENT))
+        let _ = handles_guard.insert(
+            id,
+            Handle {
+                offset: 0,
+                kind,
+                stat: flags & O_STAT == O_STAT,
+            },
+        );
+        Ok(OpenResult::SchemeLocal(id))
     }
 
     fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<usize> {
@@ -86,6 +348,7 @@ impl KernelScheme for DtbScheme {
 
         let file_len = match handle.kind {
             HandleKind::RawData => DATA.get().ok_or(Error::new(EBADFD))?.len(),
+            HandleKind::Node(ref data) | HandleKind::Data(ref data) => data.len(),
         };
 
         let new_offset = match whence {
@@ -138,6 +401,7 @@ impl KernelScheme for DtbScheme {
 
         let data = match handle.kind {
             HandleKind::RawData => DATA.get().ok_or(Error::new(EBADFD))?,
+            HandleKind::Node(ref data) | HandleKind::Data(ref data) => data,
         };
 
         let src_offset = core::cmp::min(handle.offset, data.len());
@@ -154,17 +418,17 @@ impl KernelScheme for DtbScheme {
     fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<()> {
         let handles = HANDLES.read();
         let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
-        buf.copy_exactly(&match handle.kind {
-            HandleKind::RawData => {
-                let data = DATA.get().ok_or(Error::new(EBADFD))?;
-                Stat {
-                    st_mode: MODE_FILE,
-                    st_uid: 0,
-                    st_gid: 0,
-                    st_size: data.len().try_into().unwrap_or(u64::max_value()),
-                    ..Default::default()
-                }
-            }
+        let (mode, len) = match handle.kind {
+            HandleKind::RawData => (MODE_FILE, DATA.get().ok_or(Error::new(EBADFD))?.len()),
+            HandleKind::Node(ref data) => (MODE_DIR, data.len()),
+            HandleKind::Data(ref data) => (MODE_FILE, data.len()),
+        };
+        buf.copy_exactly(&Stat {
+            st_mode: mode,
+            st_uid: 0,
+            st_gid: 0,
+            st_size: len.try_into().unwrap_or(u64::max_value()),
+            ..Default::default()
         })?;
 
         Ok(())
@@ -1,35 +1,187 @@
-use alloc::sync::Arc;
-use core::mem;
+//! `event:` - event queues, and counters for waking other threads, as behind Linux `eventfd`.
+//!
+//! Opening `event:` creates an event queue. Opening `event:counter` instead creates a counter,
+//! whose writes add a native-endian `u64` to it and whose reads return and reset it, blocking
+//! while it is zero. With `event:counter/semaphore`, reads instead return 1 and decrement it.
+//! Writes block while the counter would exceed `u64::MAX - 1`. Counters report `EVENT_READ`
+//! while nonzero and `EVENT_WRITE` while below that maximum.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::{
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use spin::{Mutex, RwLock};
 
 use crate::{
-    event::{next_queue_id, queues, queues_mut, EventQueue, EventQueueId},
+    event::{self, next_queue_id, queues, queues_mut, EventQueue, EventQueueId},
+    sync::WaitCondition,
     syscall::{
         data::Event,
         error::*,
+        ext::{F_GETEVLIMIT, F_GETEVTIMEOUT, F_SETEVLIMIT, F_SETEVTIMEOUT},
+        flag::{EventFlags, EVENT_READ, EVENT_WRITE, F_GETFL, F_SETFL, O_ACCMODE, O_NONBLOCK},
         usercopy::{UserSliceRo, UserSliceWo},
     },
 };
 
-use super::{CallerCtx, KernelScheme, OpenResult};
+use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
+
+/// Largest value of a counter.
+const COUNTER_MAX: u64 = u64::MAX - 1;
+
+struct Counter {
+    value: Mutex<u64>,
+    semaphore: bool,
+    flags: AtomicUsize,
+    read_condition: WaitCondition,
+    write_condition: WaitCondition,
+}
+
+// Counter ids are allocated along with queue ids, so that they never collide.
+// Using BTreeMap as hashbrown doesn't have a const constructor.
+static COUNTERS: RwLock<BTreeMap<usize, Arc<Counter>>> = RwLock::new(BTreeMap::new());
+
+fn counter(id: usize) -> Option<Arc<Counter>> {
+    COUNTERS.read().get(&id).cloned()
+}
+
+impl Counter {
+    fn read(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        if buf.len() < mem::size_of::<u64>() {
+            return Err(Error::new(EINVAL));
+        }
+        loop {
+            let mut value = self.value.lock();
+            if *value > 0 {
+                let read = if self.semaphore { 1 } else { *value };
+                buf.write_u64(read)?;
+                let was_full = *value == COUNTER_MAX;
+                *value -= read;
+                drop(value);
+
+                // Only notify when the counter becomes writable. Level-triggered registrations are
+                // polled again by the event queue while it stays writable.
+                if was_full {
+                    event::trigger(GlobalSchemes::Event.scheme_id(), id, EVENT_WRITE);
+                }
+                self.write_condition.notify();
+                return Ok(mem::size_of::<u64>());
+            }
+
+            if self.flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
+                return Err(Error::new(EAGAIN));
+            } else if !self.read_condition.wait(value, "Counter::read") {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+
+    fn write(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        let add = buf.read_u64()?;
+        if add > COUNTER_MAX {
+            return Err(Error::new(EINVAL));
+        }
+        loop {
+            let mut value = self.value.lock();
+            if add <= COUNTER_MAX - *value {
+                let was_zero = *value == 0;
+                *value += add;
+                drop(value);
+
+                if was_zero && add > 0 {
+                    event::trigger(GlobalSchemes::Event.scheme_id(), id, EVENT_READ);
+                }
+                self.read_condition.notify();
+                return Ok(mem::size_of::<u64>());
+            }
+
+            if self.flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
+                return Err(Error::new(EAGAIN));
+            } else if !self.write_condition.wait(value, "Counter::write") {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+}
 
 pub struct EventScheme;
 
 impl KernelScheme for EventScheme {
-    fn kopen(&self, _path: &str, _flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
+    fn kopen(&self, path: &str, flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
         let id = next_queue_id();
-        queues_mut().insert(id, Arc::new(EventQueue::new(id)));
 
+        let semaphore = match path.trim_matches('/') {
+            "" => {
+                queues_mut().insert(id, Arc::new(EventQueue::new(id)));
+                return Ok(OpenResult::SchemeLocal(id.get()));
+            }
+            "counter" => false,
+            "counter/semaphore" => true,
+            _ => return Err(Error::new(ENOENT)),
+        };
+        COUNTERS.write().insert(
+            id.get(),
+            Arc::new(Counter {
+                value: Mutex::new(0),
+                semaphore,
+                flags: AtomicUsize::new(flags & !O_ACCMODE),
+                read_condition: WaitCondition::new(),
+                write_condition: WaitCondition::new(),
+            }),
+        );
         Ok(OpenResult::SchemeLocal(id.get()))
     }
 
-    fn fcntl(&self, id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        if let Some(counter) = counter(id) {
+            return match cmd {
+                F_GETFL => Ok(counter.flags.load(Ordering::SeqCst)),
+                F_SETFL => {
+                    counter.flags.store(arg & !O_ACCMODE, Ordering::SeqCst);
+                    Ok(0)
+                }
+                _ => Err(Error::new(EINVAL)),
+            };
+        }
+
         let id = EventQueueId::from(id);
 
         let handles = queues();
-        handles.get(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+        let queue = handles.get(&id).ok_or(Error::new(EBADF))?;
+        match cmd {
+            F_GETEVLIMIT => Ok(queue.limit()),
+            F_SETEVLIMIT => queue.set_limit(arg).and(Ok(0)),
+            F_GETEVTIMEOUT => Ok(queue.timeout()),
+            F_SETEVTIMEOUT => {
+                queue.set_timeout(arg);
+                Ok(0)
+            }
+            // Handled by the kernel, using the flags of the file description.
+            F_GETFL | F_SETFL => Ok(0),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    fn fevent(&self, id: usize, flags: EventFlags) -> Result<EventFlags> {
+        let counter = counter(id).ok_or(Error::new(EBADF))?;
+        let value = *counter.value.lock();
+
+        let mut ready = EventFlags::empty();
+        if flags.contains(EVENT_READ) && value > 0 {
+            ready |= EVENT_READ;
+        }
+        if flags.contains(EVENT_WRITE) && value < COUNTER_MAX {
+            ready |= EVENT_WRITE;
+        }
+        Ok(ready)
     }
 
     fn fsync(&self, id: usize) -> Result<()> {
+        if counter(id).is_some() {
+            return Ok(());
+        }
+
         let id = EventQueueId::from(id);
 
         let handles = queues();
@@ -37,6 +189,10 @@ impl KernelScheme for EventScheme {
     }
 
     fn close(&self, id: usize) -> Result<()> {
+        if COUNTERS.write().remove(&id).is_some() {
+            return Ok(());
+        }
+
         let id = EventQueueId::from(id);
         queues_mut()
             .remove(&id)
@@ -44,6 +200,10 @@ impl KernelScheme for EventScheme {
             .and(Ok(()))
     }
     fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        if let Some(counter) = counter(id) {
+            return counter.read(id, buf);
+        }
+
         let id = EventQueueId::from(id);
 
         let queue = {
@@ -56,6 +216,10 @@ impl KernelScheme for EventScheme {
     }
 
     fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        if let Some(counter) = counter(id) {
+            return counter.write(id, buf);
+        }
+
         let id = EventQueueId::from(id);
 
         let queue = {
@@ -76,7 +240,12 @@ impl KernelScheme for EventScheme {
         Ok(events_written * mem::size_of::<Event>())
     }
 
-    fn kfpath(&self, _id: usize, buf: UserSliceWo) -> Result<usize> {
-        buf.copy_common_bytes_from_slice(b"event:")
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path: &[u8] = match counter(id) {
+            Some(counter) if counter.semaphore => b"event:counter/semaphore",
+            Some(_) => b"event:counter",
+            None => b"event:",
+        };
+        buf.copy_common_bytes_from_slice(path)
     }
 }
@@ -1,23 +1,36 @@
 use core::{
-    mem, str,
+    cmp, mem, str,
     str::FromStr,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
 };
 
-use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use alloc::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    string::String,
+    vec::Vec,
+};
 
 use spin::{Mutex, Once, RwLock};
 
-use crate::arch::interrupt::{available_irqs_iter, bsp_apic_id, is_reserved, set_reserved};
+use crate::arch::interrupt::{
+    available_irqs_iter, is_reserved, msi_message, msi_supported, set_reserved,
+};
 
 use crate::{
+    context::{self, ContextId},
     cpu_set::LogicalCpuId,
     event,
-    interrupt::irq::acknowledge,
+    interrupt::{
+        self,
+        irq::{acknowledge, mask, set_affinity},
+    },
     syscall::{
         data::Stat,
         error::*,
-        flag::{EventFlags, EVENT_READ, MODE_CHR, MODE_DIR, O_CREAT, O_DIRECTORY, O_STAT},
+        flag::{
+            EventFlags, EVENT_READ, F_GETFL, F_SETFL, MODE_CHR, MODE_DIR, MODE_FILE, O_CREAT,
+            O_DIRECTORY, O_STAT,
+        },
         usercopy::{UserSliceRo, UserSliceWo},
     },
 };
@@ -44,35 +57,307 @@ const TOTAL_IRQ_COUNT: u8 = 224;
 const INO_TOPLEVEL: u64 = 0x8002_0000_0000_0000;
 const INO_AVAIL: u64 = 0x8000_0000_0000_0000;
 const INO_BSP: u64 = 0x8001_0000_0000_0000;
+const INO_MSI: u64 = 0x8003_0000_0000_0000;
+const INO_GSI: u64 = 0x8004_0000_0000_0000;
+
+/// The extended IRQs reserved through `irq:`, as the CPU and IRQ, which GSIs may be routed to.
+static EXT_IRQS: Mutex<BTreeSet<(u8, u8)>> = Mutex::new(BTreeSet::new());
+
+/// Serializes the search for and reservation of free MSI vectors.
+static MSI_ALLOC_LOCK: Mutex<()> = Mutex::new(());
+/// The CPU each legacy IRQ has been routed to, or `None` if still routed to the BSP.
+static LEGACY_AFFINITY: Mutex<[Option<u8>; BASE_IRQ_COUNT as usize]> =
+    Mutex::new([None; BASE_IRQ_COUNT as usize]);
+
+/// Number of unacknowledged assertions within [`STORM_WINDOW_NS`] after which a line is considered
+/// to be storming, and masked until acknowledged.
+const STORM_THRESHOLD: usize = 10_000;
+const STORM_WINDOW_NS: u128 = 100_000_000;
+
+/// Per-line interrupt statistics, indexed like [`COUNTS`]. They are updated from the interrupt
+/// handler, and are therefore atomics rather than behind a lock that a syscall could hold.
+static LINE_STATS: [LineStats; TOTAL_IRQ_COUNT as usize] =
+    [LineStats::NEW; TOTAL_IRQ_COUNT as usize];
+
+struct LineStats {
+    /// Time of the latest assertion, from which acknowledgment latencies are measured.
+    asserted_at: AtomicU64,
+    /// Start of the current storm detection window, and the unacknowledged assertions within it.
+    window_start: AtomicU64,
+    window_count: AtomicUsize,
+    /// Assertions that no handle had claimed.
+    unhandled: AtomicUsize,
+    /// Times the line was masked for storming.
+    storms: AtomicUsize,
+    /// Whether the line is currently masked for storming.
+    storm_masked: AtomicBool,
+}
+impl LineStats {
+    const NEW: Self = Self {
+        asserted_at: AtomicU64::new(0),
+        window_start: AtomicU64::new(0),
+        window_count: AtomicUsize::new(0),
+        unhandled: AtomicUsize::new(0),
+        storms: AtomicUsize::new(0),
+        storm_masked: AtomicBool::new(false),
+    };
+}
+
+/// Time from assertion to acknowledgment, as seen by one handle.
+#[derive(Default)]
+struct Latency {
+    acks: AtomicU64,
+    total_ns: AtomicU64,
+    max_ns: AtomicU64,
+}
+impl Latency {
+    fn record(&self, asserted_at: u128) {
+        let ns =
+            u64::try_from(crate::time::monotonic().saturating_sub(asserted_at)).unwrap_or(u64::MAX);
+        self.acks.fetch_add(1, Ordering::Relaxed);
+        self.total_ns.fetch_add(ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(ns, Ordering::Relaxed);
+    }
+}
 
 /// Add to the input queue
 #[no_mangle]
 pub extern "C" fn irq_trigger(irq: u8) {
-    COUNTS.lock()[irq as usize] += 1;
+    let previous = {
+        let mut counts = COUNTS.lock();
+        counts[irq as usize] += 1;
+        counts[irq as usize] - 1
+    };
+    let now = crate::time::monotonic();
+    let cpu_id = crate::cpu_id();
+    let handles = HANDLES.read();
 
-    for (fd, _) in HANDLES
-        .read()
-        .iter()
-        .filter_map(|(fd, handle)| Some((fd, handle.as_irq_handle()?)))
-        .filter(|&(_, (_, handle_irq))| handle_irq == irq)
-    {
-        event::trigger(GlobalSchemes::Irq.scheme_id(), *fd, EVENT_READ);
+    // Whether the line is claimed, whether every claimant acknowledged the previous assertion, and
+    // whether it is an MSI vector, which the kernel cannot mask.
+    let mut claimed = false;
+    let mut acknowledged = true;
+    let mut msi = false;
+    for handle in handles.values() {
+        match *handle {
+            Handle::Irq {
+                irq: handle_irq,
+                ref ack,
+                ..
+            } if handle_irq == irq => {
+                claimed = true;
+                acknowledged &= ack.load(Ordering::SeqCst) == previous;
+            }
+            Handle::Msi {
+                irq: handle_irq,
+                cpu_id: handle_cpu_id,
+                ref ack,
+                ref count,
+                ..
+            } if handle_irq == irq && LogicalCpuId::new(handle_cpu_id.into()) == cpu_id => {
+                claimed = true;
+                msi = true;
+                acknowledged &= ack.load(Ordering::SeqCst) == count.load(Ordering::SeqCst);
+            }
+            _ => (),
+        }
+    }
+
+    let (storming, suppressed) = {
+        let line = &LINE_STATS[irq as usize];
+        line.asserted_at.store(now as u64, Ordering::Relaxed);
+        if !claimed {
+            line.unhandled.fetch_add(1, Ordering::Relaxed);
+        }
+        if now.saturating_sub(line.window_start.load(Ordering::Relaxed).into()) > STORM_WINDOW_NS {
+            line.window_start.store(now as u64, Ordering::Relaxed);
+            line.window_count.store(0, Ordering::Relaxed);
+        }
+        if !claimed || !acknowledged {
+            line.window_count.fetch_add(1, Ordering::Relaxed);
+        }
+        let storming = line.window_count.load(Ordering::Relaxed) >= STORM_THRESHOLD
+            && !line.storm_masked.swap(true, Ordering::SeqCst);
+        if storming {
+            line.storms.fetch_add(1, Ordering::Relaxed);
+        }
+        (
+            storming,
+            line.storm_masked.load(Ordering::SeqCst) && !storming,
+        )
+    };
+    if storming {
+        log::warn!(
+            "irq {} storming with {} unacknowledged assertions, masking until acknowledged",
+            irq,
+            STORM_THRESHOLD
+        );
+        if !msi {
+            unsafe { mask(usize::from(irq)) };
+        }
+    }
+
+    for (fd, handle) in handles.iter() {
+        match *handle {
+            Handle::Irq {
+                irq: handle_irq, ..
+            } if handle_irq == irq => (),
+            // Unlike shared legacy IRQs, MSI vectors are counted per CPU.
+            Handle::Msi {
+                irq: handle_irq,
+                cpu_id: handle_cpu_id,
+                ref count,
+                ..
+            } if handle_irq == irq && LogicalCpuId::new(handle_cpu_id.into()) == cpu_id => {
+                count.fetch_add(1, Ordering::SeqCst);
+            }
+            Handle::Stats(owner, _) if storming && line_of(&handles, owner) == Some(irq) => (),
+            // Owners have lower file descriptors, so MSI counts have already been updated.
+            Handle::Deferred(owner, ref deferred) => match handles.get(&owner) {
+                Some(&Handle::Irq {
+                    irq: handle_irq, ..
+                }) if handle_irq == irq => deferred.push(previous + 1, now),
+                Some(&Handle::Msi {
+                    irq: handle_irq,
+                    cpu_id: handle_cpu_id,
+                    ref count,
+                    ..
+                }) if handle_irq == irq && LogicalCpuId::new(handle_cpu_id.into()) == cpu_id => {
+                    deferred.push(count.load(Ordering::SeqCst), now)
+                }
+                _ => continue,
+            },
+            _ => continue,
+        }
+        // A storming MSI vector keeps firing, but its owner is only woken by the storm itself.
+        if !suppressed {
+            event::trigger(GlobalSchemes::Irq.scheme_id(), *fd, EVENT_READ);
+        }
     }
 }
 
 enum Handle {
-    Irq { ack: AtomicUsize, irq: u8 },
+    /// A claim on an IRQ line. Several handles may claim the same line, such as a shared PCI INTx
+    /// line: the line is masked when asserted, every claimant receives an event, and the line is
+    /// only unmasked once all claimants have acknowledged the latest count.
+    Irq {
+        ack: AtomicUsize,
+        irq: u8,
+        latency: Latency,
+        /// The CPU whose vector for an extended IRQ was reserved when opening with `O_CREAT`, and
+        /// is freed when closed.
+        reserved_on: Option<u8>,
+    },
+    /// A vector reserved for message signaled interrupts, freed when closed.
+    Msi {
+        ack: AtomicUsize,
+        count: AtomicUsize,
+        cpu_id: u8,
+        irq: u8,
+        latency: Latency,
+    },
+    /// The message to program into the MSI or MSI-X capability: the address as a u64, followed by
+    /// the data as a u32.
+    MsiMessage(Vec<u8>, AtomicUsize), // message, offset
+    /// The CPU an interrupt is routed to, as a hexadecimal ID in the same numbering as the
+    /// `cpu-XX` directories.
+    Affinity(AffinityTarget, AtomicUsize), // target, offset
+    /// Interrupt and latency statistics of an IRQ or MSI handle, as `<key> <value>` lines. Storms
+    /// on the line trigger an event on this handle.
+    Stats(usize, AtomicUsize), // owner fd, offset
+    /// The routing of a GSI, opened as `irq:gsi/<gsi>`. Devices outside the legacy IRQs, such as
+    /// PCI INTx lines found in the ACPI routing tables, are connected to an extended IRQ reserved
+    /// beforehand by writing `irq <irq>`, `dest <cpu>`, `trigger edge|level|default`, `polarity
+    /// high|low|default` and `masked 0|1` lines.
+    Gsi(u32, AtomicUsize), // GSI, offset
+    /// Deferred handling of the assertions of an IRQ or MSI handle. Each assertion is queued with
+    /// its count and the time it was raised at, and boosts the context that created the handle
+    /// until it acknowledges the latest count through this handle.
+    Deferred(usize, Deferred), // owner fd, state
     Avail(u8, Vec<u8>, AtomicUsize), // CPU id, data, offset
     TopLevel(Vec<u8>, AtomicUsize),  // data, offset
     Bsp,
 }
-impl Handle {
-    fn as_irq_handle<'a>(&'a self) -> Option<(&'a AtomicUsize, u8)> {
-        match self {
-            &Self::Irq { ref ack, irq } => Some((ack, irq)),
-            _ => None,
+
+/// Capacity of the event queue of a deferred handle, beyond which the oldest events are dropped.
+const DEFERRED_QUEUE_LEN: usize = 32;
+/// Size of an event read from a deferred handle: the count followed by the monotonic time in
+/// nanoseconds, both as u64.
+const DEFERRED_EVENT_SIZE: usize = 2 * mem::size_of::<u64>();
+
+struct Deferred {
+    owner: ContextId,
+    boosted: AtomicBool,
+    /// Allocated up front, so that the interrupt handler never allocates.
+    queue: Mutex<VecDeque<(u64, u64)>>,
+}
+impl Deferred {
+    fn new(owner: ContextId) -> Self {
+        Self {
+            owner,
+            boosted: AtomicBool::new(false),
+            queue: Mutex::new(VecDeque::with_capacity(DEFERRED_QUEUE_LEN)),
         }
     }
+    /// Queue an assertion and boost the owner, from the interrupt handler.
+    fn push(&self, count: usize, time: u128) {
+        {
+            let mut queue = self.queue.lock();
+            if queue.len() == DEFERRED_QUEUE_LEN {
+                queue.pop_front();
+            }
+            queue.push_back((count as u64, time as u64));
+        }
+
+        if self.boosted.load(Ordering::SeqCst) {
+            return;
+        }
+        // The interrupt may have preempted a holder of these locks, in which case the owner is
+        // only woken by the event, without the boost.
+        let Some(contexts) = context::try_contexts() else {
+            return;
+        };
+        let Some(mut context) = contexts
+            .get(self.owner)
+            .and_then(|context_lock| context_lock.try_write())
+        else {
+            return;
+        };
+        if !self.boosted.swap(true, Ordering::SeqCst) {
+            context.irq_boost += 1;
+        }
+    }
+    /// Take up to `events.len()` queued assertions, returning how many were taken. The interrupt
+    /// handler pushes under the same lock, so it is only held with interrupts disabled, and never
+    /// while accessing user memory.
+    fn pop(&self, events: &mut [(u64, u64)]) -> usize {
+        unsafe { interrupt::disable() };
+        let taken = {
+            let mut queue = self.queue.lock();
+            let taken = cmp::min(events.len(), queue.len());
+            for (event, queued) in events.iter_mut().zip(queue.drain(..taken)) {
+                *event = queued;
+            }
+            taken
+        };
+        unsafe { interrupt::enable() };
+        taken
+    }
+    fn unboost(&self) {
+        if !self.boosted.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        if let Some(context_lock) = context::contexts().get(self.owner) {
+            context_lock.write().irq_boost -= 1;
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum AffinityTarget {
+    Legacy(u8),
+    /// The file descriptor of an MSI handle. Moving it to another CPU reserves a new vector, so
+    /// the message must be read again and reprogrammed into the device.
+    Msi(usize),
 }
 
 static NEXT_FD: AtomicUsize = AtomicUsize::new(1);
@@ -82,67 +367,338 @@ pub struct IrqScheme;
 
 impl IrqScheme {
     pub fn init() {
-        #[cfg(all(feature = "acpi", any(target_arch = "x86", target_arch = "x86_64")))]
-        let cpus = {
-            use crate::acpi::madt::*;
-
-            match unsafe { MADT.as_ref() } {
-                Some(madt) => madt
-                    .iter()
-                    .filter_map(|entry| match entry {
-                        MadtEntry::LocalApic(apic) => Some(apic.id),
-                        _ => None,
-                    })
-                    .collect::<Vec<_>>(),
-                None => {
-                    log::warn!("no MADT found, defaulting to 1 CPU");
-                    vec![0]
-                }
-            }
-        };
-        #[cfg(not(all(feature = "acpi", any(target_arch = "x86", target_arch = "x86_64"))))]
-        let cpus = vec![0];
+        // Logical CPU IDs are assigned sequentially, and only those that fit in the `cpu-XX`
+        // directory names are exposed.
+        let cpus = (0..crate::cpu_count())
+            .filter_map(|cpu_id| u8::try_from(cpu_id).ok())
+            .collect::<Vec<_>>();
 
         CPUS.call_once(|| cpus);
     }
     fn open_ext_irq(flags: usize, cpu_id: u8, path_str: &str) -> Result<Handle> {
         let irq_number = u8::from_str(path_str).or(Err(Error::new(ENOENT)))?;
 
-        Ok(
-            if irq_number < BASE_IRQ_COUNT && Some(u32::from(cpu_id)) == bsp_apic_id() {
-                // Give legacy IRQs only to `irq:{0..15}` and `irq:cpu-<BSP>/{0..15}` (same handles).
-                //
-                // The only CPUs don't have the legacy IRQs in their IDTs.
+        Ok(if irq_number < BASE_IRQ_COUNT && is_bsp(cpu_id) {
+            // Give legacy IRQs only to `irq:{0..15}` and `irq:cpu-<BSP>/{0..15}` (same handles).
+            //
+            // The only CPUs don't have the legacy IRQs in their IDTs.
 
-                Handle::Irq {
-                    ack: AtomicUsize::new(0),
-                    irq: irq_number,
-                }
-            } else if irq_number < TOTAL_IRQ_COUNT {
-                if flags & O_CREAT == 0 && flags & O_STAT == 0 {
-                    return Err(Error::new(EINVAL));
-                }
-                if flags & O_STAT == 0 {
-                    if is_reserved(LogicalCpuId::new(cpu_id.into()), irq_to_vector(irq_number)) {
-                        return Err(Error::new(EEXIST));
-                    }
-                    set_reserved(
-                        LogicalCpuId::new(cpu_id.into()),
-                        irq_to_vector(irq_number),
-                        true,
-                    );
-                }
-                Handle::Irq {
-                    ack: AtomicUsize::new(0),
-                    irq: irq_number,
+            Handle::Irq {
+                ack: AtomicUsize::new(0),
+                irq: irq_number,
+                latency: Latency::default(),
+                reserved_on: None,
+            }
+        } else if irq_number < TOTAL_IRQ_COUNT {
+            if flags & O_CREAT == 0 && flags & O_STAT == 0 {
+                return Err(Error::new(EINVAL));
+            }
+            let reserved_on = if flags & O_STAT == 0 {
+                if is_reserved(LogicalCpuId::new(cpu_id.into()), irq_to_vector(irq_number)) {
+                    return Err(Error::new(EEXIST));
                 }
+                set_reserved(
+                    LogicalCpuId::new(cpu_id.into()),
+                    irq_to_vector(irq_number),
+                    true,
+                );
+                EXT_IRQS.lock().insert((cpu_id, irq_number));
+                Some(cpu_id)
             } else {
-                return Err(Error::new(ENOENT));
-            },
-        )
+                None
+            };
+            Handle::Irq {
+                ack: AtomicUsize::new(0),
+                irq: irq_number,
+                latency: Latency::default(),
+                reserved_on,
+            }
+        } else {
+            return Err(Error::new(ENOENT));
+        })
+    }
+    /// Reserve a vector for message signaled interrupts on `cpu_id`, or, if not given, on the CPU
+    /// with the most free vectors.
+    fn open_msi(flags: usize, cpu_id: Option<u8>) -> Result<Handle> {
+        if !msi_supported() {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        if flags & O_CREAT == 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let cpus = CPUS.get().expect("IRQ scheme not initialized");
+
+        let _guard = MSI_ALLOC_LOCK.lock();
+
+        let cpu_id = match cpu_id {
+            Some(cpu_id) if cpus.contains(&cpu_id) => cpu_id,
+            Some(_) => return Err(Error::new(ENOENT)),
+            None => *cpus
+                .iter()
+                .max_by_key(|&&cpu_id| free_msi_irqs(cpu_id).count())
+                .ok_or(Error::new(ENOSPC))?,
+        };
+        let irq = free_msi_irqs(cpu_id).next().ok_or(Error::new(ENOSPC))?;
+        msi_message(cpu_id.into(), irq_to_vector(irq)).ok_or(Error::new(EOPNOTSUPP))?;
+
+        set_reserved(LogicalCpuId::new(cpu_id.into()), irq_to_vector(irq), true);
+        Ok(Handle::Msi {
+            ack: AtomicUsize::new(0),
+            count: AtomicUsize::new(0),
+            cpu_id,
+            irq,
+            latency: Latency::default(),
+        })
     }
 }
 
+/// Unmask `irq` if it is claimed, and every claimant has acknowledged the latest assertion.
+fn unmask_if_acknowledged(handles: &BTreeMap<usize, Handle>, irq: u8) {
+    let current = COUNTS.lock()[usize::from(irq)];
+
+    let mut claimed = false;
+    for handle in handles.values() {
+        if let Handle::Irq {
+            irq: handle_irq,
+            ref ack,
+            ..
+        } = *handle
+        {
+            if handle_irq != irq {
+                continue;
+            }
+            if ack.load(Ordering::SeqCst) != current {
+                return;
+            }
+            claimed = true;
+        }
+    }
+    if claimed {
+        LINE_STATS[usize::from(irq)]
+            .storm_masked
+            .store(false, Ordering::SeqCst);
+        unsafe { acknowledge(usize::from(irq)) };
+    }
+}
+
+/// Acknowledge the assertions of an IRQ or MSI handle, returning whether `ack` is the latest count,
+/// without which nothing is acknowledged.
+fn write_ack(handles: &BTreeMap<usize, Handle>, handle: &Handle, ack: usize) -> Result<bool> {
+    match *handle {
+        Handle::Irq {
+            irq,
+            ack: ref handle_ack,
+            ref latency,
+            ..
+        } => {
+            let current = COUNTS.lock()[usize::from(irq)];
+            if ack != current {
+                return Ok(false);
+            }
+            if handle_ack.swap(ack, Ordering::SeqCst) != ack {
+                latency.record(asserted_at(irq));
+            }
+            unmask_if_acknowledged(handles, irq);
+            Ok(true)
+        }
+        Handle::Msi {
+            ack: ref handle_ack,
+            ref count,
+            irq,
+            ref latency,
+            ..
+        } => {
+            // Message signaled interrupts are edge-triggered and never masked by the kernel, so
+            // acknowledging only updates the event state, resuming events after a storm.
+            if ack != count.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+            if handle_ack.swap(ack, Ordering::SeqCst) != ack {
+                latency.record(asserted_at(irq));
+            }
+            LINE_STATS[usize::from(irq)]
+                .storm_masked
+                .store(false, Ordering::SeqCst);
+            Ok(true)
+        }
+        _ => Err(Error::new(EBADF)),
+    }
+}
+
+/// Time of the latest assertion of `irq`.
+fn asserted_at(irq: u8) -> u128 {
+    LINE_STATS[usize::from(irq)]
+        .asserted_at
+        .load(Ordering::Relaxed)
+        .into()
+}
+
+/// The line of the IRQ or MSI handle `fd`.
+fn line_of(handles: &BTreeMap<usize, Handle>, fd: usize) -> Option<u8> {
+    match handles.get(&fd)? {
+        &Handle::Irq { irq, .. } | &Handle::Msi { irq, .. } => Some(irq),
+        _ => None,
+    }
+}
+
+fn format_stats(handles: &BTreeMap<usize, Handle>, owner: usize) -> Result<String> {
+    let (irq, latency) = match handles.get(&owner) {
+        Some(&Handle::Irq {
+            irq, ref latency, ..
+        })
+        | Some(&Handle::Msi {
+            irq, ref latency, ..
+        }) => (irq, latency),
+        _ => return Err(Error::new(EBADF)),
+    };
+    let count = COUNTS.lock()[usize::from(irq)];
+    let line = &LINE_STATS[usize::from(irq)];
+    let acks = latency.acks.load(Ordering::Relaxed);
+
+    let mut string = String::new();
+    use core::fmt::Write;
+    let _ = writeln!(string, "count {}", count);
+    let _ = writeln!(
+        string,
+        "unhandled {}",
+        line.unhandled.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(string, "storms {}", line.storms.load(Ordering::Relaxed));
+    let _ = writeln!(
+        string,
+        "storm_masked {}",
+        u8::from(line.storm_masked.load(Ordering::Relaxed))
+    );
+    let _ = writeln!(string, "acks {}", acks);
+    let _ = writeln!(
+        string,
+        "latency_avg_ns {}",
+        latency.total_ns.load(Ordering::Relaxed) / acks.max(1)
+    );
+    let _ = writeln!(
+        string,
+        "latency_max_ns {}",
+        latency.max_ns.load(Ordering::Relaxed)
+    );
+    Ok(string)
+}
+
+/// Read the routing of `gsi`, failing with `ENOENT` if it does not exist.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn read_gsi(gsi: u32) -> Result<String> {
+    use crate::device::ioapic;
+
+    Ok(ioapic::gsi_route(gsi)
+        .ok_or(Error::new(ENOENT))?
+        .format(gsi))
+}
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn read_gsi(_gsi: u32) -> Result<String> {
+    Err(Error::new(ENOENT))
+}
+
+/// Update the routing of `gsi` from `<key> <value>` lines.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn write_gsi(gsi: u32, text: &str) -> Result<()> {
+    use crate::device::{ioapic, local_apic};
+
+    let mut route = ioapic::gsi_route(gsi).ok_or(Error::new(ENOENT))?;
+    route.parse_and_set(text, gsi)?;
+    // The destination is a local APIC ID, which need not match the logical CPU ID.
+    let cpu_id = local_apic::logical_id(route.dest.into()).ok_or(Error::new(EINVAL))?;
+    // The IRQ must have been opened with O_CREAT first, so that it is owned by the caller, rather
+    // than by the kernel.
+    let cpu_id = u8::try_from(cpu_id.get()).map_err(|_| Error::new(EINVAL))?;
+    if !EXT_IRQS.lock().contains(&(cpu_id, route.irq)) {
+        return Err(Error::new(EINVAL));
+    }
+    unsafe { ioapic::set_gsi_route(gsi, route) }
+}
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn write_gsi(_gsi: u32, _text: &str) -> Result<()> {
+    Err(Error::new(ENOENT))
+}
+
+/// Route a legacy IRQ to `cpu_id`.
+pub fn set_legacy_affinity(irq: u8, cpu_id: u8) -> Result<()> {
+    if irq >= BASE_IRQ_COUNT {
+        return Err(Error::new(EINVAL));
+    }
+    if !CPUS
+        .get()
+        .expect("IRQ scheme not initialized")
+        .contains(&cpu_id)
+    {
+        return Err(Error::new(ENOENT));
+    }
+
+    let mut affinity = LEGACY_AFFINITY.lock();
+    unsafe { set_affinity(irq, cpu_id.into())? };
+    affinity[usize::from(irq)] = Some(cpu_id);
+    Ok(())
+}
+
+/// Move an MSI vector to `new_cpu_id`, reserving a new vector there.
+fn set_msi_affinity(fd: usize, new_cpu_id: u8) -> Result<()> {
+    if !CPUS
+        .get()
+        .expect("IRQ scheme not initialized")
+        .contains(&new_cpu_id)
+    {
+        return Err(Error::new(ENOENT));
+    }
+
+    let _guard = MSI_ALLOC_LOCK.lock();
+    let mut handles = HANDLES.write();
+    let Some(Handle::Msi { cpu_id, irq, .. }) = handles.get_mut(&fd) else {
+        return Err(Error::new(EBADF));
+    };
+    if *cpu_id == new_cpu_id {
+        return Ok(());
+    }
+
+    let new_irq = free_msi_irqs(new_cpu_id).next().ok_or(Error::new(ENOSPC))?;
+    msi_message(new_cpu_id.into(), irq_to_vector(new_irq)).ok_or(Error::new(EOPNOTSUPP))?;
+
+    set_reserved(
+        LogicalCpuId::new(new_cpu_id.into()),
+        irq_to_vector(new_irq),
+        true,
+    );
+    set_reserved(
+        LogicalCpuId::new((*cpu_id).into()),
+        irq_to_vector(*irq),
+        false,
+    );
+    *cpu_id = new_cpu_id;
+    *irq = new_irq;
+    Ok(())
+}
+
+fn affinity(handles: &BTreeMap<usize, Handle>, target: AffinityTarget) -> Result<u8> {
+    match target {
+        AffinityTarget::Legacy(irq) => {
+            Ok(LEGACY_AFFINITY.lock()[usize::from(irq)].unwrap_or(LogicalCpuId::BSP.get() as u8))
+        }
+        AffinityTarget::Msi(fd) => match handles.get(&fd) {
+            Some(&Handle::Msi { cpu_id, .. }) => Ok(cpu_id),
+            _ => Err(Error::new(EBADF)),
+        },
+    }
+}
+
+/// Whether the logical CPU `cpu_id` is the BSP, which alone handles the legacy IRQs.
+fn is_bsp(cpu_id: u8) -> bool {
+    u32::from(cpu_id) == LogicalCpuId::BSP.get()
+}
+
+/// IRQs that can be reserved for MSI on `cpu_id`, excluding the legacy IRQ range.
+fn free_msi_irqs(cpu_id: u8) -> impl Iterator<Item = u8> {
+    available_irqs_iter(LogicalCpuId::new(cpu_id.into()))
+        .map(vector_to_irq)
+        .filter(|&irq| irq >= BASE_IRQ_COUNT && irq < TOTAL_IRQ_COUNT)
+}
+
 const fn irq_to_vector(irq: u8) -> u8 {
     irq + 32
 }
@@ -173,8 +729,9 @@ impl crate::scheme::KernelScheme for IrqScheme {
                 writeln!(bytes, "cpu-{:02x}", cpu_id).unwrap();
             }
 
-            if bsp_apic_id().is_some() {
-                writeln!(bytes, "bsp").unwrap();
+            writeln!(bytes, "bsp").unwrap();
+            if msi_supported() {
+                writeln!(bytes, "msi").unwrap();
             }
 
             // TODO: When signals are used for IRQs, there will probably also be a file
@@ -183,10 +740,13 @@ impl crate::scheme::KernelScheme for IrqScheme {
             Handle::TopLevel(bytes.into_bytes(), AtomicUsize::new(0))
         } else {
             if path_str == "bsp" {
-                if bsp_apic_id().is_none() {
-                    return Err(Error::new(ENOENT));
-                }
                 Handle::Bsp
+            } else if path_str == "msi" {
+                Self::open_msi(flags, None)?
+            } else if let Some(gsi) = path_str.strip_prefix("gsi/") {
+                let gsi = u32::from_str(gsi).or(Err(Error::new(ENOENT)))?;
+                read_gsi(gsi)?;
+                Handle::Gsi(gsi, AtomicUsize::new(0))
             } else if path_str.starts_with("cpu-") {
                 let path_str = &path_str[4..];
                 let cpu_id = u8::from_str_radix(&path_str[..2], 16).or(Err(Error::new(ENOENT)))?;
@@ -198,13 +758,15 @@ impl crate::scheme::KernelScheme for IrqScheme {
 
                     for vector in available_irqs_iter(LogicalCpuId::new(cpu_id.into())) {
                         let irq = vector_to_irq(vector);
-                        if Some(u32::from(cpu_id)) == bsp_apic_id() && irq < BASE_IRQ_COUNT {
+                        if is_bsp(cpu_id) && irq < BASE_IRQ_COUNT {
                             continue;
                         }
                         writeln!(data, "{}", irq).unwrap();
                     }
 
                     Handle::Avail(cpu_id, data.into_bytes(), AtomicUsize::new(0))
+                } else if path_str == "/msi" {
+                    Self::open_msi(flags, Some(cpu_id))?
                 } else if path_str.starts_with('/') {
                     let path_str = &path_str[1..];
                     Self::open_ext_irq(flags, cpu_id, path_str)?
@@ -216,6 +778,8 @@ impl crate::scheme::KernelScheme for IrqScheme {
                     Handle::Irq {
                         ack: AtomicUsize::new(0),
                         irq: plain_irq_number,
+                        latency: Latency::default(),
+                        reserved_on: None,
                     }
                 } else {
                     return Err(Error::new(ENOENT));
@@ -234,7 +798,9 @@ impl crate::scheme::KernelScheme for IrqScheme {
         let handle = handles_guard.get(&id).ok_or(Error::new(EBADF))?;
 
         match handle {
-            &Handle::Avail(_, ref buf, ref offset) | &Handle::TopLevel(ref buf, ref offset) => {
+            &Handle::Avail(_, ref buf, ref offset)
+            | &Handle::TopLevel(ref buf, ref offset)
+            | &Handle::MsiMessage(ref buf, ref offset) => {
                 let cur_offset = offset.load(Ordering::SeqCst);
                 let new_offset = calc_seek_offset(cur_offset, pos, whence, buf.len())?;
                 offset.store(new_offset as usize, Ordering::SeqCst);
@@ -244,8 +810,11 @@ impl crate::scheme::KernelScheme for IrqScheme {
         }
     }
 
-    fn fcntl(&self, _id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
-        Ok(0)
+    fn fcntl(&self, _id: usize, cmd: usize, _arg: usize) -> Result<usize> {
+        match cmd {
+            F_GETFL | F_SETFL => Ok(0),
+            _ => Err(Error::new(EINVAL)),
+        }
     }
 
     fn fevent(&self, _id: usize, _flags: EventFlags) -> Result<EventFlags> {
@@ -256,17 +825,72 @@ impl crate::scheme::KernelScheme for IrqScheme {
         Ok(())
     }
 
+    fn kdup(&self, old_id: usize, buf: UserSliceRo, _ctx: CallerCtx) -> Result<OpenResult> {
+        let mut name = [0_u8; 16];
+        if buf.len() > name.len() {
+            return Err(Error::new(EINVAL));
+        }
+        let len = buf.copy_common_bytes_to_slice(&mut name)?;
+
+        let new_handle = match (&name[..len], HANDLES.read().get(&old_id)) {
+            (_, None) => return Err(Error::new(EBADF)),
+            (b"message", Some(&Handle::Msi { cpu_id, irq, .. })) => {
+                let (address, data) =
+                    msi_message(cpu_id.into(), irq_to_vector(irq)).ok_or(Error::new(EBADFD))?;
+                let mut message = Vec::with_capacity(mem::size_of::<u64>() + mem::size_of::<u32>());
+                message.extend_from_slice(&address.to_ne_bytes());
+                message.extend_from_slice(&data.to_ne_bytes());
+                Handle::MsiMessage(message, AtomicUsize::new(0))
+            }
+            (b"affinity", Some(&Handle::Msi { .. })) => {
+                Handle::Affinity(AffinityTarget::Msi(old_id), AtomicUsize::new(0))
+            }
+            (b"affinity", Some(&Handle::Irq { irq, .. })) if irq < BASE_IRQ_COUNT => {
+                Handle::Affinity(AffinityTarget::Legacy(irq), AtomicUsize::new(0))
+            }
+            (b"stats", Some(&Handle::Irq { .. } | &Handle::Msi { .. })) => {
+                Handle::Stats(old_id, AtomicUsize::new(0))
+            }
+            (b"deferred", Some(&Handle::Irq { .. } | &Handle::Msi { .. })) => {
+                Handle::Deferred(old_id, Deferred::new(context::context_id()))
+            }
+            _ => return Err(Error::new(EINVAL)),
+        };
+
+        let fd = NEXT_FD.fetch_add(1, Ordering::Relaxed);
+        HANDLES.write().insert(fd, new_handle);
+        Ok(OpenResult::SchemeLocal(fd))
+    }
+
     fn close(&self, id: usize) -> Result<()> {
-        let handles_guard = HANDLES.read();
-        let handle = handles_guard.get(&id).ok_or(Error::new(EBADF))?;
+        let mut handles = HANDLES.write();
+        let handle = handles.remove(&id).ok_or(Error::new(EBADF))?;
 
-        if let &Handle::Irq {
-            irq: handle_irq, ..
-        } = handle
-        {
-            if handle_irq > BASE_IRQ_COUNT {
-                set_reserved(LogicalCpuId::BSP, irq_to_vector(handle_irq), false);
+        match handle {
+            Handle::Irq {
+                irq: handle_irq,
+                reserved_on,
+                ..
+            } => {
+                if let Some(cpu_id) = reserved_on {
+                    // A GSI may still be routed to the IRQ, which must not fire once unowned.
+                    unsafe { mask(usize::from(handle_irq)) };
+                    set_reserved(
+                        LogicalCpuId::new(cpu_id.into()),
+                        irq_to_vector(handle_irq),
+                        false,
+                    );
+                    EXT_IRQS.lock().remove(&(cpu_id, handle_irq));
+                } else if handle_irq < BASE_IRQ_COUNT {
+                    // The closed claimant may have been the last one not to acknowledge.
+                    unmask_if_acknowledged(&handles, handle_irq);
+                }
+            }
+            Handle::Msi { cpu_id, irq, .. } => {
+                set_reserved(LogicalCpuId::new(cpu_id.into()), irq_to_vector(irq), false);
             }
+            Handle::Deferred(_, deferred) => deferred.unboost(),
+            _ => (),
         }
         Ok(())
     }
@@ -274,27 +898,56 @@ impl crate::scheme::KernelScheme for IrqScheme {
         let handles_guard = HANDLES.read();
         let handle = handles_guard.get(&file).ok_or(Error::new(EBADF))?;
 
-        match handle {
-            &Handle::Irq {
-                irq: handle_irq,
-                ack: ref handle_ack,
-            } => {
-                if buffer.len() >= mem::size_of::<usize>() {
-                    let ack = buffer.read_usize()?;
-                    let current = COUNTS.lock()[handle_irq as usize];
+        if let Handle::Affinity(target, ref offset) = *handle {
+            let mut buf = [0_u8; 8];
+            let len = buffer.copy_common_bytes_to_slice(&mut buf)?;
+            let cpu_id = str::from_utf8(&buf[..len])
+                .ok()
+                .and_then(|text| u8::from_str_radix(text.trim(), 16).ok())
+                .ok_or(Error::new(EINVAL))?;
+            offset.store(0, Ordering::SeqCst);
+            drop(handles_guard);
 
-                    if ack == current {
-                        handle_ack.store(ack, Ordering::SeqCst);
-                        unsafe {
-                            acknowledge(handle_irq as usize);
+            match target {
+                AffinityTarget::Legacy(irq) => set_legacy_affinity(irq, cpu_id)?,
+                AffinityTarget::Msi(fd) => set_msi_affinity(fd, cpu_id)?,
+            }
+            return Ok(len);
+        }
+        if let Handle::Gsi(gsi, ref offset) = *handle {
+            let mut buf = [0_u8; 256];
+            let len = buffer.copy_common_bytes_to_slice(&mut buf)?;
+            let text = str::from_utf8(&buf[..len]).map_err(|_| Error::new(EINVAL))?;
+            offset.store(0, Ordering::SeqCst);
+            drop(handles_guard);
+
+            write_gsi(gsi, text)?;
+            return Ok(len);
+        }
+
+        match *handle {
+            Handle::Irq { .. } | Handle::Msi { .. } | Handle::Deferred(_, _) => {
+                if buffer.len() < mem::size_of::<usize>() {
+                    return Err(Error::new(EINVAL));
+                }
+                let ack = buffer.read_usize()?;
+
+                let acknowledged = match *handle {
+                    Handle::Deferred(owner, ref deferred) => {
+                        let owner_handle = handles_guard.get(&owner).ok_or(Error::new(EBADF))?;
+                        let acknowledged = write_ack(&handles_guard, owner_handle, ack)?;
+                        if acknowledged {
+                            deferred.unboost();
                         }
-                        Ok(mem::size_of::<usize>())
-                    } else {
-                        Ok(0)
+                        acknowledged
                     }
+                    _ => write_ack(&handles_guard, handle, ack)?,
+                };
+                Ok(if acknowledged {
+                    mem::size_of::<usize>()
                 } else {
-                    Err(Error::new(EINVAL))
-                }
+                    0
+                })
             }
             _ => Err(Error::new(EBADF)),
         }
@@ -316,6 +969,43 @@ impl crate::scheme::KernelScheme for IrqScheme {
                 st_nlink: 1,
                 ..Default::default()
             },
+            Handle::Msi { cpu_id, irq, .. } => Stat {
+                st_mode: MODE_CHR | 0o600,
+                st_size: mem::size_of::<usize>() as u64,
+                st_blocks: 1,
+                st_blksize: mem::size_of::<usize>() as u32,
+                st_ino: INO_MSI | u64::from(cpu_id) << 32 | u64::from(irq),
+                st_nlink: 1,
+                ..Default::default()
+            },
+            Handle::Gsi(gsi, _) => Stat {
+                st_mode: MODE_FILE | 0o600,
+                st_ino: INO_GSI | u64::from(gsi),
+                st_nlink: 1,
+                ..Default::default()
+            },
+            Handle::Deferred(_, _) => Stat {
+                st_mode: MODE_CHR | 0o600,
+                st_blksize: DEFERRED_EVENT_SIZE as u32,
+                st_nlink: 1,
+                ..Default::default()
+            },
+            Handle::Stats(_, _) => Stat {
+                st_mode: MODE_FILE | 0o400,
+                st_nlink: 1,
+                ..Default::default()
+            },
+            Handle::Affinity(_, _) => Stat {
+                st_mode: MODE_FILE | 0o600,
+                st_nlink: 1,
+                ..Default::default()
+            },
+            Handle::MsiMessage(ref buf, _) => Stat {
+                st_mode: MODE_FILE | 0o400,
+                st_size: buf.len() as u64,
+                st_nlink: 1,
+                ..Default::default()
+            },
             Handle::Bsp => Stat {
                 st_mode: MODE_CHR | 0o400,
                 st_size: mem::size_of::<usize>() as u64,
@@ -349,6 +1039,17 @@ impl crate::scheme::KernelScheme for IrqScheme {
 
         let scheme_path = match handle {
             Handle::Irq { irq, .. } => format!("irq:{}", irq),
+            Handle::Msi { cpu_id, irq, .. } => format!("irq:cpu-{:02x}/{}", cpu_id, irq),
+            Handle::MsiMessage(_, _) => format!("irq:msi"),
+            Handle::Affinity(AffinityTarget::Legacy(irq), _) => format!("irq:{}", irq),
+            Handle::Affinity(AffinityTarget::Msi(_), _) => format!("irq:msi"),
+            Handle::Gsi(gsi, _) => format!("irq:gsi/{}", gsi),
+            Handle::Stats(owner, _) | Handle::Deferred(owner, _) => {
+                match line_of(&handles_guard, *owner) {
+                    Some(irq) => format!("irq:{}", irq),
+                    None => format!("irq:"),
+                }
+            }
             Handle::Bsp => format!("irq:bsp"),
             Handle::Avail(cpu_id, _, _) => format!("irq:cpu-{:2x}", cpu_id),
             Handle::TopLevel(_, _) => format!("irq:"),
@@ -366,6 +1067,7 @@ impl crate::scheme::KernelScheme for IrqScheme {
             Handle::Irq {
                 irq: handle_irq,
                 ack: ref handle_ack,
+                ..
             } => {
                 if buffer.len() >= mem::size_of::<usize>() {
                     let current = COUNTS.lock()[handle_irq as usize];
@@ -379,18 +1081,77 @@ impl crate::scheme::KernelScheme for IrqScheme {
                     Err(Error::new(EINVAL))
                 }
             }
-            Handle::Bsp => {
+            Handle::Msi {
+                ref ack, ref count, ..
+            } => {
                 if buffer.len() < mem::size_of::<usize>() {
                     return Err(Error::new(EINVAL));
                 }
-                if let Some(bsp_apic_id) = bsp_apic_id() {
-                    buffer.write_u32(bsp_apic_id)?;
+                let current = count.load(Ordering::SeqCst);
+                if ack.load(Ordering::SeqCst) != current {
+                    buffer.write_usize(current)?;
                     Ok(mem::size_of::<usize>())
                 } else {
-                    Err(Error::new(EBADFD))
+                    Ok(0)
+                }
+            }
+            Handle::Deferred(_, ref deferred) => {
+                if buffer.len() < DEFERRED_EVENT_SIZE {
+                    return Err(Error::new(EINVAL));
+                }
+                let mut events = [(0, 0); DEFERRED_QUEUE_LEN];
+                let max = cmp::min(buffer.len() / DEFERRED_EVENT_SIZE, DEFERRED_QUEUE_LEN);
+                let taken = deferred.pop(&mut events[..max]);
+
+                let mut bytes_read = 0;
+                for (chunk, &(count, time)) in buffer
+                    .in_exact_chunks(DEFERRED_EVENT_SIZE)
+                    .zip(&events[..taken])
+                {
+                    let (count_buf, time_buf) = chunk
+                        .split_at(mem::size_of::<u64>())
+                        .expect("chunk must fit an event");
+                    count_buf.write_u64(count)?;
+                    time_buf.write_u64(time)?;
+                    bytes_read += DEFERRED_EVENT_SIZE;
+                }
+                Ok(bytes_read)
+            }
+            Handle::Gsi(gsi, ref offset) => {
+                let data = read_gsi(gsi)?;
+                let cur_offset = offset.load(Ordering::SeqCst);
+                let avail_buf = data.as_bytes().get(cur_offset..).unwrap_or(&[]);
+                let bytes_read = buffer.copy_common_bytes_from_slice(avail_buf)?;
+                offset.fetch_add(bytes_read, Ordering::SeqCst);
+                Ok(bytes_read)
+            }
+            Handle::Stats(owner, ref offset) => {
+                let data = format_stats(&handles_guard, owner)?;
+                let cur_offset = offset.load(Ordering::SeqCst);
+                let avail_buf = data.as_bytes().get(cur_offset..).unwrap_or(&[]);
+                let bytes_read = buffer.copy_common_bytes_from_slice(avail_buf)?;
+                offset.fetch_add(bytes_read, Ordering::SeqCst);
+                Ok(bytes_read)
+            }
+            Handle::Affinity(target, ref offset) => {
+                let data = format!("{:02x}\n", affinity(&handles_guard, target)?);
+                let cur_offset = offset.load(Ordering::SeqCst);
+                let avail_buf = data.as_bytes().get(cur_offset..).unwrap_or(&[]);
+                let bytes_read = buffer.copy_common_bytes_from_slice(avail_buf)?;
+                offset.fetch_add(bytes_read, Ordering::SeqCst);
+                Ok(bytes_read)
+            }
+            Handle::Bsp => {
+                if buffer.len() < mem::size_of::<usize>() {
+                    return Err(Error::new(EINVAL));
                 }
+                // The logical CPU ID, as in the `cpu-XX` directories.
+                buffer.write_u32(LogicalCpuId::BSP.get())?;
+                Ok(mem::size_of::<usize>())
             }
-            Handle::Avail(_, ref buf, ref offset) | Handle::TopLevel(ref buf, ref offset) => {
+            Handle::Avail(_, ref buf, ref offset)
+            | Handle::TopLevel(ref buf, ref offset)
+            | Handle::MsiMessage(ref buf, ref offset) => {
                 let cur_offset = offset.load(Ordering::SeqCst);
                 let avail_buf = buf.get(cur_offset..).unwrap_or(&[]);
                 let bytes_read = buffer.copy_common_bytes_from_slice(avail_buf)?;
@@ -1,26 +1,68 @@
-use alloc::collections::BTreeMap;
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+};
 use core::{
     mem, str,
     sync::atomic::{AtomicUsize, Ordering},
 };
 use spin::RwLock;
 
-use crate::syscall::{
-    data::ITimerSpec,
-    error::*,
-    flag::{EventFlags, CLOCK_MONOTONIC, CLOCK_REALTIME},
-    usercopy::{UserSliceRo, UserSliceWo},
+use crate::{
+    context::{
+        self,
+        memory::AddrSpaceWrapper,
+        timeout,
+        timer::{SignalTimer, TimerClock},
+    },
+    syscall::{
+        data::ITimerSpec,
+        error::*,
+        flag::{EventFlags, CLOCK_MONOTONIC, CLOCK_REALTIME, F_GETFL, F_SETFL},
+        usercopy::{UserSliceRo, UserSliceWo},
+    },
 };
 
 use super::{CallerCtx, KernelScheme, OpenResult};
 pub struct ITimerScheme;
 
+#[derive(Clone)]
+enum Handle {
+    Clock(usize),
+    /// A signal timer at `timer/<clock>`, stored with the address space of the process that
+    /// created it.
+    Timer {
+        process: Weak<AddrSpaceWrapper>,
+    },
+}
+
+/// The process of a timer handle, which fails with `ESRCH` once it has executed or exited.
+fn timer_process(process: &Weak<AddrSpaceWrapper>) -> Result<Arc<AddrSpaceWrapper>> {
+    process.upgrade().ok_or(Error::new(ESRCH))
+}
+
 static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
 // Using BTreeMap as hashbrown doesn't have a const constructor.
-static HANDLES: RwLock<BTreeMap<usize, usize>> = RwLock::new(BTreeMap::new());
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
 
 impl KernelScheme for ITimerScheme {
     fn kopen(&self, path: &str, _flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
+        if let Some(clock) = path.strip_prefix("timer/") {
+            let clock = TimerClock::from_name(clock).ok_or(Error::new(ENOENT))?;
+
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            let process = Arc::clone(context::current()?.read().addr_space()?);
+            process.timers.lock().insert(id, SignalTimer::new(clock));
+            HANDLES.write().insert(
+                id,
+                Handle::Timer {
+                    process: Arc::downgrade(&process),
+                },
+            );
+
+            return Ok(OpenResult::SchemeLocal(id));
+        }
+
         let clock = path.parse::<usize>().or(Err(Error::new(ENOENT)))?;
 
         match clock {
@@ -30,13 +72,16 @@ impl KernelScheme for ITimerScheme {
         }
 
         let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-        HANDLES.write().insert(id, clock);
+        HANDLES.write().insert(id, Handle::Clock(clock));
 
         Ok(OpenResult::SchemeLocal(id))
     }
 
-    fn fcntl(&self, _id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
-        Ok(0)
+    fn fcntl(&self, _id: usize, cmd: usize, _arg: usize) -> Result<usize> {
+        match cmd {
+            F_GETFL | F_SETFL => Ok(0),
+            _ => Err(Error::new(EINVAL)),
+        }
     }
 
     fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
@@ -52,16 +97,30 @@ impl KernelScheme for ITimerScheme {
     }
 
     fn close(&self, id: usize) -> Result<()> {
-        HANDLES
-            .write()
-            .remove(&id)
-            .ok_or(Error::new(EBADF))
-            .and(Ok(()))
+        let handle = HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        if let Handle::Timer { process } = handle {
+            if let Some(process) = process.upgrade() {
+                process.timers.lock().remove(&id);
+            }
+        }
+        Ok(())
     }
     fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
-        let _clock = {
+        let handle = {
             let handles = HANDLES.read();
-            *handles.get(&id).ok_or(Error::new(EBADF))?
+            handles.get(&id).ok_or(Error::new(EBADF))?.clone()
+        };
+        let _clock = match handle {
+            Handle::Clock(clock) => clock,
+            Handle::Timer { process } => {
+                let process = timer_process(&process)?;
+                let text = {
+                    let timers = process.timers.lock();
+                    let timer = timers.get(&id).ok_or(Error::new(EBADF))?;
+                    timer.format(timer.clock.now(&process.timers))
+                };
+                return buf.copy_common_bytes_from_slice(text.as_bytes());
+            }
         };
 
         let mut specs_read = 0;
@@ -76,9 +135,31 @@ impl KernelScheme for ITimerScheme {
     }
 
     fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
-        let _clock = {
+        let handle = {
             let handles = HANDLES.read();
-            *handles.get(&id).ok_or(Error::new(EBADF))?
+            handles.get(&id).ok_or(Error::new(EBADF))?.clone()
+        };
+        let _clock = match handle {
+            Handle::Clock(clock) => clock,
+            Handle::Timer { process } => {
+                let mut text = [0_u8; 256];
+                let len = buf.copy_common_bytes_to_slice(&mut text)?;
+                let text = str::from_utf8(&text[..len]).map_err(|_| Error::new(EINVAL))?;
+
+                let (clock, deadline) = {
+                    let process = timer_process(&process)?;
+                    let mut timers = process.timers.lock();
+                    let timer = timers.get_mut(&id).ok_or(Error::new(EBADF))?;
+                    let deadline = timer.parse_and_set(text, timer.clock.now(&process.timers))?;
+                    (timer.clock, deadline)
+                };
+                // CPU time timers are instead expired when a context of the process is switched
+                // away from.
+                if let (Some(clock), Some(deadline)) = (clock.timeout_clock(), deadline) {
+                    timeout::register_timer(process, id, clock, deadline);
+                }
+                return Ok(len);
+            }
         };
 
         let mut specs_written = 0;
@@ -86,18 +167,27 @@ impl KernelScheme for ITimerScheme {
         for chunk in buf.in_exact_chunks(mem::size_of::<ITimerSpec>()) {
             let time = unsafe { chunk.read_exact::<ITimerSpec>()? };
 
-            println!("{}: {:?}", specs_written, time);
+            log::debug!("{}: {:?}", specs_written, time);
             specs_written += 1;
         }
 
         Ok(specs_written * mem::size_of::<ITimerSpec>())
     }
     fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
-        let clock = {
+        let handle = {
             let handles = HANDLES.read();
-            *handles.get(&id).ok_or(Error::new(EBADF))?
+            handles.get(&id).ok_or(Error::new(EBADF))?.clone()
+        };
+        let path = match handle {
+            Handle::Clock(clock) => format!("time:{}", clock),
+            Handle::Timer { process } => {
+                let process = timer_process(&process)?;
+                let timers = process.timers.lock();
+                let timer = timers.get(&id).ok_or(Error::new(EBADF))?;
+                format!("itimer:timer/{}", timer.clock.name())
+            }
         };
 
-        buf.copy_common_bytes_from_slice(format!("time:{}", clock).as_bytes())
+        buf.copy_common_bytes_from_slice(path.as_bytes())
     }
 }
@@ -12,10 +12,10 @@ use crate::{
 use crate::paging::entry::EntryFlags;
 
 use crate::syscall::{
-    data::{Map, StatVfs},
+    data::{Map, Stat, StatVfs},
     error::*,
-    flag::MapFlags,
-    usercopy::UserSliceWo,
+    flag::{MapFlags, F_GETFL, F_SETFL, MODE_CHR},
+    usercopy::{UserSliceRo, UserSliceWo},
 };
 
 use super::{CallerCtx, KernelScheme, OpenResult};
@@ -28,6 +28,12 @@ pub struct MemoryScheme;
 enum HandleTy {
     Allocated = 0,
     PhysBorrow = 1,
+    /// `memory:null`, reading nothing and discarding writes.
+    Null = 2,
+    /// `memory:zero`, reading zeroes, discarding writes, and mapping zeroed memory.
+    Zero = 3,
+    /// `memory:full`, reading zeroes and failing writes with `ENOSPC`.
+    Full = 4,
 }
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -36,6 +42,9 @@ pub enum MemoryType {
     Uncacheable = 1,
     WriteCombining = 2,
     DeviceMemory = 3,
+    /// Zeroed write-back memory with allocation tags, which are all 0.
+    #[cfg(target_arch = "aarch64")]
+    Tagged = 4,
 }
 
 bitflags! {
@@ -50,6 +59,9 @@ fn from_raw(raw: u32) -> Option<(HandleTy, MemoryType, HandleFlags)> {
         match raw & 0xFF {
             0 => HandleTy::Allocated,
             1 => HandleTy::PhysBorrow,
+            2 => HandleTy::Null,
+            3 => HandleTy::Zero,
+            4 => HandleTy::Full,
 
             _ => return None,
         },
@@ -58,6 +70,8 @@ fn from_raw(raw: u32) -> Option<(HandleTy, MemoryType, HandleFlags)> {
             1 => MemoryType::Uncacheable,
             2 => MemoryType::WriteCombining,
             3 => MemoryType::DeviceMemory,
+            #[cfg(target_arch = "aarch64")]
+            4 => MemoryType::Tagged,
 
             _ => return None,
         },
@@ -65,6 +79,14 @@ fn from_raw(raw: u32) -> Option<(HandleTy, MemoryType, HandleFlags)> {
     ))
 }
 
+fn handle_ty(id: usize) -> Result<HandleTy> {
+    u32::try_from(id)
+        .ok()
+        .and_then(from_raw)
+        .map(|(handle_ty, _, _)| handle_ty)
+        .ok_or(Error::new(EBADF))
+}
+
 impl MemoryScheme {
     pub fn fmap_anonymous(
         addr_space: &Arc<AddrSpaceWrapper>,
@@ -108,6 +130,49 @@ impl MemoryScheme {
 
         Ok(page.start_address().data())
     }
+    /// Map zeroed memory with allocation tags. Unlike other anonymous memory, it is allocated
+    /// right away, as the tags of every page are cleared through the new mapping.
+    #[cfg(target_arch = "aarch64")]
+    fn fmap_tagged(addr_space: &Arc<AddrSpaceWrapper>, map: &Map) -> Result<usize> {
+        if !crate::arch::mte::supported() {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        if !map.flags.contains(MapFlags::PROT_WRITE)
+            || !Arc::ptr_eq(addr_space, &AddrSpace::current()?)
+        {
+            return Err(Error::new(EINVAL));
+        }
+        let span = PageSpan::validate_nonempty(VirtualAddress::new(map.address), map.size)
+            .ok_or(Error::new(EINVAL))?;
+        let page_count = NonZeroUsize::new(span.count).ok_or(Error::new(EINVAL))?;
+
+        let mut notify_files = Vec::new();
+
+        let page = addr_space.acquire_write().mmap(
+            &addr_space,
+            (map.address != 0).then_some(span.base),
+            page_count,
+            map.flags,
+            &mut notify_files,
+            |dst_page, flags, mapper, flusher| {
+                Ok(Grant::zeroed(
+                    PageSpan::new(dst_page, page_count.get()),
+                    crate::arch::mte::page_flags(flags),
+                    mapper,
+                    flusher,
+                    map.flags.contains(MapFlags::MAP_SHARED),
+                )?)
+            },
+        )?;
+
+        handle_notify_files(notify_files);
+
+        // This only fails if another thread unmapped the memory meanwhile.
+        let base = page.start_address().data();
+        crate::arch::mte::clear_user_tags(base, page_count.get() * PAGE_SIZE)?;
+
+        Ok(base)
+    }
     pub fn physmap(
         physical_address: usize,
         size: usize,
@@ -187,6 +252,10 @@ impl KernelScheme for MemoryScheme {
         let handle_ty = match before_memty {
             "" | "zeroed" => HandleTy::Allocated,
             "physical" => HandleTy::PhysBorrow,
+            "null" | "zero" | "full" if !memty_str.is_empty() => return Err(Error::new(ENOENT)),
+            "null" => HandleTy::Null,
+            "zero" => HandleTy::Zero,
+            "full" => HandleTy::Full,
 
             _ => return Err(Error::new(ENOENT)),
         };
@@ -195,6 +264,8 @@ impl KernelScheme for MemoryScheme {
             "wc" => MemoryType::WriteCombining,
             "uc" => MemoryType::Uncacheable,
             "dev" => MemoryType::DeviceMemory,
+            #[cfg(target_arch = "aarch64")]
+            "tagged" => MemoryType::Tagged,
 
             _ => return Err(Error::new(ENOENT)),
         };
@@ -210,14 +281,20 @@ impl KernelScheme for MemoryScheme {
             .collect::<Option<HandleFlags>>()
             .ok_or(Error::new(ENOENT))?;
 
+        #[cfg(target_arch = "aarch64")]
+        if mem_ty == MemoryType::Tagged && (handle_ty != HandleTy::Allocated || !flags.is_empty()) {
+            return Err(Error::new(EINVAL));
+        }
+
         // TODO: Support arches with other default memory types?
-        if ctx.uid != 0
-            && (!flags.is_empty()
-                || !matches!(
-                    (handle_ty, mem_ty),
-                    (HandleTy::Allocated, MemoryType::Writeback)
-                ))
-        {
+        let unprivileged = match (handle_ty, mem_ty) {
+            (HandleTy::Allocated, MemoryType::Writeback) => true,
+            (HandleTy::Null | HandleTy::Zero | HandleTy::Full, _) => true,
+            #[cfg(target_arch = "aarch64")]
+            (HandleTy::Allocated, MemoryType::Tagged) => true,
+            _ => false,
+        };
+        if ctx.uid != 0 && (!flags.is_empty() || !unprivileged) {
             return Err(Error::new(EACCES));
         }
 
@@ -226,13 +303,68 @@ impl KernelScheme for MemoryScheme {
         ))
     }
 
-    fn fcntl(&self, _id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
-        Ok(0)
+    fn fcntl(&self, _id: usize, cmd: usize, _arg: usize) -> Result<usize> {
+        match cmd {
+            F_GETFL | F_SETFL => Ok(0),
+            _ => Err(Error::new(EINVAL)),
+        }
     }
 
     fn close(&self, _id: usize) -> Result<()> {
         Ok(())
     }
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        match handle_ty(id)? {
+            HandleTy::Null => Ok(0),
+            HandleTy::Zero | HandleTy::Full => {
+                const ZEROES: [u8; 512] = [0; 512];
+                let mut bytes_read = 0;
+                for chunk in buf.in_variable_chunks(ZEROES.len()) {
+                    match chunk.copy_common_bytes_from_slice(&ZEROES) {
+                        Ok(count) => bytes_read += count,
+                        Err(_) if bytes_read > 0 => break,
+                        Err(error) => return Err(error),
+                    }
+                }
+                Ok(bytes_read)
+            }
+            HandleTy::Allocated | HandleTy::PhysBorrow => Err(Error::new(EBADF)),
+        }
+    }
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        match handle_ty(id)? {
+            HandleTy::Null | HandleTy::Zero => Ok(buf.len()),
+            HandleTy::Full if buf.is_empty() => Ok(0),
+            HandleTy::Full => Err(Error::new(ENOSPC)),
+            HandleTy::Allocated | HandleTy::PhysBorrow => Err(Error::new(EBADF)),
+        }
+    }
+    fn seek(&self, id: usize, _pos: isize, _whence: usize) -> Result<usize> {
+        match handle_ty(id)? {
+            // The position is always 0, as with character devices elsewhere.
+            HandleTy::Null | HandleTy::Zero | HandleTy::Full => Ok(0),
+            HandleTy::Allocated | HandleTy::PhysBorrow => Err(Error::new(ESPIPE)),
+        }
+    }
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<()> {
+        match handle_ty(id)? {
+            HandleTy::Null | HandleTy::Zero | HandleTy::Full => buf.copy_exactly(&Stat {
+                st_mode: MODE_CHR | 0o666,
+                st_blksize: PAGE_SIZE as u32,
+                ..Default::default()
+            }),
+            HandleTy::Allocated | HandleTy::PhysBorrow => Err(Error::new(EBADF)),
+        }
+    }
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let path: &[u8] = match handle_ty(id)? {
+            HandleTy::Null => b"memory:null",
+            HandleTy::Zero => b"memory:zero",
+            HandleTy::Full => b"memory:full",
+            HandleTy::Allocated | HandleTy::PhysBorrow => return Err(Error::new(EBADF)),
+        };
+        buf.copy_common_bytes_from_slice(path)
+    }
     fn kfmap(
         &self,
         id: usize,
@@ -246,12 +378,18 @@ impl KernelScheme for MemoryScheme {
             .ok_or(Error::new(EBADF))?;
 
         match handle_ty {
+            #[cfg(target_arch = "aarch64")]
+            HandleTy::Allocated if mem_ty == MemoryType::Tagged => {
+                Self::fmap_tagged(addr_space, map)
+            }
             HandleTy::Allocated => Self::fmap_anonymous(
                 addr_space,
                 map,
                 flags.contains(HandleFlags::PHYS_CONTIGUOUS),
             ),
             HandleTy::PhysBorrow => Self::physmap(map.offset, map.size, map.flags, mem_ty),
+            HandleTy::Zero => Self::fmap_anonymous(addr_space, map, false),
+            HandleTy::Null | HandleTy::Full => Err(Error::new(ENODEV)),
         }
     }
     fn kfstatvfs(&self, _file: usize, dst: UserSliceWo) -> Result<()> {
@@ -14,9 +14,10 @@ use syscall::{EventFlags, MunmapFlags, SendFdFlags, SEEK_CUR, SEEK_END, SEEK_SET
 
 use crate::{
     context::{file::FileDescription, memory::AddrSpaceWrapper},
+    sync::Tracked,
     syscall::{
         error::*,
-        usercopy::{UserSliceRo, UserSliceWo},
+        usercopy::{UserSlice, UserSliceRo, UserSliceWo},
     },
 };
 
@@ -27,11 +28,11 @@ use self::dtb::DtbScheme;
 
 use self::{
     debug::DebugScheme, event::EventScheme, irq::IrqScheme, itimer::ITimerScheme,
-    memory::MemoryScheme, pipe::PipeScheme, proc::ProcScheme, root::RootScheme, serio::SerioScheme,
-    sys::SysScheme, time::TimeScheme, user::UserScheme,
+    memory::MemoryScheme, pipe::PipeScheme, power::PowerScheme, proc::ProcScheme, root::RootScheme,
+    serio::SerioScheme, shm::ShmScheme, sys::SysScheme, time::TimeScheme, user::UserScheme,
 };
 
-/// When compiled with the "acpi" feature - `acpi:` - allows drivers to read a limited set of ACPI tables.
+/// When compiled with the "acpi" feature - `acpi:` - allows drivers to read the ACPI tables, receive ACPI events and put the system to sleep.
 #[cfg(all(feature = "acpi", any(target_arch = "x86", target_arch = "x86_64")))]
 pub mod acpi;
 #[cfg(all(any(target_arch = "aarch64")))]
@@ -55,6 +56,9 @@ pub mod memory;
 /// `pipe:` - used internally by the kernel to implement `pipe`
 pub mod pipe;
 
+/// `power:` - allows powering off and resetting the system
+pub mod power;
+
 /// `proc:` - allows tracing processes and reading/writing their memory
 pub mod proc;
 
@@ -64,6 +68,9 @@ pub mod root;
 /// `serio:` - provides access to ps/2 devices
 pub mod serio;
 
+/// `shm:` - named shared memory objects, mapped into several address spaces
+pub mod shm;
+
 /// `sys:` - system information, such as the context list and scheme list
 pub mod sys;
 
@@ -137,6 +144,8 @@ impl SchemeList {
                 Sys,
                 ProcFull,
                 ProcRestricted,
+                Power,
+                Shm,
             ]);
 
             #[cfg(all(feature = "acpi", any(target_arch = "x86", target_arch = "x86_64")))]
@@ -182,6 +191,7 @@ impl SchemeList {
         self.insert_global(ns, "memory", GlobalSchemes::Memory)
             .unwrap();
         self.insert_global(ns, "pipe", GlobalSchemes::Pipe).unwrap();
+        self.insert_global(ns, "shm", GlobalSchemes::Shm).unwrap();
         self.insert_global(ns, "sys", GlobalSchemes::Sys).unwrap();
         self.insert_global(ns, "time", GlobalSchemes::Time).unwrap();
 
@@ -213,6 +223,8 @@ impl SchemeList {
             .unwrap();
         self.insert_global(ns, "serio", GlobalSchemes::Serio)
             .unwrap();
+        self.insert_global(ns, "power", GlobalSchemes::Power)
+            .unwrap();
     }
 
     pub fn make_ns(
@@ -255,6 +267,15 @@ impl SchemeList {
         self.map.get(&id)
     }
 
+    /// The name scheme `id` is registered under in namespace `ns`, if any.
+    pub fn name_of(&self, ns: SchemeNamespace, id: SchemeId) -> Option<&str> {
+        self.names
+            .get(&ns)?
+            .iter()
+            .find(|(_, &scheme_id)| scheme_id == id)
+            .map(|(name, _)| &**name)
+    }
+
     pub fn get_name(&self, ns: SchemeNamespace, name: &str) -> Option<(SchemeId, &KernelSchemes)> {
         if let Some(names) = self.names.get(&ns) {
             if let Some(&id) = names.get(name) {
@@ -363,13 +384,27 @@ fn init_schemes() -> RwLock<SchemeList> {
 }
 
 /// Get the global schemes list, const
-pub fn schemes() -> RwLockReadGuard<'static, SchemeList> {
-    SCHEMES.call_once(init_schemes).read()
+pub fn schemes() -> Tracked<RwLockReadGuard<'static, SchemeList>> {
+    let schemes = SCHEMES.call_once(init_schemes);
+
+    #[cfg(feature = "lock_debug")]
+    return crate::sync::lock_debug::SCHEME_LIST
+        .acquire(|| schemes.try_read(), core::hint::spin_loop);
+
+    #[cfg(not(feature = "lock_debug"))]
+    schemes.read()
 }
 
 /// Get the global schemes list, mutable
-pub fn schemes_mut() -> RwLockWriteGuard<'static, SchemeList> {
-    SCHEMES.call_once(init_schemes).write()
+pub fn schemes_mut() -> Tracked<RwLockWriteGuard<'static, SchemeList>> {
+    let schemes = SCHEMES.call_once(init_schemes);
+
+    #[cfg(feature = "lock_debug")]
+    return crate::sync::lock_debug::SCHEME_LIST
+        .acquire(|| schemes.try_write(), core::hint::spin_loop);
+
+    #[cfg(not(feature = "lock_debug"))]
+    schemes.write()
 }
 
 #[allow(unused_variables)]
@@ -400,6 +435,26 @@ pub trait KernelScheme: Send + Sync + 'static {
     fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
         Err(Error::new(EBADF))
     }
+    /// Read into `bufs` in turn, as a single read. By default, only the first buffer which is not
+    /// empty is read into, since reading again could block after some bytes were read.
+    fn kreadv(&self, id: usize, bufs: &[UserSliceWo]) -> Result<usize> {
+        self.kread(id, first_nonempty(bufs))
+    }
+    /// Write `bufs` in turn, as a single write. By default, the buffers are written one at a time,
+    /// until a write is short or fails.
+    fn kwritev(&self, id: usize, bufs: &[UserSliceRo]) -> Result<usize> {
+        write_each(self, id, bufs)
+    }
+    /// Read into `buf` from `offset`, leaving the file offset of `id` unchanged. Fails with
+    /// `ESPIPE` for files which cannot be read at an offset.
+    fn kread_at(&self, id: usize, buf: UserSliceWo, offset: u64) -> Result<usize> {
+        Err(Error::new(ESPIPE))
+    }
+    /// Write `buf` at `offset`, leaving the file offset of `id` unchanged. Fails with `ESPIPE` for
+    /// files which cannot be written at an offset.
+    fn kwrite_at(&self, id: usize, buf: UserSliceRo, offset: u64) -> Result<usize> {
+        Err(Error::new(ESPIPE))
+    }
     fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
         Err(Error::new(EBADF))
     }
@@ -469,6 +524,37 @@ pub struct CallerCtx {
     pub gid: u32,
 }
 
+/// The first buffer of a vectored read or write which is not empty, or an empty one.
+pub fn first_nonempty<const READ: bool, const WRITE: bool>(
+    bufs: &[UserSlice<READ, WRITE>],
+) -> UserSlice<READ, WRITE> {
+    bufs.iter()
+        .copied()
+        .find(|buf| !buf.is_empty())
+        .unwrap_or(UserSlice::empty())
+}
+
+/// Write `bufs` to `scheme` one at a time, until a write is short or fails.
+pub fn write_each<S: KernelScheme + ?Sized>(
+    scheme: &S,
+    id: usize,
+    bufs: &[UserSliceRo],
+) -> Result<usize> {
+    let mut written = 0;
+    for &buf in bufs {
+        let count = match scheme.kwrite(id, buf) {
+            Ok(count) => count,
+            Err(_) if written > 0 => break,
+            Err(error) => return Err(error),
+        };
+        written += count;
+        if count < buf.len() {
+            break;
+        }
+    }
+    Ok(written)
+}
+
 pub fn calc_seek_offset(
     cur_pos: usize,
     rel_pos: isize,
@@ -506,6 +592,8 @@ pub enum GlobalSchemes {
     Sys,
     ProcFull,
     ProcRestricted,
+    Power,
+    Shm,
 
     #[cfg(all(feature = "acpi", any(target_arch = "x86", target_arch = "x86_64")))]
     Acpi,
@@ -547,6 +635,8 @@ impl core::ops::Deref for GlobalSchemes {
             Self::Sys => &SysScheme,
             Self::ProcFull => &ProcScheme::<true>,
             Self::ProcRestricted => &ProcScheme::<false>,
+            Self::Power => &PowerScheme,
+            Self::Shm => &ShmScheme,
             #[cfg(all(feature = "acpi", any(target_arch = "x86", target_arch = "x86_64")))]
             Self::Acpi => &AcpiScheme,
             #[cfg(target_arch = "aarch64")]
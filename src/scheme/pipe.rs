@@ -2,20 +2,29 @@ use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use alloc::{
     collections::{BTreeMap, VecDeque},
+    string::String,
     sync::Arc,
+    vec::Vec,
 };
+use core::fmt::Write;
 
 use spin::{Mutex, RwLock};
 
 use crate::{
-    event,
+    context, event,
+    memory::PAGE_SIZE,
     sync::WaitCondition,
     syscall::{
         data::Stat,
-        error::{Error, Result, EAGAIN, EBADF, EINTR, EINVAL, ENOENT, EPIPE, ESPIPE},
+        error::{Error, Result, EAGAIN, EBADF, EBUSY, EINTR, EINVAL, ENOENT, EPERM, EPIPE, ESPIPE},
+        ext::{
+            EVENT_HANGUP, FIONREAD, F_GETPIPE_SZ, F_GETRCVTIMEO, F_SETPIPE_SZ, F_SETRCVTIMEO,
+            F_SHUTDOWN, SHUT_RD, SHUT_RDWR, SHUT_WR, SPLICE_F_NONBLOCK,
+        },
         flag::{
             EventFlags, EVENT_READ, EVENT_WRITE, F_GETFL, F_SETFL, MODE_FIFO, O_ACCMODE, O_NONBLOCK,
         },
+        fs::rcvtimeo_deadline,
         usercopy::{UserSliceRo, UserSliceWo},
     },
 };
@@ -30,53 +39,354 @@ static PIPE_NEXT_ID: AtomicUsize = AtomicUsize::new(1);
 // Using BTreeMap as hashbrown doesn't have a const constructor.
 static PIPES: RwLock<BTreeMap<usize, Arc<Pipe>>> = RwLock::new(BTreeMap::new());
 
-const MAX_QUEUE_SIZE: usize = 65536;
+/// Capacity of new pipes, in bytes.
+const DEFAULT_PIPE_SIZE: usize = 65536;
+/// Upper bound of pipe capacities, even for root.
+const PIPE_SIZE_LIMIT: usize = 1 << 30;
+
+/// Largest capacity unprivileged users can set, configured through `sys:pipe_limits`.
+static MAX_PIPE_SIZE: AtomicUsize = AtomicUsize::new(1024 * 1024);
+/// Total capacity of the pipes created by an unprivileged user, beyond which their pipes cannot
+/// grow, configured through `sys:pipe_limits`.
+static USER_PIPE_LIMIT: AtomicUsize = AtomicUsize::new(16 * 1024 * 1024);
+/// Total capacity of the pipes of each user, by the uid that created them.
+static USER_PIPE_SIZES: Mutex<BTreeMap<u32, usize>> = Mutex::new(BTreeMap::new());
+
+/// Size of the buffers pipe contents are stored in.
+const CHUNK_SIZE: usize = PAGE_SIZE;
+/// Splice and tee copy fewer bytes than this out of a buffer, rather than sharing it. A shared
+/// buffer cannot be appended to, so sharing small pieces would let every write allocate a new
+/// buffer for a few bytes, while pipes are only charged for the bytes they hold.
+const SHARE_MIN: usize = CHUNK_SIZE / 2;
 
 // In almost all places where Rust (and LLVM) uses pointers, they are limited to nonnegative isize,
 // so this is fine.
 const WRITE_NOT_READ_BIT: usize = 1 << (usize::BITS - 1);
+const DUPLEX_BIT: usize = 1 << (usize::BITS - 2);
+
+#[derive(Clone, Copy)]
+enum End {
+    Read(usize),
+    Write(usize),
+    /// An end of a duplex pipe, writing to the pipe of the key, and reading from its reverse.
+    Duplex(usize),
+}
 
-fn from_raw_id(id: usize) -> (bool, usize) {
-    (id & WRITE_NOT_READ_BIT != 0, id & !WRITE_NOT_READ_BIT)
+fn from_raw_id(id: usize) -> End {
+    let key = id & !(WRITE_NOT_READ_BIT | DUPLEX_BIT);
+    if id & DUPLEX_BIT != 0 {
+        End::Duplex(key)
+    } else if id & WRITE_NOT_READ_BIT != 0 {
+        End::Write(key)
+    } else {
+        End::Read(key)
+    }
 }
 
-pub fn pipe(flags: usize) -> Result<(usize, usize)> {
-    let id = PIPE_NEXT_ID.fetch_add(1, Ordering::Relaxed);
+fn get(key: usize) -> Result<Arc<Pipe>> {
+    Ok(Arc::clone(PIPES.read().get(&key).ok_or(Error::new(EBADF))?))
+}
+/// The pipe read through the file `id`, and its key.
+fn read_pipe(id: usize) -> Result<(usize, Arc<Pipe>)> {
+    match from_raw_id(id) {
+        End::Read(key) => Ok((key, get(key)?)),
+        End::Write(_) => Err(Error::new(EBADF)),
+        End::Duplex(key) => {
+            let reverse = get(key)?.reverse.ok_or(Error::new(EBADF))?;
+            Ok((reverse, get(reverse)?))
+        }
+    }
+}
+/// The pipe written through the file `id`, and its key.
+fn write_pipe(id: usize) -> Result<(usize, Arc<Pipe>)> {
+    match from_raw_id(id) {
+        End::Read(_) => Err(Error::new(EBADF)),
+        End::Write(key) | End::Duplex(key) => Ok((key, get(key)?)),
+    }
+}
 
-    PIPES.write().insert(
-        id,
-        Arc::new(Pipe {
-            read_flags: AtomicUsize::new(flags),
-            write_flags: AtomicUsize::new(flags),
-            queue: Mutex::new(VecDeque::new()),
-            read_condition: WaitCondition::new(),
-            write_condition: WaitCondition::new(),
-            writer_is_alive: AtomicBool::new(true),
-            reader_is_alive: AtomicBool::new(true),
-            has_run_dup: AtomicBool::new(false),
-        }),
+/// Create a pipe, returning the ids of its read and write ends. Both ends start with `flags`.
+pub fn pipe(flags: usize, uid: u32) -> Result<(usize, usize)> {
+    let key = PIPE_NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let pipe = Pipe::new(flags, uid, None);
+    pipe.has_run_dup.store(true, Ordering::Relaxed);
+    pipe.open_ends.store(2, Ordering::Relaxed);
+    insert(key, pipe);
+
+    Ok((key, key | WRITE_NOT_READ_BIT))
+}
+
+/// Create a pair of pipes in opposite directions, returning the id of the first end of the duplex
+/// pipe. The second end is obtained by duplicating it with `peer`.
+fn duplex(flags: usize, uid: u32) -> usize {
+    let key = PIPE_NEXT_ID.fetch_add(2, Ordering::Relaxed);
+    insert(key, Pipe::new(flags, uid, Some(key + 1)));
+    insert(key + 1, Pipe::new(flags, uid, Some(key)));
+
+    key | DUPLEX_BIT
+}
+
+fn insert(key: usize, pipe: Pipe) {
+    *USER_PIPE_SIZES.lock().entry(pipe.uid).or_insert(0) += DEFAULT_PIPE_SIZE;
+    PIPES.write().insert(key, Arc::new(pipe));
+}
+
+/// Stop reading the pipe `key`, so that writes to it fail with `EPIPE`.
+fn shut_reader(key: usize, pipe: &Pipe) {
+    // The peer is only notified once the end is marked dead, so that it sees the hangup when
+    // polling again.
+    if pipe.reader_is_alive.swap(false, Ordering::SeqCst) {
+        event::trigger(
+            GlobalSchemes::Pipe.scheme_id(),
+            pipe.writer_id(key),
+            EVENT_WRITE | EVENT_HANGUP,
+        );
+        pipe.write_condition.notify();
+    }
+}
+
+/// Stop writing the pipe `key`, so that reads from it return EOF once it is drained.
+fn shut_writer(key: usize, pipe: &Pipe) {
+    if pipe.writer_is_alive.swap(false, Ordering::SeqCst) {
+        event::trigger(
+            GlobalSchemes::Pipe.scheme_id(),
+            pipe.reader_id(key),
+            EVENT_READ | EVENT_HANGUP,
+        );
+        pipe.read_condition.notify();
+    }
+}
+
+/// Drop the reference of a closed file to the pipe `key`, removing the pipe after the last one.
+fn release(key: usize, pipe: &Pipe) {
+    if pipe.open_ends.fetch_sub(1, Ordering::SeqCst) != 1 {
+        return;
+    }
+    let _ = PIPES.write().remove(&key);
+
+    let mut sizes = USER_PIPE_SIZES.lock();
+    if let Some(total) = sizes.get_mut(&pipe.uid) {
+        *total -= pipe.capacity.load(Ordering::SeqCst);
+        if *total == 0 {
+            sizes.remove(&pipe.uid);
+        }
+    }
+}
+
+/// Set the capacity of `pipe`, returning the capacity after rounding.
+fn set_size(key: usize, pipe: &Pipe, size: usize, privileged: bool) -> Result<usize> {
+    if size > PIPE_SIZE_LIMIT {
+        return Err(Error::new(EINVAL));
+    }
+    let size = size.max(1).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    if !privileged && size > MAX_PIPE_SIZE.load(Ordering::Relaxed) {
+        return Err(Error::new(EPERM));
+    }
+
+    let queue = pipe.queue.lock();
+    if queue.len() > size {
+        return Err(Error::new(EBUSY));
+    }
+    let old_size = pipe.capacity.load(Ordering::SeqCst);
+    {
+        let mut sizes = USER_PIPE_SIZES.lock();
+        let total = sizes.entry(pipe.uid).or_insert(0);
+        let new_total = *total - old_size + size;
+        if !privileged && size > old_size && new_total > USER_PIPE_LIMIT.load(Ordering::Relaxed) {
+            return Err(Error::new(EPERM));
+        }
+        *total = new_total;
+    }
+    pipe.capacity.store(size, Ordering::SeqCst);
+
+    if queue.len() >= old_size && queue.len() < size {
+        event::trigger(
+            GlobalSchemes::Pipe.scheme_id(),
+            pipe.writer_id(key),
+            EVENT_WRITE,
+        );
+        pipe.write_condition.notify();
+    }
+    Ok(size)
+}
+
+/// Read handler of `sys:pipe`, listing the pipe limits and the total pipe capacity of each user.
+pub fn limits_resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+    let _ = writeln!(string, "max_size {}", MAX_PIPE_SIZE.load(Ordering::Relaxed));
+    let _ = writeln!(
+        string,
+        "user_limit {}",
+        USER_PIPE_LIMIT.load(Ordering::Relaxed)
     );
+    for (uid, size) in USER_PIPE_SIZES.lock().iter() {
+        let _ = writeln!(string, "user {} {}", uid, size);
+    }
+    Ok(string.into_bytes())
+}
 
-    Ok((id, id | WRITE_NOT_READ_BIT))
+/// Write handler of `sys:pipe_limits`, taking `max_size` and `user_limit` lines in bytes.
+pub fn write_limits(buf: &[u8]) -> Result<usize> {
+    let text = core::str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+    let mut max_size = MAX_PIPE_SIZE.load(Ordering::Relaxed);
+    let mut user_limit = USER_PIPE_LIMIT.load(Ordering::Relaxed);
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (key, value) = line.split_once(' ').ok_or(Error::new(EINVAL))?;
+        let value = value.trim().parse().map_err(|_| Error::new(EINVAL))?;
+        match key {
+            "max_size" => max_size = value,
+            "user_limit" => user_limit = value,
+            _ => return Err(Error::new(EINVAL)),
+        }
+    }
+    if max_size < PAGE_SIZE || max_size > PIPE_SIZE_LIMIT {
+        return Err(Error::new(EINVAL));
+    }
+    MAX_PIPE_SIZE.store(max_size, Ordering::Relaxed);
+    USER_PIPE_LIMIT.store(user_limit, Ordering::Relaxed);
+    Ok(buf.len())
 }
 
-pub struct PipeScheme;
+/// Move up to `len` bytes buffered in the pipe read by `src_id` to the pipe written by `dst_id`,
+/// or with `tee`, duplicate them while leaving them in the source pipe. The buffers are shared by
+/// reference instead of being copied. Blocks until some bytes can be transferred, unless
+/// [`SPLICE_F_NONBLOCK`] is set, and returns 0 at the end of the source.
+pub fn splice(src_id: usize, dst_id: usize, len: usize, flags: usize, tee: bool) -> Result<usize> {
+    let (src_key, src) = read_pipe(src_id)?;
+    let (dst_key, dst) = write_pipe(dst_id)?;
+    if src_key == dst_key {
+        return Err(Error::new(EINVAL));
+    }
+    let scheme_id = GlobalSchemes::Pipe.scheme_id();
 
-impl KernelScheme for PipeScheme {
-    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
-        let (is_writer_not_reader, key) = from_raw_id(id);
-        let pipe = Arc::clone(PIPES.read().get(&key).ok_or(Error::new(EBADF))?);
+    loop {
+        // Lock in key order, so that transfers in opposite directions cannot deadlock.
+        let (mut src_queue, mut dst_queue) = if src_key < dst_key {
+            let src_queue = src.queue.lock();
+            (src_queue, dst.queue.lock())
+        } else {
+            let dst_queue = dst.queue.lock();
+            (src.queue.lock(), dst_queue)
+        };
+
+        if !dst.reader_is_alive.load(Ordering::SeqCst) {
+            return Err(Error::new(EPIPE));
+        }
+        let room = dst
+            .capacity
+            .load(Ordering::SeqCst)
+            .saturating_sub(dst_queue.len());
+        let count = len.min(src_queue.len()).min(room);
+
+        if count > 0 {
+            let src_was_full = src_queue.len() >= src.capacity.load(Ordering::SeqCst);
+            let dst_was_empty = dst_queue.is_empty();
+
+            if tee {
+                src_queue.share_front(count, &mut dst_queue);
+            } else {
+                src_queue.move_front(count, &mut dst_queue);
+            }
 
-        let flags = if is_writer_not_reader {
-            &pipe.write_flags
+            // As with reads and writes, only notify on readiness transitions.
+            if dst_was_empty {
+                event::trigger(scheme_id, dst.reader_id(dst_key), EVENT_READ);
+            }
+            dst.read_condition.notify();
+            if !tee {
+                if src_was_full {
+                    event::trigger(scheme_id, src.writer_id(src_key), EVENT_WRITE);
+                }
+                src.write_condition.notify();
+            }
+            return Ok(count);
+        } else if len == 0 {
+            return Ok(0);
+        }
+
+        if src_queue.is_empty() && !src.writer_is_alive.load(Ordering::SeqCst) {
+            return Ok(0);
+        } else if flags & SPLICE_F_NONBLOCK == SPLICE_F_NONBLOCK {
+            return Err(Error::new(EAGAIN));
+        }
+        let waited = if src_queue.is_empty() {
+            drop(dst_queue);
+            src.read_condition.wait(src_queue, "Pipe::splice")
         } else {
-            &pipe.read_flags
+            drop(src_queue);
+            dst.write_condition.wait(dst_queue, "Pipe::splice")
         };
+        if !waited {
+            return Err(Error::new(EINTR));
+        }
+    }
+}
+
+pub struct PipeScheme;
+
+impl KernelScheme for PipeScheme {
+    fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let end = from_raw_id(id);
+        let read = read_pipe(id).ok();
+        let write = write_pipe(id).ok();
+        if read.is_none() && write.is_none() {
+            return Err(Error::new(EBADF));
+        }
 
         match cmd {
-            F_GETFL => Ok(flags.load(Ordering::SeqCst)),
+            F_GETFL => Ok(match (read, write) {
+                (_, Some((_, pipe))) => pipe.write_flags.load(Ordering::SeqCst),
+                (Some((_, pipe)), None) => pipe.read_flags.load(Ordering::SeqCst),
+                (None, None) => unreachable!(),
+            }),
+            // The flags of a duplex end apply to both of its directions.
             F_SETFL => {
-                flags.store(arg & !O_ACCMODE, Ordering::SeqCst);
+                if let Some((_, ref pipe)) = read {
+                    pipe.read_flags.store(arg & !O_ACCMODE, Ordering::SeqCst);
+                }
+                if let Some((_, ref pipe)) = write {
+                    pipe.write_flags.store(arg & !O_ACCMODE, Ordering::SeqCst);
+                }
+                Ok(0)
+            }
+            F_GETRCVTIMEO => {
+                let (_, pipe) = read.ok_or(Error::new(EINVAL))?;
+                Ok(pipe.read_timeout.load(Ordering::SeqCst))
+            }
+            F_SETRCVTIMEO => {
+                let (_, pipe) = read.ok_or(Error::new(EINVAL))?;
+                pipe.read_timeout.store(arg, Ordering::SeqCst);
+                Ok(0)
+            }
+            // The capacity of a duplex end is that of the direction it writes to.
+            F_GETPIPE_SZ => {
+                let (_, pipe) = write.or(read).ok_or(Error::new(EBADF))?;
+                Ok(pipe.capacity.load(Ordering::SeqCst))
+            }
+            F_SETPIPE_SZ => {
+                let (key, pipe) = write.or(read).ok_or(Error::new(EBADF))?;
+                let privileged = context::current()?.read().euid == 0;
+                set_size(key, &pipe, arg, privileged)
+            }
+            FIONREAD => {
+                let (_, pipe) = read.or(write).ok_or(Error::new(EBADF))?;
+                Ok(pipe.queue.lock().len())
+            }
+            F_SHUTDOWN => {
+                let (End::Duplex(_), Some((read_key, read)), Some((write_key, write))) =
+                    (end, read, write)
+                else {
+                    return Err(Error::new(EINVAL));
+                };
+                match arg {
+                    SHUT_RD => shut_reader(read_key, &read),
+                    SHUT_WR => shut_writer(write_key, &write),
+                    SHUT_RDWR => {
+                        shut_reader(read_key, &read);
+                        shut_writer(write_key, &write);
+                    }
+                    _ => return Err(Error::new(EINVAL)),
+                }
                 Ok(0)
             }
             _ => Err(Error::new(EINVAL)),
@@ -84,16 +394,35 @@ impl KernelScheme for PipeScheme {
     }
 
     fn fevent(&self, id: usize, flags: EventFlags) -> Result<EventFlags> {
-        let (is_writer_not_reader, key) = from_raw_id(id);
-        let pipe = Arc::clone(PIPES.read().get(&key).ok_or(Error::new(EBADF))?);
+        let read = read_pipe(id).ok();
+        let write = write_pipe(id).ok();
+        if read.is_none() && write.is_none() {
+            return Err(Error::new(EBADF));
+        }
 
         let mut ready = EventFlags::empty();
-
-        if is_writer_not_reader && flags == EVENT_WRITE && pipe.queue.lock().len() <= MAX_QUEUE_SIZE {
-            ready |= EventFlags::EVENT_WRITE;
+        // A closed peer is reported as ready as well, since reads return EOF and writes EPIPE, and
+        // as a hangup whether registered for or not. Duplex ends only hang up once both
+        // directions are shut down.
+        let mut hangup = true;
+
+        if let Some((_, pipe)) = read {
+            let peer_gone = !pipe.writer_is_alive.load(Ordering::SeqCst);
+            if flags.contains(EVENT_READ) && (!pipe.queue.lock().is_empty() || peer_gone) {
+                ready |= EVENT_READ;
+            }
+            hangup &= peer_gone;
+        }
+        if let Some((_, pipe)) = write {
+            let peer_gone = !pipe.reader_is_alive.load(Ordering::SeqCst);
+            let has_room = pipe.queue.lock().len() < pipe.capacity.load(Ordering::SeqCst);
+            if flags.contains(EVENT_WRITE) && (has_room || peer_gone) {
+                ready |= EVENT_WRITE;
+            }
+            hangup &= peer_gone;
         }
-        if !is_writer_not_reader && flags == EVENT_READ && !pipe.queue.lock().is_empty() {
-            ready |= EventFlags::EVENT_READ;
+        if hangup {
+            ready |= EVENT_HANGUP;
         }
 
         Ok(ready)
@@ -104,29 +433,23 @@ impl KernelScheme for PipeScheme {
     }
 
     fn close(&self, id: usize) -> Result<()> {
-        let (is_write_not_read, key) = from_raw_id(id);
-
-        let pipe = Arc::clone(PIPES.read().get(&key).ok_or(Error::new(EBADF))?);
-        let scheme_id = GlobalSchemes::Pipe.scheme_id();
-
-        let can_remove = if is_write_not_read {
-            event::trigger(scheme_id, key, EVENT_READ);
-
-            pipe.read_condition.notify();
-            pipe.writer_is_alive.store(false, Ordering::SeqCst);
-
-            !pipe.reader_is_alive.load(Ordering::SeqCst)
-        } else {
-            event::trigger(scheme_id, key | WRITE_NOT_READ_BIT, EVENT_WRITE);
-
-            pipe.write_condition.notify();
-            pipe.reader_is_alive.store(false, Ordering::SeqCst);
-
-            !pipe.writer_is_alive.load(Ordering::SeqCst)
-        };
+        let read = read_pipe(id).ok();
+        let write = write_pipe(id).ok();
+        if read.is_none() && write.is_none() {
+            return Err(Error::new(EBADF));
+        }
 
-        if can_remove {
-            let _ = PIPES.write().remove(&key);
+        if let Some((key, ref pipe)) = read {
+            shut_reader(key, pipe);
+        }
+        if let Some((key, ref pipe)) = write {
+            shut_writer(key, pipe);
+        }
+        if let Some((key, ref pipe)) = read {
+            release(key, pipe);
+        }
+        if let Some((key, ref pipe)) = write {
+            release(key, pipe);
         }
 
         Ok(())
@@ -136,74 +459,92 @@ impl KernelScheme for PipeScheme {
         Err(Error::new(ESPIPE))
     }
     fn kdup(&self, old_id: usize, user_buf: UserSliceRo, _ctx: CallerCtx) -> Result<OpenResult> {
-        let (is_writer_not_reader, key) = from_raw_id(old_id);
-
-        if is_writer_not_reader {
-            return Err(Error::new(EBADF));
-        }
-
         let mut buf = [0_u8; 5];
+        let buf = &buf[..user_buf.copy_common_bytes_to_slice(&mut buf)?];
 
-        if user_buf.copy_common_bytes_to_slice(&mut buf)? < 5 || buf != *b"write" {
-            return Err(Error::new(EINVAL));
-        }
-
-        let pipe = Arc::clone(PIPES.read().get(&key).ok_or(Error::new(EBADF))?);
+        match (from_raw_id(old_id), buf) {
+            (End::Read(key), b"write") => {
+                let pipe = get(key)?;
+                if pipe.has_run_dup.swap(true, Ordering::SeqCst) {
+                    return Err(Error::new(EBADF));
+                }
+                pipe.open_ends.fetch_add(1, Ordering::SeqCst);
 
-        if pipe.has_run_dup.swap(true, Ordering::SeqCst) {
-            return Err(Error::new(EBADF));
+                Ok(OpenResult::SchemeLocal(key | WRITE_NOT_READ_BIT))
+            }
+            (End::Duplex(key), b"peer") => {
+                let pipe = get(key)?;
+                let reverse_key = pipe.reverse.ok_or(Error::new(EBADF))?;
+                let reverse = get(reverse_key)?;
+                if pipe.has_run_dup.swap(true, Ordering::SeqCst)
+                    || reverse.has_run_dup.swap(true, Ordering::SeqCst)
+                {
+                    return Err(Error::new(EBADF));
+                }
+                pipe.open_ends.fetch_add(1, Ordering::SeqCst);
+                reverse.open_ends.fetch_add(1, Ordering::SeqCst);
+
+                Ok(OpenResult::SchemeLocal(reverse_key | DUPLEX_BIT))
+            }
+            (End::Write(_), _) => Err(Error::new(EBADF)),
+            _ => Err(Error::new(EINVAL)),
         }
-
-        Ok(OpenResult::SchemeLocal(key | WRITE_NOT_READ_BIT))
     }
-    fn kopen(&self, path: &str, flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
-        if !path.trim_start_matches('/').is_empty() {
-            return Err(Error::new(ENOENT));
+    fn kopen(&self, path: &str, flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
+        match path.trim_matches('/') {
+            "" => {
+                let key = PIPE_NEXT_ID.fetch_add(1, Ordering::Relaxed);
+                insert(key, Pipe::new(flags, ctx.uid, None));
+                Ok(OpenResult::SchemeLocal(key))
+            }
+            "duplex" => Ok(OpenResult::SchemeLocal(duplex(flags, ctx.uid))),
+            _ => Err(Error::new(ENOENT)),
         }
-
-        let (read_id, _) = pipe(flags)?;
-
-        Ok(OpenResult::SchemeLocal(read_id))
     }
 
     fn kread(&self, id: usize, user_buf: UserSliceWo) -> Result<usize> {
-        let (is_write_not_read, key) = from_raw_id(id);
-
-        if is_write_not_read {
-            return Err(Error::new(EBADF));
-        }
-        let pipe = Arc::clone(PIPES.read().get(&key).ok_or(Error::new(EBADF))?);
+        self.kreadv(id, &[user_buf])
+    }
+    fn kreadv(&self, id: usize, user_bufs: &[UserSliceWo]) -> Result<usize> {
+        let (key, pipe) = read_pipe(id)?;
+        let deadline = rcvtimeo_deadline(pipe.read_timeout.load(Ordering::SeqCst));
 
         loop {
-            let mut vec = pipe.queue.lock();
-
-            let (s1, s2) = vec.as_slices();
-            let s1_count = core::cmp::min(user_buf.len(), s1.len());
+            let mut queue = pipe.queue.lock();
+            let was_full = queue.len() >= pipe.capacity.load(Ordering::SeqCst);
 
-            let (s1_dst, s2_buf) = user_buf
-                .split_at(s1_count)
-                .expect("s1_count <= user_buf.len()");
-            s1_dst.copy_from_slice(&s1[..s1_count])?;
-
-            let s2_count = core::cmp::min(s2_buf.len(), s2.len());
-            s2_buf
-                .limit(s2_count)
-                .expect("s2_count <= s2_buf.len()")
-                .copy_from_slice(&s2[..s2_count])?;
+            // Reading was shut down.
+            if !pipe.reader_is_alive.load(Ordering::SeqCst) {
+                return Ok(0);
+            }
 
-            let bytes_read = s1_count + s2_count;
-            let _ = vec.drain(..bytes_read);
+            let mut bytes_read = 0;
+            for &user_buf in user_bufs {
+                let count = match queue.read_into(user_buf) {
+                    Ok(count) => count,
+                    Err(_) if bytes_read > 0 => break,
+                    Err(error) => return Err(error),
+                };
+                bytes_read += count;
+                if count < user_buf.len() {
+                    break;
+                }
+            }
 
             if bytes_read > 0 {
-                event::trigger(
-                    GlobalSchemes::Pipe.scheme_id(),
-                    key | WRITE_NOT_READ_BIT,
-                    EVENT_WRITE,
-                );
+                // Only notify when the pipe becomes writable. Level-triggered registrations are polled
+                // again by the event queue while it stays ready.
+                if was_full {
+                    event::trigger(
+                        GlobalSchemes::Pipe.scheme_id(),
+                        pipe.writer_id(key),
+                        EVENT_WRITE,
+                    );
+                }
                 pipe.write_condition.notify();
 
                 return Ok(bytes_read);
-            } else if user_buf.is_empty() {
+            } else if user_bufs.iter().all(|user_buf| user_buf.is_empty()) {
                 return Ok(0);
             }
 
@@ -211,50 +552,77 @@ impl KernelScheme for PipeScheme {
                 return Ok(0);
             } else if pipe.read_flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
                 return Err(Error::new(EAGAIN));
-            } else if !pipe.read_condition.wait(vec, "PipeRead::read") {
-                return Err(Error::new(EINTR));
+            }
+            match pipe
+                .read_condition
+                .wait_until(queue, "PipeRead::read", deadline)
+            {
+                Ok(true) => (),
+                Ok(false) => return Err(Error::new(EINTR)),
+                Err(_) => return Err(Error::new(EAGAIN)),
             }
         }
     }
     fn kwrite(&self, id: usize, user_buf: UserSliceRo) -> Result<usize> {
-        let (is_write_not_read, key) = from_raw_id(id);
-
-        if !is_write_not_read {
-            return Err(Error::new(EBADF));
-        }
-        let pipe = Arc::clone(PIPES.read().get(&key).ok_or(Error::new(EBADF))?);
+        self.kwritev(id, &[user_buf])
+    }
+    fn kwritev(&self, id: usize, user_bufs: &[UserSliceRo]) -> Result<usize> {
+        let (key, pipe) = write_pipe(id)?;
 
         loop {
-            let mut vec = pipe.queue.lock();
+            let mut queue = pipe.queue.lock();
+            let was_empty = queue.is_empty();
 
-            let bytes_left = MAX_QUEUE_SIZE.saturating_sub(vec.len());
-            let bytes_to_write = core::cmp::min(bytes_left, user_buf.len());
-            let src_buf = user_buf
-                .limit(bytes_to_write)
-                .expect("bytes_to_write <= user_buf.len()");
+            // Writing was shut down.
+            if !pipe.writer_is_alive.load(Ordering::SeqCst) {
+                return Err(Error::new(EPIPE));
+            }
+
+            let bytes_left = pipe
+                .capacity
+                .load(Ordering::SeqCst)
+                .saturating_sub(queue.len());
 
             const TMPBUF_SIZE: usize = 512;
             let mut tmp_buf = [0_u8; TMPBUF_SIZE];
 
             let mut bytes_written = 0;
 
-            // TODO: Modify VecDeque so that the unwritten portions can be accessed directly?
-            for (idx, chunk) in src_buf.in_variable_chunks(TMPBUF_SIZE).enumerate() {
-                let chunk_byte_count = match chunk.copy_common_bytes_to_slice(&mut tmp_buf) {
-                    Ok(c) => c,
-                    Err(_) if idx > 0 => break,
-                    Err(error) => return Err(error),
-                };
-                vec.extend(&tmp_buf[..chunk_byte_count]);
-                bytes_written += chunk_byte_count;
+            // TODO: Copy directly into the last buffer of the queue?
+            'bufs: for &user_buf in user_bufs {
+                let bytes_to_write = core::cmp::min(bytes_left - bytes_written, user_buf.len());
+                let src_buf = user_buf
+                    .limit(bytes_to_write)
+                    .expect("bytes_to_write <= user_buf.len()");
+
+                for chunk in src_buf.in_variable_chunks(TMPBUF_SIZE) {
+                    let chunk_byte_count = match chunk.copy_common_bytes_to_slice(&mut tmp_buf) {
+                        Ok(c) => c,
+                        Err(_) if bytes_written > 0 => break 'bufs,
+                        Err(error) => return Err(error),
+                    };
+                    queue.extend(&tmp_buf[..chunk_byte_count]);
+                    bytes_written += chunk_byte_count;
+                }
+                if bytes_to_write < user_buf.len() {
+                    break;
+                }
             }
 
             if bytes_written > 0 {
-                event::trigger(GlobalSchemes::Pipe.scheme_id(), key, EVENT_READ);
+                // Only notify when the pipe becomes readable. Level-triggered registrations are polled
+                // again by the event queue while it stays ready.
+                if was_empty {
+                    event::trigger(
+                        GlobalSchemes::Pipe.scheme_id(),
+                        pipe.reader_id(key),
+                        EVENT_READ,
+                    );
+                }
                 pipe.read_condition.notify();
 
                 return Ok(bytes_written);
-            } else if user_buf.is_empty() {
+            } else if user_bufs.iter().all(|user_buf| user_buf.is_empty()) {
                 return Ok(0);
             }
 
@@ -262,14 +630,19 @@ impl KernelScheme for PipeScheme {
                 return Err(Error::new(EPIPE));
             } else if pipe.write_flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
                 return Err(Error::new(EAGAIN));
-            } else if !pipe.write_condition.wait(vec, "PipeWrite::write") {
+            } else if !pipe.write_condition.wait(queue, "PipeWrite::write") {
                 return Err(Error::new(EINTR));
             }
         }
     }
-    fn kfstat(&self, _id: usize, buf: UserSliceWo) -> Result<()> {
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<()> {
+        let (_, pipe) = read_pipe(id).or_else(|_| write_pipe(id))?;
+
+        // The size is the number of buffered bytes, as returned by FIONREAD.
         buf.copy_exactly(&Stat {
             st_mode: MODE_FIFO | 0o666,
+            st_size: pipe.queue.lock().len() as u64,
+            st_blksize: PAGE_SIZE as u32,
             ..Default::default()
         })?;
 
@@ -282,8 +655,183 @@ pub struct Pipe {
     write_flags: AtomicUsize,       // fcntl write flags
     read_condition: WaitCondition,  // signals whether there are available bytes to read
     write_condition: WaitCondition, // signals whether there is room for additional bytes
-    queue: Mutex<VecDeque<u8>>,
+    queue: Mutex<PipeQueue>,
     reader_is_alive: AtomicBool, // starts set, unset when reader closes
     writer_is_alive: AtomicBool, // starts set, unset when writer closes
     has_run_dup: AtomicBool,
+    read_timeout: AtomicUsize, // read timeout in microseconds, or 0
+    capacity: AtomicUsize,     // maximum number of buffered bytes
+    uid: u32,                  // creator, whose pipe size total the capacity is charged to
+    open_ends: AtomicUsize,    // files referencing the pipe, which is removed when none are left
+    reverse: Option<usize>,    // key of the pipe in the opposite direction, if duplex
+}
+impl Pipe {
+    fn new(flags: usize, uid: u32, reverse: Option<usize>) -> Self {
+        Self {
+            read_flags: AtomicUsize::new(flags),
+            write_flags: AtomicUsize::new(flags),
+            queue: Mutex::new(PipeQueue::new()),
+            read_condition: WaitCondition::new(),
+            write_condition: WaitCondition::new(),
+            writer_is_alive: AtomicBool::new(true),
+            reader_is_alive: AtomicBool::new(true),
+            has_run_dup: AtomicBool::new(false),
+            read_timeout: AtomicUsize::new(0),
+            capacity: AtomicUsize::new(DEFAULT_PIPE_SIZE),
+            uid,
+            open_ends: AtomicUsize::new(1),
+            reverse,
+        }
+    }
+    /// The id of the file reading this pipe, whose key is `key`.
+    fn reader_id(&self, key: usize) -> usize {
+        match self.reverse {
+            Some(reverse) => reverse | DUPLEX_BIT,
+            None => key,
+        }
+    }
+    /// The id of the file writing this pipe, whose key is `key`.
+    fn writer_id(&self, key: usize) -> usize {
+        match self.reverse {
+            Some(_) => key | DUPLEX_BIT,
+            None => key | WRITE_NOT_READ_BIT,
+        }
+    }
+}
+
+/// A range of a reference counted buffer. Buffers are shared between pipes by [`splice`], and
+/// only appended to while not shared.
+struct Chunk {
+    buf: Arc<Vec<u8>>,
+    start: usize,
+    end: usize,
+}
+impl Chunk {
+    fn bytes(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+    fn len(&self) -> usize {
+        self.end - self.start
+    }
+    /// A chunk sharing the first `count` bytes of this one.
+    fn prefix(&self, count: usize) -> Self {
+        Self {
+            buf: Arc::clone(&self.buf),
+            start: self.start,
+            end: self.start + count,
+        }
+    }
+}
+
+/// The buffered contents of a pipe.
+struct PipeQueue {
+    chunks: VecDeque<Chunk>,
+    len: usize,
+}
+impl PipeQueue {
+    const fn new() -> Self {
+        Self {
+            chunks: VecDeque::new(),
+            len: 0,
+        }
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    fn push(&mut self, chunk: Chunk) {
+        self.len += chunk.len();
+        self.chunks.push_back(chunk);
+    }
+
+    /// Append `bytes`, filling the last buffer first if it is not shared.
+    fn extend(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if let Some(last) = self.chunks.back_mut() {
+                let at_end = last.end == last.buf.len();
+                if let Some(buf) = Arc::get_mut(&mut last.buf).filter(|_| at_end) {
+                    let count = bytes.len().min(CHUNK_SIZE.saturating_sub(buf.len()));
+                    if count > 0 {
+                        buf.extend_from_slice(&bytes[..count]);
+                        last.end += count;
+                        self.len += count;
+                        bytes = &bytes[count..];
+                        continue;
+                    }
+                }
+            }
+            self.chunks.push_back(Chunk {
+                buf: Arc::new(Vec::with_capacity(CHUNK_SIZE)),
+                start: 0,
+                end: 0,
+            });
+        }
+    }
+
+    /// Copy and remove as many bytes as fit into `user_buf`.
+    fn read_into(&mut self, user_buf: UserSliceWo) -> Result<usize> {
+        let mut bytes_read = 0;
+        while let Some(chunk) = self.chunks.front_mut() {
+            let dst_buf = user_buf
+                .advance(bytes_read)
+                .expect("bytes_read <= user_buf.len()");
+            let count = match dst_buf.copy_common_bytes_from_slice(chunk.bytes()) {
+                Ok(0) => break,
+                Ok(count) => count,
+                Err(_) if bytes_read > 0 => break,
+                Err(error) => return Err(error),
+            };
+            chunk.start += count;
+            self.len -= count;
+            bytes_read += count;
+            if chunk.len() == 0 {
+                self.chunks.pop_front();
+            }
+        }
+        Ok(bytes_read)
+    }
+
+    /// Append the first `count` bytes to `dst`, sharing their buffers unless only a few bytes of
+    /// one are appended.
+    fn share_front(&self, count: usize, dst: &mut Self) {
+        let mut left = count;
+        for chunk in self.chunks.iter() {
+            if left == 0 {
+                break;
+            }
+            let chunk_count = left.min(chunk.len());
+            if chunk_count < SHARE_MIN {
+                dst.extend(&chunk.bytes()[..chunk_count]);
+            } else {
+                dst.push(chunk.prefix(chunk_count));
+            }
+            left -= chunk_count;
+        }
+    }
+
+    /// Move the first `count` bytes to `dst`, sharing the buffer of a chunk moved in part.
+    fn move_front(&mut self, count: usize, dst: &mut Self) {
+        let mut left = count;
+        while left > 0 {
+            let chunk = self.chunks.front_mut().expect("count <= self.len()");
+            let chunk_count = left.min(chunk.len());
+            if chunk_count < SHARE_MIN {
+                dst.extend(&chunk.bytes()[..chunk_count]);
+                chunk.start += chunk_count;
+                if chunk.len() == 0 {
+                    self.chunks.pop_front();
+                }
+            } else if chunk_count == chunk.len() {
+                let chunk = self.chunks.pop_front().expect("front exists");
+                dst.push(chunk);
+            } else {
+                dst.push(chunk.prefix(chunk_count));
+                chunk.start += chunk_count;
+            }
+            self.len -= chunk_count;
+            left -= chunk_count;
+        }
+    }
 }
@@ -0,0 +1,91 @@
+//! Powering off or resetting the system, from userspace.
+//!
+//! Root writes `off` or `reset` to `power:`, which runs the same architecture hooks as a kernel
+//! shutdown: ACPI and the emulator ports on x86, or PSCI on aarch64. Reading `power:` lists the
+//! accepted commands.
+
+use alloc::collections::BTreeSet;
+use core::{
+    str,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spin::RwLock;
+
+use crate::syscall::{
+    data::Stat,
+    error::*,
+    flag::MODE_FILE,
+    usercopy::{UserSliceRo, UserSliceWo},
+};
+
+use super::{CallerCtx, KernelScheme, OpenResult};
+
+const COMMANDS: &[u8] = b"off\nreset\n";
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+static HANDLES: RwLock<BTreeSet<usize>> = RwLock::new(BTreeSet::new());
+
+pub struct PowerScheme;
+
+impl KernelScheme for PowerScheme {
+    fn kopen(&self, path: &str, _flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
+        if ctx.uid != 0 {
+            return Err(Error::new(EPERM));
+        }
+        if !path.trim_matches('/').is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        HANDLES.write().insert(id);
+        Ok(OpenResult::SchemeLocal(id))
+    }
+
+    fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        if !HANDLES.read().contains(&id) {
+            return Err(Error::new(EBADF));
+        }
+        buf.copy_common_bytes_from_slice(COMMANDS)
+    }
+
+    fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
+        if !HANDLES.read().contains(&id) {
+            return Err(Error::new(EBADF));
+        }
+        let mut data = [0_u8; 16];
+        let count = buf.copy_common_bytes_to_slice(&mut data)?;
+        let command = str::from_utf8(&data[..count]).map_err(|_| Error::new(EINVAL))?;
+
+        match command.trim() {
+            "off" => unsafe { crate::stop::kstop() },
+            "reset" | "reboot" => unsafe { crate::stop::kreset() },
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        if !HANDLES.read().contains(&id) {
+            return Err(Error::new(EBADF));
+        }
+        buf.copy_common_bytes_from_slice(b"power:")
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<()> {
+        if !HANDLES.read().contains(&id) {
+            return Err(Error::new(EBADF));
+        }
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_FILE | 0o600,
+            st_size: COMMANDS.len() as u64,
+            ..Default::default()
+        })
+    }
+
+    fn close(&self, id: usize) -> Result<()> {
+        if !HANDLES.write().remove(&id) {
+            return Err(Error::new(EBADF));
+        }
+        Ok(())
+    }
+}
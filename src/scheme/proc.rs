@@ -3,8 +3,13 @@ use crate::{
     context::{
         self,
         file::FileDescriptor,
-        memory::{handle_notify_files, Grant, PageSpan, AddrSpaceWrapper},
-        Context, ContextId, Status, context::{HardBlockedReason, Altstack, SignalHandler},
+        memory::{
+            handle_notify_files, AccessMode, AddrSpaceWrapper, Grant, MemoryStats, PageSpan,
+            Provider,
+        },
+        rlimit::{Resource, Rlimit, UserContext, RLIM_INFINITY},
+        signal::SigaltstackData,
+        Context, ContextId, Status, context::{HardBlockedReason, SignalHandler},
     },
     memory::PAGE_SIZE,
     ptrace,
@@ -15,7 +20,7 @@ use crate::{
         error::*,
         flag::*,
         usercopy::{UserSliceRo, UserSliceWo},
-        EnvRegisters, FloatRegisters, IntRegisters,
+        EnvRegisters, FloatRegisters, GrantFlags, IntRegisters,
     },
 };
 
@@ -32,10 +37,18 @@ use core::{
     slice, str,
     sync::atomic::{AtomicUsize, Ordering},
 };
+use redox_path::RedoxPath;
 use spin::RwLock;
 use spinning_top::RwSpinlock;
 
-use super::{CallerCtx, GlobalSchemes, KernelSchemes, OpenResult};
+use super::{CallerCtx, GlobalSchemes, KernelSchemes, OpenResult, SchemeId, SchemeNamespace};
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::arch::hw_breakpoint::HwBreakpoints;
+#[cfg(target_arch = "x86_64")]
+use crate::arch::shadow_stack;
+#[cfg(target_arch = "aarch64")]
+use crate::arch::{mte, sve};
 
 fn read_from(dst: UserSliceWo, src: &[u8], offset: &mut usize) -> Result<usize> {
     let avail_src = src.get(*offset..).unwrap_or(&[]);
@@ -111,15 +124,45 @@ enum RegsKind {
     Float,
     Int,
     Env,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Debug,
+    /// The vector length in bytes, followed by the SVE state, which is empty until the context
+    /// first uses SVE.
+    #[cfg(target_arch = "aarch64")]
+    Sve,
 }
 #[derive(Clone)]
 enum Operation {
     Regs(RegsKind),
     Trace,
+    Syscall,
+    Fault,
+    /// All resource limits as text, one `<name> <soft> <hard>` line each. They are set through
+    /// [`Operation::Rlimit`].
+    Limits,
+    /// A single resource limit, as the soft and hard limits in native-endian `u64`s.
+    Rlimit(Resource),
+    Sched,
+    Stat,
+    Status,
+    Maps,
+    /// The memory of the context, read and written at the file offset. With `force`, read-only
+    /// private memory can be written, as to insert breakpoints.
+    Mem {
+        addrspace: Arc<AddrSpaceWrapper>,
+        force: bool,
+    },
     Static(&'static str),
+    /// Directory-style listing of live context IDs, opened through the bare scheme.
+    List,
     Name,
+    /// The path reported by the last `PTRACE_EVENT_ADDRSPACE_SWITCH` of a traced process.
+    ExecPath,
+    Cwd,
+    Umask,
     SessionId,
     Sighandler,
+    Sigaltstack,
     Start,
     Attr(Attr),
     NewFiletable {
@@ -153,6 +196,7 @@ enum Operation {
     SchedAffinity,
     Sigactions(Arc<RwLock<Vec<(SigAction, usize)>>>),
     Sigprocmask,
+    SignalQueue,
 
     // TODO: REMOVE
     Sigignmask,
@@ -161,6 +205,14 @@ enum Operation {
     AwaitingSigactionsChange(Arc<RwLock<Vec<(SigAction, usize)>>>),
 
     MmapMinAddr(Arc<AddrSpaceWrapper>),
+    #[cfg(target_arch = "x86_64")]
+    ShadowStack,
+    #[cfg(target_arch = "x86_64")]
+    Amx(Arc<AddrSpaceWrapper>),
+    #[cfg(target_arch = "aarch64")]
+    Bti(Arc<AddrSpaceWrapper>),
+    #[cfg(target_arch = "aarch64")]
+    Mte,
 }
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum Attr {
@@ -174,17 +226,25 @@ impl Operation {
             self,
             Self::Regs(_)
                 | Self::Trace
+                | Self::Syscall
+                | Self::Fault
                 | Self::SessionId
+                | Self::Cwd
+                | Self::Umask
                 | Self::Filetable { .. }
                 | Self::NewFiletable { .. }
                 | Self::AddrSpace { .. }
+                | Self::Maps
+                | Self::Mem { .. }
                 | Self::CurrentAddrSpace
                 | Self::CurrentFiletable
                 | Self::Sigactions(_)
                 | Self::CurrentSigactions
                 | Self::AwaitingSigactionsChange(_)
                 | Self::Sighandler
+                | Self::Sigaltstack
                 | Self::Sigprocmask
+                | Self::SignalQueue
                 | Self::Sigignmask
         )
     }
@@ -303,12 +363,47 @@ impl<const FULL: bool> ProcScheme<FULL> {
             Some("regs/float") => Operation::Regs(RegsKind::Float),
             Some("regs/int") => Operation::Regs(RegsKind::Int),
             Some("regs/env") => Operation::Regs(RegsKind::Env),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Some("regs/debug") => Operation::Regs(RegsKind::Debug),
+            #[cfg(target_arch = "aarch64")]
+            Some("regs/sve") => Operation::Regs(RegsKind::Sve),
             Some("trace") => Operation::Trace,
+            Some("syscall") => Operation::Syscall,
+            Some("fault") => Operation::Fault,
+            Some("sched") => Operation::Sched,
+            Some("limits") => Operation::Limits,
+            Some(path) if path.starts_with("rlimit/") => Operation::Rlimit(
+                Resource::from_name(&path["rlimit/".len()..]).ok_or(Error::new(ENOENT))?,
+            ),
+            Some("stat") => Operation::Stat,
+            Some("status") => Operation::Status,
+            Some("maps") => Operation::Maps,
+            Some(path @ ("mem" | "mem/force")) => {
+                let force = path == "mem/force";
+                // Only a tracer may override page protections.
+                if force && !ptrace::is_traced(pid) {
+                    return Err(Error::new(EPERM));
+                }
+                Operation::Mem {
+                    addrspace: Arc::clone(
+                        get_context(pid)?
+                            .read()
+                            .addr_space()
+                            .map_err(|_| Error::new(ENOENT))?,
+                    ),
+                    force,
+                }
+            }
             Some("exe") => Operation::Static("exe"),
             Some("name") => Operation::Name,
+            Some("exec-path") => Operation::ExecPath,
+            Some("cwd") => Operation::Cwd,
+            Some("umask") => Operation::Umask,
             Some("session_id") => Operation::SessionId,
             Some("sighandler") => Operation::Sighandler,
+            Some("sigaltstack") => Operation::Sigaltstack,
             Some("sigprocmask") => Operation::Sigprocmask,
+            Some("signal-queue") => Operation::SignalQueue,
             Some("sigignmask") => Operation::Sigignmask,
             Some("start") => Operation::Start,
             Some("uid") => Operation::Attr(Attr::Uid),
@@ -325,6 +420,24 @@ impl<const FULL: bool> ProcScheme<FULL> {
                     .map_err(|_| Error::new(ENOENT))?,
             )),
             Some("sched-affinity") => Operation::SchedAffinity,
+            #[cfg(target_arch = "x86_64")]
+            Some("shadow-stack") => Operation::ShadowStack,
+            #[cfg(target_arch = "x86_64")]
+            Some("amx") => Operation::Amx(Arc::clone(
+                get_context(pid)?
+                    .read()
+                    .addr_space()
+                    .map_err(|_| Error::new(ENOENT))?,
+            )),
+            #[cfg(target_arch = "aarch64")]
+            Some("bti") => Operation::Bti(Arc::clone(
+                get_context(pid)?
+                    .read()
+                    .addr_space()
+                    .map_err(|_| Error::new(ENOENT))?,
+            )),
+            #[cfg(target_arch = "aarch64")]
+            Some("mte") => Operation::Mte,
             _ => return Err(Error::new(EINVAL)),
         };
 
@@ -341,7 +454,12 @@ impl<const FULL: bool> ProcScheme<FULL> {
                 Operation::Static(_) => OperationData::Static(StaticData::new(
                     target.name.clone().into_owned().into_bytes().into(),
                 )),
-                Operation::AddrSpace { .. } => OperationData::Offset(0),
+                Operation::AddrSpace { .. }
+                | Operation::Limits
+                | Operation::Sched
+                | Operation::Stat
+                | Operation::Status
+                | Operation::Mem { .. } => OperationData::Offset(0),
                 _ => OperationData::Other,
             };
 
@@ -401,6 +519,12 @@ impl<const FULL: bool> ProcScheme<FULL> {
                     data.into_bytes().into_boxed_slice()
                 }));
             }
+            if let Operation::Maps = operation {
+                let addrspace = target.addr_space().map_err(|_| Error::new(ENOENT))?;
+                data = OperationData::Static(StaticData::new(
+                    format_maps(addrspace).into_bytes().into_boxed_slice(),
+                ));
+            }
         };
 
         let id = new_handle(Handle {
@@ -424,6 +548,14 @@ impl<const FULL: bool> ProcScheme<FULL> {
                 target.ptrace_stop = true;
             }
         }
+        if let Operation::SignalQueue = operation {
+            let scheme = if FULL {
+                GlobalSchemes::ProcFull
+            } else {
+                GlobalSchemes::ProcRestricted
+            };
+            context::signal_queue::open(id, pid, scheme.scheme_id());
+        }
 
         Ok(id)
     }
@@ -472,17 +604,11 @@ impl<const FULL: bool> ProcScheme<FULL> {
 
     #[cfg(target_arch = "x86_64")]
     fn read_env_regs(&self, info: &Info) -> Result<EnvRegisters> {
-        // TODO: Avoid rdmsr if fsgsbase is not enabled, if this is worth optimizing for.
         let (fsbase, gsbase) = if info.pid == context::context_id() {
-            unsafe {
-                (
-                    x86::msr::rdmsr(x86::msr::IA32_FS_BASE),
-                    x86::msr::rdmsr(x86::msr::IA32_KERNEL_GSBASE),
-                )
-            }
+            unsafe { context::current_fsgsbase() }
         } else {
             try_stop_context(info.pid, |context| {
-                Ok((context.arch.fsbase as u64, context.arch.gsbase as u64))
+                Ok((context.arch.fsbase, context.arch.gsbase))
             })?
         };
         Ok(EnvRegisters {
@@ -555,10 +681,7 @@ impl<const FULL: bool> ProcScheme<FULL> {
 
         if info.pid == context::context_id() {
             unsafe {
-                x86::msr::wrmsr(x86::msr::IA32_FS_BASE, regs.fsbase as u64);
-                // We have to write to KERNEL_GSBASE, because when the kernel returns to
-                // userspace, it will have executed SWAPGS first.
-                x86::msr::wrmsr(x86::msr::IA32_KERNEL_GSBASE, regs.gsbase as u64);
+                context::set_current_fsgsbase(regs.fsbase as usize, regs.gsbase as usize);
 
                 match context::contexts()
                     .current()
@@ -585,6 +708,13 @@ impl<const FULL: bool> ProcScheme<FULL> {
 
 impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
     fn kopen(&self, path: &str, flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
+        if matches!(path.trim_matches('/'), "" | "all") {
+            if !FULL {
+                return Err(Error::new(EACCES));
+            }
+            return open_list(flags, ctx).map(OpenResult::SchemeLocal);
+        }
+
         let mut parts = path.splitn(2, '/');
         let pid_str = parts.next().ok_or(Error::new(ENOENT))?;
 
@@ -616,7 +746,7 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
         }
     }
 
-    fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
+    fn fevent(&self, id: usize, flags: EventFlags) -> Result<EventFlags> {
         let handles = HANDLES.read();
         let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
 
@@ -624,6 +754,7 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
             Operation::Trace => ptrace::Session::with_session(handle.info.pid, |session| {
                 Ok(session.data.lock().session_fevent_flags())
             }),
+            Operation::SignalQueue => context::signal_queue::get(id)?.fevent(flags),
             _ => Ok(EventFlags::empty()),
         }
     }
@@ -640,21 +771,20 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
 
         match handle.info.operation {
             Operation::AwaitingAddrSpaceChange { new, new_sp, new_ip } => {
-                let _ = stop_context(handle.info.pid, |context: &mut Context| {
+                let path = stop_context(handle.info.pid, |context: &mut Context| {
                     let regs = context.regs_mut().ok_or(Error::new(EBADFD))?;
                     regs.set_instr_pointer(new_ip);
                     regs.set_stack_pointer(new_sp);
 
-                    Ok(context.set_addr_space(Some(new)))
+                    let _ = context.set_addr_space(Some(new));
+                    // Userspace names the context after the new image before switching.
+                    Ok(context.name.clone())
                 })?;
-                let _ = ptrace::send_event(crate::syscall::ptrace_event!(
-                    PTRACE_EVENT_ADDRSPACE_SWITCH,
-                    0
-                ));
-            }
-            Operation::AddrSpace { addrspace } | Operation::MmapMinAddr(addrspace) => {
-                drop(addrspace)
+                let _ = ptrace::exec_event(handle.info.pid, path, new_ip, new_sp);
             }
+            Operation::AddrSpace { addrspace }
+            | Operation::MmapMinAddr(addrspace)
+            | Operation::Mem { addrspace, .. } => drop(addrspace),
 
             Operation::AwaitingFiletableChange(new) => {
                 with_context_mut(handle.info.pid, |context: &mut Context| {
@@ -681,6 +811,7 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     context.ptrace_stop = false;
                 }
             }
+            Operation::SignalQueue => context::signal_queue::close(id),
             _ => (),
         }
         Ok(())
@@ -762,6 +893,84 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
             _ => Err(Error::new(EBADF)),
         }
     }
+    fn kreadv(&self, id: usize, bufs: &[UserSliceWo]) -> Result<usize> {
+        let info = {
+            let handles = HANDLES.read();
+            let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+            handle.info.clone()
+        };
+
+        match info.operation {
+            Operation::Static(_) | Operation::List | Operation::Maps => {
+                let mut handles = HANDLES.write();
+                let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+                let data = handle.data.static_data().expect("operations can't change");
+
+                let mut bytes_read = 0;
+                for buf in bufs {
+                    let src_buf = data.buf.get(data.offset..).unwrap_or(&[]);
+                    if src_buf.is_empty() {
+                        break;
+                    }
+                    let len = match buf.copy_common_bytes_from_slice(src_buf) {
+                        Ok(len) => len,
+                        Err(_) if bytes_read > 0 => break,
+                        Err(error) => return Err(error),
+                    };
+                    data.offset += len;
+                    bytes_read += len;
+                }
+                Ok(bytes_read)
+            }
+            _ => self.kread(id, scheme::first_nonempty(bufs)),
+        }
+    }
+    fn kread_at(&self, id: usize, buf: UserSliceWo, offset: u64) -> Result<usize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        if let Operation::Mem { ref addrspace, .. } = handle.info.operation {
+            let addrspace = Arc::clone(addrspace);
+            drop(handles);
+            let address = usize::try_from(offset).map_err(|_| Error::new(EINVAL))?;
+            return read_mem(&addrspace, address, buf);
+        }
+        let Some(data) = handle.data.static_data() else {
+            return Err(Error::new(ESPIPE));
+        };
+        let src_buf = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| data.buf.get(offset..))
+            .unwrap_or(&[]);
+
+        buf.copy_common_bytes_from_slice(src_buf)
+    }
+    fn kwrite_at(&self, id: usize, buf: UserSliceRo, offset: u64) -> Result<usize> {
+        let operation = HANDLES
+            .read()
+            .get(&id)
+            .ok_or(Error::new(EBADF))?
+            .info
+            .operation
+            .clone();
+        let Operation::Mem { addrspace, force } = operation else {
+            return Err(Error::new(ESPIPE));
+        };
+        let address = usize::try_from(offset).map_err(|_| Error::new(EINVAL))?;
+        write_mem(&addrspace, address, buf, force)
+    }
+    fn seek(&self, id: usize, pos: isize, whence: usize) -> Result<usize> {
+        let mut handles = HANDLES.write();
+        let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let (Operation::Mem { .. }, OperationData::Offset(ref mut offset)) =
+            (&handle.info.operation, &mut handle.data)
+        else {
+            return Err(Error::new(ESPIPE));
+        };
+
+        // The end of the file is the end of userspace.
+        *offset = scheme::calc_seek_offset(*offset, pos, whence, crate::USER_END_OFFSET)?;
+        Ok(*offset)
+    }
     fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
         // Don't hold a global lock during the context switch later on
         let info = {
@@ -771,7 +980,7 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
         };
 
         match info.operation {
-            Operation::Static(_) => {
+            Operation::Static(_) | Operation::List | Operation::Maps => {
                 let mut handles = HANDLES.write();
                 let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
                 let data = handle.data.static_data().expect("operations can't change");
@@ -786,6 +995,8 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     float: FloatRegisters,
                     int: IntRegisters,
                     env: EnvRegisters,
+                    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                    debug: HwBreakpoints,
                 }
 
                 let (output, size) = match kind {
@@ -802,7 +1013,7 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     RegsKind::Int => try_stop_context(info.pid, |context| match context.regs() {
                         None => {
                             assert!(!context.running, "try_stop_context is broken, clearly");
-                            println!("{}:{}: Couldn't read registers from stopped process", file!(), line!());
+                            log::warn!("{}:{}: Couldn't read registers from stopped process", file!(), line!());
                             Err(Error::new(ENOTRECOVERABLE))
                         },
                         Some(stack) => {
@@ -817,6 +1028,26 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                         },
                         mem::size_of::<EnvRegisters>(),
                     ),
+                    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                    RegsKind::Debug => with_context(info.pid, |context| {
+                        // Only changed through this handle, so no need to stop the context
+                        Ok((
+                            Output {
+                                debug: context.arch.hw_breakpoints,
+                            },
+                            mem::size_of::<HwBreakpoints>(),
+                        ))
+                    })?,
+                    #[cfg(target_arch = "aarch64")]
+                    RegsKind::Sve => {
+                        let vl = sve::vector_length().ok_or(Error::new(EOPNOTSUPP))?;
+                        let data = with_context(info.pid, |context| {
+                            let mut data = Vec::from((vl as u64).to_ne_bytes());
+                            data.extend_from_slice(context.arch.sve.as_deref().unwrap_or(&[]));
+                            Ok(data)
+                        })?;
+                        return buf.copy_common_bytes_from_slice(&data);
+                    }
                 };
 
                 let src_buf =
@@ -918,6 +1149,145 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
 
                 Ok(grants_read * mem::size_of::<GrantDesc>())
             }
+            Operation::Mem { ref addrspace, .. } => {
+                let OperationData::Offset(address) =
+                    HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.data
+                else {
+                    return Err(Error::new(EBADFD));
+                };
+                let read = read_mem(addrspace, address, buf)?;
+
+                match HANDLES.write().get_mut(&id).ok_or(Error::new(EBADF))?.data {
+                    OperationData::Offset(ref mut offset) => *offset = address + read,
+                    _ => return Err(Error::new(EBADFD)),
+                };
+                Ok(read)
+            }
+            Operation::Rlimit(resource) => {
+                let limit = with_context(info.pid, |context| Ok(context.rlimits.get(resource)))?;
+                let mut words = buf.in_exact_chunks(mem::size_of::<u64>());
+                let mut next = || words.next().ok_or(Error::new(EINVAL));
+                next()?.write_u64(limit.cur)?;
+                next()?.write_u64(limit.max)?;
+                Ok(2 * mem::size_of::<u64>())
+            }
+            Operation::Syscall => {
+                let contexts = context::contexts();
+                let context = contexts.get(info.pid).ok_or(Error::new(ESRCH))?.read();
+
+                // Arguments are printed raw, since they can only be decoded in the target's
+                // address space.
+                let string = if context.running {
+                    String::from("running\n")
+                } else if let Some([a, b, c, d, e, f]) = context.current_syscall() {
+                    format!("{} {:#x} {:#x} {:#x} {:#x} {:#x}\n", a, b, c, d, e, f)
+                } else {
+                    String::from("-1\n")
+                };
+
+                read_from(buf, string.as_bytes(), &mut 0)
+            }
+            Operation::Fault => {
+                let fault = with_context(info.pid, |context| Ok(context.last_fault))?;
+
+                // Empty if the context has not faulted
+                let string = match fault {
+                    Some(fault) => format!(
+                        "{} {:#x} {} {}\n",
+                        fault.signal,
+                        fault.ip,
+                        fault.address.map_or(String::from("-"), |address| format!("{:#x}", address)),
+                        match fault.access {
+                            Some(AccessMode::Read) => "read",
+                            Some(AccessMode::Write) => "write",
+                            Some(AccessMode::InstrFetch) => "exec",
+                            None => "-",
+                        }
+                    ),
+                    None => String::new(),
+                };
+
+                read_from(buf, string.as_bytes(), &mut 0)
+            }
+            Operation::Limits => {
+                let limits = with_context(info.pid, |context| Ok(context.rlimits.format()))?;
+
+                let mut handles = HANDLES.write();
+                let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+                let OperationData::Offset(ref mut offset) = handle.data else {
+                    return Err(Error::new(EBADFD));
+                };
+                read_from(buf, limits.as_bytes(), offset)
+            }
+            Operation::Sched => {
+                let sched = with_context(info.pid, |context| Ok(context.sched.format()))?;
+
+                let mut handles = HANDLES.write();
+                let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+                let OperationData::Offset(ref mut offset) = handle.data else {
+                    return Err(Error::new(EBADFD));
+                };
+                read_from(buf, sched.as_bytes(), offset)
+            }
+            Operation::Stat => {
+                let stat = with_context(info.pid, |context| {
+                    // CPU time of the current slice is not yet accounted for while running, and is
+                    // counted as user time until then.
+                    let running_time = if context.running {
+                        crate::time::monotonic().saturating_sub(context.switch_time)
+                    } else {
+                        0
+                    };
+
+                    Ok(format!(
+                        "user,system,start,switches,cpu,irq\n{},{},{},{},{},{}\n",
+                        context.user_time() + running_time,
+                        context.kernel_time,
+                        context.start_time,
+                        context.switch_count,
+                        context.cpu_id.map_or(-1, |cpu_id| i64::from(cpu_id.get())),
+                        context.irq_time,
+                    ))
+                })?;
+
+                let mut handles = HANDLES.write();
+                let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+                let OperationData::Offset(ref mut offset) = handle.data else {
+                    return Err(Error::new(EBADFD));
+                };
+                read_from(buf, stat.as_bytes(), offset)
+            }
+            Operation::Status => {
+                let (addr_space, kstack_size, kstack_used) = with_context(info.pid, |context| {
+                    Ok((
+                        context.addr_space().ok().cloned(),
+                        context.kstack.as_ref().map_or(0, |kstack| kstack.len()),
+                        context.kstack_usage(),
+                    ))
+                })?;
+                // Kernel contexts have no address space.
+                let stats = addr_space.map_or(MemoryStats::default(), |addr_space| {
+                    addr_space.acquire_read().stats()
+                });
+
+                // Sizes are in bytes. The kernel stack usage is unknown while running.
+                let status = format!(
+                    "vm_size {}\nvm_rss {}\nvm_shared {}\ngrants {}\nkstack_size {}\nkstack_used {}\n",
+                    stats.virtual_pages * PAGE_SIZE,
+                    stats.resident_pages * PAGE_SIZE,
+                    stats.shared_pages * PAGE_SIZE,
+                    stats.grants,
+                    kstack_size,
+                    kstack_used.map_or(String::from("-"), |used| used.to_string()),
+                );
+
+                let mut handles = HANDLES.write();
+                let handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+                let OperationData::Offset(ref mut offset) = handle.data else {
+                    return Err(Error::new(EBADFD));
+                };
+                read_from(buf, status.as_bytes(), offset)
+            }
             Operation::Name => read_from(
                 buf,
                 context::contexts()
@@ -928,6 +1298,19 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     .as_bytes(),
                 &mut 0,
             ),
+            Operation::ExecPath => {
+                let path = ptrace::exec_path(info.pid).ok_or(Error::new(ENOENT))?;
+                read_from(buf, path.as_bytes(), &mut 0)
+            }
+            Operation::Cwd => {
+                let cwd = with_context(info.pid, |context| Ok(context.cwd.clone()))?;
+                read_from(buf, cwd.as_bytes(), &mut 0)
+            }
+            Operation::Umask => {
+                let umask = with_context(info.pid, |context| Ok(context.umask))?;
+                buf.write_usize(umask)?;
+                Ok(mem::size_of::<usize>())
+            }
             Operation::SessionId => read_from(
                 buf,
                 &context::contexts()
@@ -941,8 +1324,11 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
             ),
 
             Operation::Sighandler => {
-                let handler = context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?.read().sig.handler;
-                let altstack = handler.and_then(|h| h.altstack);
+                let (handler, altstack) = {
+                    let context_lock = get_context(info.pid).map_err(|_| Error::new(ESRCH))?;
+                    let context = context_lock.read();
+                    (context.sig.handler, context.sig.altstack)
+                };
                 let data = SetSighandlerData {
                     entry: handler.map_or(0, |h| h.handler.get()),
                     altstack_base: altstack.map_or(0, |a| a.base.get()),
@@ -952,11 +1338,20 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
 
                 Ok(mem::size_of::<SetSighandlerData>())
             }
+            Operation::Sigaltstack => {
+                let context_lock = get_context(info.pid).map_err(|_| Error::new(ESRCH))?;
+                let data = context::signal::altstack(&context_lock.read());
+                buf.copy_exactly(data.as_bytes())?;
+                Ok(mem::size_of::<SigaltstackData>())
+            }
             Operation::Sigprocmask => {
                 let procmask = context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?.read().sig.procmask;
                 buf.write_u64(procmask)?;
                 Ok(8)
             }
+            Operation::SignalQueue => {
+                context::signal_queue::get(id)?.read(buf, info.flags & O_NONBLOCK == O_NONBLOCK)
+            }
             Operation::Sigignmask => {
                 let mut ignmask = 0_u64;
 
@@ -998,6 +1393,26 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                 buf.write_usize(addrspace.acquire_read().mmap_min)?;
                 Ok(mem::size_of::<usize>())
             }
+            #[cfg(target_arch = "x86_64")]
+            Operation::Amx(ref addrspace) => {
+                buf.write_usize(usize::from(addrspace.acquire_read().amx))?;
+                Ok(mem::size_of::<usize>())
+            }
+            #[cfg(target_arch = "aarch64")]
+            Operation::Bti(ref addrspace) => {
+                buf.write_usize(usize::from(addrspace.acquire_read().bti))?;
+                Ok(mem::size_of::<usize>())
+            }
+            // The tag check mode, followed by the mask of tags `irg` may generate.
+            #[cfg(target_arch = "aarch64")]
+            Operation::Mte => {
+                let state = with_context(info.pid, |context| Ok(context.arch.mte))?;
+                let mut chunks = buf.in_exact_chunks(mem::size_of::<usize>());
+                for word in [state.mode as usize, usize::from(state.include)] {
+                    chunks.next().ok_or(Error::new(EINVAL))?.write_usize(word)?;
+                }
+                Ok(2 * mem::size_of::<usize>())
+            }
             Operation::SchedAffinity => {
                 let mask = context::contexts()
                     .get(info.pid)
@@ -1006,8 +1421,37 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     .sched_affinity
                     .to_raw();
 
-                buf.copy_exactly(crate::cpu_set::mask_as_bytes(&mask))?;
-                Ok(mem::size_of_val(&mask))
+                // The mask is as wide as the buffer, which must at least cover every CPU.
+                if buf.len() < crate::cpu_count().div_ceil(8) as usize {
+                    return Err(Error::new(EINVAL));
+                }
+                let mut bytes = [0_u8; crate::cpu_set::MASK_SIZE];
+                crate::cpu_set::mask_to_bytes(&mask, &mut bytes);
+                for (i, chunk) in buf.in_variable_chunks(bytes.len()).enumerate() {
+                    if i == 1 {
+                        bytes.fill(0);
+                    }
+                    chunk.copy_common_bytes_from_slice(&bytes)?;
+                }
+                Ok(buf.len())
+            }
+            // The shadow stack pointer, or 0 if the shadow stack is disabled.
+            #[cfg(target_arch = "x86_64")]
+            Operation::ShadowStack => {
+                let ssp = if info.pid == context::context_id() {
+                    let enabled = context::current()?.read().arch.shadow_stack.is_some();
+                    if enabled {
+                        unsafe { shadow_stack::current_ssp() }
+                    } else {
+                        0
+                    }
+                } else {
+                    with_context(info.pid, |context| {
+                        Ok(context.arch.shadow_stack.map_or(0, |stack| stack.ssp))
+                    })?
+                };
+                buf.write_usize(ssp)?;
+                Ok(mem::size_of::<usize>())
             }
             // TODO: Replace write() with SYS_DUP_FORWARD.
             // TODO: Find a better way to switch address spaces, since they also require switching
@@ -1027,6 +1471,20 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
 
         match info.operation {
             Operation::Static(_) => Err(Error::new(EBADF)),
+            Operation::Mem { addrspace, force } => {
+                let OperationData::Offset(address) =
+                    HANDLES.read().get(&id).ok_or(Error::new(EBADF))?.data
+                else {
+                    return Err(Error::new(EBADFD));
+                };
+                let written = write_mem(&addrspace, address, buf, force)?;
+
+                match HANDLES.write().get_mut(&id).ok_or(Error::new(EBADF))?.data {
+                    OperationData::Offset(ref mut offset) => *offset = address + written,
+                    _ => return Err(Error::new(EBADFD)),
+                };
+                Ok(written)
+            }
             Operation::AddrSpace { addrspace } => {
                 let mut chunks = buf.usizes();
                 let mut words_read = 0;
@@ -1098,7 +1556,7 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
 
                     try_stop_context(info.pid, |context| match context.regs_mut() {
                         None => {
-                            println!("{}:{}: Couldn't read registers from stopped process", file!(), line!());
+                            log::warn!("{}:{}: Couldn't read registers from stopped process", file!(), line!());
                             Err(Error::new(ENOTRECOVERABLE))
                         },
                         Some(stack) => {
@@ -1113,16 +1571,69 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     self.write_env_regs(&info, regs)?;
                     Ok(mem::size_of::<EnvRegisters>())
                 }
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                RegsKind::Debug => {
+                    let breakpoints = unsafe { buf.read_exact::<HwBreakpoints>()? };
+                    breakpoints.validate()?;
+
+                    if info.pid == context::context_id() {
+                        let current = context::current()?;
+                        let mut context = current.write();
+                        let had_enabled = context.arch.hw_breakpoints.is_enabled();
+                        context.arch.hw_breakpoints = breakpoints;
+                        unsafe {
+                            if breakpoints.is_enabled() {
+                                breakpoints.load();
+                            } else if had_enabled {
+                                HwBreakpoints::clear();
+                            }
+                        }
+                    } else {
+                        try_stop_context(info.pid, |context| {
+                            context.arch.hw_breakpoints = breakpoints;
+                            Ok(())
+                        })?;
+                    }
+                    Ok(mem::size_of::<HwBreakpoints>())
+                }
+                #[cfg(target_arch = "aarch64")]
+                RegsKind::Sve => {
+                    let vl = sve::vector_length().ok_or(Error::new(EOPNOTSUPP))?;
+                    if buf.read_u64()? != vl as u64 {
+                        return Err(Error::new(EINVAL));
+                    }
+                    let mut state = vec![0; sve::state_size(vl)].into_boxed_slice();
+                    buf.advance(mem::size_of::<u64>())
+                        .ok_or(Error::new(EINVAL))?
+                        .copy_to_slice(&mut state)?;
+
+                    try_stop_context(info.pid, |context| {
+                        context.arch.sve = Some(state);
+                        Ok(mem::size_of::<u64>() + sve::state_size(vl))
+                    })
+                }
             },
             Operation::Trace => {
                 let op = buf.read_u64()?;
                 let op = PtraceFlags::from_bits(op).ok_or(Error::new(EINVAL))?;
 
+                // An optional second word replaces the signal of a PTRACE_STOP_SIGNAL stop
+                let signal_override = match buf.advance(mem::size_of::<u64>()) {
+                    Some(rest) if rest.len() >= mem::size_of::<u64>() => {
+                        Some(rest.read_u64()? as usize)
+                    }
+                    _ => None,
+                };
+
                 // Set next breakpoint
                 ptrace::Session::with_session(info.pid, |session| {
-                    session.data.lock().set_breakpoint(
+                    let mut data = session.data.lock();
+                    data.set_breakpoint(
                         Some(op).filter(|op| op.intersects(PTRACE_STOP_MASK | PTRACE_EVENT_MASK)),
                     );
+                    if signal_override.is_some() {
+                        data.signal_override = signal_override;
+                    }
                     Ok(())
                 })?;
 
@@ -1130,7 +1641,7 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     try_stop_context(info.pid, |context| {
                         match context.regs_mut() {
                             None => {
-                                println!(
+                                log::warn!(
                                     "{}:{}: Couldn't read registers from stopped process",
                                     file!(),
                                     line!()
@@ -1157,7 +1668,56 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     Ok(())
                 })?;
 
-                Ok(mem::size_of::<u64>())
+                Ok(if signal_override.is_some() {
+                    2 * mem::size_of::<u64>()
+                } else {
+                    mem::size_of::<u64>()
+                })
+            }
+            Operation::Rlimit(resource) => {
+                let mut words = buf.in_exact_chunks(mem::size_of::<u64>());
+                let mut next = || words.next().ok_or(Error::new(EINVAL))?.read_u64();
+                let new = Rlimit {
+                    cur: next()?,
+                    max: next()?,
+                };
+
+                let (caller_pid, caller_euid) = match &*context::current()?.read() {
+                    context => (context.id, context.euid),
+                };
+                // Only root, or the process itself, can change its limits. Only root can raise
+                // hard limits.
+                if caller_euid != 0 && caller_pid != info.pid {
+                    return Err(Error::new(EPERM));
+                }
+                with_context_mut(info.pid, |context| {
+                    context.rlimits.set(resource, new, caller_euid == 0)?;
+                    if resource == Resource::As {
+                        context.apply_addr_space_limit();
+                    }
+                    Ok(())
+                })?;
+
+                Ok(2 * mem::size_of::<u64>())
+            }
+            Operation::Sched => {
+                let mut sched_buf = [0_u8; 256];
+                let bytes_copied = buf.copy_common_bytes_to_slice(&mut sched_buf)?;
+                let text = str::from_utf8(&sched_buf[..bytes_copied])
+                    .map_err(|_| Error::new(EINVAL))?;
+
+                let caller_euid = context::current()?.read().euid;
+                with_context_mut(info.pid, |context| {
+                    // Only root, or a process with the same effective user, can change the
+                    // parameters. Raising the priority is further limited by the resource limits.
+                    if caller_euid != 0 && caller_euid != context.euid {
+                        return Err(Error::new(EPERM));
+                    }
+                    let limits = context.rlimits;
+                    context.sched.parse_and_set(text, &limits, caller_euid == 0)
+                })?;
+
+                Ok(bytes_copied)
             }
             Operation::Name => {
                 // TODO: What limit?
@@ -1173,6 +1733,31 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                     .name = utf8.into();
                 Ok(buf.len())
             }
+            Operation::Cwd => {
+                if buf.len() > crate::syscall::fs::PATH_MAX {
+                    return Err(Error::new(ENAMETOOLONG));
+                }
+                let mut cwd = vec![0_u8; buf.len()];
+                buf.copy_to_slice(&mut cwd)?;
+                let cwd = String::from_utf8(cwd).map_err(|_| Error::new(EINVAL))?;
+                if RedoxPath::from_absolute(&cwd).is_none() {
+                    return Err(Error::new(EINVAL));
+                }
+
+                with_context_mut(info.pid, |context| {
+                    context.cwd = cwd.into();
+                    Ok(())
+                })?;
+                Ok(buf.len())
+            }
+            Operation::Umask => {
+                let umask = buf.read_usize()? & 0o777;
+                with_context_mut(info.pid, |context| {
+                    context.umask = umask;
+                    Ok(())
+                })?;
+                Ok(mem::size_of::<usize>())
+            }
             Operation::SessionId => {
                 let session_id = ContextId::new(buf.read_usize()?);
 
@@ -1201,26 +1786,43 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
             Operation::Sighandler => {
                 let data = unsafe { buf.read_exact::<SetSighandlerData>()? };
 
-                let new_handler = match NonZeroUsize::new(data.entry) {
-                    Some(handler) => Some(SignalHandler {
-                        handler,
-                        altstack: match (NonZeroUsize::new(data.altstack_base), NonZeroUsize::new(data.altstack_len)) {
-                            (Some(base), Some(len)) => Some(Altstack { base, len }),
-                            _ => None,
-                        }
-                    }),
-                    None => None,
+                let new_handler =
+                    NonZeroUsize::new(data.entry).map(|handler| SignalHandler { handler });
+
+                let context_lock = get_context(info.pid).map_err(|_| Error::new(ESRCH))?;
+                // The alternate stack can also be set here, as before there was sigaltstack. If
+                // none is given, the one set through sigaltstack is kept.
+                let new_altstack = match (new_handler, data.altstack_base, data.altstack_len) {
+                    (Some(_), base, len) if base != 0 && len != 0 => {
+                        let addr_space = Arc::clone(context_lock.read().addr_space()?);
+                        Some(context::signal::validate_altstack(&addr_space, base, len)?)
+                    }
+                    _ => None,
                 };
 
-                context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?.write().sig.handler = new_handler;
+                let mut context = context_lock.write();
+                context.sig.handler = new_handler;
+                if let Some(new_altstack) = new_altstack {
+                    context.sig.altstack = Some(new_altstack);
+                }
 
                 Ok(mem::size_of::<SetSighandlerData>())
             }
+            Operation::Sigaltstack => {
+                let data = unsafe { buf.read_exact::<SigaltstackData>()? };
+                context::signal::set_altstack(info.pid, data)?;
+                Ok(mem::size_of::<SigaltstackData>())
+            }
             Operation::Sigprocmask => {
                 let new_procmask = buf.read_u64()?;
                 context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?.write().sig.procmask = new_procmask;
                 Ok(8)
             }
+            // The mask of signals received by the queue.
+            Operation::SignalQueue => {
+                context::signal_queue::get(id)?.set_mask(buf.read_u64()?);
+                Ok(8)
+            }
             // TODO: Remove!
             Operation::Sigignmask => {
                 let new_ignmask = buf.read_u64()?;
@@ -1237,12 +1839,19 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                 }
                 Ok(8)
             }
-            Operation::Start => match context::contexts().get(info.pid).ok_or(Error::new(ESRCH))?.write().status {
-                ref mut status @ Status::HardBlocked { reason: HardBlockedReason::NotYetStarted } => {
-                    *status = Status::Runnable;
-                    Ok(buf.len())
+            Operation::Start => {
+                let context_lock = get_context(info.pid).map_err(|_| Error::new(ESRCH))?;
+                let mut context = context_lock.write();
+                match context.status {
+                    Status::HardBlocked {
+                        reason: HardBlockedReason::NotYetStarted,
+                    } => {
+                        context.status = Status::Runnable;
+                        context::switch::wake_cpu(&mut context);
+                        Ok(buf.len())
+                    }
+                    _ => return Err(Error::new(EINVAL)),
                 }
-                _ => return Err(Error::new(EINVAL)),
             }
             Operation::Attr(attr) => {
                 // TODO: What limit?
@@ -1353,17 +1962,141 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                 addrspace.acquire_write().mmap_min = val;
                 Ok(mem::size_of::<usize>())
             }
+            // 1 guards the mappings created from now on, and 0 stops guarding new mappings.
+            #[cfg(target_arch = "aarch64")]
+            Operation::Bti(ref addrspace) => {
+                let bti = match buf.read_usize()? {
+                    0 => false,
+                    1 if crate::arch::pauth::bti_supported() => true,
+                    1 => return Err(Error::new(EOPNOTSUPP)),
+                    _ => return Err(Error::new(EINVAL)),
+                };
+                addrspace.acquire_write().bti = bti;
+                Ok(mem::size_of::<usize>())
+            }
+            // The tag check mode (0 for none, 1 for sync, 2 for async), followed by the mask of
+            // tags `irg` may generate.
+            #[cfg(target_arch = "aarch64")]
+            Operation::Mte => {
+                if !mte::supported() {
+                    return Err(Error::new(EOPNOTSUPP));
+                }
+                let mut iter = buf.usizes();
+                let mode = mte::TagCheckMode::from_raw(iter.next().ok_or(Error::new(EINVAL))??)?;
+                let include = u16::try_from(iter.next().ok_or(Error::new(EINVAL))??)
+                    .map_err(|_| Error::new(EINVAL))?;
+
+                let set = |context: &mut Context| {
+                    context.arch.mte.mode = mode;
+                    context.arch.mte.include = include;
+                };
+                if info.pid == context::context_id() {
+                    let current = context::current()?;
+                    let mut context = current.write();
+                    set(&mut *context);
+                    unsafe {
+                        mte::load(&context.arch.mte);
+                    }
+                } else {
+                    try_stop_context(info.pid, |context| {
+                        set(context);
+                        Ok(())
+                    })?;
+                }
+                Ok(2 * mem::size_of::<usize>())
+            }
             Operation::SchedAffinity => {
-                let mask = unsafe { buf.read_exact::<crate::cpu_set::RawMask>()? };
+                // Bytes past the widest mask could only stand for CPUs which were not started.
+                let mut bytes = [0_u8; crate::cpu_set::MASK_SIZE];
+                buf.copy_common_bytes_to_slice(&mut bytes)?;
+                let mask = crate::cpu_set::mask_from_bytes(&bytes).ok_or(Error::new(EINVAL))?;
+
+                let excluded_cpu = {
+                    let context_lock = context::contexts()
+                        .get(info.pid)
+                        .map(Arc::clone)
+                        .ok_or(Error::new(EBADFD))?;
+                    let mut context = context_lock.write();
+                    context.sched_affinity.override_from(&mask);
+                    let cpu_id = context.cpu_id.filter(|_| context.running);
+                    cpu_id.filter(|&cpu_id| !context.sched_affinity.contains(cpu_id))
+                };
+                // Move the context off a CPU it may no longer run on.
+                match excluded_cpu {
+                    Some(cpu_id) if cpu_id == crate::cpu_id() => {
+                        context::switch();
+                    }
+                    Some(cpu_id) => crate::ipi::ipi_single(crate::ipi::IpiKind::Switch, cpu_id),
+                    None => (),
+                }
 
-                context::contexts()
-                    .get(info.pid)
-                    .ok_or(Error::new(EBADFD))?
-                    .write()
-                    .sched_affinity
-                    .override_from(&mask);
+                Ok(buf.len())
+            }
+            // 1 permits the process to use AMX, growing the saved extended state of its contexts.
+            // The permission can't be dropped again.
+            #[cfg(target_arch = "x86_64")]
+            Operation::Amx(ref addrspace) => {
+                if buf.read_usize()? != 1 {
+                    return Err(Error::new(EINVAL));
+                }
+                if crate::arch::amx_kfx_size().is_none() {
+                    return Err(Error::new(EOPNOTSUPP));
+                }
+                addrspace.acquire_write().amx = true;
 
-                Ok(mem::size_of_val(&mask))
+                // Contexts attached later take the permission from the address space.
+                let contexts: Vec<_> = context::contexts()
+                    .iter()
+                    .map(|(_, context_lock)| Arc::clone(context_lock))
+                    .collect();
+                for context_lock in contexts {
+                    let mut context = context_lock.write();
+                    if context
+                        .addr_space()
+                        .is_ok_and(|other| Arc::ptr_eq(other, addrspace))
+                    {
+                        context.set_amx_allowed(true)?;
+                    }
+                }
+                Ok(mem::size_of::<usize>())
+            }
+            // A size in bytes maps a new shadow stack and enables it, and 0 disables it. The
+            // previous shadow stack, if any, is unmapped.
+            #[cfg(target_arch = "x86_64")]
+            Operation::ShadowStack => {
+                let size = buf.read_usize()?;
+                let addr_space =
+                    with_context(info.pid, |context| Ok(Arc::clone(context.addr_space()?)))?;
+                let new = match size {
+                    0 => None,
+                    size => Some(shadow_stack::map(&addr_space, size)?),
+                };
+
+                let replaced = if info.pid == context::context_id() {
+                    let current = context::current()?;
+                    let mut context = current.write();
+                    unsafe {
+                        shadow_stack::load(new.as_ref());
+                    }
+                    Ok(mem::replace(&mut context.arch.shadow_stack, new))
+                } else {
+                    try_stop_context(info.pid, |context| {
+                        Ok(mem::replace(&mut context.arch.shadow_stack, new))
+                    })
+                };
+                let old = match replaced {
+                    Ok(old) => old,
+                    Err(err) => {
+                        if let Some(new) = new {
+                            let _ = shadow_stack::unmap(&addr_space, &new);
+                        }
+                        return Err(err);
+                    }
+                };
+                if let Some(old) = old {
+                    shadow_stack::unmap(&addr_space, &old)?;
+                }
+                Ok(mem::size_of::<usize>())
             }
 
             _ => Err(Error::new(EBADF)),
@@ -1373,14 +2106,40 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
         let handles = HANDLES.read();
         let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
 
+        if let Operation::List = handle.info.operation {
+            return buf.copy_common_bytes_from_slice(b"proc:");
+        }
+        if let Operation::Rlimit(resource) = handle.info.operation {
+            let path = format!("proc:{}/rlimit/{}", handle.info.pid.get(), resource.name());
+            return buf.copy_common_bytes_from_slice(path.as_bytes());
+        }
+
         let path = format!("proc:{}/{}", handle.info.pid.get(), match handle.info.operation {
             Operation::Regs(RegsKind::Float) => "regs/float",
             Operation::Regs(RegsKind::Int) => "regs/int",
             Operation::Regs(RegsKind::Env) => "regs/env",
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Operation::Regs(RegsKind::Debug) => "regs/debug",
+            #[cfg(target_arch = "aarch64")]
+            Operation::Regs(RegsKind::Sve) => "regs/sve",
             Operation::Trace => "trace",
+            Operation::Syscall => "syscall",
+            Operation::Fault => "fault",
+            Operation::Limits => "limits",
+            Operation::Sched => "sched",
+            Operation::Stat => "stat",
+            Operation::Status => "status",
+            Operation::Maps => "maps",
+            Operation::Mem { force: false, .. } => "mem",
+            Operation::Mem { force: true, .. } => "mem/force",
             Operation::Static(path) => path,
             Operation::Name => "name",
+            Operation::ExecPath => "exec-path",
+            Operation::Cwd => "cwd",
+            Operation::Umask => "umask",
             Operation::Sighandler => "sighandler",
+            Operation::Sigaltstack => "sigaltstack",
+            Operation::SignalQueue => "signal-queue",
             Operation::Attr(Attr::Uid) => "uid",
             Operation::Attr(Attr::Gid) => "gid",
             Operation::Filetable { .. } => "filetable",
@@ -1392,6 +2151,14 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
             Operation::OpenViaDup => "open-via-dup",
             Operation::MmapMinAddr(_) => "mmap-min-addr",
             Operation::SchedAffinity => "sched-affinity",
+            #[cfg(target_arch = "x86_64")]
+            Operation::ShadowStack => "shadow-stack",
+            #[cfg(target_arch = "x86_64")]
+            Operation::Amx(_) => "amx",
+            #[cfg(target_arch = "aarch64")]
+            Operation::Bti(_) => "bti",
+            #[cfg(target_arch = "aarch64")]
+            Operation::Mte => "mte",
 
                 _ => return Err(Error::new(EOPNOTSUPP)),
             }
@@ -1404,7 +2171,10 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
         let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
 
         buffer.copy_exactly(&Stat {
-            st_mode: MODE_FILE | 0o666,
+            st_mode: match handle.info.operation {
+                Operation::List => MODE_DIR | 0o444,
+                _ => MODE_FILE | 0o666,
+            },
             st_size: match handle.data {
                 OperationData::Static(ref data) => (data.buf.len() - data.offset) as u64,
                 _ => 0,
@@ -1469,6 +2239,10 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
                 }
                 let filetable = filetable.upgrade().ok_or(Error::new(EOWNERDEAD))?;
 
+                #[cfg(feature = "fault_injection")]
+                if crate::fault::inject(crate::fault::Point::Heap) {
+                    return Err(Error::new(ENOMEM));
+                }
                 let new_filetable = Arc::try_new(RwLock::new(filetable.read().clone()))
                     .map_err(|_| Error::new(ENOMEM))?;
 
@@ -1539,6 +2313,177 @@ impl<const FULL: bool> KernelScheme for ProcScheme<FULL> {
         .map(OpenResult::SchemeLocal)
     }
 }
+/// Open a listing of all live contexts. Unless root, only contexts in the caller's namespace are
+/// listed.
+fn open_list(flags: usize, ctx: CallerCtx) -> Result<usize> {
+    use core::fmt::Write;
+
+    let contexts = context::contexts();
+    let caller_ens = contexts.current().ok_or(Error::new(ESRCH))?.read().ens;
+
+    let mut listing = String::new();
+    for (id, context_lock) in contexts.iter() {
+        let context = context_lock.read();
+        if let Status::Exited(_) = context.status {
+            continue;
+        }
+        if ctx.uid != 0 && context.ens != caller_ens {
+            continue;
+        }
+        let _ = writeln!(listing, "{}", id.get());
+    }
+
+    new_handle(Handle {
+        info: Info {
+            pid: ContextId::from(ctx.pid),
+            flags,
+            operation: Operation::List,
+        },
+        data: OperationData::Static(StaticData::new(listing.into_bytes().into_boxed_slice())),
+    })
+}
+
+/// Render the grants of `addrspace` like Linux `/proc/<pid>/maps`, one line per grant: the range,
+/// permissions with `s` for shared or `p` for private, the offset into the backing file or of
+/// the physical memory, and the scheme the file was opened from, or what backs the grant in
+/// brackets.
+fn format_maps(addrspace: &AddrSpaceWrapper) -> String {
+    use core::fmt::Write;
+
+    enum Backing {
+        Scheme(SchemeNamespace, SchemeId),
+        Other(&'static str),
+    }
+
+    let width = 2 * mem::size_of::<usize>();
+    let mut grants = Vec::new();
+    for (base, info) in addrspace.acquire_read().grants.iter() {
+        let flags = info.grant_flags();
+        let (offset, backing) = match (info.file_ref(), &info.provider) {
+            (Some(file_ref), _) => {
+                let description = file_ref.description.read();
+                (
+                    file_ref.base_offset,
+                    Backing::Scheme(description.namespace, description.scheme),
+                )
+            }
+            (None, Provider::PhysBorrowed { base }) => {
+                (base.start_address().data(), Backing::Other("[phys]"))
+            }
+            (None, Provider::External { .. }) => (0, Backing::Other("[borrowed]")),
+            (None, _) if flags.contains(GrantFlags::GRANT_SHARED) => {
+                (0, Backing::Other("[shared]"))
+            }
+            (None, _) => (0, Backing::Other("[anon]")),
+        };
+        grants.push((base, info.page_count(), flags, offset, backing));
+    }
+
+    // Scheme names are looked up once the address space is unlocked.
+    let schemes = scheme::schemes();
+    let mut maps = String::new();
+    for (base, page_count, flags, offset, backing) in grants {
+        let start = base.start_address().data();
+        let perm = |flag, c| if flags.contains(flag) { c } else { '-' };
+        let _ = write!(
+            maps,
+            "{:0width$x}-{:0width$x} {}{}{}{} {:08x} ",
+            start,
+            start + page_count * PAGE_SIZE,
+            perm(GrantFlags::GRANT_READ, 'r'),
+            perm(GrantFlags::GRANT_WRITE, 'w'),
+            perm(GrantFlags::GRANT_EXEC, 'x'),
+            if flags.contains(GrantFlags::GRANT_SHARED) {
+                's'
+            } else {
+                'p'
+            },
+            offset,
+        );
+        let _ = match backing {
+            Backing::Scheme(ns, scheme_id) => match schemes.name_of(ns, scheme_id) {
+                Some(name) => writeln!(maps, "{}:", name),
+                None => writeln!(maps, "[scheme {}]", scheme_id.get()),
+            },
+            Backing::Other(label) => writeln!(maps, "{}", label),
+        };
+    }
+    maps
+}
+
+/// Split `len` bytes from `address` at page boundaries, as the page, offset into it and length of
+/// each part.
+fn page_chunks(address: usize, len: usize) -> impl Iterator<Item = (Page, usize, usize)> {
+    let mut done = 0;
+    core::iter::from_fn(move || {
+        if done == len {
+            return None;
+        }
+        let address = address.checked_add(done)?;
+        let offset = address % PAGE_SIZE;
+        let chunk_len = (PAGE_SIZE - offset).min(len - done);
+        done += chunk_len;
+        Some((
+            Page::containing_address(VirtualAddress::new(address)),
+            offset,
+            chunk_len,
+        ))
+    })
+}
+
+/// Read the memory of `addrspace` from `address` into `buf`, a page at a time. Stops at the first
+/// page that cannot be read, failing only if it is the first.
+fn read_mem(addrspace: &AddrSpaceWrapper, address: usize, buf: UserSliceWo) -> Result<usize> {
+    // Copied through the kernel, so that the target address space is not locked while the caller
+    // may fault on its buffer.
+    let mut bytes = vec![0_u8; PAGE_SIZE];
+    let mut read = 0;
+    for (page, offset, len) in page_chunks(address, buf.len()) {
+        let result = addrspace
+            .access_foreign(page, offset, &mut bytes[..len], AccessMode::Read, false)
+            .and_then(|()| {
+                buf.advance(read)
+                    .and_then(|dst| dst.limit(len))
+                    .ok_or(Error::new(EINVAL))?
+                    .copy_from_slice(&bytes[..len])
+            });
+        match result {
+            Ok(()) => read += len,
+            Err(_) if read > 0 => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+/// Write `buf` to the memory of `addrspace` from `address`, a page at a time. Stops at the first
+/// page that cannot be written, failing only if it is the first.
+fn write_mem(
+    addrspace: &AddrSpaceWrapper,
+    address: usize,
+    buf: UserSliceRo,
+    force: bool,
+) -> Result<usize> {
+    let mut bytes = vec![0_u8; PAGE_SIZE];
+    let mut written = 0;
+    for (page, offset, len) in page_chunks(address, buf.len()) {
+        let result = buf
+            .advance(written)
+            .and_then(|src| src.limit(len))
+            .ok_or(Error::new(EINVAL))
+            .and_then(|src| src.copy_to_slice(&mut bytes[..len]))
+            .and_then(|()| {
+                addrspace.access_foreign(page, offset, &mut bytes[..len], AccessMode::Write, force)
+            });
+        match result {
+            Ok(()) => written += len,
+            Err(_) if written > 0 => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(written)
+}
+
 extern "C" fn clone_handler() {
     // This function will return to the syscall return assembly, and subsequently transition to
     // usermode.
@@ -1547,6 +2492,19 @@ extern "C" fn clone_handler() {
 fn inherit_context() -> Result<ContextId> {
     let new_id = {
         let current_context_lock = Arc::clone(context::contexts().current().ok_or(Error::new(ESRCH))?);
+
+        // Unless root, the contexts of the real user are bounded by the `nproc` limit.
+        let user_context = match &*current_context_lock.read() {
+            context => UserContext::new(
+                context.ruid,
+                if context.euid == 0 {
+                    RLIM_INFINITY
+                } else {
+                    context.rlimits.get(Resource::Nproc).cur
+                },
+            )?,
+        };
+
         let new_context_lock = Arc::clone(context::contexts_mut().spawn(true, clone_handler)?);
 
         // (Starts with "all signals blocked".)
@@ -1562,6 +2520,7 @@ fn inherit_context() -> Result<ContextId> {
         new_context.euid = current_context.euid;
         new_context.egid = current_context.egid;
         new_context.ruid = current_context.ruid;
+        new_context.user_context = Some(user_context);
         new_context.rgid = current_context.rgid;
         new_context.ens = current_context.ens;
         new_context.rns = current_context.rns;
@@ -1569,6 +2528,9 @@ fn inherit_context() -> Result<ContextId> {
         new_context.pgid = current_context.pgid;
         new_context.session_id = current_context.session_id;
         new_context.umask = current_context.umask;
+        new_context.cwd = current_context.cwd.clone();
+        new_context.rlimits = current_context.rlimits;
+        new_context.sched = current_context.sched;
 
         new_context.id
     };
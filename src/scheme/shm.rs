@@ -0,0 +1,243 @@
+//! `shm:` - named shared memory objects, as behind POSIX `shm_open`.
+//!
+//! Opening `shm:<name>` with `O_CREAT` creates an empty segment, which `ftruncate` sizes and
+//! `fmap` maps with `MAP_SHARED` into any number of address spaces. A segment stays reachable by
+//! name until unlinked, and its memory is freed once it is unlinked, all its descriptors are
+//! closed and all its mappings are gone.
+
+use alloc::{boxed::Box, collections::BTreeMap, string::ToString, sync::Arc, vec::Vec};
+use core::{
+    num::NonZeroUsize,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use spin::RwLock;
+
+use crate::{
+    context::memory::{handle_notify_files, AddrSpaceWrapper, Grant, PageSpan},
+    memory::{RaiiFrame, PAGE_SIZE},
+    paging::{RmmA, RmmArch, VirtualAddress},
+    syscall::{
+        data::{Map, Stat},
+        error::*,
+        flag::{
+            MapFlags, F_GETFL, F_SETFL, MODE_FILE, MODE_PERM, O_ACCMODE, O_CREAT, O_EXCL, O_RDONLY,
+            O_TRUNC, O_WRONLY,
+        },
+        usercopy::UserSliceWo,
+    },
+};
+
+use super::{CallerCtx, KernelScheme, OpenResult};
+
+pub struct ShmScheme;
+
+struct Segment {
+    name: Box<str>,
+    uid: u32,
+    gid: u32,
+    mode: u16,
+    /// The frames backing the segment, each zeroed when allocated.
+    frames: RwLock<Vec<RaiiFrame>>,
+}
+
+impl Segment {
+    fn size(&self) -> usize {
+        self.frames.read().len() * PAGE_SIZE
+    }
+    /// Whether `uid` and `gid` may access the segment as `accmode`.
+    fn permits(&self, uid: u32, gid: u32, accmode: usize) -> bool {
+        if uid == 0 {
+            return true;
+        }
+        let bits = if uid == self.uid {
+            self.mode >> 6
+        } else if gid == self.gid {
+            self.mode >> 3
+        } else {
+            self.mode
+        };
+        let read = accmode != O_WRONLY;
+        let write = accmode != O_RDONLY;
+        (!read || bits & 0o4 != 0) && (!write || bits & 0o2 != 0)
+    }
+}
+
+struct Handle {
+    segment: Arc<Segment>,
+    accmode: usize,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+// Using BTreeMap as hashbrown doesn't have a const constructor.
+static SEGMENTS: RwLock<BTreeMap<Box<str>, Arc<Segment>>> = RwLock::new(BTreeMap::new());
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn segment(id: usize) -> Result<(Arc<Segment>, usize)> {
+    let handles = HANDLES.read();
+    let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+    Ok((Arc::clone(&handle.segment), handle.accmode))
+}
+
+impl KernelScheme for ShmScheme {
+    fn kopen(&self, path: &str, flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
+        let name = path.trim_start_matches('/');
+        if name.is_empty() || name.contains('/') {
+            return Err(Error::new(EINVAL));
+        }
+        let accmode = flags & O_ACCMODE;
+
+        let segment = {
+            let mut segments = SEGMENTS.write();
+            match segments.get(name) {
+                Some(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => {
+                    return Err(Error::new(EEXIST))
+                }
+                Some(segment) => {
+                    if !segment.permits(ctx.uid, ctx.gid, accmode) {
+                        return Err(Error::new(EACCES));
+                    }
+                    Arc::clone(segment)
+                }
+                None if flags & O_CREAT == O_CREAT => {
+                    let segment = Arc::new(Segment {
+                        name: name.into(),
+                        uid: ctx.uid,
+                        gid: ctx.gid,
+                        mode: (flags & MODE_PERM as usize) as u16,
+                        frames: RwLock::new(Vec::new()),
+                    });
+                    segments.insert(name.into(), Arc::clone(&segment));
+                    segment
+                }
+                None => return Err(Error::new(ENOENT)),
+            }
+        };
+
+        if flags & O_TRUNC == O_TRUNC && accmode != O_RDONLY {
+            segment.frames.write().clear();
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        HANDLES.write().insert(id, Handle { segment, accmode });
+        Ok(OpenResult::SchemeLocal(id))
+    }
+
+    fn unlink(&self, path: &str, ctx: CallerCtx) -> Result<()> {
+        let name = path.trim_start_matches('/');
+        let mut segments = SEGMENTS.write();
+        let segment = segments.get(name).ok_or(Error::new(ENOENT))?;
+        if ctx.uid != 0 && ctx.uid != segment.uid {
+            return Err(Error::new(EPERM));
+        }
+        segments.remove(name);
+        Ok(())
+    }
+
+    fn ftruncate(&self, id: usize, len: usize) -> Result<()> {
+        let (segment, accmode) = segment(id)?;
+        if accmode == O_RDONLY {
+            return Err(Error::new(EBADF));
+        }
+
+        let page_count = len.div_ceil(PAGE_SIZE);
+        let mut frames = segment.frames.write();
+        // Pages already mapped remain so when shrinking, until unmapped.
+        frames.truncate(page_count);
+        frames
+            .try_reserve_exact(page_count - frames.len())
+            .map_err(|_| Error::new(ENOMEM))?;
+        while frames.len() < page_count {
+            let frame = RaiiFrame::allocate()?;
+            unsafe {
+                (RmmA::phys_to_virt(frame.get().start_address()).data() as *mut u8)
+                    .write_bytes(0, PAGE_SIZE);
+            }
+            frames.push(frame);
+        }
+        Ok(())
+    }
+
+    fn kfmap(
+        &self,
+        id: usize,
+        addr_space: &Arc<AddrSpaceWrapper>,
+        map: &Map,
+        _consume: bool,
+    ) -> Result<usize> {
+        let (segment, accmode) = segment(id)?;
+        if !map.flags.contains(MapFlags::MAP_SHARED) {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        if accmode == O_WRONLY || accmode == O_RDONLY && map.flags.contains(MapFlags::PROT_WRITE) {
+            return Err(Error::new(EACCES));
+        }
+        if map.offset % PAGE_SIZE != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let span = PageSpan::validate_nonempty(VirtualAddress::new(map.address), map.size)
+            .ok_or(Error::new(EINVAL))?;
+        let page_count = NonZeroUsize::new(span.count).ok_or(Error::new(EINVAL))?;
+
+        // Held until mapped, so that the frames are not freed by a concurrent ftruncate.
+        let guard = segment.frames.read();
+        let first = map.offset / PAGE_SIZE;
+        let frames = guard
+            .get(first..first + page_count.get())
+            .ok_or(Error::new(EINVAL))?;
+
+        let mut notify_files = Vec::new();
+        let page = addr_space.acquire_write().mmap(
+            addr_space,
+            (map.address != 0).then_some(span.base),
+            page_count,
+            map.flags,
+            &mut notify_files,
+            |dst_page, flags, mapper, flusher| {
+                Grant::allocated_shared(
+                    frames.iter().map(RaiiFrame::get),
+                    dst_page,
+                    flags,
+                    mapper,
+                    flusher,
+                )
+            },
+        )?;
+        handle_notify_files(notify_files);
+
+        Ok(page.start_address().data())
+    }
+
+    fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<()> {
+        let (segment, _) = segment(id)?;
+        buf.copy_exactly(&Stat {
+            st_mode: MODE_FILE | segment.mode,
+            st_uid: segment.uid,
+            st_gid: segment.gid,
+            st_size: segment.size() as u64,
+            st_blksize: PAGE_SIZE as u32,
+            st_blocks: segment.size().div_ceil(512) as u64,
+            st_nlink: 1,
+            ..Default::default()
+        })?;
+        Ok(())
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
+        let (segment, _) = segment(id)?;
+        let path = "shm:".to_string() + &segment.name;
+        buf.copy_common_bytes_from_slice(path.as_bytes())
+    }
+
+    fn fcntl(&self, id: usize, cmd: usize, _arg: usize) -> Result<usize> {
+        segment(id)?;
+        match cmd {
+            F_GETFL | F_SETFL => Ok(0),
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
+
+    fn close(&self, id: usize) -> Result<()> {
+        HANDLES.write().remove(&id).ok_or(Error::new(EBADF))?;
+        Ok(())
+    }
+}
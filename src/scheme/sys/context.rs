@@ -1,11 +1,16 @@
 use alloc::string::ToString;
 use alloc::{string::String, vec::Vec};
+use core::mem;
 
-use crate::{context, paging::PAGE_SIZE, syscall::error::Result};
+use crate::{
+    context::{self, Context},
+    paging::PAGE_SIZE,
+    syscall::error::Result,
+};
 
 pub fn resource() -> Result<Vec<u8>> {
     let mut string = format!(
-        "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<11}{:<12}{:<8}{}\n",
+        "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<11}{:<12}{:<12}{:<12}{:<8}{}\n",
         "PID",
         "PGID",
         "PPID",
@@ -20,6 +25,8 @@ pub fn resource() -> Result<Vec<u8>> {
         "CPU",
         "AFFINITY",
         "TIME",
+        "STIME",
+        "ITIME",
         "MEM",
         "NAME"
     );
@@ -28,40 +35,7 @@ pub fn resource() -> Result<Vec<u8>> {
         for (_id, context_lock) in contexts.iter() {
             let context = context_lock.read();
 
-            let mut stat_string = String::new();
-            // TODO: All user programs must have some grant in order for executable memory to even
-            // exist, but is this a good indicator of whether it is user or kernel?
-            stat_string.push(if let Ok(addr_space) = context.addr_space() {
-                if addr_space.acquire_read().grants.is_empty() {
-                    'K'
-                } else {
-                    'U'
-                }
-            } else {
-                'R'
-            });
-            match context.status {
-                context::Status::Runnable => {
-                    stat_string.push('R');
-                }
-                context::Status::Blocked | context::Status::HardBlocked { .. } => {
-                    if context.wake.is_some() {
-                        stat_string.push('S');
-                    } else {
-                        stat_string.push('B');
-                    }
-                }
-                context::Status::Stopped(_sig) => {
-                    stat_string.push('T');
-                }
-                context::Status::Exited(_status) => {
-                    stat_string.push('Z');
-                }
-            }
-            if context.running {
-                stat_string.push('+');
-            }
-
+            let stat_string = stat_string(&context);
             let cpu_string = if let Some(cpu_id) = context.cpu_id {
                 format!("{}", cpu_id)
             } else {
@@ -69,29 +43,7 @@ pub fn resource() -> Result<Vec<u8>> {
             };
             let affinity = context.sched_affinity.to_string();
 
-            let cpu_time_s = context.cpu_time / crate::time::NANOS_PER_SEC;
-            let cpu_time_ns = context.cpu_time % crate::time::NANOS_PER_SEC;
-            let cpu_time_string = format!(
-                "{:02}:{:02}:{:02}.{:02}",
-                cpu_time_s / 3600,
-                (cpu_time_s / 60) % 60,
-                cpu_time_s % 60,
-                cpu_time_ns / 10_000_000
-            );
-
-            let mut memory = context.kfx.len();
-            if let Some(ref kstack) = context.kstack {
-                memory += kstack.len();
-            }
-            if let Ok(addr_space) = context.addr_space() {
-                for (_base, info) in addr_space.acquire_read().grants.iter() {
-                    // TODO: method
-                    if matches!(info.provider, context::memory::Provider::Allocated { .. }) {
-                        memory += info.page_count() * PAGE_SIZE;
-                    }
-                }
-            }
-
+            let memory = memory(&context);
             let memory_string = if memory >= 1024 * 1024 * 1024 {
                 format!("{} GB", memory / 1024 / 1024 / 1024)
             } else if memory >= 1024 * 1024 {
@@ -103,7 +55,7 @@ pub fn resource() -> Result<Vec<u8>> {
             };
 
             string.push_str(&format!(
-                "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<11}{:<12}{:<8}{}\n",
+                "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<11}{:<12}{:<12}{:<12}{:<8}{}\n",
                 context.id.get(),
                 context.pgid.get(),
                 context.ppid.get(),
@@ -117,7 +69,9 @@ pub fn resource() -> Result<Vec<u8>> {
                 stat_string,
                 cpu_string,
                 affinity,
-                cpu_time_string,
+                time_string(context.cpu_time),
+                time_string(context.kernel_time),
+                time_string(context.irq_time),
                 memory_string,
                 context.name
             ));
@@ -126,3 +80,175 @@ pub fn resource() -> Result<Vec<u8>> {
 
     Ok(string.into_bytes())
 }
+
+/// CPU time as `hh:mm:ss.cc`.
+fn time_string(time: u128) -> String {
+    let secs = time / crate::time::NANOS_PER_SEC;
+    let nanos = time % crate::time::NANOS_PER_SEC;
+    format!(
+        "{:02}:{:02}:{:02}.{:02}",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60,
+        nanos / 10_000_000
+    )
+}
+
+fn stat_string(context: &Context) -> String {
+    let mut stat_string = String::new();
+    // TODO: All user programs must have some grant in order for executable memory to even
+    // exist, but is this a good indicator of whether it is user or kernel?
+    stat_string.push(if let Ok(addr_space) = context.addr_space() {
+        if addr_space.acquire_read().grants.is_empty() {
+            'K'
+        } else {
+            'U'
+        }
+    } else {
+        'R'
+    });
+    match context.status {
+        context::Status::Runnable => {
+            stat_string.push('R');
+        }
+        context::Status::Blocked | context::Status::HardBlocked { .. } => {
+            if context.wake.is_some() {
+                stat_string.push('S');
+            } else {
+                stat_string.push('B');
+            }
+        }
+        context::Status::Stopped(_sig) => {
+            stat_string.push('T');
+        }
+        context::Status::Exited(_status) => {
+            stat_string.push('Z');
+        }
+    }
+    if context.running {
+        stat_string.push('+');
+    }
+    stat_string
+}
+
+/// Memory owned by the context: kernel buffers and allocated grants.
+pub(super) fn memory(context: &Context) -> usize {
+    let mut memory = context.kfx.len();
+    if let Some(ref kstack) = context.kstack {
+        memory += kstack.len();
+    }
+    if let Ok(addr_space) = context.addr_space() {
+        for (_base, info) in addr_space.acquire_read().grants.iter() {
+            // TODO: method
+            if matches!(info.provider, context::memory::Provider::Allocated { .. }) {
+                memory += info.page_count() * PAGE_SIZE;
+            }
+        }
+    }
+    memory
+}
+
+/// Version of the `sys:context_records` format, bumped whenever [`ContextRecord`] changes.
+const RECORD_VERSION: u32 = 2;
+const RECORD_NAME_LEN: usize = 32;
+
+/// Header of `sys:context_records`, followed by `count` records of `record_size` bytes each.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct RecordHeader {
+    version: u32,
+    record_size: u32,
+    count: u32,
+    _reserved: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct ContextRecord {
+    id: u64,
+    pgid: u64,
+    ppid: u64,
+    session_id: u64,
+    ruid: u32,
+    rgid: u32,
+    euid: u32,
+    egid: u32,
+    rns: u64,
+    ens: u64,
+    /// The status as an ASCII letter, as in the STAT column of `sys:context`
+    status: u8,
+    running: u8,
+    /// Current CPU, or `u16::MAX` if none
+    cpu: u16,
+    nice: i8,
+    _reserved: [u8; 3],
+    /// Resident memory in bytes
+    memory: u64,
+    cpu_time: u64,
+    kernel_time: u64,
+    irq_time: u64,
+    start_time: u64,
+    /// NUL-padded, truncated name
+    name: [u8; RECORD_NAME_LEN],
+}
+
+fn record(context: &Context) -> ContextRecord {
+    let mut name = [0; RECORD_NAME_LEN];
+    let len = context.name.len().min(RECORD_NAME_LEN);
+    name[..len].copy_from_slice(&context.name.as_bytes()[..len]);
+
+    ContextRecord {
+        id: context.id.get() as u64,
+        pgid: context.pgid.get() as u64,
+        ppid: context.ppid.get() as u64,
+        session_id: context.session_id.get() as u64,
+        ruid: context.ruid,
+        rgid: context.rgid,
+        euid: context.euid,
+        egid: context.egid,
+        rns: context.rns.get() as u64,
+        ens: context.ens.get() as u64,
+        // The first letter is the user/kernel marker
+        status: stat_string(context).as_bytes()[1],
+        running: context.running.into(),
+        cpu: context
+            .cpu_id
+            .map_or(u16::MAX, |cpu_id| cpu_id.get() as u16),
+        nice: context.sched.nice,
+        _reserved: [0; 3],
+        memory: memory(context) as u64,
+        cpu_time: context.cpu_time as u64,
+        kernel_time: context.kernel_time as u64,
+        irq_time: context.irq_time as u64,
+        start_time: context.start_time as u64,
+        name,
+    }
+}
+
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// Machine-readable counterpart of [`resource`], with one fixed-size record per context.
+pub fn records() -> Result<Vec<u8>> {
+    let records = context::contexts()
+        .iter()
+        .map(|(_id, context_lock)| record(&context_lock.read()))
+        .collect::<Vec<_>>();
+
+    let header = RecordHeader {
+        version: RECORD_VERSION,
+        record_size: mem::size_of::<ContextRecord>() as u32,
+        count: records.len() as u32,
+        _reserved: 0,
+    };
+
+    let mut data = Vec::with_capacity(
+        mem::size_of::<RecordHeader>() + records.len() * mem::size_of::<ContextRecord>(),
+    );
+    data.extend_from_slice(as_bytes(&header));
+    for record in &records {
+        data.extend_from_slice(as_bytes(record));
+    }
+    Ok(data)
+}
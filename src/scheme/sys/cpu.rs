@@ -1,15 +1,30 @@
 use alloc::vec::Vec;
+use core::fmt::Write;
 
-use crate::{
-    device::cpu::cpu_info,
-    syscall::error::{Error, Result, EIO},
-};
+use crate::{cpu_set::LogicalCpuId, percpu, syscall::error::Result};
 
 pub fn resource() -> Result<Vec<u8>> {
     let mut string = format!("CPUs: {}\n", crate::cpu_count());
 
-    match cpu_info(&mut string) {
-        Ok(()) => Ok(string.into_bytes()),
-        Err(_) => Err(Error::new(EIO)),
+    // Each CPU describes itself when it is brought up, and measures its own frequency
+    for id in 0..crate::cpu_count() {
+        let Some(percpu) = percpu::get(LogicalCpuId::new(id)) else {
+            let _ = writeln!(string, "\nCPU {}: offline", id);
+            continue;
+        };
+        let _ = writeln!(
+            string,
+            "\nCPU {}: package {} core {} thread {} online",
+            id, percpu.topology.package, percpu.topology.core, percpu.topology.thread
+        );
+        let _ = match percpu.frequency.khz() {
+            Some(khz) => writeln!(string, "Current MHz: {}", khz / 1000),
+            None => writeln!(string, "Current MHz: unknown"),
+        };
+        if let Some(info) = percpu.info.get() {
+            let _ = writeln!(string, "{}", info.trim_end());
+        }
     }
+
+    Ok(string.into_bytes())
 }
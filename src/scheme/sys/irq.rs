@@ -1,7 +1,7 @@
 use alloc::{string::String, vec::Vec};
 use core::fmt::Write;
 
-use crate::syscall::error::Result;
+use crate::syscall::error::{Error, Result, EINVAL};
 
 pub fn resource() -> Result<Vec<u8>> {
     let mut string = String::new();
@@ -15,3 +15,16 @@ pub fn resource() -> Result<Vec<u8>> {
 
     Ok(string.into_bytes())
 }
+
+/// Write handler of `sys:irq_affinity`, taking `<irq> <cpu>` lines that route legacy IRQs to the
+/// given CPU, which is a hexadecimal ID as in the `irq:cpu-XX` directories.
+pub fn write_affinity(buf: &[u8]) -> Result<usize> {
+    let text = core::str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (irq, cpu_id) = line.split_once(' ').ok_or(Error::new(EINVAL))?;
+        let irq = irq.parse().map_err(|_| Error::new(EINVAL))?;
+        let cpu_id = u8::from_str_radix(cpu_id.trim(), 16).map_err(|_| Error::new(EINVAL))?;
+        crate::scheme::irq::set_legacy_affinity(irq, cpu_id)?;
+    }
+    Ok(buf.len())
+}
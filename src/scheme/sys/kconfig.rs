@@ -0,0 +1,152 @@
+//! Runtime kernel configuration, exchanged as one `<key> <value>` line per setting.
+//!
+//! The level threshold of each output sink is set by a `sink.<name>` key, such as
+//! `sink.serial debug` or `sink.display off`, and that of the kernel log records of each
+//! subsystem by a `filter.<name>` key, such as `filter.scheme debug`. The group allowed to read
+//! the kernel log besides root is set by `log.gid`, where `any` lets anyone read it, as by
+//! default. The lockup detector thresholds are set in seconds by `watchdog.soft_lockup` and
+//! `watchdog.hung`, see [`crate::context::lockup`]. With fault injection compiled in, the
+//! probabilities of faults are set by `fault.<point>` keys, and the random seed by `fault.seed`.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+#[cfg(feature = "fault_injection")]
+use crate::fault::{self, Point};
+use crate::{
+    context::lockup,
+    log::{self, Sink, Subsystem},
+    syscall::error::{Error, Result, EINVAL},
+};
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+    for sink in Sink::ALL {
+        let _ = writeln!(
+            string,
+            "sink.{} {}",
+            sink.name(),
+            log::level_filter_name(sink.threshold())
+        );
+    }
+    for subsystem in filtered_subsystems() {
+        let _ = writeln!(
+            string,
+            "filter.{} {}",
+            subsystem.name(),
+            log::level_filter_name(subsystem.threshold())
+        );
+    }
+    match log::read_gid() {
+        Some(gid) => {
+            let _ = writeln!(string, "log.gid {}", gid);
+        }
+        None => string.push_str("log.gid any\n"),
+    }
+    let _ = writeln!(
+        string,
+        "watchdog.soft_lockup {}",
+        lockup::soft_lockup_secs()
+    );
+    let _ = writeln!(string, "watchdog.hung {}", lockup::hung_secs());
+    #[cfg(feature = "fault_injection")]
+    {
+        for point in Point::ALL {
+            let _ = writeln!(string, "fault.{} {}", point.name(), point.probability());
+        }
+        let _ = writeln!(string, "fault.seed {}", fault::seed());
+    }
+    Ok(string.into_bytes())
+}
+
+/// The subsystems of kernel log records, leaving out output written by userspace.
+fn filtered_subsystems() -> impl Iterator<Item = Subsystem> {
+    Subsystem::ALL
+        .into_iter()
+        .filter(|subsystem| *subsystem != Subsystem::User)
+}
+
+/// Apply `<key> <value>` lines. All lines are validated before any setting is changed.
+pub fn write(buf: &[u8]) -> Result<usize> {
+    let text = core::str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?;
+    let mut thresholds = Sink::ALL.map(Sink::threshold);
+    let mut filters = Subsystem::ALL.map(Subsystem::threshold);
+    let mut read_gid = log::read_gid();
+    let mut soft_lockup_secs = None;
+    let mut hung_secs = None;
+    #[cfg(feature = "fault_injection")]
+    let (mut probabilities, mut seed) = (Point::ALL.map(Point::probability), None);
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (key, value) = line.split_once(' ').ok_or(Error::new(EINVAL))?;
+        let value = value.trim();
+        match key {
+            "log.gid" => {
+                read_gid = match value {
+                    "any" => None,
+                    _ => Some(value.parse().map_err(|_| Error::new(EINVAL))?),
+                };
+                continue;
+            }
+            "watchdog.soft_lockup" => {
+                soft_lockup_secs = Some(value.parse().map_err(|_| Error::new(EINVAL))?);
+                continue;
+            }
+            "watchdog.hung" => {
+                hung_secs = Some(value.parse().map_err(|_| Error::new(EINVAL))?);
+                continue;
+            }
+            #[cfg(feature = "fault_injection")]
+            "fault.seed" => {
+                seed = Some(value.parse().map_err(|_| Error::new(EINVAL))?);
+                continue;
+            }
+            _ => (),
+        }
+        #[cfg(feature = "fault_injection")]
+        if let Some(point) = key.strip_prefix("fault.").and_then(Point::from_name) {
+            probabilities[point as usize] = value
+                .parse()
+                .ok()
+                .filter(|&probability| probability <= 100)
+                .ok_or(Error::new(EINVAL))?;
+            continue;
+        }
+        let threshold = log::level_filter_from_name(value).ok_or(Error::new(EINVAL))?;
+        if let Some(subsystem) = key
+            .strip_prefix("filter.")
+            .and_then(Subsystem::from_name)
+            .filter(|subsystem| *subsystem != Subsystem::User)
+        {
+            filters[subsystem as usize] = threshold;
+            continue;
+        }
+        let sink = key
+            .strip_prefix("sink.")
+            .and_then(Sink::from_name)
+            .ok_or(Error::new(EINVAL))?;
+        thresholds[sink as usize] = threshold;
+    }
+    for (sink, threshold) in Sink::ALL.into_iter().zip(thresholds) {
+        sink.set_threshold(threshold);
+    }
+    for (subsystem, threshold) in Subsystem::ALL.into_iter().zip(filters) {
+        subsystem.set_threshold(threshold);
+    }
+    log::set_read_gid(read_gid);
+    if let Some(secs) = soft_lockup_secs {
+        lockup::set_soft_lockup_secs(secs);
+    }
+    if let Some(secs) = hung_secs {
+        lockup::set_hung_secs(secs);
+    }
+    #[cfg(feature = "fault_injection")]
+    {
+        for (point, probability) in Point::ALL.into_iter().zip(probabilities) {
+            point.set_probability(probability);
+        }
+        if let Some(seed) = seed {
+            fault::set_seed(seed);
+        }
+    }
+    Ok(buf.len())
+}
@@ -0,0 +1,20 @@
+use alloc::vec::Vec;
+
+use crate::{context::loadavg, syscall::error::Result};
+
+pub fn resource() -> Result<Vec<u8>> {
+    let [one, five, fifteen] = loadavg::averages();
+    let (runnable, total) = loadavg::count();
+    Ok(format!(
+        "{}.{:02} {}.{:02} {}.{:02} {}/{}\n",
+        one / 100,
+        one % 100,
+        five / 100,
+        five % 100,
+        fifteen / 100,
+        fifteen % 100,
+        runnable,
+        total
+    )
+    .into_bytes())
+}
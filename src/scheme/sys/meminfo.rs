@@ -0,0 +1,47 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{
+    memory::{free_frames, section_usage, total_frames, PAGE_SIZE},
+    syscall::error::Result,
+};
+
+fn kib(frames: usize) -> usize {
+    frames * (PAGE_SIZE / 1024)
+}
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+
+    let total = total_frames();
+    let free = free_frames();
+    let _ = writeln!(string, "MemTotal: {} kB", kib(total));
+    let _ = writeln!(string, "MemFree: {} kB", kib(free));
+    // There are no reclaimable caches, so everything free is available
+    let _ = writeln!(string, "MemAvailable: {} kB", kib(free));
+    // TODO: Committed memory, once lazy mappings are accounted for
+    let _ = writeln!(string, "MemUsed: {} kB", kib(total - free));
+
+    #[cfg(not(feature = "slab"))]
+    if let Some((size, used)) = crate::allocator::Allocator::usage() {
+        let _ = writeln!(string, "KernelHeap: {} kB", size / 1024);
+        let _ = writeln!(string, "KernelHeapUsed: {} kB", used / 1024);
+    }
+
+    // Huge pages are not supported
+    let _ = writeln!(string, "HugePagesTotal: 0");
+
+    for (i, section) in section_usage().enumerate() {
+        let _ = writeln!(
+            string,
+            "Section{}: base={:#x} total={} kB used={} kB shared={} kB",
+            i,
+            section.base.data(),
+            kib(section.frames),
+            kib(section.used),
+            kib(section.shared)
+        );
+    }
+
+    Ok(string.into_bytes())
+}
@@ -9,9 +9,9 @@ use crate::{
     arch::interrupt,
     syscall::{
         data::Stat,
-        error::{Error, Result, EBADF, ENOENT},
-        flag::{MODE_DIR, MODE_FILE},
-        usercopy::UserSliceWo,
+        error::{Error, Result, EACCES, EBADF, ENOENT},
+        flag::{MODE_DIR, MODE_FILE, O_ACCMODE, O_RDONLY},
+        usercopy::{UserSliceRo, UserSliceWo},
     },
 };
 
@@ -23,20 +23,34 @@ mod cpu;
 mod exe;
 mod iostat;
 mod irq;
+mod kconfig;
+mod loadavg;
 mod log;
+mod meminfo;
 mod scheme;
 mod scheme_num;
+mod schemes;
+mod stat;
 mod syscall;
+pub mod trigger;
 mod uname;
+mod uptime;
+mod version;
 
 struct Handle {
     path: &'static str,
     data: Vec<u8>,
     mode: u16,
     seek: usize,
+    write: Option<SysWriteFn>,
 }
 
 type SysFn = fn() -> Result<Vec<u8>>;
+/// Handler of a write-only control file, receiving the written bytes.
+type SysWriteFn = fn(&[u8]) -> Result<usize>;
+
+/// Maximum number of bytes accepted by a single write to a control file.
+const MAX_WRITE: usize = 4096;
 
 /// System information scheme
 pub struct SysScheme;
@@ -47,18 +61,35 @@ static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
 const FILES: &[(&'static str, SysFn)] = &[
     ("block", block::resource),
     ("context", context::resource),
+    ("context_records", context::records),
     ("cpu", cpu::resource),
     ("exe", exe::resource),
     ("iostat", iostat::resource),
     ("irq", irq::resource),
+    ("kconfig", kconfig::resource),
+    ("lastpanic", crate::pstore::resource),
+    ("loadavg", loadavg::resource),
     ("log", log::resource),
+    ("meminfo", meminfo::resource),
+    ("pipe", crate::scheme::pipe::limits_resource),
     ("scheme", scheme::resource),
     ("scheme_num", scheme_num::resource),
+    ("schemes", schemes::resource),
+    ("stat", stat::resource),
     ("syscall", syscall::resource),
     ("uname", uname::resource),
+    ("uptime", uptime::resource),
+    ("version", version::resource),
+    // The bootloader environment, which serves as the kernel command line
     ("env", || Ok(Vec::from(crate::init_env()))),
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     ("spurious_irq", interrupt::irq::spurious_irq_resource),
+    #[cfg(target_arch = "aarch64")]
+    ("rtc", crate::device::rtc::resource),
+    #[cfg(feature = "lock_debug")]
+    ("locks", crate::sync::lock_debug::resource),
+    #[cfg(feature = "fault_injection")]
+    ("faults", crate::fault::resource),
     // Disabled because the debugger is inherently unsafe and probably will break the system.
     /*
     ("trigger_debugger", || unsafe {
@@ -68,17 +99,36 @@ const FILES: &[(&'static str, SysFn)] = &[
     */
 ];
 
+/// Control files, which can only be opened for writing by root. Those also listed in [`FILES`]
+/// can be read as well, and are only write-only otherwise.
+const WRITE_FILES: &[(&'static str, SysWriteFn)] = &[
+    ("irq_affinity", irq::write_affinity),
+    ("kconfig", kconfig::write),
+    ("pipe_limits", crate::scheme::pipe::write_limits),
+    #[cfg(target_arch = "aarch64")]
+    ("rtc", crate::device::rtc::write),
+    ("trigger", trigger::write),
+    #[cfg(feature = "ktest")]
+    ("selftest", crate::ktest::sys_write),
+];
+
 impl KernelScheme for SysScheme {
-    fn kopen(&self, path: &str, _flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
+    fn kopen(&self, path: &str, flags: usize, ctx: CallerCtx) -> Result<OpenResult> {
         let path = path.trim_matches('/');
 
         if path.is_empty() {
             let mut data = Vec::new();
-            for entry in FILES.iter() {
+            let names = FILES.iter().map(|entry| entry.0).chain(
+                WRITE_FILES
+                    .iter()
+                    .map(|entry| entry.0)
+                    .filter(|name| FILES.iter().all(|entry| entry.0 != *name)),
+            );
+            for name in names {
                 if !data.is_empty() {
                     data.push(b'\n');
                 }
-                data.extend_from_slice(entry.0.as_bytes());
+                data.extend_from_slice(name.as_bytes());
             }
 
             let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
@@ -89,6 +139,7 @@ impl KernelScheme for SysScheme {
                     data,
                     mode: MODE_DIR | 0o444,
                     seek: 0,
+                    write: None,
                 },
             );
             return Ok(OpenResult::SchemeLocal(id));
@@ -96,6 +147,21 @@ impl KernelScheme for SysScheme {
             //Have to iterate to get the path without allocation
             for entry in FILES.iter() {
                 if &entry.0 == &path {
+                    // The log saved from the previous boot is as private as the current one.
+                    if matches!(entry.0, "log" | "lastpanic")
+                        && !crate::log::may_read(ctx.uid, ctx.gid)
+                    {
+                        return Err(Error::new(EACCES));
+                    }
+                    let write_entry = WRITE_FILES.iter().find(|write| write.0 == entry.0);
+                    let write = write_entry
+                        .filter(|_| flags & O_ACCMODE != O_RDONLY)
+                        .map(|write| write.1);
+                    if write.is_some() && ctx.uid != 0 {
+                        return Err(Error::new(EACCES));
+                    }
+                    let mode = if write_entry.is_some() { 0o644 } else { 0o444 };
+
                     let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
                     let data = entry.1()?;
                     HANDLES.write().insert(
@@ -103,8 +169,28 @@ impl KernelScheme for SysScheme {
                         Handle {
                             path: entry.0,
                             data,
-                            mode: MODE_FILE | 0o444,
+                            mode: MODE_FILE | mode,
+                            seek: 0,
+                            write,
+                        },
+                    );
+                    return Ok(OpenResult::SchemeLocal(id));
+                }
+            }
+            for entry in WRITE_FILES.iter() {
+                if &entry.0 == &path {
+                    if ctx.uid != 0 {
+                        return Err(Error::new(EACCES));
+                    }
+                    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+                    HANDLES.write().insert(
+                        id,
+                        Handle {
+                            path: entry.0,
+                            data: Vec::new(),
+                            mode: MODE_FILE | 0o200,
                             seek: 0,
+                            write: Some(entry.1),
                         },
                     );
                     return Ok(OpenResult::SchemeLocal(id));
@@ -160,6 +246,21 @@ impl KernelScheme for SysScheme {
         Ok(byte_count)
     }
 
+    fn kwrite(&self, id: usize, buffer: UserSliceRo) -> Result<usize> {
+        let write = {
+            let handles = HANDLES.read();
+            let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+            handle.write.ok_or(Error::new(EBADF))?
+        };
+
+        let mut data = vec![0_u8; buffer.len().min(MAX_WRITE)];
+        let byte_count = buffer.copy_common_bytes_to_slice(&mut data)?;
+        data.truncate(byte_count);
+
+        // Not holding the handle lock, as the handler may take a while.
+        write(&data)
+    }
+
     fn kfstat(&self, id: usize, buf: UserSliceWo) -> Result<()> {
         let handles = HANDLES.read();
         let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
@@ -0,0 +1,50 @@
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{
+    context,
+    scheme::{self, KernelSchemes, SchemeId, SchemeNamespace},
+    syscall::error::Result,
+};
+
+/// One line per scheme name: `<id> <name> <owner pid or -> <namespaces>`, listing the namespaces
+/// in which the scheme is visible under that name. Only root can see other namespaces.
+pub fn resource() -> Result<Vec<u8>> {
+    let (euid, ens) = match &*context::current()?.read() {
+        context => (context.euid, context.ens),
+    };
+
+    let mut entries = BTreeMap::<(SchemeId, &str), Vec<SchemeNamespace>>::new();
+    let mut string = String::new();
+
+    let schemes = scheme::schemes();
+    for (&ns, names) in schemes.names.iter() {
+        if euid != 0 && ns != ens {
+            continue;
+        }
+        for (name, &id) in names.iter() {
+            entries.entry((id, &**name)).or_default().push(ns);
+        }
+    }
+
+    for ((id, name), mut namespaces) in entries {
+        namespaces.sort_unstable();
+
+        let owner = match schemes.get(id) {
+            Some(KernelSchemes::User(user)) => user.inner.upgrade().and_then(|inner| inner.owner()),
+            _ => None,
+        };
+
+        let _ = write!(string, "{:>4} {} ", id.get(), name);
+        let _ = match owner {
+            Some(pid) => write!(string, "{}", pid.get()),
+            None => write!(string, "-"),
+        };
+        for (i, ns) in namespaces.iter().enumerate() {
+            let _ = write!(string, "{}{}", if i == 0 { " " } else { "," }, ns.get());
+        }
+        string.push('\n');
+    }
+
+    Ok(string.into_bytes())
+}
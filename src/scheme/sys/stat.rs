@@ -0,0 +1,27 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{context::cputime, cpu_set::LogicalCpuId, syscall::error::Result};
+
+/// Time spent by the CPUs in user, system, interrupt and idle mode, in nanoseconds. The first line
+/// sums all CPUs, and is followed by one line for each CPU.
+pub fn resource() -> Result<Vec<u8>> {
+    let cpus = (0..crate::cpu_count())
+        .map(|id| cputime::totals(LogicalCpuId::new(id)))
+        .collect::<Vec<_>>();
+
+    let mut sum = [0; 4];
+    for totals in &cpus {
+        for (sum, total) in sum.iter_mut().zip(totals) {
+            *sum += total;
+        }
+    }
+
+    let mut string = String::new();
+    let [user, system, irq, idle] = sum;
+    let _ = writeln!(string, "cpu {} {} {} {}", user, system, irq, idle);
+    for (id, [user, system, irq, idle]) in cpus.into_iter().enumerate() {
+        let _ = writeln!(string, "cpu{} {} {} {} {}", id, user, system, irq, idle);
+    }
+    Ok(string.into_bytes())
+}
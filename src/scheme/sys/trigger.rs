@@ -0,0 +1,300 @@
+//! Emergency debug triggers, in the spirit of Linux's magic SysRq.
+//!
+//! Commands are written by root to `sys:trigger`, or entered on a serial console by sending a
+//! break followed by the key of the command. The dumps only try to lock contexts, so that they
+//! still give partial output when the system is wedged on a context lock.
+
+use alloc::collections::BTreeSet;
+use core::{
+    str,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use syscall::SIGKILL;
+
+use crate::{
+    context::{self, signal::SignalInfo, ContextId, Status},
+    scheme::{self, SchemeId},
+    symbols::Symbolized,
+    syscall::error::{Error, Result, EBUSY, EINVAL, ENODEV, ESRCH},
+};
+
+/// Maximum number of frames printed per kernel stack.
+const MAX_FRAMES: usize = 64;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Command {
+    DumpAllStacks,
+    DumpSchedulerState,
+    KillMemoryHog,
+    EmergencySyncSchemes,
+    Reboot,
+    EnterDebugger,
+}
+impl Command {
+    const ALL: [Self; 6] = [
+        Self::DumpAllStacks,
+        Self::DumpSchedulerState,
+        Self::KillMemoryHog,
+        Self::EmergencySyncSchemes,
+        Self::Reboot,
+        Self::EnterDebugger,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::DumpAllStacks => "dump-all-stacks",
+            Self::DumpSchedulerState => "dump-scheduler-state",
+            Self::KillMemoryHog => "kill-memory-hog",
+            Self::EmergencySyncSchemes => "emergency-sync-schemes",
+            Self::Reboot => "reboot",
+            Self::EnterDebugger => "debugger",
+        }
+    }
+    /// The key selecting the command after a serial break, matching Linux where possible.
+    fn key(self) -> u8 {
+        match self {
+            Self::DumpAllStacks => b't',
+            Self::DumpSchedulerState => b'q',
+            Self::KillMemoryHog => b'f',
+            Self::EmergencySyncSchemes => b's',
+            Self::Reboot => b'b',
+            Self::EnterDebugger => b'g',
+        }
+    }
+}
+
+/// Write handler of `sys:trigger`, taking one command name.
+pub fn write(buf: &[u8]) -> Result<usize> {
+    let name = str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?.trim();
+    let command = Command::ALL
+        .into_iter()
+        .find(|command| command.name() == name)
+        .ok_or(Error::new(EINVAL))?;
+
+    run(command)?;
+    Ok(buf.len())
+}
+
+/// Set by a serial break, so that the next received byte selects a command.
+static SERIAL_ARMED: AtomicBool = AtomicBool::new(false);
+/// Key of a command entered on the serial console, to be run by [`run_serial_pending`].
+static SERIAL_PENDING: AtomicU8 = AtomicU8::new(0);
+
+/// Feed a byte received by a serial IRQ handler. Returns whether the byte was consumed, in which
+/// case it must not be passed on as regular input.
+pub fn serial_input(byte: u8, is_break: bool) -> bool {
+    if is_break {
+        SERIAL_ARMED.store(true, Ordering::Relaxed);
+        return true;
+    }
+    if !SERIAL_ARMED.swap(false, Ordering::Relaxed) {
+        return false;
+    }
+    SERIAL_PENDING.store(byte, Ordering::Relaxed);
+    true
+}
+
+/// Run the command entered on the serial console, if any. Must be called by the serial IRQ
+/// handler without holding the port lock, since the commands print to the console.
+pub fn run_serial_pending() {
+    let key = SERIAL_PENDING.swap(0, Ordering::Relaxed);
+    if key == 0 {
+        return;
+    }
+    let Some(command) = Command::ALL
+        .into_iter()
+        .find(|command| command.key() == key)
+    else {
+        log::info!("trigger: unknown key {:?}, expected one of:", key as char);
+        for command in Command::ALL {
+            log::info!("  {} {}", command.key() as char, command.name());
+        }
+        return;
+    };
+    // Syncing waits for the scheme daemons, which cannot be done in an interrupt handler.
+    if command == Command::EmergencySyncSchemes {
+        log::info!(
+            "trigger: {} is only available through sys:trigger",
+            command.name()
+        );
+        return;
+    }
+    if let Err(err) = run(command) {
+        log::info!("trigger: {} failed: {:?}", command.name(), err);
+    }
+}
+
+fn run(command: Command) -> Result<()> {
+    log::info!("trigger: {}", command.name());
+    // The dumps print a line per context from the same call sites.
+    crate::log::unlimited(|| match command {
+        Command::DumpAllStacks => dump_all_stacks(),
+        Command::DumpSchedulerState => dump_scheduler_state(),
+        Command::KillMemoryHog => kill_memory_hog(),
+        Command::EmergencySyncSchemes => emergency_sync_schemes(),
+        Command::Reboot => unsafe { crate::stop::kreset() },
+        Command::EnterDebugger => enter_debugger(),
+    })
+}
+
+/// Stop in the GDB stub, if one is listening.
+fn enter_debugger() -> Result<()> {
+    #[cfg(all(feature = "gdbstub", target_arch = "x86_64"))]
+    if crate::arch::gdbstub::enabled() {
+        crate::arch::gdbstub::enter(crate::arch::gdbstub::StopReason::Interrupt);
+        return Ok(());
+    }
+    Err(Error::new(ENODEV))
+}
+
+fn dump_all_stacks() -> Result<()> {
+    let contexts = context::try_contexts().ok_or(Error::new(EBUSY))?;
+    let current = context::context_id();
+
+    for (id, context_lock) in contexts.iter() {
+        let Some(context) = context_lock.try_read() else {
+            log::info!("{}: <locked>", id.get());
+            continue;
+        };
+        log::info!(
+            "{}: {} {:?} {}",
+            id.get(),
+            context.name,
+            context.status,
+            context.status_reason
+        );
+
+        if let Some(regs) = context.regs() {
+            log::info!("  user registers:");
+            regs.dump();
+        }
+
+        if *id == current {
+            unsafe { crate::arch::interrupt::stack_trace() };
+        } else if context.running {
+            // The saved frame pointer is stale while running elsewhere.
+            log::info!("  running on CPU {:?}", context.cpu_id);
+        } else {
+            for (fp, ip) in context.kernel_frames().take(MAX_FRAMES) {
+                log::info!("  {:>016X}: {:>016X} {}", fp, ip, Symbolized(ip));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn dump_scheduler_state() -> Result<()> {
+    let [one, five, fifteen] = context::loadavg::averages();
+    let (runnable, total) = context::loadavg::count();
+    log::info!(
+        "load {}.{:02} {}.{:02} {}.{:02}, {}/{} runnable",
+        one / 100,
+        one % 100,
+        five / 100,
+        five % 100,
+        fifteen / 100,
+        fifteen % 100,
+        runnable,
+        total
+    );
+
+    let contexts = context::try_contexts().ok_or(Error::new(EBUSY))?;
+    for (id, context_lock) in contexts.iter() {
+        let Some(context) = context_lock.try_read() else {
+            log::info!("{}: <locked>", id.get());
+            continue;
+        };
+        let running_on = match (context.running, context.cpu_id) {
+            (true, Some(cpu_id)) => cpu_id.get() as isize,
+            _ => -1,
+        };
+        log::info!(
+            "{}: {} {:?} cpu {} {} nice {} switches {} wake {:?}",
+            id.get(),
+            context.name,
+            context.status,
+            running_on,
+            context.sched.policy.name(),
+            context.sched.nice,
+            context.switch_count,
+            context.wake
+        );
+    }
+    Ok(())
+}
+
+/// Send SIGKILL to the userspace context owning the most memory, except init.
+fn kill_memory_hog() -> Result<()> {
+    let contexts = context::try_contexts().ok_or(Error::new(EBUSY))?;
+
+    let mut hog: Option<(ContextId, usize)> = None;
+    for (id, context_lock) in contexts.iter() {
+        if id.get() <= 2 {
+            continue;
+        }
+        let Some(context) = context_lock.try_read() else {
+            continue;
+        };
+        if !context.userspace || matches!(context.status, Status::Exited(_)) {
+            continue;
+        }
+        let memory = super::context::memory(&context);
+        if hog.map_or(true, |(_, max)| memory > max) {
+            hog = Some((*id, memory));
+        }
+    }
+
+    let (id, memory) = hog.ok_or(Error::new(ESRCH))?;
+    let mut context = contexts
+        .get(id)
+        .ok_or(Error::new(ESRCH))?
+        .try_write()
+        .ok_or(Error::new(EBUSY))?;
+
+    log::info!(
+        "trigger: killing {}: {} using {} KiB",
+        id.get(),
+        context.name,
+        memory / 1024
+    );
+    // Also wakes the context if it is blocked or stopped, so that it runs to die.
+    let _ = context.kill(SignalInfo::kernel(SIGKILL));
+    Ok(())
+}
+
+/// Ask every scheme with an open file to sync it, the way `fsync` would.
+fn emergency_sync_schemes() -> Result<()> {
+    let mut files = BTreeSet::<(SchemeId, usize)>::new();
+    {
+        let contexts = context::try_contexts().ok_or(Error::new(EBUSY))?;
+        for (_id, context_lock) in contexts.iter() {
+            let Some(context) = context_lock.try_read() else {
+                continue;
+            };
+            let Some(table) = context.files.try_read() else {
+                continue;
+            };
+            for file in table.iter().flatten() {
+                let description = file.description.read();
+                files.insert((description.scheme, description.number));
+            }
+        }
+    }
+
+    let mut failed = 0;
+    for &(scheme_id, number) in files.iter() {
+        let Some(scheme) = scheme::schemes().get(scheme_id).cloned() else {
+            continue;
+        };
+        if scheme.fsync(number).is_err() {
+            failed += 1;
+        }
+    }
+    log::info!(
+        "trigger: synced {} files, {} failed",
+        files.len() - failed,
+        failed
+    );
+    Ok(())
+}
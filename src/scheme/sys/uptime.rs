@@ -0,0 +1,11 @@
+use alloc::vec::Vec;
+
+use crate::{
+    syscall::error::Result,
+    time::{self, NANOS_PER_SEC},
+};
+
+pub fn resource() -> Result<Vec<u8>> {
+    let uptime = time::monotonic();
+    Ok(format!("{}.{:09}\n", uptime / NANOS_PER_SEC, uptime % NANOS_PER_SEC).into_bytes())
+}
@@ -0,0 +1,14 @@
+use alloc::vec::Vec;
+
+use crate::syscall::error::Result;
+
+pub fn resource() -> Result<Vec<u8>> {
+    Ok(format!(
+        "Version: {}\nRevision: {}\nTarget: {}\nFeatures: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        env!("KERNEL_GIT_REVISION"),
+        env!("TARGET"),
+        env!("KERNEL_FEATURES")
+    )
+    .into_bytes())
+}
@@ -1,4 +1,4 @@
-use alloc::collections::BTreeMap;
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
 use core::{
     mem, str,
     sync::atomic::{AtomicUsize, Ordering},
@@ -10,7 +10,7 @@ use crate::{
     syscall::{
         data::TimeSpec,
         error::*,
-        flag::{EventFlags, CLOCK_MONOTONIC, CLOCK_REALTIME},
+        flag::{EventFlags, CLOCK_MONOTONIC, CLOCK_REALTIME, EVENT_READ, F_GETFL, F_SETFL},
         usercopy::{UserSliceRo, UserSliceWo},
     },
     time,
@@ -18,15 +18,122 @@ use crate::{
 
 use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
 
+/// Maximum number of named timers on one handle.
+const MAX_TIMERS: usize = 64;
+/// Maximum length of a timer name.
+const MAX_TIMER_NAME: usize = 64;
+
+struct Timer {
+    /// Deadline on the clock of the handle, in nanoseconds.
+    deadline: u128,
+    /// Returned by reads of the handle once the timer has expired.
+    cookie: u64,
+}
+
+enum Handle {
+    /// Reads the time of the clock, and registers timeouts on it.
+    Clock(usize),
+    /// Reads the resolution with which timeouts of the clock fire, at `<clock>/resolution`.
+    Resolution(usize),
+    /// Named one-shot timers on the clock, at `<clock>/timers`.
+    ///
+    /// Timers are set by writing `<name> at <ns>` or `<name> in <ns>` lines, optionally followed
+    /// by a cookie, and cancelled with `<name> cancel`. Setting an existing timer replaces it.
+    /// Reads return the `u64` cookies of the expired timers, which are then removed.
+    Timers(usize, BTreeMap<String, Timer>),
+}
+
 static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
 // Using BTreeMap as hashbrown doesn't have a const constructor.
-static HANDLES: RwLock<BTreeMap<usize, usize>> = RwLock::new(BTreeMap::new());
+static HANDLES: RwLock<BTreeMap<usize, Handle>> = RwLock::new(BTreeMap::new());
+
+fn now(clock: usize) -> Result<u128> {
+    match clock {
+        CLOCK_REALTIME => Ok(time::realtime()),
+        CLOCK_MONOTONIC => Ok(time::monotonic()),
+        _ => Err(Error::new(EINVAL)),
+    }
+}
+
+/// Apply the timer lines of `text`. All lines are validated before any timer is changed.
+fn set_timers(
+    id: usize,
+    clock: usize,
+    timers: &mut BTreeMap<String, Timer>,
+    text: &str,
+) -> Result<()> {
+    let now = now(clock)?;
+    let mut changes = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let mut words = line.split_whitespace();
+        let name = words.next().ok_or(Error::new(EINVAL))?;
+        if name.len() > MAX_TIMER_NAME {
+            return Err(Error::new(ENAMETOOLONG));
+        }
+        let parse_ns = |value: Option<&str>| -> Result<u128> {
+            value
+                .ok_or(Error::new(EINVAL))?
+                .parse()
+                .map_err(|_| Error::new(EINVAL))
+        };
+        let timer = match words.next() {
+            Some("cancel") => None,
+            Some("at") => Some(parse_ns(words.next())?),
+            Some("in") => Some(now.saturating_add(parse_ns(words.next())?)),
+            _ => return Err(Error::new(EINVAL)),
+        };
+        let timer = match timer {
+            Some(deadline) => {
+                let cookie = match words.next() {
+                    Some(cookie) => cookie.parse().map_err(|_| Error::new(EINVAL))?,
+                    None => 0,
+                };
+                Some(Timer { deadline, cookie })
+            }
+            None => None,
+        };
+        if words.next().is_some() {
+            return Err(Error::new(EINVAL));
+        }
+        changes.push((name, timer));
+    }
+
+    let added = changes
+        .iter()
+        .filter(|(name, timer)| timer.is_some() && !timers.contains_key(*name))
+        .count();
+    if timers.len() + added > MAX_TIMERS {
+        return Err(Error::new(ENOSPC));
+    }
+
+    for (name, timer) in changes {
+        match timer {
+            Some(timer) => {
+                let deadline = TimeSpec {
+                    tv_sec: (timer.deadline / time::NANOS_PER_SEC) as i64,
+                    tv_nsec: (timer.deadline % time::NANOS_PER_SEC) as i32,
+                };
+                timeout::register(GlobalSchemes::Time.scheme_id(), id, clock, deadline);
+                timers.insert(name.into(), timer);
+            }
+            None => {
+                timers.remove(name);
+            }
+        }
+    }
+    Ok(())
+}
 
 pub struct TimeScheme;
 
 impl KernelScheme for TimeScheme {
     fn kopen(&self, path: &str, _flags: usize, _ctx: CallerCtx) -> Result<OpenResult> {
-        let clock = path.parse::<usize>().map_err(|_| Error::new(ENOENT))?;
+        let (clock, kind) = match path.split_once('/') {
+            Some((clock, kind @ ("resolution" | "timers"))) => (clock, kind),
+            Some(_) => return Err(Error::new(ENOENT)),
+            None => (path, ""),
+        };
+        let clock = clock.parse::<usize>().map_err(|_| Error::new(ENOENT))?;
 
         match clock {
             CLOCK_REALTIME => (),
@@ -35,21 +142,35 @@ impl KernelScheme for TimeScheme {
         }
 
         let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-        HANDLES.write().insert(id, clock);
+        let handle = match kind {
+            "resolution" => Handle::Resolution(clock),
+            "timers" => Handle::Timers(clock, BTreeMap::new()),
+            _ => Handle::Clock(clock),
+        };
+        HANDLES.write().insert(id, handle);
 
         Ok(OpenResult::SchemeLocal(id))
     }
 
-    fn fcntl(&self, _id: usize, _cmd: usize, _arg: usize) -> Result<usize> {
-        Ok(0)
+    fn fcntl(&self, _id: usize, cmd: usize, _arg: usize) -> Result<usize> {
+        match cmd {
+            F_GETFL | F_SETFL => Ok(0),
+            _ => Err(Error::new(EINVAL)),
+        }
     }
 
     fn fevent(&self, id: usize, _flags: EventFlags) -> Result<EventFlags> {
-        HANDLES
-            .read()
-            .get(&id)
-            .ok_or(Error::new(EBADF))
-            .and(Ok(EventFlags::empty()))
+        match HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+            Handle::Timers(clock, timers) => {
+                let now = now(*clock)?;
+                if timers.values().any(|timer| timer.deadline <= now) {
+                    Ok(EVENT_READ)
+                } else {
+                    Ok(EventFlags::empty())
+                }
+            }
+            _ => Ok(EventFlags::empty()),
+        }
     }
 
     fn fsync(&self, id: usize) -> Result<()> {
@@ -65,15 +186,43 @@ impl KernelScheme for TimeScheme {
             .and(Ok(()))
     }
     fn kread(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
-        let clock = *HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        let mut handles = HANDLES.write();
+        let (clock, resolution) = match handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::Clock(clock) => (*clock, false),
+            Handle::Resolution(clock) => (*clock, true),
+            Handle::Timers(clock, timers) => {
+                let now = now(*clock)?;
+                let max = buf.len() / mem::size_of::<u64>();
+                let mut cookies = Vec::new();
+                timers.retain(|_, timer| {
+                    if timer.deadline > now || cookies.len() >= max {
+                        return true;
+                    }
+                    cookies.push(timer.cookie);
+                    false
+                });
+                drop(handles);
+
+                let mut bytes_read = 0;
+                for (current_chunk, cookie) in
+                    buf.in_exact_chunks(mem::size_of::<u64>()).zip(cookies)
+                {
+                    current_chunk.copy_exactly(&cookie)?;
+
+                    bytes_read += mem::size_of::<u64>();
+                }
+                return Ok(bytes_read);
+            }
+        };
+        drop(handles);
 
         let mut bytes_read = 0;
 
         for current_chunk in buf.in_exact_chunks(mem::size_of::<TimeSpec>()) {
-            let arch_time = match clock {
-                CLOCK_REALTIME => time::realtime(),
-                CLOCK_MONOTONIC => time::monotonic(),
-                _ => return Err(Error::new(EINVAL)),
+            let arch_time = if resolution {
+                timeout::resolution()
+            } else {
+                now(clock)?
             };
             let time = TimeSpec {
                 tv_sec: (arch_time / time::NANOS_PER_SEC) as i64,
@@ -88,7 +237,29 @@ impl KernelScheme for TimeScheme {
     }
 
     fn kwrite(&self, id: usize, buf: UserSliceRo) -> Result<usize> {
-        let clock = *HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
+        // The read guard must be dropped before the write lock is taken for timers.
+        let (clock, is_timers) = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+            Handle::Clock(clock) => (*clock, false),
+            Handle::Resolution(_) => return Err(Error::new(EBADF)),
+            Handle::Timers(clock, _) => (*clock, true),
+        };
+
+        if is_timers {
+            // A write is applied as a whole, so one that would be cut short is rejected.
+            let mut text = [0_u8; 4096];
+            if buf.len() > text.len() {
+                return Err(Error::new(EINVAL));
+            }
+            let len = buf.copy_common_bytes_to_slice(&mut text)?;
+            let text = str::from_utf8(&text[..len]).map_err(|_| Error::new(EINVAL))?;
+
+            let mut handles = HANDLES.write();
+            let Some(Handle::Timers(_, timers)) = handles.get_mut(&id) else {
+                return Err(Error::new(EBADF));
+            };
+            set_timers(id, clock, timers, text)?;
+            return Ok(len);
+        }
 
         let mut bytes_written = 0;
 
@@ -103,9 +274,12 @@ impl KernelScheme for TimeScheme {
         Ok(bytes_written)
     }
     fn kfpath(&self, id: usize, buf: UserSliceWo) -> Result<usize> {
-        let clock = *HANDLES.read().get(&id).ok_or(Error::new(EBADF))?;
-
-        let scheme_path = format!("time:{}", clock).into_bytes();
+        let scheme_path = match HANDLES.read().get(&id).ok_or(Error::new(EBADF))? {
+            Handle::Clock(clock) => format!("time:{}", clock),
+            Handle::Resolution(clock) => format!("time:{}/resolution", clock),
+            Handle::Timers(clock, _) => format!("time:{}/timers", clock),
+        }
+        .into_bytes();
         buf.copy_common_bytes_from_slice(&scheme_path)
     }
 }
@@ -26,17 +26,19 @@ use crate::{
         memory::{
             AddrSpace, BorrowedFmapSource, Grant, GrantFileRef, MmapMode, PageSpan, DANGLING, AddrSpaceWrapper,
         },
-        BorrowedHtBuf, Context, Status,
+        BorrowedHtBuf, Context, ContextId, Status,
     },
     event,
-    memory::Frame,
-    paging::{Page, VirtualAddress, PAGE_SIZE},
+    memory::{Frame, RaiiFrame},
+    paging::{Page, RmmA, RmmArch, VirtualAddress, PAGE_SIZE},
     scheme::SchemeId,
     sync::WaitQueue,
     syscall::{
         data::{Map, Packet},
         error::*,
+        ext::{PositionedIo, F_GETRCVTIMEO, F_SETRCVTIMEO, SYS_PREAD, SYS_PWRITE},
         flag::{EventFlags, MapFlags, EVENT_READ, O_NONBLOCK, PROT_READ, PROT_WRITE},
+        fs::rcvtimeo_deadline,
         number::*,
         usercopy::{UserSlice, UserSliceRo, UserSliceWo},
     },
@@ -55,6 +57,8 @@ pub struct UserInner {
     todo: WaitQueue<Packet>,
     states: Mutex<HashMap<u64, State>>,
     unmounting: AtomicBool,
+    /// Read timeouts set with `F_SETRCVTIMEO`, in microseconds, by file.
+    read_timeouts: Mutex<HashMap<usize, usize>>,
 }
 
 enum State {
@@ -99,9 +103,15 @@ impl UserInner {
             todo: WaitQueue::new(),
             unmounting: AtomicBool::new(false),
             states: Mutex::new(HashMap::new()),
+            read_timeouts: Mutex::new(HashMap::new()),
         }
     }
 
+    /// The context serving this scheme, if it still exists.
+    pub fn owner(&self) -> Option<ContextId> {
+        self.context.upgrade().map(|context| context.read().id)
+    }
+
     pub fn unmount(&self) -> Result<()> {
         // First, block new requests and prepare to return EOF
         self.unmounting.store(true, Ordering::SeqCst);
@@ -116,6 +126,15 @@ impl UserInner {
         Ok(())
     }
 
+    /// Queue a request for the scheme handler, which is only notified when the queue becomes
+    /// nonempty. Level-triggered registrations are polled again by the event queue, while
+    /// edge-triggered ones are expected to read until they would block.
+    fn send_todo(&self, packet: Packet) {
+        if self.todo.send(packet) == 1 {
+            event::trigger(self.root_id, self.handle_id, EVENT_READ);
+        }
+    }
+
     fn next_id(&self) -> u64 {
         let mut guard = self.next_id.lock();
         let id = *guard;
@@ -124,8 +143,31 @@ impl UserInner {
     }
 
     pub fn call(&self, a: usize, b: usize, c: usize, d: usize) -> Result<usize> {
+        self.call_until(a, b, c, d, None)
+    }
+
+    /// Like [`Self::call`], but the request is canceled once the monotonic time `deadline` is
+    /// reached, failing with `EAGAIN` if the scheme then reports it as interrupted.
+    pub fn call_until(
+        &self,
+        a: usize,
+        b: usize,
+        c: usize,
+        d: usize,
+        deadline: Option<u128>,
+    ) -> Result<usize> {
         let ctx = context::current()?.read().caller_ctx();
-        match self.call_extended(ctx, None, [a, b, c, d])? {
+        let packet = Packet {
+            id: self.next_id(),
+            pid: ctx.pid,
+            uid: ctx.uid,
+            gid: ctx.gid,
+            a,
+            b,
+            c,
+            d,
+        };
+        match self.call_extended_inner(None, packet, deadline)? {
             Response::Regular(code) => Error::demux(code),
             Response::Fd(_) => {
                 if a & SYS_RET_FILE == SYS_RET_FILE {
@@ -155,6 +197,7 @@ impl UserInner {
                 c,
                 d,
             },
+            None,
         )
     }
 
@@ -162,10 +205,15 @@ impl UserInner {
         &self,
         fd: Option<Arc<RwLock<FileDescription>>>,
         packet: Packet,
+        mut deadline: Option<u128>,
     ) -> Result<Response> {
         if self.unmounting.load(Ordering::SeqCst) {
             return Err(Error::new(ENODEV));
         }
+        #[cfg(feature = "fault_injection")]
+        if crate::fault::inject(crate::fault::Point::Scheme) {
+            return Err(Error::new(EIO));
+        }
 
         let id = packet.id;
 
@@ -173,7 +221,14 @@ impl UserInner {
 
         {
             let mut states = self.states.lock();
-            current_context.write().block("UserScheme::call");
+            {
+                let mut context = current_context.write();
+                context.wake = deadline;
+                context.block("UserScheme::call");
+            }
+            if let Some(deadline) = deadline {
+                context::timeout::register_wake(context::context_id(), deadline);
+            }
             states.insert(
                 id,
                 State::Waiting {
@@ -184,12 +239,18 @@ impl UserInner {
             );
         }
 
-        self.todo.send(packet);
-        event::trigger(self.root_id, self.handle_id, EVENT_READ);
+        self.send_todo(packet);
 
+        let mut timed_out = false;
         loop {
             context::switch();
 
+            // The wake time is cleared when reached, in which case the request is canceled like
+            // on a signal.
+            if deadline.take().is_some() {
+                timed_out = context::current()?.write().wake.take().is_none();
+            }
+
             let eintr_if_sigkill = || if context::current()?.read().sig.deliverable() & (1 << (SIGKILL - 1)) != 0 {
                 // EINTR directly if SIGKILL was found without waiting for scheme. Data loss
                 // doesn't matter.
@@ -218,14 +279,13 @@ impl UserInner {
                         eintr_if_sigkill()?;
 
                         // TODO: Is this too dangerous when the states lock is held?
-                        self.todo.send(Packet {
+                        self.send_todo(Packet {
                             id: 0,
                             a: KSMSG_CANCEL,
                             b: packet.id as usize,
                             c: (packet.id >> 32) as usize,
                             ..packet
                         });
-                        event::trigger(self.root_id, self.handle_id, EVENT_READ);
                         context::current()?.write().block("UserInner::call");
                     }
 
@@ -237,6 +297,13 @@ impl UserInner {
 
                     State::Responded(response) => {
                         o.remove();
+                        if timed_out {
+                            if let Response::Regular(code) = response {
+                                if Error::demux(code) == Err(Error::new(EINTR)) {
+                                    return Err(Error::new(EAGAIN));
+                                }
+                            }
+                        }
                         return Ok(response);
                     }
                 },
@@ -254,6 +321,55 @@ impl UserInner {
         UserInner::capture_inner(&self.context, buf)
     }
     pub fn copy_and_capture_tail(&self, buf: &[u8]) -> Result<CaptureGuard<false, false>> {
+        self.capture_tail(buf.len(), PROT_READ, |tail| {
+            tail.copy_from_slice(buf);
+            Ok(())
+        })
+    }
+    /// Map a kernel buffer of `len` bytes, at most a page, to the scheme's userspace with `prot`,
+    /// after filling it with `fill`. The buffer is then found in the head of the guard.
+    fn capture_tail(
+        &self,
+        len: usize,
+        prot: MapFlags,
+        fill: impl FnOnce(&mut [u8]) -> Result<()>,
+    ) -> Result<CaptureGuard<false, false>> {
+        let mut tail = BorrowedHtBuf::tail()?;
+        if len > tail.buf().len() {
+            return Err(Error::new(EINVAL));
+        }
+        fill(&mut tail.buf_mut()[..len])?;
+
+        let mut guard = self.capture_page(tail.frame(), len, prot)?;
+        guard.head.src = Some(tail);
+        Ok(guard)
+    }
+    /// Like [`Self::copy_and_capture_tail`], but copying to a newly allocated page, for when the
+    /// tail buffer may also be needed to capture a user buffer. The page must outlive the guard.
+    fn copy_and_capture_page(
+        &self,
+        buf: &[u8],
+    ) -> Result<(RaiiFrame, CaptureGuard<false, false>)> {
+        if buf.len() > PAGE_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+        let frame = RaiiFrame::allocate()?;
+        let page = unsafe {
+            &mut *(RmmA::phys_to_virt(frame.get().start_address()).data() as *mut [u8; PAGE_SIZE])
+        };
+        page[..buf.len()].copy_from_slice(buf);
+        page[buf.len()..].fill(0);
+
+        let guard = self.capture_page(frame.get(), buf.len(), PROT_READ)?;
+        Ok((frame, guard))
+    }
+    /// Map `frame` to the scheme's userspace with `prot`, as a buffer of `len` bytes.
+    fn capture_page(
+        &self,
+        frame: Frame,
+        len: usize,
+        prot: MapFlags,
+    ) -> Result<CaptureGuard<false, false>> {
         let dst_addr_space = Arc::clone(
             self.context
                 .upgrade()
@@ -262,21 +378,14 @@ impl UserInner {
                 .addr_space()?,
         );
 
-        let mut tail = BorrowedHtBuf::tail()?;
-        let tail_frame = tail.frame();
-        if buf.len() > tail.buf().len() {
-            return Err(Error::new(EINVAL));
-        }
-        tail.buf_mut()[..buf.len()].copy_from_slice(buf);
-
         let is_pinned = true;
         let dst_page = dst_addr_space.acquire_write().mmap_anywhere(
             &dst_addr_space,
             ONE,
-            PROT_READ,
+            prot,
             |dst_page, flags, mapper, flusher| {
                 Ok(Grant::allocated_shared_one_page(
-                    tail_frame, dst_page, flags, mapper, flusher, is_pinned,
+                    frame, dst_page, flags, mapper, flusher, is_pinned,
                 )?)
             },
         )?;
@@ -284,10 +393,10 @@ impl UserInner {
         Ok(CaptureGuard {
             destroyed: false,
             base: dst_page.start_address().data(),
-            len: buf.len(),
+            len,
             space: Some(dst_addr_space),
             head: CopyInfo {
-                src: Some(tail),
+                src: None,
                 dst: None,
             },
             tail: CopyInfo {
@@ -583,7 +692,7 @@ impl UserInner {
         let mut states = self.states.lock();
         states.insert(packet_id, State::Fmap(Arc::downgrade(&context::current()?)));
 
-        self.todo.send(Packet {
+        self.send_todo(Packet {
             id: packet_id,
             pid: context::context_id().into(),
             a: KSMSG_MMAP,
@@ -593,7 +702,6 @@ impl UserInner {
             uid: offset as u32,
             gid: (offset >> 32) as u32,
         });
-        event::trigger(self.root_id, self.handle_id, EVENT_READ);
 
         Ok(())
     }
@@ -709,7 +817,10 @@ impl UserInner {
                     match context.status {
                         Status::HardBlocked {
                             reason: HardBlockedReason::AwaitingMmap { .. },
-                        } => context.status = Status::Runnable,
+                        } => {
+                            context.status = Status::Runnable;
+                            context::switch::wake_cpu(&mut context);
+                        }
                         _ => (),
                     }
                     context.fmap_ret = Some(Frame::containing_address(frame));
@@ -767,8 +878,13 @@ impl UserInner {
         Ok(())
     }
 
-    pub fn fevent(&self, _flags: EventFlags) -> Result<EventFlags> {
-        Ok(EventFlags::empty())
+    pub fn fevent(&self, flags: EventFlags) -> Result<EventFlags> {
+        let ready = !self.todo.inner.lock().is_empty() || self.unmounting.load(Ordering::SeqCst);
+        if flags.contains(EVENT_READ) && ready {
+            Ok(EVENT_READ)
+        } else {
+            Ok(EventFlags::empty())
+        }
     }
 
     pub fn fsync(&self) -> Result<()> {
@@ -845,6 +961,7 @@ impl UserInner {
                 #[cfg(target_pointer_width = "32")]
                 gid: 0,
             },
+            None,
         )?;
 
         // TODO: I've previously tested that this works, but because the scheme trait all of
@@ -1008,6 +1125,28 @@ impl UserScheme {
     pub fn new(inner: Weak<UserInner>) -> UserScheme {
         UserScheme { inner }
     }
+
+    /// Send a `SYS_PREAD` or `SYS_PWRITE` request for `buf`, already captured, at `offset`.
+    fn call_at(
+        inner: &UserInner,
+        a: usize,
+        file: usize,
+        buf: usize,
+        len: usize,
+        offset: u64,
+    ) -> Result<usize> {
+        let request = PositionedIo { buf, len, offset };
+        let request_bytes = unsafe {
+            core::slice::from_raw_parts(
+                &request as *const PositionedIo as *const u8,
+                size_of::<PositionedIo>(),
+            )
+        };
+        let (_frame, address) = inner.copy_and_capture_page(request_bytes)?;
+        let result = inner.call(a, file, address.base(), address.len());
+        address.release()?;
+        result
+    }
 }
 
 impl KernelScheme for UserScheme {
@@ -1063,7 +1202,20 @@ impl KernelScheme for UserScheme {
 
     fn fcntl(&self, file: usize, cmd: usize, arg: usize) -> Result<usize> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        inner.call(SYS_FCNTL, file, cmd, arg)
+        match cmd {
+            // Read timeouts are enforced by the kernel, by canceling the read.
+            F_GETRCVTIMEO => Ok(inner.read_timeouts.lock().get(&file).copied().unwrap_or(0)),
+            F_SETRCVTIMEO => {
+                let mut read_timeouts = inner.read_timeouts.lock();
+                if arg == 0 {
+                    read_timeouts.remove(&file);
+                } else {
+                    read_timeouts.insert(file, arg);
+                }
+                Ok(0)
+            }
+            _ => inner.call(SYS_FCNTL, file, cmd, arg),
+        }
     }
 
     fn fevent(&self, file: usize, flags: EventFlags) -> Result<EventFlags> {
@@ -1139,6 +1291,7 @@ impl KernelScheme for UserScheme {
 
     fn close(&self, file: usize) -> Result<()> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        inner.read_timeouts.lock().remove(&file);
         inner.call(SYS_CLOSE, file, 0, 0)?;
         Ok(())
     }
@@ -1164,8 +1317,65 @@ impl KernelScheme for UserScheme {
 
     fn kread(&self, file: usize, buf: UserSliceWo) -> Result<usize> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let timeout = inner.read_timeouts.lock().get(&file).copied().unwrap_or(0);
         let address = inner.capture_user(buf)?;
-        let result = inner.call(SYS_READ, file, address.base(), address.len());
+        let result = inner.call_until(
+            SYS_READ,
+            file,
+            address.base(),
+            address.len(),
+            rcvtimeo_deadline(timeout),
+        );
+        address.release()?;
+        result
+    }
+
+    fn kreadv(&self, file: usize, bufs: &[UserSliceWo]) -> Result<usize> {
+        let total = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        if bufs.iter().filter(|buf| !buf.is_empty()).count() < 2 || total > PAGE_SIZE {
+            return self.kread(file, super::first_nonempty(bufs));
+        }
+
+        // Small enough to be read into one page in a single request, and scattered afterwards.
+        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let timeout = inner.read_timeouts.lock().get(&file).copied().unwrap_or(0);
+        let address = inner.capture_tail(total, PROT_READ | PROT_WRITE, |tail| {
+            tail.fill(0);
+            Ok(())
+        })?;
+        let bytes_read = inner
+            .call_until(
+                SYS_READ,
+                file,
+                address.base(),
+                address.len(),
+                rcvtimeo_deadline(timeout),
+            )?
+            .min(total);
+
+        let src = address.head.src.as_ref().expect("tail buffer was captured");
+        let mut offset = 0;
+        for buf in bufs {
+            if offset == bytes_read {
+                break;
+            }
+            offset += buf.copy_common_bytes_from_slice(&src.buf()[offset..bytes_read])?;
+        }
+        address.release()?;
+        Ok(bytes_read)
+    }
+
+    fn kread_at(&self, file: usize, buf: UserSliceWo, offset: u64) -> Result<usize> {
+        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let address = inner.capture_user(buf)?;
+        let result = Self::call_at(
+            &inner,
+            SYS_PREAD,
+            file,
+            address.base(),
+            address.len(),
+            offset,
+        );
         address.release()?;
         result
     }
@@ -1177,6 +1387,39 @@ impl KernelScheme for UserScheme {
         address.release()?;
         result
     }
+    fn kwritev(&self, file: usize, bufs: &[UserSliceRo]) -> Result<usize> {
+        let total = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        if bufs.iter().filter(|buf| !buf.is_empty()).count() < 2 || total > PAGE_SIZE {
+            return super::write_each(self, file, bufs);
+        }
+
+        // Small enough to be gathered into one page, and written in a single request.
+        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let address = inner.capture_tail(total, PROT_READ, |tail| {
+            let mut offset = 0;
+            for buf in bufs {
+                offset += buf.copy_common_bytes_to_slice(&mut tail[offset..])?;
+            }
+            Ok(())
+        })?;
+        let result = inner.call(SYS_WRITE, file, address.base(), address.len());
+        address.release()?;
+        result
+    }
+    fn kwrite_at(&self, file: usize, buf: UserSliceRo, offset: u64) -> Result<usize> {
+        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let address = inner.capture_user(buf)?;
+        let result = Self::call_at(
+            &inner,
+            SYS_PWRITE,
+            file,
+            address.base(),
+            address.len(),
+            offset,
+        );
+        address.release()?;
+        result
+    }
     fn kfutimens(&self, file: usize, buf: UserSliceRo) -> Result<usize> {
         let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
         let address = inner.capture_user(buf)?;
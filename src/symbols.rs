@@ -0,0 +1,127 @@
+//! Kernel symbols, for symbolized backtraces.
+//!
+//! The bootloader loads the whole kernel ELF, symbol table included, so the symbols are taken from
+//! there instead of being generated at build time, which would need a second link. Once the heap
+//! is available, the function symbols are sorted into a compact table searched by address. Before
+//! that, lookups scan the ELF symbol table.
+
+use alloc::vec::Vec;
+use core::{fmt, slice, str, sync::atomic::Ordering};
+
+use goblin::elf::section_header::SHT_SYMTAB;
+use rustc_demangle::demangle;
+use spin::Once;
+
+use crate::{
+    elf::{sym, Elf},
+    start::KERNEL_SIZE,
+};
+
+/// A kernel function, with its name as an offset into the string table.
+struct Symbol {
+    start: usize,
+    size: u32,
+    name: u32,
+}
+
+struct Table {
+    /// Sorted by start address.
+    symbols: Vec<Symbol>,
+    names: &'static [u8],
+}
+
+static TABLE: Once<Table> = Once::new();
+
+/// The kernel ELF, as loaded by the bootloader.
+fn image() -> &'static [u8] {
+    // Mapped from its start on aarch64, and only in the linear mapping on x86.
+    #[cfg(target_arch = "aarch64")]
+    let base = crate::KERNEL_OFFSET;
+    #[cfg(not(target_arch = "aarch64"))]
+    let base = crate::start::KERNEL_BASE.load(Ordering::SeqCst) + crate::PHYS_OFFSET;
+
+    unsafe { slice::from_raw_parts(base as *const u8, KERNEL_SIZE.load(Ordering::SeqCst)) }
+}
+
+/// The string table linked from the symbol table of `elf`.
+fn names(elf: &Elf, image: &'static [u8]) -> Option<&'static [u8]> {
+    let symtab = elf
+        .sections()
+        .find(|section| section.sh_type == SHT_SYMTAB)?;
+    let strtab = elf.sections().nth(symtab.sh_link as usize)?;
+    image.get(strtab.sh_offset as usize..(strtab.sh_offset + strtab.sh_size) as usize)
+}
+
+fn name(names: &'static [u8], offset: usize) -> &'static str {
+    let name = names.get(offset..).unwrap_or(&[]);
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    str::from_utf8(&name[..len]).unwrap_or("?")
+}
+
+fn is_function(sym: &sym::Sym) -> bool {
+    sym::st_type(sym.st_info) == sym::STT_FUNC && sym.st_value != 0
+}
+
+/// Build the lookup table, once the heap is available.
+pub fn init() {
+    let image = image();
+    let Ok(elf) = Elf::from(image) else {
+        return;
+    };
+    let (Some(symbols), Some(names)) = (elf.symbols(), names(&elf, image)) else {
+        log::warn!("symbols: no symbol table, backtraces are not symbolized");
+        return;
+    };
+
+    let mut symbols = symbols
+        .filter(|sym| is_function(sym))
+        .map(|sym| Symbol {
+            start: sym.st_value as usize,
+            size: sym.st_size as u32,
+            name: sym.st_name,
+        })
+        .collect::<Vec<_>>();
+    symbols.sort_unstable_by_key(|symbol| symbol.start);
+
+    log::info!("symbols: {} functions", symbols.len());
+    TABLE.call_once(|| Table { symbols, names });
+}
+
+/// The name of the function containing `addr`, and the offset of `addr` in it.
+pub fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    if let Some(table) = TABLE.get() {
+        let index = table
+            .symbols
+            .partition_point(|symbol| symbol.start <= addr)
+            .checked_sub(1)?;
+        let symbol = &table.symbols[index];
+        let offset = addr - symbol.start;
+        return (offset < symbol.size as usize)
+            .then(|| (name(table.names, symbol.name as usize), offset));
+    }
+
+    let image = image();
+    let elf = Elf::from(image).ok()?;
+    let names = names(&elf, image)?;
+    let sym = elf.symbols()?.find(|sym| {
+        is_function(sym)
+            && addr >= sym.st_value as usize
+            && addr < (sym.st_value + sym.st_size) as usize
+    })?;
+    Some((
+        name(names, sym.st_name as usize),
+        addr - sym.st_value as usize,
+    ))
+}
+
+/// Displays a code address as the function it is in, as `name+0xoffset`.
+pub struct Symbolized(pub usize);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match lookup(self.0) {
+            Some((name, offset)) => write!(f, "{:#}+{:#x}", demangle(name), offset),
+            None => f.write_str("?"),
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Contention statistics for the kernel's major global locks, reported through `sys:locks`.
+//!
+//! The order in which these locks are taken is also checked. Each CPU counts the locks it holds,
+//! and acquiring one while holding others records that it was taken after them. Acquiring it
+//! before a lock it was once taken after is reported as a possible ABBA deadlock, before waiting
+//! for it, so that the report is seen even if the CPUs then deadlock.
+
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt::Write,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    ptr,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use crate::{cpu_set::MAX_CPU_COUNT, time};
+
+/// Number of checked locks, each with its index in [`ALL`] as its class.
+const CLASS_COUNT: usize = 4;
+
+const ZERO: AtomicU32 = AtomicU32::new(0);
+/// The locks of each class held by each CPU, as one 8-bit count per class.
+static HELD: [AtomicU32; MAX_CPU_COUNT as usize] = [ZERO; MAX_CPU_COUNT as usize];
+/// For each class, the classes that were acquired while holding it, as bits.
+static ORDER: [AtomicU32; CLASS_COUNT] = [ZERO; CLASS_COUNT];
+/// For each class, the classes that were acquired while holding it after having been held while
+/// acquiring it, as bits.
+static INVERSIONS: [AtomicU32; CLASS_COUNT] = [ZERO; CLASS_COUNT];
+
+pub struct LockStats {
+    class: usize,
+    name: &'static str,
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    /// Longest time spent waiting for the lock, in nanoseconds
+    max_wait: AtomicU64,
+    /// Longest time the lock was held, in nanoseconds. Only tracked for locks with an explicit
+    /// release point.
+    max_hold: AtomicU64,
+    /// When the lock was last acquired, for locks with an explicit release point
+    acquired_at: AtomicU64,
+}
+
+impl LockStats {
+    pub const fn new(class: usize, name: &'static str) -> Self {
+        Self {
+            class,
+            name,
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            max_wait: AtomicU64::new(0),
+            max_hold: AtomicU64::new(0),
+            acquired_at: AtomicU64::new(0),
+        }
+    }
+
+    /// Acquire a lock using `try_lock`, calling `relax` between attempts, and record whether and
+    /// for how long the lock was contended. The lock counts as held until the guard is dropped.
+    pub fn acquire<G>(
+        &'static self,
+        try_lock: impl FnMut() -> Option<G>,
+        relax: impl FnMut(),
+    ) -> Tracked<G> {
+        self.check_order();
+        let guard = self.wait(try_lock, relax);
+        Tracked {
+            guard,
+            stats: self,
+            cpu: self.hold(),
+        }
+    }
+
+    /// Acquire a lock that is not released through a guard, as with [`Self::acquire`], and mark
+    /// the start of its hold period.
+    pub fn acquire_unguarded(&self, mut try_lock: impl FnMut() -> bool, relax: impl FnMut()) {
+        self.check_order();
+        self.wait(|| try_lock().then_some(()), relax);
+        let _ = self.hold();
+        self.acquired_at
+            .store(time::monotonic() as u64, Ordering::Relaxed);
+    }
+    /// Mark the end of a hold period started with [`Self::acquire_unguarded`].
+    pub fn mark_released(&self) {
+        let held =
+            (time::monotonic() as u64).saturating_sub(self.acquired_at.load(Ordering::Relaxed));
+        self.max_hold.fetch_max(held, Ordering::Relaxed);
+        self.release(crate::cpu_id().get() as usize);
+    }
+
+    fn wait<G>(&self, mut try_lock: impl FnMut() -> Option<G>, mut relax: impl FnMut()) -> G {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(guard) = try_lock() {
+            return guard;
+        }
+
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        let start = time::monotonic();
+        let guard = loop {
+            relax();
+            if let Some(guard) = try_lock() {
+                break guard;
+            }
+        };
+        let waited = (time::monotonic() - start) as u64;
+        self.max_wait.fetch_max(waited, Ordering::Relaxed);
+
+        guard
+    }
+
+    /// Record that this lock is acquired after those held by the current CPU, and report the
+    /// first acquisition in an order opposite to one already recorded.
+    fn check_order(&self) {
+        let held = HELD[crate::cpu_id().get() as usize].load(Ordering::Relaxed);
+        let bit = 1 << self.class;
+        for (class, other) in ALL.iter().enumerate() {
+            if class == self.class || (held >> (8 * class)) & 0xFF == 0 {
+                continue;
+            }
+            ORDER[class].fetch_or(bit, Ordering::Relaxed);
+            if ORDER[self.class].load(Ordering::Relaxed) & (1 << class) != 0
+                && INVERSIONS[class].fetch_or(bit, Ordering::Relaxed) & bit == 0
+            {
+                log::warn!(
+                    "lock_debug: {} acquired while holding {}, which was acquired while holding it",
+                    self.name,
+                    other.name
+                );
+            }
+        }
+    }
+    /// Count the lock as held by the current CPU, which is returned.
+    fn hold(&self) -> usize {
+        let cpu = crate::cpu_id().get() as usize;
+        HELD[cpu].fetch_add(1 << (8 * self.class), Ordering::Relaxed);
+        cpu
+    }
+    fn release(&self, cpu: usize) {
+        HELD[cpu].fetch_sub(1 << (8 * self.class), Ordering::Relaxed);
+    }
+}
+
+/// The guard of a lock acquired through [`LockStats::acquire`], which no longer counts as held
+/// once dropped.
+pub struct Tracked<G> {
+    guard: G,
+    stats: &'static LockStats,
+    /// The CPU that acquired the lock, which the context may have since been moved away from
+    cpu: usize,
+}
+
+impl<G> Tracked<G> {
+    /// Convert the guard, such as to upgrade it, keeping the lock held.
+    pub fn map<H>(this: Self, f: impl FnOnce(G) -> H) -> Tracked<H> {
+        let this = ManuallyDrop::new(this);
+        // SAFETY: `this` is never dropped, so the guard is only moved out once.
+        let guard = unsafe { ptr::read(&this.guard) };
+        Tracked {
+            guard: f(guard),
+            stats: this.stats,
+            cpu: this.cpu,
+        }
+    }
+}
+impl<G: Deref> Deref for Tracked<G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+impl<G: DerefMut> DerefMut for Tracked<G> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+impl<G> Drop for Tracked<G> {
+    fn drop(&mut self) {
+        self.stats.release(self.cpu);
+    }
+}
+
+pub static CONTEXT_LIST: LockStats = LockStats::new(0, "context_list");
+pub static SCHEME_LIST: LockStats = LockStats::new(1, "scheme_list");
+pub static ADDR_SPACE: LockStats = LockStats::new(2, "addr_space");
+pub static CONTEXT_SWITCH: LockStats = LockStats::new(3, "context_switch");
+
+static ALL: [&LockStats; CLASS_COUNT] = [&CONTEXT_LIST, &SCHEME_LIST, &ADDR_SPACE, &CONTEXT_SWITCH];
+
+pub fn resource() -> crate::syscall::error::Result<Vec<u8>> {
+    let mut string = String::new();
+    let _ = writeln!(
+        string,
+        "{:<16}{:>14}{:>14}{:>14}{:>14}",
+        "LOCK", "ACQUIRED", "CONTENDED", "MAX_WAIT_NS", "MAX_HOLD_NS"
+    );
+    for stats in ALL {
+        let _ = writeln!(
+            string,
+            "{:<16}{:>14}{:>14}{:>14}{:>14}",
+            stats.name,
+            stats.acquisitions.load(Ordering::Relaxed),
+            stats.contended.load(Ordering::Relaxed),
+            stats.max_wait.load(Ordering::Relaxed),
+            stats.max_hold.load(Ordering::Relaxed),
+        );
+    }
+
+    // Each order seen, as `<held> -> <acquired>`, marked if it inverts an earlier one
+    let _ = writeln!(string, "\nORDER");
+    for (class, stats) in ALL.iter().enumerate() {
+        let order = ORDER[class].load(Ordering::Relaxed);
+        let inversions = INVERSIONS[class].load(Ordering::Relaxed);
+        for (next_class, next) in ALL.iter().enumerate() {
+            if order & (1 << next_class) == 0 {
+                continue;
+            }
+            let inverted = inversions & (1 << next_class) != 0;
+            let _ = writeln!(
+                string,
+                "{} -> {}{}",
+                stats.name,
+                next.name,
+                if inverted { " INVERTED" } else { "" }
+            );
+        }
+    }
+    Ok(string.into_bytes())
+}
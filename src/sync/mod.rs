@@ -1,5 +1,26 @@
-pub use self::{wait_condition::WaitCondition, wait_map::WaitMap, wait_queue::WaitQueue};
+pub use self::{
+    wait_condition::WaitCondition, wait_map::WaitMap, wait_queue::WaitQueue, wait_table::WaitTable,
+};
 
+#[cfg(feature = "lock_debug")]
+pub use self::lock_debug::Tracked;
+
+/// The guard of a lock checked by `lock_debug`, which is the plain guard without that feature.
+#[cfg(not(feature = "lock_debug"))]
+pub type Tracked<G> = G;
+
+/// Convert the guard of a lock checked by `lock_debug`, such as to upgrade it.
+pub fn map_tracked<G, H>(guard: Tracked<G>, f: impl FnOnce(G) -> H) -> Tracked<H> {
+    #[cfg(feature = "lock_debug")]
+    return Tracked::map(guard, f);
+
+    #[cfg(not(feature = "lock_debug"))]
+    f(guard)
+}
+
+#[cfg(feature = "lock_debug")]
+pub mod lock_debug;
 pub mod wait_condition;
 pub mod wait_map;
 pub mod wait_queue;
+pub mod wait_table;
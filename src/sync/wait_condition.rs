@@ -2,7 +2,10 @@ use alloc::{sync::Arc, vec::Vec};
 use spin::{Mutex, MutexGuard};
 use spinning_top::RwSpinlock;
 
-use crate::context::{self, Context};
+use crate::{
+    context::{self, timeout, Context},
+    syscall::error::{Error, Result, ETIMEDOUT},
+};
 
 #[derive(Debug)]
 pub struct WaitCondition {
@@ -16,10 +19,12 @@ impl WaitCondition {
         }
     }
 
-    // Notify all waiters
+    // Notify all waiters, those of the highest scheduling rank first, so that they are the first
+    // given an idle CPU or to preempt one
     pub fn notify(&self) -> usize {
         let mut contexts = self.contexts.lock();
         let len = contexts.len();
+        contexts.sort_by_key(|context_lock| context_lock.read().sched.rank());
         while let Some(context_lock) = contexts.pop() {
             context_lock.write().unblock();
         }
@@ -38,6 +43,16 @@ impl WaitCondition {
 
     // Wait until notified. Unlocks guard when blocking is ready. Returns false if resumed by a signal or the notify_signal function
     pub fn wait<T>(&self, guard: MutexGuard<T>, reason: &'static str) -> bool {
+        self.wait_until(guard, reason, None).unwrap_or(false)
+    }
+
+    // Like wait, but also resumed at the monotonic time deadline, if any, returning ETIMEDOUT
+    pub fn wait_until<T>(
+        &self,
+        guard: MutexGuard<T>,
+        reason: &'static str,
+        deadline: Option<u128>,
+    ) -> Result<bool> {
         let id;
         {
             let context_lock = {
@@ -49,8 +64,12 @@ impl WaitCondition {
             {
                 let mut context = context_lock.write();
                 id = context.id;
+                context.wake = deadline;
                 context.block(reason);
             }
+            if let Some(deadline) = deadline {
+                timeout::register_wake(id, deadline);
+            }
 
             self.contexts.lock().push(context_lock);
 
@@ -59,6 +78,8 @@ impl WaitCondition {
 
         context::switch();
 
+        // The wake time is cleared when reached.
+        let timed_out = deadline.is_some() && context::current()?.write().wake.take().is_none();
         let mut waited = true;
 
         {
@@ -81,7 +102,10 @@ impl WaitCondition {
             }
         }
 
-        waited
+        if !waited && timed_out {
+            return Err(Error::new(ETIMEDOUT));
+        }
+        Ok(waited)
     }
 }
 
@@ -30,19 +30,59 @@ impl<T> WaitQueue<T> {
         block: bool,
         reason: &'static str,
     ) -> Result<usize> {
+        self.receive_inner(buf, block, reason, None, |_| ())
+    }
+
+    /// Like [`Self::receive_timeout`], but passes each received value to `received`, with the
+    /// queue locked.
+    pub fn receive_into_user_with(
+        &self,
+        buf: UserSliceWo,
+        block: bool,
+        reason: &'static str,
+        deadline: Option<u128>,
+        received: impl FnMut(&T),
+    ) -> Result<usize> {
+        self.receive_inner(buf, block, reason, deadline, received)
+    }
+
+    /// Like [`Self::receive_into_user`], but blocking at most until the monotonic time `deadline`,
+    /// if any, and failing with `ETIMEDOUT` if nothing was received by then.
+    pub fn receive_timeout(
+        &self,
+        buf: UserSliceWo,
+        block: bool,
+        reason: &'static str,
+        deadline: Option<u128>,
+    ) -> Result<usize> {
+        self.receive_inner(buf, block, reason, deadline, |_| ())
+    }
+
+    fn receive_inner(
+        &self,
+        buf: UserSliceWo,
+        block: bool,
+        reason: &'static str,
+        deadline: Option<u128>,
+        mut received: impl FnMut(&T),
+    ) -> Result<usize> {
+        if !buf.is_empty() && buf.len() < core::mem::size_of::<T>() {
+            return Err(Error::new(EINVAL));
+        }
+        // Only whole values are received, as many as fit.
+        let whole_len = buf.len() - buf.len() % core::mem::size_of::<T>();
+        let buf = buf.limit(whole_len).expect("whole_len is at most len");
         loop {
             let mut inner = self.inner.lock();
 
             if inner.is_empty() {
                 if block {
-                    if !self.condition.wait(inner, reason) {
+                    if !self.condition.wait_until(inner, reason, deadline)? {
                         return Err(Error::new(EINTR));
                     }
                     continue;
                 } else if buf.is_empty() {
                     return Ok(0);
-                } else if buf.len() < core::mem::size_of::<T>() {
-                    return Err(Error::new(EINVAL));
                 } else {
                     // TODO: EWOULDBLOCK?
                     return Err(Error::new(EAGAIN));
@@ -69,7 +109,9 @@ impl<T> WaitQueue<T> {
                 bytes_copied += buf_for_s2.copy_common_bytes_from_slice(s2_bytes)?;
             }
 
-            let _ = inner.drain(..bytes_copied / core::mem::size_of::<T>());
+            for value in inner.drain(..bytes_copied / core::mem::size_of::<T>()) {
+                received(&value);
+            }
 
             return Ok(bytes_copied);
         }
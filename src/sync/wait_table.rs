@@ -0,0 +1,149 @@
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
+use spin::Mutex;
+use spinning_top::RwSpinlock;
+
+use crate::{
+    context::{self, timeout, Context, ContextId},
+    syscall::error::{Error, Result, EINTR, ETIMEDOUT},
+};
+
+struct Waiter {
+    id: ContextId,
+    context_lock: Arc<RwSpinlock<Context>>,
+}
+
+struct Inner<K> {
+    /// Waiters on each key, longest waiting first.
+    queues: BTreeMap<K, VecDeque<Waiter>>,
+    /// The key each waiter is queued on, which changes when requeued.
+    keys: BTreeMap<ContextId, K>,
+}
+
+/// Contexts waiting on keys, woken a given number at a time, and which can be moved to another
+/// key without waking them.
+pub struct WaitTable<K> {
+    inner: Mutex<Inner<K>>,
+}
+
+impl<K: Copy + Ord> WaitTable<K> {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                queues: BTreeMap::new(),
+                keys: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Wait on `key` until woken, if `check` succeeds with the table locked, so that no wakeup
+    /// can be missed between the two. `guard` is dropped once the context is queued. Fails with
+    /// `ETIMEDOUT` once the monotonic time `deadline` is reached, if any, and with `EINTR` if
+    /// resumed by a signal.
+    pub fn wait_until<G>(
+        &self,
+        key: K,
+        check: impl FnOnce() -> Result<()>,
+        guard: G,
+        reason: &'static str,
+        deadline: Option<u128>,
+    ) -> Result<()> {
+        let context_lock = context::current()?;
+        let id = {
+            let mut inner = self.inner.lock();
+            check()?;
+
+            let id = {
+                let mut context = context_lock.write();
+                context.wake = deadline;
+                context.block(reason);
+                context.id
+            };
+            if let Some(deadline) = deadline {
+                timeout::register_wake(id, deadline);
+            }
+            inner.queues.entry(key).or_default().push_back(Waiter {
+                id,
+                context_lock: Arc::clone(&context_lock),
+            });
+            inner.keys.insert(id, key);
+            id
+        };
+        drop(guard);
+
+        context::switch();
+
+        // The wake time is cleared when reached.
+        let timed_out = deadline.is_some() && context_lock.write().wake.take().is_none();
+
+        let mut inner = self.inner.lock();
+        let Some(key) = inner.keys.remove(&id) else {
+            return Ok(());
+        };
+        if let Some(queue) = inner.queues.get_mut(&key) {
+            queue.retain(|waiter| waiter.id != id);
+            if queue.is_empty() {
+                inner.queues.remove(&key);
+            }
+        }
+        Err(Error::new(if timed_out { ETIMEDOUT } else { EINTR }))
+    }
+
+    /// Wake up to `count` of the contexts waiting on `key`, longest waiting first. Returns the
+    /// number woken.
+    pub fn wake(&self, key: K, count: usize) -> usize {
+        let mut inner = self.inner.lock();
+        Self::wake_inner(&mut inner, key, count)
+    }
+
+    /// Wake up to `wake_count` of the contexts waiting on `from`, and move up to `requeue_count`
+    /// of the others to wait on `to`. Returns the number woken and the number moved.
+    pub fn requeue(
+        &self,
+        from: K,
+        to: K,
+        wake_count: usize,
+        requeue_count: usize,
+    ) -> (usize, usize) {
+        let mut inner = self.inner.lock();
+        let woken = Self::wake_inner(&mut inner, from, wake_count);
+        if from == to {
+            return (woken, 0);
+        }
+
+        let Some(mut queue) = inner.queues.remove(&from) else {
+            return (woken, 0);
+        };
+        let moved = queue.len().min(requeue_count);
+        let rest = queue.split_off(moved);
+        if !rest.is_empty() {
+            inner.queues.insert(from, rest);
+        }
+        for waiter in &queue {
+            inner.keys.insert(waiter.id, to);
+        }
+        inner.queues.entry(to).or_default().extend(queue);
+        (woken, moved)
+    }
+
+    fn wake_inner(inner: &mut Inner<K>, key: K, count: usize) -> usize {
+        let Some(queue) = inner.queues.get_mut(&key) else {
+            return 0;
+        };
+        let mut woken = 0;
+        while woken < count {
+            let Some(waiter) = queue.pop_front() else {
+                break;
+            };
+            waiter.context_lock.write().unblock();
+            inner.keys.remove(&waiter.id);
+            woken += 1;
+        }
+        if queue.is_empty() {
+            inner.queues.remove(&key);
+        }
+        woken
+    }
+}
@@ -7,6 +7,7 @@ use super::{
     flag::*,
     number::*,
     usercopy::UserSlice,
+    SYS_PIPE2, SYS_PREAD, SYS_PWRITE, SYS_READV, SYS_SPLICE, SYS_TEE, SYS_WRITEV,
 };
 
 use crate::syscall::error::Result;
@@ -58,6 +59,13 @@ pub fn format_call(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -
             debug_path(b, c).as_ref().map(|p| ByteStr(p.as_bytes())),
         ),
         SYS_CLOSE => format!("close({})", b),
+        SYS_PIPE2 => format!("pipe2({:#X}, {:#X})", b, c),
+        SYS_PREAD => format!("pread({}, {:#X}, {}, {})", b, c, d, e),
+        SYS_PWRITE => format!("pwrite({}, {:#X}, {}, {})", b, c, d, e),
+        SYS_READV => format!("readv({}, {:#X}, {})", b, c, d),
+        SYS_WRITEV => format!("writev({}, {:#X}, {})", b, c, d),
+        SYS_SPLICE => format!("splice({}, {}, {}, {:#X})", b, c, d, e),
+        SYS_TEE => format!("tee({}, {}, {}, {:#X})", b, c, d, e),
         SYS_DUP => format!(
             "dup({}, {:?})",
             b,
@@ -0,0 +1,132 @@
+//! Syscall numbers, `fcntl` commands, flags and packets the kernel implements ahead of the
+//! syscall crate.
+//!
+//! They belong in the syscall crate, next to the others in its `number`, `flag` and `data`
+//! modules, and are kept together here so that they can be moved there as a whole, without
+//! userspace having to copy them from all over the kernel. Until then, none of them may be
+//! renumbered.
+
+use crate::syscall::{flag::EventFlags, number::SYS_CLASS_FILE};
+
+/// `pread(fd, buf, len, offset)` and `pwrite(fd, buf, len, offset)`, numbered as on Linux within
+/// the file class. On 32-bit targets, the offset is split into its low and high words.
+pub const SYS_PREAD: usize = SYS_CLASS_FILE | 17;
+pub const SYS_PWRITE: usize = SYS_CLASS_FILE | 18;
+/// `readv(fd, iov, iovcnt)` and `writev(fd, iov, iovcnt)`, numbered as on 32-bit x86 Linux within
+/// the file class, since `lseek` has the x86_64 number of `readv`.
+pub const SYS_READV: usize = SYS_CLASS_FILE | 145;
+pub const SYS_WRITEV: usize = SYS_CLASS_FILE | 146;
+/// `splice(fd_in, fd_out, len, flags)`, numbered as on Linux within the file class.
+pub const SYS_SPLICE: usize = SYS_CLASS_FILE | 275;
+/// `tee(fd_in, fd_out, len, flags)`, numbered as on Linux within the file class.
+pub const SYS_TEE: usize = SYS_CLASS_FILE | 276;
+/// `pipe2(fds, flags)`, numbered as it was before pipes were opened through the pipe scheme.
+pub const SYS_PIPE2: usize = 331;
+/// `sigqueue(pid, sig, value)`, numbered after the Linux `rt_sigqueueinfo`.
+pub const SYS_SIGQUEUE: usize = 129;
+
+/// Flag of `splice`, making it fail with `EAGAIN` instead of blocking.
+pub const SPLICE_F_NONBLOCK: usize = 2;
+
+/// The request of `SYS_PREAD` and `SYS_PWRITE` packets, which is at `c` in the scheme's memory,
+/// with its size in `d` and the file in `b`. `buf` is mapped to the scheme like the buffers of
+/// `SYS_READ` and `SYS_WRITE`.
+#[repr(C)]
+pub struct PositionedIo {
+    pub buf: usize,
+    pub len: usize,
+    pub offset: u64,
+}
+
+/// `fcntl` command returning the read timeout of a file in microseconds, or 0 if reads block
+/// indefinitely.
+pub const F_GETRCVTIMEO: usize = 0x5254_0001;
+/// `fcntl` command setting the read timeout of a file in microseconds, after which blocking reads
+/// fail with `EAGAIN`. A timeout of 0 disables it.
+pub const F_SETRCVTIMEO: usize = 0x5254_0002;
+
+/// `fcntl` command returning the event limit of a queue.
+pub const F_GETEVLIMIT: usize = 0x4556_0001;
+/// `fcntl` command setting the event limit of a queue.
+pub const F_SETEVLIMIT: usize = 0x4556_0002;
+/// `fcntl` command returning the read timeout of a queue, in milliseconds.
+pub const F_GETEVTIMEOUT: usize = 0x4556_0003;
+/// `fcntl` command setting the read timeout of a queue, in milliseconds.
+///
+/// A read returns no events once the timeout elapses with the queue empty, like `epoll_wait`. A
+/// timeout of 0 polls the queue without blocking, and [`NO_TIMEOUT`], the default, blocks until an
+/// event is queued.
+pub const F_SETEVTIMEOUT: usize = 0x4556_0004;
+/// Read timeout of a queue blocking until an event is queued.
+pub const NO_TIMEOUT: usize = usize::MAX;
+/// Id of the event queued in place of dropped events, whose data is the number of events that
+/// were dropped. Since the state of any file may then be stale, the reader should resynchronize.
+pub const OVERFLOW_EVENT_ID: usize = usize::MAX;
+/// Registration flag selecting edge-triggered events, like `EPOLLET`.
+///
+/// Registrations are level-triggered by default: each time one of their events is read from the
+/// queue, the file is polled again, and the event requeued for as long as the file is ready. An
+/// edge-triggered registration is only notified when the file becomes ready, and the reader is
+/// expected to consume until it would block. Notifications for flags already in an unread event of
+/// the registration are dropped, so that a file becoming ready again and again before the reader
+/// gets to it is reported once.
+pub const EVENT_EDGE: usize = 1 << 29;
+/// Registration flag disarming the registration once it has queued an event, like
+/// `EPOLLONESHOT`. It is armed again by registering the file again.
+pub const EVENT_ONESHOT: usize = 1 << 28;
+/// Event flag reported along with readiness once the peer of a file has closed, such as the other
+/// end of a pipe. Like `POLLHUP`, it is delivered without being registered for.
+pub const EVENT_HANGUP: EventFlags = EventFlags::from_bits_retain(1 << 30);
+
+/// `fcntl` command setting the capacity of a pipe, which is rounded up to a multiple of the page
+/// size and returned. As on Linux, only root may exceed the configured maximum size, or the total
+/// capacity allowed per user. Fails with `EBUSY` if more bytes are buffered than would fit.
+pub const F_SETPIPE_SZ: usize = 1031;
+/// `fcntl` command returning the capacity of a pipe.
+pub const F_GETPIPE_SZ: usize = 1032;
+/// `fcntl` command returning the number of bytes buffered in a pipe, which is also reported as
+/// its size by `fstat`. Numbered after the Linux `ioctl`.
+pub const FIONREAD: usize = 0x541B;
+/// `fcntl` command shutting down the reading (`SHUT_RD`), writing (`SHUT_WR`) or both
+/// (`SHUT_RDWR`) directions of a duplex pipe end, like `shutdown` on a socket.
+pub const F_SHUTDOWN: usize = 0x5348_0001;
+pub const SHUT_RD: usize = 0;
+pub const SHUT_WR: usize = 1;
+pub const SHUT_RDWR: usize = 2;
+
+/// Get the line discipline flags of `debug:`.
+pub const F_GETLFLAG: usize = 0x5444_0001;
+/// Set the line discipline flags of `debug:`, as a combination of [`LFLAG_ICANON`] and
+/// [`LFLAG_ECHO`].
+pub const F_SETLFLAG: usize = 0x5444_0002;
+/// Deliver input a line at a time, after processing erase and kill characters.
+pub const LFLAG_ICANON: usize = 1;
+/// Echo input back to the consoles.
+pub const LFLAG_ECHO: usize = 2;
+
+/// Get the filter of a `debug:log` handle.
+pub const F_GETLOGFILTER: usize = 0x5444_0003;
+/// Set the filter of a `debug:log` handle, as the most verbose level to read in the low byte, in
+/// the order of `LevelFilter`, and a mask of subsystem bits shifted left by
+/// [`LOG_FILTER_SUBSYSTEM_SHIFT`].
+pub const F_SETLOGFILTER: usize = 0x5444_0004;
+pub const LOG_FILTER_SUBSYSTEM_SHIFT: usize = 8;
+
+/// The `fcntl` commands handled by schemes alone, whose result is that of the scheme. Schemes
+/// fail with `EINVAL` on those they do not support.
+pub const SCHEME_FCNTLS: [usize; 14] = [
+    F_GETRCVTIMEO,
+    F_SETRCVTIMEO,
+    F_GETEVLIMIT,
+    F_SETEVLIMIT,
+    F_GETEVTIMEOUT,
+    F_SETEVTIMEOUT,
+    F_GETPIPE_SZ,
+    F_SETPIPE_SZ,
+    FIONREAD,
+    F_SHUTDOWN,
+    F_GETLFLAG,
+    F_SETLFLAG,
+    F_GETLOGFILTER,
+    F_SETLOGFILTER,
+];
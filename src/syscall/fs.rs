@@ -10,8 +10,8 @@ use crate::{
         memory::{AddrSpace, PageSpan},
     },
     paging::{Page, VirtualAddress, PAGE_SIZE},
-    scheme::{self, CallerCtx, FileHandle, KernelScheme, OpenResult, SchemeId},
-    syscall::{data::Stat, error::*, flag::*},
+    scheme::{self, CallerCtx, FileHandle, GlobalSchemes, KernelScheme, OpenResult, SchemeId},
+    syscall::{data::Stat, error::*, ext::SCHEME_FCNTLS, flag::*},
 };
 
 use super::usercopy::{UserSlice, UserSliceRo, UserSliceWo};
@@ -54,7 +54,7 @@ pub fn copy_path_to_buf(raw_path: UserSliceRo, max_len: usize) -> Result<alloc::
     //core::str::from_utf8(&path_buf[..path_len]).map_err(|_| Error::new(EINVAL))
 }
 // TODO: Define elsewhere
-const PATH_MAX: usize = PAGE_SIZE;
+pub const PATH_MAX: usize = PAGE_SIZE;
 
 /// Open syscall
 pub fn open(raw_path: UserSliceRo, flags: usize) -> Result<FileHandle> {
@@ -284,6 +284,12 @@ pub fn sendfd(socket: FileHandle, fd: FileHandle, flags_raw: usize, arg: u64) ->
     scheme.ksendfd(number, desc_to_send, flags_to_scheme, arg)
 }
 
+/// Convert a read timeout set with [`F_SETRCVTIMEO`](super::ext::F_SETRCVTIMEO) to a monotonic
+/// deadline.
+pub fn rcvtimeo_deadline(timeout_us: usize) -> Option<u128> {
+    (timeout_us != 0).then(|| crate::time::monotonic() + timeout_us as u128 * 1000)
+}
+
 /// File descriptor controls
 pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
     let file = {
@@ -296,13 +302,15 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
     let description = file.description.read();
 
     // Communicate fcntl with scheme
-    if cmd != F_DUPFD && cmd != F_GETFD && cmd != F_SETFD {
+    let scheme_result = if cmd != F_DUPFD && cmd != F_GETFD && cmd != F_SETFD {
         let scheme = scheme::schemes()
             .get(description.scheme)
             .ok_or(Error::new(EBADF))?
             .clone();
 
-        scheme.fcntl(description.number, cmd, arg)?;
+        scheme.fcntl(description.number, cmd, arg)?
+    } else {
+        0
     };
 
     // Perform kernel operation if scheme agrees
@@ -346,6 +354,7 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
                     file.description.write().flags = new_flags;
                     Ok(0)
                 }
+                _ if SCHEME_FCNTLS.contains(&cmd) => Ok(scheme_result),
                 _ => Err(Error::new(EINVAL)),
             },
             None => Err(Error::new(EBADF)),
@@ -353,6 +362,89 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize) -> Result<usize> {
     }
 }
 
+/// Create a pipe, writing the file descriptors of its read and write ends to `fds`. Unlike opening
+/// `pipe:` and then duplicating the write end, `O_NONBLOCK` and `O_CLOEXEC` apply to both ends as
+/// soon as they exist, leaving no window for another thread to fork or exec in between.
+pub fn pipe2(fds: UserSliceWo, flags: usize) -> Result<()> {
+    if flags & !(O_NONBLOCK | O_CLOEXEC) != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    let (read_fd_buf, write_fd_buf) = fds
+        .split_at(core::mem::size_of::<usize>())
+        .ok_or(Error::new(EINVAL))?;
+
+    let (uid, scheme_ns) = match context::current()?.read() {
+        ref context => (context.euid, context.ens),
+    };
+    let (read_number, write_number) = scheme::pipe::pipe(flags & O_NONBLOCK, uid)?;
+
+    let scheme_id = GlobalSchemes::Pipe.scheme_id();
+    let end = |number, access| FileDescriptor {
+        description: Arc::new(RwLock::new(FileDescription {
+            namespace: scheme_ns,
+            scheme: scheme_id,
+            number,
+            flags: access | (flags & O_NONBLOCK),
+        })),
+        cloexec: flags & O_CLOEXEC == O_CLOEXEC,
+    };
+    let read_end = end(read_number, O_RDONLY);
+    let write_end = end(write_number, O_WRONLY);
+
+    let Some((read_fd, write_fd)) = context::current()?
+        .read()
+        .add_file_pair(read_end.clone(), write_end.clone())
+    else {
+        let _ = read_end.close();
+        let _ = write_end.close();
+        return Err(Error::new(EMFILE));
+    };
+    drop((read_end, write_end));
+
+    let copied = read_fd_buf
+        .write_usize(read_fd.get())
+        .and_then(|()| write_fd_buf.write_usize(write_fd.get()));
+    if copied.is_err() {
+        let _ = close(read_fd);
+        let _ = close(write_fd);
+    }
+    copied
+}
+
+/// Move up to `len` bytes from the pipe read by `fd_in` to the pipe written by `fd_out`, or with
+/// `tee`, duplicate them without consuming them. Only pipes are supported, whose buffers are
+/// shared instead of copied.
+pub fn splice(
+    fd_in: FileHandle,
+    fd_out: FileHandle,
+    len: usize,
+    flags: usize,
+    tee: bool,
+) -> Result<usize> {
+    let (file_in, file_out) = {
+        let context_lock = context::current()?;
+        let context = context_lock.read();
+        (
+            context.get_file(fd_in).ok_or(Error::new(EBADF))?,
+            context.get_file(fd_out).ok_or(Error::new(EBADF))?,
+        )
+    };
+    let (scheme_in, number_in) = {
+        let description = file_in.description.read();
+        (description.scheme, description.number)
+    };
+    let (scheme_out, number_out) = {
+        let description = file_out.description.read();
+        (description.scheme, description.number)
+    };
+
+    let pipe_scheme = GlobalSchemes::Pipe.scheme_id();
+    if scheme_in != pipe_scheme || scheme_out != pipe_scheme {
+        return Err(Error::new(EINVAL));
+    }
+    scheme::pipe::splice(number_in, number_out, len, flags, tee)
+}
+
 pub fn frename(fd: FileHandle, raw_path: UserSliceRo) -> Result<()> {
     let (file, caller_ctx, scheme_ns) = match context::current()?.read() {
         ref context => (
@@ -413,6 +505,62 @@ pub fn fstat(fd: FileHandle, user_buf: UserSliceWo) -> Result<()> {
     })
 }
 
+/// Maximum number of buffers passed to `readv` or `writev`.
+pub const IOV_MAX: usize = 1024;
+
+/// The buffers described by the array of `count` `struct iovec`, each a base and a length, at
+/// `iov`.
+fn iovecs<const READ: bool, const WRITE: bool>(
+    iov: usize,
+    count: usize,
+) -> Result<Vec<UserSlice<READ, WRITE>>> {
+    if count > IOV_MAX {
+        return Err(Error::new(EINVAL));
+    }
+    let mut words = UserSlice::ro(iov, count * 2 * core::mem::size_of::<usize>())?.usizes();
+
+    let mut bufs = Vec::with_capacity(count);
+    let mut total = 0_usize;
+    while let (Some(base), Some(len)) = (words.next(), words.next()) {
+        let len = len?;
+        // The total is returned, so it must fit in an isize.
+        total = total
+            .checked_add(len)
+            .filter(|&total| total <= isize::MAX as usize)
+            .ok_or(Error::new(EINVAL))?;
+        bufs.push(UserSlice::new(base?, len)?);
+    }
+    Ok(bufs)
+}
+
+/// Read from `fd` into the buffers of `iov` in turn, as a single read.
+pub fn readv(fd: FileHandle, iov: usize, count: usize) -> Result<usize> {
+    let bufs = iovecs(iov, count)?;
+    file_op_generic(fd, |scheme, number| scheme.kreadv(number, &bufs))
+}
+
+/// Write the buffers of `iov` in turn to `fd`, as a single write.
+pub fn writev(fd: FileHandle, iov: usize, count: usize) -> Result<usize> {
+    let bufs = iovecs(iov, count)?;
+    file_op_generic(fd, |scheme, number| scheme.kwritev(number, &bufs))
+}
+
+/// Read from `fd` at `offset`, without using or moving its file offset.
+pub fn pread(fd: FileHandle, buf: UserSliceWo, offset: u64) -> Result<usize> {
+    if offset > i64::MAX as u64 {
+        return Err(Error::new(EINVAL));
+    }
+    file_op_generic(fd, |scheme, number| scheme.kread_at(number, buf, offset))
+}
+
+/// Write to `fd` at `offset`, without using or moving its file offset.
+pub fn pwrite(fd: FileHandle, buf: UserSliceRo, offset: u64) -> Result<usize> {
+    if offset > i64::MAX as u64 {
+        return Err(Error::new(EINVAL));
+    }
+    file_op_generic(fd, |scheme, number| scheme.kwrite_at(number, buf, offset))
+}
+
 pub fn funmap(virtual_address: usize, length: usize) -> Result<usize> {
     // Partial lengths in funmap are allowed according to POSIX, but not particularly meaningful;
     // since the memory needs to SIGSEGV if later read, the entire page needs to disappear.
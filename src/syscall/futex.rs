@@ -2,37 +2,30 @@
 //! Futex or Fast Userspace Mutex is "a method for waiting until a certain condition becomes true."
 //!
 //! For more information about futexes, please read [this](https://eli.thegreenplace.net/2018/basics-of-futexes/) blog post, and the [futex(2)](http://man7.org/linux/man-pages/man2/futex.2.html) man page
-use alloc::{collections::VecDeque, sync::Arc};
+use alloc::sync::Arc;
 use core::sync::atomic::{AtomicU32, Ordering};
 use rmm::Arch;
-use spin::RwLock;
-use spinning_top::RwSpinlock;
 
 use crate::{
-    context::{self, memory::AddrSpace, Context},
+    context::{self, memory::AddrSpace},
     memory::PhysicalAddress,
     paging::{Page, VirtualAddress},
+    sync::WaitTable,
     time,
 };
 
 use crate::syscall::{
     data::TimeSpec,
-    error::{Error, Result, EAGAIN, EFAULT, EINVAL, ESRCH, ETIMEDOUT},
+    error::{Error, Result, EAGAIN, EFAULT, EINVAL},
     flag::{FUTEX_REQUEUE, FUTEX_WAIT, FUTEX_WAIT64, FUTEX_WAKE},
 };
 
 use super::usercopy::UserSlice;
 
-type FutexList = VecDeque<FutexEntry>;
-
-pub struct FutexEntry {
-    target_physaddr: PhysicalAddress,
-    context_lock: Arc<RwSpinlock<Context>>,
-}
-
 // TODO: Process-private futexes? In that case, put the futex table in each AddrSpace.
-// TODO: Hash table?
-static FUTEXES: RwLock<FutexList> = RwLock::new(FutexList::new());
+/// Contexts waiting on futexes, keyed by the physical address of the futex, so that processes
+/// sharing memory can use the same futex at different virtual addresses.
+static FUTEXES: WaitTable<PhysicalAddress> = WaitTable::new();
 
 fn validate_and_translate_virt(space: &AddrSpace, addr: VirtualAddress) -> Option<PhysicalAddress> {
     // TODO: Move this elsewhere!
@@ -48,6 +41,46 @@ fn validate_and_translate_virt(space: &AddrSpace, addr: VirtualAddress) -> Optio
     Some(frame.add(off))
 }
 
+/// Load the futex word at `physaddr`, of 32 bits or with `FUTEX_WAIT64`, 64 bits.
+fn load(op: usize, addr: usize, physaddr: PhysicalAddress) -> Result<u64> {
+    // Must be aligned, otherwise it could cross a page boundary and mess up the (simpler)
+    // validation done in the first place. On systems where virtual memory is not abundant, we
+    // might instead add an atomic usercopy function.
+    let accessible_addr = unsafe { crate::paging::RmmA::phys_to_virt(physaddr) }.data();
+
+    if op == FUTEX_WAIT {
+        if addr % 4 != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        return Ok(u64::from(unsafe {
+            (*(accessible_addr as *const AtomicU32)).load(Ordering::SeqCst)
+        }));
+    }
+
+    #[cfg(target_has_atomic = "64")]
+    {
+        use core::sync::atomic::AtomicU64;
+
+        if addr % 8 != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        Ok(unsafe { (*(accessible_addr as *const AtomicU64)).load(Ordering::SeqCst) })
+    }
+    #[cfg(not(target_has_atomic = "64"))]
+    {
+        Err(Error::new(crate::syscall::error::EOPNOTSUPP))
+    }
+}
+
+/// `futex(addr, op, val, val2, addr2)`.
+///
+/// - `FUTEX_WAIT` and `FUTEX_WAIT64` wait on `addr` while the 32-bit or 64-bit word there equals
+///   `val`, until woken or, if `val2` points to a `TimeSpec`, until that monotonic time. They fail
+///   with `EAGAIN` if the word differs, `ETIMEDOUT` once the time is reached and `EINTR` if
+///   interrupted by a signal.
+/// - `FUTEX_WAKE` wakes up to `val` contexts waiting on `addr`, and returns the number woken.
+/// - `FUTEX_REQUEUE` wakes up to `val` contexts waiting on `addr`, and moves up to `val2` others
+///   to wait on `addr2` instead. Returns the number woken.
 pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> Result<usize> {
     let addr_space_lock = Arc::clone(context::current()?.read().addr_space()?);
 
@@ -62,108 +95,31 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> R
     match op {
         // TODO: FUTEX_WAIT_MULTIPLE?
         FUTEX_WAIT | FUTEX_WAIT64 => {
-            let timeout_opt = UserSlice::ro(val2, core::mem::size_of::<TimeSpec>())?
+            let deadline = UserSlice::ro(val2, core::mem::size_of::<TimeSpec>())?
                 .none_if_null()
                 .map(|buf| unsafe { buf.read_exact::<TimeSpec>() })
-                .transpose()?;
-
-            {
-                let mut futexes = FUTEXES.write();
-
-                let context_lock = context::current()?;
-
-                let (fetched, expected) = if op == FUTEX_WAIT {
-                    // Must be aligned, otherwise it could cross a page boundary and mess up the
-                    // (simpler) validation we did in the first place.
-                    if addr % 4 != 0 {
-                        return Err(Error::new(EINVAL));
-                    }
-
-                    // On systems where virtual memory is not abundant, we might instead add an
-                    // atomic usercopy function.
-                    let accessible_addr =
-                        unsafe { crate::paging::RmmA::phys_to_virt(target_physaddr) }.data();
-
-                    (
-                        u64::from(unsafe {
-                            (*(accessible_addr as *const AtomicU32)).load(Ordering::SeqCst)
-                        }),
-                        u64::from(val as u32),
-                    )
-                } else {
-                    #[cfg(target_has_atomic = "64")]
-                    {
-                        use core::sync::atomic::AtomicU64;
-
-                        // op == FUTEX_WAIT64
-                        if addr % 8 != 0 {
-                            return Err(Error::new(EINVAL));
-                        }
-                        (
-                            u64::from(unsafe {
-                                (*(addr as *const AtomicU64)).load(Ordering::SeqCst)
-                            }),
-                            val as u64,
-                        )
-                    }
-                    #[cfg(not(target_has_atomic = "64"))]
-                    {
-                        return Err(Error::new(crate::syscall::error::EOPNOTSUPP));
-                    }
-                };
-                if fetched != expected {
-                    return Err(Error::new(EAGAIN));
-                }
-
-                {
-                    let mut context = context_lock.write();
-
-                    context.wake = timeout_opt.map(|TimeSpec { tv_sec, tv_nsec }| tv_sec as u128 * time::NANOS_PER_SEC + tv_nsec as u128);
-
-                    context.block("futex");
-                }
-
-                futexes.push_back(FutexEntry {
-                    target_physaddr,
-                    context_lock,
+                .transpose()?
+                .map(|TimeSpec { tv_sec, tv_nsec }| {
+                    tv_sec as u128 * time::NANOS_PER_SEC + tv_nsec as u128
                 });
-            }
-
-            drop(addr_space_guard);
-
-            context::switch();
-
-            if timeout_opt.is_some() {
-                context::current()?.write().wake = None;
-                Err(Error::new(ETIMEDOUT))
+            let expected = if op == FUTEX_WAIT {
+                u64::from(val as u32)
             } else {
-                Ok(0)
-            }
-        }
-        FUTEX_WAKE => {
-            let mut woken = 0;
-
-            {
-                let mut futexes = FUTEXES.write();
-
-                let mut i = 0;
-
-                // TODO: Use retain, once it allows the closure to tell it to stop iterating...
-                while i < futexes.len() && woken < val {
-                    if futexes[i].target_physaddr != target_physaddr {
-                        i += 1;
-                        continue;
-                    }
-                    if let Some(futex) = futexes.swap_remove_back(i) {
-                        let mut context_guard = futex.context_lock.write();
-                        context_guard.unblock();
-                        woken += 1;
-                    }
-                }
-            }
+                val as u64
+            };
 
-            Ok(woken)
+            // Checked with the table locked, so that a wakeup after changing the word is not
+            // missed.
+            let check = || {
+                if load(op, addr, target_physaddr)? != expected {
+                    return Err(Error::new(EAGAIN));
+                }
+                Ok(())
+            };
+            FUTEXES.wait_until(target_physaddr, check, addr_space_guard, "futex", deadline)?;
+            Ok(0)
         }
+        FUTEX_WAKE => Ok(FUTEXES.wake(target_physaddr, val)),
         FUTEX_REQUEUE => {
             let addr2_physaddr =
                 validate_and_translate_virt(&*addr_space_guard, VirtualAddress::new(addr2))
@@ -171,31 +127,7 @@ pub fn futex(addr: usize, op: usize, val: usize, val2: usize, addr2: usize) -> R
 
             drop(addr_space_guard);
 
-            let mut woken = 0;
-            let mut requeued = 0;
-
-            {
-                let mut futexes = FUTEXES.write();
-
-                let mut i = 0;
-                while i < futexes.len() && woken < val {
-                    if futexes[i].target_physaddr != target_physaddr {
-                        i += 1;
-                    }
-                    if let Some(futex) = futexes.swap_remove_back(i) {
-                        futex.context_lock.write().unblock();
-                        woken += 1;
-                    }
-                }
-                while i < futexes.len() && requeued < val2 {
-                    if futexes[i].target_physaddr != target_physaddr {
-                        i += 1;
-                    }
-                    futexes[i].target_physaddr = addr2_physaddr;
-                    requeued += 1;
-                }
-            }
-
+            let (woken, _requeued) = FUTEXES.requeue(target_physaddr, addr2_physaddr, val, val2);
             Ok(woken)
         }
         _ => Err(Error::new(EINVAL)),
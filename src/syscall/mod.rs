@@ -9,7 +9,8 @@ pub use self::syscall::{
 };
 
 pub use self::{
-    driver::*, fs::*, futex::futex, privilege::*, process::*, time::*, usercopy::validate_region,
+    driver::*, ext::*, fs::*, futex::futex, privilege::*, process::*, time::*,
+    usercopy::validate_region,
 };
 
 use self::{
@@ -24,7 +25,7 @@ use crate::interrupt::InterruptStack;
 use crate::percpu::PercpuBlock;
 
 use crate::{
-    context::{memory::AddrSpace, ContextId},
+    context::{cputime, memory::AddrSpace, ContextId},
     scheme::{memory::MemoryScheme, FileHandle, SchemeNamespace},
 };
 
@@ -37,6 +38,9 @@ use self::debug::{debug_end, debug_start};
 /// Driver syscalls
 pub mod driver;
 
+/// Syscall numbers and flags not yet in the syscall crate
+pub mod ext;
+
 /// Filesystem syscalls
 pub mod fs;
 
@@ -156,6 +160,18 @@ pub fn syscall(
                         }),
 
                         SYS_CLOSE => close(fd).map(|()| 0),
+                        #[cfg(target_pointer_width = "32")]
+                        SYS_PREAD => pread(fd, UserSlice::wo(c, d)?, e as u64 | (f as u64) << 32),
+                        #[cfg(target_pointer_width = "64")]
+                        SYS_PREAD => pread(fd, UserSlice::wo(c, d)?, e as u64),
+                        #[cfg(target_pointer_width = "32")]
+                        SYS_PWRITE => pwrite(fd, UserSlice::ro(c, d)?, e as u64 | (f as u64) << 32),
+                        #[cfg(target_pointer_width = "64")]
+                        SYS_PWRITE => pwrite(fd, UserSlice::ro(c, d)?, e as u64),
+                        SYS_READV => readv(fd, c, d),
+                        SYS_WRITEV => writev(fd, c, d),
+                        SYS_SPLICE => splice(fd, FileHandle::from(c), d, e, false),
+                        SYS_TEE => splice(fd, FileHandle::from(c), d, e, true),
 
                         _ => return Err(Error::new(ENOSYS)),
                     },
@@ -169,6 +185,9 @@ pub fn syscall(
             },
             _ => match a {
                 SYS_YIELD => sched_yield().map(|()| 0),
+                SYS_PIPE2 => {
+                    pipe2(UserSlice::wo(b, 2 * core::mem::size_of::<usize>())?, c).map(|()| 0)
+                }
                 SYS_NANOSLEEP => nanosleep(
                     UserSlice::ro(b, core::mem::size_of::<TimeSpec>())?,
                     UserSlice::wo(c, core::mem::size_of::<TimeSpec>())?.none_if_null(),
@@ -185,6 +204,7 @@ pub fn syscall(
 
                 SYS_EXIT => exit((b & 0xFF) << 8),
                 SYS_KILL => kill(ContextId::from(b), c),
+                SYS_SIGQUEUE => sigqueue(ContextId::from(b), c, d),
                 SYS_WAITPID => waitpid(
                     ContextId::from(b),
                     if c == 0 {
@@ -236,6 +256,7 @@ pub fn syscall(
     }
 
     PercpuBlock::current().inside_syscall.set(true);
+    cputime::enter(cputime::Mode::System);
 
     #[cfg(feature = "syscall_debug")]
     debug_start([a, b, c, d, e, f]);
@@ -246,17 +267,22 @@ pub fn syscall(
     debug_end([a, b, c, d, e, f], result);
 
     PercpuBlock::current().inside_syscall.set(false);
+    cputime::enter(cputime::Mode::User);
 
     if a != SYS_SIGRETURN {
         // errormux turns Result<usize> into -errno
         stack.set_syscall_ret_reg(Error::mux(result));
 
-        if result == Err(Error::new(EINTR)) {
+        if result == Err(Error::new(EINTR)) || a == SYS_SIGPROCMASK {
             // Although it would be cleaner to simply run the signal trampoline right after switching
             // back to any given context, where the signal set/queue is nonempty, syscalls need to
             // complete *before* any signal is delivered. Otherwise the return value would probably be
-            // overwritten.
+            // overwritten. Signals unblocked by sigprocmask are delivered right away as well.
             crate::context::signal::signal_handler();
         }
+    } else {
+        // Signals that arrived while the handler ran with them blocked are delivered once it
+        // returns, on the restored stack, or nested in the handler it returned to.
+        crate::context::signal::signal_handler();
     }
 }
@@ -241,6 +241,9 @@ pub fn setreuid(ruid: u32, euid: u32) -> Result<usize> {
 
     if setruid {
         context.ruid = ruid;
+        if let Some(user_context) = &mut context.user_context {
+            user_context.set_uid(ruid);
+        }
     }
 
     if seteuid {
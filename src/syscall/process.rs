@@ -6,6 +6,7 @@ use spin::RwLock;
 
 use crate::context::{
     memory::{AddrSpace, PageSpan, Grant},
+    signal::{SignalInfo, SI_QUEUE, SI_USER},
     ContextId, WaitpidKey,
 };
 
@@ -17,7 +18,7 @@ use crate::{
         data::{SigAction, SignalStack},
         error::*,
         flag::{
-            wifcontinued, wifstopped, MapFlags, WaitFlags, PTRACE_STOP_EXIT, SIGCONT,
+            wifcontinued, wifstopped, MapFlags, WaitFlags, PTRACE_STOP_EXIT,
             SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK, WCONTINUED, WNOHANG, WUNTRACED,
         },
         ptrace_event,
@@ -133,23 +134,48 @@ pub fn getppid() -> Result<ContextId> {
 }
 
 pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
-    let (ruid, euid, current_pgid) = {
+    send_signal(pid, sig, SI_USER, 0)
+}
+
+/// Send `sig` to the process `pid` along with `value`, which its handler receives in the
+/// [`SignalInfo`](context::signal::SignalInfo) above the signal stack. Real-time signals are
+/// queued once per call, up to the `sigpending` limit of the receiver.
+pub fn sigqueue(pid: ContextId, sig: usize, value: usize) -> Result<usize> {
+    if pid.get() as isize <= 0 {
+        return Err(Error::new(EINVAL));
+    }
+    send_signal(pid, sig, SI_QUEUE, value as u64)
+}
+
+/// Send `sig` to the processes selected by `pid` as with `kill`, with the code and value of its
+/// signal information.
+fn send_signal(pid: ContextId, sig: usize, code: i32, value: u64) -> Result<usize> {
+    let (current_pid, ruid, euid, current_pgid) = {
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
-        (context.ruid, context.euid, context.pgid)
+        (context.id, context.ruid, context.euid, context.pgid)
     };
 
-    if sig >= 0x3F {
+    if sig > 64 {
         return Err(Error::new(EINVAL));
     }
+    let info = SignalInfo {
+        signo: sig as u32,
+        code,
+        pid: current_pid.get() as u32,
+        uid: ruid,
+        value,
+    };
     let mut found = 0;
     let mut sent = 0;
+    let mut queue_full = false;
+    let mut receivers = Vec::new();
 
     {
         let contexts = context::contexts();
 
-        let send = |context: &mut context::Context| -> bool {
+        let mut send = |context: &mut context::Context| -> bool {
             // Non-root users cannot kill arbitrarily.
             if euid != 0 && euid != context.ruid && ruid != context.ruid {
                 return false;
@@ -160,14 +186,11 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
                 return true;
             }
 
-            context.sig.pending |= 1_u64 << (sig - 1);
-
-            // Convert stopped processes to blocked if sending SIGCONT
-            if sig == SIGCONT {
-                if let context::Status::Stopped(_sig) = context.status {
-                    context.status = context::Status::Blocked;
-                }
+            if context.kill(info).is_err() {
+                queue_full = true;
+                return false;
             }
+            receivers.push(context.id);
 
             true
         };
@@ -217,10 +240,14 @@ pub fn kill(pid: ContextId, sig: usize) -> Result<usize> {
         }
     }
 
+    for id in receivers {
+        context::signal_queue::notify(id, sig);
+    }
+
     if found == 0 {
         Err(Error::new(ESRCH))
     } else if sent == 0 {
-        Err(Error::new(EPERM))
+        Err(Error::new(if queue_full { EAGAIN } else { EPERM }))
     } else {
         // Switch to ensure delivery to self
         context::switch();
@@ -37,6 +37,7 @@ pub fn nanosleep(req_buf: UserSliceRo, rem_buf_opt: Option<UserSliceWo>) -> Resu
         context.wake = Some(end);
         context.block("nanosleep");
     }
+    context::timeout::register_wake(context::context_id(), end);
 
     // TODO: The previous wakeup reason was most likely signals, but is there any other possible
     // reason?
@@ -11,6 +11,11 @@ pub fn monotonic() -> u128 {
     *OFFSET.lock() + crate::arch::time::counter()
 }
 
+/// Like [`monotonic`], but returns `None` instead of waiting if the clock is locked.
+pub fn try_monotonic() -> Option<u128> {
+    Some(*OFFSET.try_lock()? + crate::arch::time::counter())
+}
+
 pub fn realtime() -> u128 {
     *START.lock() + monotonic()
 }